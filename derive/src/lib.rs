@@ -0,0 +1,101 @@
+//! The derive macro for `sqlite_riir::record::FromRow`, which is re-exported by `sqlite-riir`
+//! with its `derive` feature
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, LitStr};
+
+/// Implement `FromRow` for a struct.
+///
+/// Structs with named fields are built from the columns with the same names, ignoring case, or
+/// from the columns in the same order as the fields if the names of the columns aren't known. A
+/// field can be read from a column with another name with `#[from_row(rename = "name")]`. Tuple
+/// structs are built from the columns in the same order as their fields.
+///
+/// Every field must implement `FromValue`.
+#[proc_macro_derive(FromRow, attributes(from_row))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_row_impl(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Generate the implementation of `FromRow` for `input`.
+fn from_row_impl(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "FromRow can only be derived for structs",
+        ));
+    };
+    let body = match &data.fields {
+        Fields::Named(fields) => {
+            let mut by_position = Vec::new();
+            let mut by_name = Vec::new();
+            for (idx, field) in fields.named.iter().enumerate() {
+                let ident = field.ident.as_ref().expect("Named fields have names");
+                let name = column_name(field)?
+                    .unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+                by_position.push(quote_spanned! {field.span()=> #ident: row.get_as(#idx)? });
+                by_name
+                    .push(quote_spanned! {field.span()=> #ident: row.get_named(columns, #name)? });
+            }
+            quote! {
+                if columns.is_empty() {
+                    Ok(Self { #(#by_position),* })
+                } else {
+                    Ok(Self { #(#by_name),* })
+                }
+            }
+        }
+        Fields::Unnamed(fields) => {
+            let values = fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(idx, field)| quote_spanned! {field.span()=> row.get_as(#idx)? });
+            quote! {
+                let _ = columns;
+                Ok(Self(#(#values),*))
+            }
+        }
+        Fields::Unit => quote! {
+            let _ = (columns, row);
+            Ok(Self)
+        },
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::sqlite_riir::record::FromRow for #name #ty_generics #where_clause {
+            fn from_row<Row: ::sqlite_riir::record::RowExt + ?Sized>(
+                columns: &[::std::string::String],
+                row: &Row,
+            ) -> ::sqlite_riir::Result<Self> {
+                #body
+            }
+        }
+    })
+}
+
+/// Get the name given to the column of `field` with `#[from_row(rename = "name")]`, if any.
+fn column_name(field: &syn::Field) -> syn::Result<Option<LitStr>> {
+    let mut name = None;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("from_row") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("Unknown from_row attribute, expected `rename`"))
+            }
+        })?;
+    }
+    Ok(name)
+}
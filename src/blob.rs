@@ -0,0 +1,169 @@
+//! Reading large blobs a piece at a time, without loading them into memory
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    btree,
+    pager::{OverflowChain, Storage},
+    parse_varint,
+    record::{ColumnType, IntType, Record},
+    Database,
+};
+
+/// The most bytes a varint can take up.
+const MAX_VARINT_LEN: usize = 9;
+
+/// A blob or text value in a row, which is read with [`Read`] and [`Seek`] instead of being
+/// loaded into memory all at once.
+///
+/// Made with [`Database::blob_open`]. The part of the row stored in its btree page is copied out
+/// when it's opened, and the rest is read from the overflow pages as it's needed.
+pub struct Blob<'a, File = std::fs::File> {
+    /// The database containing the row
+    db: &'a mut Database<File>,
+    /// The part of the row's payload stored in its btree page
+    local: Vec<u8>,
+    /// The chain of overflow pages with the rest of the payload, if any
+    overflow: Option<OverflowChain>,
+    /// Where the value starts in the payload
+    start: usize,
+    /// The length of the value, in bytes
+    len: usize,
+    /// The position to read from next, relative to the start of the value
+    position: u64,
+}
+
+impl<'a, File: Storage> Blob<'a, File> {
+    /// Open the value in column `column` of the row with the given rowid, in the table btree
+    /// rooted at `root_page`.
+    pub(crate) fn open(
+        db: &'a mut Database<File>,
+        root_page: usize,
+        column: usize,
+        rowid: i64,
+    ) -> Result<Self> {
+        let (local, overflow) = btree::find_payload(&mut db.pager, root_page, rowid)?
+            .with_context(|| crate::Error::NotFound(format!("no such rowid: {rowid}")))?
+            .into_parts();
+        let mut blob = Self {
+            db,
+            local,
+            overflow,
+            start: 0,
+            len: 0,
+            position: 0,
+        };
+
+        // The header is almost always in the btree page, but can spill into overflow pages in
+        // rows with very many columns.
+        let mut header = vec![0; MAX_VARINT_LEN.min(blob.payload_len())];
+        blob.read_payload_exact(0, &mut header)?;
+        let header_len =
+            usize::try_from(parse_varint(&mut &header[..])?).context("Invalid header length")?;
+        header.resize(header_len, 0);
+        blob.read_payload_exact(0, &mut header)?;
+        let record = Record::parse(&header)?;
+
+        let mut types = record.type_iter();
+        let offset = types
+            .by_ref()
+            .take(column)
+            .map(ColumnType::body_len)
+            .sum::<usize>();
+        // Rows written before a column was added don't have a value for it.
+        let ty = types.next().unwrap_or(ColumnType::Null);
+        blob.len = match ty {
+            ColumnType::Blob(len) | ColumnType::String(len) => len as usize,
+            _ => anyhow::bail!("cannot open value of type {}", type_name(ty)),
+        };
+        blob.start = header_len + offset;
+        anyhow::ensure!(
+            blob.start + blob.len <= blob.payload_len(),
+            "Value extends past the end of the row"
+        );
+        Ok(blob)
+    }
+
+    /// Get the length of the value, in bytes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the value is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the length of the whole payload of the row.
+    fn payload_len(&self) -> usize {
+        self.local.len() + self.overflow.as_ref().map_or(0, OverflowChain::len)
+    }
+
+    /// Read bytes starting `offset` bytes into the payload into `buf`, returning how many were
+    /// read, which may be fewer than would fit.
+    fn read_payload(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Some(local) = self.local.get(offset..).filter(|local| !local.is_empty()) {
+            let take = buf.len().min(local.len());
+            buf[..take].copy_from_slice(&local[..take]);
+            return Ok(take);
+        }
+        match &mut self.overflow {
+            Some(chain) => chain.read_at(&mut self.db.pager, offset - self.local.len(), buf),
+            None => Ok(0),
+        }
+    }
+
+    /// Fill `buf` with the bytes starting `offset` bytes into the payload.
+    fn read_payload_exact(&mut self, mut offset: usize, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let read = self.read_payload(offset, buf)?;
+            anyhow::ensure!(read > 0, "Unexpected end of payload");
+            offset += read;
+            buf = &mut buf[read..];
+        }
+        Ok(())
+    }
+}
+
+/// Get the name SQLite uses for the type of a value stored as `ty`, in errors.
+fn type_name(ty: ColumnType) -> &'static str {
+    match ty {
+        ColumnType::F64 => "real",
+        ColumnType::Null => "null",
+        _ if IntType::from_column_type(ty).is_some() => "integer",
+        _ => "unknown",
+    }
+}
+
+impl<File: Storage> Read for Blob<'_, File> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = usize::try_from(self.position).unwrap_or(usize::MAX);
+        if position >= self.len {
+            return Ok(0);
+        }
+        let end = buf.len().min(self.len - position);
+        let read = self
+            .read_payload(self.start + position, &mut buf[..end])
+            .map_err(io::Error::other)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<File: Storage> Seek for Blob<'_, File> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(position) => (position, 0),
+            SeekFrom::End(offset) => (self.len as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,1038 @@
+//! Modifying btrees
+//!
+//! Pages are modified by decoding all their cells, changing the list of cells, and then writing
+//! the whole page back out. If the cells no longer fit, the page is split, and the new pages are
+//! linked into its parent.
+
+pub(crate) mod index;
+
+use std::io::{Read, Seek};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    page::{btree_header_offset, btree_table_leaf::Cell, ParsedPage},
+    pager::{OverflowChain, Pager, PtrmapEntry, DATABASE_HEADER_SIZE},
+    varint_len, write_varint,
+};
+
+/// The size of the header on a leaf page.
+const LEAF_HEADER_SIZE: usize = 8;
+/// The size of the header on an internal page.
+const INTERNAL_HEADER_SIZE: usize = 12;
+/// The size of each entry in the cell pointer array.
+const CELL_POINTER_SIZE: usize = 2;
+/// The first byte of the header on a table leaf page.
+const TABLE_LEAF_PAGE_TYPE: u8 = 0x0d;
+/// The first byte of the header on a table internal page.
+const TABLE_INTERNAL_PAGE_TYPE: u8 = 0x05;
+
+/// The decoded contents of a table btree page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    /// A leaf, holding `(rowid, payload)` for each row in rowid order.
+    ///
+    /// Payloads which spill onto overflow pages stay there, so only the part in the page and the
+    /// pointer to the rest are moved around.
+    Leaf(Vec<(i64, PartialPayload)>),
+    /// An internal page.
+    Internal {
+        /// `(left child page, key)` for each cell, where every rowid in the left child is at most
+        /// the key.
+        cells: Vec<(u32, i64)>,
+        /// The child holding every rowid greater than all the keys.
+        rightmost: u32,
+    },
+}
+
+impl Node {
+    /// Read the node stored in the given page.
+    fn read<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<Self> {
+        let page = pager.read_page(page_num)?;
+        Ok(match page.parse() {
+            ParsedPage::BTreeTableLeaf(leaf) => Self::Leaf(
+                leaf.cells()
+                    .map(|cell| (cell.row_id(), PartialPayload::of(&cell)))
+                    .collect(),
+            ),
+            ParsedPage::BTreeTableInternal(internal) => Self::Internal {
+                cells: internal
+                    .cells()
+                    .map(|cell| (cell.left_child_page, cell.key))
+                    .collect(),
+                rightmost: internal.rightmost_child_idx(),
+            },
+            ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                anyhow::bail!("Expected a table page at {page_num}")
+            }
+        })
+    }
+
+    /// The number of bytes of the page this node needs, excluding any database header.
+    fn size(&self) -> usize {
+        match self {
+            Self::Leaf(cells) => {
+                LEAF_HEADER_SIZE
+                    + cells
+                        .iter()
+                        .map(|(rowid, payload)| leaf_cell_size(*rowid, payload))
+                        .sum::<usize>()
+            }
+            Self::Internal { cells, .. } => {
+                INTERNAL_HEADER_SIZE
+                    + cells
+                        .iter()
+                        .map(|(_, key)| internal_cell_size(*key))
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Split `self` into pieces which each fit in `capacity` bytes.
+    ///
+    /// Returns the pieces to go in new pages to the left, each with its key in the parent, and
+    /// then the rightmost piece, which takes the place of the original node. Cells are packed
+    /// into the leftmost pieces first, so appending rows leaves full pages behind.
+    fn split(self, capacity: usize) -> (Vec<(Self, i64)>, Self) {
+        let mut pieces = Vec::new();
+        match self {
+            Self::Leaf(cells) => {
+                let mut current = Vec::new();
+                let mut used = LEAF_HEADER_SIZE;
+                for (rowid, payload) in cells {
+                    let size = leaf_cell_size(rowid, &payload);
+                    if !current.is_empty() && used + size > capacity {
+                        let key = current.last().map_or(rowid, |(rowid, _)| *rowid);
+                        pieces.push((Self::Leaf(std::mem::take(&mut current)), key));
+                        used = LEAF_HEADER_SIZE;
+                    }
+                    used += size;
+                    current.push((rowid, payload));
+                }
+                (pieces, Self::Leaf(current))
+            }
+            Self::Internal { cells, rightmost } => {
+                let mut current = Vec::new();
+                let mut used = INTERNAL_HEADER_SIZE;
+                for (child, key) in cells {
+                    let size = internal_cell_size(key);
+                    if current.len() >= 2 && used + size > capacity {
+                        // The last cell moves up into the parent, with its child becoming the
+                        // rightmost child of the piece it ends. Each piece keeps at least one
+                        // cell, since SQLite rejects interior pages without any.
+                        let (last_child, separator) = current.pop().unwrap();
+                        pieces.push((
+                            Self::Internal {
+                                cells: std::mem::take(&mut current),
+                                rightmost: last_child,
+                            },
+                            separator,
+                        ));
+                        used = INTERNAL_HEADER_SIZE;
+                    }
+                    used += size;
+                    current.push((child, key));
+                }
+                (
+                    pieces,
+                    Self::Internal {
+                        cells: current,
+                        rightmost,
+                    },
+                )
+            }
+        }
+    }
+
+    /// Write `self` into the given page, which must have room for it.
+    fn write<File: Read + Seek>(&self, pager: &mut Pager<File>, page_num: usize) -> Result<()> {
+        match self {
+            Self::Leaf(rows) => {
+                let cells = rows
+                    .iter()
+                    .map(|(rowid, payload)| {
+                        let mut cell = Vec::with_capacity(leaf_cell_size(*rowid, payload));
+                        write_varint(payload.len() as i64, &mut cell);
+                        write_varint(*rowid, &mut cell);
+                        cell.extend(&payload.local);
+                        if let Some((first_page, _)) = payload.overflow {
+                            cell.extend(first_page.to_be_bytes());
+                        }
+                        cell
+                    })
+                    .collect::<Vec<_>>();
+                write_page(pager, page_num, TABLE_LEAF_PAGE_TYPE, None, &cells)?;
+                // Rows move between pages too, so their overflow chains get their new page
+                // recorded as well.
+                for (_, payload) in rows {
+                    if let Some((first_page, _)) = payload.overflow {
+                        pager.set_ptrmap(
+                            first_page as usize,
+                            PtrmapEntry::FirstOverflow(page_num as u32),
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Internal { cells, rightmost } => {
+                let cells = cells
+                    .iter()
+                    .map(|(child, key)| {
+                        let mut cell = child.to_be_bytes().to_vec();
+                        write_varint(*key, &mut cell);
+                        cell
+                    })
+                    .collect::<Vec<_>>();
+                write_page(
+                    pager,
+                    page_num,
+                    TABLE_INTERNAL_PAGE_TYPE,
+                    Some(*rightmost),
+                    &cells,
+                )
+            }
+        }
+    }
+}
+
+/// The payload of a cell in a table leaf, copied out of its page so the rest of it can be read
+/// from overflow pages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PartialPayload {
+    /// The part of the payload stored in the page
+    local: Vec<u8>,
+    /// The first overflow page and the number of bytes stored in overflow pages, if any
+    overflow: Option<(u32, usize)>,
+}
+impl PartialPayload {
+    /// Copy the part of `cell`'s payload which is in its page.
+    pub(crate) fn of(cell: &Cell) -> Self {
+        let local = cell.payload_bytes().to_vec();
+        let overflow = cell
+            .overflow_page()
+            .map(|page| (page, cell.payload_size() - local.len()));
+        Self { local, overflow }
+    }
+
+    /// The size of the whole payload, including the part in overflow pages.
+    fn len(&self) -> usize {
+        self.local.len() + self.overflow.map_or(0, |(_, len)| len)
+    }
+
+    /// Read the rest of the payload from its overflow pages, if any.
+    pub(crate) fn complete<File: Read + Seek>(self, pager: &mut Pager<File>) -> Result<Vec<u8>> {
+        let mut payload = self.local;
+        if let Some((first_page, len)) = self.overflow {
+            payload.extend(pager.read_overflow(first_page, len)?);
+        }
+        Ok(payload)
+    }
+
+    /// Split into the part of the payload in its page, and the chain of overflow pages holding
+    /// the rest, if any, so the rest can be read without reading all of it.
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Option<OverflowChain>) {
+        let chain = self
+            .overflow
+            .map(|(first_page, len)| OverflowChain::new(first_page, len));
+        (self.local, chain)
+    }
+}
+
+/// Write a btree page made up of the given encoded cells.
+///
+/// `rightmost` is the rightmost child pointer, which only internal pages have.
+fn write_page<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    page_type: u8,
+    rightmost: Option<u32>,
+    cells: &[Vec<u8>],
+) -> Result<()> {
+    let page_size = pager.page_size();
+    let usable_size = pager.usable_size();
+    let offset = btree_header_offset(page_num);
+    let header_size = if rightmost.is_some() {
+        INTERNAL_HEADER_SIZE
+    } else {
+        LEAF_HEADER_SIZE
+    };
+    anyhow::ensure!(
+        offset
+            + header_size
+            + cells
+                .iter()
+                .map(|cell| cell.len() + CELL_POINTER_SIZE)
+                .sum::<usize>()
+            <= usable_size,
+        "Btree node too large for its page"
+    );
+    let mut page = vec![0; page_size];
+    // The reserved bytes at the end of the page belong to whatever reserved them.
+    if let Ok(existing) = pager.read_page_bytes(page_num) {
+        page[usable_size..].copy_from_slice(&existing[usable_size..]);
+    }
+    if page_num == 1 {
+        // Keep the database header at the start of the first page
+        page[..DATABASE_HEADER_SIZE]
+            .copy_from_slice(&pager.read_page_bytes(1)?[..DATABASE_HEADER_SIZE]);
+    }
+    page[offset] = page_type;
+    if let Some(rightmost) = rightmost {
+        page[offset + 8..offset + 12].copy_from_slice(&rightmost.to_be_bytes());
+    }
+    // Cell contents are packed against the end of the page, in reverse order.
+    let mut content_start = usable_size;
+    for (idx, cell) in cells.iter().enumerate() {
+        content_start -= cell.len();
+        page[content_start..content_start + cell.len()].copy_from_slice(cell);
+        let pointer_offset = offset + header_size + idx * CELL_POINTER_SIZE;
+        page[pointer_offset..pointer_offset + CELL_POINTER_SIZE]
+            .copy_from_slice(&(content_start as u16).to_be_bytes());
+    }
+    page[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    // A content start of 65536 wraps around to 0, which is how SQLite stores it.
+    page[offset + 5..offset + 7].copy_from_slice(&(content_start as u16).to_be_bytes());
+    pager.write_page(page_num, &page)?;
+    if let Some(rightmost) = rightmost {
+        // Splits and merges move pages between parents, so every child's parent gets recorded.
+        let children = cells
+            .iter()
+            .map(|cell| u32::from_be_bytes(cell[..4].try_into().unwrap()))
+            .chain([rightmost]);
+        for child in children {
+            pager.set_ptrmap(child as usize, PtrmapEntry::BTree(page_num as u32))?;
+        }
+    }
+    Ok(())
+}
+
+/// Make the given page the root of an empty table btree.
+pub(crate) fn init<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<()> {
+    Node::Leaf(Vec::new()).write(pager, page_num)
+}
+
+/// Copy the btree rooted at `root_page`, which may be a table or an index, into a new btree in
+/// `to` rooted at `new_root`, which must be an unused page.
+///
+/// The contents are inserted in order, so the copy's pages are packed as full as they can be.
+pub(crate) fn copy<From: Read + Seek, To: Read + Seek>(
+    from: &mut Pager<From>,
+    root_page: usize,
+    to: &mut Pager<To>,
+    new_root: usize,
+) -> Result<()> {
+    let page_type = page_type(from, root_page)?;
+    if matches!(page_type, TABLE_LEAF_PAGE_TYPE | TABLE_INTERNAL_PAGE_TYPE) {
+        init(to, new_root)?;
+        copy_rows(from, root_page, to, new_root)
+    } else {
+        index::copy(from, root_page, to, new_root)
+    }
+}
+
+/// Insert every row in the subtree rooted at `page_num` into the table btree in `to` rooted at
+/// `root_page`.
+fn copy_rows<From: Read + Seek, To: Read + Seek>(
+    from: &mut Pager<From>,
+    page_num: usize,
+    to: &mut Pager<To>,
+    root_page: usize,
+) -> Result<()> {
+    match Node::read(from, page_num)? {
+        Node::Leaf(cells) => {
+            for (rowid, payload) in cells {
+                insert(to, root_page, rowid, &payload.complete(from)?)?;
+            }
+        }
+        Node::Internal { cells, rightmost } => {
+            for (child, _) in cells {
+                copy_rows(from, child as usize, to, root_page)?;
+            }
+            copy_rows(from, rightmost as usize, to, root_page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove everything from the btree rooted at `root_page`, which may be a table or an index,
+/// moving every page but the root to the freelist.
+///
+/// Only the internal pages have to be read, so this is much faster than deleting each row.
+pub(crate) fn clear<File: Read + Seek>(pager: &mut Pager<File>, root_page: usize) -> Result<()> {
+    match page_type(pager, root_page)? {
+        TABLE_LEAF_PAGE_TYPE | TABLE_INTERNAL_PAGE_TYPE => {
+            free_descendants(pager, root_page)?;
+            init(pager, root_page)
+        }
+        _ => index::clear(pager, root_page),
+    }
+}
+
+/// Move every page below `page_num` in a table btree to the freelist, along with the overflow
+/// pages of every row under it.
+fn free_descendants<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<()> {
+    let children = match pager.read_page(page_num)?.parse() {
+        ParsedPage::BTreeTableLeaf(leaf) => {
+            // Only rows with overflow pages have anything outside the page to free.
+            let chains = leaf
+                .cells()
+                .filter_map(|cell| PartialPayload::of(&cell).overflow)
+                .collect::<Vec<_>>();
+            for (first_page, len) in chains {
+                pager.free_overflow(first_page, len)?;
+            }
+            return Ok(());
+        }
+        ParsedPage::BTreeTableInternal(internal) => internal
+            .cells()
+            .map(|cell| cell.left_child_page)
+            .chain([internal.rightmost_child_idx()])
+            .collect::<Vec<_>>(),
+        ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+            anyhow::bail!("Expected a table page at {page_num}")
+        }
+    };
+    for child in children {
+        free_descendants(pager, child as usize)?;
+        pager.free_page(child as usize)?;
+    }
+    Ok(())
+}
+
+/// Get the byte at the start of a btree page's header which says what kind of page it is.
+fn page_type<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<u8> {
+    let offset = btree_header_offset(page_num);
+    pager
+        .read_page_bytes(page_num)?
+        .get(offset)
+        .copied()
+        .with_context(|| format!("Unexpected end of page {page_num}"))
+}
+
+/// Insert a row into the table btree rooted at `root_page`.
+///
+/// Fails if the table already contains a row with the given rowid.
+pub(crate) fn insert<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    rowid: i64,
+    payload: &[u8],
+) -> Result<()> {
+    // TODO Support overflow pages for rows too large to fit in a page
+    let max_local = pager.usable_size() - 35;
+    anyhow::ensure!(
+        payload.len() <= max_local,
+        "Rows larger than {max_local} bytes are unimplemented"
+    );
+    insert_into(pager, root_page, true, rowid, payload)?;
+    Ok(())
+}
+
+/// Insert a row into the subtree rooted at `page_num`.
+///
+/// If the page had to be split, returns the cells to insert into its parent, immediately before
+/// the cell pointing to `page_num`.
+fn insert_into<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    rowid: i64,
+    payload: &[u8],
+) -> Result<Vec<(u32, i64)>> {
+    let mut node = Node::read(pager, page_num)?;
+    match &mut node {
+        Node::Leaf(cells) => {
+            let idx = cells.partition_point(|(cell_rowid, _)| *cell_rowid < rowid);
+            anyhow::ensure!(
+                cells.get(idx).map(|(cell_rowid, _)| *cell_rowid) != Some(rowid),
+                "A row with rowid {rowid} already exists"
+            );
+            let payload = PartialPayload {
+                local: payload.to_vec(),
+                overflow: None,
+            };
+            cells.insert(idx, (rowid, payload));
+        }
+        Node::Internal { cells, rightmost } => {
+            let idx = cells.partition_point(|(_, key)| *key < rowid);
+            let child = cells.get(idx).map_or(*rightmost, |(child, _)| *child);
+            let new_cells = insert_into(pager, child as usize, false, rowid, payload)?;
+            if new_cells.is_empty() {
+                return Ok(Vec::new());
+            }
+            cells.splice(idx..idx, new_cells);
+        }
+    }
+    store(pager, page_num, is_root, node)
+}
+
+/// Write `node` into the given page, splitting it if it doesn't fit.
+///
+/// If the page had to be split, returns the cells to insert into its parent, immediately before
+/// the cell pointing to `page_num`.
+fn store<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    node: Node,
+) -> Result<Vec<(u32, i64)>> {
+    let usable_size = pager.usable_size();
+    if btree_header_offset(page_num) + node.size() <= usable_size {
+        node.write(pager, page_num)?;
+        return Ok(Vec::new());
+    }
+    let (pieces, rightmost) = node.split(usable_size);
+    let mut parent_cells = Vec::with_capacity(pieces.len());
+    for (piece, key) in pieces {
+        let new_page = pager.allocate_page()?;
+        piece.write(pager, new_page)?;
+        parent_cells.push((new_page as u32, key));
+    }
+    if is_root {
+        // The root has to stay where it is, so everything moves into new pages below it.
+        let new_page = pager.allocate_page()?;
+        rightmost.write(pager, new_page)?;
+        Node::Internal {
+            cells: parent_cells,
+            rightmost: new_page as u32,
+        }
+        .write(pager, page_num)?;
+        Ok(Vec::new())
+    } else {
+        rightmost.write(pager, page_num)?;
+        Ok(parent_cells)
+    }
+}
+
+/// Find the largest rowid in the table btree rooted at `root_page`.
+///
+/// Returns `None` if the table is empty.
+pub(crate) fn max_rowid<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+) -> Result<Option<i64>> {
+    let mut page_num = root_page;
+    loop {
+        let page = pager.read_page(page_num)?;
+        match page.parse() {
+            ParsedPage::BTreeTableLeaf(leaf) => {
+                return Ok(leaf.cells().last().map(|cell| cell.row_id()))
+            }
+            ParsedPage::BTreeTableInternal(internal) => {
+                page_num = internal.rightmost_child_idx() as usize;
+            }
+            ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                anyhow::bail!("Expected a table page at {page_num}")
+            }
+        }
+    }
+}
+
+/// List the rowids in the table btree rooted at `root_page`, in ascending order.
+pub(crate) fn rowids<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+) -> Result<Vec<i64>> {
+    let mut rowids = Vec::new();
+    let mut pending = vec![root_page];
+    while let Some(page_num) = pending.pop() {
+        let page = pager.read_page(page_num)?;
+        match page.parse() {
+            ParsedPage::BTreeTableLeaf(leaf) => {
+                rowids.extend(leaf.cells().map(|cell| cell.row_id()));
+            }
+            ParsedPage::BTreeTableInternal(internal) => {
+                // Children are visited in the reverse of the order they're pushed.
+                pending.push(internal.rightmost_child_idx() as usize);
+                let children = internal
+                    .cells()
+                    .map(|cell| cell.left_child_page as usize)
+                    .collect::<Vec<_>>();
+                pending.extend(children.into_iter().rev());
+            }
+            ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                anyhow::bail!("Expected a table page at {page_num}")
+            }
+        }
+    }
+    Ok(rowids)
+}
+
+/// Check whether the table btree rooted at `root_page` has a row with the given rowid.
+pub(crate) fn contains_rowid<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    rowid: i64,
+) -> Result<bool> {
+    Ok(find_row(pager, root_page, rowid)?.is_some())
+}
+
+/// Find the payload of the row with the given rowid in the table btree rooted at `root_page`.
+pub(crate) fn find_row<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    rowid: i64,
+) -> Result<Option<Vec<u8>>> {
+    find_payload(pager, root_page, rowid)?
+        .map(|payload| payload.complete(pager))
+        .transpose()
+}
+
+/// Find the row with the given rowid in the table btree rooted at `root_page`, without reading
+/// the part of its payload in overflow pages.
+pub(crate) fn find_payload<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    rowid: i64,
+) -> Result<Option<PartialPayload>> {
+    let mut page_num = root_page;
+    loop {
+        let page = pager.read_page(page_num)?;
+        match page.parse() {
+            ParsedPage::BTreeTableLeaf(leaf) => {
+                return Ok(leaf
+                    .find_cell_by_rowid(rowid)
+                    .map(|cell| PartialPayload::of(&cell)));
+            }
+            ParsedPage::BTreeTableInternal(internal) => {
+                page_num = internal.find_child_for_key(rowid) as usize;
+            }
+            ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                anyhow::bail!("Expected a table page at {page_num}")
+            }
+        }
+    }
+}
+
+/// Delete the row with the given rowid from the table btree rooted at `root_page`.
+///
+/// Returns whether there was such a row. Pages left empty are moved to the freelist, and
+/// internal pages left with a single child are merged with a neighbour, but partially-empty
+/// pages otherwise aren't.
+pub(crate) fn delete<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    rowid: i64,
+) -> Result<bool> {
+    match delete_from(pager, root_page, true, rowid)? {
+        None => return Ok(false),
+        Some(Removed::Kept(_)) => {}
+        Some(Removed::Empty) => Node::Leaf(Vec::new()).write(pager, root_page)?,
+        Some(Removed::OnlyChild(child)) => {
+            // The root has to stay where it is, so its child moves up into it if there's room.
+            let child_node = Node::read(pager, child as usize)?;
+            if btree_header_offset(root_page) + child_node.size() <= pager.usable_size() {
+                child_node.write(pager, root_page)?;
+                pager.free_page(child as usize)?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// How a subtree changed when a row was deleted from it, which its parent has to account for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Removed {
+    /// The subtree still has rows under its root page, which had to be split if it holds the
+    /// given cells for its parent, as from [`insert_into`].
+    Kept(Vec<(u32, i64)>),
+    /// The subtree has no rows left, so its root page can be freed.
+    Empty,
+    /// The subtree's root page has no cells left, only the given child.
+    ///
+    /// Replacing the page with its child would leave the child's leaves higher up the tree than
+    /// any others, so unless the page is the root, it has to be merged with a neighbour instead.
+    OnlyChild(u32),
+}
+
+/// Delete a row from the subtree rooted at `page_num`, returning `None` if there was no such row.
+fn delete_from<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    rowid: i64,
+) -> Result<Option<Removed>> {
+    let mut node = Node::read(pager, page_num)?;
+    match &mut node {
+        Node::Leaf(cells) => {
+            let Ok(idx) = cells.binary_search_by_key(&rowid, |(cell_rowid, _)| *cell_rowid) else {
+                return Ok(None);
+            };
+            let (_, payload) = cells.remove(idx);
+            if let Some((first_page, len)) = payload.overflow {
+                pager.free_overflow(first_page, len)?;
+            }
+            if cells.is_empty() {
+                return Ok(Some(Removed::Empty));
+            }
+        }
+        Node::Internal { cells, rightmost } => {
+            let idx = cells.partition_point(|(_, key)| *key < rowid);
+            let child = cells.get(idx).map_or(*rightmost, |(child, _)| *child);
+            match delete_from(pager, child as usize, false, rowid)? {
+                None => return Ok(None),
+                Some(Removed::Kept(new_cells)) => {
+                    if new_cells.is_empty() {
+                        return Ok(Some(Removed::Kept(Vec::new())));
+                    }
+                    cells.splice(idx..idx, new_cells);
+                }
+                Some(Removed::OnlyChild(grandchild)) if cells.is_empty() => {
+                    // Only the root can be left without cells, and then this is its only child,
+                    // so the whole level below it goes at once.
+                    *rightmost = grandchild;
+                    pager.free_page(child as usize)?;
+                }
+                Some(Removed::OnlyChild(_)) => {
+                    merge_children(pager, cells, *rightmost, idx.saturating_sub(1))?;
+                }
+                Some(Removed::Empty) => {
+                    pager.free_page(child as usize)?;
+                    if idx < cells.len() {
+                        cells.remove(idx);
+                    } else if let Some((last_child, _)) = cells.pop() {
+                        *rightmost = last_child;
+                    } else {
+                        return Ok(Some(Removed::Empty));
+                    }
+                }
+            }
+            if cells.is_empty() {
+                let only_child = *rightmost;
+                node.write(pager, page_num)?;
+                return Ok(Some(Removed::OnlyChild(only_child)));
+            }
+        }
+    }
+    Ok(Some(Removed::Kept(store(pager, page_num, is_root, node)?)))
+}
+
+/// Merge the internal pages on either side of the key in `cells[idx]`, one of which was left
+/// with no cells of its own, so every leaf stays at the same depth.
+///
+/// The key moves down between their children, and the left page is freed. If the result doesn't
+/// fit in a page, the children are shared out between the two pages instead, and a new key
+/// between them takes the old key's place.
+fn merge_children<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    cells: &mut Vec<(u32, i64)>,
+    rightmost: u32,
+    idx: usize,
+) -> Result<()> {
+    let (left_page, key) = cells[idx];
+    let right_page = cells.get(idx + 1).map_or(rightmost, |(child, _)| *child);
+    let Node::Internal {
+        cells: mut merged,
+        rightmost: left_rightmost,
+    } = Node::read(pager, left_page as usize)?
+    else {
+        anyhow::bail!("Expected an internal page at {left_page}");
+    };
+    let Node::Internal {
+        cells: right_cells,
+        rightmost,
+    } = Node::read(pager, right_page as usize)?
+    else {
+        anyhow::bail!("Expected an internal page at {right_page}");
+    };
+    merged.push((left_rightmost, key));
+    merged.extend(right_cells);
+
+    let size = merged
+        .iter()
+        .map(|(_, key)| internal_cell_size(*key))
+        .sum::<usize>();
+    if btree_header_offset(right_page as usize) + INTERNAL_HEADER_SIZE + size <= pager.usable_size()
+    {
+        Node::Internal {
+            cells: merged,
+            rightmost,
+        }
+        .write(pager, right_page as usize)?;
+        pager.free_page(left_page as usize)?;
+        cells.remove(idx);
+        return Ok(());
+    }
+    // Each page gets about half of the bytes, and at least one cell.
+    let half = size / 2;
+    let mut used = 0;
+    let mid = merged
+        .iter()
+        .position(|(_, key)| {
+            used += internal_cell_size(*key);
+            used > half
+        })
+        .unwrap_or(0)
+        .clamp(1, merged.len() - 2);
+    let right_cells = merged.split_off(mid + 1);
+    let (mid_child, new_key) = merged.pop().unwrap();
+    Node::Internal {
+        cells: merged,
+        rightmost: mid_child,
+    }
+    .write(pager, left_page as usize)?;
+    Node::Internal {
+        cells: right_cells,
+        rightmost,
+    }
+    .write(pager, right_page as usize)?;
+    cells[idx].1 = new_key;
+    Ok(())
+}
+
+/// The number of bytes a cell in a leaf page takes up, including its cell pointer.
+fn leaf_cell_size(rowid: i64, payload: &PartialPayload) -> usize {
+    let overflow_pointer_size = if payload.overflow.is_some() { 4 } else { 0 };
+    varint_len(payload.len() as i64)
+        + varint_len(rowid)
+        + payload.local.len()
+        + overflow_pointer_size
+        + CELL_POINTER_SIZE
+}
+
+/// The number of bytes a cell in an internal page takes up, including its cell pointer.
+fn internal_cell_size(key: i64) -> usize {
+    4 + varint_len(key) + CELL_POINTER_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::record::{Record, Value};
+
+    fn open_fixture(path: &str) -> Pager<Cursor<Vec<u8>>> {
+        let contents = std::fs::read(path).expect("Failed to read test database");
+        Pager::new(Cursor::new(contents)).expect("Failed to parse test database")
+    }
+
+    /// Read every `(rowid, payload)` in the table, in order.
+    fn scan(pager: &mut Pager<Cursor<Vec<u8>>>, page_num: usize) -> Vec<(i64, Vec<u8>)> {
+        match Node::read(pager, page_num).expect("Failed to read node") {
+            Node::Leaf(cells) => cells
+                .into_iter()
+                .map(|(rowid, payload)| (rowid, payload.complete(pager).unwrap()))
+                .collect(),
+            Node::Internal { cells, rightmost } => cells
+                .into_iter()
+                .map(|(child, _)| child)
+                .chain([rightmost])
+                .flat_map(|child| scan(pager, child as usize))
+                .collect(),
+        }
+    }
+
+    /// Find how many levels down each leaf is, in order.
+    fn leaf_depths(pager: &mut Pager<Cursor<Vec<u8>>>, page_num: usize) -> Vec<usize> {
+        match Node::read(pager, page_num).expect("Failed to read node") {
+            Node::Leaf(_) => vec![0],
+            Node::Internal { cells, rightmost } => cells
+                .into_iter()
+                .map(|(child, _)| child)
+                .chain([rightmost])
+                .flat_map(|child| leaf_depths(pager, child as usize))
+                .map(|depth| depth + 1)
+                .collect(),
+        }
+    }
+
+    /// Count the cells in each interior page of the tree.
+    fn interior_cell_counts(pager: &mut Pager<Cursor<Vec<u8>>>, page_num: usize) -> Vec<usize> {
+        match Node::read(pager, page_num).expect("Failed to read node") {
+            Node::Leaf(_) => Vec::new(),
+            Node::Internal { cells, rightmost } => {
+                let mut counts = vec![cells.len()];
+                for child in cells.into_iter().map(|(child, _)| child).chain([rightmost]) {
+                    counts.extend(interior_cell_counts(pager, child as usize));
+                }
+                counts
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_keeps_interior_cells() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        // Three rows fill a leaf, so interior pages fill up quickly.
+        let payload = Record::build(&[Value::Blob(&[0xAB; 1300][..])]);
+        // Appending puts each new cell at the end of its interior page, which is where a page
+        // which overflows is split.
+        for rowid in 1..=2400 {
+            insert(&mut pager, 2, rowid, &payload).expect("Failed to insert");
+            let counts = interior_cell_counts(&mut pager, 2);
+            assert!(
+                !counts.contains(&0),
+                "Interior page without cells after inserting {rowid}: {counts:?}",
+            );
+        }
+        assert!(
+            interior_cell_counts(&mut pager, 2).len() > 2,
+            "An interior page should have been split",
+        );
+        assert_eq!(scan(&mut pager, 2).len(), 2400);
+    }
+
+    #[test]
+    fn test_insert_with_splits() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let payload = |rowid: i64| Record::build(&[Value::<&[u8]>::int(rowid); 20]);
+        // Insert out of order to exercise inserting into the middle of pages
+        let rowids = (0..2000).map(|n| (n * 7919) % 2000 + 1).collect::<Vec<_>>();
+        for &rowid in &rowids {
+            insert(&mut pager, 2, rowid, &payload(rowid)).expect("Failed to insert");
+        }
+        assert!(
+            insert(&mut pager, 2, 5, &payload(5)).is_err(),
+            "Rowids should be unique",
+        );
+        assert!(pager.page_count() > 20, "The table should have been split");
+        assert_eq!(
+            scan(&mut pager, 2),
+            (1..=2000)
+                .map(|rowid| (rowid, payload(rowid)))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(max_rowid(&mut pager, 2).unwrap(), Some(2000));
+        assert!(contains_rowid(&mut pager, 2, 1234).unwrap());
+        assert!(!contains_rowid(&mut pager, 2, 2001).unwrap());
+        assert_eq!(max_rowid(&mut pager, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_count = pager.page_count();
+        let payload = |rowid: i64| Record::build(&[Value::<&[u8]>::int(rowid); 20]);
+        for rowid in 1..=2000 {
+            insert(&mut pager, 2, rowid, &payload(rowid)).expect("Failed to insert");
+        }
+        let deleted = (0..2000)
+            .map(|n| (n * 7919) % 2000 + 1)
+            .filter(|rowid| rowid % 3 != 0)
+            .collect::<Vec<_>>();
+        for &rowid in &deleted {
+            assert!(delete(&mut pager, 2, rowid).expect("Failed to delete"));
+        }
+        assert!(
+            !delete(&mut pager, 2, 1).unwrap(),
+            "The row is already gone"
+        );
+        assert_eq!(
+            scan(&mut pager, 2),
+            (1..=2000)
+                .filter(|rowid| rowid % 3 == 0)
+                .map(|rowid| (rowid, payload(rowid)))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(find_row(&mut pager, 2, 3).unwrap(), Some(payload(3)));
+        assert_eq!(find_row(&mut pager, 2, 4).unwrap(), None);
+
+        for rowid in (1..=2000).filter(|rowid| rowid % 3 == 0) {
+            assert!(delete(&mut pager, 2, rowid).expect("Failed to delete"));
+        }
+        assert_eq!(Node::read(&mut pager, 2).unwrap(), Node::Leaf(Vec::new()));
+        let free_count = u32::from_be_bytes(
+            pager.read_page_bytes(1).unwrap()[36..40]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(
+            free_count as usize,
+            pager.page_count() - page_count,
+            "Every page the table grew into should be free again",
+        );
+
+        // With a page per row, the table is three levels deep, and deleting all but one of the
+        // rows under an internal page leaves it with a single child.
+        let payload = |rowid: i64| Record::build(&[Value::int(rowid), Value::Blob(&[7; 3000][..])]);
+        for rowid in 1..=1200 {
+            insert(&mut pager, 2, rowid, &payload(rowid)).expect("Failed to insert");
+        }
+        assert_eq!(leaf_depths(&mut pager, 2)[0], 2);
+        let deleted = (1..600).chain((600..=1200).filter(|rowid| rowid % 5 != 0));
+        for rowid in deleted {
+            assert!(delete(&mut pager, 2, rowid).expect("Failed to delete"));
+            let depths = leaf_depths(&mut pager, 2);
+            assert!(
+                depths.iter().all(|depth| *depth == depths[0]),
+                "Every leaf should be at the same depth after deleting {rowid}",
+            );
+        }
+        assert_eq!(
+            scan(&mut pager, 2),
+            (600..=1200)
+                .filter(|rowid| rowid % 5 == 0)
+                .map(|rowid| (rowid, payload(rowid)))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_overflow_rows() {
+        let mut pager = open_fixture("test-data/overflow.sqlite");
+        let free_count = |pager: &mut Pager<Cursor<Vec<u8>>>| {
+            u32::from_be_bytes(
+                pager.read_page_bytes(1).unwrap()[36..40]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        let rows = scan(&mut pager, 2);
+        assert_eq!(free_count(&mut pager), 0);
+
+        // Rows with overflow pages can share a leaf with new rows.
+        let payload = Record::build(&[Value::<&[u8]>::Null, Value::int(5)]);
+        insert(&mut pager, 2, 5, &payload).expect("Failed to insert");
+        assert_eq!(scan(&mut pager, 2)[..rows.len()], rows);
+        assert_eq!(find_row(&mut pager, 2, 5).unwrap(), Some(payload));
+
+        // Row 2 spills onto two overflow pages, and row 3 onto one.
+        assert!(delete(&mut pager, 2, 2).expect("Failed to delete"));
+        assert_eq!(free_count(&mut pager), 2);
+        clear(&mut pager, 2).expect("Failed to clear");
+        assert_eq!(
+            free_count(&mut pager) as usize,
+            pager.page_count() - 2,
+            "Every page but the schema and the table's root should be free",
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_count = pager.page_count();
+        let payload = |rowid: i64| Record::build(&[Value::<&[u8]>::int(rowid); 20]);
+        for rowid in 1..=2000 {
+            insert(&mut pager, 2, rowid, &payload(rowid)).expect("Failed to insert");
+        }
+        clear(&mut pager, 2).expect("Failed to clear");
+        assert_eq!(Node::read(&mut pager, 2).unwrap(), Node::Leaf(Vec::new()));
+        let free_count = u32::from_be_bytes(
+            pager.read_page_bytes(1).unwrap()[36..40]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(free_count as usize, pager.page_count() - page_count);
+        insert(&mut pager, 2, 1, &payload(1)).expect("Failed to insert");
+        assert_eq!(scan(&mut pager, 2), [(1, payload(1))]);
+    }
+
+    #[test]
+    fn test_split_first_page() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let header = pager.read_page(1).unwrap().as_bytes()[..DATABASE_HEADER_SIZE].to_vec();
+        let original_rows = scan(&mut pager, 1);
+        let payload = Record::build(&[Value::String(&[b'x'; 500][..])]);
+        for rowid in 10..30 {
+            insert(&mut pager, 1, rowid, &payload).expect("Failed to insert");
+        }
+        assert_eq!(
+            pager.read_page(1).unwrap().as_bytes()[..DATABASE_HEADER_SIZE],
+            header,
+            "The database header should be untouched",
+        );
+        assert_eq!(scan(&mut pager, 1).len(), original_rows.len() + 20);
+        assert_eq!(scan(&mut pager, 1)[..original_rows.len()], original_rows);
+    }
+}
@@ -0,0 +1,1092 @@
+//! Modifying index btrees
+//!
+//! Unlike in a table btree, every cell in an index btree is an entry in the index, including the
+//! cells in internal pages. Each entry is a record of the indexed values followed by the rowid.
+
+use std::{
+    cmp::Ordering,
+    io::{Read, Seek},
+};
+
+use anyhow::{Context, Result};
+
+use super::{write_page, CELL_POINTER_SIZE, INTERNAL_HEADER_SIZE, LEAF_HEADER_SIZE};
+use crate::{
+    page::{btree_header_offset, local_payload_size},
+    pager::{Pager, PtrmapEntry},
+    parse_varint,
+    record::Record,
+    varint_len, write_varint,
+};
+
+/// The first byte of the header on an index leaf page.
+const INDEX_LEAF_PAGE_TYPE: u8 = 0x0a;
+/// The first byte of the header on an index internal page.
+const INDEX_INTERNAL_PAGE_TYPE: u8 = 0x02;
+
+/// The decoded contents of an index btree page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    /// A leaf, holding the entries in order.
+    Leaf(Vec<Entry>),
+    /// An internal page.
+    Internal {
+        /// `(left child page, entry)` for each cell, where every entry in the left child is less
+        /// than the cell's entry.
+        cells: Vec<(u32, Entry)>,
+        /// The child holding every entry greater than all the cells' entries.
+        rightmost: u32,
+    },
+}
+
+impl Node {
+    /// Read the node stored in the given page.
+    fn read<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<Self> {
+        let usable_size = pager.usable_size();
+        let max_local = max_local_payload(usable_size);
+        let page = pager.read_page_bytes(page_num)?;
+        let offset = btree_header_offset(page_num);
+        let header = page
+            .get(offset..offset + INTERNAL_HEADER_SIZE)
+            .context("Unexpected end of page")?;
+        let is_internal = match header[0] {
+            INDEX_LEAF_PAGE_TYPE => false,
+            INDEX_INTERNAL_PAGE_TYPE => true,
+            byte => anyhow::bail!("Expected an index page at {page_num}, found type {byte}"),
+        };
+        let header_size = if is_internal {
+            INTERNAL_HEADER_SIZE
+        } else {
+            LEAF_HEADER_SIZE
+        };
+        let cell_count = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let rightmost = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let pointers = page
+            .get(offset + header_size..offset + header_size + cell_count * CELL_POINTER_SIZE)
+            .context("Unexpected end of page in cell pointer array")?;
+        let cells = pointers
+            .chunks_exact(CELL_POINTER_SIZE)
+            .map(|pointer| {
+                let mut cell = page
+                    .get(usize::from(u16::from_be_bytes([pointer[0], pointer[1]]))..)
+                    .context("Cell pointer out of bounds")?;
+                let child = if is_internal {
+                    let (child, rest) = cell
+                        .split_first_chunk()
+                        .context("Unexpected end of page in cell")?;
+                    cell = rest;
+                    u32::from_be_bytes(*child)
+                } else {
+                    0
+                };
+                let len = usize::try_from(parse_varint(&mut cell)?)
+                    .context("Invalid index entry length")?;
+                let local_len = local_payload_size(len, usable_size, max_local);
+                let local = cell
+                    .get(..local_len)
+                    .context("Unexpected end of page in cell")?;
+                let overflow = if local_len < len {
+                    let first_page = cell
+                        .get(local_len..local_len + 4)
+                        .context("Unexpected end of page in cell")?;
+                    Some((
+                        u32::from_be_bytes(first_page.try_into().unwrap()),
+                        local_len,
+                    ))
+                } else {
+                    None
+                };
+                Ok((child, local.to_vec(), len, overflow))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Comparing entries needs all of them, so the parts in overflow pages are read as well.
+        let cells = cells
+            .into_iter()
+            .map(|(child, mut record, len, overflow)| {
+                if let Some((first_page, local_len)) = overflow {
+                    record.extend(pager.read_overflow(first_page, len - local_len)?);
+                }
+                Ok((child, Entry { record, overflow }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(if is_internal {
+            Self::Internal { cells, rightmost }
+        } else {
+            Self::Leaf(cells.into_iter().map(|(_, entry)| entry).collect())
+        })
+    }
+
+    /// The entries in this node, in order.
+    fn entries(&self) -> Vec<&[u8]> {
+        match self {
+            Self::Leaf(cells) => cells.iter().map(|entry| entry.record.as_slice()).collect(),
+            Self::Internal { cells, .. } => cells
+                .iter()
+                .map(|(_, entry)| entry.record.as_slice())
+                .collect(),
+        }
+    }
+
+    /// The number of bytes of the page this node needs.
+    fn size(&self) -> usize {
+        match self {
+            Self::Leaf(cells) => LEAF_HEADER_SIZE + cells.iter().map(leaf_cell_size).sum::<usize>(),
+            Self::Internal { cells, .. } => {
+                INTERNAL_HEADER_SIZE
+                    + cells
+                        .iter()
+                        .map(|(_, entry)| leaf_cell_size(entry) + 4)
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Split `self` into pieces which each fit in `capacity` bytes.
+    ///
+    /// Returns the pieces to go in new pages to the left, each with the entry which separates it
+    /// from the next piece, and then the rightmost piece, which takes the place of the original
+    /// node. The separating entries move up into the parent, so they're in none of the pieces.
+    fn split(self, capacity: usize) -> (Vec<(Self, Entry)>, Self) {
+        let mut pieces = Vec::new();
+        match self {
+            Self::Leaf(cells) => {
+                let mut current = Vec::new();
+                let mut used = LEAF_HEADER_SIZE;
+                for entry in cells {
+                    let size = leaf_cell_size(&entry);
+                    if current.len() >= 2 && used + size > capacity {
+                        let separator = current.pop().unwrap();
+                        pieces.push((Self::Leaf(std::mem::take(&mut current)), separator));
+                        used = LEAF_HEADER_SIZE;
+                    }
+                    used += size;
+                    current.push(entry);
+                }
+                (pieces, Self::Leaf(current))
+            }
+            Self::Internal { cells, rightmost } => {
+                let mut current = Vec::new();
+                let mut used = INTERNAL_HEADER_SIZE;
+                for (child, entry) in cells {
+                    let size = leaf_cell_size(&entry) + 4;
+                    if current.len() >= 2 && used + size > capacity {
+                        // The last cell moves up into the parent, with its child becoming the
+                        // rightmost child of the piece it ends.
+                        let (last_child, separator) = current.pop().unwrap();
+                        pieces.push((
+                            Self::Internal {
+                                cells: std::mem::take(&mut current),
+                                rightmost: last_child,
+                            },
+                            separator,
+                        ));
+                        used = INTERNAL_HEADER_SIZE;
+                    }
+                    used += size;
+                    current.push((child, entry));
+                }
+                (
+                    pieces,
+                    Self::Internal {
+                        cells: current,
+                        rightmost,
+                    },
+                )
+            }
+        }
+    }
+
+    /// Write `self` into the given page, which must have room for it.
+    fn write<File: Read + Seek>(&self, pager: &mut Pager<File>, page_num: usize) -> Result<()> {
+        match self {
+            Self::Leaf(entries) => {
+                let cells = entries
+                    .iter()
+                    .map(|entry| {
+                        let mut cell = Vec::with_capacity(leaf_cell_size(entry));
+                        entry.encode(&mut cell);
+                        cell
+                    })
+                    .collect::<Vec<_>>();
+                write_page(pager, page_num, INDEX_LEAF_PAGE_TYPE, None, &cells)?;
+            }
+            Self::Internal { cells, rightmost } => {
+                let encoded = cells
+                    .iter()
+                    .map(|(child, entry)| {
+                        let mut cell = child.to_be_bytes().to_vec();
+                        entry.encode(&mut cell);
+                        cell
+                    })
+                    .collect::<Vec<_>>();
+                write_page(
+                    pager,
+                    page_num,
+                    INDEX_INTERNAL_PAGE_TYPE,
+                    Some(*rightmost),
+                    &encoded,
+                )?;
+            }
+        }
+        // Entries move between pages too, so their overflow chains get their new page recorded
+        // as well.
+        let entries = match self {
+            Self::Leaf(entries) => entries.iter().collect::<Vec<_>>(),
+            Self::Internal { cells, .. } => cells.iter().map(|(_, entry)| entry).collect(),
+        };
+        for entry in entries {
+            if let Some((first_page, _)) = entry.overflow {
+                pager.set_ptrmap(
+                    first_page as usize,
+                    PtrmapEntry::FirstOverflow(page_num as u32),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An entry in an index btree, along with where the part of it which doesn't fit in a cell is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    /// The whole record, including any part in overflow pages
+    record: Vec<u8>,
+    /// The first overflow page and the number of bytes stored in the cell, for entries too large
+    /// to fit in one
+    overflow: Option<(u32, usize)>,
+}
+
+impl Entry {
+    /// Make an entry for a new record, writing the part which doesn't fit in a cell to new
+    /// overflow pages.
+    fn new<File: Read + Seek>(pager: &mut Pager<File>, record: &[u8]) -> Result<Self> {
+        let usable_size = pager.usable_size();
+        let local_len =
+            local_payload_size(record.len(), usable_size, max_local_payload(usable_size));
+        let overflow = if local_len < record.len() {
+            let first_page = pager.write_overflow(&record[local_len..])?;
+            Some((first_page, local_len))
+        } else {
+            None
+        };
+        Ok(Self {
+            record: record.to_vec(),
+            overflow,
+        })
+    }
+
+    /// The part of the record stored in the cell.
+    fn local(&self) -> &[u8] {
+        match self.overflow {
+            Some((_, local_len)) => &self.record[..local_len],
+            None => &self.record,
+        }
+    }
+
+    /// Append the entry's cell, after any child pointer, to `cell`.
+    fn encode(&self, cell: &mut Vec<u8>) {
+        write_varint(self.record.len() as i64, cell);
+        cell.extend(self.local());
+        if let Some((first_page, _)) = self.overflow {
+            cell.extend(first_page.to_be_bytes());
+        }
+    }
+
+    /// Move the entry's overflow pages to the freelist, once it's no longer in the index.
+    fn free<File: Read + Seek>(self, pager: &mut Pager<File>) -> Result<()> {
+        if let Some((first_page, local_len)) = self.overflow {
+            pager.free_overflow(first_page, self.record.len() - local_len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Insert an entry into the index btree rooted at `root_page`.
+///
+/// `entry` is a record of the indexed values followed by the rowid, and `descending` says which of
+/// the indexed values sort in descending order.
+pub(crate) fn insert<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    entry: &[u8],
+    descending: &[bool],
+) -> Result<()> {
+    let entry = Entry::new(pager, entry)?;
+    insert_into(pager, root_page, true, &entry, descending, false)?;
+    Ok(())
+}
+
+/// Remove every entry from the index btree rooted at `root_page`, moving every page but the root
+/// to the freelist.
+pub(crate) fn clear<File: Read + Seek>(pager: &mut Pager<File>, root_page: usize) -> Result<()> {
+    free_descendants(pager, root_page)?;
+    Node::Leaf(Vec::new()).write(pager, root_page)
+}
+
+/// Move every page below `page_num` in an index btree to the freelist, along with the overflow
+/// pages of every entry in the subtree.
+fn free_descendants<File: Read + Seek>(pager: &mut Pager<File>, page_num: usize) -> Result<()> {
+    let (cells, rightmost) = match Node::read(pager, page_num)? {
+        Node::Leaf(entries) => {
+            for entry in entries {
+                entry.free(pager)?;
+            }
+            return Ok(());
+        }
+        Node::Internal { cells, rightmost } => (cells, rightmost),
+    };
+    for (child, entry) in cells {
+        entry.free(pager)?;
+        free_descendants(pager, child as usize)?;
+        pager.free_page(child as usize)?;
+    }
+    free_descendants(pager, rightmost as usize)?;
+    pager.free_page(rightmost as usize)?;
+    Ok(())
+}
+
+/// Copy the index btree rooted at `root_page` into a new btree in `to` rooted at `new_root`, which
+/// must be an unused page.
+pub(crate) fn copy<From: Read + Seek, To: Read + Seek>(
+    from: &mut Pager<From>,
+    root_page: usize,
+    to: &mut Pager<To>,
+    new_root: usize,
+) -> Result<()> {
+    Node::Leaf(Vec::new()).write(to, new_root)?;
+    copy_entries(from, root_page, to, new_root)
+}
+
+/// Append every entry in the subtree rooted at `page_num` to the index btree in `to` rooted at
+/// `root_page`, in order.
+///
+/// The entries aren't compared, so the copy keeps the original's order even for indexes whose
+/// order can't be worked out yet.
+fn copy_entries<From: Read + Seek, To: Read + Seek>(
+    from: &mut Pager<From>,
+    page_num: usize,
+    to: &mut Pager<To>,
+    root_page: usize,
+) -> Result<()> {
+    // Overflow pages belong to the database they're in, so large entries get new ones.
+    let append = |to: &mut Pager<To>, entry: &Entry| {
+        let entry = Entry::new(to, &entry.record)?;
+        insert_into(to, root_page, true, &entry, &[], true)
+    };
+    match Node::read(from, page_num)? {
+        Node::Leaf(entries) => {
+            for entry in entries {
+                append(to, &entry)?;
+            }
+        }
+        Node::Internal { cells, rightmost } => {
+            for (child, entry) in cells {
+                copy_entries(from, child as usize, to, root_page)?;
+                append(to, &entry)?;
+            }
+            copy_entries(from, rightmost as usize, to, root_page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Insert an entry into the subtree rooted at `page_num`.
+///
+/// If `append` is set, the entry goes after every entry already in the subtree, instead of
+/// wherever it sorts. If the page had to be split, returns the cells to insert into its parent,
+/// immediately before the cell pointing to `page_num`.
+fn insert_into<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    entry: &Entry,
+    descending: &[bool],
+    append: bool,
+) -> Result<Vec<(u32, Entry)>> {
+    let mut node = Node::read(pager, page_num)?;
+    let idx = if append {
+        node.entries().len()
+    } else {
+        lower_bound(&node.entries(), &entry.record, usize::MAX, descending)?
+    };
+    match &mut node {
+        Node::Leaf(cells) => cells.insert(idx, entry.clone()),
+        Node::Internal { cells, rightmost } => {
+            let child = cells.get(idx).map_or(*rightmost, |(child, _)| *child);
+            let new_cells = insert_into(pager, child as usize, false, entry, descending, append)?;
+            if new_cells.is_empty() {
+                return Ok(Vec::new());
+            }
+            cells.splice(idx..idx, new_cells);
+        }
+    }
+
+    store(pager, page_num, is_root, node)
+}
+
+/// Write `node` into the given page, splitting it if it doesn't fit.
+///
+/// Returns the cells to insert into the parent, immediately before the cell pointing to
+/// `page_num`, if the page was split.
+fn store<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    node: Node,
+) -> Result<Vec<(u32, Entry)>> {
+    let usable_size = pager.usable_size();
+    if btree_header_offset(page_num) + node.size() <= usable_size {
+        node.write(pager, page_num)?;
+        return Ok(Vec::new());
+    }
+    let (pieces, rightmost) = node.split(usable_size);
+    let mut parent_cells = Vec::with_capacity(pieces.len());
+    for (piece, separator) in pieces {
+        let new_page = pager.allocate_page()?;
+        piece.write(pager, new_page)?;
+        parent_cells.push((new_page as u32, separator));
+    }
+    if is_root {
+        // The root has to stay where it is, so everything moves into new pages below it.
+        let new_page = pager.allocate_page()?;
+        rightmost.write(pager, new_page)?;
+        Node::Internal {
+            cells: parent_cells,
+            rightmost: new_page as u32,
+        }
+        .write(pager, page_num)?;
+        Ok(Vec::new())
+    } else {
+        rightmost.write(pager, page_num)?;
+        Ok(parent_cells)
+    }
+}
+
+/// Find an entry in the index btree rooted at `root_page` whose first `columns` values are equal
+/// to those of `key`, where `descending` says which of the indexed values sort in descending order.
+pub(crate) fn find_key<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    key: &[u8],
+    columns: usize,
+    descending: &[bool],
+) -> Result<Option<Vec<u8>>> {
+    let mut page_num = root_page;
+    loop {
+        let node = Node::read(pager, page_num)?;
+        let entries = node.entries();
+        let idx = lower_bound(&entries, key, columns, descending)?;
+        if let Some(entry) = entries.get(idx) {
+            if compare_records(entry, key, columns, descending)? == Ordering::Equal {
+                return Ok(Some(entry.to_vec()));
+            }
+        }
+        match node {
+            Node::Leaf(_) => return Ok(None),
+            Node::Internal { cells, rightmost } => {
+                page_num = cells.get(idx).map_or(rightmost, |(child, _)| *child) as usize;
+            }
+        }
+    }
+}
+
+/// Read every entry in the index btree rooted at `root_page`, in order.
+pub(crate) fn entries<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+) -> Result<Vec<Vec<u8>>> {
+    match Node::read(pager, root_page)? {
+        Node::Leaf(cells) => Ok(cells.into_iter().map(|entry| entry.record).collect()),
+        Node::Internal { cells, rightmost } => {
+            let mut all = Vec::new();
+            for (child, entry) in cells {
+                all.extend(entries(pager, child as usize)?);
+                all.push(entry.record);
+            }
+            all.extend(entries(pager, rightmost as usize)?);
+            Ok(all)
+        }
+    }
+}
+
+/// Find every entry in the index btree rooted at `root_page` whose first `columns` values are
+/// equal to those of `key`, in order.
+pub(crate) fn find_all<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    key: &[u8],
+    columns: usize,
+    descending: &[bool],
+) -> Result<Vec<Vec<u8>>> {
+    let mut found = Vec::new();
+    collect_matches(pager, root_page, key, columns, descending, &mut found)?;
+    Ok(found)
+}
+
+/// Append every entry in the subtree rooted at `page_num` which [`find_all`] is looking for to
+/// `found`, in order.
+fn collect_matches<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    key: &[u8],
+    columns: usize,
+    descending: &[bool],
+    found: &mut Vec<Vec<u8>>,
+) -> Result<()> {
+    match Node::read(pager, page_num)? {
+        Node::Leaf(entries) => {
+            for entry in entries {
+                match compare_records(&entry.record, key, columns, descending)? {
+                    Ordering::Less => {}
+                    Ordering::Equal => found.push(entry.record),
+                    Ordering::Greater => break,
+                }
+            }
+        }
+        Node::Internal { cells, rightmost } => {
+            for (child, entry) in cells {
+                // Every entry in the child is less than the cell's, so the child can only hold
+                // matches if the cell's entry isn't less than the key.
+                match compare_records(&entry.record, key, columns, descending)? {
+                    Ordering::Less => {}
+                    Ordering::Equal => {
+                        collect_matches(pager, child as usize, key, columns, descending, found)?;
+                        found.push(entry.record);
+                    }
+                    Ordering::Greater => {
+                        return collect_matches(
+                            pager,
+                            child as usize,
+                            key,
+                            columns,
+                            descending,
+                            found,
+                        );
+                    }
+                }
+            }
+            collect_matches(pager, rightmost as usize, key, columns, descending, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete an entry from the index btree rooted at `root_page`.
+///
+/// `descending` says which of the indexed values sort in descending order. Returns whether the
+/// index had the entry. As with tables, pages left empty are freed and internal pages left with a
+/// single child are merged with a neighbour, but partially-empty pages otherwise aren't.
+pub(crate) fn delete<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    root_page: usize,
+    entry: &[u8],
+    descending: &[bool],
+) -> Result<bool> {
+    let mut orphans = Vec::new();
+    let Some((entry, removed)) = remove_from(
+        pager,
+        root_page,
+        true,
+        Some(entry),
+        descending,
+        &mut orphans,
+    )?
+    else {
+        return Ok(false);
+    };
+    entry.free(pager)?;
+    match removed {
+        Removed::Kept(_) => {}
+        Removed::Empty => Node::Leaf(Vec::new()).write(pager, root_page)?,
+        Removed::OnlyChild(child) => {
+            // The root has to stay where it is, so its child moves up into it if there's room.
+            let child_node = Node::read(pager, child as usize)?;
+            if btree_header_offset(root_page) + child_node.size() <= pager.usable_size() {
+                child_node.write(pager, root_page)?;
+                pager.free_page(child as usize)?;
+            }
+        }
+    }
+    for orphan in orphans {
+        insert_into(pager, root_page, true, &orphan, descending, false)?;
+    }
+    Ok(true)
+}
+
+/// How a subtree changed when an entry was removed from it, which its parent has to account for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Removed {
+    /// The subtree still has entries under its root page, which may have been split, in which
+    /// case these cells go into the parent immediately before the cell pointing to it.
+    Kept(Vec<(u32, Entry)>),
+    /// The subtree has no entries left, so its root page can be freed.
+    Empty,
+    /// The subtree's root page has no cells left, only the given child.
+    ///
+    /// Unless the page is the root, it has to be merged with a neighbour rather than replaced by
+    /// its child, which would leave the child's leaves higher up the tree than any others.
+    OnlyChild(u32),
+}
+
+/// Remove `target` from the subtree rooted at `page_num`, or its last entry if `target` is
+/// `None`, returning the removed entry.
+///
+/// When an internal cell loses its child, its entry can't stay in the node, so it's added to
+/// `orphans` to be inserted again once the removal is done. An entry removed from an internal
+/// cell is replaced by the largest entry in the cell's child, which can grow the node enough to
+/// split it.
+fn remove_from<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    page_num: usize,
+    is_root: bool,
+    target: Option<&[u8]>,
+    descending: &[bool],
+    orphans: &mut Vec<Entry>,
+) -> Result<Option<(Entry, Removed)>> {
+    let mut node = Node::read(pager, page_num)?;
+    let idx = match target {
+        Some(target) => lower_bound(&node.entries(), target, usize::MAX, descending)?,
+        None => node.entries().len(),
+    };
+    let found = match target {
+        Some(target) => match node.entries().get(idx) {
+            Some(entry) => {
+                compare_records(entry, target, usize::MAX, descending)? == Ordering::Equal
+            }
+            None => false,
+        },
+        None => false,
+    };
+    match &mut node {
+        Node::Leaf(cells) => {
+            let idx = if target.is_some() {
+                if !found {
+                    return Ok(None);
+                }
+                idx
+            } else if let Some(last) = cells.len().checked_sub(1) {
+                last
+            } else {
+                return Ok(None);
+            };
+            let entry = cells.remove(idx);
+            let removed = if cells.is_empty() {
+                Removed::Empty
+            } else {
+                Removed::Kept(Vec::new())
+            };
+            node.write(pager, page_num)?;
+            Ok(Some((entry, removed)))
+        }
+        Node::Internal { cells, rightmost } => {
+            let child = cells.get(idx).map_or(*rightmost, |(child, _)| *child);
+            let (entry, child_removed) = if found {
+                let (predecessor, child_removed) =
+                    remove_from(pager, child as usize, false, None, descending, orphans)?
+                        .context("Index btree has an empty page")?;
+                (
+                    std::mem::replace(&mut cells[idx].1, predecessor),
+                    child_removed,
+                )
+            } else {
+                let Some(result) =
+                    remove_from(pager, child as usize, false, target, descending, orphans)?
+                else {
+                    return Ok(None);
+                };
+                result
+            };
+            match child_removed {
+                Removed::Kept(new_cells) => {
+                    if new_cells.is_empty() && !found {
+                        return Ok(Some((entry, Removed::Kept(Vec::new()))));
+                    }
+                    cells.splice(idx..idx, new_cells);
+                }
+                Removed::OnlyChild(grandchild) if cells.is_empty() => {
+                    // Only the root can be left without cells, and then this is its only child,
+                    // so the whole level below it goes at once.
+                    *rightmost = grandchild;
+                    pager.free_page(child as usize)?;
+                }
+                Removed::OnlyChild(_) => {
+                    merge_children(pager, cells, *rightmost, idx.saturating_sub(1))?;
+                }
+                Removed::Empty => {
+                    pager.free_page(child as usize)?;
+                    if idx < cells.len() {
+                        orphans.push(cells.remove(idx).1);
+                    } else if let Some((last_child, last_entry)) = cells.pop() {
+                        *rightmost = last_child;
+                        orphans.push(last_entry);
+                    } else {
+                        return Ok(Some((entry, Removed::Empty)));
+                    }
+                }
+            }
+            if cells.is_empty() {
+                let only_child = *rightmost;
+                node.write(pager, page_num)?;
+                return Ok(Some((entry, Removed::OnlyChild(only_child))));
+            }
+            let new_cells = store(pager, page_num, is_root, node)?;
+            Ok(Some((entry, Removed::Kept(new_cells))))
+        }
+    }
+}
+
+/// Merge the internal pages on either side of the entry in `cells[idx]`, one of which was left
+/// with no cells of its own, so every leaf stays at the same depth.
+///
+/// The entry moves down between their children, and the left page is freed. If the result
+/// doesn't fit in a page, the children are shared out between the two pages instead, and a new
+/// entry between them takes the old entry's place.
+fn merge_children<File: Read + Seek>(
+    pager: &mut Pager<File>,
+    cells: &mut Vec<(u32, Entry)>,
+    rightmost: u32,
+    idx: usize,
+) -> Result<()> {
+    let left_page = cells[idx].0;
+    let right_page = cells.get(idx + 1).map_or(rightmost, |(child, _)| *child);
+    let Node::Internal {
+        cells: mut merged,
+        rightmost: left_rightmost,
+    } = Node::read(pager, left_page as usize)?
+    else {
+        anyhow::bail!("Expected an internal page at {left_page}");
+    };
+    let Node::Internal {
+        cells: right_cells,
+        rightmost,
+    } = Node::read(pager, right_page as usize)?
+    else {
+        anyhow::bail!("Expected an internal page at {right_page}");
+    };
+    merged.push((left_rightmost, cells[idx].1.clone()));
+    merged.extend(right_cells);
+
+    let size = merged
+        .iter()
+        .map(|(_, entry)| 4 + leaf_cell_size(entry))
+        .sum::<usize>();
+    if btree_header_offset(right_page as usize) + INTERNAL_HEADER_SIZE + size <= pager.usable_size()
+    {
+        Node::Internal {
+            cells: merged,
+            rightmost,
+        }
+        .write(pager, right_page as usize)?;
+        pager.free_page(left_page as usize)?;
+        cells.remove(idx);
+        return Ok(());
+    }
+    // Each page gets about half of the bytes, and at least one cell.
+    let half = size / 2;
+    let mut used = 0;
+    let mid = merged
+        .iter()
+        .position(|(_, entry)| {
+            used += 4 + leaf_cell_size(entry);
+            used > half
+        })
+        .unwrap_or(0)
+        .clamp(1, merged.len() - 2);
+    let right_cells = merged.split_off(mid + 1);
+    let (mid_child, new_entry) = merged.pop().unwrap();
+    Node::Internal {
+        cells: merged,
+        rightmost: mid_child,
+    }
+    .write(pager, left_page as usize)?;
+    Node::Internal {
+        cells: right_cells,
+        rightmost,
+    }
+    .write(pager, right_page as usize)?;
+    cells[idx].1 = new_entry;
+    Ok(())
+}
+
+/// Find the index of the first of `entries` which isn't less than `key`, comparing only the first
+/// `columns` values.
+fn lower_bound(
+    entries: &[&[u8]],
+    key: &[u8],
+    columns: usize,
+    descending: &[bool],
+) -> Result<usize> {
+    for (idx, entry) in entries.iter().enumerate() {
+        if compare_records(entry, key, columns, descending)? != Ordering::Less {
+            return Ok(idx);
+        }
+    }
+    Ok(entries.len())
+}
+
+/// Compare the first `columns` values of two records, in the order used by indexes.
+///
+/// The `n`th value sorts in reverse if `descending[n]` is set. Values past the end of
+/// `descending`, such as the trailing rowid, always sort in ascending order. If one record is a
+/// prefix of the other, the shorter one is less.
+fn compare_records(a: &[u8], b: &[u8], columns: usize, descending: &[bool]) -> Result<Ordering> {
+    let a = Record::parse(a)?;
+    let b = Record::parse(b)?;
+    let mut a = a.value_iter().take(columns);
+    let mut b = b.value_iter().take(columns);
+    let mut descending = descending.iter().copied();
+    loop {
+        let reverse = descending.next().unwrap_or(false);
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => match a.sqlite_cmp(&b) {
+                Ordering::Equal => {}
+                ordering if reverse => return Ok(ordering.reverse()),
+                ordering => return Ok(ordering),
+            },
+            (a, b) => return Ok(a.is_some().cmp(&b.is_some())),
+        }
+    }
+}
+
+/// The largest payload an index page can hold without spilling onto overflow pages.
+fn max_local_payload(usable_size: usize) -> usize {
+    (usable_size - 12) * 64 / 255 - 23
+}
+
+/// The number of bytes a cell in a leaf page takes up, including its cell pointer.
+///
+/// Cells in internal pages also have a 4-byte child pointer.
+fn leaf_cell_size(entry: &Entry) -> usize {
+    let overflow_pointer_size = if entry.overflow.is_some() { 4 } else { 0 };
+    varint_len(entry.record.len() as i64)
+        + entry.local().len()
+        + overflow_pointer_size
+        + CELL_POINTER_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::record::{OwnedValue, Value};
+
+    fn open_empty_index() -> (Pager<Cursor<Vec<u8>>>, usize) {
+        let contents = std::fs::read("test-data/minimal-test.sqlite").unwrap();
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        let root_page = pager.allocate_page().unwrap();
+        Node::Leaf(Vec::new()).write(&mut pager, root_page).unwrap();
+        (pager, root_page)
+    }
+
+    /// An entry with a mix of value types, so they sort in an interesting order.
+    fn entry(n: i64, rowid: i64) -> Vec<u8> {
+        let value: OwnedValue = match n % 3 {
+            0 => Value::int(n),
+            1 => Value::F64(n as f64 + 0.5),
+            _ => Value::String(format!("{n:0>40}").into_bytes().into_boxed_slice()),
+        };
+        Record::build(&[value, Value::int(rowid)])
+    }
+
+    /// Find how many levels down each leaf is, in order.
+    fn leaf_depths(pager: &mut Pager<Cursor<Vec<u8>>>, page_num: usize) -> Vec<usize> {
+        match Node::read(pager, page_num).expect("Failed to read node") {
+            Node::Leaf(_) => vec![0],
+            Node::Internal { cells, rightmost } => cells
+                .into_iter()
+                .map(|(child, _)| child)
+                .chain([rightmost])
+                .flat_map(|child| leaf_depths(pager, child as usize))
+                .map(|depth| depth + 1)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_insert_and_probe() {
+        let (mut pager, root_page) = open_empty_index();
+
+        let keys = (0..3000).map(|n| (n * 7919) % 3000).collect::<Vec<_>>();
+        for &n in &keys {
+            insert(&mut pager, root_page, &entry(n, n + 1), &[]).expect("Failed to insert");
+        }
+        assert!(pager.page_count() > 20, "The index should have been split");
+        for n in [0, 1, 2, 1500, 2999] {
+            assert_eq!(
+                find_key(&mut pager, root_page, &entry(n, 0), 1, &[]).unwrap(),
+                Some(entry(n, n + 1)),
+            );
+            assert_eq!(
+                find_key(&mut pager, root_page, &entry(n, 0), 2, &[]).unwrap(),
+                None
+            );
+        }
+        assert_eq!(
+            find_key(&mut pager, root_page, &entry(3001, 0), 1, &[]).unwrap(),
+            None
+        );
+
+        // Read the entries back in order, and check they're sorted.
+        let entries = entries(&mut pager, root_page).unwrap();
+        assert_eq!(entries.len(), keys.len());
+        assert!(entries.windows(2).all(|pair| {
+            compare_records(&pair[0], &pair[1], usize::MAX, &[]).unwrap() == Ordering::Less
+        }));
+    }
+
+    #[test]
+    fn test_overflow_entries() {
+        let (mut pager, root_page) = open_empty_index();
+        let page_count = pager.page_count();
+        // Entries this long spill onto overflow pages, with only the start of them in the cell.
+        let long_entry = |n: i64| {
+            let text = format!("{n:0>5}").repeat(1000);
+            Record::build(&[Value::String(text.as_bytes()), Value::int(n)])
+        };
+        let keys = (0..200).map(|n| (n * 7919) % 200).collect::<Vec<_>>();
+        for &n in &keys {
+            insert(&mut pager, root_page, &long_entry(n), &[]).expect("Failed to insert");
+        }
+        assert!(
+            matches!(Node::read(&mut pager, root_page), Ok(Node::Internal { .. })),
+            "The index should have been split",
+        );
+        assert_eq!(
+            entries(&mut pager, root_page).unwrap(),
+            (0..200).map(long_entry).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            find_key(&mut pager, root_page, &long_entry(123), 1, &[]).unwrap(),
+            Some(long_entry(123)),
+        );
+
+        for &n in keys.iter().filter(|n| *n % 3 != 0) {
+            assert!(delete(&mut pager, root_page, &long_entry(n), &[]).expect("Failed to delete"));
+        }
+        assert_eq!(
+            entries(&mut pager, root_page).unwrap(),
+            (0..200)
+                .filter(|n| n % 3 == 0)
+                .map(long_entry)
+                .collect::<Vec<_>>(),
+        );
+        clear(&mut pager, root_page).expect("Failed to clear");
+        let header = pager.read_page_bytes(1).unwrap();
+        let free_count = u32::from_be_bytes(header[36..40].try_into().unwrap());
+        assert_eq!(
+            free_count as usize,
+            pager.page_count() - page_count,
+            "Every overflow page should be free again",
+        );
+    }
+
+    #[test]
+    fn test_find_all() {
+        let (mut pager, root_page) = open_empty_index();
+        for n in 0..3000 {
+            insert(&mut pager, root_page, &entry(n % 7, n), &[]).expect("Failed to insert");
+        }
+        assert!(pager.page_count() > 20, "The index should have been split");
+        let found = find_all(&mut pager, root_page, &entry(3, 0), 1, &[]).unwrap();
+        assert_eq!(
+            found,
+            (0..3000)
+                .filter(|n| n % 7 == 3)
+                .map(|n| entry(3, n))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            find_all(&mut pager, root_page, &entry(7, 0), 1, &[]).unwrap(),
+            Vec::<Vec<u8>>::new(),
+        );
+    }
+
+    #[test]
+    fn test_descending() {
+        let (mut pager, root_page) = open_empty_index();
+        let keys = (0..1000).map(|n| (n * 7919) % 1000).collect::<Vec<_>>();
+        for &n in &keys {
+            insert(&mut pager, root_page, &entry(n, n + 1), &[true]).expect("Failed to insert");
+        }
+        // Two entries with equal values still sort by ascending rowid.
+        insert(&mut pager, root_page, &entry(3, 0), &[true]).expect("Failed to insert");
+        assert_eq!(
+            find_key(&mut pager, root_page, &entry(500, 0), 1, &[true]).unwrap(),
+            Some(entry(500, 501)),
+        );
+
+        let mut expected = keys
+            .iter()
+            .map(|&n| entry(n, n + 1))
+            .chain([entry(3, 0)])
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| compare_records(a, b, usize::MAX, &[]).unwrap());
+        expected.sort_by(|a, b| compare_records(b, a, 1, &[]).unwrap());
+        assert_eq!(entries(&mut pager, root_page).unwrap(), expected);
+
+        for &n in &keys {
+            assert!(delete(&mut pager, root_page, &entry(n, n + 1), &[true]).unwrap());
+        }
+        assert_eq!(entries(&mut pager, root_page).unwrap(), [entry(3, 0)]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let (mut pager, root_page) = open_empty_index();
+        let page_count = pager.page_count();
+        let keys = (0..3000).map(|n| (n * 7919) % 3000).collect::<Vec<_>>();
+        for &n in &keys {
+            insert(&mut pager, root_page, &entry(n, n + 1), &[]).expect("Failed to insert");
+        }
+        // Deleting in a scattered order removes entries from internal pages as well as leaves.
+        for &n in keys.iter().filter(|n| *n % 4 != 0) {
+            assert!(delete(&mut pager, root_page, &entry(n, n + 1), &[]).expect("Failed to delete"));
+        }
+        assert!(
+            !delete(&mut pager, root_page, &entry(1, 2), &[]).unwrap(),
+            "The entry is already gone",
+        );
+        let mut expected = (0..3000)
+            .filter(|n| n % 4 == 0)
+            .map(|n| entry(n, n + 1))
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| compare_records(a, b, usize::MAX, &[]).unwrap());
+        assert_eq!(entries(&mut pager, root_page).unwrap(), expected);
+
+        for entry in &expected {
+            assert!(delete(&mut pager, root_page, entry, &[]).expect("Failed to delete"));
+        }
+        assert_eq!(
+            Node::read(&mut pager, root_page).unwrap(),
+            Node::Leaf(Vec::new())
+        );
+        let header = pager.read_page_bytes(1).unwrap();
+        let free_count = u32::from_be_bytes(header[36..40].try_into().unwrap());
+        assert_eq!(free_count as usize, pager.page_count() - page_count);
+
+        // With a dozen entries to a page, the index is several levels deep, and deleting entries
+        // empties whole pages and leaves internal pages with a single child.
+        let long_entry =
+            |n: i64| Record::build(&[Value::String(format!("{n:0>300}")), Value::int(n)]);
+        for n in &keys {
+            insert(&mut pager, root_page, &long_entry(*n), &[]).expect("Failed to insert");
+        }
+        assert!(leaf_depths(&mut pager, root_page)[0] >= 3);
+        for n in keys.iter().filter(|n| *n % 3 != 0).chain(&keys[..1000]) {
+            delete(&mut pager, root_page, &long_entry(*n), &[]).expect("Failed to delete");
+            let depths = leaf_depths(&mut pager, root_page);
+            assert!(
+                depths.iter().all(|depth| *depth == depths[0]),
+                "Every leaf should be at the same depth after deleting {n}",
+            );
+        }
+        let mut expected = keys[1000..]
+            .iter()
+            .filter(|n| *n % 3 == 0)
+            .map(|n| long_entry(*n))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(entries(&mut pager, root_page).unwrap(), expected);
+    }
+}
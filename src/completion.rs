@@ -0,0 +1,172 @@
+//! Completing the names of tables, columns, keywords and commands as they're typed in the shell
+//!
+//! The names are taken from the schema when [`Completions`] is built, so it has to be built again
+//! after the schema changes.
+
+use crate::schema::Schema;
+
+/// The words which can be completed, and what's completed where.
+#[derive(Clone, Debug, Default)]
+pub struct Completions {
+    /// The tables and views, each with the names of its columns
+    tables: Vec<(String, Vec<String>)>,
+    /// The names of all the columns, in order, without repeats
+    columns: Vec<String>,
+    /// The shell's commands, without the `.` they start with
+    commands: Vec<String>,
+}
+
+impl Completions {
+    /// Build the completions for the tables and views in `schema`, and the shell's `commands`,
+    /// given without the `.` they start with.
+    #[must_use]
+    pub fn new(schema: &Schema, commands: &[&str]) -> Self {
+        let tables = schema
+            .tables
+            .iter()
+            .map(|table| {
+                let columns = table.columns.iter().map(|column| column.name.clone());
+                (table.name.clone(), columns.collect())
+            })
+            .chain(
+                schema
+                    .views
+                    .iter()
+                    .map(|view| (view.name.clone(), view.columns.clone())),
+            )
+            .collect::<Vec<(String, Vec<String>)>>();
+        let mut columns = tables
+            .iter()
+            .flat_map(|(_, columns)| columns.iter().cloned())
+            .collect::<Vec<_>>();
+        columns.sort();
+        columns.dedup();
+        Self {
+            tables,
+            columns,
+            commands: commands.iter().map(|&command| command.to_owned()).collect(),
+        }
+    }
+
+    /// Get the words which could complete the one ending at `pos` in `line`, in order, along with
+    /// the index in `line` where the word starts.
+    ///
+    /// Words are matched ignoring case. After `name.`, only the columns of the table `name` are
+    /// completed. In a shell command's arguments, only tables are completed. Nothing is completed
+    /// for an empty word, except after `name.`.
+    #[must_use]
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = word_start(before);
+        let word = &before[start..];
+
+        let command = before.trim_start().strip_prefix('.');
+        if let Some(command) = command {
+            if !command.contains(char::is_whitespace) {
+                return (start, matching(word, &self.commands));
+            }
+        }
+        // The columns of a table are completed after its name and a `.`.
+        if let Some(table) = before[..start].strip_suffix('.') {
+            let table = &table[word_start(table)..];
+            let columns = self
+                .tables
+                .iter()
+                .filter(|(name, _)| !table.is_empty() && name.eq_ignore_ascii_case(table))
+                .flat_map(|(_, columns)| columns);
+            return (start, matching(word, columns));
+        }
+        if word.is_empty() {
+            return (start, Vec::new());
+        }
+        let tables = self.tables.iter().map(|(name, _)| name);
+        if command.is_some() {
+            return (start, matching(word, tables));
+        }
+        let mut words = matching(word, tables.chain(&self.columns));
+        // Keywords are written in the case the word was started in.
+        let lowercase = !word.contains(|c: char| c.is_ascii_uppercase());
+        let keywords = sqlparser::keywords::ALL_KEYWORDS
+            .iter()
+            .filter(|keyword| starts_with_ignore_case(keyword, word))
+            .map(|keyword| {
+                if lowercase {
+                    keyword.to_ascii_lowercase()
+                } else {
+                    (*keyword).to_owned()
+                }
+            })
+            .filter(|keyword| !words.iter().any(|word| word.eq_ignore_ascii_case(keyword)))
+            .collect::<Vec<_>>();
+        words.extend(keywords);
+        (start, words)
+    }
+}
+
+/// Get the names in `candidates` which start with `word`, ignoring case, sorted and without
+/// repeats.
+fn matching<'a>(word: &str, candidates: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut matches = candidates
+        .into_iter()
+        .filter(|candidate| starts_with_ignore_case(candidate, word))
+        .cloned()
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+/// Get the index in `text` of the start of the name it ends with, which is `text.len()` if it
+/// doesn't end with one.
+fn word_start(text: &str) -> usize {
+    text.char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_alphanumeric() || c == '_')
+        .last()
+        .map_or(text.len(), |(idx, _)| idx)
+}
+
+/// Whether `text` starts with `prefix`, ignoring ASCII case.
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::Completions;
+    use crate::Database;
+
+    #[test]
+    fn test_complete() {
+        let db = Database::new(
+            File::open("test-data/constraints.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let completions = Completions::new(db.schema(), &["schema", "select", "tables"]);
+        let complete = |line: &str| {
+            let (start, words) = completions.complete(line, line.len());
+            (start, words.join(" "))
+        };
+
+        assert_eq!(
+            complete("SELECT Na"),
+            (7, "name NANOSECOND NANOSECONDS NATIONAL NATURAL".to_owned())
+        );
+        assert_eq!(
+            complete("select * from ta"),
+            (14, "tags table tables tablesample tag target".to_owned())
+        );
+        assert_eq!(
+            complete("SELECT USERS."),
+            (13, "email id name team".to_owned())
+        );
+        assert_eq!(complete("select users.E"), (13, "email".to_owned()));
+        assert_eq!(complete("select u.e"), (9, String::new()));
+        assert_eq!(complete("  .s"), (3, "schema select".to_owned()));
+        assert_eq!(complete(".schema U"), (8, "users".to_owned()));
+        assert_eq!(complete("select "), (7, String::new()));
+    }
+}
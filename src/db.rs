@@ -1,152 +1,1080 @@
 //! Database implementation
 
-use std::fs::File;
+mod analyze;
+mod backup;
+mod delete;
+mod dump;
+mod insert;
+mod integrity;
+#[cfg(not(target_arch = "wasm32"))]
+mod open;
+mod plan;
+mod pragma;
+mod recover;
+mod returning;
+mod select;
+mod sequence;
+mod update;
+mod vacuum;
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
 
-use crate::{pager::Pager, record::OwnedValue, table_iter::TableIter};
+use crate::{
+    blob::Blob,
+    pager::{
+        AutoVacuum, Checkpoint, CheckpointMode, DatabaseHeader, JournalMode, LockLevel,
+        PageAccessMap, PageCodec, Pager, PagerStats, Storage, Vfs, VfsFile, Wal,
+    },
+    record::{FromRow, OwnedValue},
+    rows::{ColumnInfo, Rows},
+    schema::{IndexSchema, ObjectKind, Schema, SchemaObject, SchemaWarning, TableSchema},
+    script::{split_script, ScriptLine, ScriptPart},
+    table::Table,
+    table_iter::TableIter,
+    Error,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use open::OpenOptions;
+use plan::Stats;
+
+/// The size of the pages in new databases, which is the same as SQLite's default.
+const DEFAULT_PAGE_SIZE: usize = 4096;
 
-/// A SQLite database
-pub struct Database {
+/// How many frames the WAL can hold before commits checkpoint it, which is the same as SQLite's
+/// default.
+const WAL_AUTOCHECKPOINT_FRAMES: usize = 1000;
+
+/// A SQLite database, stored in a file unless another [`Storage`] is given.
+pub struct Database<File = std::fs::File> {
     /// Paging on the file
     pub(crate) pager: Pager<File>,
+    /// The kind of transaction that is open.
+    ///
+    /// While one is open, writes are held in the pager until it is committed, instead of being
+    /// flushed at the end of each statement.
+    transaction: TransactionState,
+    /// The names of the open savepoints, innermost last.
+    ///
+    /// These line up with the savepoints in the pager.
+    savepoints: Vec<String>,
+    /// The contents of `sqlite_schema`, parsed when the database was opened.
+    schema: Schema,
+    /// The schema cookie from the database header as of when [`Self::schema`] was read.
+    ///
+    /// Every change to the schema changes the cookie, so the schema only has to be read again
+    /// when another connection has changed it.
+    schema_cookie: u32,
+    /// The statistics from `sqlite_stat1`, read along with [`Self::schema`].
+    stats: Stats,
+    /// How many rows have been read from tables since the current statement started.
+    pub(crate) rows_examined: u64,
+    /// Set to stop the statement being run, as by [`InterruptHandle::interrupt`].
+    interrupt: InterruptHandle,
+}
+
+/// The kind of transaction open on a [`Database`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TransactionState {
+    /// No transaction is open, so each statement commits its own writes.
+    Autocommit,
+    /// A transaction opened by `BEGIN`, which lasts until `COMMIT` or `ROLLBACK`.
+    Explicit,
+    /// A transaction opened by a `SAVEPOINT` outside of any transaction, which also commits when
+    /// that savepoint is released.
+    Savepoint,
 }
 
+// There's no filesystem on wasm32 in a browser, so databases there are opened over some other
+// `Storage`, like an `OpfsFile`.
+#[cfg(not(target_arch = "wasm32"))]
 impl Database {
-    pub fn new(file: File) -> Result<Self> {
+    /// Create a new, empty database at `path`, which is opened with its writes protected by a
+    /// rollback journal.
+    ///
+    /// This fails if there is already a file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> crate::Result<Self> {
+        use std::io::Seek;
+
+        use crate::pager::journal_path_for;
+
+        let path = path.as_ref();
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Pager::create(&file, DEFAULT_PAGE_SIZE).context("Failed to create database")?;
+        file.rewind().context("Error seeking in database")?;
+        Self::with_journal(file, journal_path_for(path))
+    }
+}
+
+impl Database<VfsFile> {
+    /// Open the database at `path` through `vfs`, which its rollback journal and WAL are also
+    /// kept in.
+    ///
+    /// See [`Pager::open_with_vfs`].
+    pub fn open_with_vfs(vfs: Arc<dyn Vfs>, path: impl AsRef<Path>) -> crate::Result<Self> {
+        let pager = Pager::open_with_vfs(vfs, path.as_ref()).context("Failed to parse file")?;
+        Self::from_pager(pager)
+    }
+}
+
+impl Database<Cursor<Vec<u8>>> {
+    /// Create a new, empty database held in memory, like SQLite's `:memory:` databases.
+    ///
+    /// Nothing is kept once it's dropped, unless its contents are taken with [`Self::into_bytes`].
+    pub fn open_in_memory() -> crate::Result<Self> {
+        let pager = Pager::create(Cursor::new(Vec::new()), DEFAULT_PAGE_SIZE)
+            .context("Failed to create database")?;
+        Self::from_pager(pager)
+    }
+
+    /// Open a database held in memory, like one embedded in the program, from a copy of `bytes`.
+    ///
+    /// Writes change the copy, which [`Self::into_bytes`] gives back.
+    pub fn from_bytes(bytes: impl Into<Vec<u8>>) -> crate::Result<Self> {
+        Self::new(Cursor::new(bytes.into()))
+    }
+
+    /// Get the contents of a database held in memory, as of the last commit.
+    #[must_use]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.pager.into_inner().into_inner()
+    }
+}
+
+impl<File: Storage> Database<File> {
+    pub fn new(file: File) -> crate::Result<Self> {
         let pager = Pager::new(file).context("Failed to parse file")?;
-        Ok(Self { pager })
+        Self::from_pager(pager)
+    }
+
+    /// Open a database whose writes are protected by a rollback journal at `journal_path`.
+    ///
+    /// Use [`pager::journal_path_for`](crate::pager::journal_path_for) to get the journal path
+    /// SQLite would use for a database file.
+    pub fn with_journal(file: File, journal_path: PathBuf) -> crate::Result<Self> {
+        let pager = Pager::with_journal(file, journal_path).context("Failed to parse file")?;
+        Self::from_pager(pager)
+    }
+
+    /// Open a database whose pages are stored encoded with `codec`, like an encrypted database,
+    /// whose writes are protected by a rollback journal at `journal_path` if it's given.
+    ///
+    /// See [`Pager::with_codec`].
+    pub fn with_codec(
+        file: File,
+        journal_path: Option<PathBuf>,
+        codec: Arc<dyn PageCodec>,
+    ) -> crate::Result<Self> {
+        let pager = Pager::with_codec(file, journal_path, codec).context("Failed to parse file")?;
+        Self::from_pager(pager)
+    }
+
+    /// Open a read-only view of the database as it was after an earlier commit, which is still
+    /// in its WAL.
+    ///
+    /// The view is of the last commit in `wal` which ends at or before `frame`, so pass the
+    /// [`frame`](crate::pager::WalCommit::frame) of one of [`Wal::commits`] to see the database
+    /// right after that commit, or 0 to see it as it was before all of them.
+    pub fn with_wal_snapshot(file: File, wal: &mut Wal<File>, frame: usize) -> crate::Result<Self> {
+        let pager = Pager::with_wal_snapshot(file, wal, frame).context("Failed to parse file")?;
+        Self::from_pager(pager)
+    }
+
+    /// Open the database read by `pager`, reading its schema.
+    fn from_pager(pager: Pager<File>) -> crate::Result<Self> {
+        let mut db = Self {
+            pager,
+            transaction: TransactionState::Autocommit,
+            savepoints: Vec::new(),
+            schema: Schema::default(),
+            schema_cookie: 0,
+            stats: Stats::default(),
+            rows_examined: 0,
+            interrupt: InterruptHandle::default(),
+        };
+        db.with_lock(Self::read_schema)?;
+        Ok(db)
+    }
+
+    /// Parse the contents of `sqlite_schema` into [`Self::schema`].
+    fn read_schema(&mut self) -> Result<()> {
+        self.schema_cookie = self.pager.header()?.schema_cookie;
+        // `sqlite_schema` is always rooted at the first page
+        let rows = TableIter::from_root_page(self, 1, None).collect::<Vec<_>>();
+        self.schema = Schema::parse(rows);
+        self.stats = self.read_stats()?;
+        Ok(())
+    }
+
+    /// Get everything defined in the database's schema.
+    #[must_use]
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Get the problems with objects in the schema which couldn't be parsed.
+    ///
+    /// Those objects are left out of [`Self::schema`], but the rest of the database is usable.
+    #[must_use]
+    pub fn schema_warnings(&self) -> &[SchemaWarning] {
+        &self.schema.warnings
     }
 
     /// Execute the given statement.
     ///
-    /// For each returned value, `callback` is called.
+    /// For each returned value, `callback` is called. Once the statement has finished, the
+    /// resources it used are returned.
     pub fn execute_statement(
         &mut self,
         statement: &sqlparser::ast::Statement,
-        mut callback: impl FnMut(Vec<OwnedValue>) -> Result<()>,
+        mut callback: impl FnMut(Vec<OwnedValue>) -> crate::Result<()>,
+    ) -> crate::Result<QueryStats> {
+        let start = Instant::now();
+        self.pager.reset_page_accesses();
+        self.rows_examined = 0;
+        self.clear_interrupt();
+        let mut rows_returned = 0;
+        let mut callback = |row| {
+            rows_returned += 1;
+            Ok(callback(row)?)
+        };
+        self.with_lock(|db| db.run_statement(statement, &mut callback))?;
+        // Scans stop early when interrupted, so the rows returned may not be all of them.
+        self.check_interrupt()?;
+        let page_reads = self.pager.page_accesses().values().sum::<u64>();
+        Ok(QueryStats {
+            rows_examined: self.rows_examined,
+            rows_returned,
+            pages_read: self.pager.page_loads(),
+            // Pages read ahead of a scan which stopped early are loaded without being read.
+            cache_hits: page_reads.saturating_sub(self.pager.page_loads()),
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Run every statement in `sql` in order, as in a migration or the output of [`Self::dump`],
+    /// stopping at the first one which fails.
+    ///
+    /// The rows statements return are discarded. `VACUUM` and `ANALYZE` are run with
+    /// [`Self::vacuum`] and [`Self::analyze`], since `sqlparser` can't parse them. The error from
+    /// a statement which fails says which one it was and the line it starts on. The statements
+    /// before it aren't undone, and a transaction they opened is left open, as in SQLite's
+    /// `sqlite3_exec`.
+    pub fn execute_batch(&mut self, sql: &str) -> crate::Result<()> {
+        for (idx, ScriptLine { line, part }) in split_script(sql).into_iter().enumerate() {
+            let result = match part {
+                ScriptPart::Sql(sql) => self.execute_batch_statement(sql),
+                ScriptPart::DotCommand(command) => Err(anyhow::anyhow!(Error::Syntax(format!(
+                    "Shell commands can't be run as SQL: .{command}"
+                )))),
+            };
+            result.with_context(|| format!("Error in statement {} near line {line}", idx + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Run one of the statements given to [`Self::execute_batch`].
+    fn execute_batch_statement(&mut self, sql: &str) -> Result<()> {
+        let command = sql.trim().trim_end_matches(';').trim_end();
+        if command.eq_ignore_ascii_case("vacuum") {
+            return Ok(self.vacuum()?);
+        }
+        if command.eq_ignore_ascii_case("analyze") {
+            return Ok(self.analyze()?);
+        }
+        for statement in
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)?
+        {
+            self.execute_statement(&statement, |_| Ok(()))?;
+        }
+        Ok(())
+    }
+
+    /// Run `sql`, a single statement, returning the rows it returns.
+    ///
+    /// The statement is run to completion before this returns, so that it can't be left
+    /// partway through its changes, and every row it returns is held in memory until it's read.
+    /// Each row has the names of its columns and where they come from, as given by
+    /// [`Self::statement_column_info`], so its values can be read by name.
+    pub fn query(&mut self, sql: &str) -> crate::Result<Rows> {
+        let mut statements =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .map_err(anyhow::Error::from)?;
+        if statements.len() != 1 {
+            return Err(Error::Other(format!(
+                "Expected one statement, got {}",
+                statements.len()
+            )));
+        }
+        let statement = statements.remove(0);
+        let mut rows = Vec::new();
+        self.execute_statement(&statement, |row| {
+            rows.push(row);
+            Ok(())
+        })?;
+        let columns = self.statement_column_info(&statement)?;
+        Ok(Rows::new(columns, rows))
+    }
+
+    /// Run `sql`, a single statement, and build a `T` from each row it returns.
+    pub fn query_rows<T: FromRow>(&mut self, sql: &str) -> crate::Result<Vec<T>> {
+        self.query(sql)?
+            .enumerate()
+            .map(|(idx, row)| {
+                Ok(row?
+                    .get_row()
+                    .with_context(|| format!("Failed to read row {idx}"))?)
+            })
+            .collect()
+    }
+
+    /// Run `sql`, a single statement, and build a `T` from the first row it returns.
+    ///
+    /// This fails if it returns no rows. See [`Self::query_rows`].
+    pub fn query_row<T: FromRow>(&mut self, sql: &str) -> crate::Result<T> {
+        let row = self
+            .query(sql)?
+            .next()
+            .ok_or_else(|| Error::NotFound("The statement returned no rows".to_owned()))??;
+        Ok(row.get_row().context("Failed to read row")?)
+    }
+
+    /// Run `sql`, a single statement, and deserialize each row it returns into a `T`.
+    ///
+    /// Rows are read into structs and maps by the names of their columns, as given by
+    /// [`Self::statement_columns`]. See [`de`](crate::de) for how rows are deserialized.
+    #[cfg(feature = "serde")]
+    pub fn query_as<T: serde::de::DeserializeOwned>(&mut self, sql: &str) -> crate::Result<Vec<T>> {
+        self.query(sql)?
+            .enumerate()
+            .map(|(idx, row)| {
+                let row = row?;
+                crate::de::from_row(row.column_names(), row.values())
+                    .map_err(|e| Error::Mismatch(format!("Failed to deserialize row {idx}: {e}")))
+            })
+            .collect()
+    }
+
+    /// Run `sql`, a single statement, and deserialize the first row it returns into a `T`.
+    ///
+    /// This fails if it returns no rows. See [`Self::query_as`].
+    #[cfg(feature = "serde")]
+    pub fn query_row_as<T: serde::de::DeserializeOwned>(&mut self, sql: &str) -> crate::Result<T> {
+        let row = self
+            .query(sql)?
+            .next()
+            .ok_or_else(|| Error::NotFound("The statement returned no rows".to_owned()))??;
+        crate::de::from_row(row.column_names(), row.values())
+            .map_err(|e| Error::Mismatch(format!("Failed to deserialize row: {e}")))
+    }
+
+    /// Get the names of the columns in the rows `statement` returns, in the same way as SQLite
+    /// names them.
+    ///
+    /// This is empty for statements which don't return rows.
+    pub fn statement_columns(
+        &self,
+        statement: &sqlparser::ast::Statement,
+    ) -> crate::Result<Vec<String>> {
+        Ok(self
+            .statement_column_info(statement)?
+            .into_iter()
+            .map(|column| column.name)
+            .collect())
+    }
+
+    /// Get the names of the columns in the rows `statement` returns, along with the declared
+    /// type and table of those which are read straight from a column of a table.
+    ///
+    /// This is empty for statements which don't return rows.
+    pub fn statement_column_info(
+        &self,
+        statement: &sqlparser::ast::Statement,
+    ) -> crate::Result<Vec<ColumnInfo>> {
+        use sqlparser::ast::{FromTable, Statement};
+
+        let (table_name, returning) = match statement {
+            Statement::Query(query) => return Ok(self.query_columns(query, &mut Vec::new())?),
+            Statement::Pragma { name, .. } => {
+                return Ok(pragma::pragma_columns(name)
+                    .into_iter()
+                    .map(ColumnInfo::named)
+                    .collect())
+            }
+            Statement::Insert(insert) => (
+                insert
+                    .table_name
+                    .0
+                    .first()
+                    .take_if(|_| insert.table_name.0.len() == 1)
+                    .map(|name| name.value.as_str()),
+                insert.returning.as_ref(),
+            ),
+            Statement::Update {
+                table, returning, ..
+            } => (plain_table_name(table), returning.as_ref()),
+            Statement::Delete(delete) => {
+                let (FromTable::WithFromKeyword(from) | FromTable::WithoutKeyword(from)) =
+                    &delete.from;
+                (
+                    from.first()
+                        .take_if(|_| from.len() == 1)
+                        .and_then(plain_table_name),
+                    delete.returning.as_ref(),
+                )
+            }
+            _ => return Ok(Vec::new()),
+        };
+        let (Some(table_name), Some(_)) = (table_name, returning) else {
+            return Ok(Vec::new());
+        };
+        let schema = self.table_schema(table_name)?;
+        Ok(returning::Returning::resolve(&schema, returning)?
+            .map_or_else(Vec::new, |returning| returning.columns(&schema)))
+    }
+
+    /// Execute the given statement, calling `callback` with each returned value.
+    fn run_statement(
+        &mut self,
+        statement: &sqlparser::ast::Statement,
+        callback: &mut impl FnMut(Vec<OwnedValue>) -> Result<()>,
     ) -> Result<()> {
         match statement {
             sqlparser::ast::Statement::Query(query) => {
-                match query.body.as_ref() {
-                    sqlparser::ast::SetExpr::Select(select) => {
-                        // TODO Loosen these restrictions as I implement more of it.
-                        let sqlparser::ast::Select {
-                            distinct: None,
-                            top: None,
-                            projection,
-                            into: None,
-                            from,
-                            lateral_views,
-                            prewhere: None,
-                            selection: None,
-                            group_by: _, // TODO figure out this field
-                            cluster_by,
-                            distribute_by,
-                            sort_by,
-                            having: None,
-                            named_window,
-                            qualify: None,
-                            window_before_qualify: _,
-                            value_table_mode: None,
-                            connect_by: None,
-                        } = select.as_ref()
-                        else {
-                            anyhow::bail!("Unimplemented SELECT arguments");
-                        };
-                        if !(projection.len() == 1
-                            && lateral_views.is_empty()
-                            && cluster_by.is_empty()
-                            && distribute_by.is_empty()
-                            && sort_by.is_empty()
-                            && named_window.is_empty())
-                        {
-                            anyhow::bail!("Unimplemented SELECT arguments 2");
-                        }
-                        let sqlparser::ast::SelectItem::Wildcard(
-                            sqlparser::ast::WildcardAdditionalOptions {
-                                opt_ilike: None,
-                                opt_except: None,
-                                opt_rename: None,
-                                opt_exclude: None,
-                                opt_replace: None,
-                            },
-                        ) = projection[0]
-                        else {
-                            anyhow::bail!("Unimplemented projection");
-                        };
-                        let Some(sqlparser::ast::TableWithJoins {
-                            joins,
-                            relation:
-                                sqlparser::ast::TableFactor::Table {
-                                    name: table_name,
-                                    alias: None,
-                                    args: None,
-                                    with_hints,
-                                    version: None,
-                                    with_ordinality: false,
-                                    partitions,
-                                },
-                        }) = from.first().take_if(|_| from.len() == 1)
-                        else {
-                            anyhow::bail!("Unimplemented FROM target");
-                        };
-                        if !(joins.is_empty() && with_hints.is_empty() && partitions.is_empty()) {
-                            anyhow::bail!("Unimplemented FROM target");
-                        }
-                        let Some(table_name) =
-                            table_name.0.first().take_if(|_| table_name.0.len() == 1)
-                        else {
-                            anyhow::bail!("Unimplemented FROM target");
-                        };
-                        let table_name = &table_name.value;
-                        for row in TableIter::new(self, table_name)? {
-                            callback(row)?;
-                        }
-                    }
-                    _ => anyhow::bail!("Unimplemented command"),
+                self.execute_query(query, &mut Vec::new(), callback)?;
+            }
+            sqlparser::ast::Statement::Insert(insert) => {
+                for row in self.write_statement(|db| db.execute_insert(insert))? {
+                    callback(row)?;
                 }
             }
-            _ => anyhow::bail!("Unimplemented command"),
+            sqlparser::ast::Statement::Update {
+                table,
+                assignments,
+                from: None,
+                selection,
+                returning,
+            } => {
+                let rows = self.write_statement(|db| {
+                    db.execute_update(table, assignments, selection.as_ref(), returning.as_ref())
+                })?;
+                for row in rows {
+                    callback(row)?;
+                }
+            }
+            sqlparser::ast::Statement::Delete(delete) => {
+                for row in self.write_statement(|db| db.execute_delete(delete))? {
+                    callback(row)?;
+                }
+            }
+            sqlparser::ast::Statement::StartTransaction { .. } => {
+                anyhow::ensure!(
+                    !self.in_transaction(),
+                    Error::Sql("cannot start a transaction within a transaction".to_owned())
+                );
+                self.transaction = TransactionState::Explicit;
+            }
+            sqlparser::ast::Statement::Commit { chain: false } => {
+                anyhow::ensure!(
+                    self.in_transaction(),
+                    Error::Sql("cannot commit - no transaction is active".to_owned())
+                );
+                self.commit()?;
+            }
+            sqlparser::ast::Statement::Rollback {
+                chain: false,
+                savepoint: None,
+            } => {
+                anyhow::ensure!(
+                    self.in_transaction(),
+                    Error::Sql("cannot rollback - no transaction is active".to_owned())
+                );
+                self.pager.rollback();
+                self.savepoints.clear();
+                self.transaction = TransactionState::Autocommit;
+            }
+            sqlparser::ast::Statement::Savepoint { name } => {
+                if !self.in_transaction() {
+                    self.transaction = TransactionState::Savepoint;
+                }
+                self.pager.open_savepoint();
+                self.savepoints.push(name.value.clone());
+            }
+            sqlparser::ast::Statement::Rollback {
+                chain: false,
+                savepoint: Some(name),
+            } => {
+                let depth = self.find_savepoint(&name.value)?;
+                self.pager.rollback_to_savepoint(depth)?;
+                self.savepoints.truncate(depth + 1);
+            }
+            sqlparser::ast::Statement::ReleaseSavepoint { name } => {
+                let depth = self.find_savepoint(&name.value)?;
+                if depth == 0 && self.transaction == TransactionState::Savepoint {
+                    self.commit()?;
+                } else {
+                    self.pager.release_savepoint(depth)?;
+                    self.savepoints.truncate(depth);
+                }
+            }
+            sqlparser::ast::Statement::Pragma { name, value, .. } => {
+                self.execute_pragma(name, value.as_ref(), callback)?;
+            }
+            _ => anyhow::bail!(Error::UnsupportedSql("Unimplemented command".to_owned())),
         }
         Ok(())
     }
 
-    pub fn table_names(&mut self) -> Result<impl Iterator<Item = String> + '_> {
-        Ok(self
-            .table_root_page_indices_by_name()?
-            .map(|(name, _)| name))
+    /// Get how many times each page was read by the most recently executed statement.
+    #[must_use]
+    pub fn page_accesses(&self) -> &PageAccessMap {
+        self.pager.page_accesses()
     }
 
-    pub(crate) fn table_root_page_indices_by_name(
+    /// Get a handle which stops the statement being run when it's interrupted.
+    #[must_use]
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Make `handle` interrupt this database's statements, instead of its own handle, so one
+    /// handle can interrupt several databases.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Forget any interrupt from before the statement about to be run started, since interrupts
+    /// only stop statements which are running when they happen.
+    pub(crate) fn clear_interrupt(&self) {
+        self.interrupt.flag.store(false, Ordering::Relaxed);
+    }
+
+    /// Fail if the statement being run has been interrupted.
+    pub(crate) fn check_interrupt(&self) -> Result<()> {
+        anyhow::ensure!(
+            !self.interrupt.flag.load(Ordering::Relaxed),
+            Error::Interrupted
+        );
+        Ok(())
+    }
+
+    /// Get counts of what the pager has done since the database was opened, like how often pages
+    /// were found in the cache.
+    #[must_use]
+    pub fn pager_stats(&self) -> PagerStats {
+        self.pager.stats()
+    }
+
+    /// Set whether to check pages against checksums of their contents when they're read again,
+    /// to catch the database file being changed by something other than SQLite.
+    ///
+    /// See [`Pager::set_verify_checksums`].
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.pager.set_verify_checksums(verify);
+    }
+
+    /// Get the number of pages in the database.
+    pub fn page_count(&mut self) -> usize {
+        self.pager.page_count()
+    }
+
+    /// Read the database header, for tools which inspect the file.
+    pub fn header(&mut self) -> crate::Result<DatabaseHeader> {
+        self.with_lock(|db| db.pager.header())
+    }
+
+    /// Whether an explicit transaction is currently open.
+    #[must_use]
+    pub fn in_transaction(&self) -> bool {
+        self.transaction != TransactionState::Autocommit
+    }
+
+    /// Run `f` while holding at least a SHARED lock on the database file, so other connections
+    /// can't change it in the meantime.
+    ///
+    /// If another connection changed the schema since the file was last locked, or put it in WAL
+    /// mode, the schema is read again first. Outside of a transaction, the lock is released
+    /// afterwards.
+    ///
+    /// `f` can fail with either an [`anyhow::Error`] or an [`Error`], so that it's as easy
+    /// to use from the public API as from inside the crate.
+    fn with_lock<T, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<T, E>
+    where
+        E: From<anyhow::Error> + From<Error>,
+    {
+        if self.pager.lock_level() == LockLevel::None {
+            let journal_mode = self.pager.journal_mode();
+            self.pager.lock(LockLevel::Shared)?;
+            if let Err(e) = self.reload_schema_if_changed(journal_mode) {
+                self.pager.unlock(LockLevel::None)?;
+                return Err(e.into());
+            }
+        }
+        let result = f(self);
+        if !self.in_transaction() {
+            let unlocked = self.pager.unlock(LockLevel::None).map_err(E::from);
+            return result.and_then(|output| unlocked.map(|()| output));
+        }
+        result
+    }
+
+    /// Read the schema again if another connection changed it, given the journal mode from before
+    /// the file was locked.
+    ///
+    /// Other changes to the file only discard the cached pages, since the parsed schema is still
+    /// accurate as long as the schema cookie is the same.
+    fn reload_schema_if_changed(&mut self, journal_mode: JournalMode) -> Result<()> {
+        let changed = self.pager.reload_if_changed()?;
+        if self.pager.journal_mode() != journal_mode
+            || (changed && self.pager.header()?.schema_cookie != self.schema_cookie)
+        {
+            self.read_schema()?;
+        }
+        Ok(())
+    }
+
+    /// Run a statement which writes to the database.
+    ///
+    /// Either all or none of the statement's writes take effect. Outside of an explicit
+    /// transaction, they are also committed once the statement finishes.
+    fn write_statement<T>(&mut self, statement: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        // Checked up front, since a read-only file can't be locked for writing either.
+        anyhow::ensure!(
+            !self.pager.read_only(),
+            Error::ReadOnly("attempt to write a readonly database".to_owned())
+        );
+        self.pager.lock(LockLevel::Reserved)?;
+        let depth = self.pager.open_savepoint();
+        // A statement which was interrupted may have stopped partway through its changes, so
+        // they're undone.
+        let result = statement(self).and_then(|output| self.check_interrupt().map(|()| output));
+        if result.is_err() {
+            self.pager.rollback_to_savepoint(depth)?;
+        }
+        self.pager.release_savepoint(depth)?;
+        let output = result?;
+        if !self.in_transaction() {
+            if let Err(e) = self.flush() {
+                self.pager.rollback();
+                return Err(e.context("Failed to commit statement"));
+            }
+        }
+        Ok(output)
+    }
+
+    /// Write all changes back to the file.
+    ///
+    /// Committing can remove free pages from the end of auto-vacuum databases, in which case the
+    /// file is shrunk to match. In WAL mode, the WAL is checkpointed once it grows large.
+    fn flush(&mut self) -> Result<()> {
+        // Only connections which have made changes hold a RESERVED lock.
+        if self.pager.lock_level() >= LockLevel::Reserved {
+            self.pager.lock(LockLevel::Exclusive)?;
+        }
+        self.pager.flush()?;
+        if self.pager.auto_vacuum() != AutoVacuum::None {
+            self.pager.truncate_file()?;
+        }
+        if self.pager.wal_frame_count() >= WAL_AUTOCHECKPOINT_FRAMES {
+            self.pager.checkpoint(CheckpointMode::Passive)?;
+        }
+        Ok(())
+    }
+
+    /// Switch between protecting commits with a rollback journal and with the WAL, as
+    /// `PRAGMA journal_mode` does.
+    ///
+    /// See [`Pager::set_journal_mode`] for where the WAL is kept.
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> crate::Result<()> {
+        if self.in_transaction() {
+            return Err(Error::Sql(
+                "cannot change the journal mode from within a transaction".to_owned(),
+            ));
+        }
+        self.with_lock(|db| db.pager.set_journal_mode(mode))
+    }
+
+    /// Copy the pages in the WAL back into the database file, as `PRAGMA wal_checkpoint` does.
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> crate::Result<Checkpoint> {
+        self.with_lock(|db| db.pager.checkpoint(mode))
+    }
+
+    /// Open a read-only handle on the same database, which can be sent to another thread to run
+    /// queries alongside this one.
+    ///
+    /// See [`Pager::reader`] for how the handles share their page cache.
+    pub fn reader(&mut self) -> crate::Result<Database> {
+        Database::from_pager(self.pager.reader()?)
+    }
+
+    /// Commit the open transaction, closing all savepoints.
+    fn commit(&mut self) -> Result<()> {
+        self.flush().context("Failed to commit transaction")?;
+        self.savepoints.clear();
+        self.transaction = TransactionState::Autocommit;
+        Ok(())
+    }
+
+    /// Find the depth of the innermost open savepoint with the given name.
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.eq_ignore_ascii_case(name))
+            .with_context(|| Error::NotFound(format!("no such savepoint: {name}")))
+    }
+
+    /// Search for tables whose names or column names match `pattern`.
+    ///
+    /// `pattern` is a SQL `LIKE` pattern; if it contains no wildcards, it matches anywhere within
+    /// a name. If `search_sql` is set, the `CREATE TABLE` statements are searched too.
+    ///
+    /// This is meant for finding your way around unfamiliar databases with many tables. Names are
+    /// only matched with `LIKE` patterns, not regular expressions or an n-gram index, and only the
+    /// `main` schema is searched, since attaching other databases isn't supported yet.
+    pub fn find_tables(&self, pattern: &str, search_sql: bool) -> crate::Result<Vec<TableMatch>> {
+        let pattern = if pattern.contains(['%', '_']) {
+            pattern.to_owned()
+        } else {
+            format!("%{pattern}%")
+        };
+        let mut matches = Vec::new();
+        for object in &self.schema.objects {
+            if object.kind != ObjectKind::Table {
+                continue;
+            }
+            let sql = object.sql.as_deref().unwrap_or_default();
+            let mut locations = Vec::new();
+            if crate::like_matches(&pattern, &object.name) {
+                locations.push(TableMatchLocation::Name);
+            }
+            // Tables with SQL we can't parse can still be found by name
+            if let Some(table) = self.schema.table(&object.name) {
+                locations.extend(
+                    table
+                        .columns
+                        .iter()
+                        .filter(|column| crate::like_matches(&pattern, &column.name))
+                        .map(|column| TableMatchLocation::Column(column.name.clone())),
+                );
+            }
+            if search_sql && crate::like_matches(&pattern, sql) {
+                locations.push(TableMatchLocation::Sql);
+            }
+            if !locations.is_empty() {
+                matches.push(TableMatch {
+                    schema: "main".to_owned(),
+                    table: object.name.clone(),
+                    locations,
+                });
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Get the objects in the schema which belong to a table whose name matches `table_pattern`
+    /// as a SQL `LIKE` pattern, or all of them if there's no pattern.
+    pub fn schema_objects<'a>(
+        &'a self,
+        table_pattern: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a SchemaObject> {
+        self.schema.objects.iter().filter(move |object| {
+            table_pattern.map_or(true, |pattern| {
+                crate::like_matches(pattern, &object.table_name)
+            })
+        })
+    }
+
+    /// Get the `CREATE` statements of the objects in the schema, as the `sqlite3` shell's
+    /// `.schema` command prints them.
+    ///
+    /// If `pattern` is given, only the objects whose names or table names match it as a SQL
+    /// `LIKE` pattern are included, so a table's name also picks out its indexes and triggers.
+    /// The schema table itself is only included if it's matched by name, since it has no
+    /// statement stored in the database.
+    #[must_use]
+    pub fn schema_statements(&self, pattern: Option<&str>) -> Vec<String> {
+        let mut statements = Vec::new();
+        if let Some(pattern) = pattern {
+            for name in ["sqlite_master", "sqlite_schema"] {
+                if crate::like_matches(pattern, name) {
+                    statements.push(format!(
+                        "CREATE TABLE {name} (\n  type text,\n  name text,\n  tbl_name text,\n  \
+                         rootpage integer,\n  sql text\n)"
+                    ));
+                }
+            }
+        }
+        statements.extend(
+            self.schema
+                .objects
+                .iter()
+                .filter(|object| {
+                    pattern.map_or(true, |pattern| {
+                        crate::like_matches(pattern, &object.name)
+                            || crate::like_matches(pattern, &object.table_name)
+                    })
+                })
+                // Automatic indexes have no statement
+                .filter_map(|object| object.sql.clone()),
+        );
+        statements
+    }
+
+    /// Get a handle to the table with the given name.
+    pub fn table(&mut self, name: &str) -> crate::Result<Table<'_, File>> {
+        let root_page = self.table_root_page(name)?;
+        let rowid_alias = self
+            .table_schema(name)
+            .map_or(None, |schema| schema.rowid_alias);
+        Ok(Table::new(self, root_page, rowid_alias))
+    }
+
+    /// Open the blob or text in the column called `column` of the row with the given rowid in
+    /// `table`, to read it a piece at a time with [`Read`](std::io::Read) and
+    /// [`Seek`](std::io::Seek) instead of loading all of it into memory.
+    pub fn blob_open(
         &mut self,
-    ) -> Result<impl Iterator<Item = (String, usize)> + '_> {
-        Ok([("sqlite_schema".to_owned(), 1)].into_iter().chain({
-            TableIter::new(self, "sqlite_schema")?.filter_map(|cell| {
-                if cell.first()?.as_str()? != "table" {
-                    return None;
+        table: &str,
+        column: &str,
+        rowid: i64,
+    ) -> crate::Result<Blob<'_, File>> {
+        let schema = self.table_schema(table)?;
+        if schema.without_rowid {
+            return Err(Error::UnsupportedSql(format!(
+                "cannot open table without rowid: {table}"
+            )));
+        }
+        let column_idx = schema
+            .column_index(column)
+            .ok_or_else(|| Error::NotFound(format!("no such column: \"{column}\"")))?;
+        // The value of a rowid alias is the rowid, which is stored in the cell instead of the
+        // record.
+        if schema.rowid_alias == Some(column_idx) {
+            return Err(Error::Mismatch(
+                "cannot open value of type integer".to_owned(),
+            ));
+        }
+        let root_page = self.table_root_page(table)?;
+        Ok(Blob::open(self, root_page, column_idx, rowid)?)
+    }
+
+    /// Get the definition of the table with the given name.
+    pub fn table_schema(&self, table_name: &str) -> crate::Result<TableSchema> {
+        if TableSchema::is_sqlite_schema(table_name) {
+            return Ok(TableSchema::sqlite_schema());
+        }
+        if let Some(table) = self.schema.table(table_name) {
+            return Ok(table.clone());
+        }
+        match self.schema.warning(ObjectKind::Table, table_name) {
+            Some(warning) => Err(Error::UnsupportedSql(warning.to_string())),
+            None => Err(Error::NotFound(format!(
+                "Failed to find table {table_name}"
+            ))),
+        }
+    }
+
+    /// Get the indexes on `table`, along with the page each one's btree is rooted at.
+    ///
+    /// Fails if any index on the table couldn't be parsed, since writes to the table would leave
+    /// that index out of date.
+    pub(crate) fn table_indexes(&self, table: &TableSchema) -> Result<Vec<(usize, IndexSchema)>> {
+        self.schema
+            .objects
+            .iter()
+            .filter(|object| {
+                object.kind == ObjectKind::Index
+                    && object.table_name.eq_ignore_ascii_case(&table.name)
+            })
+            .map(|object| {
+                let index = self
+                    .schema
+                    .indexes
+                    .iter()
+                    .find(|index| index.name.eq_ignore_ascii_case(&object.name));
+                match (index, object.root_page) {
+                    (Some(index), Some(root_page)) => Ok((root_page, index.clone())),
+                    _ => match self.schema.warning(ObjectKind::Index, &object.name) {
+                        Some(warning) => anyhow::bail!("{warning}"),
+                        None => anyhow::bail!("Failed to read index {}", object.name),
+                    },
                 }
-                Some((cell.get(2)?.as_str()?.to_owned(), cell.get(3)?.as_usize()?))
             })
-        }))
+            .collect()
+    }
+
+    /// Find the root page of the table with the given name.
+    pub(crate) fn table_root_page(&self, table_name: &str) -> Result<usize> {
+        if TableSchema::is_sqlite_schema(table_name) {
+            // schema table is always rooted at the first page
+            return Ok(1);
+        }
+        self.schema
+            .object(ObjectKind::Table, table_name)
+            .and_then(|object| object.root_page)
+            .ok_or_else(|| Error::NotFound(format!("Failed to find table {table_name}")).into())
+    }
+
+    pub fn table_names(&self) -> crate::Result<impl Iterator<Item = String> + '_> {
+        Ok(self.table_root_page_indices_by_name().map(|(name, _)| name))
+    }
+
+    pub(crate) fn table_root_page_indices_by_name(
+        &self,
+    ) -> impl Iterator<Item = (String, usize)> + '_ {
+        [("sqlite_schema".to_owned(), 1)]
+            .into_iter()
+            .chain(self.schema.objects.iter().filter_map(|object| {
+                if object.kind != ObjectKind::Table {
+                    return None;
+                }
+                Some((object.name.clone(), object.root_page?))
+            }))
+    }
+}
+
+/// Get the name of the table in a `FROM` clause or similar, if it's just a table with no joins.
+fn plain_table_name(table: &sqlparser::ast::TableWithJoins) -> Option<&str> {
+    let sqlparser::ast::TableWithJoins {
+        joins,
+        relation:
+            sqlparser::ast::TableFactor::Table {
+                name,
+                alias: None,
+                args: None,
+                with_hints,
+                version: None,
+                with_ordinality: false,
+                partitions,
+            },
+    } = table
+    else {
+        return None;
+    };
+    if !(joins.is_empty() && with_hints.is_empty() && partitions.is_empty()) {
+        return None;
+    }
+    let table_name = name.0.first().take_if(|_| name.0.len() == 1)?;
+    Some(&table_name.value)
+}
+
+/// Check whether `function` is `COUNT(*)`.
+fn is_count_star(function: &sqlparser::ast::Function) -> bool {
+    let sqlparser::ast::Function {
+        name,
+        parameters: sqlparser::ast::FunctionArguments::None,
+        args: sqlparser::ast::FunctionArguments::List(args),
+        filter: None,
+        null_treatment: None,
+        over: None,
+        within_group,
+    } = function
+    else {
+        return false;
+    };
+    name.0.len() == 1
+        && name.0[0].value.eq_ignore_ascii_case("count")
+        && within_group.is_empty()
+        && args.duplicate_treatment.is_none()
+        && args.clauses.is_empty()
+        && matches!(
+            args.args.as_slice(),
+            [sqlparser::ast::FunctionArg::Unnamed(
+                sqlparser::ast::FunctionArgExpr::Wildcard
+            )]
+        )
+}
+
+/// A handle which stops the statement a [`Database`] is running, as returned by
+/// [`Database::interrupt_handle`].
+///
+/// This can be used from other threads, and from signal handlers, as it only sets a flag.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    /// Whether the statement has been interrupted
+    flag: Arc<AtomicBool>,
+}
+impl InterruptHandle {
+    /// Stop the statement being run, which fails with an error once it notices.
+    ///
+    /// Any changes it made are undone. Statements started after this aren't stopped by it.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
     }
 }
 
+/// The resources used by a statement, as returned by [`Database::execute_statement`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct QueryStats {
+    /// The number of rows read from tables, including rows which didn't match a `WHERE` clause.
+    pub rows_examined: u64,
+    /// The number of rows passed to the callback.
+    pub rows_returned: u64,
+    /// The number of pages loaded from the file.
+    pub pages_read: u64,
+    /// The number of page reads served from memory instead of the file.
+    pub cache_hits: u64,
+    /// How long the statement took to run.
+    pub elapsed: Duration,
+}
+
+/// A table found by [`Database::find_tables`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMatch {
+    /// The schema containing the table.
+    ///
+    /// This is always `main`, since attaching other databases isn't supported yet.
+    pub schema: String,
+    /// The name of the table.
+    pub table: String,
+    /// Where the search pattern matched.
+    pub locations: Vec<TableMatchLocation>,
+}
+
+/// The part of a table's definition which matched a search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableMatchLocation {
+    /// The name of the table.
+    Name,
+    /// The name of the given column.
+    Column(String),
+    /// The SQL statement which created the table.
+    Sql,
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, fs::File};
 
     use super::*;
+    use crate::{
+        pager::journal_path_for,
+        record::{RowExt, Value},
+    };
 
     #[test]
     fn test_table_root_page_indices() {
-        let mut db = Database::new(
+        let db = Database::new(
             File::open("test-data/minimal-test.sqlite").expect("Failed to open test database"),
         )
         .expect("Failed to parse test database");
         assert_eq!(
             db.table_root_page_indices_by_name()
-                .expect("failed to read table list")
                 .collect::<HashSet<(String, usize)>>(),
             HashSet::from_iter([
                 ("sqlite_schema".to_owned(), 1),
@@ -161,4 +1089,875 @@ mod tests {
             HashSet::from_iter(["sqlite_schema".to_owned(), "t1".to_owned(), "t2".to_owned()]),
         );
     }
+
+    #[test]
+    fn test_schema_table_names() {
+        let mut db = Database::new(
+            File::open("test-data/minimal-test.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        // `sqlite_master` is another name for `sqlite_schema`, and both ignore case.
+        for name in [
+            "sqlite_schema",
+            "sqlite_master",
+            "SQLITE_MASTER",
+            "Sqlite_Schema",
+        ] {
+            assert_eq!(
+                query(&mut db, &format!("SELECT tbl_name, rootpage FROM {name}"))
+                    .expect("Failed to query schema"),
+                [
+                    [Value::String(Box::from(&b"t1"[..])), Value::int(2)],
+                    [Value::String(Box::from(&b"t2"[..])), Value::int(3)],
+                ],
+                "Failed to read {name}",
+            );
+        }
+        let schema = db
+            .table_schema("SQLITE_MASTER")
+            .expect("Missing schema table");
+        assert_eq!(schema.column_index("SQL"), Some(4));
+    }
+
+    #[test]
+    fn test_find_tables() {
+        let db = Database::new(
+            File::open("test-data/many-tables.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let found = db.find_tables("t10_", false).expect("Failed to search");
+        assert_eq!(found.len(), 10);
+        assert!(
+            found
+                .iter()
+                .all(|found| found.locations == [TableMatchLocation::Name]),
+            "Only the names should match",
+        );
+        let found = db.find_tables("NAME", false).expect("Failed to search");
+        assert_eq!(found.len(), 1024);
+        assert_eq!(
+            found[0],
+            TableMatch {
+                schema: "main".to_owned(),
+                table: "t1".to_owned(),
+                locations: vec![TableMatchLocation::Column("name".to_owned())],
+            },
+        );
+        let found = db.find_tables("string", false).expect("Failed to search");
+        assert!(found.is_empty(), "Column types shouldn't match by default");
+        let found = db.find_tables("string", true).expect("Failed to search");
+        assert_eq!(found.len(), 1024);
+    }
+
+    #[test]
+    fn test_schema_statements() {
+        let db = Database::new(
+            File::open("test-data/constraints.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let users = "CREATE TABLE users(id INTEGER PRIMARY KEY, email TEXT UNIQUE, name TEXT, \
+                     team, UNIQUE(name, team))";
+        let users_team = "CREATE INDEX users_team ON users(team)";
+        let tags = "CREATE TABLE tags(name TEXT PRIMARY KEY, n)";
+        assert_eq!(db.schema_statements(None), [users, users_team, tags]);
+        assert_eq!(db.schema_statements(Some("USERS")), [users, users_team]);
+        assert_eq!(db.schema_statements(Some("users_team")), [users_team]);
+        assert_eq!(db.schema_statements(Some("t%")), [tags]);
+        assert!(db.schema_statements(Some("%_idx")).is_empty());
+        let schema_table = db.schema_statements(Some("sqlite_master"));
+        assert_eq!(schema_table.len(), 1);
+        assert!(schema_table[0].starts_with("CREATE TABLE sqlite_master (\n  type text,"));
+    }
+
+    #[test]
+    fn test_schema_objects() {
+        let db = Database::new(
+            File::open("test-data/constraints.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let names = |pattern| {
+            db.schema_objects(pattern)
+                .filter(|object| object.kind == ObjectKind::Index)
+                .map(|object| (object.name.as_str(), object.table_name.as_str()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(None),
+            [
+                ("sqlite_autoindex_users_1", "users"),
+                ("sqlite_autoindex_users_2", "users"),
+                ("users_team", "users"),
+                ("sqlite_autoindex_tags_1", "tags"),
+            ]
+        );
+        assert_eq!(names(Some("T%")), [("sqlite_autoindex_tags_1", "tags")]);
+        assert!(names(Some("users_team")).is_empty());
+        assert_eq!(db.schema_objects(Some("users")).count(), 4);
+    }
+
+    #[test]
+    fn test_wal_snapshots() {
+        let mut wal = Wal::open(
+            File::open("test-data/wal-history.sqlite-wal").expect("Failed to open test WAL"),
+        )
+        .expect("Failed to read test WAL");
+        let mut open_at = |frame| {
+            let file =
+                File::open("test-data/wal-history.sqlite").expect("Failed to open test database");
+            Database::with_wal_snapshot(file, &mut wal, frame).expect("Failed to open snapshot")
+        };
+        let values = |db: &mut Database| {
+            query(db, "SELECT * FROM t")
+                .expect("Failed to query")
+                .iter()
+                .take(2)
+                .map(|row| row.get_as::<String>(1).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(
+            open_at(0).table("t").is_err(),
+            "The table was created in the WAL"
+        );
+        assert!(values(&mut open_at(2)).is_empty());
+        assert_eq!(values(&mut open_at(3)), ["first"]);
+        // Frames in the middle of a commit round down to the commit before them.
+        assert_eq!(values(&mut open_at(4)), ["first", "second"]);
+        assert_eq!(values(&mut open_at(9)), ["updated", "second"]);
+        let mut db = open_at(10);
+        assert_eq!(db.table("t").unwrap().count().unwrap(), 202);
+        assert_eq!(db.page_count(), 5);
+
+        assert!(
+            run(&mut db, "INSERT INTO t VALUES (1000, 'new')").is_err(),
+            "Snapshots are read-only",
+        );
+        assert_eq!(db.table("t").unwrap().count().unwrap(), 202);
+    }
+
+    #[test]
+    fn test_open_wal_mode() {
+        let path = temp_copy("test-data/wal-history.sqlite", "open-wal-mode");
+        let wal_path = crate::pager::wal_path_for(&path);
+        std::fs::copy("test-data/wal-history.sqlite-wal", &wal_path).expect("Failed to copy WAL");
+        let open = || {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .expect("Failed to open test database");
+            Database::with_journal(file, journal_path_for(&path))
+                .expect("Failed to parse test database")
+        };
+        let mut db = open();
+        assert_eq!(db.pager.journal_mode(), JournalMode::Wal);
+        // The table only exists in the WAL, so it's only found by reading through it.
+        assert_eq!(db.table("t").unwrap().count().unwrap(), 202);
+        assert_eq!(db.page_count(), 5);
+        run(&mut db, "INSERT INTO t VALUES (1000, 'new')").unwrap();
+        drop(db);
+
+        let mut db = open();
+        assert_eq!(db.table("t").unwrap().count().unwrap(), 203);
+        let rows = query(&mut db, "SELECT * FROM t").unwrap();
+        assert_eq!(rows[0][1], Value::String(Box::from(&b"updated"[..])));
+        assert_eq!(rows[202][1], Value::String(Box::from(&b"new"[..])));
+
+        // Without a journal path, the WAL can't be found, so the database can't be read.
+        let file = File::open(&path).expect("Failed to open test database");
+        assert!(Database::new(file).is_err());
+    }
+
+    #[test]
+    fn test_utf16_database() {
+        let path = temp_copy("test-data/utf16.sqlite", "utf16");
+        let mut db = open_rw(&path);
+        assert_eq!(
+            db.pager.text_encoding(),
+            crate::record::TextEncoding::Utf16Le
+        );
+        // The schema is stored as UTF-16 too, so finding the table at all means it was decoded.
+        let words = |db: &mut Database| {
+            query(db, "SELECT * FROM words")
+                .expect("Failed to query")
+                .iter()
+                .map(|row| row.get_as::<String>(1).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            words(&mut db),
+            ["hello", "héllo wörld", "日本語", "🦀 crab"]
+        );
+
+        run(&mut db, "INSERT INTO words VALUES (5, 'ñandú', NULL)").unwrap();
+        assert_eq!(words(&mut db)[4], "ñandú");
+        // Deleting the row has to find its entry in the index on `word`, which is only there if
+        // it was encoded the same way as the row.
+        run(&mut db, "DELETE FROM words WHERE id = 2").unwrap();
+        assert_eq!(words(&mut db), ["hello", "日本語", "🦀 crab", "ñandú"]);
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let path = temp_copy("test-data/reserved-bytes.sqlite", "reserved-bytes");
+        let mut db = open_rw(&path);
+        assert_eq!(db.pager.usable_size(), db.pager.page_size() - 32);
+        let rows = query(&mut db, "SELECT * FROM t").unwrap();
+        assert_eq!(rows.len(), 201);
+        assert_eq!(rows[200].get_as::<String>(1).unwrap(), "z".repeat(3000));
+
+        // The new rows go before the existing ones, away from the leaf holding the overflowing
+        // row, since rows with overflow pages can't be written yet.
+        for id in -299..=0 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t VALUES ({id}, 'new row {id}')"),
+            )
+            .unwrap();
+        }
+        assert_eq!(query(&mut db, "SELECT * FROM t").unwrap().len(), 501);
+        drop(db);
+        // Nothing should have been written into the reserved bytes at the end of each page.
+        let contents = std::fs::read(&path).expect("Failed to read database");
+        for (idx, page) in contents.chunks(1024).enumerate() {
+            assert!(
+                page[1024 - 32..].iter().all(|&byte| byte == 0),
+                "Page {} was written past its usable size",
+                idx + 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_schema_warnings() {
+        let path = temp_copy("test-data/schema-objects.sqlite", "schema-warnings");
+        let mut db = open_rw(&path);
+        let warnings = db
+            .schema_warnings()
+            .iter()
+            .map(|warning| (warning.kind.as_str(), warning.name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings, [("table", "legacy")]);
+        assert_eq!(db.schema().views[0].columns, ["x"]);
+        assert_eq!(db.schema().triggers[0].table_name, "t");
+
+        // Tables we can't parse can still be read, just not written to.
+        assert_eq!(query(&mut db, "SELECT * FROM legacy").unwrap().len(), 1);
+        assert!(db
+            .table_schema("legacy")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to read table legacy: "));
+        assert!(run(&mut db, "INSERT INTO legacy VALUES ('a', 2)").is_err());
+        assert_eq!(query(&mut db, "SELECT * FROM t").unwrap().len(), 2);
+        // The descending index on `t` is kept up to date.
+        run(&mut db, "INSERT INTO t VALUES (3, 'three', 30)").unwrap();
+        assert_eq!(query(&mut db, "SELECT * FROM t").unwrap().len(), 3);
+        assert!(run(
+            &mut db,
+            "INSERT INTO sqlite_schema VALUES ('table', 'x', 'x', 9, NULL)"
+        )
+        .is_err());
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_schema_reload() {
+        let path = temp_copy("test-data/many-tables.sqlite", "schema-reload");
+        let mut reader = open_rw(&path);
+        let mut writer = open_rw(&path);
+        // Changing rows leaves the schema cookie alone, so the schema isn't read again.
+        run(&mut writer, "INSERT INTO t1 VALUES (1, 'one')").unwrap();
+        assert_eq!(query(&mut reader, "SELECT * FROM t1").unwrap().len(), 1);
+        assert!(
+            reader.page_accesses().len() <= 2,
+            "Only the header and the table should be read",
+        );
+
+        // Vacuuming moves the tables, and changes the cookie to say so.
+        run(&mut writer, "DELETE FROM t1").unwrap();
+        writer.vacuum().expect("Failed to vacuum");
+        assert!(query(&mut reader, "SELECT * FROM t1").unwrap().is_empty());
+        assert!(
+            reader.page_accesses().len() > 2,
+            "The schema should be read again",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_external_changes() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "external-changes");
+        let mut db = open_rw(&path);
+        let rows = query(&mut db, "SELECT * FROM t1").unwrap();
+
+        // Copy the database to another file, change it there, and write it back like another
+        // program would, with a change counter too new for the page count in the header.
+        let copy = temp_copy("test-data/minimal-test.sqlite", "external-changes-copy");
+        let mut other = open_rw(&copy);
+        run(&mut other, "INSERT INTO t1 VALUES (4)").unwrap();
+        drop(other);
+        let mut contents = std::fs::read(&copy).unwrap();
+        let page_size = usize::from(u16::from_be_bytes([contents[16], contents[17]]));
+        contents[28..32].copy_from_slice(&1_u32.to_be_bytes());
+        contents[92..96].copy_from_slice(&0_u32.to_be_bytes());
+        contents.extend(vec![0; page_size]);
+        std::fs::write(&path, &contents).unwrap();
+
+        assert_eq!(
+            query(&mut db, "SELECT * FROM t1").unwrap().len(),
+            rows.len() + 1
+        );
+        assert_eq!(
+            db.page_count(),
+            contents.len() / page_size,
+            "The page count should come from the file's size"
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+        std::fs::remove_file(copy).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut db = Database::new(
+            File::open("test-data/minimal-test.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let read_marker = |db: &mut Database| {
+            *db.pager
+                .read_page(3)
+                .expect("Failed to read page")
+                .as_bytes()
+                .last()
+                .unwrap()
+        };
+        let write_marker = |db: &mut Database, marker| {
+            let mut page = db
+                .pager
+                .read_page(3)
+                .expect("Failed to read page")
+                .as_bytes()
+                .to_vec();
+            *page.last_mut().unwrap() = marker;
+            db.pager.write_page(3, &page).expect("Failed to write page");
+        };
+        assert!(
+            run(&mut db, "RELEASE a").is_err(),
+            "Releasing a nonexistent savepoint should fail",
+        );
+        run(&mut db, "SAVEPOINT a").expect("Failed to open savepoint");
+        assert!(db.in_transaction(), "SAVEPOINT should open a transaction");
+        write_marker(&mut db, 1);
+        run(&mut db, "SAVEPOINT b").expect("Failed to open savepoint");
+        write_marker(&mut db, 2);
+        run(&mut db, "SAVEPOINT c").expect("Failed to open savepoint");
+        write_marker(&mut db, 3);
+        run(&mut db, "RELEASE c").expect("Failed to release savepoint");
+        assert_eq!(read_marker(&mut db), 3);
+        run(&mut db, "ROLLBACK TO b").expect("Failed to roll back to savepoint");
+        assert_eq!(read_marker(&mut db), 1);
+        assert!(
+            run(&mut db, "RELEASE c").is_err(),
+            "Savepoint `c` should have been released",
+        );
+        write_marker(&mut db, 4);
+        run(&mut db, "ROLLBACK TO B").expect("Failed to roll back to savepoint");
+        assert_eq!(read_marker(&mut db), 1);
+        run(&mut db, "ROLLBACK TO a").expect("Failed to roll back to savepoint");
+        assert_eq!(read_marker(&mut db), 0);
+        assert!(
+            db.in_transaction(),
+            "ROLLBACK TO shouldn't end the transaction"
+        );
+        run(&mut db, "ROLLBACK").expect("Failed to roll back");
+        assert!(!db.in_transaction(), "ROLLBACK should end the transaction");
+    }
+
+    #[test]
+    fn test_page_accesses() {
+        let mut db = Database::new(
+            File::open("test-data/many-tables.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        run(&mut db, "SELECT * FROM sqlite_schema").expect("Failed to run query");
+        let scan_accesses = db.page_accesses().clone();
+        assert!(
+            scan_accesses.len() > 2,
+            "Scanning should touch the root and every leaf",
+        );
+        assert!(
+            scan_accesses[&1] > 1,
+            "The root should be revisited between leaves",
+        );
+        run(&mut db, "SELECT * FROM t1").expect("Failed to run query");
+        assert!(
+            db.page_accesses().len() < scan_accesses.len(),
+            "Page accesses should be reset for each statement",
+        );
+    }
+
+    #[test]
+    fn test_query_stats() {
+        let path = temp_copy("test-data/constraints.sqlite", "query-stats");
+        let mut db = open_rw(&path);
+        let stats = |db: &mut Database, sql| {
+            let statements =
+                sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                    .unwrap();
+            db.execute_statement(&statements[0], |_| Ok(()))
+                .expect("Failed to run statement")
+        };
+        let scan = stats(&mut db, "SELECT * FROM users");
+        assert_eq!((scan.rows_examined, scan.rows_returned), (2, 2));
+        assert_eq!(
+            scan.pages_read, 1,
+            "The table's only page isn't read at open"
+        );
+        let scan = stats(&mut db, "SELECT * FROM users");
+        assert_eq!(scan.pages_read, 0, "The page should be cached by now");
+        assert_eq!(scan.cache_hits, db.page_accesses().values().sum::<u64>());
+
+        let delete = stats(&mut db, "DELETE FROM users WHERE id = 2 RETURNING *");
+        assert_eq!((delete.rows_examined, delete.rows_returned), (2, 1));
+        let count = stats(&mut db, "SELECT COUNT(*) FROM users");
+        assert_eq!((count.rows_examined, count.rows_returned), (1, 1));
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let path = temp_copy("test-data/constraints.sqlite", "interrupt");
+        let mut db = open_rw(&path);
+        let parse = |sql| {
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .unwrap()
+                .remove(0)
+        };
+        let handle = db.interrupt_handle();
+        let mut rows = 0;
+        let result = db.execute_statement(&parse("SELECT * FROM users"), |_| {
+            rows += 1;
+            handle.interrupt();
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "interrupted");
+        assert_eq!(rows, 1, "The scan should stop once it's interrupted");
+
+        // Interrupt a delete once it's made its changes, as if it were interrupted partway
+        // through them.
+        let sqlparser::ast::Statement::Delete(delete) = parse("DELETE FROM users") else {
+            unreachable!("Parsed a DELETE as something else");
+        };
+        let result = db.with_lock(|db| {
+            db.write_statement(|db| {
+                let rows = db.execute_delete(&delete)?;
+                handle.interrupt();
+                Ok(rows)
+            })
+        });
+        assert!(result.is_err());
+        let count = db
+            .query_row::<(i64,)>("SELECT COUNT(*) FROM users")
+            .expect("Statements after an interrupt should run");
+        assert_eq!(count, (2,), "The interrupted delete should be undone");
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_execute_batch() {
+        let mut db = Database::from_bytes(
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database"),
+        )
+        .expect("Failed to parse test database");
+        db.execute_batch(
+            "INSERT INTO tags VALUES ('y', 2);\n\
+             -- Statements can span lines, and have semicolons in their strings.\n\
+             UPDATE tags SET n = 3\n  WHERE name = 'y;';\n\
+             SELECT * FROM tags; ANALYZE",
+        )
+        .expect("Failed to run batch");
+        assert_eq!(query(&mut db, "SELECT * FROM tags").unwrap().len(), 2);
+
+        let error = db
+            .execute_batch(
+                "INSERT INTO tags VALUES ('z', 3);\n\
+                 INSERT INTO tags VALUES ('x', 4);\n\
+                 INSERT INTO tags VALUES ('w', 5);",
+            )
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::Constraint(message)
+                if message.starts_with("Error in statement 2 near line 2")),
+            "{error:?}"
+        );
+        assert_eq!(
+            query(&mut db, "SELECT name FROM tags").unwrap(),
+            [
+                [Value::String(b"x".as_slice().into())],
+                [Value::String(b"y".as_slice().into())],
+                [Value::String(b"z".as_slice().into())],
+            ],
+            "Statements before the one which failed should stay run, and none after it"
+        );
+
+        let error = db.execute_batch("SELEC 1").unwrap_err();
+        assert!(matches!(error, Error::Syntax(_)), "{error:?}");
+        let error = db.execute_batch(".tables").unwrap_err();
+        assert!(matches!(error, Error::Syntax(_)), "{error:?}");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let mut db = Database::from_bytes(
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database"),
+        )
+        .expect("Failed to parse test database");
+        let mut error = |sql| {
+            db.query_rows::<(i64,)>(sql)
+                .expect_err("The statement should fail")
+        };
+        let constraint = error("INSERT INTO users (id) VALUES (1)");
+        assert!(
+            matches!(&constraint, Error::Constraint(message) if message.ends_with("UNIQUE constraint failed: users.id")),
+            "{constraint:?}"
+        );
+        for sql in [
+            "SELECT * FROM no_such_table",
+            "SELECT no_such_column FROM users",
+            "SELECT * FROM no_such_function()",
+        ] {
+            let not_found = error(sql);
+            assert!(
+                matches!(not_found, Error::NotFound(_)),
+                "{sql}: {not_found:?}"
+            );
+        }
+        let unsupported = error("SELECT * FROM users, tags");
+        assert!(
+            matches!(unsupported, Error::UnsupportedSql(_)),
+            "{unsupported:?}"
+        );
+        let syntax = error("SELEC * FROM users");
+        assert!(matches!(syntax, Error::Syntax(_)), "{syntax:?}");
+        for sql in [
+            "COMMIT",
+            "INSERT INTO tags VALUES ('x')",
+            "DELETE FROM sqlite_schema",
+        ] {
+            let sql_error = error(sql);
+            assert!(matches!(sql_error, Error::Sql(_)), "{sql}: {sql_error:?}");
+        }
+        let not_found = error("INSERT INTO tags (no_such_column) VALUES (1)");
+        assert!(matches!(not_found, Error::NotFound(_)), "{not_found:?}");
+        let mismatch = error("UPDATE tags SET rowid = 'x'");
+        assert!(matches!(mismatch, Error::Mismatch(_)), "{mismatch:?}");
+
+        let not_found = db.blob_open("users", "no_such_column", 1).err();
+        assert!(
+            matches!(not_found, Some(Error::NotFound(_))),
+            "{not_found:?}"
+        );
+    }
+
+    /// Copy a test database to a temporary file, so it can be modified.
+    pub(super) fn temp_copy(fixture: &str, name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-{name}-{}.sqlite", std::process::id()));
+        std::fs::copy(fixture, &path).expect("Failed to copy test database");
+        path
+    }
+
+    /// Open a database for reading and writing.
+    pub(super) fn open_rw(path: &PathBuf) -> Database {
+        Database::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .open(path)
+                .expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database")
+    }
+
+    /// Parse and run `sql`, returning all rows.
+    pub(super) fn query<File: Storage>(
+        db: &mut Database<File>,
+        sql: &str,
+    ) -> Result<Vec<Vec<OwnedValue>>> {
+        let mut rows = Vec::new();
+        for statement in
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)?
+        {
+            db.execute_statement(&statement, |row| {
+                rows.push(row);
+                Ok(())
+            })?;
+        }
+        Ok(rows)
+    }
+
+    /// Parse and run `sql`, discarding any returned rows.
+    pub(super) fn run<File: Storage>(db: &mut Database<File>, sql: &str) -> Result<()> {
+        for statement in
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)?
+        {
+            db.execute_statement(&statement, |_| Ok(()))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let mut db = Database::new(
+            File::open("test-data/minimal-test.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        assert!(
+            run(&mut db, "COMMIT").is_err(),
+            "COMMIT outside a transaction should fail",
+        );
+        run(&mut db, "BEGIN").expect("Failed to begin transaction");
+        assert!(db.in_transaction(), "BEGIN should open a transaction");
+        assert!(
+            run(&mut db, "BEGIN").is_err(),
+            "Transactions shouldn't nest",
+        );
+        let original = db
+            .pager
+            .read_page(3)
+            .expect("Failed to read page")
+            .as_bytes()
+            .to_vec();
+        let mut modified = original.clone();
+        *modified.last_mut().unwrap() = 0xff;
+        db.pager
+            .write_page(3, &modified)
+            .expect("Failed to write page");
+        db.pager
+            .write_page(4, &modified)
+            .expect("Failed to append page");
+        assert_eq!(db.pager.page_count(), 4);
+        run(&mut db, "ROLLBACK").expect("Failed to roll back transaction");
+        assert!(
+            !db.in_transaction(),
+            "ROLLBACK should close the transaction"
+        );
+        assert_eq!(
+            db.pager
+                .read_page(3)
+                .expect("Failed to read page")
+                .as_bytes(),
+            original,
+            "ROLLBACK should restore the original page",
+        );
+        assert_eq!(db.pager.page_count(), 3);
+    }
+
+    #[test]
+    fn test_empty_pages() {
+        // A database with no tables, so page 1 is an empty leaf after the database header.
+        let mut db = Database::new(
+            File::open("test-data/empty-database.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        assert!(query(&mut db, "SELECT * FROM sqlite_schema")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            query(&mut db, "SELECT COUNT(*) FROM sqlite_schema").unwrap(),
+            [[Value::int(0)]],
+        );
+
+        // 64KiB pages, where an empty page's content offset of 65536 is stored as 0.
+        let path = temp_copy("test-data/large-pages.sqlite", "empty-pages");
+        let mut db = open_rw(&path);
+        assert!(query(&mut db, "SELECT * FROM empty").unwrap().is_empty());
+        assert_eq!(
+            query(&mut db, "SELECT count(*) FROM empty").unwrap(),
+            [[Value::int(0)]],
+        );
+        assert_eq!(
+            query(&mut db, "SELECT COUNT(*) FROM t").unwrap(),
+            [[Value::int(3)]],
+        );
+        assert_eq!(db.table("empty").unwrap().sample(5).unwrap().len(), 0);
+        run(&mut db, "INSERT INTO empty VALUES (1), (2)").expect("Failed to insert");
+        drop(db);
+        let mut db = open_rw(&path);
+        let rows = query(&mut db, "SELECT * FROM empty").unwrap();
+        assert_eq!(
+            rows.iter()
+                .map(|row| row.get_as::<i64>(0).unwrap())
+                .collect::<Vec<_>>(),
+            [1, 2],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_count_multilevel() {
+        let mut db = Database::new(
+            File::open("test-data/many-tables.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        assert_eq!(
+            query(&mut db, "SELECT COUNT(*) FROM sqlite_schema").unwrap(),
+            [[Value::int(1024)]],
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-create-{}.sqlite", std::process::id()));
+        let mut db = Database::create(&path).expect("Failed to create database");
+        assert_eq!(db.page_count(), 1);
+        assert!(query(&mut db, "SELECT * FROM sqlite_schema")
+            .unwrap()
+            .is_empty());
+        drop(db);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4096);
+        assert!(
+            Database::create(&path).is_err(),
+            "An existing database shouldn't be overwritten",
+        );
+        let db = open_rw(&path);
+        assert!(db.schema().objects.is_empty());
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_open_in_memory() {
+        let mut db = Database::open_in_memory().expect("Failed to create database");
+        assert_eq!(db.page_count(), 1);
+        assert!(db.schema().objects.is_empty());
+        assert_eq!(
+            query(&mut db, "PRAGMA page_size").unwrap(),
+            [[Value::int(DEFAULT_PAGE_SIZE as i64)]],
+        );
+        let contents = db.into_bytes();
+        assert_eq!(contents.len(), DEFAULT_PAGE_SIZE);
+        let db = Database::from_bytes(contents).expect("Failed to reopen database");
+        assert!(db.schema().objects.is_empty());
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let fixture = std::fs::read("test-data/autoincrement.sqlite").expect("Failed to read");
+        let mut db = Database::from_bytes(fixture.as_slice()).expect("Failed to parse database");
+        run(&mut db, "INSERT INTO t(x) VALUES ('m')").expect("Failed to insert");
+        run(&mut db, "DELETE FROM u").expect("Failed to delete");
+        db.vacuum().expect("Failed to vacuum");
+        let contents = db.into_bytes();
+        assert_ne!(contents, fixture);
+
+        let mut db = Database::from_bytes(contents).expect("Failed to reopen database");
+        assert_eq!(
+            query(&mut db, "SELECT * FROM t").unwrap().last().unwrap(),
+            &[Value::int(4), Value::String("m".as_bytes().into())],
+        );
+        assert_eq!(
+            db.integrity_check(integrity::DEFAULT_MAX_PROBLEMS).unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(
+            db.set_journal_mode(JournalMode::Wal).is_err(),
+            "Databases in memory have nowhere to keep a WAL",
+        );
+    }
+
+    #[test]
+    fn test_readers() {
+        let path = temp_copy("test-data/many-tables.sqlite", "readers");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .expect("Failed to open test database");
+        let mut db = Database::with_journal(file, journal_path_for(&path))
+            .expect("Failed to parse test database");
+        run(&mut db, "INSERT INTO t1 VALUES (1, 'one')").unwrap();
+        let expected = query(&mut db, "SELECT * FROM t1").unwrap();
+        let threads = (0..4)
+            .map(|_| {
+                let mut reader = db.reader().expect("Failed to open reader");
+                std::thread::spawn(move || query(&mut reader, "SELECT * FROM t1").unwrap())
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), expected);
+        }
+
+        let mut reader = db.reader().expect("Failed to open reader");
+        assert_eq!(query(&mut reader, "SELECT * FROM t1").unwrap(), expected);
+        assert_eq!(
+            reader.pager.page_loads(),
+            0,
+            "Pages should be read from the shared cache",
+        );
+
+        // Readers notice changes to the database, even though they share cached pages.
+        run(&mut db, "INSERT INTO t1 VALUES (2, 'two')").unwrap();
+        assert_eq!(query(&mut reader, "SELECT * FROM t1").unwrap().len(), 2);
+        assert!(run(&mut reader, "INSERT INTO t1 VALUES (3, 'three')").is_err());
+        assert_eq!(query(&mut db, "SELECT * FROM t1").unwrap().len(), 2);
+
+        assert!(
+            Database::open_in_memory().unwrap().reader().is_err(),
+            "Databases in memory can't be opened again",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_blob_open() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut db = Database::new(
+            File::open("test-data/overflow.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        // Row 2 has text which spills across several overflow pages.
+        let text = (0..3000)
+            .map(|idx| b'a' + (idx % 26) as u8)
+            .collect::<Vec<_>>();
+        let mut blob = db.blob_open("big", "BODY", 2).expect("Failed to open blob");
+        assert_eq!(blob.len(), text.len());
+        let mut read = Vec::new();
+        blob.read_to_end(&mut read).unwrap();
+        assert_eq!(read, text);
+
+        // Reads can start anywhere, including partway through an overflow page.
+        let mut buf = [0; 100];
+        blob.seek(SeekFrom::Start(1234)).unwrap();
+        blob.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, text[1234..1334]);
+        assert_eq!(blob.seek(SeekFrom::End(-10)).unwrap(), 2990);
+        let mut tail = Vec::new();
+        blob.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, text[2990..]);
+        assert_eq!(blob.read(&mut buf).unwrap(), 0, "Reads stop at the end");
+        assert!(blob.seek(SeekFrom::Current(-5000)).is_err());
+
+        let mut blob = Vec::new();
+        db.blob_open("big", "body", 3)
+            .unwrap()
+            .read_to_end(&mut blob)
+            .unwrap();
+        assert_eq!(blob, (0..4).flat_map(|_| 0..=255_u8).collect::<Vec<_>>());
+        let mut small = String::new();
+        db.blob_open("big", "body", 1)
+            .unwrap()
+            .read_to_string(&mut small)
+            .unwrap();
+        assert_eq!(small, "small");
+
+        let mut error = |column, rowid| match db.blob_open("big", column, rowid) {
+            Ok(_) => panic!("Opening {column} in row {rowid} should fail"),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(error("id", 1), "cannot open value of type integer");
+        assert_eq!(error("body", 5), "no such rowid: 5");
+        assert_eq!(error("x", 1), "no such column: \"x\"");
+    }
 }
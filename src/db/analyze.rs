@@ -0,0 +1,203 @@
+//! Gathering the statistics the planner uses with `ANALYZE`
+
+use anyhow::{Context, Result};
+
+use super::{plan::STAT1_TABLE, Database};
+use crate::{
+    btree,
+    pager::{AutoVacuum, Storage},
+    record::{OwnedValue, Record, Value},
+};
+
+/// The definition SQLite gives `sqlite_stat1` when `ANALYZE` creates it.
+const STAT1_SQL: &str = "CREATE TABLE sqlite_stat1(tbl,idx,stat)";
+
+impl<File: Storage> Database<File> {
+    /// Count the rows in each table and how many rows share each key of its indexes, storing the
+    /// results in `sqlite_stat1` for choosing how to run later statements.
+    ///
+    /// This does what an `ANALYZE` statement does, which `sqlparser` can't parse.
+    pub fn analyze(&mut self) -> crate::Result<()> {
+        Ok(self.with_lock(|db| {
+            db.write_statement(Self::analyze_locked)?;
+            // The statistics are read along with the schema, which may have gained a table.
+            db.read_schema()
+        })?)
+    }
+
+    /// Replace the contents of `sqlite_stat1` with fresh statistics, creating it if needed.
+    fn analyze_locked(&mut self) -> Result<()> {
+        let mut rows = Vec::new();
+        let tables = self
+            .schema
+            .tables
+            .iter()
+            .filter(|table| !table.name.to_ascii_lowercase().starts_with("sqlite_"))
+            .cloned()
+            .collect::<Vec<_>>();
+        for table in tables {
+            let indexes = self
+                .table_indexes(&table)
+                .with_context(|| format!("Failed to analyze table {}", table.name))?;
+            if indexes.is_empty() {
+                let count = self.table(&table.name)?.count()?;
+                // Like SQLite, empty tables are left out.
+                if count > 0 {
+                    rows.push((table.name.clone(), None, count.to_string()));
+                }
+                continue;
+            }
+            for (root_page, index) in indexes {
+                let Some(stat) = self.index_stat(root_page, index.columns.len())? else {
+                    continue;
+                };
+                rows.push((table.name.clone(), Some(index.name), stat));
+            }
+        }
+
+        let root_page = match self.schema.table(STAT1_TABLE) {
+            Some(_) => {
+                let root_page = self.table_root_page(STAT1_TABLE)?;
+                btree::clear(&mut self.pager, root_page)?;
+                root_page
+            }
+            None => self.create_stat1_table()?,
+        };
+        let encoding = self.pager.text_encoding();
+        let text = |text: &str| Value::String(text.as_bytes().into());
+        for (rowid, (table, index, stat)) in (1..).zip(rows) {
+            let record: [OwnedValue; 3] = [
+                text(&table),
+                index.as_deref().map_or(Value::Null, text),
+                text(&stat),
+            ];
+            btree::insert(
+                &mut self.pager,
+                root_page,
+                rowid,
+                &Record::build_encoded(&record, encoding),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Work out the `sqlite_stat1` entry for the index btree rooted at `root_page`, which is keyed
+    /// on `columns` values, or `None` if it's empty.
+    ///
+    /// This is the number of entries, followed by the average number of entries (rounded up) which
+    /// share the same first one, two, ... values.
+    fn index_stat(&mut self, root_page: usize, columns: usize) -> Result<Option<String>> {
+        let mut distinct = vec![0_u64; columns];
+        let mut previous: Option<Vec<OwnedValue>> = None;
+        let entries = btree::index::entries(&mut self.pager, root_page)?;
+        for entry in &entries {
+            let values = Record::parse(entry)?
+                .value_iter()
+                .take(columns)
+                .map(|value| value.to_owned())
+                .collect::<Vec<_>>();
+            // Every prefix at least as long as the first value which differs is a new key.
+            let same = previous.as_ref().map_or(0, |previous| {
+                previous
+                    .iter()
+                    .zip(&values)
+                    .take_while(|(a, b)| a.sqlite_cmp(b).is_eq())
+                    .count()
+            });
+            for count in distinct.iter_mut().skip(same) {
+                *count += 1;
+            }
+            previous = Some(values);
+        }
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let rows = entries.len() as u64;
+        let mut stat = rows.to_string();
+        for count in distinct {
+            stat.push_str(&format!(" {}", rows.div_ceil(count)));
+        }
+        Ok(Some(stat))
+    }
+
+    /// Add an empty `sqlite_stat1` table to the schema, returning its root page.
+    fn create_stat1_table(&mut self) -> Result<usize> {
+        // TODO Support creating tables in auto-vacuum databases, whose root pages have to be moved
+        // to the start of the file.
+        anyhow::ensure!(
+            self.pager.auto_vacuum() == AutoVacuum::None,
+            crate::Error::UnsupportedSql(format!(
+                "Creating {STAT1_TABLE} in auto-vacuum databases is unimplemented"
+            ))
+        );
+        let root_page = self.pager.allocate_page()?;
+        btree::init(&mut self.pager, root_page)?;
+        let text = |text: &str| Value::String(text.as_bytes().into());
+        let record: [OwnedValue; 5] = [
+            text("table"),
+            text(STAT1_TABLE),
+            text(STAT1_TABLE),
+            Value::int(root_page as i64),
+            text(STAT1_SQL),
+        ];
+        // `sqlite_schema` is always rooted at the first page
+        let rowid = btree::max_rowid(&mut self.pager, 1)?.map_or(1, |max| max + 1);
+        let encoding = self.pager.text_encoding();
+        btree::insert(
+            &mut self.pager,
+            1,
+            rowid,
+            &Record::build_encoded(&record, encoding),
+        )?;
+        self.pager.change_schema_cookie()?;
+        Ok(root_page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::tests::{open_rw, query, run, temp_copy},
+        Database,
+    };
+
+    /// Read `(tbl, idx, stat)` for every row of `sqlite_stat1`.
+    fn stat1(db: &mut Database) -> Vec<Vec<String>> {
+        query(db, "SELECT * FROM sqlite_stat1")
+            .expect("Failed to query")
+            .iter()
+            .map(|row| row.iter().map(ToString::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_analyze() {
+        let path = temp_copy("test-data/constraints.sqlite", "analyze");
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM tags").expect("Failed to delete");
+        run(
+            &mut db,
+            "INSERT INTO users(email, name, team) VALUES ('c@example.com', 'carol', 1), \
+             (NULL, 'carol', 2), (NULL, 'dave', 2)",
+        )
+        .expect("Failed to insert");
+        db.analyze().expect("Failed to analyze");
+        assert_eq!(
+            stat1(&mut db),
+            [
+                [r#""users""#, r#""sqlite_autoindex_users_1""#, r#""5 2""#],
+                [r#""users""#, r#""sqlite_autoindex_users_2""#, r#""5 2 1""#],
+                [r#""users""#, r#""users_team""#, r#""5 3""#],
+            ],
+            "The empty tags table should be left out",
+        );
+        // Analyzing again replaces the old statistics.
+        run(&mut db, "INSERT INTO tags VALUES ('x', 1)").expect("Failed to insert");
+        db.analyze().expect("Failed to analyze");
+        assert_eq!(
+            stat1(&mut db)[3],
+            [r#""tags""#, r#""sqlite_autoindex_tags_1""#, r#""1 1""#],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,97 @@
+//! Copying the database to another file with `.backup`
+
+use std::{io::Write, path::Path};
+
+use anyhow::{Context, Result};
+
+use super::Database;
+use crate::pager::{LockLevel, Storage};
+
+impl<File: Storage> Database<File> {
+    /// Copy the database to a new file at `path`, page by page, as the `sqlite3` shell's
+    /// `.backup` command does.
+    ///
+    /// The pages are read under a shared lock, like any other read, so the copy is of the
+    /// database as it was at one moment even while other connections write to it. Changes which
+    /// haven't been committed can't be copied, so this fails if any have been made. Pages are
+    /// copied as they're read, after any [`PageCodec`](crate::pager::PageCodec) has decoded them.
+    ///
+    /// The copy is written next to `path` and then moved there, replacing any file already
+    /// there, so a backup which fails partway through leaves it as it was.
+    pub fn backup(&mut self, path: impl AsRef<Path>) -> crate::Result<()> {
+        if self.pager.lock_level() >= LockLevel::Reserved {
+            return Err(crate::Error::Other(
+                "cannot back up a database with uncommitted changes".to_owned(),
+            ));
+        }
+        let path = path.as_ref();
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(format!("-backup-{}", std::process::id()));
+        let temp_path = Path::new(&temp_name);
+        let result = self
+            .with_lock(|db| db.write_backup(temp_path))
+            .and_then(|()| std::fs::rename(temp_path, path).context("Failed to move backup"));
+        if result.is_err() {
+            // The partial copy is of no use, and may not even have been made.
+            let _ = std::fs::remove_file(temp_path);
+        }
+        Ok(result?)
+    }
+
+    /// Write every page of the database to a new file at `path`, while holding a lock on it.
+    fn write_backup(&mut self, path: &Path) -> Result<()> {
+        let mut file = std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context("Failed to create backup")?;
+        for page_idx in 1..=self.pager.page_count() {
+            file.write_all(self.pager.read_page_bytes(page_idx)?)
+                .context("Failed to write backup")?;
+        }
+        file.sync_all().context("Failed to write backup")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use crate::{
+        db::tests::{open_rw, query, run, temp_copy},
+        record::Value,
+        Database,
+    };
+
+    #[test]
+    fn test_backup() {
+        let mut db = Database::new(
+            File::open("test-data/constraints.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let path = std::env::temp_dir().join(format!("sqlite-riir-backup-{}", std::process::id()));
+        db.backup(&path).expect("Failed to back up database");
+        assert_eq!(
+            std::fs::read(&path).expect("Failed to read backup"),
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database"),
+        );
+        std::fs::remove_file(&path).expect("Failed to clean up");
+
+        let source = temp_copy("test-data/constraints.sqlite", "backup-source");
+        let mut db = open_rw(&source);
+        run(&mut db, "BEGIN").unwrap();
+        run(&mut db, "INSERT INTO tags VALUES ('y', 2)").expect("Failed to insert");
+        assert!(db.backup(&path).is_err());
+        assert!(!path.exists(), "A failed backup shouldn't leave a file");
+        run(&mut db, "COMMIT").unwrap();
+        db.backup(&path).expect("Failed to back up database");
+        let mut copy = Database::new(File::open(&path).expect("Failed to open backup"))
+            .expect("Failed to parse backup");
+        assert_eq!(
+            query(&mut copy, "SELECT n FROM tags").expect("Failed to read backup"),
+            [[Value::int(1)], [Value::int(2)]],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+        std::fs::remove_file(source).expect("Failed to clean up");
+    }
+}
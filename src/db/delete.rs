@@ -0,0 +1,112 @@
+//! Executing `DELETE` statements
+
+use anyhow::Result;
+use sqlparser::ast::FromTable;
+
+use super::{
+    insert::{column_value, WritableTable},
+    plain_table_name,
+    returning::Returning,
+    Database,
+};
+use crate::{
+    btree,
+    expr::{evaluate, truth},
+    pager::Storage,
+    record::OwnedValue,
+    Error,
+};
+
+impl<File: Storage> Database<File> {
+    /// Execute a `DELETE` statement, returning the rows its `RETURNING` clause gives, if any.
+    pub(super) fn execute_delete(
+        &mut self,
+        delete: &sqlparser::ast::Delete,
+    ) -> Result<Vec<Vec<OwnedValue>>> {
+        let sqlparser::ast::Delete {
+            tables,
+            from: FromTable::WithFromKeyword(from),
+            using: None,
+            selection,
+            returning,
+            order_by,
+            limit: None,
+        } = delete
+        else {
+            anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented DELETE arguments".to_owned()
+            ));
+        };
+        anyhow::ensure!(
+            tables.is_empty() && order_by.is_empty(),
+            Error::UnsupportedSql("Unimplemented DELETE arguments".to_owned())
+        );
+        let Some(table_name) = from
+            .first()
+            .take_if(|_| from.len() == 1)
+            .and_then(plain_table_name)
+        else {
+            anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented DELETE target".to_owned()
+            ));
+        };
+
+        let WritableTable {
+            schema,
+            root_page,
+            indexes,
+        } = self.writable_table(table_name)?;
+        let returning = Returning::resolve(&schema, returning.as_ref())?;
+        if selection.is_none() && returning.is_none() {
+            // Like SQLite, every row can be dropped at once when none need looking at.
+            btree::clear(&mut self.pager, root_page)?;
+            for (index_root, _) in &indexes {
+                btree::clear(&mut self.pager, *index_root)?;
+            }
+            return Ok(Vec::new());
+        }
+        let mut returned = Vec::new();
+        for rowid in self.candidate_rowids(&schema, root_page, &indexes, selection.as_ref())? {
+            let row = self.read_row(&schema, root_page, rowid)?;
+            self.rows_examined += 1;
+            if let Some(selection) = selection {
+                let matches = evaluate(selection, &mut |name| {
+                    column_value(&schema, &row, rowid, name)
+                })?;
+                if truth(&matches) != Some(true) {
+                    continue;
+                }
+            }
+            if let Some(returning) = &returning {
+                returned.push(returning.row(&schema, &row, rowid)?);
+            }
+            self.delete_row(&schema, root_page, &indexes, rowid)?;
+        }
+        Ok(returned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::tests::{open_rw, run, temp_copy};
+
+    #[test]
+    fn test_delete() {
+        let path = temp_copy("test-data/constraints.sqlite", "delete");
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM users WHERE team > 1 OR name IS NULL").expect("Failed to delete");
+        assert_eq!(db.table("users").unwrap().count().unwrap(), 1);
+        // The deleted row is gone from the indexes too.
+        run(
+            &mut db,
+            "INSERT INTO users(email, name, team) VALUES ('b@example.com', 'bob', 2)",
+        )
+        .expect("Failed to insert");
+
+        run(&mut db, "DELETE FROM users; DELETE FROM tags").expect("Failed to delete");
+        assert_eq!(db.table("users").unwrap().count().unwrap(), 0);
+        assert_eq!(db.table("tags").unwrap().count().unwrap(), 0);
+        run(&mut db, "INSERT INTO tags VALUES ('x', 1)").expect("Failed to insert");
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
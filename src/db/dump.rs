@@ -0,0 +1,228 @@
+//! Writing the database out as SQL with `.dump`
+//!
+//! The output is laid out like the `sqlite3` shell's: the tables with an `INSERT` for each of
+//! their rows, then the indexes, views and triggers, all in one transaction. Running it on an
+//! empty database rebuilds the original one.
+
+use std::{fmt::Write as _, io::Write};
+
+use anyhow::{Context, Result};
+
+use super::{plan::STAT1_TABLE, sequence::SEQUENCE_TABLE, Database};
+use crate::{
+    pager::Storage,
+    record::{OwnedValue, Value},
+    schema::{ObjectKind, SchemaObject},
+};
+
+impl<File: Storage> Database<File> {
+    /// Write SQL statements which recreate the database to `out`, as the `sqlite3` shell's
+    /// `.dump` command does.
+    ///
+    /// If `pattern` is given, only the tables whose names match it as a SQL `LIKE` pattern are
+    /// written, along with their indexes and triggers.
+    pub fn dump(&mut self, pattern: Option<&str>, out: &mut impl Write) -> crate::Result<()> {
+        let matches = |object: &SchemaObject| {
+            pattern.map_or(true, |pattern| {
+                crate::like_matches(pattern, &object.table_name)
+            })
+        };
+        let (tables, others): (Vec<_>, Vec<_>) = self
+            .schema
+            .objects
+            .iter()
+            .filter(|object| matches(object))
+            // Automatic indexes are made along with their tables
+            .filter(|object| object.sql.is_some())
+            .cloned()
+            .partition(|object| object.kind == ObjectKind::Table);
+
+        self.clear_interrupt();
+        writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+        writeln!(out, "BEGIN TRANSACTION;")?;
+        for table in &tables {
+            self.dump_table(table, out)
+                .with_context(|| format!("Failed to dump table {}", table.name))?;
+        }
+        for object in &others {
+            writeln!(out, "{};", object.sql.as_deref().unwrap_or_default())?;
+        }
+        writeln!(out, "COMMIT;")?;
+        Ok(())
+    }
+
+    /// Write the statements which recreate `table` and its rows to `out`.
+    fn dump_table(&mut self, table: &SchemaObject, out: &mut impl Write) -> Result<()> {
+        let name = table.name.as_str();
+        if name.eq_ignore_ascii_case(SEQUENCE_TABLE) {
+            // `sqlite_sequence` is made along with the first `AUTOINCREMENT` table, and may
+            // already have rows for those tables by the time it's filled in.
+            writeln!(out, "DELETE FROM {SEQUENCE_TABLE};")?;
+        } else if name.eq_ignore_ascii_case(STAT1_TABLE) {
+            // `sqlite_stat1` can't be created directly, but `ANALYZE` makes an empty one.
+            writeln!(out, "ANALYZE sqlite_schema;")?;
+        } else if name.to_ascii_lowercase().starts_with("sqlite_") {
+            // Any other internal tables are rebuilt by SQLite itself.
+            return Ok(());
+        } else {
+            writeln!(out, "{};", table.sql.as_deref().unwrap_or_default())?;
+        }
+        // Virtual tables have no btree, so their rows aren't stored in the database.
+        if table.root_page.map_or(true, |root_page| root_page == 0) {
+            return Ok(());
+        }
+        let schema = self.schema.table(name).cloned();
+        anyhow::ensure!(
+            schema.as_ref().map_or(true, |schema| !schema.without_rowid),
+            crate::Error::UnsupportedSql(
+                "Dumping tables without rowids is unimplemented".to_owned()
+            )
+        );
+        // Rows written before a column was added don't have a value for it, so they get the
+        // column's default.
+        let defaults = schema
+            .iter()
+            .flat_map(|schema| &schema.columns)
+            .map(|column| column.default.clone().unwrap_or_else(|| "NULL".to_owned()))
+            .collect::<Vec<_>>();
+        let insert = format!("INSERT INTO {} VALUES(", quote_identifier(name));
+        self.table(name)?.for_each_row(|row| {
+            let mut values = row
+                .values()
+                .map(|value| quote_value(&value.decode_text(row.encoding())))
+                .collect::<Vec<_>>();
+            values.extend(defaults.iter().skip(values.len()).cloned());
+            writeln!(out, "{insert}{});", values.join(","))?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+/// Quote `name` for use as an identifier, if it needs quoting.
+pub(super) fn quote_identifier(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && sqlparser::keywords::ALL_KEYWORDS
+            .binary_search(&name.to_ascii_uppercase().as_str())
+            .is_err();
+    if plain {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// Write `value` as a SQL literal which reads back as the same value.
+pub(super) fn quote_value(value: &OwnedValue) -> String {
+    match value {
+        Value::Null | Value::SQLiteReserved => "NULL".to_owned(),
+        // SQLite stores NaN as `NULL`, so it can only come from a corrupt database.
+        Value::F64(n) if n.is_nan() => "NULL".to_owned(),
+        Value::Int(n, _) => n.to_string(),
+        // Too large a number is read back as infinity.
+        Value::F64(n) if n.is_infinite() && n.is_sign_positive() => "9.0e+999".to_owned(),
+        Value::F64(n) if n.is_infinite() => "-9.0e+999".to_owned(),
+        // `Debug` gives the shortest text which reads back as the same number, and always has a
+        // `.` or an exponent, so it isn't read back as an integer.
+        Value::F64(n) => format!("{n:?}"),
+        Value::Blob(blob) => quote_blob(blob),
+        // Text which isn't valid UTF-8, or has control characters which could be mangled on the
+        // way, is written as its bytes so that it reads back exactly.
+        Value::String(text) => match std::str::from_utf8(text) {
+            Ok(text)
+                if !text
+                    .chars()
+                    .any(|c| c.is_control() && c != '\n' && c != '\t') =>
+            {
+                format!("'{}'", text.replace('\'', "''"))
+            }
+            _ => format!("CAST({} AS TEXT)", quote_blob(text)),
+        },
+    }
+}
+
+/// Write `blob` as a SQL blob literal.
+fn quote_blob(blob: &[u8]) -> String {
+    let mut literal = String::with_capacity(blob.len() * 2 + 3);
+    literal.push_str("X'");
+    for byte in blob {
+        // Writing to a `String` can't fail.
+        let _ = write!(literal, "{byte:02x}");
+    }
+    literal.push('\'');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::{quote_identifier, quote_value};
+    use crate::{record::Value, Database};
+
+    #[test]
+    fn test_dump() {
+        let mut db = Database::new(
+            File::open("test-data/constraints.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let mut out = Vec::new();
+        db.dump(None, &mut out).expect("Failed to dump database");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE users(id INTEGER PRIMARY KEY, email TEXT UNIQUE, name TEXT, team, UNIQUE(name, team));
+INSERT INTO users VALUES(1,'a@example.com','alice',1);
+INSERT INTO users VALUES(2,'b@example.com','bob',2);
+CREATE TABLE tags(name TEXT PRIMARY KEY, n);
+INSERT INTO tags VALUES('x',1);
+CREATE INDEX users_team ON users(team);
+COMMIT;
+",
+        );
+
+        let mut out = Vec::new();
+        db.dump(Some("TAGS"), &mut out)
+            .expect("Failed to dump database");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE tags(name TEXT PRIMARY KEY, n);
+INSERT INTO tags VALUES('x',1);
+COMMIT;
+",
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        for (value, literal) in [
+            (Value::Null, "NULL"),
+            (Value::int(-7), "-7"),
+            (Value::F64(1.0), "1.0"),
+            (Value::F64(0.1 + 0.2), "0.30000000000000004"),
+            (Value::F64(1e300), "1e300"),
+            (Value::F64(f64::NEG_INFINITY), "-9.0e+999"),
+            (Value::Blob(Box::from(&[0x00, 0xAB][..])), "X'00ab'"),
+            (Value::String(Box::from(&b"it's"[..])), "'it''s'"),
+            (Value::String(Box::from(&b"two\nlines"[..])), "'two\nlines'"),
+            (
+                Value::String(Box::from(&b"a\0b"[..])),
+                "CAST(X'610062' AS TEXT)",
+            ),
+            (
+                Value::String(Box::from(&b"\xff"[..])),
+                "CAST(X'ff' AS TEXT)",
+            ),
+        ] {
+            assert_eq!(quote_value(&value), literal);
+        }
+        assert_eq!(quote_identifier("users"), "users");
+        assert_eq!(quote_identifier("odd \"name\""), "\"odd \"\"name\"\"\"");
+        assert_eq!(quote_identifier("select"), "\"select\"");
+        assert_eq!(quote_identifier("2fast"), "\"2fast\"");
+    }
+}
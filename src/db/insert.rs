@@ -0,0 +1,1105 @@
+//! Executing `INSERT` statements
+
+use std::hash::{BuildHasher, RandomState};
+
+use anyhow::{Context, Result};
+use sqlparser::ast::{
+    Assignment, AssignmentTarget, ConflictTarget, DoUpdate, Expr, Ident, OnConflict,
+    OnConflictAction, OnInsert, SqliteOnConflict,
+};
+
+use super::{returning::Returning, sequence::Sequence, Database};
+use crate::{
+    btree,
+    expr::{evaluate, evaluate_constant, truth},
+    pager::Storage,
+    record::{OwnedValue, Record, Value},
+    schema::{IndexColumn, IndexSchema, TableSchema},
+    Error,
+};
+
+/// The names which refer to the rowid of a table, unless a column has the same name.
+pub(crate) const ROWID_NAMES: &[&str] = &["rowid", "_rowid_", "oid"];
+
+/// The name `DO UPDATE` expressions use for the row which couldn't be inserted.
+const EXCLUDED_NAME: &str = "excluded";
+
+/// How many random rowids to try before giving up, once the largest rowid has been used.
+const RANDOM_ROWID_ATTEMPTS: usize = 100;
+
+/// Where a value given in an `INSERT` goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum InsertTarget {
+    /// The column with the given index
+    Column(usize),
+    /// The rowid
+    Rowid,
+}
+
+/// A table which a statement is changing.
+pub(super) struct WritableTable {
+    /// The table's definition
+    pub(super) schema: TableSchema,
+    /// The page the table's btree is rooted at
+    pub(super) root_page: usize,
+    /// The table's indexes, each with the page its btree is rooted at
+    pub(super) indexes: Vec<(usize, IndexSchema)>,
+}
+
+/// An existing row which a new row conflicts with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Conflict {
+    /// The indices of the columns in the violated constraint, in ascending order
+    ///
+    /// This is empty for a conflicting rowid, unless the table has a rowid alias, and for an index
+    /// on expressions.
+    columns: Vec<usize>,
+    /// The columns in the violated constraint, in the form SQLite's error messages use
+    description: String,
+    /// The rowid of the existing row
+    rowid: i64,
+}
+
+/// An `ON CONFLICT` clause, resolved against the table's schema.
+struct Upsert<'a> {
+    /// The columns of the constraint this applies to, in ascending order, or `None` if it applies
+    /// to every constraint
+    target: Option<Vec<usize>>,
+    /// The changes to make to the existing row, or `None` to leave it alone
+    update: Option<Update<'a>>,
+}
+
+/// The changes made to a row by an `UPDATE` statement or the `DO UPDATE` part of an
+/// `ON CONFLICT` clause.
+pub(super) struct Update<'a> {
+    /// The value to assign to each column
+    assignments: Vec<(InsertTarget, &'a Expr)>,
+    /// The condition for updating the row, if any
+    selection: Option<&'a Expr>,
+}
+
+impl<File: Storage> Database<File> {
+    /// Execute an `INSERT` statement, returning the rows its `RETURNING` clause gives, if any.
+    pub(super) fn execute_insert(
+        &mut self,
+        insert: &sqlparser::ast::Insert,
+    ) -> Result<Vec<Vec<OwnedValue>>> {
+        let sqlparser::ast::Insert {
+            or,
+            ignore: false,
+            into: _,
+            table_name,
+            table_alias: None,
+            columns,
+            overwrite: false,
+            source: Some(source),
+            partitioned: None,
+            after_columns,
+            table: false,
+            on,
+            returning,
+            replace_into: false,
+            priority: None,
+            insert_alias: None,
+        } = insert
+        else {
+            anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented INSERT arguments".to_owned()
+            ));
+        };
+        anyhow::ensure!(
+            after_columns.is_empty(),
+            Error::UnsupportedSql("Unimplemented INSERT arguments".to_owned())
+        );
+        let resolution = match or {
+            None | Some(SqliteOnConflict::Abort) => SqliteOnConflict::Abort,
+            Some(resolution @ (SqliteOnConflict::Replace | SqliteOnConflict::Ignore)) => {
+                *resolution
+            }
+            Some(resolution) => anyhow::bail!(Error::UnsupportedSql(format!(
+                "Unimplemented conflict resolution: {resolution}"
+            ))),
+        };
+        let Some(table_name) = table_name.0.first().take_if(|_| table_name.0.len() == 1) else {
+            anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented INSERT target".to_owned()
+            ));
+        };
+        let table_name = &table_name.value;
+        let sqlparser::ast::SetExpr::Values(values) = source.body.as_ref() else {
+            anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented INSERT source".to_owned()
+            ));
+        };
+
+        let WritableTable {
+            schema,
+            root_page,
+            indexes,
+        } = self.writable_table(table_name)?;
+        let returning = Returning::resolve(&schema, returning.as_ref())?;
+        let upsert = match on {
+            None => None,
+            Some(OnInsert::OnConflict(on_conflict)) => {
+                Some(Upsert::resolve(&schema, &indexes, on_conflict)?)
+            }
+            Some(_) => anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented INSERT arguments".to_owned()
+            )),
+        };
+
+        let targets = if columns.is_empty() {
+            (0..schema.columns.len())
+                .map(InsertTarget::Column)
+                .collect::<Vec<_>>()
+        } else {
+            columns
+                .iter()
+                .map(|column| {
+                    resolve_column(&schema, &column.value).ok_or_else(|| {
+                        Error::NotFound(format!(
+                            "table {table_name} has no column named {}",
+                            column.value
+                        ))
+                    })
+                })
+                .collect::<crate::Result<Vec<_>>>()?
+        };
+
+        let mut sequence = schema
+            .autoincrement
+            .then(|| self.read_sequence(&schema.name))
+            .transpose()?;
+        let mut returned = Vec::new();
+        'rows: for row in &values.rows {
+            anyhow::ensure!(
+                row.len() == targets.len(),
+                Error::Sql(format!(
+                    "{} values for {} columns",
+                    row.len(),
+                    targets.len()
+                ))
+            );
+            let mut record = vec![Value::Null; schema.columns.len()];
+            let mut rowid = None;
+            for (target, expr) in targets.iter().zip(row) {
+                let value = evaluate_constant(expr)?;
+                match *target {
+                    InsertTarget::Column(idx) if Some(idx) == schema.rowid_alias => {
+                        rowid = rowid_from_value(&value)?;
+                    }
+                    InsertTarget::Column(idx) => {
+                        record[idx] = value.apply_affinity(schema.columns[idx].affinity());
+                    }
+                    InsertTarget::Rowid => rowid = rowid_from_value(&value)?,
+                }
+            }
+            let rowid = match (rowid, &sequence) {
+                (Some(rowid), _) => rowid,
+                (None, Some(sequence)) => self.next_autoincrement_rowid(root_page, sequence)?,
+                (None, None) => self.new_rowid(root_page)?,
+            };
+            // Like SQLite, the rowid is used up even if the row turns out not to be inserted.
+            if let Some(sequence) = &mut sequence {
+                sequence.value = sequence.value.max(rowid);
+            }
+            while let Some(conflict) =
+                self.find_conflict(&schema, root_page, &indexes, &record, rowid)?
+            {
+                if let Some(upsert) = upsert.as_ref().filter(|upsert| upsert.applies(&conflict)) {
+                    let Some(update) = &upsert.update else {
+                        continue 'rows;
+                    };
+                    let updated = self.upsert_row(
+                        &schema,
+                        root_page,
+                        &indexes,
+                        update,
+                        conflict.rowid,
+                        (&record, rowid),
+                    )?;
+                    if let (Some(returning), Some((new, new_rowid))) = (&returning, updated) {
+                        returned.push(returning.row(&schema, &new, new_rowid)?);
+                    }
+                    continue 'rows;
+                }
+                match resolution {
+                    SqliteOnConflict::Replace => {
+                        self.delete_row(&schema, root_page, &indexes, conflict.rowid)?;
+                    }
+                    SqliteOnConflict::Ignore => continue 'rows,
+                    _ => anyhow::bail!(Error::Constraint(format!(
+                        "UNIQUE constraint failed: {}",
+                        conflict.description
+                    ))),
+                }
+            }
+            self.insert_row(&schema, root_page, &indexes, &record, rowid)?;
+            if let Some(returning) = &returning {
+                returned.push(returning.row(&schema, &record, rowid)?);
+            }
+        }
+        if let Some(sequence) = sequence {
+            self.write_sequence(&schema.name, sequence)?;
+        }
+        Ok(returned)
+    }
+
+    /// Look up a table which a statement is going to change.
+    pub(super) fn writable_table(&self, table_name: &str) -> Result<WritableTable> {
+        let root_page = self.table_root_page(table_name)?;
+        // The schema is only read when the database is opened, so it mustn't change under us.
+        anyhow::ensure!(
+            root_page != 1,
+            Error::Sql(format!("table {table_name} may not be modified"))
+        );
+        let schema = self.table_schema(table_name)?;
+        anyhow::ensure!(
+            !schema.without_rowid,
+            Error::UnsupportedSql("Writing to WITHOUT ROWID tables is unimplemented".to_owned())
+        );
+        let indexes = self.table_indexes(&schema)?;
+        Ok(WritableTable {
+            schema,
+            root_page,
+            indexes,
+        })
+    }
+
+    /// Apply the `DO UPDATE` part of an upsert to the row with rowid `existing`, which the row
+    /// `proposed` (with its rowid) conflicted with.
+    ///
+    /// Returns the updated row and its rowid, or `None` if the `WHERE` clause left it alone.
+    fn upsert_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        update: &Update,
+        existing: i64,
+        proposed: (&[OwnedValue], i64),
+    ) -> Result<Option<(Vec<OwnedValue>, i64)>> {
+        let old = self.read_row(schema, root_page, existing)?;
+        self.rows_examined += 1;
+        // Plain names refer to the existing row, and `excluded` to the one which wasn't inserted.
+        let mut column = |name: &[Ident]| match name {
+            [table, column] if table.value.eq_ignore_ascii_case(EXCLUDED_NAME) => {
+                row_value(schema, proposed.0, proposed.1, &column.value).with_context(|| {
+                    Error::NotFound(format!("no such column: {}", display_name(name)))
+                })
+            }
+            _ => column_value(schema, &old, existing, name),
+        };
+        self.update_row(
+            schema,
+            root_page,
+            indexes,
+            update,
+            &mut column,
+            (&old, existing),
+        )
+    }
+
+    /// Apply an update to the row `old` with the given rowid, calling `column` to get the value
+    /// of each column the update refers to.
+    ///
+    /// Returns the updated row and its rowid, or `None` if the `WHERE` clause left it alone.
+    pub(super) fn update_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        update: &Update,
+        column: &mut dyn FnMut(&[Ident]) -> Result<OwnedValue>,
+        (old, rowid): (&[OwnedValue], i64),
+    ) -> Result<Option<(Vec<OwnedValue>, i64)>> {
+        if let Some(selection) = update.selection {
+            if truth(&evaluate(selection, column)?) != Some(true) {
+                return Ok(None);
+            }
+        }
+        // Every assignment sees the values from before any of them were applied.
+        let mut new = old.to_vec();
+        let mut new_rowid = rowid;
+        for (target, expr) in &update.assignments {
+            let value = evaluate(expr, column)?;
+            match *target {
+                InsertTarget::Column(idx) if Some(idx) != schema.rowid_alias => {
+                    new[idx] = value.apply_affinity(schema.columns[idx].affinity());
+                }
+                _ => {
+                    new_rowid = rowid_from_value(&value)?.ok_or_else(|| {
+                        Error::Mismatch("datatype mismatch: rowid must be an integer".to_owned())
+                    })?;
+                }
+            }
+        }
+
+        self.delete_row(schema, root_page, indexes, rowid)?;
+        if let Some(conflict) = self.find_conflict(schema, root_page, indexes, &new, new_rowid)? {
+            anyhow::bail!(Error::Constraint(format!(
+                "UNIQUE constraint failed: {}",
+                conflict.description
+            )));
+        }
+        self.insert_row(schema, root_page, indexes, &new, new_rowid)?;
+        Ok(Some((new, new_rowid)))
+    }
+
+    /// Add a row to the table and its indexes.
+    fn insert_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        record: &[OwnedValue],
+        rowid: i64,
+    ) -> Result<()> {
+        let encoding = self.pager.text_encoding();
+        btree::insert(
+            &mut self.pager,
+            root_page,
+            rowid,
+            &Record::build_encoded(record, encoding),
+        )?;
+        for (index_root, index) in indexes {
+            let entry = index_entry(schema, index, record, rowid)?;
+            btree::index::insert(
+                &mut self.pager,
+                *index_root,
+                &Record::build_encoded(&entry, encoding),
+                &index.descending,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove a row from the table and its indexes.
+    pub(super) fn delete_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        rowid: i64,
+    ) -> Result<()> {
+        let record = self.read_row(schema, root_page, rowid)?;
+        let encoding = self.pager.text_encoding();
+        for (index_root, index) in indexes {
+            let entry = index_entry(schema, index, &record, rowid)?;
+            anyhow::ensure!(
+                btree::index::delete(
+                    &mut self.pager,
+                    *index_root,
+                    &Record::build_encoded(&entry, encoding),
+                    &index.descending,
+                )?,
+                "Index {} is missing the entry for rowid {rowid}",
+                index.name
+            );
+        }
+        btree::delete(&mut self.pager, root_page, rowid)?;
+        Ok(())
+    }
+
+    /// Read the values of the row with the given rowid, with one for every column.
+    pub(super) fn read_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        rowid: i64,
+    ) -> Result<Vec<OwnedValue>> {
+        let payload = btree::find_row(&mut self.pager, root_page, rowid)?
+            .with_context(|| format!("No row with rowid {rowid} in {}", schema.name))?;
+        let encoding = self.pager.text_encoding();
+        let mut record = Record::parse(&payload)?
+            .value_iter()
+            .map(|value| value.decode_text(encoding))
+            .collect::<Vec<_>>();
+        // Rows written before a column was added don't have a value for it.
+        // TODO Use the column's default value
+        record.resize(schema.columns.len(), Value::Null);
+        Ok(record)
+    }
+
+    /// Find an existing row which inserting a row would conflict with, because of a `UNIQUE` or
+    /// `PRIMARY KEY` constraint.
+    fn find_conflict(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        record: &[OwnedValue],
+        rowid: i64,
+    ) -> Result<Option<Conflict>> {
+        let describe = |columns: &[usize]| {
+            columns
+                .iter()
+                .map(|&idx| format!("{}.{}", schema.name, schema.columns[idx].name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if btree::contains_rowid(&mut self.pager, root_page, rowid)? {
+            let columns = schema.rowid_alias.into_iter().collect::<Vec<_>>();
+            let description = if columns.is_empty() {
+                format!("{}.rowid", schema.name)
+            } else {
+                describe(&columns)
+            };
+            return Ok(Some(Conflict {
+                columns,
+                description,
+                rowid,
+            }));
+        }
+        for (index_root, index) in indexes {
+            if !index.unique {
+                continue;
+            }
+            let entry = index_entry(schema, index, record, rowid)?;
+            let key = &entry[..index.columns.len()];
+            // NULLs are distinct from each other, so they never conflict.
+            if key.iter().any(Value::is_null) {
+                continue;
+            }
+            let key_record = Record::build_encoded(key, self.pager.text_encoding());
+            if let Some(existing) = btree::index::find_key(
+                &mut self.pager,
+                *index_root,
+                &key_record,
+                key.len(),
+                &index.descending,
+            )? {
+                let existing = Record::parse(&existing)?
+                    .value_iter()
+                    .last()
+                    .context("Index entry has no rowid")?
+                    .get::<i64>()
+                    .context("Invalid rowid in index entry")?;
+                let (columns, description) = match index.column_indices() {
+                    Some(columns) => {
+                        let description = describe(&columns);
+                        let mut columns = columns;
+                        columns.sort_unstable();
+                        (columns, description)
+                    }
+                    // Like SQLite, indexes on expressions are described by name.
+                    None => (Vec::new(), format!("index '{}'", index.name)),
+                };
+                return Ok(Some(Conflict {
+                    columns,
+                    description,
+                    rowid: existing,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Choose a rowid for a new row in the `AUTOINCREMENT` table rooted at `root_page`, which is
+    /// one more than the largest rowid the table has ever used.
+    fn next_autoincrement_rowid(&mut self, root_page: usize, sequence: &Sequence) -> Result<i64> {
+        let max = btree::max_rowid(&mut self.pager, root_page)?
+            .unwrap_or(0)
+            .max(sequence.value);
+        max.checked_add(1)
+            .context("database or disk is full: AUTOINCREMENT rowids are used up")
+    }
+
+    /// Choose a rowid for a new row in the table rooted at `root_page`.
+    ///
+    /// Like SQLite, this is one more than the largest rowid in the table, unless that rowid has
+    /// been used, in which case unused rowids are picked at random.
+    fn new_rowid(&mut self, root_page: usize) -> Result<i64> {
+        match btree::max_rowid(&mut self.pager, root_page)? {
+            None => Ok(1),
+            Some(i64::MAX) => {
+                let random = RandomState::new();
+                for attempt in 0..RANDOM_ROWID_ATTEMPTS {
+                    let rowid = (random.hash_one(attempt) >> 1) as i64;
+                    if rowid > 0 && !btree::contains_rowid(&mut self.pager, root_page, rowid)? {
+                        return Ok(rowid);
+                    }
+                }
+                anyhow::bail!("database or disk is full: failed to find an unused rowid")
+            }
+            Some(max) => Ok(max + 1),
+        }
+    }
+}
+
+impl<'a> Upsert<'a> {
+    /// Check an `ON CONFLICT` clause against the table it's inserting into.
+    fn resolve(
+        schema: &TableSchema,
+        indexes: &[(usize, IndexSchema)],
+        on_conflict: &'a OnConflict,
+    ) -> Result<Self> {
+        let target = match &on_conflict.conflict_target {
+            None => None,
+            Some(ConflictTarget::Columns(columns)) => {
+                let mut target = columns
+                    .iter()
+                    .map(|column| match resolve_column(schema, &column.value) {
+                        Some(InsertTarget::Column(idx)) => Ok(idx),
+                        _ => anyhow::bail!(Error::NotFound(format!(
+                            "no such column: {}",
+                            column.value
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                target.sort_unstable();
+                target.dedup();
+                let is_constraint = schema.rowid_alias.is_some_and(|alias| target == [alias])
+                    || indexes.iter().any(|(_, index)| {
+                        index.unique
+                            && index.column_indices().is_some_and(|mut columns| {
+                                columns.sort_unstable();
+                                columns == target
+                            })
+                    });
+                anyhow::ensure!(
+                    is_constraint,
+                    Error::Sql(
+                        "ON CONFLICT clause does not match any PRIMARY KEY or UNIQUE constraint"
+                            .to_owned()
+                    )
+                );
+                Some(target)
+            }
+            Some(ConflictTarget::OnConstraint(_)) => {
+                anyhow::bail!(Error::UnsupportedSql(
+                    "Unimplemented ON CONFLICT target".to_owned()
+                ))
+            }
+        };
+        let update = match &on_conflict.action {
+            OnConflictAction::DoNothing => None,
+            OnConflictAction::DoUpdate(DoUpdate {
+                assignments,
+                selection,
+            }) => Some(Update::resolve(schema, assignments, selection.as_ref())?),
+        };
+        Ok(Self { target, update })
+    }
+
+    /// Whether this clause handles the given conflict.
+    fn applies(&self, conflict: &Conflict) -> bool {
+        self.target
+            .as_ref()
+            .map_or(true, |target| *target == conflict.columns)
+    }
+}
+
+impl<'a> Update<'a> {
+    /// Check the assignments of an update against the table it changes.
+    pub(super) fn resolve(
+        schema: &TableSchema,
+        assignments: &'a [Assignment],
+        selection: Option<&'a Expr>,
+    ) -> Result<Self> {
+        let assignments = assignments
+            .iter()
+            .map(|assignment| {
+                let AssignmentTarget::ColumnName(name) = &assignment.target else {
+                    anyhow::bail!(Error::UnsupportedSql(
+                        "Unimplemented assignment target".to_owned()
+                    ));
+                };
+                let column = match name.0.as_slice() {
+                    [column] => column,
+                    [table, column] if table.value.eq_ignore_ascii_case(&schema.name) => column,
+                    _ => anyhow::bail!(Error::NotFound(format!("no such column: {name}"))),
+                };
+                let target = resolve_column(schema, &column.value)
+                    .with_context(|| Error::NotFound(format!("no such column: {name}")))?;
+                Ok((target, &assignment.value))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            assignments,
+            selection,
+        })
+    }
+}
+
+/// Get the value of the column called `name` in a row of the table, where `name` may be
+/// qualified with the table's name.
+pub(super) fn column_value(
+    schema: &TableSchema,
+    row: &[OwnedValue],
+    rowid: i64,
+    name: &[Ident],
+) -> Result<OwnedValue> {
+    let value = match name {
+        [column] => row_value(schema, row, rowid, &column.value),
+        [table, column] if table.value.eq_ignore_ascii_case(&schema.name) => {
+            row_value(schema, row, rowid, &column.value)
+        }
+        _ => None,
+    };
+    value.with_context(|| Error::NotFound(format!("no such column: {}", display_name(name))))
+}
+
+/// Get the value of the column called `name` in a row of the table.
+fn row_value(
+    schema: &TableSchema,
+    row: &[OwnedValue],
+    rowid: i64,
+    name: &str,
+) -> Option<OwnedValue> {
+    match resolve_column(schema, name)? {
+        InsertTarget::Column(idx) if Some(idx) != schema.rowid_alias => Some(row[idx].clone()),
+        _ => Some(Value::int(rowid)),
+    }
+}
+
+/// Find where the column with the given name is, including the names for the rowid.
+fn resolve_column(schema: &TableSchema, name: &str) -> Option<InsertTarget> {
+    if let Some(idx) = schema.column_index(name) {
+        Some(InsertTarget::Column(idx))
+    } else if ROWID_NAMES
+        .iter()
+        .any(|rowid_name| rowid_name.eq_ignore_ascii_case(name))
+    {
+        Some(InsertTarget::Rowid)
+    } else {
+        None
+    }
+}
+
+/// Write a possibly-qualified name the way it appears in SQL.
+fn display_name(name: &[Ident]) -> String {
+    name.iter()
+        .map(|part| part.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Build the entry for a row in an index: the indexed values, followed by the rowid.
+fn index_entry(
+    schema: &TableSchema,
+    index: &IndexSchema,
+    record: &[OwnedValue],
+    rowid: i64,
+) -> Result<Vec<OwnedValue>> {
+    index
+        .columns
+        .iter()
+        .map(|column| match column {
+            IndexColumn::Column(idx) if Some(*idx) == schema.rowid_alias => Ok(Value::int(rowid)),
+            IndexColumn::Column(idx) => Ok(record[*idx].clone()),
+            IndexColumn::Expr(expr) => {
+                evaluate(expr, &mut |name| column_value(schema, record, rowid, name))
+                    .with_context(|| format!("Failed to evaluate {expr} for index {}", index.name))
+            }
+        })
+        .chain([Ok(Value::int(rowid))])
+        .collect()
+}
+
+/// Convert a value given for the rowid into the rowid, or `None` if one should be picked.
+fn rowid_from_value(value: &OwnedValue) -> Result<Option<i64>> {
+    match value {
+        Value::Null => Ok(None),
+        Value::F64(n) if n.fract() == 0.0 && n.abs() < 2_f64.powi(63) => Ok(Some(*n as i64)),
+        _ => value
+            .get::<i64>()
+            .map(Some)
+            .context("datatype mismatch: rowid must be an integer"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::tests::{open_rw, query, run, temp_copy},
+        record::{ColumnType, RowExt, Value},
+    };
+
+    #[test]
+    fn test_insert_rowid_alias() {
+        let path = temp_copy("test-data/rowid-alias.sqlite", "insert-rowid-alias");
+        let mut db = open_rw(&path);
+        run(
+            &mut db,
+            "INSERT INTO users(name) VALUES ('carol'); INSERT INTO users VALUES (3, 'dave')",
+        )
+        .expect("Failed to insert");
+        run(
+            &mut db,
+            "INSERT INTO users(name, rowid) VALUES ('erin', 10)",
+        )
+        .expect("Failed to insert");
+        assert!(
+            run(&mut db, "INSERT INTO users VALUES ('x', 'frank')").is_err(),
+            "The rowid must be an integer",
+        );
+        drop(db);
+
+        let mut db = open_rw(&path);
+        let rows = query(&mut db, "SELECT * FROM users").expect("Failed to query");
+        let rows = rows
+            .iter()
+            .map(|row| {
+                (
+                    row[0].get::<i64>().unwrap(),
+                    row[1].get::<String>().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                (1, "alice".to_owned()),
+                (3, "dave".to_owned()),
+                (5, "bob".to_owned()),
+                (6, "carol".to_owned()),
+                (10, "erin".to_owned()),
+            ],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_insert_after_max_rowid() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "insert-max-rowid");
+        let mut db = open_rw(&path);
+        run(
+            &mut db,
+            "INSERT INTO t1(rowid, id) VALUES (9223372036854775807, 1)",
+        )
+        .expect("Failed to insert");
+        run(&mut db, "INSERT INTO t1 VALUES (2), (3)").expect("Failed to insert");
+        let root_page = db.table_root_page("t1").unwrap();
+        let mut rowids = db
+            .table("t1")
+            .unwrap()
+            .rows()
+            .map(|row| row[0].get::<i64>().unwrap())
+            .collect::<Vec<_>>();
+        rowids.sort_unstable();
+        assert_eq!(rowids, [1, 2, 3]);
+        assert!(
+            btree::contains_rowid(&mut db.pager, root_page, i64::MAX).unwrap(),
+            "The largest rowid should still be used",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_insert_applies_affinity() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "insert-affinity");
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM t1").expect("Failed to delete");
+        run(
+            &mut db,
+            "INSERT INTO t1 VALUES ('12'), (' 1.5 '), ('x'), (2.0)",
+        )
+        .expect("Failed to insert");
+        run(&mut db, "UPDATE t1 SET id = '4.0' WHERE id = 'x'").expect("Failed to update");
+        let rows = query(&mut db, "SELECT * FROM t1").expect("Failed to query");
+        let values = rows
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                Value::int(12),
+                Value::F64(1.5),
+                Value::int(4),
+                Value::int(2)
+            ],
+            "Numbers in text should be stored as numbers in an integer column",
+        );
+        assert!(
+            values[2..]
+                .iter()
+                .all(|value| value.ty() != ColumnType::F64),
+            "Whole numbers should be stored as integers"
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_autoincrement() {
+        let path = temp_copy("test-data/autoincrement.sqlite", "insert-autoincrement");
+        let mut db = open_rw(&path);
+        let rows = |db: &mut Database, sql| {
+            query(db, sql)
+                .expect("Failed to query")
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        // Row 3 was deleted, but its rowid isn't reused.
+        run(&mut db, "INSERT INTO t(x) VALUES ('d')").expect("Failed to insert");
+        // An ignored row still uses up its rowid.
+        run(&mut db, "INSERT OR IGNORE INTO t VALUES (100, 'd')").expect("Failed to insert");
+        run(&mut db, "DELETE FROM t WHERE x = 'd'").expect("Failed to delete");
+        run(&mut db, "INSERT INTO t(x) VALUES ('e')").expect("Failed to insert");
+        // Tables get a row in the sequence table on their first insert.
+        run(&mut db, "INSERT INTO u VALUES (-5, 'y')").expect("Failed to insert");
+        assert_eq!(
+            rows(&mut db, "SELECT * FROM t"),
+            [["1", r#""a""#], ["2", r#""b""#], ["101", r#""e""#]],
+        );
+        assert_eq!(
+            rows(&mut db, "SELECT * FROM sqlite_sequence"),
+            [[r#""t""#, "101"], [r#""u""#, "0"]],
+        );
+
+        run(&mut db, "INSERT INTO t VALUES (9223372036854775807, 'f')").expect("Failed to insert");
+        run(&mut db, "DELETE FROM t").expect("Failed to delete");
+        assert!(run(&mut db, "INSERT INTO t(x) VALUES ('g')")
+            .unwrap_err()
+            .to_string()
+            .starts_with("database or disk is full"));
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_insert_is_atomic() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "insert-atomic");
+        let mut db = open_rw(&path);
+        assert!(
+            run(&mut db, "INSERT INTO t1(rowid, id) VALUES (1, 1), (1, 2)").is_err(),
+            "Duplicate rowids should fail",
+        );
+        assert!(
+            query(&mut db, "SELECT * FROM t1").unwrap().is_empty(),
+            "A failed statement shouldn't insert anything",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_unique_constraints() {
+        let path = temp_copy("test-data/constraints.sqlite", "insert-unique");
+        let mut db = open_rw(&path);
+        let error = |db: &mut Database, sql| run(db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error(
+                &mut db,
+                "INSERT INTO users VALUES (1, 'c@example.com', 'carol', 1)"
+            ),
+            "UNIQUE constraint failed: users.id",
+        );
+        assert_eq!(
+            error(&mut db, "INSERT INTO users(email) VALUES ('a@example.com')"),
+            "UNIQUE constraint failed: users.email",
+        );
+        assert_eq!(
+            error(&mut db, "INSERT INTO users(name, team) VALUES ('bob', 2)"),
+            "UNIQUE constraint failed: users.name, users.team",
+        );
+        assert_eq!(
+            error(&mut db, "INSERT INTO tags VALUES ('y', 1), ('x', 2)"),
+            "UNIQUE constraint failed: tags.name",
+        );
+        // NULLs never conflict, and only the combination of name and team must be unique.
+        run(
+            &mut db,
+            "INSERT INTO users(email, name, team) VALUES (NULL, 'bob', 1), (NULL, 'bob', NULL), \
+             (NULL, 'bob', NULL)",
+        )
+        .expect("Failed to insert");
+        run(&mut db, "INSERT INTO tags VALUES ('y', 1)").expect("Failed to insert");
+        assert_eq!(db.table("users").unwrap().count().unwrap(), 5);
+        assert_eq!(db.table("tags").unwrap().count().unwrap(), 2);
+
+        // The new rows made it into the indexes too.
+        assert_eq!(
+            error(&mut db, "INSERT INTO users(name, team) VALUES ('bob', 1)"),
+            "UNIQUE constraint failed: users.name, users.team",
+        );
+        assert_eq!(
+            error(&mut db, "INSERT INTO tags VALUES ('y', 3)"),
+            "UNIQUE constraint failed: tags.name",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_expression_indexes() {
+        let path = temp_copy(
+            "test-data/expression-index.sqlite",
+            "insert-expression-index",
+        );
+        let mut db = open_rw(&path);
+        assert_eq!(
+            run(&mut db, "INSERT INTO users VALUES (3, 'ALICE', 1)")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: index 'users_name'",
+        );
+        run(
+            &mut db,
+            "INSERT INTO users VALUES (3, 'carol', -5), (4, NULL, 2)",
+        )
+        .expect("Failed to insert");
+        run(&mut db, "INSERT OR REPLACE INTO users VALUES (5, 'BOB', 4)")
+            .expect("Failed to insert");
+        let rows = query(&mut db, "SELECT * FROM users")
+            .expect("Failed to query")
+            .iter()
+            .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                ["1", r#""Alice""#, "-3"],
+                ["3", r#""carol""#, "-5"],
+                ["4", "null", "2"],
+                ["5", r#""BOB""#, "4"],
+            ],
+        );
+        // The replacing row's entry took the place of the old one.
+        run(&mut db, "INSERT INTO users VALUES (6, 'Bob', 0)").unwrap_err();
+        run(&mut db, "DELETE FROM users").expect("Failed to delete");
+        run(&mut db, "INSERT INTO users VALUES (6, 'Bob', 0)").expect("Failed to insert");
+
+        let index_info = query(&mut db, "PRAGMA index_info('users_score')")
+            .expect("Failed to query")
+            .iter()
+            .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(index_info, [["0", "-2", "null"], ["1", "0", r#""id""#]]);
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    /// Read `(id, email, name, team)` for every row in the `users` table of `constraints.sqlite`.
+    fn users(db: &mut Database) -> Vec<(i64, String, String, i64)> {
+        query(db, "SELECT * FROM users")
+            .expect("Failed to query")
+            .iter()
+            .map(|row| {
+                (
+                    row.get_as(0).unwrap(),
+                    row.get_as(1).unwrap(),
+                    row.get_as(2).unwrap(),
+                    row.get_as(3).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_conflict_resolution() {
+        let path = temp_copy("test-data/constraints.sqlite", "insert-conflict-resolution");
+        let mut db = open_rw(&path);
+        run(
+            &mut db,
+            "INSERT OR IGNORE INTO users VALUES (1, 'c@example.com', 'carol', 3); \
+             INSERT OR IGNORE INTO users(email, name, team) \
+             VALUES ('d@example.com', 'dave', 4), ('a@example.com', 'erin', 5)",
+        )
+        .expect("Failed to insert");
+        assert_eq!(db.table("users").unwrap().count().unwrap(), 3);
+
+        // The new row conflicts with two existing rows, through different constraints.
+        run(
+            &mut db,
+            "INSERT OR REPLACE INTO users VALUES (4, 'a@example.com', 'bob', 2)",
+        )
+        .expect("Failed to insert");
+        run(&mut db, "REPLACE INTO tags VALUES ('x', 5)").expect("Failed to insert");
+        let s = ToOwned::to_owned;
+        assert_eq!(
+            users(&mut db),
+            [
+                (3, s("d@example.com"), s("dave"), 4),
+                (4, s("a@example.com"), s("bob"), 2),
+            ],
+        );
+        assert_eq!(
+            query(&mut db, "SELECT * FROM tags").unwrap()[0]
+                .get_as::<i64>(1)
+                .unwrap(),
+            5,
+        );
+
+        // The replaced rows are gone from the indexes too.
+        run(
+            &mut db,
+            "INSERT INTO users(email, name, team) VALUES ('b@example.com', 'alice', 1)",
+        )
+        .expect("Failed to insert");
+        assert_eq!(
+            run(&mut db, "INSERT INTO users(email) VALUES ('a@example.com')")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.email",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_upsert() {
+        let path = temp_copy("test-data/constraints.sqlite", "insert-upsert");
+        let mut db = open_rw(&path);
+        let error = |db: &mut Database, sql| run(db, sql).unwrap_err().to_string();
+        let tag_count = |db: &mut Database| {
+            query(db, "SELECT * FROM tags").unwrap()[0]
+                .get_as::<i64>(1)
+                .unwrap()
+        };
+        run(
+            &mut db,
+            "INSERT INTO tags VALUES ('x', 10) ON CONFLICT (name) DO UPDATE SET n = n + excluded.n",
+        )
+        .expect("Failed to upsert");
+        assert_eq!(tag_count(&mut db), 11);
+        run(
+            &mut db,
+            "INSERT INTO tags VALUES ('x', 1) ON CONFLICT DO NOTHING; \
+             INSERT INTO tags VALUES ('x', 1) ON CONFLICT (name) DO UPDATE SET n = 0 \
+             WHERE tags.n > 100",
+        )
+        .expect("Failed to upsert");
+        assert_eq!(tag_count(&mut db), 11);
+        assert_eq!(
+            error(
+                &mut db,
+                "INSERT INTO tags VALUES ('x', 1) ON CONFLICT (n) DO NOTHING"
+            ),
+            "ON CONFLICT clause does not match any PRIMARY KEY or UNIQUE constraint",
+        );
+
+        // The clause only handles conflicts with the constraint it names.
+        assert_eq!(
+            error(
+                &mut db,
+                "INSERT INTO users VALUES (1, 'c@example.com', 'carol', 3) \
+                 ON CONFLICT (email) DO NOTHING"
+            ),
+            "UNIQUE constraint failed: users.id",
+        );
+        // The update itself mustn't conflict with another row.
+        assert_eq!(
+            error(
+                &mut db,
+                "INSERT INTO users(email) VALUES ('a@example.com') \
+                 ON CONFLICT (email) DO UPDATE SET email = 'b@example.com'"
+            ),
+            "UNIQUE constraint failed: users.email",
+        );
+        // Updates can move the row to a new rowid.
+        run(
+            &mut db,
+            "INSERT INTO users(email, name) VALUES ('a@example.com', 'carol') \
+             ON CONFLICT (email) DO UPDATE SET id = id + 10, name = excluded.name",
+        )
+        .expect("Failed to upsert");
+        let s = ToOwned::to_owned;
+        assert_eq!(
+            users(&mut db),
+            [
+                (2, s("b@example.com"), s("bob"), 2),
+                (11, s("a@example.com"), s("carol"), 1),
+            ],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
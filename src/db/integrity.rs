@@ -0,0 +1,550 @@
+//! Checking the structure of the database file, for `PRAGMA integrity_check`
+//!
+//! The check walks the freelist and every btree, reading the pages as raw bytes so that damaged
+//! pages are reported instead of failing to parse. Every page should be found exactly once along
+//! the way, apart from the pointer map pages and the lock-byte page.
+
+use anyhow::Result;
+
+use super::Database;
+use crate::{
+    page::{btree_header_offset, local_payload_size},
+    pager::{AutoVacuum, Pager, PtrmapEntry, Storage},
+    parse_varint,
+    schema::ObjectKind,
+};
+
+/// The most problems `PRAGMA integrity_check` reports if not told otherwise, which is the same as
+/// SQLite's.
+pub(super) const DEFAULT_MAX_PROBLEMS: usize = 100;
+
+impl<File: Storage> Database<File> {
+    /// Check the structure of the database, returning a description of each problem found.
+    ///
+    /// At most `max_problems` problems are returned, and none means the database is intact.
+    pub fn integrity_check(&mut self, max_problems: usize) -> crate::Result<Vec<String>> {
+        self.with_lock(|db| {
+            let roots = db
+                .schema
+                .objects
+                .iter()
+                .filter(|object| matches!(object.kind, ObjectKind::Table | ObjectKind::Index))
+                .filter_map(|object| object.root_page)
+                .collect::<Vec<_>>();
+            let mut check = IntegrityCheck::new(&mut db.pager, max_problems);
+            check.check_freelist()?;
+            // `sqlite_schema` is rooted at the first page, but isn't listed in itself.
+            check.check_tree(1)?;
+            for root in roots {
+                check.check_tree(root)?;
+            }
+            check.check_all_used();
+            Ok(check.problems)
+        })
+    }
+}
+
+/// A walk over the pages of a database, collecting the problems found along the way.
+struct IntegrityCheck<'a, File> {
+    /// The pager to read the pages from
+    pager: &'a mut Pager<File>,
+    /// Whether each page has been reached yet, indexed by page number minus one.
+    used: Vec<bool>,
+    /// The problems found so far.
+    problems: Vec<String>,
+    /// How many problems to stop reporting after.
+    max_problems: usize,
+}
+
+/// The parts of a btree cell which the check needs to follow.
+struct CellInfo {
+    /// The size of the cell in its page.
+    len: usize,
+    /// The child page to the left of the cell, on internal pages.
+    left_child: Option<u32>,
+    /// The rowid or key of the cell, in table btrees.
+    rowid: Option<i64>,
+    /// The first overflow page, with the number of bytes of the payload stored in the chain.
+    overflow: Option<(u32, usize)>,
+}
+
+impl<'a, File: Storage> IntegrityCheck<'a, File> {
+    fn new(pager: &'a mut Pager<File>, max_problems: usize) -> Self {
+        let page_count = pager.page_count();
+        // The pointer map pages and the lock-byte page aren't referred to by anything.
+        let used = (1..=page_count)
+            .map(|page_idx| pager.is_ptrmap_page(page_idx) || pager.is_lock_byte_page(page_idx))
+            .collect();
+        Self {
+            pager,
+            used,
+            problems: Vec::new(),
+            max_problems,
+        }
+    }
+
+    /// Note a problem with the database.
+    fn report(&mut self, problem: String) {
+        if self.problems.len() < self.max_problems {
+            self.problems.push(problem);
+        }
+    }
+
+    /// Read the usable part of the given page.
+    fn read(&mut self, page_idx: usize) -> Result<Vec<u8>> {
+        let usable_size = self.pager.usable_size();
+        Ok(self.pager.read_page_bytes(page_idx)?[..usable_size].to_vec())
+    }
+
+    /// Note that the given page was reached, reporting it with `prefix` if it can't be used.
+    ///
+    /// Returns whether the page should be followed, which it shouldn't if it doesn't exist or was
+    /// already reached some other way.
+    fn mark_used(&mut self, page_idx: usize, prefix: &str) -> bool {
+        let Some(used) = page_idx
+            .checked_sub(1)
+            .and_then(|idx| self.used.get_mut(idx))
+        else {
+            self.report(format!("{prefix}invalid page number {page_idx}"));
+            return false;
+        };
+        if std::mem::replace(used, true) {
+            self.report(format!("{prefix}2nd reference to page {page_idx}"));
+            return false;
+        }
+        true
+    }
+
+    /// Check that the pointer map records the given page as `expected`, if there is a pointer map.
+    fn check_ptrmap(&mut self, page_idx: usize, expected: PtrmapEntry, prefix: &str) {
+        if self.pager.auto_vacuum() == AutoVacuum::None || page_idx == 1 {
+            return;
+        }
+        match self.pager.read_ptrmap(page_idx) {
+            Ok(entry) if entry == expected => {}
+            Ok(entry) => self.report(format!(
+                "{prefix}Bad ptr map entry for page {page_idx}: {entry:?} but should be {expected:?}"
+            )),
+            Err(e) => self.report(format!("{prefix}{e:#}")),
+        }
+    }
+
+    /// Check the freelist, whose pages are listed in trunk pages chained from the header.
+    fn check_freelist(&mut self) -> Result<()> {
+        const PREFIX: &str = "Freelist: ";
+        let header = self.pager.header()?;
+        let max_leaves = self.pager.usable_size() / 4 - 2;
+        let mut found = 0;
+        let mut trunk_idx = header.first_freelist_trunk as usize;
+        while trunk_idx != 0 {
+            if !self.mark_used(trunk_idx, PREFIX) {
+                break;
+            }
+            self.check_ptrmap(trunk_idx, PtrmapEntry::FreePage, PREFIX);
+            found += 1;
+            let trunk = self.read(trunk_idx)?;
+            let leaf_count = read_u32(&trunk, 4) as usize;
+            if leaf_count > max_leaves {
+                self.report(format!("{PREFIX}leaf count too big on page {trunk_idx}"));
+                break;
+            }
+            for idx in 0..leaf_count {
+                let leaf_idx = read_u32(&trunk, 8 + 4 * idx) as usize;
+                if self.mark_used(leaf_idx, PREFIX) {
+                    self.check_ptrmap(leaf_idx, PtrmapEntry::FreePage, PREFIX);
+                }
+                found += 1;
+            }
+            trunk_idx = read_u32(&trunk, 0) as usize;
+        }
+        if found != header.freelist_count as usize {
+            self.report(format!(
+                "{PREFIX}size is {found} but should be {}",
+                header.freelist_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the btree rooted at the given page.
+    fn check_tree(&mut self, root: usize) -> Result<()> {
+        let prefix = format!("On tree page {root}: ");
+        if self.mark_used(root, &prefix) {
+            self.check_ptrmap(root, PtrmapEntry::RootPage, &prefix);
+            self.check_page(root, None, None, None)?;
+        }
+        Ok(())
+    }
+
+    /// Check a page of a btree, and then the subtree below it.
+    ///
+    /// `table` is whether the btree is a table btree, if that's known from the page's parent. In
+    /// table btrees, the rowids in the page must be greater than `lower` and at most `upper`.
+    ///
+    /// Returns the depth of the subtree, if it could be found.
+    fn check_page(
+        &mut self,
+        page_idx: usize,
+        table: Option<bool>,
+        lower: Option<i64>,
+        upper: Option<i64>,
+    ) -> Result<Option<usize>> {
+        let page = self.read(page_idx)?;
+        let usable_size = page.len();
+        let header_offset = btree_header_offset(page_idx);
+        let page_type = page[header_offset];
+        let (is_table, is_leaf) = match page_type {
+            0x0d => (true, true),
+            0x05 => (true, false),
+            0x0a => (false, true),
+            0x02 => (false, false),
+            byte => {
+                self.report(format!("Page {page_idx}: unknown page type {byte}"));
+                return Ok(None);
+            }
+        };
+        if table.is_some_and(|table| table != is_table) {
+            self.report(format!(
+                "Page {page_idx}: table and index pages are mixed in one btree"
+            ));
+            return Ok(None);
+        }
+        let header_size = if is_leaf { 8 } else { 12 };
+        let first_freeblock = usize::from(read_u16(&page, header_offset + 1));
+        let cell_count = usize::from(read_u16(&page, header_offset + 3));
+        let content_offset = match read_u16(&page, header_offset + 5) {
+            0 => 65536,
+            offset => usize::from(offset),
+        };
+        let fragmented_bytes = usize::from(page[header_offset + 7]);
+        if !is_leaf && cell_count == 0 {
+            // SQLite refuses to read interior pages without cells, even with a rightmost child.
+            self.report(format!("Page {page_idx}: interior page has no cells"));
+        }
+        let pointers_start = header_offset + header_size;
+        let pointers_end = pointers_start + 2 * cell_count;
+        if !(pointers_end..=usable_size).contains(&content_offset) {
+            self.report(format!(
+                "Page {page_idx}: cell content area starts at {content_offset}, outside of \
+                 {pointers_end}..={usable_size}"
+            ));
+            return Ok(None);
+        }
+
+        // Which bytes of the content area are taken by cells or freeblocks
+        let mut covered = vec![false; usable_size];
+        let mut page_ok = true;
+        let mut children = Vec::new();
+        let mut previous_rowid = lower;
+        for idx in 0..cell_count {
+            let prefix = format!("On tree page {page_idx} cell {idx}: ");
+            let offset = usize::from(read_u16(&page, pointers_start + 2 * idx));
+            if !(content_offset..usable_size).contains(&offset) {
+                self.report(format!("{prefix}Offset {offset} out of range"));
+                page_ok = false;
+                continue;
+            }
+            let Some(cell) = parse_cell(&page[offset..], is_table, is_leaf, usable_size) else {
+                self.report(format!("{prefix}Extends off end of page"));
+                page_ok = false;
+                continue;
+            };
+            if !self.cover(&mut covered, offset, cell.len, page_idx) {
+                page_ok = false;
+            }
+            if let Some((first_overflow, len)) = cell.overflow {
+                self.check_overflow(first_overflow as usize, len, page_idx, &prefix)?;
+            }
+            if let Some(rowid) = cell.rowid {
+                // Leaves can't repeat a rowid, but an internal key may equal the one before it.
+                let in_order = previous_rowid.map_or(true, |previous| {
+                    rowid > previous || (!is_leaf && rowid == previous)
+                }) && upper.map_or(true, |upper| rowid <= upper);
+                if !in_order {
+                    self.report(format!("{prefix}Rowid {rowid} out of order"));
+                }
+                if let Some(left_child) = cell.left_child {
+                    children.push((left_child as usize, previous_rowid, Some(rowid), prefix));
+                }
+                previous_rowid = Some(rowid);
+            } else if let Some(left_child) = cell.left_child {
+                children.push((left_child as usize, None, None, prefix));
+            }
+        }
+        if !is_leaf {
+            let rightmost = read_u32(&page, header_offset + 8) as usize;
+            let prefix = format!("On tree page {page_idx} cell {cell_count}: ");
+            children.push((rightmost, previous_rowid, upper, prefix));
+        }
+
+        let mut freeblock = first_freeblock;
+        let mut previous_freeblock = 0;
+        while freeblock != 0 {
+            if freeblock <= previous_freeblock
+                || freeblock < content_offset
+                || freeblock + 4 > usable_size
+            {
+                self.report(format!(
+                    "Page {page_idx}: freeblock at {freeblock} is out of order or out of range"
+                ));
+                page_ok = false;
+                break;
+            }
+            let size = usize::from(read_u16(&page, freeblock + 2));
+            if size < 4 || freeblock + size > usable_size {
+                self.report(format!(
+                    "Page {page_idx}: freeblock at {freeblock} extends off end of page"
+                ));
+                page_ok = false;
+                break;
+            }
+            if !self.cover(&mut covered, freeblock, size, page_idx) {
+                page_ok = false;
+            }
+            previous_freeblock = freeblock;
+            freeblock = usize::from(read_u16(&page, freeblock));
+        }
+        // Whatever in the content area isn't a cell or a freeblock is a fragment too small to be
+        // a freeblock, and the header keeps count of those.
+        let fragments = covered[content_offset..]
+            .iter()
+            .filter(|&&covered| !covered)
+            .count();
+        if page_ok && fragments != fragmented_bytes {
+            self.report(format!(
+                "Fragmentation of {fragments} bytes reported as {fragmented_bytes} on page \
+                 {page_idx}"
+            ));
+        }
+
+        let mut depth = None;
+        for (child, lower, upper, prefix) in children {
+            if !self.mark_used(child, &prefix) {
+                continue;
+            }
+            self.check_ptrmap(child, PtrmapEntry::BTree(page_idx as u32), &prefix);
+            let Some(child_depth) = self.check_page(child, Some(is_table), lower, upper)? else {
+                continue;
+            };
+            match depth {
+                None => depth = Some(child_depth),
+                Some(depth) if depth == child_depth => {}
+                Some(_) => self.report(format!("Page {page_idx}: Child page depth differs")),
+            }
+        }
+        Ok(if is_leaf {
+            Some(0)
+        } else {
+            depth.map(|depth| depth + 1)
+        })
+    }
+
+    /// Mark `len` bytes at `offset` in a page as covered, reporting if any of them already were.
+    ///
+    /// Returns whether none of them were.
+    fn cover(&mut self, covered: &mut [bool], offset: usize, len: usize, page_idx: usize) -> bool {
+        let range = &mut covered[offset..offset + len];
+        if let Some(overlap) = range.iter().position(|&covered| covered) {
+            self.report(format!(
+                "Multiple uses for byte {} of page {page_idx}",
+                offset + overlap
+            ));
+            return false;
+        }
+        range.fill(true);
+        true
+    }
+
+    /// Check the chain of overflow pages holding `len` bytes of a payload in a cell on the page
+    /// `parent`.
+    fn check_overflow(
+        &mut self,
+        first_page: usize,
+        len: usize,
+        parent: usize,
+        prefix: &str,
+    ) -> Result<()> {
+        let expected_pages = len.div_ceil(self.pager.usable_size() - 4);
+        let mut page_idx = first_page;
+        let mut expected_entry = PtrmapEntry::FirstOverflow(parent as u32);
+        for found in 1..=expected_pages {
+            if !self.mark_used(page_idx, prefix) {
+                return Ok(());
+            }
+            self.check_ptrmap(page_idx, expected_entry, prefix);
+            let next = read_u32(&self.read(page_idx)?, 0) as usize;
+            if found < expected_pages && next == 0 {
+                self.report(format!(
+                    "{prefix}{} of {expected_pages} pages missing from overflow list starting at \
+                     {first_page}",
+                    expected_pages - found
+                ));
+                return Ok(());
+            }
+            expected_entry = PtrmapEntry::Overflow(page_idx as u32);
+            page_idx = next;
+        }
+        Ok(())
+    }
+
+    /// Report every page which wasn't reached by the rest of the check.
+    fn check_all_used(&mut self) {
+        let unused = (1..=self.used.len())
+            .filter(|&page_idx| !self.used[page_idx - 1])
+            .collect::<Vec<_>>();
+        for page_idx in unused {
+            self.report(format!("Page {page_idx}: never used"));
+        }
+    }
+}
+
+/// Find the extent of the cell at the start of `cell`, or `None` if it runs off the end.
+fn parse_cell(
+    mut cell: &[u8],
+    is_table: bool,
+    is_leaf: bool,
+    usable_size: usize,
+) -> Option<CellInfo> {
+    let start_len = cell.len();
+    let left_child = if is_leaf {
+        None
+    } else {
+        let (child, rest) = cell.split_first_chunk::<4>()?;
+        cell = rest;
+        Some(u32::from_be_bytes(*child))
+    };
+    if is_table && !is_leaf {
+        // Table internal cells are just a child pointer and a key.
+        let key = parse_varint(&mut cell).ok()?;
+        return Some(CellInfo {
+            len: start_len - cell.len(),
+            left_child,
+            rowid: Some(key),
+            overflow: None,
+        });
+    }
+    let payload_size = usize::try_from(parse_varint(&mut cell).ok()?).ok()?;
+    let rowid = is_table.then(|| parse_varint(&mut cell)).transpose().ok()?;
+    let max_local = if is_table {
+        usable_size - 35
+    } else {
+        (usable_size - 12) * 64 / 255 - 23
+    };
+    let local_size = local_payload_size(payload_size, usable_size, max_local);
+    let overflow = if local_size < payload_size {
+        let pointer = cell.get(local_size..local_size + 4)?;
+        Some((
+            u32::from_be_bytes(pointer.try_into().unwrap()),
+            payload_size - local_size,
+        ))
+    } else {
+        cell.get(..local_size)?;
+        None
+    };
+    let overflow_pointer_size = if overflow.is_some() { 4 } else { 0 };
+    Some(CellInfo {
+        len: start_len - cell.len() + local_size + overflow_pointer_size,
+        left_child,
+        rowid,
+        overflow,
+    })
+}
+
+/// Read the big-endian `u16` at `offset` in `page`.
+fn read_u16(page: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([page[offset], page[offset + 1]])
+}
+
+/// Read the big-endian `u32` at `offset` in `page`.
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    use crate::db::tests::{open_rw, query, temp_copy};
+
+    /// Overwrite the bytes at `offset` in the file at `path`.
+    fn corrupt(path: &Path, offset: u64, bytes: &[u8]) {
+        let mut file = std::fs::File::options()
+            .write(true)
+            .open(path)
+            .expect("Failed to open test database");
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes)
+            .expect("Failed to corrupt test database");
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        for fixture in [
+            "test-data/auto-vacuum.sqlite",
+            "test-data/constraints.sqlite",
+            "test-data/header-fields.sqlite",
+            "test-data/many-tables.sqlite",
+            "test-data/overflow.sqlite",
+            "test-data/reserved-bytes.sqlite",
+        ] {
+            let path = temp_copy(fixture, "integrity-ok");
+            let mut db = open_rw(&path);
+            assert_eq!(
+                db.integrity_check(100).unwrap(),
+                Vec::<String>::new(),
+                "{fixture} should be intact"
+            );
+            std::fs::remove_file(path).expect("Failed to clean up");
+        }
+
+        // Point the second cell of `users` at the first one.
+        let path = temp_copy("test-data/constraints.sqlite", "integrity-cells");
+        corrupt(&path, 4096 + 10, &4071_u16.to_be_bytes());
+        let mut db = open_rw(&path);
+        assert_eq!(
+            db.integrity_check(100).unwrap(),
+            [
+                "Multiple uses for byte 4071 of page 2",
+                "On tree page 2 cell 1: Rowid 1 out of order",
+            ],
+        );
+        assert_eq!(
+            query(&mut db, "PRAGMA integrity_check(1)").unwrap().len(),
+            1
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+
+        // Empty the interior root of the table in `overflow.sqlite`, leaving its rightmost child,
+        // as a page split once did.
+        let path = temp_copy("test-data/overflow.sqlite", "integrity-interior");
+        corrupt(&path, 1024 + 3, &[0, 0, 0x04, 0]);
+        let mut db = open_rw(&path);
+        assert_eq!(
+            db.integrity_check(100).unwrap(),
+            [
+                "Page 2: interior page has no cells",
+                "Page 3: never used",
+                "Page 4: never used",
+                "Page 6: never used",
+            ],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+
+        // Drop the last page from the only freelist trunk, which lists pages 10 to 13 and then 8.
+        let path = temp_copy("test-data/header-fields.sqlite", "integrity-freelist");
+        corrupt(&path, 36, &5_u32.to_be_bytes());
+        corrupt(&path, 8 * 1024 + 4, &4_u32.to_be_bytes());
+        let mut db = open_rw(&path);
+        assert_eq!(db.integrity_check(100).unwrap(), ["Page 8: never used"]);
+        corrupt(&path, 36, &7_u32.to_be_bytes());
+        let mut db = open_rw(&path);
+        assert_eq!(
+            db.integrity_check(100).unwrap(),
+            ["Freelist: size is 5 but should be 7", "Page 8: never used"],
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
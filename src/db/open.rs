@@ -0,0 +1,219 @@
+//! Opening databases stored in files, with [`OpenOptions`]
+
+use std::{fs, io::Seek, path::Path, time::Duration};
+
+use anyhow::Context;
+
+use super::{Database, DEFAULT_PAGE_SIZE};
+use crate::pager::{journal_path_for, CacheSize, Pager, PagerConfig, SyncPolicy};
+
+/// How to open a database stored in a file, as given to [`Database::open_with`].
+///
+/// Like [`std::fs::OpenOptions`], each option is set by the method with its name, which can be
+/// chained. Without any set, the database must already exist, and is opened for reading and
+/// writing with SQLite's default settings.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// The settings for the database's pager
+    config: PagerConfig,
+    /// Whether to create a new database if there isn't one
+    create: bool,
+    /// Whether to read pages straight from a map of the file in memory
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+}
+
+impl OpenOptions {
+    /// Make options which open an existing database with the default settings.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether to only read the database, so that statements which would change it fail.
+    ///
+    /// Files which can't be written are opened for reading anyway, so they can still be queried.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Set whether to create a new, empty database if there's no file, or an empty one, at the
+    /// path.
+    ///
+    /// This is ignored for databases opened [read-only](Self::read_only).
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Set how much the page cache holds, as `PRAGMA cache_size` does.
+    pub fn cache_size(&mut self, cache_size: CacheSize) -> &mut Self {
+        self.config.cache_size = cache_size;
+        self
+    }
+
+    /// Set how long to keep trying to lock the database while another connection holds a
+    /// conflicting lock, before failing with [`Error::Busy`](crate::Error::Busy).
+    ///
+    /// See [`Pager::set_busy_timeout`].
+    pub fn busy_timeout(&mut self, busy_timeout: Duration) -> &mut Self {
+        self.config.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Set how carefully commits are synced to persistent storage.
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.config.sync_policy = sync_policy;
+        self
+    }
+
+    /// Set whether to check pages against checksums when they're loaded again.
+    ///
+    /// See [`Pager::set_verify_checksums`].
+    pub fn verify_checksums(&mut self, verify_checksums: bool) -> &mut Self {
+        self.config.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Set whether to read pages straight from a map of the file in memory, rather than copying
+    /// them into the page cache.
+    ///
+    /// # Safety
+    /// Other processes must only change the file as [`Pager::new_mmap`] requires.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Get the settings the database's pager is opened with.
+    #[must_use]
+    pub fn pager_config(&self) -> &PagerConfig {
+        &self.config
+    }
+}
+
+impl Database {
+    /// Open the existing database at `path` for reading and writing, with its writes protected
+    /// by a rollback journal next to it.
+    ///
+    /// Read-only files are opened for reading only, so they can still be queried. See
+    /// [`Self::open_with`] to set other options, like creating the database if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Open the database at `path` with the given options, with its writes protected by a
+    /// rollback journal next to it.
+    ///
+    /// Read-only files are opened for reading only, so they can still be queried, as are all
+    /// files if [`read_only`](OpenOptions::read_only) is set.
+    pub fn open_with(path: impl AsRef<Path>, options: &OpenOptions) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let config = &options.config;
+        let create = options.create && !config.read_only;
+        let mut file = if config.read_only {
+            fs::File::open(path)
+        } else {
+            fs::File::options()
+                .read(true)
+                .write(true)
+                .create(create)
+                .open(path)
+                .or_else(|_| fs::File::open(path))
+        }
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+        if create
+            && file
+                .metadata()
+                .context("Failed to read file metadata")?
+                .len()
+                == 0
+        {
+            Pager::create(&file, DEFAULT_PAGE_SIZE).context("Failed to create database")?;
+            file.rewind().context("Error seeking in database")?;
+        }
+
+        let journal_path = journal_path_for(path);
+        #[cfg(feature = "mmap")]
+        let pager = if options.mmap {
+            // SAFETY: Guaranteed by the caller of `OpenOptions::mmap`.
+            unsafe { Pager::new_mmap_with_journal(file, journal_path) }
+        } else {
+            Pager::with_journal(file, journal_path)
+        };
+        #[cfg(not(feature = "mmap"))]
+        let pager = Pager::with_journal(file, journal_path);
+        let mut pager = pager.context("Failed to parse file")?;
+        pager.configure(config);
+        Self::from_pager(pager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::db::tests::{query, run, temp_copy};
+
+    #[test]
+    fn test_open_with() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "open-with");
+        let mut db = Database::open_with(
+            &path,
+            OpenOptions::new()
+                .cache_size(CacheSize::Pages(4))
+                .busy_timeout(Duration::from_millis(100)),
+        )
+        .expect("Failed to open database");
+        assert_eq!(db.pager.cache_size(), CacheSize::Pages(4));
+        assert_eq!(db.pager.busy_timeout(), Duration::from_millis(100));
+        assert_eq!(db.pager.cache_capacity(), 4 * db.pager.page_size());
+        let rows = query(&mut db, "SELECT * FROM t1").unwrap().len();
+        run(&mut db, "INSERT INTO t1 VALUES (4)").unwrap();
+        drop(db);
+
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(query(&mut db, "SELECT * FROM t1").unwrap().len(), rows + 1);
+        assert!(Database::open(path.with_extension("missing")).is_err());
+
+        let mut db = Database::open_with(&path, OpenOptions::new().read_only(true)).unwrap();
+        assert_eq!(query(&mut db, "SELECT * FROM t1").unwrap().len(), rows + 1);
+        assert_eq!(
+            run(&mut db, "INSERT INTO t1 VALUES (5)")
+                .unwrap_err()
+                .to_string(),
+            "attempt to write a readonly database",
+        );
+        assert_eq!(query(&mut db, "SELECT * FROM t1").unwrap().len(), rows + 1);
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_open_create() {
+        let path = std::env::temp_dir().join(format!(
+            "sqlite-riir-open-create-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        assert!(
+            Database::open_with(&path, OpenOptions::new().read_only(true).create(true)).is_err(),
+            "Read-only databases aren't created",
+        );
+        let mut db = Database::open_with(&path, OpenOptions::new().create(true))
+            .expect("Failed to create database");
+        assert!(query(&mut db, "SELECT * FROM sqlite_schema")
+            .unwrap()
+            .is_empty());
+        drop(db);
+        assert!(Database::open(&path).is_ok(), "The database should be kept");
+
+        // Existing databases are opened as they are.
+        fs::copy("test-data/minimal-test.sqlite", &path).expect("Failed to copy database");
+        let db = Database::open_with(&path, OpenOptions::new().create(true)).unwrap();
+        assert!(db.table_schema("t1").is_ok());
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,407 @@
+//! Choosing how to find the rows a statement reads, using the statistics `ANALYZE` stores in
+//! `sqlite_stat1` when there are any
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use sqlparser::ast::{BinaryOperator, Expr, FromTable, Statement};
+
+use super::{insert::WritableTable, plain_table_name, Database};
+use crate::{
+    btree,
+    expr::evaluate_constant,
+    pager::Storage,
+    record::{OwnedValue, Record, RowExt},
+    schema::{IndexColumn, IndexSchema, TableSchema},
+    table_iter::TableIter,
+    Error,
+};
+
+/// The name of the table `ANALYZE` stores its statistics in.
+pub(super) const STAT1_TABLE: &str = "sqlite_stat1";
+
+/// How many rows a table is assumed to have when it hasn't been analyzed, which is what SQLite
+/// assumes too.
+const DEFAULT_TABLE_ROWS: u64 = 1 << 20;
+
+/// How many rows are assumed to share each key of an index which hasn't been analyzed, which is
+/// what SQLite assumes too.
+const DEFAULT_ROWS_PER_KEY: u64 = 10;
+
+/// The statistics read from `sqlite_stat1`, keyed by the lowercased names of the tables and
+/// indexes they describe.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct Stats {
+    /// The number of rows in each table
+    tables: HashMap<String, u64>,
+    /// For each index, the number of rows in it followed by the average number of rows which
+    /// share the same values in its first one, two, ... columns
+    indexes: HashMap<String, Vec<u64>>,
+}
+
+impl Stats {
+    /// Record a row of `sqlite_stat1`, ignoring it if it's malformed, as SQLite does.
+    fn add(&mut self, row: &[OwnedValue]) {
+        let (Ok(table), Ok(stat)) = (row.get_as::<String>(0), row.get_as::<String>(2)) else {
+            return;
+        };
+        // Anything after the numbers, like `unordered`, is a hint this doesn't use.
+        let counts = stat
+            .split_ascii_whitespace()
+            .map_while(|count| count.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        let Some(&rows) = counts.first() else {
+            return;
+        };
+        self.tables.insert(table.to_ascii_lowercase(), rows);
+        if let Ok(index) = row.get_as::<String>(1) {
+            self.indexes.insert(index.to_ascii_lowercase(), counts);
+        }
+    }
+
+    /// Get the number of rows in the given table.
+    fn table_rows(&self, table: &str) -> u64 {
+        self.tables
+            .get(&table.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(DEFAULT_TABLE_ROWS)
+    }
+
+    /// Estimate how many rows have the same values in the first `columns` columns of `index`.
+    fn rows_per_key(&self, index: &IndexSchema, columns: usize) -> u64 {
+        match self.indexes.get(&index.name.to_ascii_lowercase()) {
+            Some(counts) => counts.get(columns).copied().unwrap_or(1),
+            None if index.unique && columns == index.columns.len() => 1,
+            None => DEFAULT_ROWS_PER_KEY,
+        }
+    }
+}
+
+/// A way to find the rows of a table which might match a `WHERE` clause.
+#[derive(Debug, Clone, PartialEq)]
+enum Scan<'a> {
+    /// Read every row of the table.
+    Full,
+    /// Read the rows whose entries in `index` start with `key`.
+    Index {
+        /// The page the index's btree is rooted at
+        root_page: usize,
+        /// The index to look in
+        index: &'a IndexSchema,
+        /// The values the first columns of the index must have
+        key: Vec<OwnedValue>,
+    },
+}
+
+impl<File: Storage> Database<File> {
+    /// Read the statistics in `sqlite_stat1`, if the database has been analyzed.
+    pub(super) fn read_stats(&mut self) -> Result<Stats> {
+        let mut stats = Stats::default();
+        if self.schema.table(STAT1_TABLE).is_some() {
+            for row in TableIter::new(self, STAT1_TABLE)? {
+                stats.add(&row);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Get the rowids of the rows of a table which might match `selection`, in the order they
+    /// should be visited.
+    ///
+    /// Terms of `selection` which compare a column to a constant are looked up in an index when
+    /// that's estimated to read fewer rows than scanning the whole table. The rows still have to
+    /// be checked against `selection`.
+    pub(super) fn candidate_rowids(
+        &mut self,
+        schema: &TableSchema,
+        root_page: usize,
+        indexes: &[(usize, IndexSchema)],
+        selection: Option<&Expr>,
+    ) -> Result<Vec<i64>> {
+        let Scan::Index {
+            root_page: index_root,
+            index,
+            key,
+        } = self.plan_scan(schema, indexes, selection)
+        else {
+            return btree::rowids(&mut self.pager, root_page);
+        };
+        let key_record = Record::build_encoded(&key, self.pager.text_encoding());
+        btree::index::find_all(
+            &mut self.pager,
+            index_root,
+            &key_record,
+            key.len(),
+            &index.descending,
+        )?
+        .iter()
+        .map(|entry| {
+            Record::parse(entry)?
+                .value_iter()
+                .last()
+                .context("Index entry has no rowid")?
+                .get::<i64>()
+                .context("Invalid rowid in index entry")
+        })
+        .collect()
+    }
+
+    /// Describe how `statement` finds the rows it reads, with a step for each table, in the words
+    /// SQLite's `EXPLAIN QUERY PLAN` uses.
+    ///
+    /// Statements which don't read any rows, like an `INSERT` of values, have no steps.
+    pub fn query_plan(&self, statement: &Statement) -> crate::Result<Vec<String>> {
+        let (table, selection) = match statement {
+            Statement::Query(query) => return Ok(self.query_scans(query, &mut Vec::new())?),
+            Statement::Update {
+                table, selection, ..
+            } => (
+                plain_table_name(table).context(Error::UnsupportedSql(
+                    "Unimplemented UPDATE target".to_owned(),
+                ))?,
+                selection.as_ref(),
+            ),
+            Statement::Delete(delete) => {
+                if delete.selection.is_none() && delete.returning.is_none() {
+                    // Every row is dropped at once, without reading any.
+                    return Ok(Vec::new());
+                }
+                let (FromTable::WithFromKeyword(from) | FromTable::WithoutKeyword(from)) =
+                    &delete.from;
+                let table = from
+                    .first()
+                    .take_if(|_| from.len() == 1)
+                    .and_then(plain_table_name)
+                    .context(Error::UnsupportedSql(
+                        "Unimplemented DELETE target".to_owned(),
+                    ))?;
+                (table, delete.selection.as_ref())
+            }
+            _ => return Ok(Vec::new()),
+        };
+        let WritableTable {
+            schema, indexes, ..
+        } = self.writable_table(table)?;
+        let step = match self.plan_scan(&schema, &indexes, selection) {
+            Scan::Full => format!("SCAN {}", schema.name),
+            Scan::Index { index, key, .. } => {
+                let terms = index
+                    .columns
+                    .iter()
+                    .take(key.len())
+                    .filter_map(|column| match column {
+                        IndexColumn::Column(idx) => {
+                            Some(format!("{}=?", schema.columns[*idx].name))
+                        }
+                        IndexColumn::Expr(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+                format!(
+                    "SEARCH {} USING INDEX {} ({})",
+                    schema.name,
+                    index.name,
+                    terms.join(" AND ")
+                )
+            }
+        };
+        Ok(vec![step])
+    }
+
+    /// Choose how to find the rows of a table which might match `selection`.
+    ///
+    /// Finding each row through an index costs a search of the table's btree, so an index is only
+    /// used if the rows it's expected to find, times the depth of that search, are fewer than the
+    /// rows in the table.
+    fn plan_scan<'a>(
+        &self,
+        schema: &TableSchema,
+        indexes: &'a [(usize, IndexSchema)],
+        selection: Option<&Expr>,
+    ) -> Scan<'a> {
+        let mut terms = Vec::new();
+        if let Some(selection) = selection {
+            equality_terms(schema, selection, &mut terms);
+        }
+        let table_rows = self.stats.table_rows(&schema.name);
+        let search_cost = u64::from(table_rows.max(1).ilog2()) + 1;
+        let mut best = (table_rows, Scan::Full);
+        for (root_page, index) in indexes {
+            let key = index
+                .columns
+                .iter()
+                .map_while(|column| match column {
+                    IndexColumn::Column(idx) => terms
+                        .iter()
+                        .find(|(term_column, _)| term_column == idx)
+                        .map(|(_, value)| value.clone()),
+                    IndexColumn::Expr(_) => None,
+                })
+                .collect::<Vec<_>>();
+            if key.is_empty() {
+                continue;
+            }
+            let cost = self
+                .stats
+                .rows_per_key(index, key.len())
+                .saturating_mul(search_cost);
+            if cost < best.0 {
+                best = (
+                    cost,
+                    Scan::Index {
+                        root_page: *root_page,
+                        index,
+                        key,
+                    },
+                );
+            }
+        }
+        best.1
+    }
+}
+
+/// Add the columns which `selection` requires to equal a constant to `terms`, with the constants.
+///
+/// Only the terms joined by `AND` at the top of `selection` are considered, since a row which
+/// fails any of them can't match.
+fn equality_terms(schema: &TableSchema, selection: &Expr, terms: &mut Vec<(usize, OwnedValue)>) {
+    match selection {
+        Expr::Nested(expr) => equality_terms(schema, expr, terms),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            equality_terms(schema, left, terms);
+            equality_terms(schema, right, terms);
+        }
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => {
+            for (column, value) in [(left, right), (right, left)] {
+                let Some(column) = column_index(schema, column) else {
+                    continue;
+                };
+                if let Ok(value) = evaluate_constant(value) {
+                    // Nothing is equal to `NULL`, but that's left for the `WHERE` clause to check.
+                    if !value.is_null() {
+                        terms.push((column, value));
+                    }
+                    return;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Get the index of the column of `schema` which `expr` names, if it's a column name.
+fn column_index(schema: &TableSchema, expr: &Expr) -> Option<usize> {
+    let name = match expr {
+        Expr::Identifier(name) => name,
+        Expr::CompoundIdentifier(parts) => match parts.as_slice() {
+            [table, name] if table.value.eq_ignore_ascii_case(&schema.name) => name,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    schema.column_index(&name.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use crate::{
+        db::tests::{open_rw, query, temp_copy},
+        Database, QueryStats,
+    };
+
+    fn stats(db: &mut Database, sql: &str) -> QueryStats {
+        let statements =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .unwrap();
+        db.execute_statement(&statements[0], |_| Ok(()))
+            .expect("Failed to run statement")
+    }
+
+    fn count(db: &mut Database, table: &str) -> u64 {
+        db.table(table).unwrap().count().unwrap()
+    }
+
+    #[test]
+    fn test_analyzed_plans() {
+        let path = temp_copy("test-data/analyzed.sqlite", "plan-analyzed");
+        let mut db = open_rw(&path);
+        // Each category has 10 rows, so looking them up in an index is cheaper than a scan.
+        let delete = stats(&mut db, "DELETE FROM items WHERE category = 3");
+        assert_eq!(delete.rows_examined, 10);
+        assert_eq!(count(&mut db, "items"), 990);
+        let update = stats(
+            &mut db,
+            "UPDATE items SET category = 3 WHERE flag = 1 AND items.category = 5",
+        );
+        assert_eq!(update.rows_examined, 10);
+        assert_eq!(
+            query(&mut db, "SELECT * FROM items").unwrap().len(),
+            990,
+            "Updating the indexed column moved the rows instead of adding any",
+        );
+
+        // Half the rows have each flag, so it's cheaper to read the whole table.
+        let delete = stats(&mut db, "DELETE FROM items WHERE flag = 0");
+        assert_eq!(delete.rows_examined, 990);
+        assert_eq!(count(&mut db, "items"), 490);
+        // A term which isn't on the first column of an index can't use it.
+        let delete = stats(&mut db, "DELETE FROM items WHERE 5 = id");
+        assert_eq!(delete.rows_examined, 490);
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_unanalyzed_plans() {
+        let path = temp_copy("test-data/constraints.sqlite", "plan-unanalyzed");
+        let mut db = open_rw(&path);
+        // Without statistics, an index is assumed to be worth using.
+        let delete = stats(&mut db, "DELETE FROM users WHERE team = 2 OR team = 3");
+        assert_eq!(delete.rows_examined, 2, "OR can't use an index");
+        let delete = stats(
+            &mut db,
+            "DELETE FROM users WHERE (team = 1) AND name = 'alice'",
+        );
+        assert_eq!(delete.rows_examined, 1);
+        assert_eq!(count(&mut db, "users"), 0);
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_query_plan() {
+        let db = Database::new(
+            File::open("test-data/analyzed.sqlite").expect("Failed to open test database"),
+        )
+        .expect("Failed to parse test database");
+        let plan = |sql: &str| {
+            let statements =
+                sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                    .unwrap();
+            db.query_plan(&statements[0]).map(|steps| steps.join("\n"))
+        };
+        assert_eq!(plan("SELECT * FROM items").unwrap(), "SCAN items");
+        assert_eq!(
+            plan("DELETE FROM items WHERE category = 3").unwrap(),
+            "SEARCH items USING INDEX items_category (category=?)"
+        );
+        assert_eq!(
+            plan("UPDATE items SET id = 1 WHERE flag = 1 AND category = 5").unwrap(),
+            "SEARCH items USING INDEX items_category (category=?)"
+        );
+        assert_eq!(
+            plan("DELETE FROM items WHERE flag = 0").unwrap(),
+            "SCAN items"
+        );
+        assert_eq!(plan("DELETE FROM items").unwrap(), "");
+        assert_eq!(plan("INSERT INTO items VALUES (1, 2, 3)").unwrap(), "");
+        assert!(plan("SELECT * FROM missing").is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Reading and changing settings with `PRAGMA` statements
+
+use anyhow::{Context, Result};
+
+use super::{integrity::DEFAULT_MAX_PROBLEMS, Database};
+use crate::{
+    pager::{CacheSize, CheckpointMode, JournalMode, Storage, SyncPolicy},
+    record::{OwnedValue, Value},
+    schema::{IndexColumn, ObjectKind, TableSchema},
+    Error,
+};
+
+impl<File: Storage> Database<File> {
+    /// Run `PRAGMA name`, or `PRAGMA name = value` if a value is given, calling `callback` with
+    /// each returned row.
+    ///
+    /// `sqlparser` only accepts numbers and quoted strings as values, so names have to be quoted,
+    /// as in `PRAGMA synchronous = 'normal'`.
+    pub(super) fn execute_pragma(
+        &mut self,
+        name: &sqlparser::ast::ObjectName,
+        value: Option<&sqlparser::ast::Value>,
+        callback: &mut impl FnMut(Vec<OwnedValue>) -> Result<()>,
+    ) -> Result<()> {
+        let [name] = name.0.as_slice() else {
+            anyhow::bail!("Pragmas on other schemas are unsupported");
+        };
+        let value = value.map(pragma_value).transpose()?;
+        match (name.value.to_ascii_lowercase().as_str(), value) {
+            ("synchronous", None) => callback(vec![Value::int(
+                self.pager.sync_policy().as_number().into(),
+            )]),
+            ("synchronous", Some(value)) => {
+                let policy = SyncPolicy::parse(&value).with_context(|| {
+                    Error::UnsupportedSql(format!("Unsupported synchronous setting {value:?}"))
+                })?;
+                anyhow::ensure!(
+                    !self.in_transaction(),
+                    "Safety level may not be changed inside a transaction"
+                );
+                self.pager.set_sync_policy(policy);
+                Ok(())
+            }
+            ("cache_size", None) => callback(vec![Value::int(self.pager.cache_size().as_pragma())]),
+            ("cache_size", Some(value)) => {
+                let size = value
+                    .parse()
+                    .with_context(|| format!("Invalid cache size {value:?}"))?;
+                self.pager.set_cache_size(CacheSize::from_pragma(size));
+                Ok(())
+            }
+            ("page_size", None) => callback(vec![Value::int(self.pager.page_size() as i64)]),
+            ("page_count", None) => callback(vec![Value::int(self.pager.page_count() as i64)]),
+            ("freelist_count", None) => {
+                let count = self.pager.header()?.freelist_count;
+                callback(vec![Value::int(count.into())])
+            }
+            ("encoding", None) => callback(vec![text_value(self.pager.text_encoding().as_str())]),
+            ("user_version", None) => {
+                let version = self.pager.header()?.user_version;
+                callback(vec![Value::int(version.into())])
+            }
+            ("application_id", None) => {
+                let id = self.pager.header()?.application_id;
+                callback(vec![Value::int(id.into())])
+            }
+            ("schema_version", None) => {
+                let cookie = self.pager.header()?.schema_cookie;
+                callback(vec![Value::int(cookie.into())])
+            }
+            (
+                name @ ("page_size" | "page_count" | "freelist_count" | "encoding" | "user_version"
+                | "application_id" | "schema_version"),
+                Some(_),
+            ) => anyhow::bail!("Changing {name} is unsupported"),
+            ("table_info", Some(table)) => {
+                let Some(table) = self.introspected_table(&table)? else {
+                    return Ok(());
+                };
+                for (idx, column) in table.columns.iter().enumerate() {
+                    let pk = table
+                        .primary_key
+                        .iter()
+                        .position(|&key_column| key_column == idx)
+                        .map_or(0, |position| position + 1);
+                    callback(vec![
+                        Value::int(idx as i64),
+                        text_value(&column.name),
+                        text_value(column.declared_type.as_deref().unwrap_or_default()),
+                        Value::int(column.not_null.into()),
+                        column.default.as_deref().map_or(Value::Null, text_value),
+                        Value::int(pk as i64),
+                    ])?;
+                }
+                Ok(())
+            }
+            ("index_list", Some(table)) => {
+                let Some(table) = self.introspected_table(&table)? else {
+                    return Ok(());
+                };
+                // Like SQLite, this lists the most recently created indexes first.
+                let indexes = self
+                    .schema
+                    .indexes
+                    .iter()
+                    .rev()
+                    .filter(|index| index.table_name.eq_ignore_ascii_case(&table.name));
+                for (seq, index) in indexes.enumerate() {
+                    let origin = if !index.name.starts_with("sqlite_autoindex_") {
+                        "c"
+                    } else if index.column_indices().as_ref() == Some(&table.primary_key) {
+                        "pk"
+                    } else {
+                        "u"
+                    };
+                    callback(vec![
+                        Value::int(seq as i64),
+                        text_value(&index.name),
+                        Value::int(index.unique.into()),
+                        text_value(origin),
+                        // Partial indexes are unimplemented, so none can be listed.
+                        Value::int(0),
+                    ])?;
+                }
+                Ok(())
+            }
+            ("index_info", Some(index)) => {
+                let Some(index) = self.schema.index(&index) else {
+                    return Ok(());
+                };
+                let table = self.table_schema(&index.table_name)?;
+                for (seqno, column) in index.columns.iter().enumerate() {
+                    // Like SQLite, expressions are listed as column -2, with no name.
+                    let (cid, name) = match column {
+                        IndexColumn::Column(idx) => {
+                            (*idx as i64, text_value(&table.columns[*idx].name))
+                        }
+                        IndexColumn::Expr(_) => (-2, Value::Null),
+                    };
+                    callback(vec![Value::int(seqno as i64), Value::int(cid), name])?;
+                }
+                Ok(())
+            }
+            ("integrity_check", value) => {
+                let max_problems = match value {
+                    Some(value) => value
+                        .parse()
+                        .with_context(|| format!("Invalid maximum number of problems {value:?}"))?,
+                    None => DEFAULT_MAX_PROBLEMS,
+                };
+                let problems = self.integrity_check(max_problems)?;
+                if problems.is_empty() {
+                    return callback(vec![text_value("ok")]);
+                }
+                for problem in problems {
+                    callback(vec![text_value(&problem)])?;
+                }
+                Ok(())
+            }
+            ("journal_mode", value) => {
+                if let Some(value) = value {
+                    let mode = match value.to_ascii_lowercase().as_str() {
+                        "delete" => JournalMode::Delete,
+                        "wal" => JournalMode::Wal,
+                        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+                            "Unsupported journal mode {value:?}"
+                        ))),
+                    };
+                    self.set_journal_mode(mode)?;
+                }
+                callback(vec![text_value(self.pager.journal_mode().as_str())])
+            }
+            ("wal_checkpoint", value) => {
+                let mode = match value.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                    None | Some("passive") => CheckpointMode::Passive,
+                    Some("full") => CheckpointMode::Full,
+                    Some(value) => anyhow::bail!(Error::UnsupportedSql(format!(
+                        "Unsupported checkpoint mode {value:?}"
+                    ))),
+                };
+                // Like SQLite, this reports a checkpoint of nothing outside of WAL mode.
+                let (busy, wal_frames, checkpointed_frames) =
+                    if self.pager.journal_mode() == JournalMode::Wal {
+                        let checkpoint = self.checkpoint(mode)?;
+                        (
+                            i64::from(checkpoint.busy),
+                            checkpoint.wal_frames as i64,
+                            checkpoint.checkpointed_frames as i64,
+                        )
+                    } else {
+                        (0, -1, -1)
+                    };
+                callback(vec![
+                    Value::int(busy),
+                    Value::int(wal_frames),
+                    Value::int(checkpointed_frames),
+                ])
+            }
+            (name, _) => anyhow::bail!(Error::UnsupportedSql(format!("Unsupported pragma {name}"))),
+        }
+    }
+}
+
+impl<File: Storage> Database<File> {
+    /// Get the definition of the table a pragma asks about, or `None` if there's no such table.
+    ///
+    /// Like SQLite, introspecting a missing table returns no rows rather than failing.
+    fn introspected_table(&self, name: &str) -> Result<Option<TableSchema>> {
+        if self.schema.object(ObjectKind::Table, name).is_none()
+            && !TableSchema::is_sqlite_schema(name)
+        {
+            return Ok(None);
+        }
+        Ok(Some(self.table_schema(name)?))
+    }
+}
+
+/// Get the names of the columns in the rows the pragma called `name` returns, as SQLite names
+/// them.
+///
+/// The pragmas which return a single value name its column after themselves.
+pub(super) fn pragma_columns(name: &sqlparser::ast::ObjectName) -> Vec<String> {
+    let name = name
+        .0
+        .last()
+        .map(|name| name.value.to_ascii_lowercase())
+        .unwrap_or_default();
+    let columns: &[&str] = match name.as_str() {
+        "table_info" => &["cid", "name", "type", "notnull", "dflt_value", "pk"],
+        "index_list" => &["seq", "name", "unique", "origin", "partial"],
+        "index_info" => &["seqno", "cid", "name"],
+        "wal_checkpoint" => &["busy", "log", "checkpointed"],
+        _ => return vec![name],
+    };
+    columns.iter().map(|&column| column.to_owned()).collect()
+}
+
+/// Make a text value to return from a pragma.
+fn text_value(text: &str) -> OwnedValue {
+    Value::String(Box::from(text.as_bytes()))
+}
+
+/// Get the text of a value given to a pragma.
+fn pragma_value(value: &sqlparser::ast::Value) -> Result<String> {
+    match value {
+        sqlparser::ast::Value::Number(number, _) => Ok(number.clone()),
+        sqlparser::ast::Value::SingleQuotedString(string)
+        | sqlparser::ast::Value::DoubleQuotedString(string) => Ok(string.clone()),
+        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unsupported pragma value {value}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{open_rw, query, run, temp_copy};
+    use super::*;
+
+    #[test]
+    fn test_pragma_columns() {
+        let db = Database::new(std::fs::File::open("test-data/constraints.sqlite").unwrap())
+            .expect("Failed to parse test database");
+        let columns = |sql| {
+            let statement =
+                &sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                    .unwrap()[0];
+            db.statement_columns(statement).unwrap()
+        };
+        assert_eq!(
+            columns("PRAGMA table_info('users')"),
+            ["cid", "name", "type", "notnull", "dflt_value", "pk"],
+        );
+        assert_eq!(columns("PRAGMA Page_Size"), ["page_size"]);
+        assert_eq!(columns("PRAGMA journal_mode = 'wal'"), ["journal_mode"]);
+    }
+
+    #[test]
+    fn test_synchronous() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "pragma-synchronous");
+        let mut db = open_rw(&path);
+        assert_eq!(
+            query(&mut db, "PRAGMA synchronous").unwrap(),
+            [[Value::int(2)]]
+        );
+        run(&mut db, "PRAGMA synchronous = 'off'").unwrap();
+        assert_eq!(db.pager.sync_policy(), SyncPolicy::Off);
+        run(&mut db, "PRAGMA synchronous(1)").unwrap();
+        assert_eq!(
+            query(&mut db, "PRAGMA synchronous").unwrap(),
+            [[Value::int(1)]]
+        );
+        assert!(run(&mut db, "PRAGMA synchronous = 'extra'").is_err());
+
+        run(&mut db, "BEGIN").unwrap();
+        assert!(run(&mut db, "PRAGMA synchronous = 2").is_err());
+        run(&mut db, "INSERT INTO t1 VALUES (4)").unwrap();
+        run(&mut db, "COMMIT").unwrap();
+        assert_eq!(db.pager.sync_policy(), SyncPolicy::Normal);
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_cache_size() {
+        let mut db = Database::new(std::fs::File::open("test-data/many-tables.sqlite").unwrap())
+            .expect("Failed to parse test database");
+        assert_eq!(
+            query(&mut db, "PRAGMA cache_size").unwrap(),
+            [[Value::int(-2000)]]
+        );
+        run(&mut db, "PRAGMA cache_size = 3").unwrap();
+        assert_eq!(db.pager.cache_capacity(), 3 * db.pager.page_size());
+        run(&mut db, "PRAGMA cache_size = '-16'").unwrap();
+        assert_eq!(db.pager.cache_capacity(), 16 * 1024);
+        assert_eq!(
+            query(&mut db, "PRAGMA cache_size").unwrap(),
+            [[Value::int(-16)]]
+        );
+        assert!(run(&mut db, "PRAGMA cache_size = 'big'").is_err());
+    }
+
+    #[test]
+    fn test_header_pragmas() {
+        let path = temp_copy("test-data/header-fields.sqlite", "pragma-header");
+        let mut db = open_rw(&path);
+        let mut pragma = |name: &str| {
+            query(&mut db, &format!("PRAGMA {name}"))
+                .unwrap()
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pragma("page_size"), [["1024"]]);
+        assert_eq!(pragma("page_count"), [["13"]]);
+        assert_eq!(pragma("freelist_count"), [["6"]]);
+        assert_eq!(pragma("encoding"), [[r#""UTF-8""#]]);
+        assert_eq!(pragma("user_version"), [["-7"]]);
+        assert_eq!(pragma("application_id"), [["1234567890"]]);
+        assert_eq!(pragma("schema_version"), [["1"]]);
+        assert_eq!(pragma("journal_mode"), [[r#""delete""#]]);
+        assert!(run(&mut db, "PRAGMA user_version = 3").is_err());
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_introspection_pragmas() {
+        let path = temp_copy("test-data/constraints.sqlite", "pragma-introspection");
+        let mut db = open_rw(&path);
+        let mut pragma = |sql: &str| {
+            query(&mut db, sql)
+                .unwrap()
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            pragma("PRAGMA table_info('users')"),
+            [
+                ["0", r#""id""#, r#""INTEGER""#, "0", "null", "1"],
+                ["1", r#""email""#, r#""TEXT""#, "0", "null", "0"],
+                ["2", r#""name""#, r#""TEXT""#, "0", "null", "0"],
+                ["3", r#""team""#, r#""""#, "0", "null", "0"],
+            ],
+        );
+        assert_eq!(
+            pragma("PRAGMA index_list('users')"),
+            [
+                ["0", r#""users_team""#, "0", r#""c""#, "0"],
+                ["1", r#""sqlite_autoindex_users_2""#, "1", r#""u""#, "0"],
+                ["2", r#""sqlite_autoindex_users_1""#, "1", r#""u""#, "0"],
+            ],
+        );
+        assert_eq!(
+            pragma("PRAGMA index_list('tags')"),
+            [["0", r#""sqlite_autoindex_tags_1""#, "1", r#""pk""#, "0"]],
+        );
+        assert_eq!(
+            pragma("PRAGMA index_info('sqlite_autoindex_users_2')"),
+            [["0", "2", r#""name""#], ["1", "3", r#""team""#]],
+        );
+        // Missing tables and indexes have nothing to report.
+        assert!(pragma("PRAGMA table_info('missing')").is_empty());
+        assert!(pragma("PRAGMA index_list('missing')").is_empty());
+        assert!(pragma("PRAGMA index_info('missing')").is_empty());
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_journal_mode_wal() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "pragma-journal-mode");
+        let wal_path = crate::pager::wal_path_for(&path);
+        let shm_path = crate::pager::shm_path_for(&path);
+        let open = |path: &std::path::PathBuf| {
+            let file = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .open(path)
+                .expect("Failed to open test database");
+            Database::with_journal(file, crate::pager::journal_path_for(path))
+                .expect("Failed to parse test database")
+        };
+        let mut db = open(&path);
+        assert_eq!(
+            query(&mut db, "PRAGMA wal_checkpoint").unwrap(),
+            [[Value::int(0), Value::int(-1), Value::int(-1)]]
+        );
+        assert_eq!(
+            query(&mut db, "PRAGMA journal_mode = 'wal'").unwrap(),
+            [[Value::String(Box::from(&b"wal"[..]))]]
+        );
+        assert!(wal_path.exists() && shm_path.exists());
+        run(&mut db, "INSERT INTO t1 VALUES (4)").unwrap();
+        run(&mut db, "INSERT INTO t1 VALUES (5)").unwrap();
+        assert_eq!(db.pager.wal_frame_count(), 2);
+        assert_eq!(
+            query(&mut db, "PRAGMA wal_checkpoint('full')").unwrap(),
+            [[Value::int(0), Value::int(2), Value::int(2)]]
+        );
+        // The checkpointed WAL is started over by the next commit.
+        run(&mut db, "INSERT INTO t1 VALUES (6)").unwrap();
+        assert_eq!(db.pager.wal_frame_count(), 1);
+
+        run(&mut db, "PRAGMA journal_mode = 'delete'").unwrap();
+        assert!(!wal_path.exists() && !shm_path.exists());
+        drop(db);
+        let mut db = open(&path);
+        assert_eq!(
+            query(&mut db, "SELECT COUNT(*) FROM t1").unwrap(),
+            [[Value::int(3)]]
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,313 @@
+//! Recovering what rows can be found in a damaged database with `.recover`
+//!
+//! Rather than reading each table by following its btree down from its root, which a damaged page
+//! cuts off partway, every page of the file is read directly and each cell of each page which
+//! parses as a table leaf becomes a row. The btrees are only followed to work out which table each
+//! leaf belongs to, and the rows of leaves which can't be reached from any table are put in a
+//! `lost_and_found` table, as the `sqlite3` shell does.
+
+use std::{collections::HashMap, fmt::Write as _, io::Write};
+
+use anyhow::Result;
+
+use super::{
+    dump::{quote_identifier, quote_value},
+    Database,
+};
+use crate::{
+    btree::PartialPayload,
+    page::ParsedPage,
+    pager::Storage,
+    record::{OwnedValue, Record},
+    schema::{ObjectKind, SchemaObject},
+};
+
+/// The columns of the table rows which can't be placed in their own tables are written to, before
+/// their values' columns.
+const LOST_AND_FOUND_COLUMNS: &str = "rootpgno INTEGER, pgno INTEGER, nfield INTEGER, id INTEGER";
+
+/// A table whose rows can be recovered into it
+struct RecoveredTable {
+    /// The statement which inserts a row into the table, up to its values
+    insert: String,
+    /// The column filled in with the rowid of each row, if any
+    rowid_alias: Option<usize>,
+    /// The value of each column of rows which don't have it, as SQL, or `None` if the table's
+    /// definition couldn't be parsed, so its rows are written as they're found
+    defaults: Option<Vec<String>>,
+}
+
+/// A row found in a page which couldn't be placed in its table
+struct LostRow {
+    /// The page the row was found in
+    page_idx: usize,
+    /// The rowid of the row
+    row_id: i64,
+    /// The values in the row
+    values: Vec<OwnedValue>,
+}
+
+impl<File: Storage> Database<File> {
+    /// Write SQL statements which recreate as much of the database as can be found to `out`, as
+    /// the `sqlite3` shell's `.recover` command does.
+    ///
+    /// This is for databases too damaged to [`dump`](Self::dump). Every page is read, whether or
+    /// not it can be reached from the schema, so rows in pages which the damage has cut off from
+    /// their table, or which have been freed, are still found. Those whose table can't be told are
+    /// written to a `lost_and_found` table, along with the page they were found in and their rowid.
+    ///
+    /// Rows which don't parse are skipped, and the internal `sqlite_` tables aren't recovered.
+    pub fn recover(&mut self, out: &mut impl Write) -> crate::Result<()> {
+        Ok(self.with_lock(|db| db.write_recovery(out))?)
+    }
+
+    /// Write the statements recreating the database to `out`, while holding a lock on it.
+    fn write_recovery(&mut self, out: &mut impl Write) -> Result<()> {
+        self.clear_interrupt();
+        let (tables, others): (Vec<_>, Vec<_>) = self
+            .schema
+            .objects
+            .iter()
+            .filter(|object| object.sql.is_some())
+            .cloned()
+            .partition(|object| object.kind == ObjectKind::Table);
+
+        writeln!(out, "BEGIN;")?;
+        // `sqlite_schema` is always rooted at the first page, and its rows are the schema itself.
+        let mut roots = HashMap::from([(1, None)]);
+        for table in &tables {
+            let internal = table.name.to_ascii_lowercase().starts_with("sqlite_");
+            if !internal {
+                writeln!(out, "{};", table.sql.as_deref().unwrap_or_default())?;
+            }
+            if let Some(root_page) = table.root_page.filter(|&root_page| root_page != 0) {
+                roots.insert(root_page, Some(table).filter(|_| !internal));
+            }
+        }
+        let owners = self.page_owners(roots.keys().copied());
+
+        let mut recovered = HashMap::new();
+        let mut lost = Vec::new();
+        for page_idx in 1..=self.pager.page_count() {
+            let table = owners.get(&page_idx).map(|root_page| roots[root_page]);
+            if table.is_some_and(|table| table.is_none()) {
+                // The rows of `sqlite_schema` and the internal tables aren't recovered.
+                continue;
+            }
+            for (row_id, values) in self.leaf_rows(page_idx) {
+                self.check_interrupt()?;
+                let table = table
+                    .flatten()
+                    .map(|table| {
+                        recovered
+                            .entry(table.name.as_str())
+                            .or_insert_with(|| self.recovered_table(table))
+                    })
+                    .filter(|table| {
+                        table
+                            .defaults
+                            .as_ref()
+                            .map_or(true, |defaults| values.len() <= defaults.len())
+                    });
+                let Some(table) = table else {
+                    lost.push(LostRow {
+                        page_idx,
+                        row_id,
+                        values,
+                    });
+                    continue;
+                };
+                let mut values = values.iter().map(quote_value).collect::<Vec<_>>();
+                if let Some(value) = table.rowid_alias.and_then(|idx| values.get_mut(idx)) {
+                    *value = row_id.to_string();
+                }
+                if let Some(defaults) = &table.defaults {
+                    values.extend(defaults.iter().skip(values.len()).cloned());
+                }
+                writeln!(out, "{}{});", table.insert, values.join(","))?;
+            }
+        }
+        self.write_lost_rows(&lost, out)?;
+
+        for object in &others {
+            writeln!(out, "{};", object.sql.as_deref().unwrap_or_default())?;
+        }
+        writeln!(out, "COMMIT;")?;
+        Ok(())
+    }
+
+    /// Find which of the btrees rooted at `roots` each page is in, as far as they can be followed.
+    ///
+    /// Pages which can't be read, or which are already in another btree, aren't followed.
+    fn page_owners(&mut self, roots: impl IntoIterator<Item = usize>) -> HashMap<usize, usize> {
+        let page_count = self.pager.page_count();
+        let mut owners = HashMap::new();
+        for root_page in roots {
+            let mut stack = vec![root_page];
+            while let Some(page_idx) = stack.pop() {
+                if !(1..=page_count).contains(&page_idx) || owners.contains_key(&page_idx) {
+                    continue;
+                }
+                let Ok(page) = self.pager.read_page(page_idx) else {
+                    continue;
+                };
+                owners.insert(page_idx, root_page);
+                if let ParsedPage::BTreeTableInternal(page) = page.parse() {
+                    stack.extend(page.cells().map(|cell| cell.left_child_page as usize));
+                    stack.push(page.rightmost_child_idx() as usize);
+                }
+            }
+        }
+        owners
+    }
+
+    /// Read the rows in the page at `page_idx`, if it's a table leaf, skipping any which don't
+    /// parse.
+    fn leaf_rows(&mut self, page_idx: usize) -> Vec<(i64, Vec<OwnedValue>)> {
+        let encoding = self.pager.text_encoding();
+        let Ok(page) = self.pager.read_page(page_idx) else {
+            return Vec::new();
+        };
+        let ParsedPage::BTreeTableLeaf(leaf) = page.parse() else {
+            return Vec::new();
+        };
+        let cells = leaf
+            .cells()
+            .map(|cell| (cell.row_id(), PartialPayload::of(&cell)))
+            .collect::<Vec<_>>();
+        cells
+            .into_iter()
+            .filter_map(|(row_id, payload)| {
+                let payload = payload.complete(&mut self.pager).ok()?;
+                let values = Record::parse(&payload).ok()?.checked_values().ok()?;
+                let values = values
+                    .iter()
+                    .map(|value| value.decode_text(encoding))
+                    .collect();
+                Some((row_id, values))
+            })
+            .collect()
+    }
+
+    /// Get how rows are recovered into `table`.
+    fn recovered_table(&self, table: &SchemaObject) -> RecoveredTable {
+        let schema = self.schema.table(&table.name);
+        RecoveredTable {
+            // Rows may turn up more than once, in pages which have been freed or copied.
+            insert: format!(
+                "INSERT OR IGNORE INTO {} VALUES(",
+                quote_identifier(&table.name)
+            ),
+            rowid_alias: schema.and_then(|schema| schema.rowid_alias),
+            defaults: schema.map(|schema| {
+                schema
+                    .columns
+                    .iter()
+                    .map(|column| column.default.clone().unwrap_or_else(|| "NULL".to_owned()))
+                    .collect()
+            }),
+        }
+    }
+
+    /// Write the statements creating the `lost_and_found` table and filling it with `rows` to
+    /// `out`, if there are any.
+    ///
+    /// The table is given a different name if there's already one called `lost_and_found`.
+    fn write_lost_rows(&self, rows: &[LostRow], out: &mut impl Write) -> Result<()> {
+        let Some(fields) = rows.iter().map(|row| row.values.len()).max() else {
+            return Ok(());
+        };
+        // One of these names is free, since there are more of them than tables.
+        let name = std::iter::once("lost_and_found".to_owned())
+            .chain((0..self.schema.tables.len()).map(|n| format!("lost_and_found_{n}")))
+            .find(|name| self.schema.table(name).is_none())
+            .unwrap_or_default();
+        let mut columns = String::new();
+        for idx in 0..fields {
+            // Writing to a `String` can't fail.
+            let _ = write!(columns, ", c{idx}");
+        }
+        writeln!(
+            out,
+            "CREATE TABLE {name}({LOST_AND_FOUND_COLUMNS}{columns});"
+        )?;
+        for row in rows {
+            let values = row
+                .values
+                .iter()
+                .map(quote_value)
+                .chain(std::iter::repeat_with(|| "NULL".to_owned()))
+                .take(fields)
+                .collect::<Vec<_>>();
+            writeln!(
+                out,
+                "INSERT INTO {name} VALUES(NULL,{},{},{},{});",
+                row.page_idx,
+                row.values.len(),
+                row.row_id,
+                values.join(",")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::tests::run, Database};
+
+    #[test]
+    fn test_recover() {
+        let mut db = Database::from_bytes(
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database"),
+        )
+        .expect("Failed to parse test database");
+        let mut out = Vec::new();
+        db.recover(&mut out).expect("Failed to recover database");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "BEGIN;
+CREATE TABLE users(id INTEGER PRIMARY KEY, email TEXT UNIQUE, name TEXT, team, UNIQUE(name, team));
+CREATE TABLE tags(name TEXT PRIMARY KEY, n);
+INSERT OR IGNORE INTO users VALUES(1,'a@example.com','alice',1);
+INSERT OR IGNORE INTO users VALUES(2,'b@example.com','bob',2);
+INSERT OR IGNORE INTO tags VALUES('x',1);
+CREATE INDEX users_team ON users(team);
+COMMIT;
+",
+        );
+
+        // Once the root of a table is overwritten, its leaves can't be reached from it.
+        for n in 2..=100 {
+            run(
+                &mut db,
+                &format!("INSERT INTO tags VALUES ('{n:0>100}', {n})"),
+            )
+            .expect("Failed to insert");
+        }
+        let root_page = db.table_root_page("tags").unwrap();
+        let page_size = db.pager.page_size();
+        let mut bytes = db.into_bytes();
+        bytes[(root_page - 1) * page_size] = 0;
+        let mut db = Database::from_bytes(bytes).expect("Failed to parse damaged database");
+        let mut out = Vec::new();
+        db.recover(&mut out).expect("Failed to recover database");
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 108);
+        assert_eq!(
+            lines[5],
+            "CREATE TABLE lost_and_found(rootpgno INTEGER, pgno INTEGER, nfield INTEGER, \
+             id INTEGER, c0, c1);"
+        );
+        let lost = &lines[6..106];
+        assert!(lost
+            .iter()
+            .all(|line| line.starts_with("INSERT INTO lost_and_found VALUES(NULL,")));
+        assert!(lost.iter().any(|line| line.ends_with(",2,1,'x',1);")));
+        assert!(lost
+            .iter()
+            .any(|line| line.ends_with(&format!(",2,100,'{:0>100}',100);", 100))));
+        assert_eq!(lines[106], "CREATE INDEX users_team ON users(team);");
+    }
+}
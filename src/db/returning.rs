@@ -0,0 +1,213 @@
+//! Evaluating the `RETURNING` clause of statements which change rows
+
+use anyhow::Result;
+use sqlparser::ast::{Expr, SelectItem, WildcardAdditionalOptions};
+
+use super::{insert::column_value, select::expr_column_name};
+use crate::{
+    expr::evaluate,
+    record::{OwnedValue, Value},
+    rows::ColumnInfo,
+    schema::{ColumnDef, TableSchema},
+};
+
+/// A `RETURNING` clause, checked against the table the statement changes.
+pub(super) struct Returning<'a> {
+    /// What to return for each changed row
+    items: Vec<ReturningItem<'a>>,
+}
+
+/// One of the comma-separated parts of a `RETURNING` clause.
+enum ReturningItem<'a> {
+    /// Every column of the row
+    Wildcard,
+    /// The value of an expression over the row, and the name of its column
+    Expr(&'a Expr, String),
+}
+
+impl<'a> Returning<'a> {
+    /// Check the items of a `RETURNING` clause, returning `None` if there aren't any.
+    pub(super) fn resolve(
+        schema: &TableSchema,
+        items: Option<&'a Vec<SelectItem>>,
+    ) -> Result<Option<Self>> {
+        let Some(items) = items else {
+            return Ok(None);
+        };
+        let items = items
+            .iter()
+            .map(|item| match item {
+                SelectItem::Wildcard(options) if is_plain_wildcard(options) => {
+                    Ok(ReturningItem::Wildcard)
+                }
+                SelectItem::QualifiedWildcard(name, options)
+                    if is_plain_wildcard(options)
+                        && name.0.len() == 1
+                        && name.0[0].value.eq_ignore_ascii_case(&schema.name) =>
+                {
+                    Ok(ReturningItem::Wildcard)
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    Ok(ReturningItem::Expr(expr, expr_column_name(expr)?))
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    Ok(ReturningItem::Expr(expr, alias.value.clone()))
+                }
+                _ => anyhow::bail!(crate::Error::UnsupportedSql(format!(
+                    "Unimplemented RETURNING item: {item}"
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let returning = Self { items };
+        // Check the names in the clause now, rather than only once a row has changed.
+        returning.row(schema, &vec![Value::Null; schema.columns.len()], 0)?;
+        Ok(Some(returning))
+    }
+
+    /// Evaluate the clause over a row of the table.
+    pub(super) fn row(
+        &self,
+        schema: &TableSchema,
+        row: &[OwnedValue],
+        rowid: i64,
+    ) -> Result<Vec<OwnedValue>> {
+        let mut values = Vec::new();
+        for item in &self.items {
+            match item {
+                ReturningItem::Wildcard => {
+                    values.extend(row.iter().enumerate().map(|(idx, value)| {
+                        if Some(idx) == schema.rowid_alias {
+                            Value::int(rowid)
+                        } else {
+                            value.clone()
+                        }
+                    }));
+                }
+                ReturningItem::Expr(expr, _) => values.push(evaluate(expr, &mut |name| {
+                    column_value(schema, row, rowid, name)
+                })?),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Get what's known about the columns of the rows the clause returns.
+    pub(super) fn columns(&self, schema: &TableSchema) -> Vec<ColumnInfo> {
+        let column_info = |column: &ColumnDef| ColumnInfo {
+            name: column.name.clone(),
+            declared_type: column.declared_type.clone(),
+            table: Some(schema.name.clone()),
+            origin: Some(column.name.clone()),
+        };
+        let mut columns = Vec::new();
+        for item in &self.items {
+            match item {
+                ReturningItem::Wildcard => columns.extend(schema.columns.iter().map(column_info)),
+                ReturningItem::Expr(expr, name) => {
+                    // Only columns which just read a column of the table say where they're from.
+                    let column = match expr {
+                        Expr::Identifier(column) => Some(column),
+                        Expr::CompoundIdentifier(parts) => match parts.as_slice() {
+                            [table, column] if table.value.eq_ignore_ascii_case(&schema.name) => {
+                                Some(column)
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let info = column
+                        .and_then(|column| {
+                            schema
+                                .columns
+                                .iter()
+                                .find(|def| def.name.eq_ignore_ascii_case(&column.value))
+                        })
+                        .map_or_else(ColumnInfo::default, column_info);
+                    columns.push(ColumnInfo {
+                        name: name.clone(),
+                        ..info
+                    });
+                }
+            }
+        }
+        columns
+    }
+}
+
+/// Whether a `*` has none of the options that other databases allow on it.
+pub(super) fn is_plain_wildcard(options: &WildcardAdditionalOptions) -> bool {
+    matches!(
+        options,
+        WildcardAdditionalOptions {
+            opt_ilike: None,
+            opt_exclude: None,
+            opt_except: None,
+            opt_replace: None,
+            opt_rename: None,
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::tests::{open_rw, query, temp_copy};
+
+    #[test]
+    fn test_returning() {
+        let path = temp_copy("test-data/constraints.sqlite", "returning");
+        let mut db = open_rw(&path);
+        let mut returned = |sql| {
+            query(&mut db, sql)
+                .expect("Failed to run statement")
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            returned(
+                "INSERT INTO users(email, name) VALUES ('c@example.com', 'carol') \
+                 RETURNING *, users.id * 2 AS double"
+            ),
+            [["3", r#""c@example.com""#, r#""carol""#, "null", "6"]],
+        );
+        assert!(returned(
+            "INSERT OR IGNORE INTO users(id) VALUES (1) RETURNING id; \
+             INSERT INTO tags VALUES ('x', 2) ON CONFLICT DO NOTHING RETURNING *"
+        )
+        .is_empty());
+        assert_eq!(
+            returned(
+                "INSERT INTO tags VALUES ('x', 2) ON CONFLICT (name) \
+                 DO UPDATE SET n = n + excluded.n RETURNING n"
+            ),
+            [["3"]],
+        );
+        assert_eq!(
+            returned("UPDATE users SET team = 5 WHERE id > 1 RETURNING rowid, name, team"),
+            [["2", r#""bob""#, "5"], ["3", r#""carol""#, "5"]],
+        );
+        assert_eq!(
+            returned("DELETE FROM users WHERE name = 'alice' RETURNING email"),
+            [[r#""a@example.com""#]],
+        );
+
+        let statement = &sqlparser::parser::Parser::parse_sql(
+            &sqlparser::dialect::SQLiteDialect {},
+            "UPDATE users SET team = 1 RETURNING *, name AS n, users.id + 1",
+        )
+        .unwrap()[0];
+        assert_eq!(
+            db.statement_columns(statement).unwrap(),
+            ["id", "email", "name", "team", "n", "users.id + 1"],
+        );
+
+        // Names are checked even if no rows change.
+        assert_eq!(
+            query(&mut db, "DELETE FROM users WHERE 0 RETURNING missing")
+                .unwrap_err()
+                .to_string(),
+            "no such column: missing",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
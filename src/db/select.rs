@@ -0,0 +1,614 @@
+//! Running `SELECT` statements, including over views
+
+use anyhow::{Context, Result};
+use sqlparser::ast::{
+    Expr, FunctionArg, FunctionArgExpr, Ident, Query, Select, SelectItem, SetExpr, TableFactor,
+    TableFunctionArgs, TableWithJoins,
+};
+
+use super::{is_count_star, plain_table_name, returning::is_plain_wildcard, Database};
+use crate::{
+    expr::{evaluate, evaluate_constant},
+    pager::Storage,
+    record::{OwnedValue, Value},
+    rows::ColumnInfo,
+    table_iter::TableIter,
+    vtab::{table_function, VirtualTable},
+    Error,
+};
+
+/// Where a `SELECT` reads its rows from.
+enum Source<'a> {
+    /// The table or view with this name
+    Named(&'a str),
+    /// A table-valued function, called with these arguments
+    Function(&'static dyn VirtualTable, &'a [FunctionArg]),
+}
+
+/// One of the comma-separated result columns of a `SELECT`.
+enum ResultColumn<'a> {
+    /// Every column of the source
+    Wildcard,
+    /// The value of an expression over each row
+    Expr(&'a Expr),
+}
+
+impl<File: Storage> Database<File> {
+    /// Run a query, calling `callback` with each row it returns.
+    ///
+    /// `views` holds the names of the views whose queries are being run, innermost last, so that
+    /// a view defined in terms of itself is caught instead of recursing forever.
+    pub(super) fn execute_query(
+        &mut self,
+        query: &Query,
+        views: &mut Vec<String>,
+        callback: &mut dyn FnMut(Vec<OwnedValue>) -> Result<()>,
+    ) -> Result<()> {
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            anyhow::bail!(Error::UnsupportedSql("Unimplemented command".to_owned()));
+        };
+        let source = select_source(select)?;
+        let count_rows = matches!(
+            select.projection.as_slice(),
+            [SelectItem::UnnamedExpr(Expr::Function(function))] if is_count_star(function)
+        );
+        let result_columns = if count_rows {
+            Vec::new()
+        } else {
+            select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::Wildcard(options) if is_plain_wildcard(options) => {
+                        Ok(ResultColumn::Wildcard)
+                    }
+                    SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                        Ok(ResultColumn::Expr(expr))
+                    }
+                    _ => {
+                        anyhow::bail!(Error::UnsupportedSql("Unimplemented projection".to_owned()))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        let source = match source {
+            Source::Named(name) => name,
+            Source::Function(table, args) => {
+                let rows = function_rows(table, args)?;
+                if count_rows {
+                    callback(vec![Value::int(
+                        rows.len().try_into().context("Too many rows to count")?,
+                    )])?;
+                } else {
+                    let columns = function_columns(table);
+                    for row in rows {
+                        callback(project(&result_columns, table.name(), &columns, &row)?)?;
+                    }
+                }
+                return Ok(());
+            }
+        };
+        // Only look up the names of the columns if they're needed, so that tables whose
+        // definitions couldn't be parsed can still be read with `*`.
+        let columns = if result_columns
+            .iter()
+            .any(|column| matches!(column, ResultColumn::Expr(_)))
+        {
+            self.source_columns(source, views)?
+                .into_iter()
+                .map(|column| column.name)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        if let Some(view) = self.schema.view(source) {
+            // Run the view's query as a derived table, then select from its rows.
+            let view_query = view.query.clone();
+            enter_view(views, source)?;
+            let mut rows = Vec::new();
+            self.execute_query(&view_query, views, &mut |row| {
+                rows.push(row);
+                Ok(())
+            })?;
+            views.pop();
+            if count_rows {
+                callback(vec![Value::int(
+                    rows.len().try_into().context("Too many rows to count")?,
+                )])?;
+            } else {
+                for row in rows {
+                    callback(project(&result_columns, source, &columns, &row)?)?;
+                }
+            }
+        } else if count_rows {
+            let count = self.table(source)?.count()?;
+            self.rows_examined += count;
+            callback(vec![Value::int(
+                count.try_into().context("Too many rows to count")?,
+            )])?;
+        } else {
+            for row in TableIter::new(self, source)? {
+                callback(project(&result_columns, source, &columns, &row)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe how `query` reads each table, for [`Database::query_plan`].
+    ///
+    /// Views are described by the tables their queries read, as SQLite flattens them into the
+    /// query.
+    pub(super) fn query_scans(
+        &self,
+        query: &Query,
+        views: &mut Vec<String>,
+    ) -> Result<Vec<String>> {
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            anyhow::bail!(Error::UnsupportedSql("Unimplemented command".to_owned()));
+        };
+        let name = match select_source(select)? {
+            Source::Named(name) => name,
+            Source::Function(table, _) => {
+                return Ok(vec![format!("SCAN {} VIRTUAL TABLE", table.name())]);
+            }
+        };
+        let Some(view) = self.schema.view(name) else {
+            self.table_root_page(name)?;
+            return Ok(vec![format!("SCAN {name}")]);
+        };
+        enter_view(views, name)?;
+        let scans = self.query_scans(&view.query, views);
+        views.pop();
+        scans
+    }
+
+    /// Get what's known about the columns of the table or view with the given name.
+    fn source_columns(&self, name: &str, views: &mut Vec<String>) -> Result<Vec<ColumnInfo>> {
+        let Some(view) = self.schema.view(name) else {
+            let schema = self.table_schema(name)?;
+            return Ok(schema
+                .columns
+                .into_iter()
+                .map(|column| ColumnInfo {
+                    table: Some(schema.name.clone()),
+                    origin: Some(column.name.clone()),
+                    name: column.name,
+                    declared_type: column.declared_type,
+                })
+                .collect());
+        };
+        let view_columns = view.columns.clone();
+        let view_query = view.query.clone();
+        enter_view(views, name)?;
+        let columns = self.query_columns(&view_query, views);
+        views.pop();
+        if view_columns.is_empty() {
+            return columns;
+        }
+        // The view names its columns itself, so its query only says where they come from, and
+        // isn't needed to know their names.
+        let columns = columns.unwrap_or_default();
+        Ok(view_columns
+            .into_iter()
+            .enumerate()
+            .map(|(idx, name)| ColumnInfo {
+                name,
+                ..columns.get(idx).cloned().unwrap_or_default()
+            })
+            .collect())
+    }
+
+    /// Get what's known about the columns a query returns, named in the same way as SQLite names
+    /// them.
+    pub(super) fn query_columns(
+        &self,
+        query: &Query,
+        views: &mut Vec<String>,
+    ) -> Result<Vec<ColumnInfo>> {
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            anyhow::bail!(Error::UnsupportedSql("Unimplemented command".to_owned()));
+        };
+        let mut columns = Vec::new();
+        for item in &select.projection {
+            match item {
+                SelectItem::Wildcard(options) if is_plain_wildcard(options) => {
+                    match select_source(select)? {
+                        Source::Named(name) => columns.extend(self.source_columns(name, views)?),
+                        Source::Function(table, _) => columns
+                            .extend(function_columns(table).into_iter().map(ColumnInfo::named)),
+                    }
+                }
+                SelectItem::ExprWithAlias { expr, alias } => columns.push(ColumnInfo {
+                    name: alias.value.clone(),
+                    ..self.expr_column_info(select, expr, views)
+                }),
+                SelectItem::UnnamedExpr(expr) => columns.push(ColumnInfo {
+                    name: expr_column_name(expr)?,
+                    ..self.expr_column_info(select, expr, views)
+                }),
+                _ => anyhow::bail!(Error::UnsupportedSql("Unimplemented projection".to_owned())),
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Get where the result column of `select` holding `expr` comes from, if it's just a column
+    /// of the table or view it reads.
+    ///
+    /// Nothing is known about columns holding other expressions, so the result is empty for them.
+    fn expr_column_info(
+        &self,
+        select: &Select,
+        expr: &Expr,
+        views: &mut Vec<String>,
+    ) -> ColumnInfo {
+        let Ok(Source::Named(source)) = select_source(select) else {
+            return ColumnInfo::default();
+        };
+        let column = match expr {
+            Expr::Identifier(column) => column,
+            Expr::CompoundIdentifier(parts) => match parts.as_slice() {
+                [table, column] if table.value.eq_ignore_ascii_case(source) => column,
+                _ => return ColumnInfo::default(),
+            },
+            _ => return ColumnInfo::default(),
+        };
+        self.source_columns(source, views)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|info| info.name.eq_ignore_ascii_case(&column.value))
+            .unwrap_or_default()
+    }
+}
+
+/// Get the name SQLite gives the column of results holding `expr`, when it has no alias.
+///
+/// Columns are named after the column they read, if `expr` is just a column, and after the text
+/// of `expr` otherwise.
+pub(super) fn expr_column_name(expr: &Expr) -> Result<String> {
+    Ok(match expr {
+        Expr::Identifier(ident) => ident.value.clone(),
+        Expr::CompoundIdentifier(parts) => parts.last().context("Empty column name")?.value.clone(),
+        expr => expr.to_string(),
+    })
+}
+
+/// Get the table, view, or table-valued function a `SELECT` reads from, checking that it doesn't
+/// use any features which aren't implemented yet.
+fn select_source(select: &Select) -> Result<Source<'_>> {
+    // TODO Loosen these restrictions as I implement more of it.
+    let Select {
+        distinct: None,
+        top: None,
+        projection: _,
+        into: None,
+        from,
+        lateral_views,
+        prewhere: None,
+        selection: None,
+        group_by: _, // TODO figure out this field
+        cluster_by,
+        distribute_by,
+        sort_by,
+        having: None,
+        named_window,
+        qualify: None,
+        window_before_qualify: _,
+        value_table_mode: None,
+        connect_by: None,
+    } = select
+    else {
+        anyhow::bail!(Error::UnsupportedSql(
+            "Unimplemented SELECT arguments".to_owned()
+        ));
+    };
+    if !(lateral_views.is_empty()
+        && cluster_by.is_empty()
+        && distribute_by.is_empty()
+        && sort_by.is_empty()
+        && named_window.is_empty())
+    {
+        anyhow::bail!(Error::UnsupportedSql(
+            "Unimplemented SELECT arguments 2".to_owned()
+        ));
+    }
+    let from = from
+        .first()
+        .take_if(|_| from.len() == 1)
+        .context(Error::UnsupportedSql(
+            "Unimplemented FROM target".to_owned(),
+        ))?;
+    if let Some((name, args)) = function_call(from) {
+        let table = table_function(name)
+            .with_context(|| Error::NotFound(format!("no such table-valued function: {name}")))?;
+        return Ok(Source::Function(table, args));
+    }
+    plain_table_name(from)
+        .map(Source::Named)
+        .context(Error::UnsupportedSql(
+            "Unimplemented FROM target".to_owned(),
+        ))
+}
+
+/// Get the name and arguments of a call to a table-valued function in `FROM`, if `table` is one.
+fn function_call(table: &TableWithJoins) -> Option<(&str, &[FunctionArg])> {
+    let TableWithJoins {
+        joins,
+        relation:
+            TableFactor::Table {
+                name,
+                alias: None,
+                args:
+                    Some(TableFunctionArgs {
+                        args,
+                        settings: None,
+                    }),
+                with_hints,
+                version: None,
+                with_ordinality: false,
+                partitions,
+            },
+    } = table
+    else {
+        return None;
+    };
+    if !(joins.is_empty() && with_hints.is_empty() && partitions.is_empty()) {
+        return None;
+    }
+    let name = name.0.first().take_if(|_| name.0.len() == 1)?;
+    Some((&name.value, args))
+}
+
+/// Evaluate the arguments of a call to a table-valued function, then compute its rows.
+fn function_rows(table: &dyn VirtualTable, args: &[FunctionArg]) -> Result<Vec<Vec<OwnedValue>>> {
+    let args = args
+        .iter()
+        .map(|arg| match arg {
+            FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => evaluate_constant(expr),
+            _ => anyhow::bail!(Error::UnsupportedSql(
+                "Unimplemented table-valued function argument".to_owned()
+            )),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    table.rows(&args)
+}
+
+/// Get the names of the columns of a table-valued function.
+fn function_columns(table: &dyn VirtualTable) -> Vec<String> {
+    table
+        .columns()
+        .iter()
+        .map(|&column| column.to_owned())
+        .collect()
+}
+
+/// Note that the query of the view called `name` is being run, failing if it already is.
+fn enter_view(views: &mut Vec<String>, name: &str) -> Result<()> {
+    if views.iter().any(|view| view.eq_ignore_ascii_case(name)) {
+        anyhow::bail!("view {name} is circularly defined");
+    }
+    views.push(name.to_owned());
+    Ok(())
+}
+
+/// Evaluate the result columns of a `SELECT` over a row from `source`, whose columns are called
+/// `columns`.
+fn project(
+    result_columns: &[ResultColumn<'_>],
+    source: &str,
+    columns: &[String],
+    row: &[OwnedValue],
+) -> Result<Vec<OwnedValue>> {
+    let mut values = Vec::new();
+    for result_column in result_columns {
+        match result_column {
+            ResultColumn::Wildcard => values.extend_from_slice(row),
+            ResultColumn::Expr(expr) => values.push(evaluate(expr, &mut |name| {
+                column_value(source, columns, row, name)
+            })?),
+        }
+    }
+    Ok(values)
+}
+
+/// Get the value of the column called `name` in a row from `source`, where `name` may be qualified
+/// with the source's name.
+fn column_value(
+    source: &str,
+    columns: &[String],
+    row: &[OwnedValue],
+    name: &[Ident],
+) -> Result<OwnedValue> {
+    let column = match name {
+        [column] => Some(column),
+        [table, column] if table.value.eq_ignore_ascii_case(source) => Some(column),
+        _ => None,
+    };
+    column
+        .and_then(|column| {
+            columns
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(&column.value))
+        })
+        .and_then(|idx| row.get(idx))
+        .cloned()
+        .with_context(|| {
+            let name = name
+                .iter()
+                .map(|part| part.value.as_str())
+                .collect::<Vec<_>>();
+            Error::NotFound(format!("no such column: {}", name.join(".")))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::tests::{open_rw, query, temp_copy};
+
+    #[test]
+    fn test_select_from_view() {
+        let path = temp_copy("test-data/schema-objects.sqlite", "select-view");
+        let mut db = open_rw(&path);
+        let mut selected = |sql| {
+            query(&mut db, sql)
+                .expect("Failed to run query")
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(selected("SELECT * FROM v"), [[r#""one""#], [r#""two""#]]);
+        assert_eq!(selected("SELECT count(*) FROM V"), [["2"]]);
+        assert_eq!(
+            selected("SELECT v.x, x || '!' FROM v"),
+            [[r#""one""#, r#""one!""#], [r#""two""#, r#""two!""#]],
+        );
+        // The columns of views are visible only under their declared names.
+        assert_eq!(
+            query(&mut db, "SELECT b FROM v").unwrap_err().to_string(),
+            "no such column: b",
+        );
+
+        // Views defined in terms of themselves are caught.
+        db.schema.views[0].query = Box::new(
+            match sqlparser::parser::Parser::parse_sql(
+                &sqlparser::dialect::SQLiteDialect {},
+                "SELECT * FROM v",
+            )
+            .expect("Failed to parse query")
+            .remove(0)
+            {
+                sqlparser::ast::Statement::Query(query) => *query,
+                _ => unreachable!(),
+            },
+        );
+        assert_eq!(
+            query(&mut db, "SELECT * FROM v").unwrap_err().to_string(),
+            "view v is circularly defined",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_select_from_table_function() {
+        let path = temp_copy("test-data/minimal-test.sqlite", "table-function");
+        let mut db = open_rw(&path);
+        let mut selected = |sql| {
+            query(&mut db, sql)
+                .expect("Failed to run query")
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|value| match value.as_str() {
+                            Some(text) => text.to_owned(),
+                            None => value.to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            selected(r#"SELECT * FROM json_each('{"a":1,"b":[2,3.5,"x"],"c d":null,"e":true}')"#),
+            [
+                ["a", "1", "integer", "1", "1", "null", "$.a", "$"],
+                [
+                    "b",
+                    r#"[2,3.5,"x"]"#,
+                    "array",
+                    "null",
+                    "2",
+                    "null",
+                    "$.b",
+                    "$"
+                ],
+                [
+                    "c d",
+                    "null",
+                    "null",
+                    "null",
+                    "6",
+                    "null",
+                    r#"$."c d""#,
+                    "$"
+                ],
+                ["e", "1", "true", "1", "7", "null", "$.e", "$"],
+            ],
+        );
+        assert_eq!(
+            selected(r#"SELECT * FROM json_tree('{"a":1,"b":[2,{"f":false}]}')"#),
+            [
+                [
+                    "null",
+                    r#"{"a":1,"b":[2,{"f":false}]}"#,
+                    "object",
+                    "null",
+                    "0",
+                    "null",
+                    "$",
+                    "$"
+                ],
+                ["a", "1", "integer", "1", "1", "0", "$.a", "$"],
+                [
+                    "b",
+                    r#"[2,{"f":false}]"#,
+                    "array",
+                    "null",
+                    "2",
+                    "0",
+                    "$.b",
+                    "$"
+                ],
+                ["0", "2", "integer", "2", "3", "2", "$.b[0]", "$.b"],
+                [
+                    "1",
+                    r#"{"f":false}"#,
+                    "object",
+                    "null",
+                    "4",
+                    "2",
+                    "$.b[1]",
+                    "$.b"
+                ],
+                ["f", "0", "false", "0", "5", "4", "$.b[1].f", "$.b[1]"],
+            ],
+        );
+        assert_eq!(
+            selected(
+                r#"SELECT key, value, fullkey, path FROM json_each('{"a":{"b":[1,2]}}', '$.a.b')"#
+            ),
+            [
+                ["0", "1", "$.a.b[0]", "$.a.b"],
+                ["1", "2", "$.a.b[1]", "$.a.b"]
+            ],
+        );
+        // Values which aren't arrays or objects are a single row.
+        assert_eq!(
+            selected("SELECT key, value, fullkey, path FROM json_each('5')"),
+            [["null", "5", "$", "$"]],
+        );
+        assert_eq!(
+            selected("SELECT key, value, json_tree.path FROM json_tree('[1]', '$[0]')"),
+            [["0", "1", "$"]],
+        );
+        assert!(selected(r#"SELECT * FROM json_each('{"a":1}', '$.z')"#).is_empty());
+        assert!(selected("SELECT * FROM json_each(NULL)").is_empty());
+        assert_eq!(
+            selected("SELECT count(*) FROM json_each('[1,2,3]')"),
+            [["3"]]
+        );
+
+        assert_eq!(
+            query(&mut db, "SELECT * FROM json_each('[1')")
+                .unwrap_err()
+                .to_string(),
+            "malformed JSON",
+        );
+        assert_eq!(
+            query(&mut db, "SELECT * FROM no_such_function(1)")
+                .unwrap_err()
+                .to_string(),
+            "no such table-valued function: no_such_function",
+        );
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,90 @@
+//! Keeping track of the largest rowid used by each `AUTOINCREMENT` table in `sqlite_sequence`
+
+use anyhow::{Context, Result};
+
+use super::Database;
+use crate::{
+    btree,
+    pager::Storage,
+    record::{OwnedValue, Record, Value},
+    schema::TableSchema,
+};
+
+/// The name of the table holding the largest rowid used in each `AUTOINCREMENT` table.
+pub(super) const SEQUENCE_TABLE: &str = "sqlite_sequence";
+
+/// The row of `sqlite_sequence` for a table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) struct Sequence {
+    /// The rowid of the row in `sqlite_sequence`, or `None` if the table doesn't have one yet
+    rowid: Option<i64>,
+    /// The largest rowid the table has used, as stored in `sqlite_sequence`
+    stored: i64,
+    /// The largest rowid the table has used, including any since the sequence was read
+    pub(super) value: i64,
+}
+
+impl<File: Storage> Database<File> {
+    /// Read the row of `sqlite_sequence` for the table with the given name.
+    pub(super) fn read_sequence(&mut self, table: &str) -> Result<Sequence> {
+        let (schema, root_page) = self.sequence_table()?;
+        let column = |name| {
+            schema
+                .column_index(name)
+                .with_context(|| format!("{SEQUENCE_TABLE} has no {name} column"))
+        };
+        let (name_column, seq_column) = (column("name")?, column("seq")?);
+        for rowid in btree::rowids(&mut self.pager, root_page)? {
+            let row = self.read_row(&schema, root_page, rowid)?;
+            if row[name_column].as_str() == Some(table) {
+                let value = row[seq_column].get::<i64>().unwrap_or(0);
+                return Ok(Sequence {
+                    rowid: Some(rowid),
+                    stored: value,
+                    value,
+                });
+            }
+        }
+        Ok(Sequence {
+            rowid: None,
+            stored: 0,
+            value: 0,
+        })
+    }
+
+    /// Store the sequence for the table with the given name back into `sqlite_sequence`.
+    ///
+    /// Like SQLite, this adds a row for the table even if no rowids were used.
+    pub(super) fn write_sequence(&mut self, table: &str, sequence: Sequence) -> Result<()> {
+        if sequence.rowid.is_some() && sequence.value == sequence.stored {
+            return Ok(());
+        }
+        let (_, root_page) = self.sequence_table()?;
+        let rowid = match sequence.rowid {
+            Some(rowid) => {
+                btree::delete(&mut self.pager, root_page, rowid)?;
+                rowid
+            }
+            None => btree::max_rowid(&mut self.pager, root_page)?.map_or(1, |max| max + 1),
+        };
+        let record: [OwnedValue; 2] = [
+            Value::String(table.as_bytes().into()),
+            Value::int(sequence.value),
+        ];
+        let encoding = self.pager.text_encoding();
+        btree::insert(
+            &mut self.pager,
+            root_page,
+            rowid,
+            &Record::build_encoded(&record, encoding),
+        )
+    }
+
+    /// Get the schema and root page of `sqlite_sequence`.
+    fn sequence_table(&self) -> Result<(TableSchema, usize)> {
+        let schema = self
+            .table_schema(SEQUENCE_TABLE)
+            .with_context(|| format!("{SEQUENCE_TABLE} is missing"))?;
+        Ok((schema, self.table_root_page(SEQUENCE_TABLE)?))
+    }
+}
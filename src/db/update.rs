@@ -0,0 +1,106 @@
+//! Executing `UPDATE` statements
+
+use anyhow::Result;
+use sqlparser::ast::{Assignment, Expr, SelectItem, TableWithJoins};
+
+use super::{
+    insert::{column_value, Update, WritableTable},
+    plain_table_name,
+    returning::Returning,
+    Database,
+};
+use crate::{pager::Storage, record::OwnedValue};
+
+impl<File: Storage> Database<File> {
+    /// Execute an `UPDATE` statement, returning the rows its `RETURNING` clause gives, if any.
+    pub(super) fn execute_update(
+        &mut self,
+        table: &TableWithJoins,
+        assignments: &[Assignment],
+        selection: Option<&Expr>,
+        returning: Option<&Vec<SelectItem>>,
+    ) -> Result<Vec<Vec<OwnedValue>>> {
+        let Some(table_name) = plain_table_name(table) else {
+            anyhow::bail!(crate::Error::UnsupportedSql(
+                "Unimplemented UPDATE target".to_owned()
+            ));
+        };
+        let WritableTable {
+            schema,
+            root_page,
+            indexes,
+        } = self.writable_table(table_name)?;
+        let update = Update::resolve(&schema, assignments, selection)?;
+        let returning = Returning::resolve(&schema, returning)?;
+
+        let mut returned = Vec::new();
+        // Rows are looked up before changing any, so a row whose rowid changes isn't seen twice.
+        for rowid in self.candidate_rowids(&schema, root_page, &indexes, selection)? {
+            let old = self.read_row(&schema, root_page, rowid)?;
+            self.rows_examined += 1;
+            let updated = self.update_row(
+                &schema,
+                root_page,
+                &indexes,
+                &update,
+                &mut |name| column_value(&schema, &old, rowid, name),
+                (&old, rowid),
+            )?;
+            if let (Some(returning), Some((new, new_rowid))) = (&returning, updated) {
+                returned.push(returning.row(&schema, &new, new_rowid)?);
+            }
+        }
+        Ok(returned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::tests::{open_rw, query, run, temp_copy},
+        record::RowExt,
+    };
+
+    #[test]
+    fn test_update() {
+        let path = temp_copy("test-data/constraints.sqlite", "update");
+        let mut db = open_rw(&path);
+        let ids_and_teams = |db: &mut _| {
+            query(db, "SELECT * FROM users")
+                .unwrap()
+                .iter()
+                .map(|row| (row.get_as::<i64>(0).unwrap(), row.get_as::<i64>(3).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        run(
+            &mut db,
+            "UPDATE users SET team = team + 10 WHERE name = 'bob'",
+        )
+        .expect("Failed to update");
+        assert_eq!(ids_and_teams(&mut db), [(1, 1), (2, 12)]);
+        run(&mut db, "UPDATE users SET id = id + 10, team = 0").expect("Failed to update");
+        assert_eq!(ids_and_teams(&mut db), [(11, 0), (12, 0)]);
+
+        assert_eq!(
+            run(&mut db, "UPDATE users SET email = 'a@example.com'")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.email",
+        );
+        assert_eq!(
+            query(&mut db, "SELECT * FROM users").unwrap()[1]
+                .get_as::<String>(1)
+                .unwrap(),
+            "b@example.com",
+            "A failed update shouldn't change anything",
+        );
+        // The indexes were updated along with the rows.
+        run(
+            &mut db,
+            "UPDATE users SET email = 'c@example.com' WHERE id = 11; \
+             INSERT INTO users(email) VALUES ('a@example.com')",
+        )
+        .expect("Failed to insert");
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
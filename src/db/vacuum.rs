@@ -0,0 +1,301 @@
+//! Rebuilding the database with `VACUUM`
+//!
+//! The database is copied into a temporary file one btree at a time, which packs each btree's
+//! pages full and leaves the free pages behind. Like SQLite, every btree's root page is allocated
+//! before anything is copied, so the root pages come first, as auto-vacuum databases need. The
+//! copy's pages are then written over the original ones in a single write, so the swap is as
+//! atomic as any other statement.
+
+use std::{
+    io::{Seek, Write},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+use super::Database;
+use crate::{
+    btree,
+    pager::{AutoVacuum, Pager, PtrmapEntry, Storage, DATABASE_HEADER_SIZE},
+    record::{Record, Value},
+    Error,
+};
+
+/// Where the page count is stored in the database header.
+const PAGE_COUNT_OFFSET: usize = 28;
+/// Where the first freelist trunk page and the number of free pages are stored in the header.
+const FREELIST_OFFSET: usize = 32;
+/// Where the schema cookie, which changes whenever the schema does, is stored in the header.
+const SCHEMA_COOKIE_OFFSET: usize = 40;
+/// Where the largest root page of an auto-vacuum database is stored in the header.
+const LARGEST_ROOT_PAGE_OFFSET: usize = 52;
+/// The column of `sqlite_schema` holding each object's root page.
+const ROOT_PAGE_COLUMN: usize = 3;
+
+/// How many temporary databases this process has made, to give each a different name.
+static TEMP_DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+impl<File: Storage> Database<File> {
+    /// Rebuild the database, so that it has no free pages and each table and index is stored in
+    /// as few pages as possible.
+    ///
+    /// This does what a `VACUUM` statement does, which `sqlparser` can't parse.
+    pub fn vacuum(&mut self) -> crate::Result<()> {
+        if self.in_transaction() {
+            return Err(Error::Sql(
+                "cannot VACUUM from within a transaction".to_owned(),
+            ));
+        }
+        Ok(self.with_lock(Self::vacuum_locked)?)
+    }
+
+    /// Rebuild the database, while holding a lock on it.
+    fn vacuum_locked(&mut self) -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "sqlite-riir-vacuum-{}-{}.sqlite",
+            std::process::id(),
+            TEMP_DATABASES.fetch_add(1, Ordering::Relaxed),
+        ));
+        let result = self.vacuum_into(&path);
+        let removed = std::fs::remove_file(&path);
+        result?;
+        removed.context("Failed to remove temporary database")?;
+        // Every table and index has moved to a new root page.
+        self.read_schema()
+    }
+
+    /// Remove up to `max_pages` free pages (or all of them, if `None`) from the end of an
+    /// auto-vacuum database, returning how many were removed.
+    ///
+    /// This is only needed for databases with incremental auto-vacuum, since those with full
+    /// auto-vacuum remove their free pages whenever changes are committed.
+    pub fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> crate::Result<usize> {
+        Ok(self.with_lock(|db| {
+            db.write_statement(|db| Ok(db.pager.incremental_vacuum(max_pages)?))
+        })?)
+    }
+
+    /// Rebuild the database into a new file at `path`, and then copy it back over the database.
+    fn vacuum_into(&mut self, path: &Path) -> Result<()> {
+        let page_size = self.pager.page_size();
+        let mut first_page = vec![0; page_size];
+        first_page[..DATABASE_HEADER_SIZE]
+            .copy_from_slice(&self.pager.read_page_bytes(1)?[..DATABASE_HEADER_SIZE]);
+        // The copy starts out as just `sqlite_schema`, with no free pages.
+        first_page[PAGE_COUNT_OFFSET..PAGE_COUNT_OFFSET + 4].copy_from_slice(&1_u32.to_be_bytes());
+        first_page[FREELIST_OFFSET..FREELIST_OFFSET + 8].fill(0);
+        let cookie = u32::from_be_bytes(
+            first_page[SCHEMA_COOKIE_OFFSET..SCHEMA_COOKIE_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        );
+        first_page[SCHEMA_COOKIE_OFFSET..SCHEMA_COOKIE_OFFSET + 4]
+            .copy_from_slice(&cookie.wrapping_add(1).to_be_bytes());
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context("Failed to create temporary database")?;
+        file.write_all(&first_page)
+            .context("Failed to write temporary database")?;
+        file.rewind()
+            .context("Failed to seek in temporary database")?;
+        let mut copy = Pager::new(file).context("Failed to read temporary database")?;
+        btree::init(&mut copy, 1)?;
+
+        let mut schema_rows = Vec::new();
+        let mut btrees = Vec::new();
+        for rowid in btree::rowids(&mut self.pager, 1)? {
+            let payload = btree::find_row(&mut self.pager, 1, rowid)?
+                .context("sqlite_schema changed while reading it")?;
+            let mut values = Record::parse(&payload)?
+                .value_iter()
+                .map(|value| value.to_owned())
+                .collect::<Vec<_>>();
+            let root_page = values
+                .get(ROOT_PAGE_COLUMN)
+                .and_then(|value| value.get::<i64>().ok())
+                .unwrap_or(0);
+            if root_page > 0 {
+                let new_root = copy.allocate_page()?;
+                copy.set_ptrmap(new_root, PtrmapEntry::RootPage)?;
+                values[ROOT_PAGE_COLUMN] = Value::int(new_root as i64);
+                btrees.push((root_page as usize, new_root));
+            }
+            schema_rows.push((rowid, values));
+        }
+        if copy.auto_vacuum() != AutoVacuum::None {
+            let largest_root = btrees.last().map_or(1, |&(_, new_root)| new_root);
+            let mut first_page = copy.read_page_bytes(1)?.to_vec();
+            first_page[LARGEST_ROOT_PAGE_OFFSET..LARGEST_ROOT_PAGE_OFFSET + 4]
+                .copy_from_slice(&(largest_root as u32).to_be_bytes());
+            copy.write_page(1, &first_page)?;
+        }
+        for (root_page, new_root) in btrees {
+            btree::copy(&mut self.pager, root_page, &mut copy, new_root)
+                .with_context(|| format!("Failed to copy btree rooted at page {root_page}"))?;
+        }
+        for (rowid, values) in schema_rows {
+            btree::insert(&mut copy, 1, rowid, &Record::build(&values))?;
+        }
+        copy.flush().context("Failed to write temporary database")?;
+
+        let page_count = copy.page_count();
+        self.write_statement(|db| {
+            for page_idx in 1..=page_count {
+                db.pager
+                    .write_page(page_idx, copy.read_page_bytes(page_idx)?)?;
+            }
+            Ok(db.pager.truncate(page_count)?)
+        })?;
+        Ok(self.pager.truncate_file()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{open_rw, query, run, temp_copy};
+
+    #[test]
+    fn test_vacuum() {
+        let path = temp_copy("test-data/constraints.sqlite", "vacuum");
+        let mut db = open_rw(&path);
+        run(&mut db, "BEGIN").unwrap();
+        for i in 0..500 {
+            run(
+                &mut db,
+                &format!(
+                    "INSERT INTO users(email, name, team) VALUES ('user{i}@example.com', 'user{i}', {})",
+                    i % 7
+                ),
+            )
+            .expect("Failed to insert");
+        }
+        run(&mut db, "COMMIT").unwrap();
+        run(&mut db, "DELETE FROM users WHERE id > 10").expect("Failed to delete");
+        let rows = query(&mut db, "SELECT * FROM users").unwrap();
+        let page_count = db.page_count();
+        let free_pages = |db: &mut Database| {
+            u32::from_be_bytes(
+                db.pager.read_page_bytes(1).unwrap()[FREELIST_OFFSET + 4..FREELIST_OFFSET + 8]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        assert!(free_pages(&mut db) > 0);
+
+        db.vacuum().expect("Failed to vacuum");
+        assert!(db.page_count() < page_count);
+        assert_eq!(free_pages(&mut db), 0);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (db.page_count() * db.pager.page_size()) as u64,
+            "The file should shrink too",
+        );
+        assert_eq!(query(&mut db, "SELECT * FROM users").unwrap(), rows);
+        // The indexes were copied along with the tables.
+        assert_eq!(
+            run(
+                &mut db,
+                "INSERT INTO users(email) VALUES ('user3@example.com')"
+            )
+            .unwrap_err()
+            .to_string(),
+            "UNIQUE constraint failed: users.email",
+        );
+
+        run(&mut db, "BEGIN").unwrap();
+        assert_eq!(
+            db.vacuum().unwrap_err().to_string(),
+            "cannot VACUUM from within a transaction",
+        );
+        run(&mut db, "COMMIT").unwrap();
+        drop(db);
+        let mut db = open_rw(&path);
+        assert_eq!(query(&mut db, "SELECT * FROM users").unwrap(), rows);
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_auto_vacuum() {
+        let file_pages = |db: &mut Database, path: &Path| {
+            assert_eq!(
+                u32::from_be_bytes(
+                    db.pager.read_page_bytes(1).unwrap()[FREELIST_OFFSET + 4..FREELIST_OFFSET + 8]
+                        .try_into()
+                        .unwrap(),
+                ),
+                0,
+                "Every free page should have been removed",
+            );
+            std::fs::metadata(path).unwrap().len() as usize / db.pager.page_size()
+        };
+
+        // The test database uses incremental auto-vacuum, which leaves free pages until asked.
+        let path = temp_copy("test-data/auto-vacuum.sqlite", "incremental-vacuum");
+        let mut db = open_rw(&path);
+        let page_count = db.page_count();
+        run(&mut db, "DELETE FROM items WHERE n >= 100").expect("Failed to delete");
+        assert_eq!(db.page_count(), page_count);
+        assert_eq!(db.incremental_vacuum(Some(2)).unwrap(), 2);
+        assert_eq!(db.page_count(), page_count - 2);
+        assert!(db.incremental_vacuum(None).unwrap() > 0);
+        assert_eq!(file_pages(&mut db, &path), db.page_count());
+        assert!(db.page_count() < page_count / 2);
+        assert_eq!(db.table("items").unwrap().count().unwrap(), 100);
+        std::fs::remove_file(path).expect("Failed to clean up");
+
+        // With full auto-vacuum, free pages are removed whenever changes are committed.
+        let path = temp_copy("test-data/auto-vacuum.sqlite", "full-auto-vacuum");
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[64..68].fill(0);
+        std::fs::write(&path, contents).unwrap();
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM items WHERE n >= 100").expect("Failed to delete");
+        assert_eq!(file_pages(&mut db, &path), db.page_count());
+        assert!(db.page_count() < page_count / 2);
+        let rows = query(&mut db, "SELECT * FROM items").unwrap();
+        assert_eq!(rows.len(), 100);
+        assert_eq!(
+            run(&mut db, "INSERT INTO items(name) VALUES ('item-00042')")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: items.name",
+        );
+        drop(db);
+        let mut db = open_rw(&path);
+        assert_eq!(query(&mut db, "SELECT * FROM items").unwrap(), rows);
+        std::fs::remove_file(path).expect("Failed to clean up");
+
+        // Vacuuming keeps auto-vacuum, with the root pages first and the pointer map rebuilt.
+        let path = temp_copy("test-data/auto-vacuum.sqlite", "vacuum-auto-vacuum");
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM items WHERE n % 3 = 0").expect("Failed to delete");
+        let rows = query(&mut db, "SELECT * FROM items").unwrap();
+        db.vacuum().expect("Failed to vacuum");
+        assert_eq!(file_pages(&mut db, &path), db.page_count());
+        assert_eq!(db.integrity_check(100).unwrap(), Vec::<String>::new());
+        assert_eq!(query(&mut db, "SELECT * FROM items").unwrap(), rows);
+        let root_pages = query(&mut db, "SELECT rootpage FROM sqlite_schema")
+            .unwrap()
+            .into_iter()
+            .map(|row| row[0].get::<i64>().unwrap())
+            .filter(|&root_page| root_page > 0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            root_pages,
+            (3..3 + root_pages.len() as i64).collect::<Vec<_>>()
+        );
+        let header = db.pager.header().unwrap();
+        assert_eq!(header.largest_root_page as usize, 2 + root_pages.len());
+        drop(db);
+        let mut db = open_rw(&path);
+        run(&mut db, "DELETE FROM items WHERE n >= 100").expect("Failed to delete");
+        assert!(db.incremental_vacuum(None).unwrap() > 0);
+        assert_eq!(db.integrity_check(100).unwrap(), Vec::<String>::new());
+        std::fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,431 @@
+//! Deserializing rows into Rust types with serde
+//!
+//! A row deserializes into a struct or map by its column names, or into a tuple or sequence by
+//! the positions of its columns. A row with a single column can also deserialize straight into
+//! that column's value, so `SELECT count(*)` can be read as an `i64`.
+
+use std::fmt;
+
+use serde::{
+    de::{
+        self, value::BorrowedStrDeserializer, DeserializeSeed, IntoDeserializer, MapAccess,
+        SeqAccess, Visitor,
+    },
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::record::{FromValue, OwnedValue, Value};
+
+/// An error deserializing a row.
+#[derive(Debug)]
+pub struct Error(anyhow::Error);
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+impl std::error::Error for Error {}
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(anyhow::anyhow!("{msg}"))
+    }
+}
+
+/// Deserialize `row`, whose columns are called `columns`, into a `T`.
+pub fn from_row<'de, T: Deserialize<'de>>(
+    columns: &'de [String],
+    row: &'de [OwnedValue],
+) -> Result<T, Error> {
+    T::deserialize(RowDeserializer { columns, row })
+}
+
+/// A deserializer over the values in a row and the names of its columns.
+pub struct RowDeserializer<'de> {
+    /// The name of each column, which may be empty if they aren't known
+    columns: &'de [String],
+    /// The value in each column
+    row: &'de [OwnedValue],
+}
+impl<'de> RowDeserializer<'de> {
+    /// Deserialize `row`, whose columns are called `columns`.
+    #[must_use]
+    pub fn new(columns: &'de [String], row: &'de [OwnedValue]) -> Self {
+        Self { columns, row }
+    }
+
+    /// Get the deserializer for the only column of the row, for types which are a single value.
+    fn single_column(&self) -> Result<ValueDeserializer<'de>, Error> {
+        match self.row {
+            [value] => Ok(ValueDeserializer(value)),
+            _ => Err(Error(anyhow::anyhow!(
+                "Expected a single column, but the row has {}",
+                self.row.len()
+            ))),
+        }
+    }
+}
+
+/// Deserialize types which are a single value from the row's only column.
+macro_rules! single_column {
+    ($($method:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.single_column()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for RowDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    single_column!(
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_identifier,
+    );
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single_column()?.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single_column()?
+            .deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut values = ColumnValues {
+            columns: self.columns,
+            row: self.row,
+            next: 0,
+        };
+        let result = visitor.visit_seq(&mut values)?;
+        if values.next != self.row.len() {
+            return Err(de::Error::invalid_length(
+                self.row.len(),
+                &format!("{} columns", values.next).as_str(),
+            ));
+        }
+        Ok(result)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.columns.len() != self.row.len() {
+            return Err(Error(anyhow::anyhow!(
+                "The names of the columns aren't known, so the row can only be read by position"
+            )));
+        }
+        visitor.visit_map(ColumnValues {
+            columns: self.columns,
+            row: self.row,
+            next: 0,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// The columns of a row, read in order as a sequence of values or a map from names to values.
+struct ColumnValues<'de> {
+    /// The name of each column
+    columns: &'de [String],
+    /// The value in each column
+    row: &'de [OwnedValue],
+    /// The index of the next column to read
+    next: usize,
+}
+impl<'de> ColumnValues<'de> {
+    /// Deserialize the value in column `idx`, noting which column it was in any error.
+    fn value<T: DeserializeSeed<'de>>(&self, seed: T, idx: usize) -> Result<T::Value, Error> {
+        seed.deserialize(ValueDeserializer(&self.row[idx]))
+            .map_err(|Error(error)| {
+                Error(match self.columns.get(idx) {
+                    Some(name) => error.context(format!("Invalid value in column {name}")),
+                    None => error.context(format!("Invalid value in column {idx}")),
+                })
+            })
+    }
+}
+impl<'de> SeqAccess<'de> for ColumnValues<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.next == self.row.len() {
+            return Ok(None);
+        }
+        self.next += 1;
+        self.value(seed, self.next - 1).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.row.len() - self.next)
+    }
+}
+impl<'de> MapAccess<'de> for ColumnValues<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some(name) = self.columns.get(self.next) else {
+            return Ok(None);
+        };
+        seed.deserialize(BorrowedStrDeserializer::new(name))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        self.next += 1;
+        self.value(seed, self.next - 1)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.row.len() - self.next)
+    }
+}
+
+/// A deserializer over a single value.
+struct ValueDeserializer<'de>(&'de OwnedValue);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            Value::F64(n) => visitor.visit_f64(*n),
+            Value::String(text) => match std::str::from_utf8(text) {
+                Ok(text) => visitor.visit_borrowed_str(text),
+                Err(_) => visitor.visit_borrowed_bytes(text),
+            },
+            Value::Blob(blob) => visitor.visit_borrowed_bytes(blob),
+            value => visitor.visit_i64(i64::from_value(value).map_err(|e| Error(e.into()))?),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_bool(bool::from_value(self.0).map_err(|e| Error(e.into()))?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f64(f64::from_value(self.0).map_err(|e| Error(e.into()))?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Blob(blob) | Value::String(blob) => visitor.visit_borrowed_bytes(blob),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Enums whose variants hold nothing are read from the names of their variants.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let text = String::from_value(self.0).map_err(|e| Error(e.into()))?;
+        visitor.visit_enum(text.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 char str string seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs::File};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::Database;
+
+    fn open() -> Database {
+        Database::new(File::open("test-data/constraints.sqlite").unwrap())
+            .expect("Failed to parse test database")
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct User {
+        id: i64,
+        name: String,
+        email: Option<String>,
+        team: u8,
+    }
+
+    #[test]
+    fn test_query_as() {
+        let mut db = open();
+        let users: Vec<User> = db.query_as("SELECT * FROM users").unwrap();
+        assert_eq!(
+            users,
+            [
+                User {
+                    id: 1,
+                    name: "alice".to_owned(),
+                    email: Some("a@example.com".to_owned()),
+                    team: 1,
+                },
+                User {
+                    id: 2,
+                    name: "bob".to_owned(),
+                    email: Some("b@example.com".to_owned()),
+                    team: 2,
+                },
+            ]
+        );
+
+        let (name, n): (String, f64) = db.query_row_as("SELECT * FROM tags").unwrap();
+        assert_eq!((name.as_str(), n), ("x", 1.0));
+        let counts: HashMap<String, i64> = db.query_row_as("SELECT n AS count FROM tags").unwrap();
+        assert_eq!(counts, HashMap::from([("count".to_owned(), 1)]));
+        let email: String = db.query_row_as("SELECT email FROM users").unwrap();
+        assert_eq!(email, "a@example.com");
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let mut db = open();
+        let error = db
+            .query_row_as::<(String, String)>("SELECT * FROM tags")
+            .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Invalid value in column n"),
+            "Unexpected error {error:#}"
+        );
+        let error = db.query_row_as::<i64>("SELECT * FROM tags").unwrap_err();
+        assert!(
+            format!("{error:#}").contains("single column"),
+            "Unexpected error {error:#}"
+        );
+        let error = db.query_as::<User>("SELECT * FROM tags").unwrap_err();
+        assert!(
+            format!("{error:#}").contains("missing field"),
+            "Unexpected error {error:#}"
+        );
+
+        let columns = ["n".to_owned()];
+        let row = [Value::Null];
+        assert_eq!(from_row::<Option<i64>>(&columns, &row).unwrap(), None);
+        let error = from_row::<HashMap<String, i64>>(&columns, &row).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Invalid value in column n"),
+            "Unexpected error {error:#}"
+        );
+        assert!(from_row::<HashMap<String, i64>>(&[], &row).is_err());
+    }
+}
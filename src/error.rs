@@ -0,0 +1,196 @@
+//! The errors returned by the crate's public API
+//!
+//! Internally, errors are built up with [`anyhow`], adding context as they're passed up. Where
+//! the kind of failure is known, the error at the root of the chain is an [`Error`] saying so,
+//! and when the error leaves the crate, the chain is flattened into that [`Error`], with the
+//! context added to its message. Errors of no particular kind become [`Error::Other`].
+
+use std::{fmt, io};
+
+/// The result of an operation which can fail with an [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The ways an operation on a database can fail.
+///
+/// Each kind of failure holds a message describing it, including what was being done when it
+/// happened.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading from or writing to the database's storage failed.
+    Io {
+        /// What was being done when the error happened, followed by the error
+        message: String,
+        /// The error from the storage
+        error: io::Error,
+    },
+    /// The database file is malformed.
+    Corrupt {
+        /// The page the problem was found in, if it's known
+        page: Option<usize>,
+        /// What's wrong with it
+        reason: String,
+    },
+    /// A statement couldn't be parsed as SQL.
+    Syntax(String),
+    /// A statement uses SQL which isn't supported yet.
+    UnsupportedSql(String),
+    /// A statement can't be run as written, like committing without a transaction open, or
+    /// inserting the wrong number of values.
+    Sql(String),
+    /// A table, column, function, or other named object doesn't exist.
+    NotFound(String),
+    /// A change would break a constraint, like `UNIQUE`.
+    Constraint(String),
+    /// A value couldn't be converted to the Rust type asked for.
+    Mismatch(String),
+    /// The database can't be written to through this connection.
+    ReadOnly(String),
+    /// Another connection holds a lock on the database which this one needs.
+    Busy(String),
+    /// The statement was stopped with an
+    /// [`InterruptHandle`](crate::InterruptHandle).
+    Interrupted,
+    /// Any other failure.
+    Other(String),
+}
+
+impl Error {
+    /// Make an error for a malformed database, found in `page` if it's known.
+    pub(crate) fn corrupt(page: Option<usize>, reason: impl Into<String>) -> Self {
+        Self::Corrupt {
+            page,
+            reason: reason.into(),
+        }
+    }
+
+    /// Make an error for a lock which another connection holds.
+    pub(crate) fn busy() -> Self {
+        Self::Busy("database is locked".to_owned())
+    }
+
+    /// Replace the message of `self` with `message`, which says more about how it happened.
+    fn with_message(self, message: String) -> Self {
+        match self {
+            Self::Io { error, .. } => Self::Io { message, error },
+            Self::Corrupt { page, .. } => Self::Corrupt {
+                page,
+                reason: message,
+            },
+            Self::Syntax(_) => Self::Syntax(message),
+            Self::UnsupportedSql(_) => Self::UnsupportedSql(message),
+            Self::Sql(_) => Self::Sql(message),
+            Self::NotFound(_) => Self::NotFound(message),
+            Self::Constraint(_) => Self::Constraint(message),
+            Self::Mismatch(_) => Self::Mismatch(message),
+            Self::ReadOnly(_) => Self::ReadOnly(message),
+            Self::Busy(_) => Self::Busy(message),
+            Self::Interrupted => Self::Interrupted,
+            Self::Other(_) => Self::Other(message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { message, .. }
+            | Self::Corrupt {
+                reason: message, ..
+            }
+            | Self::Syntax(message)
+            | Self::UnsupportedSql(message)
+            | Self::Sql(message)
+            | Self::NotFound(message)
+            | Self::Constraint(message)
+            | Self::Mismatch(message)
+            | Self::ReadOnly(message)
+            | Self::Busy(message)
+            | Self::Other(message) => f.write_str(message),
+            Self::Interrupted => f.write_str("interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io {
+            message: error.to_string(),
+            error,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// Flatten the chain of context in `error` into the [`Error`] at its root, or into an
+    /// [`Error::Io`] or [`Error::Syntax`] if that's what's at its root.
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{error:#}");
+        let error = match error.downcast::<Self>() {
+            Ok(error) => return error.with_message(message),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<io::Error>() {
+            Ok(error) => return Self::Io { message, error },
+            Err(error) => error,
+        };
+        if error.is::<sqlparser::parser::ParserError>() {
+            Self::Syntax(message)
+        } else {
+            Self::Other(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::Error;
+
+    #[test]
+    fn test_from_anyhow() {
+        let error = Error::from(
+            anyhow::Error::from(Error::NotFound("no such table: t".to_owned()))
+                .context("Failed to read rows"),
+        );
+        assert!(
+            matches!(&error, Error::NotFound(message) if message == "Failed to read rows: no such table: t"),
+            "{error:?}"
+        );
+
+        let error = Error::from(
+            Err::<(), _>(std::io::Error::other("disk on fire"))
+                .context("Failed to read page 2")
+                .context("Failed to read rows")
+                .unwrap_err(),
+        );
+        assert!(
+            matches!(&error, Error::Io { error, .. } if error.to_string() == "disk on fire"),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "Failed to read rows: Failed to read page 2: disk on fire"
+        );
+        assert_eq!(
+            std::error::Error::source(&error).map(ToString::to_string),
+            Some("disk on fire".to_owned()),
+        );
+
+        let error = Error::from(anyhow::Error::from(Error::Interrupted).context("Failed to run"));
+        assert!(matches!(error, Error::Interrupted), "{error:?}");
+
+        let error = Error::from(anyhow::anyhow!("something else"));
+        assert!(matches!(error, Error::Other(_)), "{error:?}");
+    }
+}
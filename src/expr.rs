@@ -0,0 +1,439 @@
+//! Evaluating SQL expressions
+
+use anyhow::{Context, Result};
+use sqlparser::ast::{
+    BinaryOperator, CastKind, Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments,
+    Ident, UnaryOperator,
+};
+
+use crate::{
+    record::{Affinity, OwnedValue, Value},
+    Error,
+};
+
+/// Evaluate an expression which doesn't refer to any columns.
+pub(crate) fn evaluate_constant(expr: &Expr) -> Result<OwnedValue> {
+    evaluate(expr, &mut |name| {
+        let name = name
+            .iter()
+            .map(|part| part.value.as_str())
+            .collect::<Vec<_>>();
+        anyhow::bail!(Error::NotFound(format!(
+            "no such column: {}",
+            name.join(".")
+        )))
+    })
+}
+
+/// Evaluate an expression, calling `column` to get the value of each column it refers to.
+///
+/// `column` is given each part of the name, so `t.a` is passed as `["t", "a"]`.
+pub(crate) fn evaluate(
+    expr: &Expr,
+    column: &mut dyn FnMut(&[Ident]) -> Result<OwnedValue>,
+) -> Result<OwnedValue> {
+    match expr {
+        Expr::Value(value) => evaluate_literal(value),
+        Expr::Identifier(name) => column(std::slice::from_ref(name)),
+        Expr::CompoundIdentifier(name) => column(name),
+        Expr::Nested(expr)
+        | Expr::UnaryOp {
+            op: UnaryOperator::Plus,
+            expr,
+        } => evaluate(expr, column),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => Ok(match to_numeric(evaluate(expr, column)?) {
+            Value::Null => Value::Null,
+            Value::F64(n) => Value::F64(-n),
+            value => {
+                let n = value.get::<i64>()?;
+                n.checked_neg()
+                    .map_or_else(|| Value::F64(-(n as f64)), Value::int)
+            }
+        }),
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => Ok(from_truth(truth(&evaluate(expr, column)?).map(|b| !b))),
+        Expr::IsNull(expr) => Ok(from_truth(Some(evaluate(expr, column)?.is_null()))),
+        Expr::IsNotNull(expr) => Ok(from_truth(Some(!evaluate(expr, column)?.is_null()))),
+        Expr::BinaryOp { left, op, right } => {
+            let left = evaluate(left, column)?;
+            let right = evaluate(right, column)?;
+            binary_op(&left, op, &right)
+        }
+        Expr::Function(function) => {
+            let (name, args) = function_args(function)?;
+            let args = args
+                .into_iter()
+                .map(|arg| evaluate(arg, column))
+                .collect::<Result<Vec<_>>>()?;
+            call_function(name, &args)
+        }
+        Expr::Cast {
+            kind: CastKind::Cast,
+            expr,
+            data_type,
+            format: None,
+        } => Ok(cast(
+            evaluate(expr, column)?,
+            Affinity::from_declared_type(&data_type.to_string()),
+        )),
+        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented expression: {expr}"
+        ))),
+    }
+}
+
+/// Convert a value to the type with the given affinity, for `CAST`.
+///
+/// Unlike storing a value in a column, this always converts it, so text which isn't a number is
+/// read as 0 when cast to a number, and floats are truncated when cast to an integer.
+fn cast(value: OwnedValue, affinity: Affinity) -> OwnedValue {
+    if value.is_null() {
+        return value;
+    }
+    match affinity {
+        Affinity::Text => Value::String(to_text(&value).into()),
+        Affinity::Blob => Value::Blob(to_text(&value).into()),
+        Affinity::Numeric => match value {
+            Value::String(_) | Value::Blob(_) => to_numeric(value).apply_affinity(affinity),
+            value => value,
+        },
+        Affinity::Integer => match to_numeric(value) {
+            Value::F64(n) => Value::int(n as i64),
+            value => value,
+        },
+        Affinity::Real => Value::F64(to_numeric(value).get().unwrap_or_default()),
+    }
+}
+
+/// Get the name of a call to a scalar function and the expressions passed to it.
+fn function_args(function: &Function) -> Result<(&str, Vec<&Expr>)> {
+    let Function {
+        name,
+        parameters: FunctionArguments::None,
+        args,
+        filter: None,
+        null_treatment: None,
+        over: None,
+        within_group,
+    } = function
+    else {
+        anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented function call: {function}"
+        )));
+    };
+    let ([name], true) = (name.0.as_slice(), within_group.is_empty()) else {
+        anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented function call: {function}"
+        )));
+    };
+    let args = match args {
+        FunctionArguments::List(list)
+            if list.duplicate_treatment.is_none() && list.clauses.is_empty() =>
+        {
+            list.args
+                .iter()
+                .map(|arg| match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => Ok(expr),
+                    _ => anyhow::bail!(Error::UnsupportedSql(format!(
+                        "Unimplemented function argument: {arg}"
+                    ))),
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented function call: {function}"
+        ))),
+    };
+    Ok((&name.value, args))
+}
+
+/// Call the scalar function with the given name.
+fn call_function(name: &str, args: &[OwnedValue]) -> Result<OwnedValue> {
+    let function: fn(&OwnedValue) -> Result<OwnedValue> = match name.to_ascii_lowercase().as_str() {
+        // Like SQLite without ICU, these only change the case of ASCII letters.
+        "lower" => |arg| Ok(Value::String(to_text(arg).to_ascii_lowercase().into())),
+        "upper" => |arg| Ok(Value::String(to_text(arg).to_ascii_uppercase().into())),
+        "length" => |arg| {
+            Ok(Value::int(match arg {
+                Value::Blob(blob) => blob.len() as i64,
+                _ => String::from_utf8_lossy(&to_text(arg)).chars().count() as i64,
+            }))
+        },
+        "abs" => |arg| match to_numeric(arg.clone()) {
+            Value::F64(n) => Ok(Value::F64(n.abs())),
+            value => Ok(Value::int(
+                value
+                    .get::<i64>()?
+                    .checked_abs()
+                    .context("integer overflow")?,
+            )),
+        },
+        _ => anyhow::bail!(Error::NotFound(format!("no such function: {name}"))),
+    };
+    let [arg] = args else {
+        anyhow::bail!("wrong number of arguments to function {name}()");
+    };
+    if arg.is_null() {
+        return Ok(Value::Null);
+    }
+    function(arg)
+}
+
+/// Apply a binary operator to two values.
+fn binary_op(left: &OwnedValue, op: &BinaryOperator, right: &OwnedValue) -> Result<OwnedValue> {
+    Ok(match op {
+        BinaryOperator::And => from_truth(match (truth(left), truth(right)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }),
+        BinaryOperator::Or => from_truth(match (truth(left), truth(right)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        }),
+        _ if left.is_null() || right.is_null() => Value::Null,
+        BinaryOperator::Eq => from_truth(Some(left.sqlite_cmp(right).is_eq())),
+        BinaryOperator::NotEq => from_truth(Some(left.sqlite_cmp(right).is_ne())),
+        BinaryOperator::Lt => from_truth(Some(left.sqlite_cmp(right).is_lt())),
+        BinaryOperator::LtEq => from_truth(Some(left.sqlite_cmp(right).is_le())),
+        BinaryOperator::Gt => from_truth(Some(left.sqlite_cmp(right).is_gt())),
+        BinaryOperator::GtEq => from_truth(Some(left.sqlite_cmp(right).is_ge())),
+        BinaryOperator::StringConcat => {
+            let mut text = to_text(left);
+            text.extend(to_text(right));
+            Value::String(text.into_boxed_slice())
+        }
+        BinaryOperator::Plus
+        | BinaryOperator::Minus
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulo => {
+            arithmetic(&to_numeric(left.clone()), op, &to_numeric(right.clone()))
+        }
+        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented operator: {op}"
+        ))),
+    })
+}
+
+/// Apply an arithmetic operator to two numbers.
+///
+/// Like SQLite, integer arithmetic which overflows is done with floats instead, and dividing by
+/// zero gives `NULL`.
+fn arithmetic(left: &OwnedValue, op: &BinaryOperator, right: &OwnedValue) -> OwnedValue {
+    if let (Ok(a), Ok(b)) = (left.get::<i64>(), right.get::<i64>()) {
+        let result = match op {
+            BinaryOperator::Plus => a.checked_add(b),
+            BinaryOperator::Minus => a.checked_sub(b),
+            BinaryOperator::Multiply => a.checked_mul(b),
+            _ if b == 0 => return Value::Null,
+            BinaryOperator::Divide => a.checked_div(b),
+            // The only overflow is `i64::MIN % -1`, which is 0 anyway.
+            _ => Some(a.checked_rem(b).unwrap_or(0)),
+        };
+        if let Some(result) = result {
+            return Value::int(result);
+        }
+    }
+    let a = left.get::<f64>().unwrap_or_default();
+    let b = right.get::<f64>().unwrap_or_default();
+    let result = match op {
+        BinaryOperator::Plus => a + b,
+        BinaryOperator::Minus => a - b,
+        BinaryOperator::Multiply => a * b,
+        _ if b == 0.0 => return Value::Null,
+        BinaryOperator::Divide => a / b,
+        // SQLite takes the remainder of the operands as integers.
+        _ => return Value::F64(((a as i64).checked_rem(b as i64).unwrap_or(0)) as f64),
+    };
+    Value::F64(result)
+}
+
+/// Convert a value into a number for arithmetic, leaving `NULL` alone.
+///
+/// Text is read as a number if it is one, and as 0 otherwise.
+fn to_numeric(value: OwnedValue) -> OwnedValue {
+    match value {
+        Value::String(text) | Value::Blob(text) => {
+            let text = String::from_utf8_lossy(&text);
+            let text = text.trim();
+            text.parse::<i64>().map_or_else(
+                |_| Value::F64(text.parse::<f64>().unwrap_or_default()),
+                Value::int,
+            )
+        }
+        Value::SQLiteReserved => Value::int(0),
+        value => value,
+    }
+}
+
+/// Convert a value into text, for concatenation.
+fn to_text(value: &OwnedValue) -> Vec<u8> {
+    match value {
+        Value::String(text) | Value::Blob(text) => text.to_vec(),
+        value => match value.apply_affinity(Affinity::Text) {
+            Value::String(text) => text.into_vec(),
+            _ => Vec::new(),
+        },
+    }
+}
+
+/// Interpret a value as a condition, with `None` for `NULL`.
+pub(crate) fn truth(value: &OwnedValue) -> Option<bool> {
+    match to_numeric(value.clone()) {
+        Value::Null => None,
+        Value::F64(n) => Some(n != 0.0),
+        value => Some(value.get::<i64>().is_ok_and(|n| n != 0)),
+    }
+}
+
+/// Convert the result of a condition into a value.
+fn from_truth(truth: Option<bool>) -> OwnedValue {
+    truth.map_or(Value::Null, |truth| Value::int(i64::from(truth)))
+}
+
+/// Evaluate a literal value.
+fn evaluate_literal(value: &sqlparser::ast::Value) -> Result<OwnedValue> {
+    use sqlparser::ast::Value as Literal;
+    Ok(match value {
+        Literal::Null => Value::Null,
+        Literal::Number(n, _) => parse_number(n)?,
+        Literal::Boolean(b) => Value::int(i64::from(*b)),
+        Literal::SingleQuotedString(s) => Value::String(s.as_bytes().into()),
+        Literal::HexStringLiteral(hex) => Value::Blob(parse_hex(hex)?.into_boxed_slice()),
+        _ => anyhow::bail!(Error::UnsupportedSql(format!(
+            "Unimplemented literal: {value}"
+        ))),
+    })
+}
+
+/// Parse a numeric literal, as an integer if possible.
+fn parse_number(n: &str) -> Result<OwnedValue> {
+    if let Ok(n) = n.parse::<i64>() {
+        return Ok(Value::int(n));
+    }
+    if let Some(hex) = n.strip_prefix("0x").or_else(|| n.strip_prefix("0X")) {
+        // Hex literals are 64-bit two's complement, so large ones come out negative.
+        return u64::from_str_radix(hex, 16)
+            .map(|n| Value::int(n as i64))
+            .with_context(|| format!("Invalid hex literal: {n}"));
+    }
+    n.parse::<f64>()
+        .map(Value::F64)
+        .with_context(|| format!("Invalid numeric literal: {n}"))
+}
+
+/// Parse the contents of a blob literal, like `X'0A1B'`.
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    anyhow::ensure!(
+        hex.len() % 2 == 0,
+        "Blob literal must have an even number of digits"
+    );
+    hex.as_bytes()
+        .chunks(2)
+        .map(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .context("Invalid digit in blob literal")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::{dialect::SQLiteDialect, parser::Parser};
+
+    use super::*;
+
+    fn eval(sql: &str) -> Result<OwnedValue> {
+        let expr = Parser::new(&SQLiteDialect {})
+            .try_with_sql(sql)
+            .unwrap()
+            .parse_expr()
+            .unwrap();
+        evaluate(&expr, &mut |name| match name {
+            [column] if column.value == "a" => Ok(Value::int(3)),
+            [column] if column.value == "b" => Ok(Value::Null),
+            _ => anyhow::bail!("no such column"),
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let int = |n| Some(Value::int(n));
+        let cases: &[(&str, Option<OwnedValue>)] = &[
+            ("a + 1", int(4)),
+            ("-a * 2 - 1", int(-7)),
+            ("7 / 2", int(3)),
+            ("7.0 / 2", Some(Value::F64(3.5))),
+            ("7 % a", int(1)),
+            ("1 / 0", Some(Value::Null)),
+            (
+                "9223372036854775807 + 1",
+                Some(Value::F64(9.223_372_036_854_776e18)),
+            ),
+            ("'12' + a", int(15)),
+            ("b + 1", Some(Value::Null)),
+            ("a || 'x'", Some(Value::String(b"3x"[..].into()))),
+            ("a = 3 AND a < 4", int(1)),
+            ("a > 3 OR b", Some(Value::Null)),
+            ("b AND 0", int(0)),
+            ("NOT b", Some(Value::Null)),
+            ("b IS NULL", int(1)),
+            ("a IS NOT NULL", int(1)),
+            (
+                "lower('AbC') || upper('é')",
+                Some(Value::String("abcé".as_bytes().into())),
+            ),
+            ("length('héllo')", int(5)),
+            ("length(X'0000')", int(2)),
+            ("length(-1.5)", int(4)),
+            ("abs(-a)", int(3)),
+            ("abs('-2.5')", Some(Value::F64(2.5))),
+            ("abs(b)", Some(Value::Null)),
+            ("abs(-9223372036854775807 - 1)", None),
+            ("abs(1, 2)", None),
+            ("nope(1)", None),
+            ("'x' > a", int(1)),
+            ("c", None),
+            ("CAST('1.5' AS INTEGER)", int(1)),
+            ("CAST('abc' AS REAL)", Some(Value::F64(0.0))),
+            ("CAST(X'3132' AS NUMERIC)", int(12)),
+            ("CAST(a AS TEXT)", Some(Value::String(b"3"[..].into()))),
+            (
+                "CAST(1.0 AS VARCHAR(10))",
+                Some(Value::String(b"1.0"[..].into())),
+            ),
+            ("CAST('x' AS BLOB)", Some(Value::Blob(b"x"[..].into()))),
+            ("CAST(b AS TEXT)", Some(Value::Null)),
+            ("(0.1 + 0.2) || ''", Some(Value::String(b"0.3"[..].into()))),
+        ];
+        for (sql, expected) in cases {
+            let result = eval(sql);
+            match expected {
+                Some(expected) => assert_eq!(
+                    result.as_ref().map_err(ToString::to_string),
+                    Ok(expected),
+                    "{sql}"
+                ),
+                None => assert!(result.is_err(), "{sql}"),
+            }
+        }
+        assert!(evaluate_constant(&Expr::Identifier("a".into())).is_err());
+        assert!(
+            matches!(eval("CAST(3 AS REAL)"), Ok(Value::F64(_))),
+            "Casting to REAL should always give a float"
+        );
+        assert!(
+            matches!(eval("CAST(2.0 AS NUMERIC)"), Ok(Value::F64(_))),
+            "Casting a number to NUMERIC leaves it alone"
+        );
+    }
+}
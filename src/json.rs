@@ -0,0 +1,375 @@
+//! Parsing JSON text for the JSON functions
+//!
+//! This keeps the order of the keys in objects, and the text of numbers, so that values can be
+//! written back out the way SQLite does.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::record::{OwnedValue, Value};
+
+/// How deeply arrays and objects can be nested, which is the same as SQLite's limit.
+const MAX_DEPTH: usize = 1000;
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    /// A number, kept as the text it was written as
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// An object's keys and values, in the order they were written
+    Object(Vec<(String, Json)>),
+}
+impl Json {
+    /// Parse `text`, which must be a single JSON value, optionally surrounded by whitespace.
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0);
+        parser.skip_whitespace();
+        value
+            .filter(|_| parser.pos == parser.text.len())
+            .context("malformed JSON")
+    }
+
+    /// Get the value with the given key, if `self` is an object with it.
+    ///
+    /// If the key appears more than once, the first one is used, as SQLite does.
+    pub(crate) fn get_key(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Object(entries) => entries
+                .iter()
+                .find_map(|(name, value)| (name == key).then_some(value)),
+            _ => None,
+        }
+    }
+
+    /// Get the value at the given index, if `self` is an array that long.
+    pub(crate) fn get_index(&self, idx: usize) -> Option<&Self> {
+        match self {
+            Self::Array(items) => items.get(idx),
+            _ => None,
+        }
+    }
+
+    /// Whether `self` is an array or an object.
+    pub(crate) fn is_container(&self) -> bool {
+        matches!(self, Self::Array(_) | Self::Object(_))
+    }
+
+    /// Get the name SQLite gives to the type of `self`, as returned by `json_type`.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::Bool(true) => "true",
+            Self::Bool(false) => "false",
+            Self::Number(n) if n.contains(['.', 'e', 'E']) => "real",
+            Self::Number(_) => "integer",
+            Self::String(_) => "text",
+            Self::Array(_) => "array",
+            Self::Object(_) => "object",
+        }
+    }
+
+    /// Convert `self` into the SQL value SQLite gives for it, with arrays and objects as their
+    /// minified JSON text.
+    ///
+    /// Integers too large for 64 bits are converted to reals, as they are in SQLite.
+    pub(crate) fn to_sql(&self) -> OwnedValue {
+        match self {
+            Self::Null => Value::Null,
+            Self::Bool(b) => Value::int(i64::from(*b)),
+            Self::Number(n) => match n.parse::<i64>() {
+                Ok(n) => Value::int(n),
+                Err(_) => Value::F64(n.parse().unwrap_or_default()),
+            },
+            Self::String(text) => Value::String(text.as_bytes().into()),
+            Self::Array(_) | Self::Object(_) => Value::String(self.to_string().into_bytes().into()),
+        }
+    }
+}
+
+/// Write `self` as minified JSON.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => f.write_str(n),
+            Self::String(text) => write_string(f, text),
+            Self::Array(items) => {
+                f.write_str("[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Object(entries) => {
+                f.write_str("{")?;
+                for (idx, (key, value)) in entries.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// Write `text` as a quoted JSON string, escaping the characters which need it.
+fn write_string(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            c if c.is_control() && u32::from(c) < 0x20 => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// A recursive descent parser over JSON text.
+///
+/// Its methods return `None` if the text isn't valid JSON, since there's only one error to give.
+struct Parser<'a> {
+    text: &'a [u8],
+    /// The index of the next byte to read
+    pos: usize,
+}
+impl Parser<'_> {
+    /// Parse the value starting at the next non-whitespace byte, inside `depth` arrays and
+    /// objects.
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth >= MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+        match self.text.get(self.pos)? {
+            b'n' => self.keyword("null", Json::Null),
+            b't' => self.keyword("true", Json::Bool(true)),
+            b'f' => self.keyword("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Some(Json::Array(items))
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        entries.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Some(Json::Object(entries))
+            }
+            _ => self.number(),
+        }
+    }
+
+    /// Parse the keyword `word`, which becomes `value`.
+    fn keyword(&mut self, word: &str, value: Json) -> Option<Json> {
+        let end = self.pos + word.len();
+        (self.text.get(self.pos..end)? == word.as_bytes()).then(|| {
+            self.pos = end;
+            value
+        })
+    }
+
+    /// Parse a number, keeping its text.
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        self.eat_byte(b'-');
+        if !self.eat_byte(b'0') && self.digits() == 0 {
+            return None;
+        }
+        if self.eat_byte(b'.') && self.digits() == 0 {
+            return None;
+        }
+        if self.eat_byte(b'e') || self.eat_byte(b'E') {
+            let _ = self.eat_byte(b'+') || self.eat_byte(b'-');
+            if self.digits() == 0 {
+                return None;
+            }
+        }
+        let text = std::str::from_utf8(&self.text[start..self.pos]).ok()?;
+        Some(Json::Number(text.to_owned()))
+    }
+
+    /// Skip over any digits, returning how many there were.
+    fn digits(&mut self) -> usize {
+        let count = self.text[self.pos..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count();
+        self.pos += count;
+        count
+    }
+
+    /// Parse a string, starting at its opening quote.
+    fn string(&mut self) -> Option<String> {
+        if !self.eat_byte(b'"') {
+            return None;
+        }
+        let mut text = Vec::new();
+        loop {
+            let byte = *self.text.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.text.get(self.pos)?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => text.push(escape),
+                        b'b' => text.push(b'\x08'),
+                        b'f' => text.push(b'\x0c'),
+                        b'n' => text.push(b'\n'),
+                        b'r' => text.push(b'\r'),
+                        b't' => text.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            text.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => return None,
+                    }
+                }
+                0..0x20 => return None,
+                _ => text.push(byte),
+            }
+        }
+        String::from_utf8(text).ok()
+    }
+
+    /// Parse the rest of a `\u` escape, including the second half of a surrogate pair.
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high);
+        }
+        if self.text.get(self.pos..self.pos + 2)? != b"\\u" {
+            return None;
+        }
+        self.pos += 2;
+        let low = self.hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+    }
+
+    /// Parse four hex digits.
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.text.get(self.pos..self.pos + 4)?).ok()?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+
+    /// Skip over whitespace.
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|byte| matches!(byte, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace, then consume `byte` if it's next, returning whether it was.
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        self.eat_byte(byte)
+    }
+
+    /// Skip whitespace, then consume `byte`, which must be next.
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.eat(byte).then_some(())
+    }
+
+    /// Consume `byte` if it's the next byte, returning whether it was.
+    fn eat_byte(&mut self, byte: u8) -> bool {
+        let found = self.text.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+    use crate::record::Value;
+
+    #[test]
+    fn test_parse_json() {
+        let json = Json::parse(
+            " {\"b\" : [1.50, -0, 2e3], \"a\":{\"x\\ty\":\"\\u00e9\\ud83d\\ude00\"}, \"c\": null} ",
+        )
+        .expect("Failed to parse JSON");
+        // Keys keep their order, and numbers keep their text.
+        assert_eq!(
+            json.to_string(),
+            r#"{"b":[1.50,-0,2e3],"a":{"x\ty":"é😀"},"c":null}"#,
+        );
+        let numbers = json.get_key("b").expect("Missing key");
+        assert_eq!(numbers.get_index(0).map(Json::type_name), Some("real"));
+        assert_eq!(numbers.get_index(1).map(Json::to_sql), Some(Value::int(0)));
+        assert_eq!(numbers.get_index(3), None);
+        let big = Json::parse("99999999999999999999").expect("Failed to parse JSON");
+        assert_eq!(big.type_name(), "integer");
+        assert_eq!(big.to_sql(), Value::F64(1e20));
+
+        for text in [
+            "", "[1", "[1,]", "{\"a\"}", "01", "1.", "-", "\"\\x\"", "tru", "[1] x", "'a'",
+        ] {
+            assert!(Json::parse(text).is_err(), "{text:?} should be malformed");
+        }
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(
+            Json::parse(&nested(1000)).is_ok(),
+            "1000 levels should be allowed"
+        );
+        assert!(
+            Json::parse(&nested(1001)).is_err(),
+            "Deeper nesting should be rejected"
+        );
+    }
+}
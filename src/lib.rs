@@ -1,34 +1,176 @@
-use anyhow::{Context, Result};
+use anyhow::Context;
 
 // `rustyline` is needed for the CLI interface
+#[cfg(not(target_arch = "wasm32"))]
 use rustyline as _;
+// `serde` is only needed by the tests without the `serde` feature, for deriving `Deserialize`
+#[cfg(all(test, not(feature = "serde")))]
+use serde as _;
 
+// Lets the code generated by `#[derive(FromRow)]` name this crate in its own tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as sqlite_riir;
+
+pub mod blob;
+mod btree;
+pub mod completion;
 mod db;
+#[cfg(feature = "serde")]
+pub mod de;
+mod error;
+mod expr;
+mod json;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod output;
 pub mod page;
 pub mod pager;
 pub mod record;
+pub mod rows;
+pub mod schema;
+pub mod script;
+pub mod table;
 pub mod table_iter;
+mod vtab;
 
-pub use db::Database;
+#[cfg(not(target_arch = "wasm32"))]
+pub use db::OpenOptions;
+pub use db::{Database, InterruptHandle, QueryStats, TableMatch, TableMatchLocation};
+pub use error::{Error, Result};
+pub use rows::{ColumnInfo, Row, Rows};
 
 /// Parse a variable-length integer
-fn parse_varint(buffer: &mut &[u8]) -> Result<i64> {
-    let mut acc = 0;
+///
+/// Varints are big-endian, with 7 bits in each byte whose high bit is set to mark that more bytes
+/// follow. The ninth byte, if reached, contributes all 8 of its bits.
+fn parse_varint(buffer: &mut &[u8]) -> anyhow::Result<i64> {
+    let mut acc = 0_u64;
     let mut length = 0;
     loop {
         let new_byte = buffer
             .get(length)
             .context("Unexpected end of buffer inside varint")?;
-        if length == 8 {
-            acc |= i64::from(*new_byte) << 56;
+        length += 1;
+        if length == 9 {
+            acc = (acc << 8) | u64::from(*new_byte);
             break;
         }
-        acc |= i64::from(new_byte & 0x7F) << (length * 7);
-        length += 1;
+        acc = (acc << 7) | u64::from(new_byte & 0x7F);
         if new_byte & 0x80 == 0 {
             break;
         }
     }
     *buffer = &buffer[length..];
-    Ok(acc)
+    Ok(acc as i64)
+}
+
+/// Append `value` to `buffer` as a variable-length integer.
+///
+/// This is the inverse of [`parse_varint`].
+fn write_varint(value: i64, buffer: &mut Vec<u8>) {
+    let value = value as u64;
+    if value >> 56 != 0 {
+        // Needs all nine bytes, the last of which holds a full 8 bits
+        for shift in (1..=8).rev() {
+            buffer.push(((value >> (shift * 7 + 1)) & 0x7F) as u8 | 0x80);
+        }
+        buffer.push(value as u8);
+        return;
+    }
+    let groups = (u64::BITS - value.leading_zeros()).div_ceil(7).max(1);
+    for group in (1..groups).rev() {
+        buffer.push(((value >> (group * 7)) & 0x7F) as u8 | 0x80);
+    }
+    buffer.push((value & 0x7F) as u8);
+}
+
+/// The number of bytes `value` takes up when written as a varint.
+fn varint_len(value: i64) -> usize {
+    let mut buffer = Vec::with_capacity(9);
+    write_varint(value, &mut buffer);
+    buffer.len()
+}
+
+/// Check whether `text` matches a SQL `LIKE` pattern.
+///
+/// As in SQLite, `%` matches any sequence of characters, `_` matches any single character, and
+/// ASCII letters are matched case-insensitively.
+fn like_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Just past the last `%` seen, and how far into the text it's matched up to. Only the last
+    // `%` ever has to match more, since anything an earlier one could match, it can match too, so
+    // this takes at most `pattern.len() * text.len()` steps.
+    let mut last_wildcard = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('%') => {
+                p += 1;
+                last_wildcard = Some((p, t));
+            }
+            Some(&c) if c == '_' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((after_wildcard, matched)) = last_wildcard else {
+                    return false;
+                };
+                last_wildcard = Some((after_wildcard, matched + 1));
+                p = after_wildcard;
+                t = matched + 1;
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_round_trip() {
+        for (value, len) in [
+            (0, 1),
+            (0x7f, 1),
+            (0x80, 2),
+            (0x3fff, 2),
+            (0x4000, 3),
+            (1 << 55, 8),
+            (1 << 56, 9),
+            (-1, 9),
+            (i64::MIN, 9),
+            (i64::MAX, 9),
+        ] {
+            let mut buffer = Vec::new();
+            write_varint(value, &mut buffer);
+            assert_eq!(buffer.len(), len, "Wrong length for varint {value}");
+            assert_eq!(varint_len(value), len, "Wrong length for varint {value}");
+            buffer.push(0xAB);
+            let mut remaining = buffer.as_slice();
+            assert_eq!(parse_varint(&mut remaining).unwrap(), value);
+            assert_eq!(remaining, [0xAB], "Varint should consume exactly its bytes");
+        }
+        assert_eq!(parse_varint(&mut &[0x81, 0x00][..]).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn test_like_matches() {
+        assert!(like_matches("t%", "T12"));
+        assert!(like_matches("%name%", "first_NAME_idx"));
+        assert!(like_matches("t_", "t1"));
+        assert!(!like_matches("t_", "t12"));
+        assert!(!like_matches("users", "user"));
+        assert!(like_matches("", ""));
+        assert!(like_matches("%", ""));
+        assert!(like_matches("%_%s", "users"));
+        assert!(!like_matches("%_%s", "s"));
+        assert!(like_matches("a%b%c", "aXbYbZc"));
+        assert!(!like_matches("a%b%c", "aXcYb"));
+        // Patterns with many wildcards don't take exponential time to fail.
+        let text = "a".repeat(5000);
+        assert!(!like_matches(&format!("{}b", "%a".repeat(20)), &text));
+    }
 }
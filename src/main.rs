@@ -1,17 +1,92 @@
-#![allow(clippy::print_stdout)]
+#![allow(clippy::print_stdout, clippy::print_stderr)]
 
-use std::fs::File;
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{Cursor, IsTerminal, Read},
+    process::ExitCode,
+    sync::LazyLock,
+    time::Instant,
+};
 
 use anyhow::Context;
-use sqlite_riir::{page::ParsedPage, pager::Pager, Database};
+// `io-uring` is only needed by the library, for reading pages in batches
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use io_uring as _;
+// `memmap2` is only needed by the library, for the memory-mapped pager
+#[cfg(feature = "mmap")]
+use memmap2 as _;
+// `sqlite-riir-derive` is only needed by the library, which re-exports its derive macro
+#[cfg(feature = "derive")]
+use sqlite_riir_derive as _;
+// `serde` is only needed by the library, for deserializing rows, and by its tests
+#[cfg(any(test, feature = "serde"))]
+use serde as _;
+#[cfg(feature = "http")]
+use sqlite_riir::pager::HttpFile;
+use sqlite_riir::{
+    completion::Completions,
+    output::{OutputFormat, OutputMode},
+    page::{btree_index_leaf, ParsedPage},
+    pager::{Pager, Storage},
+    record::TextEncoding,
+    schema::ObjectKind,
+    script::{is_complete, split_script, ScriptLine, ScriptPart},
+    Database, InterruptHandle, OpenOptions, QueryStats, TableMatchLocation,
+};
+// `tokio` is only needed by the library, for its async API
+#[cfg(feature = "tokio")]
+use tokio as _;
+
+/// The handle which interrupts the statement being run when Ctrl-C is pressed, shared by every
+/// database the shell opens.
+static INTERRUPT: LazyLock<InterruptHandle> = LazyLock::new(InterruptHandle::default);
+
+/// Interrupt the statement being run, when Ctrl-C is pressed while it runs.
+///
+/// At the prompt, Ctrl-C is read as a key instead, so this only runs while a command does.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPT.interrupt();
+}
+
+/// The name which opens an empty database in memory instead of a file, as in the `sqlite3` shell.
+const MEMORY_DATABASE: &str = ":memory:";
+
+/// Print the values in an entry of an index, whose text is stored in `encoding`.
+fn display_index_entry(cell: &btree_index_leaf::Cell, encoding: TextEncoding) {
+    match cell.payload() {
+        Ok(Some(record)) => {
+            for value in record.value_iter() {
+                println!("{}: {}", value.ty(), value.decode_text(encoding));
+            }
+        }
+        Ok(None) => println!(
+            "{} byte payload continued in overflow page {}",
+            cell.payload_size(),
+            cell.overflow_page().unwrap_or_default(),
+        ),
+        Err(e) => println!("Error while parsing payload:\n{e:?}"),
+    }
+}
 
 /// Print the contents of a database file.
 fn display_database(path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
     let mut pager = Pager::new(File::open(path).context("Failed to open file")?)
         .context("Failed to read database")?;
     let page_count = pager.page_count();
+    let encoding = pager.text_encoding();
     println!("\n{page_count} pages:\n\n");
     for page_idx in 1..=page_count {
+        if pager.is_ptrmap_page(page_idx) {
+            println!("Page {page_idx}: Pointer map\n");
+            continue;
+        }
+        if pager.is_lock_byte_page(page_idx) {
+            println!("Page {page_idx}: Lock-byte page\n");
+            continue;
+        }
         match pager.read_page(page_idx) {
             Ok(page) => match page.parse() {
                 ParsedPage::BTreeTableLeaf(page) => {
@@ -21,8 +96,17 @@ fn display_database(path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
                     );
                     for cell in page.cells() {
                         println!("Cell {}:", cell.row_id());
-                        for value in cell.payload().value_iter() {
-                            println!("{}: {value}", value.ty());
+                        match cell.payload() {
+                            Some(record) => {
+                                for value in record.value_iter() {
+                                    println!("{}: {}", value.ty(), value.decode_text(encoding));
+                                }
+                            }
+                            None => println!(
+                                "{} byte payload continued in overflow page {}",
+                                cell.payload_size(),
+                                cell.overflow_page().unwrap_or_default(),
+                            ),
                         }
                         println!();
                     }
@@ -43,6 +127,33 @@ fn display_database(path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
                     println!();
                     println!();
                 }
+                ParsedPage::BTreeIndexLeaf(page) => {
+                    println!(
+                        "Page {page_idx}: Index btree leaf with {} cells",
+                        page.num_cells(),
+                    );
+                    for (idx, cell) in page.cells().enumerate() {
+                        println!("Cell {idx}:");
+                        display_index_entry(&cell, encoding);
+                        println!();
+                    }
+                    println!();
+                }
+                ParsedPage::BTreeIndexInternal(page) => {
+                    println!(
+                        "Page {page_idx}: Index btree internal with {} cells",
+                        page.num_cells(),
+                    );
+                    for (idx, cell) in page.cells().enumerate() {
+                        println!("Cell {idx}:");
+                        display_index_entry(&cell.entry, encoding);
+                        println!("Left Child Page: {}", cell.left_child_page);
+                        println!();
+                    }
+                    println!("Right-most child Page: {}", page.rightmost_child_idx());
+                    println!();
+                    println!();
+                }
             },
             Err(e) => println!("Page {page_idx}: Error while reading:\n{e:?}"),
         }
@@ -50,96 +161,776 @@ fn display_database(path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn display_tables(db: &mut Database) -> anyhow::Result<()> {
-    let statement = sqlparser::parser::Parser::parse_sql(
-        &sqlparser::dialect::SQLiteDialect {},
-        "SElECT * FROM sqlite_schema",
-    )
-    .context("failed to parse command")?;
-    anyhow::ensure!(statement.len() == 1, "command parsed unexpectedly");
-    db.execute_statement(&statement[0], |table| {
+/// Print the objects in the schema, optionally only for the tables matching the pattern in
+/// `args`.
+fn display_tables<File: Storage>(db: &Database<File>, args: &str) {
+    let pattern = Some(args).filter(|pattern| !pattern.is_empty());
+    for object in db.schema_objects(pattern) {
         println!(
             "Table {table_name}: \"{create_command}\" @ {page_num}",
-            table_name = table[2].as_str().context("invalid string in table name")?,
-            create_command = table[4].as_str().context("invalid string in table name")?,
-            page_num = table[3]
-                .as_usize()
-                .context("invalid number in table root page number")?,
+            table_name = object.table_name,
+            create_command = object.sql.as_deref().unwrap_or_default(),
+            page_num = object.root_page.unwrap_or_default(),
         );
+    }
+}
+
+/// Print the indexes and the tables they're on, optionally only for the tables matching the
+/// pattern in `args`.
+fn display_indexes<File: Storage>(db: &Database<File>, args: &str) {
+    let pattern = Some(args).filter(|pattern| !pattern.is_empty());
+    for object in db.schema_objects(pattern) {
+        if object.kind == ObjectKind::Index {
+            println!("{} on {}", object.name, object.table_name);
+        }
+    }
+}
+
+/// Print a histogram of the pages read by the last statement, laid out in page order.
+///
+/// Each character stands for a run of pages, shaded by how many reads landed in them, so full
+/// scans show up as a solid band while seeks show up as a few scattered marks.
+fn display_heatmap<File: Storage>(db: &mut Database<File>) {
+    const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+    const MAX_CELLS: usize = 256;
+    const CELLS_PER_LINE: usize = 64;
+
+    let page_count = db.page_count();
+    let accesses = db.page_accesses();
+    let total = accesses.values().sum::<u64>();
+    println!(
+        "{} reads of {} distinct pages (of {page_count})",
+        total,
+        accesses.len()
+    );
+    let pages_per_cell = page_count.div_ceil(MAX_CELLS).max(1);
+    let mut cells = vec![0_u64; page_count.div_ceil(pages_per_cell)];
+    for (&page, &count) in accesses {
+        if let Some(cell) = cells.get_mut((page - 1) / pages_per_cell) {
+            *cell += count;
+        }
+    }
+    let max = cells.iter().copied().max().unwrap_or_default().max(1);
+    for (line_idx, line) in cells.chunks(CELLS_PER_LINE).enumerate() {
+        let rendered = line
+            .iter()
+            .map(|&count| match count {
+                0 => SHADES[0],
+                // Any reads at all should be visible, so nonzero counts start at the second shade
+                _ => SHADES[1 + (count * 3 / max) as usize],
+            })
+            .collect::<String>();
+        println!(
+            "{:>8} |{rendered}|",
+            line_idx * CELLS_PER_LINE * pages_per_cell + 1
+        );
+    }
+    if pages_per_cell > 1 {
+        println!("Each character covers {pages_per_cell} pages");
+    }
+}
+
+/// Print what the pager has done since the database was opened.
+fn display_stats<File: Storage>(db: &Database<File>) {
+    let stats = db.pager_stats();
+    let reads = stats.cache_hits + stats.cache_misses;
+    println!(
+        "{reads} page reads: {} cache hits, {} cache misses",
+        stats.cache_hits, stats.cache_misses
+    );
+    println!(
+        "{} pages ({} bytes) read from disk",
+        stats.pages_read, stats.bytes_read
+    );
+    println!("{} pages evicted from the cache", stats.evictions);
+}
+
+/// Print the `CREATE` statements in the schema, optionally only for the objects matching the
+/// pattern in `args`.
+fn display_schema<File: Storage>(db: &Database<File>, args: &str) {
+    let pattern = Some(args).filter(|pattern| !pattern.is_empty());
+    for statement in db.schema_statements(pattern) {
+        println!("{statement};");
+    }
+}
+
+/// Print the tables matching a search.
+///
+/// `args` is the search pattern, optionally preceded by `--sql` to also search the `CREATE TABLE`
+/// statements.
+fn find_tables<File: Storage>(db: &mut Database<File>, args: &str) -> anyhow::Result<()> {
+    let (search_sql, pattern) = match args.strip_prefix("--sql") {
+        Some(pattern) => (true, pattern.trim()),
+        None => (false, args),
+    };
+    anyhow::ensure!(!pattern.is_empty(), "Usage: .find [--sql] PATTERN");
+    for found in db.find_tables(pattern, search_sql)? {
+        let locations = found
+            .locations
+            .iter()
+            .map(|location| match location {
+                TableMatchLocation::Name => "name".to_owned(),
+                TableMatchLocation::Column(column) => format!("column {column}"),
+                TableMatchLocation::Sql => "sql".to_owned(),
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}.{} ({})",
+            found.schema,
+            found.table,
+            locations.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Change the output mode to the one named in `args`, or print the current one if none is named.
+fn set_mode(format: &mut OutputFormat, args: &str) -> anyhow::Result<()> {
+    if args.is_empty() {
+        println!("current output mode: {}", format.mode.as_str());
+        return Ok(());
+    }
+    let names = OutputMode::ALL.map(OutputMode::as_str);
+    format.mode = OutputMode::from_name(args)
+        .with_context(|| format!("Unknown mode {args:?}, use one of: {}", names.join(", ")))?;
+    Ok(())
+}
+
+/// Parse the `on` or `off` given to a setting's command, as the `sqlite3` shell does.
+fn parse_switch(args: &str) -> Option<bool> {
+    match args.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "1" => Some(true),
+        "off" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Turn the headers in the `list` and `csv` modes on or off, as given in `args`.
+fn set_headers(format: &mut OutputFormat, args: &str) -> anyhow::Result<()> {
+    format.headers = parse_switch(args).context("Usage: .headers on|off")?;
+    Ok(())
+}
+
+/// Turn the timing of statements on or off, as given in `args`.
+fn set_timer(timer: &mut bool, args: &str) -> anyhow::Result<()> {
+    *timer = parse_switch(args).context("Usage: .timer on|off")?;
+    Ok(())
+}
+
+/// Split the arguments to a command at whitespace, except where it's in quotes.
+fn split_args(args: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut chars = args.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '\'' || c == '"' {
+            arg.extend(chars.by_ref().take_while(|&next| next != c));
+        } else {
+            arg.push(c);
+            while let Some(next) = chars.next_if(|next| !next.is_whitespace()) {
+                arg.push(next);
+            }
+        }
+        split.push(arg);
+    }
+    split
+}
+
+/// Replace the escapes for tabs, newlines, carriage returns and backslashes in `arg` with the
+/// characters they stand for, as the `sqlite3` shell does for separators.
+fn unescape(arg: &str) -> String {
+    let mut unescaped = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') | None => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+        }
+    }
+    unescaped
+}
+
+/// Set the text printed for `NULL` to the one given in `args`.
+fn set_null_value(format: &mut OutputFormat, args: &str) -> anyhow::Result<()> {
+    let args = split_args(args);
+    let [null_value] = args.as_slice() else {
+        anyhow::bail!("Usage: .nullvalue STRING");
+    };
+    format.null_value = unescape(null_value);
+    Ok(())
+}
+
+/// Set the separators between the values in a row, and optionally between rows, in the `list`
+/// mode to those given in `args`.
+fn set_separator(format: &mut OutputFormat, args: &str) -> anyhow::Result<()> {
+    let args = split_args(args);
+    anyhow::ensure!(
+        matches!(args.len(), 1 | 2),
+        "Usage: .separator COLUMN [ROW]"
+    );
+    format.column_separator = unescape(&args[0]);
+    if let Some(row_separator) = args.get(1) {
+        format.row_separator = unescape(row_separator);
+    }
+    Ok(())
+}
+
+/// Set the widths of the columns in the `table` mode to those given in `args`, or make them all
+/// fit their values if none are given.
+fn set_widths(format: &mut OutputFormat, args: &str) -> anyhow::Result<()> {
+    format.widths = split_args(args)
+        .iter()
+        .map(|width| width.parse())
+        .collect::<Result<_, _>>()
+        .context("Usage: .width NUM1 NUM2 ...")?;
+    Ok(())
+}
+
+/// Copy the database to the file given in `args`.
+fn backup<File: Storage>(db: &mut Database<File>, args: &str) -> anyhow::Result<()> {
+    let args = split_args(args);
+    let [path] = args.as_slice() else {
+        anyhow::bail!("Usage: .backup FILE");
+    };
+    db.backup(path)
+        .with_context(|| format!("Error backing up to {path}"))
+}
+
+/// What the shell prints about each statement besides the rows it returns.
+#[derive(Clone, Copy, Debug, Default)]
+struct StatementOutput {
+    /// Whether to print how long each statement takes, as set by `.timer`
+    timer: bool,
+    /// Whether to print the query plan of each statement before it runs, as set by `.eqp`
+    eqp: bool,
+    /// Whether to print what each statement did, as set by `.stats`
+    stats: bool,
+}
+
+/// Run `statement`, printing the rows it returns in the given format, along with its query plan
+/// and what it did if `output` asks for them.
+fn run_statement<File: Storage>(
+    db: &mut Database<File>,
+    statement: &sqlparser::ast::Statement,
+    format: &OutputFormat,
+    output: StatementOutput,
+) -> anyhow::Result<()> {
+    if output.eqp {
+        // A statement which can't be planned fails to run too, which says why.
+        if let Ok(plan) = db.query_plan(statement) {
+            display_query_plan(&plan);
+        }
+    }
+    let mut rows = Vec::new();
+    let result = db.execute_statement(statement, |row| {
+        rows.push(row);
         Ok(())
-    })?;
+    });
+    // The rows from before an error are still printed, as the `sqlite3` shell does.
+    let columns = db.statement_columns(statement).unwrap_or_default();
+    format
+        .write(&columns, &rows, &mut std::io::stdout().lock())
+        .context("Failed to print rows")?;
+    let result = result?;
+    if output.stats {
+        display_query_stats(&result);
+    }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let file_path = std::env::args_os()
-        .nth(1)
-        .unwrap_or(std::ffi::OsString::from("./test-data/minimal-test.sqlite"));
-    let mut db = Database::new(File::open(&file_path).context("Failed to open file")?)
-        .context("Failed to read database")?;
-    let mut readline =
-        rustyline::DefaultEditor::new().context("Error setting up readline instance")?;
-    loop {
-        match readline.readline("sqlite-riir>> ") {
-            Ok(line) => {
-                if let Some(debug_cmd) = line.strip_prefix('.') {
-                    match debug_cmd {
-                        "debug" => {
-                            if let Err(e) = display_database(&file_path) {
-                                println!(
-                                    "{:?}",
-                                    e.context(format!(
-                                        "Error displaying database at {}",
-                                        std::path::Path::new(&file_path).display()
-                                    ))
-                                );
-                            }
-                        }
-                        "tables" => {
-                            if let Err(e) = display_tables(&mut db) {
-                                println!(
-                                    "{:?}",
-                                    e.context(format!(
-                                        "Error displaying database at {}",
-                                        std::path::Path::new(&file_path).display()
-                                    ))
-                                );
-                            }
-                        }
-                        _ => println!("Unrecognized debug command: {debug_cmd:?}"),
-                    }
-                } else {
-                    let statements = match sqlparser::parser::Parser::parse_sql(
-                        &sqlparser::dialect::SQLiteDialect {},
-                        &line,
-                    ) {
-                        Ok(cmd) => cmd,
-                        Err(e) => {
-                            println!(
-                                "{:?}",
-                                anyhow::Error::new(e).context("Error parsing command")
-                            );
+/// Print the steps of a statement's query plan as a tree, as the `sqlite3` shell does, unless it
+/// has none.
+fn display_query_plan(plan: &[String]) {
+    if plan.is_empty() {
+        return;
+    }
+    println!("QUERY PLAN");
+    for (idx, step) in plan.iter().enumerate() {
+        let branch = if idx + 1 == plan.len() { "`--" } else { "|--" };
+        println!("{branch}{step}");
+    }
+}
+
+/// Print the rows and pages read by a statement.
+fn display_query_stats(stats: &QueryStats) {
+    let page_reads = stats.pages_read + stats.cache_hits;
+    println!("Rows scanned:     {}", stats.rows_examined);
+    println!("Rows returned:    {}", stats.rows_returned);
+    println!(
+        "Pages read:       {page_reads} ({} from disk)",
+        stats.pages_read
+    );
+    if page_reads > 0 {
+        let ratio = stats.cache_hits as f64 / page_reads as f64;
+        println!("Cache hit ratio:  {:.1}%", ratio * 100.0);
+    }
+}
+
+/// Run `run` against `db`, printing how long it took and how many pages it read afterwards if
+/// `timer` is set, as the `sqlite3` shell's `.timer` does.
+fn timed<File: Storage, T, E>(
+    db: &mut Database<File>,
+    timer: bool,
+    run: impl FnOnce(&mut Database<File>) -> Result<T, E>,
+) -> Result<T, E> {
+    if !timer {
+        return run(db);
+    }
+    let start = Instant::now();
+    let before = db.pager_stats();
+    let result = run(db);
+    let elapsed = start.elapsed();
+    let stats = db.pager_stats().since(&before);
+    println!(
+        "Run Time: real {:.6} page reads {} cache misses {} bytes read {}",
+        elapsed.as_secs_f64(),
+        stats.cache_hits + stats.cache_misses,
+        stats.cache_misses,
+        stats.bytes_read,
+    );
+    result
+}
+
+/// Run the SQL statements in `sql`, printing the rows they return, and stopping at the first one
+/// which fails.
+///
+/// What's printed about each statement besides its rows, like the time it takes, is set by
+/// `output`.
+fn run_sql<File: Storage>(
+    db: &mut Database<File>,
+    sql: &str,
+    format: &OutputFormat,
+    output: StatementOutput,
+) -> anyhow::Result<()> {
+    let command = sql.trim().trim_end_matches(';').trim_end();
+    if command.eq_ignore_ascii_case("vacuum") {
+        // sqlparser can't parse `VACUUM`, so it's handled before parsing.
+        return timed(db, output.timer, Database::vacuum).context("Error vacuuming database");
+    }
+    if command.eq_ignore_ascii_case("analyze") {
+        // Nor can it parse `ANALYZE`.
+        return timed(db, output.timer, Database::analyze).context("Error analyzing database");
+    }
+    let statements =
+        sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+            .context("Error parsing command")?;
+    for statement in statements {
+        timed(db, output.timer, |db| {
+            run_statement(db, &statement, format, output)
+        })
+        .context("Error running given command")?;
+    }
+    Ok(())
+}
+
+/// A database open in the shell, which can be stored in a few different ways.
+enum ShellDatabase {
+    /// A database file
+    File(Database),
+    /// A database held in memory, from `:memory:`
+    Memory(Database<Cursor<Vec<u8>>>),
+    /// A database read from a web server
+    #[cfg(feature = "http")]
+    Http(Database<HttpFile>),
+}
+
+/// Evaluate `$body` with `$db` bound to the [`Database`] in a [`ShellDatabase`], whatever it's
+/// stored in.
+macro_rules! with_db {
+    ($shell_db:expr, $db:ident => $body:expr) => {
+        match $shell_db {
+            ShellDatabase::File($db) => $body,
+            ShellDatabase::Memory($db) => $body,
+            #[cfg(feature = "http")]
+            ShellDatabase::Http($db) => $body,
+        }
+    };
+}
+
+impl ShellDatabase {
+    /// Open the database at `path`, which is a file unless it's `:memory:` or an `http://` URL.
+    ///
+    /// Files which don't exist are created, unless `read_only` is set. Its statements are
+    /// interrupted by [`INTERRUPT`].
+    fn open(path: &OsStr, read_only: bool) -> anyhow::Result<Self> {
+        let mut db = Self::open_storage(path, read_only)?;
+        with_db!(&mut db, db => db.set_interrupt_handle(INTERRUPT.clone()));
+        Ok(db)
+    }
+
+    /// Open the database at `path`, in whichever kind of storage it names.
+    fn open_storage(path: &OsStr, read_only: bool) -> anyhow::Result<Self> {
+        if path == MEMORY_DATABASE {
+            return Ok(Self::Memory(Database::open_in_memory()?));
+        }
+        if let Some(url) = path.to_str().filter(|path| path.starts_with("http://")) {
+            #[cfg(feature = "http")]
+            {
+                let file = HttpFile::open(url).with_context(|| format!("Failed to open {url}"))?;
+                return Ok(Self::Http(
+                    Database::new(file).context("Failed to read database")?,
+                ));
+            }
+            #[cfg(not(feature = "http"))]
+            anyhow::bail!("Opening {url} needs the `http` feature");
+        }
+        Ok(Self::File(
+            Database::open_with(path, OpenOptions::new().read_only(read_only).create(true))
+                .context("Failed to read database")?,
+        ))
+    }
+}
+
+/// Run the shell on the database named by the first argument.
+///
+/// As with the `sqlite3` shell, SQL given as the second argument or piped to stdin is run without
+/// starting the interactive prompt, and the exit status is a failure if any of it fails.
+fn main() -> anyhow::Result<ExitCode> {
+    let mut args = std::env::args_os().skip(1);
+    let file_path = args
+        .next()
+        .unwrap_or(OsString::from("./test-data/minimal-test.sqlite"));
+    let db = ShellDatabase::open(&file_path, false)?;
+    let mut shell = Shell {
+        db,
+        file_path,
+        format: OutputFormat::default(),
+        output: StatementOutput::default(),
+        read_depth: 0,
+        failed: false,
+    };
+    if let Some(sql) = args.next() {
+        let sql = sql
+            .into_string()
+            .map_err(|sql| anyhow::anyhow!("The SQL to run isn't valid UTF-8: {sql:?}"))?;
+        shell.run_script(&sql, None);
+    } else if std::io::stdin().is_terminal() {
+        shell.repl()?;
+    } else {
+        let mut script = String::new();
+        std::io::stdin()
+            .read_to_string(&mut script)
+            .context("Failed to read SQL from stdin")?;
+        shell.run_script(&script, None);
+    }
+    Ok(if shell.failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// The shell's commands, without the `.` they start with, for completing them.
+const DOT_COMMANDS: [&str; 19] = [
+    "backup",
+    "debug",
+    "dump",
+    "eqp",
+    "find",
+    "headers",
+    "heatmap",
+    "indexes",
+    "mode",
+    "nullvalue",
+    "open",
+    "read",
+    "recover",
+    "schema",
+    "separator",
+    "stats",
+    "tables",
+    "timer",
+    "width",
+];
+
+/// The readline helper, which completes names from the database's schema as they're typed.
+struct ShellHelper {
+    /// The names which can be completed
+    completions: Completions,
+}
+
+impl rustyline::completion::Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.completions.complete(line, pos))
+    }
+}
+
+impl rustyline::hint::Hinter for ShellHelper {
+    type Hint = String;
+
+    /// Hint the rest of the word being typed at the end of the line, if only one word completes
+    /// it.
+    fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        match self.completions.complete(line, pos) {
+            (start, words) if words.len() == 1 => words[0]
+                .get(pos - start..)
+                .filter(|rest| !rest.is_empty())
+                .map(str::to_owned),
+            _ => None,
+        }
+    }
+}
+
+impl rustyline::highlight::Highlighter for ShellHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        // Hints are dimmed, so they aren't mistaken for what's been typed.
+        Cow::Owned(format!("\x1b[2m{hint}\x1b[0m"))
+    }
+}
+
+impl rustyline::validate::Validator for ShellHelper {}
+
+impl rustyline::Helper for ShellHelper {}
+
+/// The most scripts `.read` can be nested in, so that scripts which read themselves stop.
+const MAX_READ_DEPTH: usize = 25;
+
+/// The state of the shell, as commands are run.
+struct Shell {
+    /// The open database
+    db: ShellDatabase,
+    /// The path the database was opened from
+    file_path: OsString,
+    /// How rows returned by statements are printed
+    format: OutputFormat,
+    /// What's printed about each statement besides its rows
+    output: StatementOutput,
+    /// How many scripts are being run with `.read`, each from the one before
+    read_depth: usize,
+    /// Whether any command has failed, so the shell should exit with a failure
+    failed: bool,
+}
+
+impl Shell {
+    /// Read commands from the terminal and run them against the database.
+    fn repl(&mut self) -> anyhow::Result<()> {
+        self.print_warnings();
+        #[cfg(unix)]
+        {
+            // The handle is made now, since making it in the signal handler would allocate.
+            LazyLock::force(&INTERRUPT);
+            // SAFETY: `on_interrupt` only sets an atomic flag, which is safe to do in a signal
+            // handler.
+            #[allow(clippy::fn_to_numeric_cast_any)]
+            unsafe {
+                // `signal` takes the handler's address.
+                libc::signal(libc::SIGINT, on_interrupt as libc::sighandler_t);
+            }
+        }
+        let mut readline =
+            rustyline::Editor::<ShellHelper, rustyline::history::DefaultHistory>::new()
+                .context("Error setting up readline instance")?;
+        // The lines of a statement which hasn't been finished yet
+        let mut statement = String::new();
+        loop {
+            // The schema may have changed since the last command.
+            let completions =
+                with_db!(&self.db, db => Completions::new(db.schema(), &DOT_COMMANDS));
+            readline.set_helper(Some(ShellHelper { completions }));
+            let prompt = if statement.is_empty() {
+                "sqlite-riir>> "
+            } else {
+                "         ...> "
+            };
+            match readline.readline(prompt) {
+                Ok(line) => {
+                    if statement.is_empty() {
+                        if let Some(command) = line.trim_start().strip_prefix('.') {
+                            self.run_dot_command(command);
                             continue;
                         }
-                    };
-                    for statement in statements {
-                        if let Err(e) = db.execute_statement(&statement, |row| {
-                            println!("{row:?}");
-                            Ok(())
-                        }) {
-                            println!("{:?}", e.context("Error running given command"));
-                            break;
-                        }
                     }
+                    statement.push_str(&line);
+                    statement.push('\n');
+                    // Statements are only run once they're finished with a `;`, so they can be
+                    // typed or pasted over several lines.
+                    if is_complete(&statement) {
+                        let sql = std::mem::take(&mut statement);
+                        self.run_prompt_sql(&sql);
+                    }
+                }
+                Err(rustyline::error::ReadlineError::Eof) => {
+                    if !statement.is_empty() {
+                        self.run_prompt_sql(&statement);
+                    }
+                    break;
                 }
+                Err(rustyline::error::ReadlineError::Interrupted) => {
+                    // Interrupting a statement partway through throws it away.
+                    if statement.is_empty() {
+                        println!("^C");
+                        break;
+                    }
+                    statement.clear();
+                }
+                Err(e) => return Err(e).context("Failed to read command from CLI"),
             }
-            Err(rustyline::error::ReadlineError::Eof) => break,
-            Err(rustyline::error::ReadlineError::Interrupted) => {
-                println!("^C");
-                break;
+        }
+        // Errors at the prompt have already been seen, so they don't fail the whole session.
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Run the SQL typed at the prompt, printing any error.
+    fn run_prompt_sql(&mut self, sql: &str) {
+        if let Err(e) = with_db!(&mut self.db, db => run_sql(db, sql, &self.format, self.output)) {
+            self.report(&e);
+        }
+    }
+
+    /// Print `error`, and remember that a command failed.
+    fn report(&mut self, error: &anyhow::Error) {
+        eprintln!("{error:?}");
+        self.failed = true;
+    }
+
+    /// Print the problems found with the schema of the database.
+    fn print_warnings(&self) {
+        for warning in with_db!(&self.db, db => db.schema_warnings()) {
+            println!("Warning: {warning}");
+        }
+    }
+
+    /// Run one of the shell's commands, without the `.` it starts with.
+    fn run_dot_command(&mut self, command: &str) {
+        let (command, args) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(command, args)| (command, args.trim()));
+        let result = match command {
+            "debug" => display_database(&self.file_path).with_context(|| {
+                format!(
+                    "Error displaying database at {}",
+                    std::path::Path::new(&self.file_path).display()
+                )
+            }),
+            "tables" => {
+                with_db!(&self.db, db => display_tables(db, args));
+                Ok(())
+            }
+            "indexes" => {
+                with_db!(&self.db, db => display_indexes(db, args));
+                Ok(())
+            }
+            "schema" => {
+                with_db!(&self.db, db => display_schema(db, args));
+                Ok(())
+            }
+            "mode" => set_mode(&mut self.format, args),
+            "headers" => set_headers(&mut self.format, args),
+            "nullvalue" => set_null_value(&mut self.format, args),
+            "separator" => set_separator(&mut self.format, args),
+            "width" => set_widths(&mut self.format, args),
+            "timer" => set_timer(&mut self.output.timer, args),
+            "eqp" => parse_switch(args)
+                .map(|eqp| self.output.eqp = eqp)
+                .context("Usage: .eqp on|off"),
+            "backup" => with_db!(&mut self.db, db => backup(db, args)),
+            "dump" => {
+                let pattern = Some(args).filter(|pattern| !pattern.is_empty());
+                with_db!(&mut self.db, db => db.dump(pattern, &mut std::io::stdout().lock()))
+                    .context("Error dumping database")
+            }
+            "recover" => with_db!(&mut self.db, db => db.recover(&mut std::io::stdout().lock()))
+                .context("Error recovering database"),
+            "read" => self.read_script(args),
+            "open" => self.open(args),
+            "heatmap" => {
+                with_db!(&mut self.db, db => display_heatmap(db));
+                Ok(())
+            }
+            "stats" if args.is_empty() => {
+                with_db!(&self.db, db => display_stats(db));
+                Ok(())
+            }
+            "stats" => parse_switch(args)
+                .map(|stats| self.output.stats = stats)
+                .context("Usage: .stats [on|off]"),
+            "find" => with_db!(&mut self.db, db => find_tables(db, args))
+                .context("Error searching for tables"),
+            _ => Err(anyhow::anyhow!("Unrecognized debug command: {command:?}")),
+        };
+        if let Err(e) = result {
+            self.report(&e);
+        }
+    }
+
+    /// Close the database and open the one given in `args`, which may start with `--readonly`.
+    ///
+    /// The database is left open if the new one can't be opened.
+    fn open(&mut self, args: &str) -> anyhow::Result<()> {
+        let read_only_path = args
+            .strip_prefix("--readonly")
+            .filter(|path| path.is_empty() || path.starts_with(char::is_whitespace));
+        let (read_only, path) = match read_only_path {
+            Some(path) => (true, path.trim()),
+            None => (false, args),
+        };
+        anyhow::ensure!(!path.is_empty(), "Usage: .open [--readonly] FILE");
+        self.db = ShellDatabase::open(OsStr::new(path), read_only)
+            .with_context(|| format!("Error opening {path}"))?;
+        self.file_path = OsString::from(path);
+        self.print_warnings();
+        Ok(())
+    }
+
+    /// Run the statements and commands in the script at `path`.
+    fn read_script(&mut self, path: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(!path.is_empty(), "Usage: .read FILE");
+        anyhow::ensure!(
+            self.read_depth < MAX_READ_DEPTH,
+            "Scripts can only be nested {MAX_READ_DEPTH} deep"
+        );
+        let script =
+            std::fs::read_to_string(path).with_context(|| format!("Error reading {path}"))?;
+        self.read_depth += 1;
+        self.run_script(&script, Some(path));
+        self.read_depth -= 1;
+        Ok(())
+    }
+
+    /// Run the statements and commands in `script`, which was read from `source` if it's a file,
+    /// printing any errors along with the line they're on.
+    ///
+    /// As in the `sqlite3` shell, the rest of the script still runs after an error.
+    fn run_script(&mut self, script: &str, source: Option<&str>) {
+        for ScriptLine { line, part } in split_script(script) {
+            match part {
+                ScriptPart::DotCommand(command) => self.run_dot_command(command),
+                ScriptPart::Sql(sql) => {
+                    let result =
+                        with_db!(&mut self.db, db => run_sql(db, sql, &self.format, self.output));
+                    if let Err(e) = result {
+                        let context = source.map_or_else(
+                            || format!("Error near line {line}"),
+                            |source| format!("Error near line {line} of {source}"),
+                        );
+                        self.report(&e.context(context));
+                    }
+                }
             }
-            Err(e) => return Err(e).context("Failed to read command from CLI"),
         }
     }
-    Ok(())
 }
@@ -0,0 +1,255 @@
+//! Reading databases from async code, without blocking the runtime
+//!
+//! Reading pages is blocking file IO, so like [`tokio::fs`], each call here is run on tokio's
+//! thread pool for blocking work. The database or pager is held behind a lock while a call runs,
+//! so calls made at the same time take turns rather than interleaving.
+
+use std::{
+    fs,
+    io::{Read, Seek},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use sqlparser::ast::Statement;
+
+use crate::{
+    page::Page,
+    pager::{journal_path_for, Pager, Storage},
+    record::OwnedValue,
+    Database, QueryStats,
+};
+
+/// Run `f` on `value` on the thread pool for blocking work, once the lock on it is free.
+async fn run_locked<Value: Send + 'static, T: Send + 'static>(
+    value: &Arc<Mutex<Value>>,
+    f: impl FnOnce(&mut Value) -> crate::Result<T> + Send + 'static,
+) -> crate::Result<T> {
+    let value = Arc::clone(value);
+    tokio::task::spawn_blocking(move || {
+        // A panic partway through a statement could leave changes half-made.
+        let mut value = value
+            .lock()
+            .map_err(|e| crate::Error::Other(format!("A previous call panicked: {e}")))?;
+        f(&mut value)
+    })
+    .await
+    .context("Failed to run blocking task")?
+}
+
+/// Open the file at `path` for reading and writing, or only reading if it can't be written.
+async fn open_file(path: &Path) -> Result<fs::File> {
+    let file = match tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .await
+    {
+        Ok(file) => file,
+        // Read-only files can still be queried
+        Err(_) => tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?,
+    };
+    Ok(file.into_std().await)
+}
+
+/// A database which can be queried from async code.
+///
+/// This is a handle to the database, so clones of it query the same one.
+pub struct AsyncDatabase<File = fs::File> {
+    /// The database, which is moved onto a blocking thread for each call
+    db: Arc<Mutex<Database<File>>>,
+}
+
+impl AsyncDatabase {
+    /// Open the database at `path`, with its writes protected by a rollback journal next to it.
+    pub async fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = open_file(&path).await?;
+        let db = tokio::task::spawn_blocking(move || {
+            Database::with_journal(file, journal_path_for(&path))
+        })
+        .await
+        .context("Failed to run blocking task")??;
+        Ok(Self::new(db))
+    }
+}
+
+impl<File: Storage + Send + 'static> AsyncDatabase<File> {
+    /// Wrap a database to be queried from async code.
+    #[must_use]
+    pub fn new(db: Database<File>) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// Execute the given statement, returning the values it returned.
+    ///
+    /// See [`Database::execute_statement`].
+    pub async fn execute_statement(
+        &self,
+        statement: Statement,
+    ) -> crate::Result<(Vec<Vec<OwnedValue>>, QueryStats)> {
+        run_locked(&self.db, move |db| {
+            let mut rows = Vec::new();
+            let stats = db.execute_statement(&statement, |row| {
+                rows.push(row);
+                Ok(())
+            })?;
+            Ok((rows, stats))
+        })
+        .await
+    }
+
+    /// Run `f` on the database, for anything this doesn't have an async version of.
+    pub async fn with_database<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Database<File>) -> crate::Result<T> + Send + 'static,
+    ) -> crate::Result<T> {
+        run_locked(&self.db, f).await
+    }
+}
+
+impl<File> Clone for AsyncDatabase<File> {
+    fn clone(&self) -> Self {
+        Self {
+            db: Arc::clone(&self.db),
+        }
+    }
+}
+
+/// A pager which can be read from async code.
+///
+/// This is a handle to the pager, so clones of it read through the same one.
+pub struct AsyncPager<File = fs::File> {
+    /// The pager, which is moved onto a blocking thread for each call
+    pager: Arc<Mutex<Pager<File>>>,
+}
+
+impl AsyncPager {
+    /// Open a pager over the database file at `path`.
+    pub async fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let file = open_file(path.as_ref()).await?;
+        let pager = tokio::task::spawn_blocking(move || Pager::new(file))
+            .await
+            .context("Failed to run blocking task")??;
+        Ok(Self::new(pager))
+    }
+}
+
+impl<File: Read + Seek + Send + 'static> AsyncPager<File> {
+    /// Wrap a pager to be read from async code.
+    #[must_use]
+    pub fn new(pager: Pager<File>) -> Self {
+        Self {
+            pager: Arc::new(Mutex::new(pager)),
+        }
+    }
+
+    /// Read the given page, and pass it to `f`.
+    ///
+    /// The page borrows the pager, so it's only available to `f`, which runs on the thread the
+    /// page was read on. See [`Pager::read_page`].
+    pub async fn read_page<T: Send + 'static>(
+        &self,
+        page_idx: usize,
+        f: impl FnOnce(Page<'_>) -> crate::Result<T> + Send + 'static,
+    ) -> crate::Result<T> {
+        run_locked(&self.pager, move |pager| f(pager.read_page(page_idx)?)).await
+    }
+}
+
+impl<File> Clone for AsyncPager<File> {
+    fn clone(&self) -> Self {
+        Self {
+            pager: Arc::clone(&self.pager),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use sqlparser::{dialect::SQLiteDialect, parser::Parser};
+
+    use super::*;
+
+    /// Run `future` to completion on a new runtime.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Failed to start runtime")
+            .block_on(future)
+    }
+
+    fn parse(sql: &str) -> Statement {
+        Parser::parse_sql(&SQLiteDialect {}, sql)
+            .expect("Failed to parse statement")
+            .remove(0)
+    }
+
+    #[test]
+    fn test_async_database() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-async-{}.sqlite", std::process::id()));
+        fs::copy("test-data/many-tables.sqlite", &path).expect("Failed to copy database");
+        block_on(async {
+            let db = AsyncDatabase::open(&path)
+                .await
+                .expect("Failed to open database");
+            let tasks = (0..4)
+                .map(|i| {
+                    let db = db.clone();
+                    tokio::spawn(async move {
+                        db.execute_statement(parse(&format!("INSERT INTO t1 VALUES ({i}, 'row')")))
+                            .await
+                    })
+                })
+                .collect::<Vec<_>>();
+            for task in tasks {
+                task.await.unwrap().expect("Failed to insert");
+            }
+            let (rows, stats) = db
+                .execute_statement(parse("SELECT * FROM t1"))
+                .await
+                .expect("Failed to query");
+            // The tasks may have run in any order.
+            let mut ids = rows
+                .iter()
+                .map(|row| row[0].get::<i64>().unwrap())
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            assert_eq!(ids, [0, 1, 2, 3]);
+            assert_eq!(stats.rows_returned, 4);
+            assert!(db
+                .execute_statement(parse("SELECT * FROM missing"))
+                .await
+                .is_err());
+            let page_count = db.with_database(|db| Ok(db.page_count())).await.unwrap();
+            assert!(page_count > 1, "The database should have tables in it");
+        });
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_async_pager() {
+        let mut pager = Pager::new(fs::File::open("test-data/minimal-test.sqlite").unwrap())
+            .expect("Failed to parse test database");
+        let expected = pager.read_page(2).unwrap().as_bytes().to_vec();
+        block_on(async {
+            let pager = AsyncPager::open("test-data/minimal-test.sqlite")
+                .await
+                .expect("Failed to open database");
+            let page = pager
+                .read_page(2, |page| Ok(page.as_bytes().to_vec()))
+                .await
+                .unwrap();
+            assert_eq!(page, expected);
+            assert!(pager.read_page(100, |_| Ok(())).await.is_err());
+        });
+    }
+}
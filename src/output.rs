@@ -0,0 +1,489 @@
+//! Formatting the rows returned by statements, in the shell's output modes
+//!
+//! The modes are the same as those of the `sqlite3` shell's `.mode` command, and lay out their
+//! output in the same way.
+
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::{
+    json::Json,
+    record::{format_real, OwnedValue, Value},
+};
+
+/// How rows are laid out when they're written.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The values in each row separated by `|`
+    #[default]
+    List,
+    /// Comma-separated values, quoted where needed
+    Csv,
+    /// An array with an object for each row, keyed by column name
+    Json,
+    /// Each value on its own line, after its column's name
+    Line,
+    /// An aligned table with borders, with the column names as headers
+    Table,
+}
+impl OutputMode {
+    /// All of the modes, in the order they're listed in errors.
+    pub const ALL: [Self; 5] = [Self::List, Self::Csv, Self::Json, Self::Line, Self::Table];
+
+    /// Get the mode with the name `.mode` uses for it, ignoring case.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// Get the name `.mode` uses for this mode.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::List => "list",
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Line => "line",
+            Self::Table => "table",
+        }
+    }
+}
+
+/// The settings for writing out rows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// The layout of the rows
+    pub mode: OutputMode,
+    /// Whether to write the column names before the rows in the `list` and `csv` modes.
+    ///
+    /// The other modes always include the column names.
+    pub headers: bool,
+    /// The text written for `NULL`, in every mode but `json`
+    pub null_value: String,
+    /// The text written between the values in a row, in the `list` mode
+    pub column_separator: String,
+    /// The text written after each row, in the `list` mode
+    pub row_separator: String,
+    /// The widths of the columns in the `table` mode, in order.
+    ///
+    /// Values wider than their column are wrapped onto more lines, and values in columns with a
+    /// negative width are aligned to the right. Columns with a width of zero, or past the end of
+    /// this, are as wide as their widest value.
+    pub widths: Vec<isize>,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self {
+            mode: OutputMode::default(),
+            headers: false,
+            null_value: String::new(),
+            column_separator: "|".to_owned(),
+            row_separator: "\n".to_owned(),
+            widths: Vec::new(),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Write `rows`, whose columns are named `columns`, to `out`.
+    ///
+    /// Columns without a name are named by their position, starting from `column1`. Nothing is
+    /// written if there are no rows.
+    pub fn write(
+        &self,
+        columns: &[String],
+        rows: &[Vec<OwnedValue>],
+        out: &mut impl Write,
+    ) -> crate::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let width = rows.iter().map(Vec::len).max().unwrap_or_default();
+        let columns = (0..width)
+            .map(|idx| {
+                columns
+                    .get(idx)
+                    .cloned()
+                    .unwrap_or_else(|| format!("column{}", idx + 1))
+            })
+            .collect::<Vec<_>>();
+        match self.mode {
+            OutputMode::List => {
+                let (column_separator, row_separator) =
+                    (&self.column_separator, &self.row_separator);
+                if self.headers {
+                    write!(out, "{}{row_separator}", columns.join(column_separator))?;
+                }
+                for row in rows {
+                    let row = row.iter().map(|value| self.text(value)).collect::<Vec<_>>();
+                    write!(out, "{}{row_separator}", row.join(column_separator))?;
+                }
+            }
+            OutputMode::Csv => {
+                // CSV lines end with CRLF, as in RFC 4180.
+                if self.headers {
+                    let header = columns
+                        .iter()
+                        .map(|name| csv_field(name))
+                        .collect::<Vec<_>>();
+                    write!(out, "{}\r\n", header.join(","))?;
+                }
+                for row in rows {
+                    let row = row
+                        .iter()
+                        .map(|value| self.csv_value(value))
+                        .collect::<Vec<_>>();
+                    write!(out, "{}\r\n", row.join(","))?;
+                }
+            }
+            OutputMode::Json => {
+                for (idx, row) in rows.iter().enumerate() {
+                    let object = Json::Object(
+                        columns
+                            .iter()
+                            .cloned()
+                            .zip(row.iter().map(json_value))
+                            .collect(),
+                    );
+                    let (open, close) = match (idx == 0, idx + 1 == rows.len()) {
+                        (true, true) => ("[", "]"),
+                        (true, false) => ("[", ","),
+                        (false, true) => ("", "]"),
+                        (false, false) => ("", ","),
+                    };
+                    writeln!(out, "{open}{object}{close}")?;
+                }
+            }
+            OutputMode::Line => {
+                let name_width = columns
+                    .iter()
+                    .map(|name| name.chars().count())
+                    .max()
+                    .unwrap_or_default();
+                for (idx, row) in rows.iter().enumerate() {
+                    if idx > 0 {
+                        writeln!(out)?;
+                    }
+                    for (name, value) in columns.iter().zip(row) {
+                        writeln!(out, "{name:>name_width$} = {}", self.text(value))?;
+                    }
+                }
+            }
+            OutputMode::Table => self.write_table(&columns, rows, out)?,
+        }
+        Ok(())
+    }
+
+    /// Get the text written for `value`, where blobs are written as their bytes, as if they were
+    /// text.
+    fn text(&self, value: &OwnedValue) -> String {
+        match value {
+            Value::Null | Value::SQLiteReserved => self.null_value.clone(),
+            _ => display_text(value),
+        }
+    }
+
+    /// Get the CSV field for `value`, where numbers and `NULL` are never quoted.
+    fn csv_value(&self, value: &OwnedValue) -> String {
+        match value {
+            Value::Blob(_) | Value::String(_) => csv_field(&display_text(value)),
+            _ => self.text(value),
+        }
+    }
+
+    /// Write `rows` as a table with borders, with a header row holding `columns`.
+    ///
+    /// Values with several lines of text take up several lines of the table.
+    fn write_table(
+        &self,
+        columns: &[String],
+        rows: &[Vec<OwnedValue>],
+        out: &mut impl Write,
+    ) -> Result<()> {
+        // The width set for each column, and whether it's aligned to the right
+        let fixed = (0..columns.len())
+            .map(|idx| {
+                let width = self.widths.get(idx).copied().unwrap_or_default();
+                (
+                    Some(width.unsigned_abs()).filter(|&width| width > 0),
+                    width < 0,
+                )
+            })
+            .collect::<Vec<_>>();
+        let cells = rows
+            .iter()
+            .map(|row| {
+                fixed
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &(width, _))| {
+                        let text = row
+                            .get(idx)
+                            .map_or_else(String::new, |value| self.text(value));
+                        wrap_lines(&text, width)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let widths = columns
+            .iter()
+            .zip(&fixed)
+            .enumerate()
+            .map(|(idx, (name, &(width, _)))| {
+                width.unwrap_or_else(|| {
+                    cells
+                        .iter()
+                        .flat_map(|row| &row[idx])
+                        .chain([name])
+                        .map(|line| line.chars().count())
+                        .max()
+                        .unwrap_or_default()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let border = widths
+            .iter()
+            .map(|width| "-".repeat(width + 2))
+            .collect::<Vec<_>>();
+        let border = format!("+{}+", border.join("+"));
+        writeln!(out, "{border}")?;
+        let header = columns
+            .iter()
+            .zip(&widths)
+            .map(|(name, &width)| {
+                // Headers are centered, with any odd space on the right, and cut short if their
+                // column is narrower.
+                let name = name.chars().take(width).collect::<String>();
+                let padding = width - name.chars().count();
+                format!(
+                    " {}{name}{} ",
+                    " ".repeat(padding / 2),
+                    " ".repeat(padding - padding / 2)
+                )
+            })
+            .collect::<Vec<_>>();
+        writeln!(out, "|{}|", header.join("|"))?;
+        writeln!(out, "{border}")?;
+        for row in &cells {
+            let height = row.iter().map(Vec::len).max().unwrap_or_default().max(1);
+            for line_idx in 0..height {
+                let line = row
+                    .iter()
+                    .zip(&widths)
+                    .zip(&fixed)
+                    .map(|((cell, &width), &(_, right))| {
+                        let text = cell.get(line_idx).map_or("", String::as_str);
+                        let padding = " ".repeat(width - text.chars().count());
+                        if right {
+                            format!(" {padding}{text} ")
+                        } else {
+                            format!(" {text}{padding} ")
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(out, "|{}|", line.join("|"))?;
+            }
+        }
+        writeln!(out, "{border}")?;
+        Ok(())
+    }
+}
+
+/// Split `text` into its lines, wrapping any longer than `width` characters onto more lines, if
+/// there's a width.
+fn wrap_lines(text: &str, width: Option<usize>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        match width {
+            Some(width) if line.chars().count() > width => {
+                let chars = line.chars().collect::<Vec<_>>();
+                lines.extend(chars.chunks(width).map(|chunk| chunk.iter().collect()));
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    lines
+}
+
+/// Get the text the shell shows for `value`, where `NULL` is shown as nothing.
+///
+/// Blobs are shown as their bytes, as if they were text.
+fn display_text(value: &OwnedValue) -> String {
+    match value {
+        Value::Null | Value::SQLiteReserved => String::new(),
+        Value::Int(n, _) => n.to_string(),
+        Value::F64(n) => format_real(*n),
+        Value::Blob(bytes) | Value::String(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Quote `text` for a CSV field if it needs it.
+///
+/// As in the `sqlite3` shell, text is quoted if it's empty or has anything but printable ASCII
+/// other than spaces, commas and quotes in it, so that it reads back the same.
+fn csv_field(text: &str) -> String {
+    if !text.is_empty()
+        && text
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !b",\"".contains(&byte))
+    {
+        text.to_owned()
+    } else {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    }
+}
+
+/// Convert `value` to JSON, where blobs become strings of their bytes.
+fn json_value(value: &OwnedValue) -> Json {
+    match value {
+        Value::Null | Value::SQLiteReserved => Json::Null,
+        Value::Int(n, _) => Json::Number(n.to_string()),
+        // JSON has no infinity, but a number too large for a float is read back as one.
+        Value::F64(n) if n.is_infinite() => {
+            Json::Number(if *n > 0.0 { "9e999" } else { "-9e999" }.to_owned())
+        }
+        Value::F64(n) => Json::Number(format_real(*n)),
+        Value::Blob(_) | Value::String(_) => Json::String(display_text(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputFormat, OutputMode};
+    use crate::record::{OwnedValue, Value};
+
+    /// Write some sample rows in `mode`.
+    fn write(mode: OutputMode, headers: bool) -> String {
+        let text = |text: &str| Value::String(text.as_bytes().into());
+        let columns = ["id", "name", "n", "f"].map(str::to_owned);
+        let rows: Vec<Vec<OwnedValue>> = vec![
+            vec![
+                Value::int(1),
+                text("al,\"ice"),
+                Value::Null,
+                Value::F64(2.5),
+            ],
+            vec![Value::int(22), text("bob"), text("x"), Value::int(-1)],
+        ];
+        let mut out = Vec::new();
+        OutputFormat {
+            mode,
+            headers,
+            ..OutputFormat::default()
+        }
+        .write(&columns, &rows, &mut out)
+        .expect("Failed to write rows");
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_output_modes() {
+        assert_eq!(
+            write(OutputMode::List, false),
+            "1|al,\"ice||2.5\n22|bob|x|-1\n"
+        );
+        assert_eq!(
+            write(OutputMode::List, true),
+            "id|name|n|f\n1|al,\"ice||2.5\n22|bob|x|-1\n"
+        );
+        assert_eq!(
+            write(OutputMode::Csv, true),
+            "id,name,n,f\r\n1,\"al,\"\"ice\",,2.5\r\n22,bob,x,-1\r\n"
+        );
+        assert_eq!(
+            write(OutputMode::Json, false),
+            "[{\"id\":1,\"name\":\"al,\\\"ice\",\"n\":null,\"f\":2.5},\n\
+             {\"id\":22,\"name\":\"bob\",\"n\":\"x\",\"f\":-1}]\n"
+        );
+        assert_eq!(
+            write(OutputMode::Line, false),
+            "  id = 1\nname = al,\"ice\n   n = \n   f = 2.5\n\n  id = 22\nname = bob\n   n = x\n   f = -1\n"
+        );
+        assert_eq!(
+            write(OutputMode::Table, false),
+            "\
++----+---------+---+-----+
+| id |  name   | n |  f  |
++----+---------+---+-----+
+| 1  | al,\"ice |   | 2.5 |
+| 22 | bob     | x | -1  |
++----+---------+---+-----+
+"
+        );
+    }
+
+    #[test]
+    fn test_table_multiline() {
+        let rows = vec![vec![
+            Value::String(b"a\nb".as_slice().into()),
+            Value::String("é".as_bytes().into()),
+        ]];
+        let mut out = Vec::new();
+        OutputFormat {
+            mode: OutputMode::Table,
+            ..OutputFormat::default()
+        }
+        .write(&["x".to_owned()], &rows, &mut out)
+        .expect("Failed to write rows");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
++---+---------+
+| x | column2 |
++---+---------+
+| a | é       |
+| b |         |
++---+---------+
+"
+        );
+        assert_eq!(OutputMode::from_name("TABLE"), Some(OutputMode::Table));
+        assert_eq!(OutputMode::from_name("box"), None);
+    }
+
+    #[test]
+    fn test_format_settings() {
+        let rows = vec![
+            vec![Value::String(b"abcdefg".as_slice().into()), Value::Null],
+            vec![Value::Null, Value::int(42)],
+        ];
+        let write = |format: &OutputFormat| {
+            let mut out = Vec::new();
+            format
+                .write(&["letters".to_owned(), "n".to_owned()], &rows, &mut out)
+                .expect("Failed to write rows");
+            String::from_utf8(out).unwrap()
+        };
+        let mut format = OutputFormat {
+            headers: true,
+            null_value: "NULL".to_owned(),
+            column_separator: "\t".to_owned(),
+            row_separator: ";\n".to_owned(),
+            ..OutputFormat::default()
+        };
+        assert_eq!(write(&format), "letters\tn;\nabcdefg\tNULL;\nNULL\t42;\n");
+        format.mode = OutputMode::Csv;
+        assert_eq!(write(&format), "letters,n\r\nabcdefg,NULL\r\nNULL,42\r\n");
+
+        format.mode = OutputMode::Table;
+        format.widths = vec![3, -4];
+        assert_eq!(
+            write(&format),
+            "\
++-----+------+
+| let |  n   |
++-----+------+
+| abc | NULL |
+| def |      |
+| g   |      |
+| NUL |   42 |
+| L   |      |
++-----+------+
+"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 //! Implementation of the various page types
 
+pub mod btree_index_internal;
+pub mod btree_index_leaf;
 pub mod btree_table_internal;
 pub mod btree_table_leaf;
 
@@ -14,17 +16,38 @@ use crate::pager::DATABASE_HEADER_SIZE;
 /// This can contain any type of page inside.
 pub struct Page<'a> {
     /// The byte buffer it points at
-    contents: &'a mut [u8],
+    contents: &'a [u8],
+    /// The offset of the btree page header, which comes after the database header on page 1.
+    header_offset: usize,
 }
 
 impl<'a> Page<'a> {
-    pub(crate) fn new(contents: &'a mut [u8]) -> Result<Self> {
-        let maybe_self = Self { contents };
+    /// Wrap the contents of the page with the given (1-based) index.
+    pub(crate) fn new(contents: &'a [u8], page_idx: usize) -> Result<Self> {
+        let maybe_self = Self {
+            contents,
+            header_offset: btree_header_offset(page_idx),
+        };
         // Ensure that it parses correctly
         maybe_self.parse_checked()?;
         Ok(maybe_self)
     }
 
+    /// Wrap the contents of the page with the given (1-based) index, which were already checked
+    /// by [`Self::new`].
+    pub(crate) fn new_unchecked(contents: &'a [u8], page_idx: usize) -> Self {
+        Self {
+            contents,
+            header_offset: btree_header_offset(page_idx),
+        }
+    }
+
+    /// Get the raw bytes of this page.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.contents
+    }
+
     #[must_use]
     pub fn parse(&self) -> ParsedPage {
         // We ensure the parse succeeds in the type invariants
@@ -37,14 +60,24 @@ impl<'a> Page<'a> {
     /// to ensure that it parses correctly.
     fn parse_checked(&self) -> Result<ParsedPage> {
         // TODO Don't assume all pages are btree pages
-        let (page_type, ..) = BTreePageHeader::parse(self.contents)?;
+        let (page_type, ..) = BTreePageHeader::parse(self.contents, self.header_offset)?;
         match page_type {
-            PageType::BTreeTableLeaf => btree_table_leaf::BTreeTableLeafPage::new(self.contents)
-                .map(ParsedPage::BTreeTableLeaf),
+            PageType::BTreeTableLeaf => {
+                btree_table_leaf::BTreeTableLeafPage::new(self.contents, self.header_offset)
+                    .map(ParsedPage::BTreeTableLeaf)
+            }
             PageType::BTreeTableInternal => {
-                btree_table_internal::BTreeTableInternalPage::new(self.contents)
+                btree_table_internal::BTreeTableInternalPage::new(self.contents, self.header_offset)
                     .map(ParsedPage::BTreeTableInternal)
             }
+            PageType::BTreeIndexLeaf => {
+                btree_index_leaf::BTreeIndexLeafPage::new(self.contents, self.header_offset)
+                    .map(ParsedPage::BTreeIndexLeaf)
+            }
+            PageType::BTreeIndexInternal => {
+                btree_index_internal::BTreeIndexInternalPage::new(self.contents, self.header_offset)
+                    .map(ParsedPage::BTreeIndexInternal)
+            }
         }
     }
 }
@@ -55,6 +88,10 @@ pub enum ParsedPage<'a> {
     BTreeTableLeaf(btree_table_leaf::BTreeTableLeafPage<'a>),
     /// A leaf in the table btree.
     BTreeTableInternal(btree_table_internal::BTreeTableInternalPage<'a>),
+    /// A leaf in an index btree.
+    BTreeIndexLeaf(btree_index_leaf::BTreeIndexLeafPage<'a>),
+    /// An internal page in an index btree.
+    BTreeIndexInternal(btree_index_internal::BTreeIndexInternalPage<'a>),
 }
 
 /// The page types
@@ -64,11 +101,17 @@ pub enum PageType {
     BTreeTableLeaf,
     /// An internal page in the table btree.
     BTreeTableInternal,
+    /// A leaf in an index btree.
+    BTreeIndexLeaf,
+    /// An internal page in an index btree.
+    BTreeIndexInternal,
 }
 impl PageType {
     fn from_header_byte(byte: u8) -> Result<Self> {
         Ok(match byte {
             0x05 => Self::BTreeTableInternal,
+            0x02 => Self::BTreeIndexInternal,
+            0x0a => Self::BTreeIndexLeaf,
             0x0d => Self::BTreeTableLeaf,
             _ => anyhow::bail!("Unrecognized header byte: {byte}"),
         })
@@ -77,6 +120,40 @@ impl PageType {
 
 const BTREE_PAGE_HEADER_SIZE: usize = 8;
 
+/// The offset at which the btree page header starts in the page with the given index.
+///
+/// The first page starts with the database header, so its btree page header comes after that.
+pub(crate) fn btree_header_offset(page_idx: usize) -> usize {
+    if page_idx == 1 {
+        DATABASE_HEADER_SIZE
+    } else {
+        0
+    }
+}
+
+/// The number of bytes of a payload of `payload_size` bytes which are stored in its btree page,
+/// with the rest going in overflow pages.
+///
+/// Payloads of up to `max_local` bytes are stored entirely in the page. Larger ones keep as much
+/// in the page as leaves their last overflow page full, if that's at most `max_local` bytes, and
+/// otherwise the minimum amount.
+pub(crate) fn local_payload_size(
+    payload_size: usize,
+    usable_size: usize,
+    max_local: usize,
+) -> usize {
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let size = min_local + (payload_size - min_local) % (usable_size - 4);
+    if size <= max_local {
+        size
+    } else {
+        min_local
+    }
+}
+
 /// The header at the start of every btree page
 #[derive(Debug)]
 struct BTreePageHeader {
@@ -85,33 +162,24 @@ struct BTreePageHeader {
     /// The number of cells in this page
     cell_count: u16,
     /// The offset at which content starts
+    ///
+    /// This is stored as 0 when it would be 65536, which can only happen for an empty page with a
+    /// page size of 65536, so we convert it on parsing.
     cell_content_offset: u32,
     /// The number of fragmented free bytes in the content area
     _fragmented_bytes_count: u8,
 }
 impl BTreePageHeader {
-    fn parse(buffer: &[u8]) -> Result<(PageType, Self, usize)> {
-        let (parse_from_arr, total_len): (&[u8; BTREE_PAGE_HEADER_SIZE], usize) =
-            if buffer[0] == b'S' {
-                // The first page contains the database header, so we cut that off
-                (
-                    buffer
-                        .get(DATABASE_HEADER_SIZE..DATABASE_HEADER_SIZE + BTREE_PAGE_HEADER_SIZE)
-                        .context("Unexpected end of page")?
-                        .try_into()
-                        .unwrap(),
-                    DATABASE_HEADER_SIZE + BTREE_PAGE_HEADER_SIZE,
-                )
-            } else {
-                (
-                    buffer
-                        .get(..BTREE_PAGE_HEADER_SIZE)
-                        .context("Unexpected end of page")?
-                        .try_into()
-                        .unwrap(),
-                    BTREE_PAGE_HEADER_SIZE,
-                )
-            };
+    /// Parse the header starting at `offset` in `buffer`.
+    ///
+    /// Returns the page type, the header, and the offset just past the header.
+    fn parse(buffer: &[u8], offset: usize) -> Result<(PageType, Self, usize)> {
+        let total_len = offset + BTREE_PAGE_HEADER_SIZE;
+        let parse_from_arr: &[u8; BTREE_PAGE_HEADER_SIZE] = buffer
+            .get(offset..total_len)
+            .context("Unexpected end of page")?
+            .try_into()
+            .unwrap();
         let page_type = PageType::from_header_byte(parse_from_arr[0])?;
         let first_free_block =
             NonZeroU16::new(u16::from_be_bytes([parse_from_arr[1], parse_from_arr[2]]));
@@ -134,3 +202,22 @@ impl BTreePageHeader {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_payload_size() {
+        // The limits for index pages and table leaves
+        let index_max = (4096 - 12) * 64 / 255 - 23;
+        let table_max = 4096 - 35;
+        assert_eq!(local_payload_size(1002, 4096, index_max), 1002);
+        // Past the maximum, the overflow pages are filled exactly if enough stays in the page.
+        assert_eq!(local_payload_size(5000, 4096, index_max), 908);
+        assert_eq!(local_payload_size(1003, 4096, index_max), 489);
+        assert_eq!(local_payload_size(4061, 4096, table_max), 4061);
+        assert_eq!(local_payload_size(4062, 4096, table_max), 489);
+        assert_eq!(local_payload_size(5000, 4096, table_max), 908);
+    }
+}
@@ -0,0 +1,171 @@
+//! Implementation for btree index internal pages
+
+use anyhow::{Context, Result};
+
+use super::btree_index_leaf;
+use crate::page::PageType;
+
+/// A parsed internal page in an index's btree
+pub struct BTreeIndexInternalPage<'a> {
+    /// The header for the page
+    header: super::BTreePageHeader,
+    /// The page number of the subtree root containing entries greater than every cell's.
+    rightmost_pointer: u32,
+    /// The pointers to cells
+    ///
+    /// Per SQLite format, you need to subtract the cell content offset in [`Self::header`] first
+    /// and then you can index into [`Self::cell_contents`].
+    cell_pointers: &'a [u8],
+    /// The contents of the cells
+    cell_contents: &'a [u8],
+    /// The number of usable bytes in each page, which determines how much of a payload is stored
+    /// in the page itself
+    usable_size: usize,
+}
+
+impl<'a> BTreeIndexInternalPage<'a> {
+    pub(super) fn new(contents: &'a [u8], header_offset: usize) -> Result<Self> {
+        let (page_type, header, header_len) =
+            super::BTreePageHeader::parse(contents, header_offset)?;
+        anyhow::ensure!(page_type == PageType::BTreeIndexInternal, "Wrong page type");
+        let rightmost_pointer = u32::from_be_bytes(
+            contents
+                .get(header_len..header_len + 4)
+                .context("Unexpected end of page in rightmost pointer")?
+                .try_into()
+                .unwrap(),
+        );
+        let body = &contents[header_len + 4..];
+        let cell_pointers = body
+            .get(..header.cell_count as usize * 2)
+            .context("Unexpected end of page in cell pointer array")?;
+        let cell_contents = contents
+            .get(header.cell_content_offset as usize..)
+            .context("Unexpected end of page in cell contents")?;
+        Ok(Self {
+            header,
+            rightmost_pointer,
+            cell_pointers,
+            cell_contents,
+            // The page is only given the usable part of its contents.
+            usable_size: contents.len(),
+        })
+    }
+
+    /// Get the index of the rightmost (greatest) child page.
+    #[must_use]
+    pub fn rightmost_child_idx(&self) -> u32 {
+        self.rightmost_pointer
+    }
+
+    /// Get the number of cells in this page
+    #[must_use]
+    pub fn num_cells(&self) -> usize {
+        self.header.cell_count as usize
+    }
+
+    pub fn cells(&'a self) -> impl Iterator<Item = Cell<'a>> + 'a {
+        CellIter { page: self, idx: 0 }
+    }
+}
+
+/// An entry in an index, along with the page holding the entries less than it.
+pub struct Cell<'a> {
+    pub left_child_page: u32,
+    /// The entry, which is stored the same way as in a leaf
+    pub entry: btree_index_leaf::Cell<'a>,
+}
+impl<'a> Cell<'a> {
+    fn parse(contents: &'a [u8], usable_size: usize) -> Result<Self> {
+        let (left_child_page, entry) = contents.split_first_chunk().context("cell too short")?;
+        Ok(Self {
+            left_child_page: u32::from_be_bytes(*left_child_page),
+            entry: btree_index_leaf::parse_cell(entry, usable_size)?,
+        })
+    }
+}
+
+/// An iterator over the cells in a page.
+struct CellIter<'a> {
+    /// The page we're iterating over
+    page: &'a BTreeIndexInternalPage<'a>,
+    /// The index of iteration
+    idx: usize,
+}
+impl<'a> Iterator for CellIter<'a> {
+    type Item = Cell<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx * 2 >= self.page.cell_pointers.len() {
+            return None;
+        }
+        // TODO Error checking
+        let pointer_bytes = [
+            self.page.cell_pointers[self.idx * 2],
+            self.page.cell_pointers[self.idx * 2 + 1],
+        ];
+        self.idx += 1;
+        // Do this arithmetic in `usize`, since the content offset may be 65536.
+        let pointer = usize::from(u16::from_be_bytes(pointer_bytes))
+            .checked_sub(self.page.header.cell_content_offset as usize)
+            .expect("Cell pointer before the cell content area");
+        Some(
+            Cell::parse(&self.page.cell_contents[pointer..], self.page.usable_size)
+                .expect("Failed to parse"),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.page.num_cells().saturating_sub(self.idx);
+        (len, Some(len))
+    }
+
+    fn count(self) -> usize {
+        self.size_hint().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        btree,
+        page::ParsedPage,
+        pager::Pager,
+        record::{Record, Value},
+    };
+
+    #[test]
+    fn test_parse_index_internal() {
+        let contents =
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database");
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        // Fill the index on `users.team`, at page 5, until its root splits.
+        for rowid in 3..1000 {
+            let entry = Record::build(&[Value::<&[u8]>::int(rowid % 10), Value::int(rowid)]);
+            btree::index::insert(&mut pager, 5, &entry, &[]).expect("Failed to insert");
+        }
+        let page = pager.read_page(5).expect("Failed to read page");
+        let ParsedPage::BTreeIndexInternal(root) = page.parse() else {
+            panic!("The index root should have split");
+        };
+        let children = root
+            .cells()
+            .map(|cell| {
+                let record = cell.entry.payload().unwrap().expect("Entry should fit");
+                assert_eq!(record.value_iter().count(), 2);
+                cell.left_child_page as usize
+            })
+            .chain([root.rightmost_child_idx() as usize])
+            .collect::<Vec<_>>();
+        assert_eq!(children.len(), root.num_cells() + 1);
+        for child in children {
+            let page = pager.read_page(child).expect("Failed to read page");
+            assert!(matches!(
+                page.parse(),
+                ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_)
+            ));
+        }
+    }
+}
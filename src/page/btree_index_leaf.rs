@@ -0,0 +1,200 @@
+//! Implementation for btree index leaf pages
+
+use anyhow::{Context, Result};
+
+use crate::{page::PageType, parse_varint, record::Record};
+
+/// A parsed leaf in an index's btree
+pub struct BTreeIndexLeafPage<'a> {
+    /// The header for the page
+    header: super::BTreePageHeader,
+    /// The pointers to cells
+    ///
+    /// Per SQLite format, you need to subtract the cell content offset in [`Self::header`] first
+    /// and then you can index into [`Self::cell_contents`].
+    cell_pointers: &'a [u8],
+    /// The contents of the cells
+    cell_contents: &'a [u8],
+    /// The number of usable bytes in each page, which determines how much of a payload is stored
+    /// in the page itself
+    usable_size: usize,
+}
+
+impl<'a> BTreeIndexLeafPage<'a> {
+    pub(super) fn new(contents: &'a [u8], header_offset: usize) -> Result<Self> {
+        let (page_type, header, header_len) =
+            super::BTreePageHeader::parse(contents, header_offset)?;
+        let body = &contents[header_len..];
+        anyhow::ensure!(page_type == PageType::BTreeIndexLeaf, "Wrong page type");
+        let cell_pointers = body
+            .get(..header.cell_count as usize * 2)
+            .context("Unexpected end of page in cell pointer array")?;
+        let cell_contents = contents
+            .get(header.cell_content_offset as usize..)
+            .context("Unexpected end of page in cell contents")?;
+        Ok(Self {
+            header,
+            cell_pointers,
+            cell_contents,
+            // The page is only given the usable part of its contents.
+            usable_size: contents.len(),
+        })
+    }
+
+    /// Get the number of cells in this page
+    #[must_use]
+    pub fn num_cells(&self) -> usize {
+        self.header.cell_count as usize
+    }
+
+    pub fn cells(&'a self) -> impl Iterator<Item = Cell<'a>> + 'a {
+        CellIter { page: self, idx: 0 }
+    }
+}
+
+/// An entry in an index, which is a record of the indexed values followed by the rowid.
+pub struct Cell<'a> {
+    /// The size of the whole payload, including any part in overflow pages
+    payload_size: usize,
+    /// The raw bytes of the part of the record stored in this page
+    local_payload: &'a [u8],
+    /// The first overflow page holding the rest of the record, if it didn't fit in this page
+    overflow_page: Option<u32>,
+}
+impl<'a> Cell<'a> {
+    fn new(payload_size: usize, contents: &'a [u8], usable_size: usize) -> Result<Self> {
+        let max_local = (usable_size - 12) * 64 / 255 - 23;
+        let local_size = super::local_payload_size(payload_size, usable_size, max_local);
+        let local_payload = contents
+            .get(..local_size)
+            .context("Unexpected end of contents")?;
+        let overflow_page = if local_size < payload_size {
+            let pointer = contents
+                .get(local_size..local_size + 4)
+                .context("Unexpected end of contents in overflow pointer")?;
+            Some(u32::from_be_bytes(pointer.try_into().unwrap()))
+        } else {
+            None
+        };
+        Ok(Self {
+            payload_size,
+            local_payload,
+            overflow_page,
+        })
+    }
+
+    /// Get the size of this cell's payload, including any part of it in overflow pages
+    #[must_use]
+    pub fn payload_size(&self) -> usize {
+        self.payload_size
+    }
+
+    /// Get the record in this cell, if it's stored entirely in this page
+    ///
+    /// # Errors
+    /// If the record is malformed.
+    pub fn payload(&self) -> crate::Result<Option<Record<'a>>> {
+        if self.overflow_page.is_some() {
+            return Ok(None);
+        }
+        Ok(Some(Record::parse(self.local_payload)?))
+    }
+
+    /// Get the raw bytes of the part of this cell's payload stored in this page
+    #[must_use]
+    pub fn local_payload_bytes(&self) -> &'a [u8] {
+        self.local_payload
+    }
+
+    /// Get the first overflow page holding the rest of this cell's payload, if it has one
+    #[must_use]
+    pub fn overflow_page(&self) -> Option<u32> {
+        self.overflow_page
+    }
+}
+
+/// An iterator over the cells in a page.
+struct CellIter<'a> {
+    /// The page we're iterating over
+    page: &'a BTreeIndexLeafPage<'a>,
+    /// The index of iteration
+    idx: usize,
+}
+impl<'a> Iterator for CellIter<'a> {
+    type Item = Cell<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx * 2 >= self.page.cell_pointers.len() {
+            return None;
+        }
+        // TODO Error checking
+        let pointer_bytes = [
+            self.page.cell_pointers[self.idx * 2],
+            self.page.cell_pointers[self.idx * 2 + 1],
+        ];
+        self.idx += 1;
+        // Do this arithmetic in `usize`, since the content offset may be 65536.
+        let pointer = usize::from(u16::from_be_bytes(pointer_bytes))
+            .checked_sub(self.page.header.cell_content_offset as usize)
+            .expect("Cell pointer before the cell content area");
+        Some(
+            parse_cell(&self.page.cell_contents[pointer..], self.page.usable_size)
+                .expect("Failed to parse"),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.page.num_cells().saturating_sub(self.idx);
+        (len, Some(len))
+    }
+
+    fn count(self) -> usize {
+        self.size_hint().0
+    }
+}
+
+/// Parse a cell from the given buffer
+pub(super) fn parse_cell(mut buffer: &[u8], usable_size: usize) -> Result<Cell<'_>> {
+    let length = parse_varint(&mut buffer)? as usize;
+    Cell::new(length, buffer, usable_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{page::ParsedPage, pager::Pager};
+
+    #[test]
+    fn test_parse_index_leaf() {
+        let contents =
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database");
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        let mut entries = 0;
+        for page_idx in 1..=pager.page_count() {
+            let page = pager.read_page(page_idx).expect("Failed to read page");
+            let ParsedPage::BTreeIndexLeaf(leaf) = page.parse() else {
+                continue;
+            };
+            for cell in leaf.cells() {
+                assert_eq!(cell.overflow_page(), None);
+                assert_eq!(cell.payload_size(), cell.local_payload_bytes().len());
+                let record = cell
+                    .payload()
+                    .unwrap()
+                    .expect("Entry should fit in the page");
+                // Every entry ends with the rowid of its row.
+                let rowid = record
+                    .value_iter()
+                    .last()
+                    .expect("Entry should have values");
+                assert!(
+                    rowid.get::<i64>().is_ok(),
+                    "Entries should end with the rowid"
+                );
+                entries += 1;
+            }
+        }
+        assert!(entries > 0, "The database should have index entries");
+    }
+}
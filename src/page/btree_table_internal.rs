@@ -20,8 +20,9 @@ pub struct BTreeTableInternalPage<'a> {
 }
 
 impl<'a> BTreeTableInternalPage<'a> {
-    pub(super) fn new(contents: &'a [u8]) -> Result<Self> {
-        let (page_type, header, header_len) = super::BTreePageHeader::parse(contents)?;
+    pub(super) fn new(contents: &'a [u8], header_offset: usize) -> Result<Self> {
+        let (page_type, header, header_len) =
+            super::BTreePageHeader::parse(contents, header_offset)?;
         let rightmost_pointer = u32::from_be_bytes([
             contents[header_len],
             contents[header_len + 1],
@@ -59,6 +60,44 @@ impl<'a> BTreeTableInternalPage<'a> {
     pub fn cells(&'a self) -> impl Iterator<Item = Cell> + 'a {
         CellIter { page: self, idx: 0 }
     }
+
+    /// Get the cell at the given index, if there is one.
+    #[must_use]
+    pub fn cell(&self, idx: usize) -> Option<Cell> {
+        let pointer_bytes = self.cell_pointers.get(idx * 2..idx * 2 + 2)?;
+        // Do this arithmetic in `usize`, since the content offset may be 65536.
+        let pointer = usize::from(u16::from_be_bytes([pointer_bytes[0], pointer_bytes[1]]))
+            .checked_sub(self.header.cell_content_offset as usize)
+            .expect("Cell pointer before the cell content area");
+        Some(Cell::parse(&self.cell_contents[pointer..]).expect("Failed to parse"))
+    }
+
+    /// Find the index of the child whose subtree holds the row with the given key, if any row
+    /// does, where the rightmost child comes after every cell.
+    ///
+    /// Each cell's key is the greatest key in its left child, so this is the first cell whose key
+    /// isn't less than `key`. The cells are sorted by key, so they're binary searched.
+    #[must_use]
+    pub fn find_child_idx_for_key(&self, key: i64) -> usize {
+        let (mut low, mut high) = (0, self.num_cells());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.cell(mid).is_some_and(|cell| cell.key < key) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// Find the page number of the child whose subtree holds the row with the given key, if any
+    /// row does.
+    #[must_use]
+    pub fn find_child_for_key(&self, key: i64) -> u32 {
+        self.cell(self.find_child_idx_for_key(key))
+            .map_or(self.rightmost_pointer, |cell| cell.left_child_page)
+    }
 }
 
 pub struct Cell {
@@ -90,18 +129,14 @@ impl<'a> Iterator for CellIter<'a> {
     type Item = Cell;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx * 2 >= self.page.cell_pointers.len() {
-            return None;
-        }
-        // TODO Error checking
-        let pointer_bytes = [
-            self.page.cell_pointers[self.idx * 2],
-            self.page.cell_pointers[self.idx * 2 + 1],
-        ];
+        let cell = self.page.cell(self.idx)?;
         self.idx += 1;
-        let pointer =
-            u16::from_be_bytes(pointer_bytes) - self.page.header.cell_content_offset as u16;
-        Some(Cell::parse(&self.page.cell_contents[pointer as usize..]).expect("Failed to parse"))
+        Some(cell)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.idx = self.idx.saturating_add(n);
+        self.next()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -113,3 +148,40 @@ impl<'a> Iterator for CellIter<'a> {
         self.size_hint().0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{btree, page::ParsedPage, pager::Pager, record::Record};
+
+    #[test]
+    fn test_find_child_for_key() {
+        let contents =
+            std::fs::read("test-data/minimal-test.sqlite").expect("Failed to read test database");
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        // Fill `t1`, at page 2, until its root splits.
+        for rowid in (1..=2000).map(|n| n * 2) {
+            btree::insert(&mut pager, 2, rowid, &Record::build::<&[u8]>(&[]))
+                .expect("Failed to insert");
+        }
+        let page = pager.read_page(2).expect("Failed to read page");
+        let ParsedPage::BTreeTableInternal(root) = page.parse() else {
+            panic!("The table root should have split");
+        };
+        assert!(root.num_cells() > 1, "The root should have several cells");
+        for key in -1..=4002 {
+            let expected = root
+                .cells()
+                .find(|cell| cell.key >= key)
+                .map_or(root.rightmost_child_idx(), |cell| cell.left_child_page);
+            assert_eq!(
+                root.find_child_for_key(key),
+                expected,
+                "Wrong child for {key}"
+            );
+        }
+        assert_eq!(root.find_child_idx_for_key(i64::MAX), root.num_cells());
+        assert_eq!(root.find_child_idx_for_key(i64::MIN), 0);
+    }
+}
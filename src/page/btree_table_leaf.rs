@@ -1,5 +1,7 @@
 //! Implementation for btree table leaf pages
 
+use std::cmp::Ordering;
+
 use anyhow::{Context, Result};
 
 use crate::{page::PageType, parse_varint, record::Record};
@@ -15,11 +17,15 @@ pub struct BTreeTableLeafPage<'a> {
     cell_pointers: &'a [u8],
     /// The contents of the cells
     cell_contents: &'a [u8],
+    /// The number of usable bytes in each page, which determines how much of a payload is stored
+    /// in the page itself
+    usable_size: usize,
 }
 
 impl<'a> BTreeTableLeafPage<'a> {
-    pub(super) fn new(contents: &'a [u8]) -> Result<Self> {
-        let (page_type, header, header_len) = super::BTreePageHeader::parse(contents)?;
+    pub(super) fn new(contents: &'a [u8], header_offset: usize) -> Result<Self> {
+        let (page_type, header, header_len) =
+            super::BTreePageHeader::parse(contents, header_offset)?;
         let body = &contents[header_len..];
         anyhow::ensure!(page_type == PageType::BTreeTableLeaf, "Wrong page type");
         let cell_pointers = body
@@ -32,6 +38,8 @@ impl<'a> BTreeTableLeafPage<'a> {
             header,
             cell_pointers,
             cell_contents,
+            // The page is only given the usable part of its contents.
+            usable_size: contents.len(),
         })
     }
 
@@ -44,22 +52,78 @@ impl<'a> BTreeTableLeafPage<'a> {
     pub fn cells(&'a self) -> impl Iterator<Item = Cell<'a>> + 'a {
         CellIter { page: self, idx: 0 }
     }
+
+    /// Get the cell at the given index, if there is one.
+    #[must_use]
+    pub fn cell(&self, idx: usize) -> Option<Cell<'a>> {
+        let pointer_bytes = self.cell_pointers.get(idx * 2..idx * 2 + 2)?;
+        // Do this arithmetic in `usize`, since the content offset may be 65536.
+        let pointer = usize::from(u16::from_be_bytes([pointer_bytes[0], pointer_bytes[1]]))
+            .checked_sub(self.header.cell_content_offset as usize)
+            .expect("Cell pointer before the cell content area");
+        Some(parse_cell(&self.cell_contents[pointer..], self.usable_size).expect("Failed to parse"))
+    }
+
+    /// Find the index of the cell with the given rowid.
+    ///
+    /// As with [`slice::binary_search`], this returns `Err` with the index where a cell with that
+    /// rowid would go if there isn't one. The cells are sorted by rowid, so they're binary searched.
+    pub fn find_cell_idx_by_rowid(&self, rowid: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.num_cells());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.cell(mid).map(|cell| cell.row_id.cmp(&rowid)) {
+                Some(Ordering::Less) => low = mid + 1,
+                Some(Ordering::Equal) => return Ok(mid),
+                Some(Ordering::Greater) | None => high = mid,
+            }
+        }
+        Err(low)
+    }
+
+    /// Find the cell with the given rowid, if there is one.
+    #[must_use]
+    pub fn find_cell_by_rowid(&self, rowid: i64) -> Option<Cell<'a>> {
+        self.cell(self.find_cell_idx_by_rowid(rowid).ok()?)
+    }
 }
 
 pub struct Cell<'a> {
     row_id: i64,
-    record: Record<'a>,
-    // TODO Handle cells too large to fit in a page
+    /// The size of the whole payload, including any part in overflow pages
+    payload_size: usize,
+    /// The raw bytes of the part of the record stored in this page
+    payload: &'a [u8],
+    /// The first overflow page holding the rest of the record, if it didn't fit in this page
+    overflow_page: Option<u32>,
+    /// The record, if it's stored entirely in this page
+    record: Option<Record<'a>>,
 }
 impl<'a> Cell<'a> {
-    fn new(length: usize, mut contents: &'a [u8]) -> Result<Self> {
+    fn new(payload_size: usize, mut contents: &'a [u8], usable_size: usize) -> Result<Self> {
         let row_id = parse_varint(&mut contents)?;
-        let contents = contents
-            .get(..length)
+        let local_size = super::local_payload_size(payload_size, usable_size, usable_size - 35);
+        let payload = contents
+            .get(..local_size)
             .context("Unexpected end of contents")?;
+        if local_size < payload_size {
+            let pointer = contents
+                .get(local_size..local_size + 4)
+                .context("Unexpected end of contents in overflow pointer")?;
+            return Ok(Self {
+                row_id,
+                payload_size,
+                payload,
+                overflow_page: Some(u32::from_be_bytes(pointer.try_into().unwrap())),
+                record: None,
+            });
+        }
         Ok(Self {
             row_id,
-            record: Record::parse(contents)?,
+            payload_size,
+            payload,
+            overflow_page: None,
+            record: Some(Record::parse(payload)?),
         })
     }
 
@@ -69,11 +133,32 @@ impl<'a> Cell<'a> {
         self.row_id
     }
 
-    /// Get the payload bytes of this cell
+    /// Get the payload of this cell, if it's stored entirely in this page
+    ///
+    /// Otherwise, the rest of it has to be read with
+    /// [`Pager::read_overflow`](crate::pager::Pager::read_overflow).
     #[must_use]
-    pub fn payload(&self) -> Record<'a> {
+    pub fn payload(&self) -> Option<Record<'a>> {
         self.record
     }
+
+    /// Get the raw bytes of the part of this cell's payload stored in this page
+    #[must_use]
+    pub fn payload_bytes(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Get the size of this cell's payload, including any part of it in overflow pages
+    #[must_use]
+    pub fn payload_size(&self) -> usize {
+        self.payload_size
+    }
+
+    /// Get the first overflow page holding the rest of this cell's payload, if it has one
+    #[must_use]
+    pub fn overflow_page(&self) -> Option<u32> {
+        self.overflow_page
+    }
 }
 
 /// An iterator over the cells in a page.
@@ -87,18 +172,14 @@ impl<'a> Iterator for CellIter<'a> {
     type Item = Cell<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx * 2 >= self.page.cell_pointers.len() {
-            return None;
-        }
-        // TODO Error checking
-        let pointer_bytes = [
-            self.page.cell_pointers[self.idx * 2],
-            self.page.cell_pointers[self.idx * 2 + 1],
-        ];
+        let cell = self.page.cell(self.idx)?;
         self.idx += 1;
-        let pointer =
-            u16::from_be_bytes(pointer_bytes) - self.page.header.cell_content_offset as u16;
-        Some(parse_cell(&self.page.cell_contents[pointer as usize..]).expect("Failed to parse"))
+        Some(cell)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.idx = self.idx.saturating_add(n);
+        self.next()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -112,7 +193,42 @@ impl<'a> Iterator for CellIter<'a> {
 }
 
 /// Parse a cell from the given buffer
-fn parse_cell(mut buffer: &[u8]) -> Result<Cell<'_>> {
+fn parse_cell(mut buffer: &[u8], usable_size: usize) -> Result<Cell<'_>> {
     let length = parse_varint(&mut buffer)? as usize;
-    Cell::new(length, buffer)
+    Cell::new(length, buffer, usable_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{btree, page::ParsedPage, pager::Pager, record::Record};
+
+    #[test]
+    fn test_find_cell_by_rowid() {
+        let contents =
+            std::fs::read("test-data/minimal-test.sqlite").expect("Failed to read test database");
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        // Only even rowids, so odd ones fall between cells.
+        for rowid in (1..=50).map(|n| n * 2) {
+            btree::insert(&mut pager, 2, rowid, &Record::build::<&[u8]>(&[]))
+                .expect("Failed to insert");
+        }
+        let page = pager.read_page(2).expect("Failed to read page");
+        let ParsedPage::BTreeTableLeaf(leaf) = page.parse() else {
+            panic!("The table should fit in its root");
+        };
+        let rowids = leaf.cells().map(|cell| cell.row_id()).collect::<Vec<_>>();
+        for rowid in -1..=102 {
+            assert_eq!(
+                leaf.find_cell_idx_by_rowid(rowid),
+                rowids.binary_search(&rowid),
+                "Wrong index for {rowid}",
+            );
+            assert_eq!(
+                leaf.find_cell_by_rowid(rowid).map(|cell| cell.row_id()),
+                rowids.contains(&rowid).then_some(rowid),
+            );
+        }
+    }
 }
@@ -1,13 +1,56 @@
 //! A pager to control reading pages from disk and writing them back.
 
+mod checksum;
+mod codec;
+mod config;
+mod freelist;
+#[cfg(feature = "http")]
+mod http;
+mod journal;
+mod lock;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(all(feature = "opfs", target_arch = "wasm32"))]
+mod opfs;
+mod overflow;
+mod ptrmap;
+mod storage;
+mod sync;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod vfs;
+mod wal;
+
 use anyhow::{Context, Result};
 use std::{
-    collections::{hash_map, HashMap},
-    io::{self, Read, Seek},
-    ptr::NonNull,
+    collections::{btree_map, hash_map, BTreeMap, HashMap},
+    fs,
+    io::{self, Read, Seek, Write},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
 };
 
-use crate::page::Page;
+use crate::{page::Page, record::TextEncoding, Error};
+
+pub use codec::PageCodec;
+pub use config::{CacheSize, PagerConfig};
+#[cfg(feature = "http")]
+pub use http::HttpFile;
+pub use journal::journal_path_for;
+pub use lock::{LockKind, LockLevel};
+#[cfg(all(feature = "opfs", target_arch = "wasm32"))]
+pub use opfs::OpfsFile;
+pub(crate) use overflow::OverflowChain;
+pub use ptrmap::AutoVacuum;
+pub(crate) use ptrmap::PtrmapEntry;
+pub use storage::Storage;
+pub use sync::{SyncFile, SyncPolicy};
+pub use vfs::{OpenMode, OsVfs, Vfs, VfsFile};
+pub use wal::{
+    shm_path_for, wal_path_for, Checkpoint, CheckpointMode, JournalMode, Wal, WalCommit,
+};
 
 /// The pager itself
 pub struct Pager<File> {
@@ -16,138 +59,1345 @@ pub struct Pager<File> {
     /// The header for this database
     header: DatabaseHeader,
     /// The page cache.
-    page_cache: PageCache,
+    ///
+    /// This only ever holds the versions of pages which are on disk.
+    page_cache: PagerCache,
+    /// How much the page cache holds, as it was last set.
+    cache_size: CacheSize,
+    /// The page last returned by [`Self::page_buffer`] from the page cache, which is pinned here
+    /// while it's borrowed, so another thread can't evict it from a shared cache.
+    cached_page: Option<Arc<[u8]>>,
+    /// The pages which have been modified but not yet written back to the file.
+    ///
+    /// Reads consult these before the page cache, so an open transaction observes its own
+    /// uncommitted writes.
+    dirty_pages: BTreeMap<usize, Box<[u8]>>,
+    /// The number of pages in the file itself, not counting pages added by unflushed writes.
+    disk_page_count: u32,
+    /// Where to write the rollback journal when flushing, if anywhere.
+    journal_path: Option<PathBuf>,
+    /// Where the rollback journal and the WAL are opened and deleted.
+    vfs: Arc<dyn Vfs>,
+    /// The open savepoints, innermost last.
+    savepoints: Vec<Savepoint>,
+    /// How many times each page has been read since the counts were last reset.
+    page_accesses: PageAccessMap,
+    /// How many of those reads had to load the page from the file.
+    page_loads: u64,
+    /// What the pager has done since it was opened, except for evictions, which the page caches
+    /// count.
+    stats: PagerStats,
+    /// The pages from the WAL which take the place of those in the file, if reading a snapshot
+    /// of the database as of a commit in the WAL.
+    ///
+    /// Snapshots are read-only, since writing to the file under a WAL would corrupt it.
+    wal_snapshot: Option<wal::WalSnapshot>,
+    /// The WAL which commits are appended to, if the database is in WAL mode.
+    wal: Option<wal::LiveWal>,
+    /// The lock held on the file, so other processes can use it at the same time.
+    lock: LockLevel,
+    /// How long [`Self::lock`] keeps trying to take a lock which conflicts with another
+    /// connection's before giving up.
+    busy_timeout: Duration,
+    /// How carefully flushes are synced to persistent storage.
+    sync_policy: SyncPolicy,
+    /// Whether this pager only reads the database, as opened by [`Self::reader`].
+    read_only: bool,
+    /// The codec pages are decoded with as they're read, and encoded with as they're written, if
+    /// they're stored encoded.
+    codec: Option<Arc<dyn PageCodec>>,
+    /// The file mapped into memory, if pages are read from a map of it instead of the page cache.
+    #[cfg(feature = "mmap")]
+    mmap: Option<mmap::MappedFile>,
+    /// The ring to read batches of pages with, if they're read with `io_uring`.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<uring::PageRing>,
 }
 impl<File: Read> Pager<File> {
     /// Construct a new pager over the given file.
     ///
     /// We assume that the file is currently at the beginning, this function may behave
     /// unexpectedly otherwise.
-    pub fn new(mut file: File) -> Result<Self> {
-        let header = {
-            let mut buf = [0; DATABASE_HEADER_SIZE];
-            file.read_exact(&mut buf)
-                .context("Error reading database header from file")?;
-            DatabaseHeader::parse(&buf)?
-        };
+    pub fn new(file: File) -> crate::Result<Self> {
+        Ok(Self::open(file, None)?)
+    }
+
+    /// Construct a new pager over the given file, whose pages are encoded with `codec` if given.
+    fn open(mut file: File, codec: Option<Arc<dyn PageCodec>>) -> Result<Self> {
+        let header = codec::read_header(&mut file, codec.as_deref())?;
         Ok(Self {
             file,
             header,
-            page_cache: PageCache::new(header.page_size()),
+            page_cache: PagerCache::new(header.page_size()),
+            cache_size: CacheSize::default(),
+            cached_page: None,
+            dirty_pages: BTreeMap::new(),
+            disk_page_count: header.page_count,
+            journal_path: None,
+            vfs: Arc::new(OsVfs),
+            savepoints: Vec::new(),
+            page_accesses: PageAccessMap::new(),
+            page_loads: 0,
+            stats: PagerStats::default(),
+            wal_snapshot: None,
+            wal: None,
+            lock: LockLevel::None,
+            busy_timeout: Duration::ZERO,
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            codec,
+            #[cfg(feature = "mmap")]
+            mmap: None,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: None,
+        })
+    }
+
+    /// Construct a new pager over the given file, which protects writes with a rollback journal
+    /// at `journal_path`.
+    ///
+    /// We assume that the file is currently at the beginning, this function may behave
+    /// unexpectedly otherwise.
+    pub fn with_journal(file: File, journal_path: PathBuf) -> crate::Result<Self> {
+        Ok(Self {
+            journal_path: Some(journal_path),
+            ..Self::new(file)?
         })
     }
 }
 impl<File: Read + Seek> Pager<File> {
+    /// Construct a read-only pager over the given file, which reads the database as it was after
+    /// the last commit in `wal` which ends at or before `frame`.
+    ///
+    /// Frame 0 reads the database as it was before every commit in the WAL. Frames are numbered
+    /// as in [`WalCommit::frame`].
+    pub fn with_wal_snapshot<WalFile: Read + Seek>(
+        file: File,
+        wal: &mut Wal<WalFile>,
+        frame: usize,
+    ) -> crate::Result<Self> {
+        let mut pager = Self::new(file)?;
+        if wal.page_size() != pager.page_size() {
+            return Err(Error::corrupt(
+                None,
+                "WAL page size doesn't match the database",
+            ));
+        }
+        let snapshot = wal.snapshot(frame)?;
+        if let Some(first_page) = snapshot.pages.get(&1) {
+            pager.header =
+                DatabaseHeader::parse(first_page[..DATABASE_HEADER_SIZE].try_into().unwrap())?;
+        }
+        if let Some(page_count) = snapshot.page_count {
+            pager.header.page_count = page_count;
+        }
+        pager.disk_page_count = pager.header.page_count;
+        pager.wal_snapshot = Some(snapshot);
+        Ok(pager)
+    }
+
     /// Read the given page.
-    pub fn read_page(&mut self, page_idx: usize) -> Result<Page> {
+    ///
+    /// The page only covers the usable part of the page, without the reserved bytes at its end.
+    pub fn read_page(&mut self, page_idx: usize) -> crate::Result<Page> {
+        let usable_size = self.header.usable_size();
+        Page::new(&self.page_buffer(page_idx)?[..usable_size], page_idx)
+            .map_err(|e| Error::corrupt(Some(page_idx), format!("{e:#}")))
+    }
+
+    /// Read the raw contents of the given page, without parsing it.
+    ///
+    /// This is for pages which [`Page`] can't parse yet.
+    pub(crate) fn read_page_bytes(&mut self, page_idx: usize) -> Result<&[u8]> {
+        self.page_buffer(page_idx)
+    }
+
+    /// Read the given page, and pin it in memory until the returned guard is dropped.
+    ///
+    /// Unlike [`Self::read_page`], the pinned page doesn't borrow the pager, so pages can be read
+    /// while it's held. A pinned page is never evicted from the page cache, and it keeps the
+    /// contents it had when it was pinned, even if the page is written to afterwards.
+    pub fn pin_page(&mut self, page_idx: usize) -> crate::Result<PinnedPage> {
+        let buffer = self.page_buffer(page_idx)?.as_ptr();
+        let contents = match &self.cached_page {
+            Some(page) if page.as_ptr() == buffer => Arc::clone(page),
+            // Dirty, snapshotted, and mapped pages aren't in the cache, so they're copied instead.
+            _ => Arc::from(
+                self.dirty_pages
+                    .get(&page_idx)
+                    .map(|page| &**page)
+                    .or_else(|| {
+                        self.wal_snapshot
+                            .as_ref()
+                            .and_then(|snapshot| snapshot.pages.get(&page_idx))
+                            .map(|page| &**page)
+                    })
+                    .or_else(|| self.mapped_page(page_idx))
+                    .context("Page read from outside the cache wasn't found again")?,
+            ),
+        };
+        let usable_size = self.header.usable_size();
+        // Check that it parses, so `PinnedPage::page` needn't.
+        Page::new(&contents[..usable_size], page_idx)?;
+        Ok(PinnedPage {
+            page_idx,
+            contents,
+            usable_size,
+        })
+    }
+
+    /// Get the buffer holding the current contents of the given page.
+    fn page_buffer(&mut self, page_idx: usize) -> Result<&[u8]> {
         anyhow::ensure!(
             page_idx <= self.header.page_count as usize,
             "`page_idx` out of bounds"
         );
-        let buffer = self.page_cache.get_or_load(page_idx, |buf, page_idx| {
+        *self.page_accesses.entry(page_idx).or_default() += 1;
+        if let Some(buffer) = self.dirty_pages.get(&page_idx) {
+            self.stats.cache_hits += 1;
+            return Ok(buffer);
+        }
+        if let Some(buffer) = self
+            .wal_snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.pages.get(&page_idx))
+        {
+            self.stats.cache_hits += 1;
+            return Ok(buffer);
+        }
+        let page_size = self.header.page_size();
+        // This is `Self::mapped_page`, but only borrowing the fields it needs, so the page cache
+        // can be borrowed afterwards.
+        #[cfg(feature = "mmap")]
+        if let Some(page) = self
+            .mmap
+            .as_ref()
+            .filter(|_| self.wal.is_none())
+            .and_then(|mmap| mmap.page(page_idx, page_size))
+        {
+            self.page_loads += 1;
+            self.stats.record_load(page.len());
+            return Ok(page);
+        }
+        // The last page returned is no longer borrowed, so it needn't be kept pinned.
+        self.cached_page = None;
+        let misses = self.stats.cache_misses;
+        let page = self
+            .page_cache
+            .get_or_load(self.wal.is_some(), page_idx, |buf, page_idx| {
+                self.page_loads += 1;
+                self.stats.cache_misses += 1;
+                Self::load_page(
+                    &mut self.file,
+                    self.wal.as_mut(),
+                    self.codec.as_deref(),
+                    &mut self.stats,
+                    page_size,
+                    page_idx,
+                    buf,
+                )
+            })?;
+        if self.stats.cache_misses == misses {
+            self.stats.cache_hits += 1;
+        }
+        Ok(self.cached_page.insert(page))
+    }
+
+    /// Read the given page into `buf`, bypassing the cache, and count the read in `stats`.
+    ///
+    /// The page is read from `wal` if it holds a version of the page, and `file` otherwise, and
+    /// then decoded with `codec` if there is one.
+    fn load_page(
+        file: &mut File,
+        wal: Option<&mut wal::LiveWal>,
+        codec: Option<&dyn PageCodec>,
+        stats: &mut PagerStats,
+        page_size: usize,
+        page_idx: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        if let Some(wal) = wal {
+            if wal.read_newest(page_idx, buf)? {
+                stats.record_load(buf.len());
+                return Self::decode_page(codec, page_idx, buf);
+            }
+        }
+        file.seek(io::SeekFrom::Start(
+            (page_size
+                * (page_idx
+                    .checked_sub(1)
+                    .context("page index out of bounds")?)) as u64,
+        ))
+        .context("Error seeking in database")?;
+        file.read_exact(buf)
+            .context("Error reading from database file")?;
+        stats.record_load(buf.len());
+        Self::decode_page(codec, page_idx, buf)
+    }
+
+    /// Decode the page at `page_idx`, just read into `buf`, with `codec` if there is one.
+    fn decode_page(codec: Option<&dyn PageCodec>, page_idx: usize, buf: &mut [u8]) -> Result<()> {
+        match codec {
+            Some(codec) => codec
+                .decode(page_idx, buf)
+                .with_context(|| format!("Failed to decode page {page_idx}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Read the database header, including any changes which haven't been flushed yet.
+    pub fn header(&mut self) -> crate::Result<DatabaseHeader> {
+        let first_page = self.read_page_bytes(1)?;
+        let mut header =
+            DatabaseHeader::parse(first_page[..DATABASE_HEADER_SIZE].try_into().unwrap())?;
+        // The page count is only written into the first page when the database is flushed.
+        header.page_count = self.header.page_count;
+        Ok(header)
+    }
+
+    /// Change the schema cookie, which tells other connections to read the schema again.
+    pub(crate) fn change_schema_cookie(&mut self) -> Result<()> {
+        let mut first_page = self.read_page_bytes(1)?.to_vec();
+        let cookie = u32::from_be_bytes(first_page[40..44].try_into().unwrap());
+        first_page[40..44].copy_from_slice(&cookie.wrapping_add(1).to_be_bytes());
+        Ok(self.write_page(1, &first_page)?)
+    }
+}
+
+impl<File: Read + Write + Seek> Pager<File> {
+    /// Construct a pager over a new, empty database, written to `file` with pages of `page_size`
+    /// bytes.
+    ///
+    /// `file` should be empty, since only the first page is written to it.
+    pub fn create(mut file: File, page_size: usize) -> crate::Result<Self> {
+        let mut first_page = vec![0; page_size];
+        first_page[..DATABASE_HEADER_SIZE]
+            .copy_from_slice(&DatabaseHeader::new_database(page_size)?);
+        // `sqlite_schema` starts out as an empty table leaf, with its cell content area starting
+        // at the end of the page.
+        first_page[DATABASE_HEADER_SIZE] = 0x0d;
+        first_page[DATABASE_HEADER_SIZE + 5..DATABASE_HEADER_SIZE + 7]
+            .copy_from_slice(&(page_size as u16).to_be_bytes());
+        file.rewind().context("Error seeking in database")?;
+        file.write_all(&first_page)
+            .context("Error writing new database")?;
+        file.flush().context("Error flushing database file")?;
+        file.rewind().context("Error seeking in database")?;
+        Self::new(file)
+    }
+
+    /// Write all dirty pages back to the file.
+    ///
+    /// This also bumps the file change counter in the database header, so other readers of the
+    /// file can tell that it has changed.
+    ///
+    /// If this pager has a journal, the original contents of every page about to be overwritten
+    /// are written to it first, and it is deleted once all the pages have been written. Each step
+    /// is synced to persistent storage before the next as the [sync policy](Self::sync_policy)
+    /// requires, so a crash at any point leaves either the old or the new database.
+    ///
+    /// In WAL mode, the pages are appended to the WAL instead, leaving the database file alone.
+    ///
+    /// If the database has full auto-vacuum enabled, its free pages are removed first, as by
+    /// [`Self::incremental_vacuum`].
+    pub fn flush(&mut self) -> crate::Result<()>
+    where
+        File: SyncFile,
+    {
+        if self.dirty_pages.is_empty() && self.header.page_count == self.disk_page_count {
+            return Ok(());
+        }
+        if self.wal_snapshot.is_some() {
+            return Err(Error::ReadOnly(
+                "Cannot write to a snapshot of the database from its WAL".to_owned(),
+            ));
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly(
+                "Cannot write to a read-only handle on the database".to_owned(),
+            ));
+        }
+        if self.header.auto_vacuum == AutoVacuum::Full {
+            self.incremental_vacuum(None)
+                .context("Failed to remove free pages")?;
+        }
+        // Other connections notice commits to the WAL through its index, so like SQLite, the
+        // first page is only rewritten there if it changed anyway.
+        if self.wal.is_none()
+            || self.dirty_pages.contains_key(&1)
+            || self.header.page_count != self.disk_page_count
+        {
+            self.header.file_change_counter = self.header.file_change_counter.wrapping_add(1);
+            let mut first_page = match self.dirty_pages.remove(&1) {
+                Some(first_page) => first_page,
+                None => {
+                    if let Some(page) = self.mapped_page(1) {
+                        Box::from(page)
+                    } else {
+                        let page_size = self.header.page_size();
+                        Box::from(&*self.page_cache.get_or_load(
+                            self.wal.is_some(),
+                            1,
+                            |buf, page_idx| {
+                                Self::load_page(
+                                    &mut self.file,
+                                    self.wal.as_mut(),
+                                    self.codec.as_deref(),
+                                    &mut self.stats,
+                                    page_size,
+                                    page_idx,
+                                    buf,
+                                )
+                            },
+                        )?)
+                    }
+                }
+            };
+            self.header.write_counters(&mut first_page);
+            self.dirty_pages.insert(1, first_page);
+        }
+        if let Some(wal) = &mut self.wal {
+            if let Some(codec) = &self.codec {
+                let encoded = self
+                    .dirty_pages
+                    .iter()
+                    .map(|(&page_idx, page)| {
+                        let encoded = codec::encoded(Some(&**codec), page_idx, page)?;
+                        Ok((page_idx, Box::from(encoded)))
+                    })
+                    .collect::<Result<_>>()?;
+                wal.commit(&encoded, self.header.page_count, self.sync_policy)?;
+            } else {
+                wal.commit(&self.dirty_pages, self.header.page_count, self.sync_policy)?;
+            }
+        } else {
+            self.write_dirty_pages()?;
+            self.remap()?;
+        }
+        self.disk_page_count = self.header.page_count;
+        self.savepoints.clear();
+        // The file now matches the dirty pages, so they become the cached on-disk versions, unless
+        // those are read from the map of the file instead.
+        for (page_idx, buffer) in std::mem::take(&mut self.dirty_pages) {
+            if self.mapped_page(page_idx).is_none() {
+                self.page_cache.put(self.wal.is_some(), page_idx, &buffer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrite the pages in the database file with the dirty pages, protected by the journal if
+    /// there is one.
+    fn write_dirty_pages(&mut self) -> Result<()>
+    where
+        File: SyncFile,
+    {
+        let page_size = self.header.page_size();
+        let journal = self
+            .journal_path
+            .clone()
+            .map(|journal_path| self.write_journal(journal_path))
+            .transpose()?;
+        for (&page_idx, buffer) in &self.dirty_pages {
             self.file
-                .seek(io::SeekFrom::Start(
-                    (self.header.page_size()
-                        * (page_idx
-                            .checked_sub(1)
-                            .context("page index out of bounds")?)) as u64,
-                ))
+                .seek(io::SeekFrom::Start((page_size * (page_idx - 1)) as u64))
                 .context("Error seeking in database")?;
             self.file
-                .read_exact(buf)
-                .context("Error reading from database file")?;
-            Ok(())
-        })?;
-        Page::new(buffer)
+                .write_all(&codec::encoded(self.codec.as_deref(), page_idx, buffer)?)
+                .with_context(|| format!("Error writing page {page_idx} to database file"))?;
+        }
+        self.file.flush().context("Error flushing database file")?;
+        if self.sync_policy != SyncPolicy::Off {
+            self.file.sync().context("Error syncing database file")?;
+        }
+        if let Some(journal) = journal {
+            journal.delete()?;
+        }
+        Ok(())
+    }
+
+    /// Write the original contents of every dirty page to a new journal at `journal_path`.
+    fn write_journal(&mut self, journal_path: PathBuf) -> Result<journal::Journal> {
+        let page_size = self.header.page_size();
+        let mut journal = journal::Journal::create(
+            Arc::clone(&self.vfs),
+            journal_path,
+            page_size,
+            self.disk_page_count,
+            self.sync_policy,
+        )?;
+        for &page_idx in self.dirty_pages.keys() {
+            // Pages past the original end of the file get removed by truncation on rollback
+            if page_idx > self.disk_page_count as usize {
+                continue;
+            }
+            let original = self
+                .page_cache
+                .get_or_load(false, page_idx, |buf, page_idx| {
+                    Self::load_page(
+                        &mut self.file,
+                        None,
+                        self.codec.as_deref(),
+                        &mut self.stats,
+                        page_size,
+                        page_idx,
+                        buf,
+                    )
+                })?;
+            journal.append_page(
+                page_idx,
+                &codec::encoded(self.codec.as_deref(), page_idx, &original)?,
+            )?;
+        }
+        journal.sync()?;
+        Ok(journal)
+    }
+}
+
+impl<File: Storage> Pager<File> {
+    /// Shrink the file to the size of the database, after [`Self::truncate`] removed pages from
+    /// its end.
+    ///
+    /// This does nothing if the file is no longer than the database, or in WAL mode, where other
+    /// connections may still be reading the pages past the end and checkpoints shrink the file.
+    pub fn truncate_file(&mut self) -> crate::Result<()> {
+        if !self.dirty_pages.is_empty() {
+            return Err(Error::Other(
+                "Cannot truncate the file before flushing changes to it".to_owned(),
+            ));
+        }
+        if self.wal.is_some() {
+            return Ok(());
+        }
+        let len = (self.header.page_size() * self.header.page_count as usize) as u64;
+        let file_len = self
+            .file
+            .size()
+            .context("Error reading database file size")?;
+        if file_len > len {
+            self.file
+                .set_len(len)
+                .context("Error truncating database file")?;
+            self.remap()?;
+        }
+        Ok(())
+    }
+
+    /// Hint that the given pages are about to be read, so the ones which aren't already in memory
+    /// can be read from the file ahead of time.
+    ///
+    /// This is only a hint, so the pages aren't loaded into the page cache, and nothing happens in
+    /// WAL mode, where the newest version of a page may not be in the file.
+    pub fn prefetch(&mut self, pages: impl IntoIterator<Item = usize>) -> crate::Result<()> {
+        if self.wal.is_some() {
+            return Ok(());
+        }
+        let page_size = self.header.page_size() as u64;
+        for run in self.uncached_runs(pages) {
+            self.file
+                .prefetch(
+                    (run.start as u64 - 1) * page_size,
+                    run.len() as u64 * page_size,
+                )
+                .context("Failed to read ahead in database file")?;
+        }
+        Ok(())
+    }
+
+    /// Load the given pages into the page cache, unless they're already in memory.
+    ///
+    /// Each run of adjacent pages is read from the file at once, which takes far fewer reads than
+    /// loading the pages one at a time when they're laid out in order, as they are after a vacuum.
+    /// Like [`Self::prefetch`], nothing happens in WAL mode.
+    pub fn read_pages(&mut self, pages: impl IntoIterator<Item = usize>) -> crate::Result<()> {
+        if self.wal.is_some() {
+            return Ok(());
+        }
+        let page_size = self.header.page_size();
+        let runs = self.uncached_runs(pages);
+        for (run, mut buffer) in runs.iter().zip(self.read_runs(&runs)?) {
+            for (page_idx, contents) in run.clone().zip(buffer.chunks_exact_mut(page_size)) {
+                self.page_loads += 1;
+                self.stats.record_load(page_size);
+                Self::decode_page(self.codec.as_deref(), page_idx, contents)?;
+                self.page_cache.put_loaded(page_idx, contents)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read each of the given runs of adjacent pages from the file, returning the contents of each.
+    fn read_runs(&mut self, runs: &[Range<usize>]) -> Result<Vec<Vec<u8>>> {
+        let page_size = self.header.page_size();
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.uring {
+            let buffers = ring.read_runs(runs, page_size);
+            if buffers.is_err() {
+                // Reads may have been left in flight, so pages are read as usual from now on.
+                self.uring = None;
+            }
+            return buffers.context("Error reading from database file");
+        }
+        runs.iter()
+            .map(|run| {
+                let mut buffer = vec![0; run.len() * page_size];
+                self.file
+                    .seek(io::SeekFrom::Start(((run.start - 1) * page_size) as u64))
+                    .context("Error seeking in database")?;
+                self.file
+                    .read_exact(&mut buffer)
+                    .context("Error reading from database file")?;
+                Ok(buffer)
+            })
+            .collect()
+    }
+
+    /// Group the given pages which would have to be loaded from the file into runs of adjacent
+    /// pages, in order.
+    fn uncached_runs(&self, pages: impl IntoIterator<Item = usize>) -> Vec<Range<usize>> {
+        let mut pages = pages
+            .into_iter()
+            .filter(|&page_idx| {
+                (1..=self.disk_page_count as usize).contains(&page_idx)
+                    && !self.dirty_pages.contains_key(&page_idx)
+                    && !self
+                        .wal_snapshot
+                        .as_ref()
+                        .is_some_and(|snapshot| snapshot.pages.contains_key(&page_idx))
+                    && self.mapped_page(page_idx).is_none()
+                    && !self.page_cache.contains(self.wal.is_some(), page_idx)
+            })
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+        pages.dedup();
+        let mut runs = Vec::<Range<usize>>::new();
+        for page_idx in pages {
+            match runs.last_mut() {
+                Some(run) if run.end == page_idx => run.end += 1,
+                _ => runs.push(Range {
+                    start: page_idx,
+                    end: page_idx + 1,
+                }),
+            }
+        }
+        runs
+    }
+
+    /// Open a read-only pager on the same database, which can be sent to another thread to read
+    /// alongside this one.
+    ///
+    /// Outside of WAL mode, the pagers share a page cache, so a page read by one of them doesn't
+    /// have to be read from the file again by the others. Like the WAL, the database file is found
+    /// next to the rollback journal, so this needs the pager to have a journal path.
+    pub fn reader(&mut self) -> crate::Result<Pager<fs::File>> {
+        let journal_path = self
+            .journal_path
+            .clone()
+            .context("Can only open readers on databases with a journal next to them")?;
+        let db_path = journal::db_path_for_journal(&journal_path)
+            .context("The journal path doesn't name a database")?;
+        // Opening the file again, rather than duplicating the handle, gives the reader its own
+        // locks on it.
+        let file = fs::File::open(db_path)
+            .with_context(|| format!("Failed to open {}", db_path.display()))?;
+        let mut reader = Pager::open(file, self.codec.clone())?;
+        reader.journal_path = Some(journal_path);
+        reader.read_only = true;
+        reader.busy_timeout = self.busy_timeout;
+        reader.page_cache.shared = Some(self.page_cache.share());
+        Ok(reader)
     }
 }
 
 impl<File> Pager<File> {
+    /// Get the encoding of the text stored in this database.
+    #[must_use]
+    pub fn text_encoding(&self) -> TextEncoding {
+        self.header.text_encoding
+    }
+
     /// Return the number of pages in the database.
     pub fn page_count(&mut self) -> usize {
         self.header.page_count as usize
     }
+
+    /// Return the size of each page, in bytes.
+    #[must_use]
+    pub fn page_size(&self) -> usize {
+        self.header.page_size()
+    }
+
+    /// Return the number of bytes in each page which hold its contents.
+    ///
+    /// This is less than the page size if the database reserves space at the end of each page,
+    /// which extensions like encryption use.
+    #[must_use]
+    pub fn usable_size(&self) -> usize {
+        self.header.usable_size()
+    }
+
+    /// Get how many bytes of pages the page cache holds before evicting the least recently used
+    /// ones.
+    #[must_use]
+    pub fn cache_capacity(&self) -> usize {
+        self.page_cache.capacity()
+    }
+
+    /// Set how many bytes of pages the page cache holds before evicting the least recently used
+    /// ones.
+    ///
+    /// The cache always holds at least one page, however small this is. Modified pages which
+    /// haven't been flushed are held separately, and don't count towards this.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.page_cache.set_capacity(capacity);
+        self.cache_size = CacheSize::Kibibytes(capacity.div_ceil(1024) as u64);
+    }
+
+    /// Overwrite the given page with `contents`.
+    ///
+    /// The new contents are held as a dirty page and only reach the file when [`Self::flush`] is
+    /// called, though [`Self::read_page`] returns them immediately. Writing to the page one past
+    /// the end of the database grows it by a page.
+    pub fn write_page(&mut self, page_idx: usize, contents: &[u8]) -> crate::Result<()> {
+        if !(1..=self.header.page_count as usize + 1).contains(&page_idx) {
+            return Err(Error::Other("`page_idx` out of bounds".to_owned()));
+        }
+        if contents.len() != self.header.page_size() {
+            return Err(Error::Other(
+                "Page contents must be exactly one page long".to_owned(),
+            ));
+        }
+        if page_idx > self.header.page_count as usize {
+            self.header.page_count += 1;
+        }
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint
+                .original_pages
+                .entry(page_idx)
+                .or_insert_with(|| self.dirty_pages.get(&page_idx).cloned());
+        }
+        match self.dirty_pages.entry(page_idx) {
+            btree_map::Entry::Occupied(mut slot) => slot.get_mut().copy_from_slice(contents),
+            btree_map::Entry::Vacant(slot) => {
+                slot.insert(contents.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every page after the first `page_count` from the database.
+    ///
+    /// Like a write, this only reaches the file when [`Self::flush`] is called, and even then the
+    /// file keeps its length until [`Pager::truncate_file`] is called.
+    pub fn truncate(&mut self, page_count: usize) -> crate::Result<()> {
+        if !(1..=self.header.page_count as usize).contains(&page_count) {
+            return Err(Error::Other("`page_count` out of bounds".to_owned()));
+        }
+        let removed = self.dirty_pages.split_off(&(page_count + 1));
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            for (page_idx, contents) in removed {
+                savepoint
+                    .original_pages
+                    .entry(page_idx)
+                    .or_insert(Some(contents));
+            }
+        }
+        self.header.page_count = page_count as u32;
+        Ok(())
+    }
+
+    /// Discard all modified pages which haven't been written back to the file.
+    ///
+    /// This also closes all savepoints.
+    pub fn rollback(&mut self) {
+        self.dirty_pages.clear();
+        self.savepoints.clear();
+        self.header.page_count = self.disk_page_count;
+    }
+
+    /// Open a new savepoint, which later writes can be rolled back to.
+    ///
+    /// Returns the depth of the new savepoint, which identifies it in
+    /// [`Self::rollback_to_savepoint`] and [`Self::release_savepoint`].
+    pub fn open_savepoint(&mut self) -> usize {
+        self.savepoints.push(Savepoint {
+            original_pages: HashMap::new(),
+            page_count: self.header.page_count,
+        });
+        self.savepoints.len() - 1
+    }
+
+    /// Undo all writes since the savepoint at `depth` was opened.
+    ///
+    /// Savepoints opened after it are closed, but it remains open.
+    pub fn rollback_to_savepoint(&mut self, depth: usize) -> crate::Result<()> {
+        if depth >= self.savepoints.len() {
+            return Err(Error::NotFound("No such savepoint".to_owned()));
+        }
+        for savepoint in self.savepoints.drain(depth..).rev() {
+            self.header.page_count = savepoint.page_count;
+            for (page_idx, original) in savepoint.original_pages {
+                match original {
+                    Some(original) => self.dirty_pages.insert(page_idx, original),
+                    None => self.dirty_pages.remove(&page_idx),
+                };
+            }
+        }
+        self.open_savepoint();
+        Ok(())
+    }
+
+    /// Close the savepoint at `depth` and all savepoints opened after it, keeping their writes.
+    pub fn release_savepoint(&mut self, depth: usize) -> crate::Result<()> {
+        if depth >= self.savepoints.len() {
+            return Err(Error::NotFound("No such savepoint".to_owned()));
+        }
+        let released = self.savepoints.split_off(depth);
+        if let Some(parent) = self.savepoints.last_mut() {
+            // The parent needs to be able to undo any pages first written inside the released
+            // savepoints, using the oldest version of each page.
+            for savepoint in released {
+                for (page_idx, original) in savepoint.original_pages {
+                    parent.original_pages.entry(page_idx).or_insert(original);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get how many times each page has been read since [`Self::reset_page_accesses`] was last
+    /// called.
+    ///
+    /// Pages which haven't been read are absent, and reads are counted whether or not they were
+    /// served from the cache.
+    #[must_use]
+    pub fn page_accesses(&self) -> &PageAccessMap {
+        &self.page_accesses
+    }
+
+    /// Get how many of the reads counted by [`Self::page_accesses`] had to load the page from
+    /// the file, rather than finding it in memory.
+    #[must_use]
+    pub fn page_loads(&self) -> u64 {
+        self.page_loads
+    }
+
+    /// Get counts of what the pager has done since it was opened, like how often pages were found
+    /// in the cache.
+    #[must_use]
+    pub fn stats(&self) -> PagerStats {
+        PagerStats {
+            evictions: self.page_cache.evictions(),
+            ..self.stats
+        }
+    }
+
+    /// Reset the counts returned by [`Self::page_accesses`] and [`Self::page_loads`].
+    pub fn reset_page_accesses(&mut self) {
+        self.page_accesses.clear();
+        self.page_loads = 0;
+    }
+
+    /// Whether there are modified pages which haven't been written back to the file.
+    #[must_use]
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty_pages.is_empty()
+    }
+
+    /// Get the on-disk contents of the given page from the map of the file, if pages are read
+    /// from one.
+    #[cfg_attr(not(feature = "mmap"), allow(clippy::unused_self))]
+    fn mapped_page(&self, page_idx: usize) -> Option<&[u8]> {
+        // Pages in the WAL take the place of those in the file, so the map can only be read from
+        // outside of WAL mode.
+        #[cfg(feature = "mmap")]
+        if self.wal.is_none() {
+            return self.mmap.as_ref()?.page(page_idx, self.header.page_size());
+        }
+        let _ = page_idx;
+        None
+    }
+
+    /// Map the file into memory again, if pages are read from a map of it, since its length may
+    /// have changed.
+    #[cfg_attr(
+        not(feature = "mmap"),
+        allow(clippy::unnecessary_wraps, clippy::unused_self)
+    )]
+    fn remap(&mut self) -> Result<()> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &mut self.mmap {
+            mmap.remap().context("Failed to map database file")?;
+        }
+        Ok(())
+    }
+
+    /// Close the pager, giving back the file it reads from.
+    ///
+    /// Any changes which haven't been flushed are discarded.
+    #[must_use]
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+/// The number of times each page was accessed, keyed by page number.
+pub type PageAccessMap = BTreeMap<usize, u64>;
+
+/// Counts of what a pager has done since it was opened, as returned by [`Pager::stats`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PagerStats {
+    /// The number of page reads served from memory, without loading the page.
+    pub cache_hits: u64,
+    /// The number of page reads which had to load the page, from the file or the WAL.
+    pub cache_misses: u64,
+    /// The number of pages loaded from the file or the WAL, including those loaded to write the
+    /// rollback journal.
+    pub pages_read: u64,
+    /// The number of bytes in the pages counted by [`Self::pages_read`].
+    pub bytes_read: u64,
+    /// The number of pages evicted from the page cache to make room for others.
+    ///
+    /// Pagers sharing a page cache, as opened by [`Pager::reader`], count each other's evictions
+    /// from it too.
+    pub evictions: u64,
+}
+impl PagerStats {
+    /// Get the counts of what the pager did between when `earlier` was taken and these were.
+    ///
+    /// Counts which went down, as when a pager is replaced, are taken to have stayed the same.
+    #[must_use]
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            cache_misses: self.cache_misses.saturating_sub(earlier.cache_misses),
+            pages_read: self.pages_read.saturating_sub(earlier.pages_read),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            evictions: self.evictions.saturating_sub(earlier.evictions),
+        }
+    }
+
+    /// Count a page of `len` bytes being loaded.
+    fn record_load(&mut self, len: usize) {
+        self.pages_read += 1;
+        self.bytes_read += len as u64;
+    }
+}
+
+/// A page pinned in memory, as returned by [`Pager::pin_page`].
+///
+/// The page stays in the page cache, if it was read from there, until this is dropped.
+pub struct PinnedPage {
+    /// The index of the page
+    page_idx: usize,
+    /// The contents of the page, which are shared with the page cache
+    contents: Arc<[u8]>,
+    /// The number of usable bytes at the start of the page
+    usable_size: usize,
+}
+impl PinnedPage {
+    /// Get the index of the page.
+    #[must_use]
+    pub fn page_idx(&self) -> usize {
+        self.page_idx
+    }
+
+    /// Get the page.
+    ///
+    /// The page only covers the usable part of the page, as with [`Pager::read_page`].
+    #[must_use]
+    pub fn page(&self) -> Page<'_> {
+        Page::new_unchecked(&self.contents[..self.usable_size], self.page_idx)
+    }
+}
+
+/// The state needed to roll back to a savepoint.
+struct Savepoint {
+    /// The version of each page from before it was first written after the savepoint was opened,
+    /// or `None` if it wasn't dirty then.
+    original_pages: HashMap<usize, Option<Box<[u8]>>>,
+    /// The number of pages in the database when the savepoint was opened.
+    page_count: u32,
 }
 
 /// The size of the database header.
 pub const DATABASE_HEADER_SIZE: usize = 100;
 
-/// The header to the database
-#[derive(Copy, Clone, Debug)]
-struct DatabaseHeader {
+/// The schema format number written to new databases.
+///
+/// Format 4 is the newest, and has been readable by every version of SQLite since 3.3.0.
+const SCHEMA_FORMAT: u32 = 4;
+
+/// The version of SQLite whose file format is written, recorded in the header of new databases.
+const SQLITE_VERSION_NUMBER: u32 = 3_046_000;
+
+/// The 100-byte header at the start of the database file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseHeader {
     /// `$\log_2$` of the page size.
     ///
     /// The page size will be an integer power of 2, so this stores it more space-efficiently.
     page_size_exp: u8,
+    /// The file format version needed to write the database: 1 for a rollback journal, 2 for WAL.
+    pub write_version: u8,
+    /// The file format version needed to read the database: 1 for a rollback journal, 2 for WAL.
+    pub read_version: u8,
+    /// The number of bytes at the end of each page which are reserved for extensions.
+    pub reserved_bytes: u8,
+    /// The fractions of a page that a payload may fill in its cell, which are always 64, 32, and 32.
+    pub payload_fractions: [u8; 3],
     /// The number of times this file has been changed.
-    _file_change_counter: u32,
+    pub file_change_counter: u32,
     /// The number of pages in the database.
-    page_count: u32,
+    pub page_count: u32,
+    /// The first freelist trunk page, or 0 if there are no free pages.
+    pub first_freelist_trunk: u32,
+    /// The number of pages in the freelist.
+    pub freelist_count: u32,
+    /// The counter which changes whenever the schema does.
+    pub schema_cookie: u32,
+    /// The format of the schema, from 1 to 4.
+    pub schema_format: u32,
+    /// The suggested size of the page cache, set by `PRAGMA default_cache_size`.
+    pub default_cache_size: i32,
+    /// The largest root page of a btree in an auto-vacuum database, or 0 in other databases.
+    pub largest_root_page: u32,
     /// The format of text data in this database.
-    _text_encoding: TextEncoding,
+    pub text_encoding: TextEncoding,
+    /// The number set by `PRAGMA user_version`.
+    pub user_version: i32,
+    /// Whether this database removes unused pages from the file by itself.
+    pub auto_vacuum: AutoVacuum,
+    /// The number set by `PRAGMA application_id`.
+    pub application_id: i32,
+    /// The change counter as of the last time the page count was written.
+    pub version_valid_for: u32,
+    /// The version of SQLite which last wrote the database.
+    pub sqlite_version: u32,
 }
 impl DatabaseHeader {
+    /// Encode the header of a new database with pages of `page_size` bytes and nothing in it.
+    fn new_database(page_size: usize) -> Result<[u8; DATABASE_HEADER_SIZE]> {
+        anyhow::ensure!(
+            page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+            "Invalid page size: {page_size}"
+        );
+        let mut buffer = [0; DATABASE_HEADER_SIZE];
+        buffer[..16].copy_from_slice(b"SQLite format 3\0");
+        // 65536 doesn't fit in a u16, so it's stored as 1.
+        buffer[16..18]
+            .copy_from_slice(&(page_size as u16 | u16::from(page_size == 65536)).to_be_bytes());
+        // The file format versions, which are 1 for databases using a rollback journal.
+        buffer[18] = 1;
+        buffer[19] = 1;
+        // The payload fractions, which SQLite requires to be these values.
+        buffer[21..24].copy_from_slice(&[64, 32, 32]);
+        buffer[44..48].copy_from_slice(&SCHEMA_FORMAT.to_be_bytes());
+        buffer[56..60].copy_from_slice(&1_u32.to_be_bytes());
+        buffer[96..100].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+        let mut header = Self::parse(&buffer)?;
+        header.file_change_counter = 1;
+        header.page_count = 1;
+        header.write_counters(&mut buffer);
+        Ok(buffer)
+    }
+
     fn parse(buffer: &[u8; DATABASE_HEADER_SIZE]) -> Result<Self> {
         anyhow::ensure!(
             buffer.starts_with(b"SQLite format 3\0"),
             "File did not begin with header, is it a SQLite database?"
         );
+        let word =
+            |offset: usize| u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap());
         let page_size_raw = u16::from_be_bytes(buffer[16..18].try_into().unwrap());
         let page_size_exp = match page_size_raw {
-            0 => 16,
-            n if n.is_power_of_two() => n.ilog2() as u8,
+            // 65536 doesn't fit in a u16, so it's stored as 1.
+            1 => 16,
+            n if n.is_power_of_two() && n >= 512 => n.ilog2() as u8,
             _ => anyhow::bail!("Invalid page size value in header"),
         };
-        let file_change_counter = u32::from_be_bytes(buffer[24..28].try_into().unwrap());
-        let page_count = u32::from_be_bytes(buffer[28..32].try_into().unwrap());
-        let text_encoding = match u32::from_be_bytes(buffer[56..60].try_into().unwrap()) {
-            1 => TextEncoding::Utf8,
+        let reserved_bytes = buffer[20];
+        anyhow::ensure!(
+            (1 << page_size_exp) - usize::from(reserved_bytes) >= 480,
+            "Too many reserved bytes in each page"
+        );
+        let text_encoding = match word(56) {
+            // A database with nothing in it yet may not have chosen an encoding.
+            0 | 1 => TextEncoding::Utf8,
             2 => TextEncoding::Utf16Le,
             3 => TextEncoding::Utf16Be,
             n => anyhow::bail!("Invalid text format: {n}"),
         };
+        // Auto-vacuum databases have the largest root page here, and no others have a value here.
+        let largest_root_page = word(52);
+        let incremental_vacuum = word(64) != 0;
+        let auto_vacuum = match (largest_root_page, incremental_vacuum) {
+            (0, _) => AutoVacuum::None,
+            (_, false) => AutoVacuum::Full,
+            (_, true) => AutoVacuum::Incremental,
+        };
         Ok(Self {
             page_size_exp,
-            _file_change_counter: file_change_counter,
-            page_count,
-            _text_encoding: text_encoding,
+            write_version: buffer[18],
+            read_version: buffer[19],
+            reserved_bytes,
+            payload_fractions: buffer[21..24].try_into().unwrap(),
+            file_change_counter: word(24),
+            page_count: word(28),
+            first_freelist_trunk: word(32),
+            freelist_count: word(36),
+            schema_cookie: word(40),
+            schema_format: word(44),
+            default_cache_size: word(48) as i32,
+            largest_root_page,
+            text_encoding,
+            user_version: word(60) as i32,
+            auto_vacuum,
+            application_id: word(68) as i32,
+            version_valid_for: word(92),
+            sqlite_version: word(96),
         })
     }
 
     /// Get the size of a page
-    fn page_size(&self) -> usize {
+    #[must_use]
+    pub fn page_size(&self) -> usize {
         1 << usize::from(self.page_size_exp)
     }
+
+    /// Get the number of bytes in each page which hold its contents, before the reserved bytes.
+    #[must_use]
+    pub fn usable_size(&self) -> usize {
+        self.page_size() - usize::from(self.reserved_bytes)
+    }
+
+    /// Write the change counter and page count into the header at the start of `buffer`.
+    ///
+    /// The "version-valid-for" field is kept in sync with the change counter, since SQLite only
+    /// trusts the in-header page count when they match.
+    fn write_counters(&self, buffer: &mut [u8]) {
+        buffer[24..28].copy_from_slice(&self.file_change_counter.to_be_bytes());
+        buffer[28..32].copy_from_slice(&self.page_count.to_be_bytes());
+        buffer[92..96].copy_from_slice(&self.file_change_counter.to_be_bytes());
+    }
+}
+
+/// How many bytes of pages the page cache holds by default, which matches SQLite's default
+/// `cache_size` of 2000 KiB.
+pub const DEFAULT_CACHE_CAPACITY: usize = 2000 * 1024;
+
+/// How many shards a [`SharedPageCache`] is split into.
+const CACHE_SHARDS: usize = 16;
+
+/// The page caches a pager keeps the on-disk versions of pages in.
+struct PagerCache {
+    /// The cache only this pager uses.
+    local: PageCache,
+    /// The cache shared with the read-only handles opened by [`Pager::reader`], if any have been.
+    ///
+    /// In WAL mode, each connection may be reading the database as of a different commit, so this
+    /// is only used outside of it.
+    shared: Option<Arc<SharedPageCache>>,
+}
+impl PagerCache {
+    fn new(page_size: usize) -> Self {
+        Self {
+            local: PageCache::new(page_size, DEFAULT_CACHE_CAPACITY),
+            shared: None,
+        }
+    }
+
+    /// Get the cache to use, depending on whether the pager is in WAL mode.
+    fn get(&mut self, in_wal: bool) -> CacheRef<'_> {
+        match &self.shared {
+            Some(shared) if !in_wal => CacheRef::Shared(shared.as_ref()),
+            _ => CacheRef::Local(&mut self.local),
+        }
+    }
+
+    /// Get the page at the given index, loading it if required, from the cache used in or out of
+    /// WAL mode.
+    fn get_or_load(
+        &mut self,
+        in_wal: bool,
+        page_idx: usize,
+        loader: impl FnOnce(&mut [u8], usize) -> Result<()>,
+    ) -> Result<Arc<[u8]>> {
+        match self.get(in_wal) {
+            CacheRef::Local(cache) => cache.get_or_load(page_idx, loader).cloned(),
+            CacheRef::Shared(cache) => cache.shard(page_idx).get_or_load(page_idx, loader).cloned(),
+        }
+    }
+
+    /// Check whether the page at the given index is in the cache used in or out of WAL mode.
+    fn contains(&self, in_wal: bool, page_idx: usize) -> bool {
+        match &self.shared {
+            Some(shared) if !in_wal => shared.shard(page_idx).entries.contains_key(&page_idx),
+            _ => self.local.entries.contains_key(&page_idx),
+        }
+    }
+
+    /// Store `contents` as the page at the given index, in the cache used in or out of WAL mode.
+    fn put(&mut self, in_wal: bool, page_idx: usize, contents: &[u8]) {
+        match self.get(in_wal) {
+            CacheRef::Local(cache) => cache.put(page_idx, contents),
+            CacheRef::Shared(cache) => cache.shard(page_idx).put(page_idx, contents),
+        }
+    }
+
+    /// Store `contents`, which were just loaded from the file, as the page at the given index, in
+    /// the cache used outside of WAL mode.
+    fn put_loaded(&mut self, page_idx: usize, contents: &[u8]) -> Result<()> {
+        match self.get(false) {
+            CacheRef::Local(cache) => cache.put_loaded(page_idx, contents),
+            CacheRef::Shared(cache) => cache.shard(page_idx).put_loaded(page_idx, contents),
+        }
+    }
+
+    /// Start sharing the cache used outside of WAL mode, returning the shared cache.
+    fn share(&mut self) -> Arc<SharedPageCache> {
+        let local = &mut self.local;
+        let shared = self.shared.get_or_insert_with(|| {
+            let shared = SharedPageCache::new(local.page_size, local.capacity);
+            shared.set_verify_checksums(local.checksums.is_some());
+            // The pages in here could go stale while the shared cache is in use.
+            local.clear(local.page_size);
+            Arc::new(shared)
+        });
+        Arc::clone(shared)
+    }
+
+    /// Get how many pages have been evicted from both caches.
+    fn evictions(&self) -> u64 {
+        let shared = self.shared.as_ref().map_or(0, |shared| {
+            (0..shared.shards.len())
+                .map(|page_idx| shared.shard(page_idx).evictions)
+                .sum()
+        });
+        self.local.evictions + shared
+    }
+
+    /// Get how many bytes of pages the caches hold.
+    fn capacity(&self) -> usize {
+        self.local.capacity
+    }
+
+    /// Change how many bytes of pages the caches hold.
+    fn set_capacity(&mut self, capacity: usize) {
+        self.local.set_capacity(capacity);
+        if let Some(shared) = &self.shared {
+            shared.set_capacity(capacity);
+        }
+    }
+
+    /// Evict every page from both caches, and hold pages of `page_size` bytes from now on.
+    fn clear(&mut self, page_size: usize) {
+        self.local.clear(page_size);
+        if let Some(shared) = &self.shared {
+            shared.clear(page_size);
+        }
+    }
+
+    /// Set whether both caches check the pages they load against checksums.
+    fn set_verify_checksums(&mut self, verify: bool) {
+        self.local.set_verify_checksums(verify);
+        if let Some(shared) = &self.shared {
+            shared.set_verify_checksums(verify);
+        }
+    }
+}
+
+/// One of the caches in a [`PagerCache`].
+enum CacheRef<'a> {
+    /// The cache only one pager uses
+    Local(&'a mut PageCache),
+    /// The cache shared between pagers, whose shards are locked as they're used
+    Shared(&'a SharedPageCache),
+}
+
+/// A page cache which is shared between threads.
+///
+/// The cache is split into shards which are locked separately, by page index, so threads reading
+/// different pages rarely have to wait for each other.
+struct SharedPageCache {
+    /// The shards, each of which holds the pages whose indices are the same modulo the number of
+    /// shards.
+    shards: Box<[Mutex<PageCache>]>,
 }
+impl SharedPageCache {
+    fn new(page_size: usize, capacity: usize) -> Self {
+        Self {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(PageCache::new(page_size, capacity / CACHE_SHARDS)))
+                .collect(),
+        }
+    }
+
+    /// Lock the shard holding the page at the given index.
+    fn shard(&self, page_idx: usize) -> MutexGuard<'_, PageCache> {
+        // The cache is never left half-changed, since pages are only inserted once they've been
+        // loaded, so it can still be used after a panic.
+        self.shards[page_idx % self.shards.len()]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
 
-#[derive(Copy, Clone, Debug)]
-enum TextEncoding {
-    Utf8,
-    Utf16Le,
-    Utf16Be,
+    /// Change how many bytes of pages the cache holds, across all of its shards.
+    fn set_capacity(&self, capacity: usize) {
+        for page_idx in 0..self.shards.len() {
+            self.shard(page_idx).set_capacity(capacity / CACHE_SHARDS);
+        }
+    }
+
+    /// Evict every page, and hold pages of `page_size` bytes from now on.
+    fn clear(&self, page_size: usize) {
+        for page_idx in 0..self.shards.len() {
+            self.shard(page_idx).clear(page_size);
+        }
+    }
+
+    /// Set whether every shard checks the pages it loads against checksums.
+    fn set_verify_checksums(&self, verify: bool) {
+        for page_idx in 0..self.shards.len() {
+            self.shard(page_idx).set_verify_checksums(verify);
+        }
+    }
 }
 
 struct PageCache {
     page_size: usize,
-    /// The entries in the cache.
-    ///
-    /// TODO This cache has no eviction policy and will grow without bound.
-    ///
-    /// # SAFETY
-    /// Each entry must always point to an address which starts a byte array of length
-    /// `self.page_size`.
-    entries: HashMap<usize, NonNull<u8>>,
+    /// How many bytes of pages to hold before evicting the least recently used ones.
+    capacity: usize,
+    /// The contents of each cached page, along with when it was last used.
+    entries: HashMap<usize, (Arc<[u8]>, u64)>,
+    /// The index of each cached page, keyed by when it was last used.
+    recency: BTreeMap<u64, usize>,
+    /// When the most recent use of a page was, counted in uses.
+    clock: u64,
+    /// How many pages have been evicted to make room for others.
+    evictions: u64,
+    /// The checksums of the pages the cache has held, if pages are checked against them when
+    /// they're loaded.
+    checksums: Option<checksum::PageChecksums>,
 }
 impl PageCache {
-    fn new(page_size: usize) -> Self {
+    fn new(page_size: usize, capacity: usize) -> Self {
         Self {
             page_size,
+            capacity,
             entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            evictions: 0,
+            checksums: None,
         }
     }
 
     /// Get the page at the given index, loading if required.
     ///
+    /// If loading the page takes the cache over its capacity, the least recently used pages are
+    /// evicted, though the cache always keeps the page it returns.
+    ///
     /// # Arguments
     /// * `page_idx`: The index number of the page being loaded.
     /// * `loader`: A function that reads into the given buffer the given page number.
@@ -155,17 +1405,437 @@ impl PageCache {
         &mut self,
         page_idx: usize,
         loader: impl FnOnce(&mut [u8], usize) -> Result<()>,
-    ) -> Result<&mut [u8]> {
-        let raw_ptr = match self.entries.entry(page_idx) {
-            hash_map::Entry::Occupied(slot) => slot.get().as_ptr(),
+    ) -> Result<&Arc<[u8]>> {
+        self.clock += 1;
+        match self.entries.entry(page_idx) {
+            hash_map::Entry::Occupied(mut slot) => {
+                let last_used = &mut slot.get_mut().1;
+                self.recency.remove(last_used);
+                *last_used = self.clock;
+            }
             hash_map::Entry::Vacant(slot) => {
-                let mut buffer = vec![0; self.page_size].into_boxed_slice();
+                let mut buffer = vec![0; self.page_size];
                 loader(&mut buffer, page_idx).context("Failed to read from buffer")?;
-                let ptr = Box::leak(buffer);
-                slot.insert(NonNull::from(ptr).cast::<u8>()).as_ptr()
+                if let Some(checksums) = &mut self.checksums {
+                    checksums.verify(page_idx, &buffer)?;
+                }
+                slot.insert((Arc::from(buffer), self.clock));
+            }
+        }
+        self.recency.insert(self.clock, page_idx);
+        // The page being returned was used last, so it isn't evicted.
+        self.evict();
+        Ok(&self
+            .entries
+            .get(&page_idx)
+            .expect("The page just used was evicted")
+            .0)
+    }
+
+    /// Store `contents` as the page at the given index, replacing any cached version.
+    fn put(&mut self, page_idx: usize, contents: &[u8]) {
+        if let Some(checksums) = &mut self.checksums {
+            checksums.record(page_idx, contents);
+        }
+        self.clock += 1;
+        if let Some((_, last_used)) = self
+            .entries
+            .insert(page_idx, (Arc::from(contents), self.clock))
+        {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.clock, page_idx);
+        self.evict();
+    }
+
+    /// Store `contents`, which were just loaded from the file, as the page at the given index,
+    /// checking them against the page's checksum first.
+    fn put_loaded(&mut self, page_idx: usize, contents: &[u8]) -> Result<()> {
+        if let Some(checksums) = &mut self.checksums {
+            checksums.verify(page_idx, contents)?;
+        }
+        self.put(page_idx, contents);
+        Ok(())
+    }
+
+    /// Change how many bytes of pages the cache holds, evicting pages if it now holds too many.
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Set whether to check the pages loaded against checksums, starting from the pages cached
+    /// now.
+    fn set_verify_checksums(&mut self, verify: bool) {
+        if verify == self.checksums.is_some() {
+            return;
+        }
+        self.checksums = verify.then(|| {
+            let mut checksums = checksum::PageChecksums::default();
+            for (&page_idx, (contents, _)) in &self.entries {
+                checksums.record(page_idx, contents);
             }
+            checksums
+        });
+    }
+
+    /// Evict the least recently used pages until the cache fits in its capacity, keeping at least
+    /// the most recently used page and any pinned pages.
+    fn evict(&mut self) {
+        let mut pinned = Vec::new();
+        while self.entries.len() * self.page_size > self.capacity {
+            let Some((last_used, page_idx)) = self.recency.pop_first() else {
+                break;
+            };
+            // Pages are pinned by holding onto another reference to them.
+            if last_used == self.clock || Arc::strong_count(&self.entries[&page_idx].0) > 1 {
+                pinned.push((last_used, page_idx));
+                continue;
+            }
+            self.entries.remove(&page_idx);
+            self.evictions += 1;
+        }
+        self.recency.extend(pinned);
+    }
+
+    /// Evict every page, and hold pages of `page_size` bytes from now on.
+    ///
+    /// Pages cleared out like this aren't counted as evictions. Their checksums are forgotten
+    /// too, since the cache is cleared when the file changes.
+    fn clear(&mut self, page_size: usize) {
+        *self = Self {
+            evictions: self.evictions,
+            checksums: self
+                .checksums
+                .as_ref()
+                .map(|_| checksum::PageChecksums::default()),
+            ..Self::new(page_size, self.capacity)
         };
-        // SAFETY: `self.entries` only contains pointers to pages of `self.page_size` size.
-        Ok(unsafe { std::slice::from_raw_parts_mut(raw_ptr, self.page_size) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::page::ParsedPage;
+
+    fn open_fixture(path: &str) -> Pager<Cursor<Vec<u8>>> {
+        let contents = fs::read(path).expect("Failed to read test database");
+        Pager::new(Cursor::new(contents)).expect("Failed to parse test database")
+    }
+
+    #[test]
+    fn test_write_and_flush() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let change_counter = pager.header.file_change_counter;
+        let page_size = pager.page_size();
+        let mut page = pager
+            .read_page(2)
+            .expect("Failed to read page")
+            .as_bytes()
+            .to_vec();
+        // Write a recognizable pattern into the unused region of the page.
+        page[page_size - 4..].copy_from_slice(b"riir");
+        pager.write_page(2, &page).expect("Failed to write page");
+        assert!(pager.is_dirty(), "Written page should be dirty");
+        assert_eq!(
+            pager.read_page(2).expect("Failed to read page").as_bytes(),
+            page,
+            "Reads should observe unflushed writes",
+        );
+        assert_eq!(
+            pager.file.get_ref()[2 * page_size - 4..2 * page_size],
+            [0; 4],
+            "Unflushed writes shouldn't reach the file",
+        );
+        pager.flush().expect("Failed to flush pager");
+        assert!(!pager.is_dirty(), "Flushed pager should be clean");
+
+        let contents = pager.file.into_inner();
+        assert_eq!(&contents[2 * page_size - 4..2 * page_size], b"riir");
+        let mut reopened = Pager::new(Cursor::new(contents)).expect("Failed to reopen database");
+        assert_eq!(
+            reopened.header.file_change_counter,
+            change_counter.wrapping_add(1),
+            "Flushing should bump the change counter",
+        );
+        assert_eq!(reopened.page_count(), 3);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_size = pager.page_size();
+        pager.set_cache_capacity(2 * page_size);
+        for page_idx in [1, 2, 3, 3, 2] {
+            pager.read_page(page_idx).expect("Failed to read page");
+        }
+        assert_eq!(pager.page_loads(), 3);
+        // Page 1 was used least recently, so it was evicted to make room for page 3.
+        pager.read_page(1).expect("Failed to read page");
+        assert_eq!(pager.page_loads(), 4);
+        pager.read_page(2).expect("Failed to read page");
+        assert_eq!(pager.page_loads(), 4);
+        pager.read_page(3).expect("Failed to read page");
+        assert_eq!(pager.page_loads(), 5);
+
+        // Even with no room, the page being read is kept until the next one is.
+        pager.set_cache_capacity(0);
+        assert_eq!(pager.page_cache.local.entries.len(), 1);
+        let page = pager
+            .read_page_bytes(2)
+            .expect("Failed to read page")
+            .to_vec();
+        assert_eq!(pager.read_page_bytes(2).unwrap(), page);
+        assert_eq!(pager.page_loads(), 6);
+    }
+
+    #[test]
+    fn test_pin_page() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_size = pager.page_size();
+        pager.set_cache_capacity(page_size);
+        let pinned = pager.pin_page(2).expect("Failed to pin page");
+        let contents = pinned.page().as_bytes().to_vec();
+        // The pinned page stays cached while other pages come and go.
+        for page_idx in [1, 3, 1] {
+            pager.read_page(page_idx).expect("Failed to read page");
+        }
+        assert!(pager.page_cache.local.entries.contains_key(&2));
+        assert_eq!(pager.page_loads(), 4, "Page 1 should have been evicted");
+        pager.read_page(2).expect("Failed to read page");
+        assert_eq!(pager.page_loads(), 4);
+
+        // Writes after pinning don't change the pinned copy.
+        let mut changed = pager.read_page_bytes(2).unwrap().to_vec();
+        // Count some fragmented free bytes, which leaves the page valid.
+        changed[7] = 3;
+        pager.write_page(2, &changed).expect("Failed to write page");
+        assert_eq!(pinned.page().as_bytes(), contents);
+        assert_eq!(pinned.page_idx(), 2);
+
+        // Once it's dropped, the page can be evicted.
+        drop(pinned);
+        pager.read_page(3).expect("Failed to read page");
+        pager.read_page(1).expect("Failed to read page");
+        assert!(!pager.page_cache.local.entries.contains_key(&2));
+
+        // Dirty pages are copied rather than pinned in the cache.
+        let pinned = pager.pin_page(2).expect("Failed to pin page");
+        assert_eq!(pinned.page().as_bytes(), &changed[..pager.usable_size()]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_size = pager.page_size();
+        pager.set_cache_capacity(page_size);
+        for page_idx in [1, 2] {
+            pager.read_page(page_idx).expect("Failed to read page");
+        }
+        let before = pager.stats();
+        for page_idx in [2, 1] {
+            pager.read_page(page_idx).expect("Failed to read page");
+        }
+        pager
+            .write_page(3, &vec![0; page_size])
+            .expect("Failed to write page");
+        pager.read_page_bytes(3).expect("Failed to read page");
+        assert_eq!(
+            pager.stats(),
+            PagerStats {
+                cache_hits: 2,
+                cache_misses: 3,
+                pages_read: 3,
+                bytes_read: 3 * page_size as u64,
+                evictions: 2,
+            },
+        );
+        assert_eq!(
+            pager.stats().since(&before),
+            PagerStats {
+                cache_hits: 2,
+                cache_misses: 1,
+                pages_read: 1,
+                bytes_read: page_size as u64,
+                evictions: 1,
+            },
+        );
+    }
+
+    #[test]
+    fn test_read_pages() {
+        let mut pager = open_fixture("test-data/many-tables.sqlite");
+        pager.read_page(3).expect("Failed to read page");
+        pager
+            .read_pages([2, 4, 3, 5, 9, 8])
+            .expect("Failed to read pages");
+        assert_eq!(
+            pager.page_loads(),
+            6,
+            "Cached pages shouldn't be loaded again"
+        );
+        assert_eq!(pager.stats().pages_read, 6);
+        for page_idx in [2, 3, 4, 5, 8, 9] {
+            pager.read_page(page_idx).expect("Failed to read page");
+        }
+        assert_eq!(pager.page_loads(), 6, "The pages should have been cached");
+
+        let mut copy = open_fixture("test-data/many-tables.sqlite");
+        for page_idx in [2, 4, 5, 8, 9] {
+            assert_eq!(
+                pager.read_page_bytes(page_idx).unwrap(),
+                copy.read_page_bytes(page_idx).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut pager = open_fixture("test-data/many-tables.sqlite");
+        pager.read_page(3).expect("Failed to read page");
+        pager
+            .write_page(5, &vec![0; pager.page_size()])
+            .expect("Failed to write page");
+        // Pages which are cached, dirty, or past the end of the file are skipped.
+        let page_count = pager.page_count();
+        assert_eq!(
+            pager.uncached_runs([7, 2, 3, 4, 5, 6, 2, page_count + 1]),
+            [2..3, 4..5, 6..8],
+        );
+        pager
+            .prefetch(1..=page_count)
+            .expect("Failed to read ahead");
+        assert_eq!(pager.page_loads(), 1, "Read-ahead shouldn't load pages");
+
+        let mut pager = Pager::new(fs::File::open("test-data/many-tables.sqlite").unwrap())
+            .expect("Failed to parse test database");
+        pager
+            .prefetch(1..=page_count)
+            .expect("Failed to read ahead in file");
+    }
+
+    #[test]
+    fn test_header() {
+        let mut pager = open_fixture("test-data/header-fields.sqlite");
+        let header = pager.header().expect("Failed to read header");
+        assert_eq!(header.page_size(), 1024);
+        assert_eq!(header.usable_size(), 1024);
+        assert_eq!(
+            header,
+            DatabaseHeader {
+                page_size_exp: 10,
+                write_version: 1,
+                read_version: 1,
+                reserved_bytes: 0,
+                payload_fractions: [64, 32, 32],
+                file_change_counter: 7,
+                page_count: 13,
+                first_freelist_trunk: 9,
+                freelist_count: 6,
+                schema_cookie: 1,
+                schema_format: 4,
+                default_cache_size: 500,
+                largest_root_page: 3,
+                text_encoding: TextEncoding::Utf8,
+                user_version: -7,
+                auto_vacuum: AutoVacuum::Incremental,
+                application_id: 1_234_567_890,
+                version_valid_for: 7,
+                sqlite_version: 3_040_001,
+            },
+        );
+        // Pages added by writes which haven't been flushed are counted.
+        pager
+            .write_page(14, &[0; 1024])
+            .expect("Failed to write page");
+        assert_eq!(
+            pager.header().expect("Failed to read header").page_count,
+            14
+        );
+    }
+
+    #[test]
+    fn test_write_grows_database() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_size = pager.page_size();
+        let new_page = vec![0; page_size];
+        assert!(
+            pager.write_page(5, &new_page).is_err(),
+            "Writing past the end should fail",
+        );
+        pager
+            .write_page(4, &new_page)
+            .expect("Failed to append page");
+        assert_eq!(pager.page_count(), 4);
+        pager.flush().expect("Failed to flush pager");
+        let contents = pager.file.into_inner();
+        assert_eq!(contents.len(), 4 * page_size);
+        let mut reopened = Pager::new(Cursor::new(contents)).expect("Failed to reopen database");
+        assert_eq!(reopened.page_count(), 4);
+    }
+
+    #[test]
+    fn test_create() {
+        for page_size in [512, 4096, 65536] {
+            let pager = Pager::create(Cursor::new(Vec::new()), page_size)
+                .expect("Failed to create database");
+            assert_eq!(pager.page_size(), page_size);
+            let contents = pager.file.into_inner();
+            assert_eq!(contents.len(), page_size);
+            let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse database");
+            assert_eq!(pager.page_count(), 1);
+            let page = pager.read_page(1).unwrap();
+            let ParsedPage::BTreeTableLeaf(leaf) = page.parse() else {
+                panic!("sqlite_schema should be a table leaf");
+            };
+            assert_eq!(leaf.num_cells(), 0);
+        }
+        assert!(Pager::create(Cursor::new(Vec::new()), 1000).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut pager = open_fixture("test-data/minimal-test.sqlite");
+        let page_size = pager.page_size();
+        pager
+            .write_page(4, &vec![1; page_size])
+            .expect("Failed to append page");
+        let depth = pager.open_savepoint();
+        pager.truncate(2).expect("Failed to truncate");
+        assert_eq!(pager.page_count(), 2);
+        assert!(pager.read_page_bytes(3).is_err());
+
+        pager
+            .rollback_to_savepoint(depth)
+            .expect("Failed to roll back");
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.read_page_bytes(4).unwrap(), vec![1; page_size]);
+        pager.truncate(2).expect("Failed to truncate");
+        pager.release_savepoint(depth).unwrap();
+        pager.flush().expect("Failed to flush pager");
+        let reopened = Pager::new(Cursor::new(pager.file.into_inner())).unwrap();
+        assert_eq!(reopened.header.page_count, 2);
+    }
+
+    #[test]
+    fn test_flush_with_journal() {
+        let journal_path =
+            std::env::temp_dir().join(format!("sqlite-riir-flush-journal-{}", std::process::id()));
+        let contents = fs::read("test-data/minimal-test.sqlite").expect("Failed to read");
+        let mut pager = Pager::with_journal(Cursor::new(contents), journal_path.clone())
+            .expect("Failed to parse test database");
+        let page_size = pager.page_size();
+        pager
+            .write_page(4, &vec![0; page_size])
+            .expect("Failed to append page");
+        pager.flush().expect("Failed to flush pager");
+        assert!(
+            !journal_path.exists(),
+            "Journal should be deleted after a successful flush",
+        );
+        assert_eq!(pager.file.into_inner().len(), 4 * page_size);
     }
 }
@@ -0,0 +1,114 @@
+//! Catching pages which change behind the pager's back, by checking them against checksums
+//!
+//! The pager assumes that the database only changes when it writes to it, or when another
+//! connection commits, which it notices through the file change counter and clears its cache for.
+//! Anything else, like another program writing to the file or the storage flipping bits, goes
+//! unnoticed. When checksums are verified, the page cache keeps a checksum of every page it loads
+//! or writes, even after evicting the page, and fails to load a page again if it no longer
+//! matches.
+
+use std::collections::{hash_map, HashMap};
+
+use anyhow::Result;
+
+use super::{wal::wal_checksum, Pager};
+
+/// The checksums of the pages a page cache has held.
+#[derive(Default)]
+pub(super) struct PageChecksums {
+    /// The checksum of each page, as of when it was last loaded or written
+    checksums: HashMap<usize, [u32; 2]>,
+}
+impl PageChecksums {
+    /// Check that `contents`, which were just loaded from the file, match the checksum of the
+    /// page as it was when it was last loaded or written, if it has been before.
+    pub(super) fn verify(&mut self, page_idx: usize, contents: &[u8]) -> Result<()> {
+        let checksum = checksum(contents);
+        match self.checksums.entry(page_idx) {
+            hash_map::Entry::Occupied(entry) => anyhow::ensure!(
+                *entry.get() == checksum,
+                "Page {page_idx} changed since it was last read, so the database file was \
+                 modified by something else or is corrupt"
+            ),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(checksum);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that the page now holds `contents`.
+    pub(super) fn record(&mut self, page_idx: usize, contents: &[u8]) {
+        self.checksums.insert(page_idx, checksum(contents));
+    }
+}
+
+/// Compute the checksum of a page, the same way as for frames in the WAL.
+fn checksum(contents: &[u8]) -> [u32; 2] {
+    wal_checksum([0, 0], contents, false)
+}
+
+impl<File> Pager<File> {
+    /// Get whether pages are checked against checksums when they're loaded again.
+    #[must_use]
+    pub fn verify_checksums(&self) -> bool {
+        self.page_cache.local.checksums.is_some()
+    }
+
+    /// Set whether to keep checksums of the pages which have been loaded, and check pages
+    /// against them when they're loaded again, failing if they've changed.
+    ///
+    /// This catches changes to the database file which the pager wouldn't otherwise notice, like
+    /// writes by programs other than SQLite, at the cost of hashing every page loaded or written.
+    /// Pages read from a memory map of the file are never checked.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.page_cache.set_verify_checksums(verify);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn test_verify_checksums() {
+        let contents = fs::read("test-data/many-tables.sqlite").unwrap();
+        let mut pager = Pager::new(Cursor::new(contents)).unwrap();
+        pager.set_verify_checksums(true);
+        assert!(pager.verify_checksums());
+        let page_size = pager.page_size();
+        pager.set_cache_capacity(page_size);
+        pager.read_page_bytes(2).unwrap();
+        pager.read_page_bytes(3).unwrap();
+
+        // Writes by the pager itself change the checksum along with the page.
+        let mut changed = pager.read_page_bytes(3).unwrap().to_vec();
+        changed[100] ^= 1;
+        pager.write_page(3, &changed).unwrap();
+        pager.flush().unwrap();
+        pager.read_page_bytes(2).unwrap();
+        pager.read_page_bytes(3).unwrap();
+
+        // Page 2 was evicted, so changing it in the file is caught when it's loaded again.
+        let offset = page_size + 100;
+        pager.file.get_mut()[offset] ^= 1;
+        pager.read_page_bytes(4).unwrap();
+        let error = pager.read_page_bytes(2).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Page 2 changed"),
+            "Unexpected error {error:#}"
+        );
+        pager.file.get_mut()[offset] ^= 1;
+        pager
+            .read_page(2)
+            .expect("The page should match once it's restored");
+
+        // Pages aren't checked once it's turned off.
+        pager.set_verify_checksums(false);
+        pager.file.get_mut()[offset] ^= 1;
+        pager.read_page_bytes(4).unwrap();
+        pager.read_page_bytes(2).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Transforming pages on their way to and from storage, like encrypting them
+//!
+//! As with SQLite's codec hooks, which encryption extensions like SEE are built on, a
+//! [`PageCodec`] sees every page the pager reads from the database, its journal, or its WAL
+//! just after the read, and every page it writes just before the write. Everything in memory,
+//! including the page cache, holds decoded pages. Codecs which need to store something alongside
+//! each page, like a nonce or a MAC, keep it in the bytes reserved at the end of each page.
+//!
+//! The pager has to read the page size from the database header before it can read the first
+//! page to decode it, so codecs must leave bytes 16 to 23 of the first page as they are, as SEE
+//! does.
+
+use std::{borrow::Cow, io::Read, sync::Arc};
+
+use anyhow::{Context, Result};
+
+use super::{DatabaseHeader, Pager, DATABASE_HEADER_SIZE};
+
+/// A transformation applied to pages as they're written to storage, and undone as they're read.
+pub trait PageCodec: Send + Sync {
+    /// Get how many bytes the codec needs reserved at the end of each page.
+    ///
+    /// Databases opened with the codec must reserve at least this many bytes.
+    fn reserved_bytes(&self) -> u8;
+
+    /// Turn `page`, the page at `page_idx` as it was read from storage, back into its contents.
+    ///
+    /// # Errors
+    /// If the page can't be decoded, like if it fails authentication.
+    fn decode(&self, page_idx: usize, page: &mut [u8]) -> Result<()>;
+
+    /// Turn `page`, the contents of the page at `page_idx`, into what's written to storage.
+    ///
+    /// # Errors
+    /// If the page can't be encoded.
+    fn encode(&self, page_idx: usize, page: &mut [u8]) -> Result<()>;
+}
+
+/// Encode the page at `page_idx` with `codec`, if there is one, for writing it to storage.
+pub(super) fn encoded<'a>(
+    codec: Option<&dyn PageCodec>,
+    page_idx: usize,
+    contents: &'a [u8],
+) -> Result<Cow<'a, [u8]>> {
+    let Some(codec) = codec else {
+        return Ok(Cow::Borrowed(contents));
+    };
+    let mut page = contents.to_vec();
+    codec
+        .encode(page_idx, &mut page)
+        .with_context(|| format!("Failed to encode page {page_idx}"))?;
+    Ok(Cow::Owned(page))
+}
+
+/// Read the database header from the start of `file`, decoding the first page with `codec` if
+/// there is one.
+pub(super) fn read_header(
+    file: &mut impl Read,
+    codec: Option<&dyn PageCodec>,
+) -> Result<DatabaseHeader> {
+    let mut buf = [0; DATABASE_HEADER_SIZE];
+    file.read_exact(&mut buf)
+        .context("Error reading database header from file")?;
+    let Some(codec) = codec else {
+        return DatabaseHeader::parse(&buf);
+    };
+    // The page size is left as it is by the codec, so the rest of the first page can be read.
+    let page_size = match u16::from_be_bytes([buf[16], buf[17]]) {
+        1 => 65536,
+        size => usize::from(size),
+    };
+    anyhow::ensure!(
+        page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+        "Invalid page size {page_size}"
+    );
+    let mut first_page = buf.to_vec();
+    first_page.resize(page_size, 0);
+    file.read_exact(&mut first_page[DATABASE_HEADER_SIZE..])
+        .context("Error reading first page from file")?;
+    codec
+        .decode(1, &mut first_page)
+        .context("Failed to decode page 1")?;
+    let header = DatabaseHeader::parse(first_page[..DATABASE_HEADER_SIZE].try_into().unwrap())?;
+    anyhow::ensure!(
+        header.page_size() == page_size,
+        "The decoded page size doesn't match the encoded one"
+    );
+    Ok(header)
+}
+
+impl<File: Read> Pager<File> {
+    /// Construct a new pager over the given file, whose pages are encoded with `codec`, and
+    /// whose writes are protected by a rollback journal at `journal_path` if it's given.
+    ///
+    /// We assume that the file is currently at the beginning, this function may behave
+    /// unexpectedly otherwise.
+    pub fn with_codec(
+        file: File,
+        journal_path: Option<std::path::PathBuf>,
+        codec: Arc<dyn PageCodec>,
+    ) -> crate::Result<Self> {
+        let pager = Self::open(file, Some(codec))?;
+        let reserved = pager.header.page_size() - pager.header.usable_size();
+        let needed = pager
+            .codec
+            .as_ref()
+            .map_or(0, |codec| codec.reserved_bytes());
+        if reserved < usize::from(needed) {
+            return Err(crate::Error::corrupt(
+                Some(1),
+                format!(
+                    "The codec needs {needed} reserved bytes per page, but the database reserves \
+                     {reserved}"
+                ),
+            ));
+        }
+        Ok(Self {
+            journal_path,
+            ..pager
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A codec which flips every bit of the page but the ones it must leave alone, and keeps the
+    /// page's index in its last reserved byte, to check it's given the right index.
+    struct FlipCodec;
+    impl FlipCodec {
+        fn flip(page_idx: usize, page: &mut [u8]) {
+            for (idx, byte) in page.iter_mut().enumerate() {
+                if page_idx != 1 || !(16..24).contains(&idx) {
+                    *byte = !*byte;
+                }
+            }
+        }
+    }
+    impl PageCodec for FlipCodec {
+        fn reserved_bytes(&self) -> u8 {
+            1
+        }
+
+        fn decode(&self, page_idx: usize, page: &mut [u8]) -> Result<()> {
+            Self::flip(page_idx, page);
+            anyhow::ensure!(
+                usize::from(*page.last().unwrap()) == page_idx % 256,
+                "Page {page_idx} was read from the wrong place"
+            );
+            Ok(())
+        }
+
+        fn encode(&self, page_idx: usize, page: &mut [u8]) -> Result<()> {
+            *page.last_mut().unwrap() = page_idx as u8;
+            Self::flip(page_idx, page);
+            Ok(())
+        }
+    }
+
+    /// Create an empty database in memory which reserves a byte at the end of each page, with
+    /// its first page encoded by [`FlipCodec`].
+    fn encoded_database(page_size: usize) -> Vec<u8> {
+        let mut contents = Pager::create(Cursor::new(Vec::new()), page_size)
+            .unwrap()
+            .into_inner()
+            .into_inner();
+        contents[20] = 1;
+        // The cell content area starts at the end of the usable part of the page.
+        contents[DATABASE_HEADER_SIZE + 5..DATABASE_HEADER_SIZE + 7]
+            .copy_from_slice(&(page_size as u16 - 1).to_be_bytes());
+        FlipCodec.encode(1, &mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_codec() {
+        let contents = encoded_database(512);
+        assert!(Pager::new(Cursor::new(contents.clone())).is_err());
+        let mut pager =
+            Pager::with_codec(Cursor::new(contents), None, Arc::new(FlipCodec)).unwrap();
+        assert_eq!(pager.usable_size(), 511);
+        let mut page = vec![0; 512];
+        page[..7].copy_from_slice(b"encoded");
+        pager.write_page(2, &page).unwrap();
+        pager.flush().unwrap();
+
+        let contents = pager.into_inner().into_inner();
+        assert_eq!(contents.len(), 1024);
+        assert!(
+            !contents.windows(7).any(|window| window == b"encoded"),
+            "Written pages should be encoded"
+        );
+        let mut pager =
+            Pager::with_codec(Cursor::new(contents), None, Arc::new(FlipCodec)).unwrap();
+        assert_eq!(pager.page_count(), 2);
+        assert_eq!(pager.read_page_bytes(2).unwrap()[..511], page[..511]);
+        pager
+            .read_page(1)
+            .expect("The first page should be decoded");
+    }
+
+    #[test]
+    fn test_codec_needs_reserved_bytes() {
+        let contents = Pager::create(Cursor::new(Vec::new()), 512)
+            .unwrap()
+            .into_inner()
+            .into_inner();
+        /// A codec which changes nothing, but needs space for a MAC.
+        struct MacCodec;
+        impl PageCodec for MacCodec {
+            fn reserved_bytes(&self) -> u8 {
+                16
+            }
+
+            fn decode(&self, _page_idx: usize, _page: &mut [u8]) -> Result<()> {
+                Ok(())
+            }
+
+            fn encode(&self, _page_idx: usize, _page: &mut [u8]) -> Result<()> {
+                Ok(())
+            }
+        }
+        let error = Pager::with_codec(Cursor::new(contents), None, Arc::new(MacCodec))
+            .err()
+            .expect("The database reserves no bytes for the codec");
+        assert!(
+            format!("{error:#}").contains("needs 16 reserved bytes"),
+            "Unexpected error {error:#}"
+        );
+    }
+}
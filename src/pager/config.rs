@@ -0,0 +1,138 @@
+//! Settings for how the pager behaves, which can be given when opening a database
+
+use std::time::Duration;
+
+use super::{Pager, SyncPolicy};
+
+/// How much the page cache holds, as set by `PRAGMA cache_size`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheSize {
+    /// Hold this many pages.
+    Pages(u64),
+    /// Hold as many pages as fit in this many KiB.
+    Kibibytes(u64),
+}
+impl Default for CacheSize {
+    /// SQLite's default, 2000 KiB.
+    fn default() -> Self {
+        Self::Kibibytes(2000)
+    }
+}
+impl CacheSize {
+    /// Parse the value given to `PRAGMA cache_size`, which is a number of pages if it's positive,
+    /// or a number of KiB if it's negative.
+    #[must_use]
+    pub fn from_pragma(value: i64) -> Self {
+        if value >= 0 {
+            Self::Pages(value.unsigned_abs())
+        } else {
+            Self::Kibibytes(value.unsigned_abs())
+        }
+    }
+
+    /// Get the number `PRAGMA cache_size` reports for this size.
+    #[must_use]
+    pub fn as_pragma(self) -> i64 {
+        match self {
+            Self::Pages(pages) => i64::try_from(pages).unwrap_or(i64::MAX),
+            Self::Kibibytes(kibibytes) => i64::try_from(kibibytes).map_or(i64::MIN, |kib| -kib),
+        }
+    }
+
+    /// Get how many bytes of pages this is, for pages of `page_size` bytes.
+    #[must_use]
+    pub fn bytes(self, page_size: usize) -> usize {
+        let bytes = match self {
+            Self::Pages(pages) => pages.saturating_mul(page_size as u64),
+            Self::Kibibytes(kibibytes) => kibibytes.saturating_mul(1024),
+        };
+        usize::try_from(bytes).unwrap_or(usize::MAX)
+    }
+}
+
+/// Settings for a pager, as given to [`Database::open_with`](crate::Database::open_with) in its
+/// [`OpenOptions`](crate::OpenOptions).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PagerConfig {
+    /// How much the page cache holds.
+    pub cache_size: CacheSize,
+    /// How carefully commits are synced to persistent storage.
+    pub sync_policy: SyncPolicy,
+    /// Whether to check pages against checksums when they're loaded again, as set by
+    /// [`Pager::set_verify_checksums`].
+    pub verify_checksums: bool,
+    /// Whether to only read the database, so that statements which would change it fail.
+    pub read_only: bool,
+    /// How long to keep trying to lock the database while another connection holds a conflicting
+    /// lock, as set by [`Pager::set_busy_timeout`].
+    pub busy_timeout: Duration,
+}
+
+impl<File> Pager<File> {
+    /// Apply the settings in `config`.
+    pub fn configure(&mut self, config: &PagerConfig) {
+        self.set_cache_size(config.cache_size);
+        self.set_sync_policy(config.sync_policy);
+        self.set_verify_checksums(config.verify_checksums);
+        self.set_busy_timeout(config.busy_timeout);
+        // Readers from `Self::reader` share their cache, so they have to stay read-only.
+        self.read_only |= config.read_only;
+    }
+
+    /// Get whether this pager only reads the database, as set by [`PagerConfig::read_only`] and
+    /// for readers opened with [`Self::reader`].
+    #[must_use]
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Get how much the page cache holds, as it was last set.
+    #[must_use]
+    pub fn cache_size(&self) -> CacheSize {
+        self.cache_size
+    }
+
+    /// Set how much the page cache holds, evicting pages if it now holds too many.
+    ///
+    /// See [`Self::set_cache_capacity`].
+    pub fn set_cache_size(&mut self, cache_size: CacheSize) {
+        self.set_cache_capacity(cache_size.bytes(self.page_size()));
+        self.cache_size = cache_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn test_cache_size() {
+        for value in [0, 100, -100, i64::MIN, i64::MAX] {
+            assert_eq!(CacheSize::from_pragma(value).as_pragma(), value);
+        }
+        assert_eq!(CacheSize::default().bytes(4096), 2000 * 1024);
+        assert_eq!(CacheSize::Pages(10).bytes(1024), 10 * 1024);
+        assert_eq!(CacheSize::Kibibytes(u64::MAX).bytes(1024), usize::MAX);
+
+        let contents = fs::read("test-data/many-tables.sqlite").unwrap();
+        let mut pager = Pager::new(Cursor::new(contents)).unwrap();
+        assert_eq!(pager.cache_size(), CacheSize::default());
+        pager.configure(&PagerConfig {
+            cache_size: CacheSize::Pages(2),
+            sync_policy: SyncPolicy::Off,
+            verify_checksums: true,
+            read_only: false,
+            busy_timeout: Duration::from_millis(10),
+        });
+        assert_eq!(pager.cache_capacity(), 2 * pager.page_size());
+        assert_eq!(pager.sync_policy(), SyncPolicy::Off);
+        assert!(pager.verify_checksums());
+        assert_eq!(pager.busy_timeout(), Duration::from_millis(10));
+        for page_idx in 1..=4 {
+            pager.read_page_bytes(page_idx).unwrap();
+        }
+        assert_eq!(pager.stats().evictions, 2);
+    }
+}
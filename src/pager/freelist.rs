@@ -0,0 +1,176 @@
+//! Tracking unused pages, so they can be reused
+//!
+//! The freelist is a linked list of trunk pages, starting from the one named in the database
+//! header. Each trunk page holds the index of the next trunk, then the number of leaves it has,
+//! then the indices of those leaves. The leaves are free pages whose contents don't matter.
+
+use std::io::{Read, Seek};
+
+use anyhow::Result;
+
+use super::{ptrmap::PtrmapEntry, Pager};
+use crate::Error;
+
+/// The offset in the database header of the index of the first freelist trunk page.
+const FIRST_TRUNK_OFFSET: usize = 32;
+/// The offset in the database header of the total number of freelist pages.
+const FREELIST_COUNT_OFFSET: usize = 36;
+
+impl<File: Read + Seek> Pager<File> {
+    /// Get a zeroed page to put new contents in, returning its index.
+    ///
+    /// This reuses a page from the freelist if there is one, and otherwise adds a page to the end
+    /// of the database.
+    pub fn allocate_page(&mut self) -> crate::Result<usize> {
+        let (first_trunk, free_count) = self.freelist_head()?;
+        let page_idx = if first_trunk == 0 {
+            let mut page_idx = self.header.page_count as usize + 1;
+            if self.is_lock_byte_page(page_idx) {
+                // Like SQLite, the lock-byte page is counted in the database's size, but is never
+                // written to.
+                self.header.page_count += 1;
+                page_idx += 1;
+            }
+            if self.is_ptrmap_page(page_idx) {
+                // The pointer map page has to exist before the pages it covers.
+                self.write_page(page_idx, &vec![0; self.page_size()])?;
+                page_idx + 1
+            } else {
+                page_idx
+            }
+        } else {
+            let mut trunk = self.read_page_bytes(first_trunk)?.to_vec();
+            let leaf_count = read_u32(&trunk, 4) as usize;
+            if leaf_count == 0 {
+                // The trunk itself is reused, with the next trunk taking its place.
+                self.set_freelist_head(read_u32(&trunk, 0) as usize, free_count - 1)?;
+                first_trunk
+            } else {
+                let leaf = read_u32(&trunk, 4 + 4 * leaf_count) as usize;
+                trunk[4..8].copy_from_slice(&(leaf_count as u32 - 1).to_be_bytes());
+                self.write_page(first_trunk, &trunk)?;
+                self.set_freelist_head(first_trunk, free_count - 1)?;
+                leaf
+            }
+        };
+        self.write_page(page_idx, &vec![0; self.page_size()])?;
+        Ok(page_idx)
+    }
+
+    /// Add a page which is no longer in use to the freelist.
+    pub fn free_page(&mut self, page_idx: usize) -> crate::Result<()> {
+        if !(2..=self.header.page_count as usize).contains(&page_idx)
+            || self.is_ptrmap_page(page_idx)
+            || self.is_lock_byte_page(page_idx)
+        {
+            return Err(Error::Other(format!("Cannot free page {page_idx}")));
+        }
+        self.set_ptrmap(page_idx, PtrmapEntry::FreePage)?;
+        let (first_trunk, free_count) = self.freelist_head()?;
+        if first_trunk != 0 {
+            let mut trunk = self.read_page_bytes(first_trunk)?.to_vec();
+            let leaf_count = read_u32(&trunk, 4) as usize;
+            // SQLite versions before 3.6.0 mishandle trunks which are any fuller than this.
+            if leaf_count < self.usable_size() / 4 - 8 {
+                let offset = 8 + 4 * leaf_count;
+                trunk[offset..offset + 4].copy_from_slice(&(page_idx as u32).to_be_bytes());
+                trunk[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                self.write_page(first_trunk, &trunk)?;
+                return Ok(self.set_freelist_head(first_trunk, free_count + 1)?);
+            }
+        }
+        // The page becomes a new trunk at the start of the list.
+        let mut trunk = vec![0; self.page_size()];
+        trunk[..4].copy_from_slice(&(first_trunk as u32).to_be_bytes());
+        self.write_page(page_idx, &trunk)?;
+        Ok(self.set_freelist_head(page_idx, free_count + 1)?)
+    }
+
+    /// Get the index of every page in the freelist, trunks included.
+    pub(super) fn free_pages(&mut self) -> Result<Vec<usize>> {
+        let (mut trunk_idx, free_count) = self.freelist_head()?;
+        let mut pages = Vec::with_capacity(free_count as usize);
+        while trunk_idx != 0 {
+            anyhow::ensure!(
+                pages.len() < free_count as usize,
+                Error::corrupt(Some(1), "Freelist is longer than the database header says")
+            );
+            pages.push(trunk_idx);
+            let trunk = self.read_page_bytes(trunk_idx)?;
+            let leaf_count = read_u32(trunk, 4) as usize;
+            pages.extend((0..leaf_count).map(|idx| read_u32(trunk, 8 + 4 * idx) as usize));
+            trunk_idx = read_u32(trunk, 0) as usize;
+        }
+        Ok(pages)
+    }
+
+    /// Get the index of the first trunk page (or 0 if there are none), and the total number of
+    /// pages in the freelist.
+    fn freelist_head(&mut self) -> Result<(usize, u32)> {
+        let header = self.read_page_bytes(1)?;
+        Ok((
+            read_u32(header, FIRST_TRUNK_OFFSET) as usize,
+            read_u32(header, FREELIST_COUNT_OFFSET),
+        ))
+    }
+
+    /// Store the freelist's first trunk page and page count in the database header.
+    pub(super) fn set_freelist_head(&mut self, first_trunk: usize, free_count: u32) -> Result<()> {
+        let mut page = self.read_page_bytes(1)?.to_vec();
+        page[FIRST_TRUNK_OFFSET..FIRST_TRUNK_OFFSET + 4]
+            .copy_from_slice(&(first_trunk as u32).to_be_bytes());
+        page[FREELIST_COUNT_OFFSET..FREELIST_COUNT_OFFSET + 4]
+            .copy_from_slice(&free_count.to_be_bytes());
+        Ok(self.write_page(1, &page)?)
+    }
+}
+
+/// Read the big-endian `u32` at `offset` in `buffer`.
+pub(super) fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_free_and_reuse() {
+        let contents = std::fs::read("test-data/minimal-test.sqlite").unwrap();
+        let mut pager = Pager::new(Cursor::new(contents)).expect("Failed to parse test database");
+        let page_count = pager.page_count();
+        let max_leaves = pager.page_size() / 4 - 8;
+        // Enough pages to need a second trunk
+        let freed = (0..max_leaves + 3)
+            .map(|_| pager.allocate_page().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pager.page_count(), page_count + freed.len());
+        for &page_idx in &freed {
+            pager
+                .write_page(page_idx, &vec![0xff; pager.page_size()])
+                .unwrap();
+            pager.free_page(page_idx).expect("Failed to free page");
+        }
+        assert_eq!(pager.freelist_head().unwrap().1 as usize, freed.len());
+        assert!(pager.free_page(1).is_err(), "Page 1 can't be freed");
+
+        let mut reused = (0..freed.len())
+            .map(|_| pager.allocate_page().unwrap())
+            .collect::<Vec<_>>();
+        reused.sort_unstable();
+        assert_eq!(reused, freed, "Every freed page should be reused");
+        assert_eq!(pager.freelist_head().unwrap(), (0, 0));
+        assert_eq!(pager.page_count(), page_count + freed.len());
+        assert!(
+            pager
+                .read_page_bytes(freed[0])
+                .unwrap()
+                .iter()
+                .all(|&b| b == 0),
+            "Reused pages should be zeroed",
+        );
+        assert_eq!(pager.allocate_page().unwrap(), page_count + freed.len() + 1);
+    }
+}
@@ -0,0 +1,623 @@
+//! Reading databases hosted on a web server, like object storage, with HTTP range requests
+//!
+//! Only the parts of the file which are read are fetched, in chunks which are kept for as long as
+//! the file is open, since a remote database is slow to read but can't change under a reader.
+//! Reads which carry on from the last one fetch twice as far ahead each time, so scans need few
+//! requests while lookups don't fetch much they don't use.
+//!
+//! Connections are made with plain HTTP/1.1, so only `http://` URLs are supported, though
+//! redirects to other `http://` URLs are followed. Every connection, read and write gives up after
+//! a timeout, so an unresponsive server makes reads fail rather than hang.
+//!
+//! TODO Support `https://` URLs.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::{LockKind, Storage, SyncFile};
+
+/// The size of each chunk of the file which is fetched and cached.
+const CHUNK_SIZE: u64 = 16 * 1024;
+/// The most chunks to fetch in one request when reading ahead.
+const MAX_READ_AHEAD_CHUNKS: u64 = 64;
+/// The most redirects to follow for one request.
+const MAX_REDIRECTS: usize = 5;
+
+/// A read-only database file fetched over HTTP.
+///
+/// This can be opened as a database with [`Database::new`](crate::Database::new), without a
+/// journal, and fails any writes to it.
+pub struct HttpFile {
+    /// The host and port to connect to, as given in the URL
+    authority: String,
+    /// The path of the file on the server
+    path: String,
+    /// How long connecting, sending a request, or waiting for more of a response can take
+    timeout: Duration,
+    /// The connection to the server, which is kept open between requests
+    connection: Option<BufReader<TcpStream>>,
+    /// The length of the file
+    len: u64,
+    /// The position reads start from
+    position: u64,
+    /// The chunks of the file which have been fetched, keyed by their index
+    chunks: HashMap<u64, Box<[u8]>>,
+    /// The index of the chunk after the last one fetched, which sequential reads continue from
+    next_chunk: u64,
+    /// How many chunks to fetch in the next request, if it continues from the last one
+    read_ahead: u64,
+}
+
+/// What the server answered a request with.
+enum Response {
+    /// The body, along with the length of the whole file if the server sent only the range
+    Body(Option<u64>, Vec<u8>),
+    /// The URL the file has moved to
+    Redirect(String),
+}
+
+impl HttpFile {
+    /// How long connecting, sending a request, or waiting for more of a response can take, unless
+    /// the file is opened with [`Self::open_with_timeout`].
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Open the file at the given `http://` URL, giving up on the server after
+    /// [`Self::DEFAULT_TIMEOUT`].
+    ///
+    /// # Errors
+    /// If the URL isn't supported, or the file can't be fetched.
+    pub fn open(url: &str) -> io::Result<Self> {
+        Self::open_with_timeout(url, Self::DEFAULT_TIMEOUT)
+    }
+
+    /// Open the file at the given `http://` URL, giving up on connecting, sending a request, or
+    /// waiting for more of a response after `timeout`.
+    ///
+    /// # Errors
+    /// If the URL isn't supported, or the file can't be fetched.
+    pub fn open_with_timeout(url: &str, timeout: Duration) -> io::Result<Self> {
+        let (authority, path) = parse_url(url)?;
+        let mut file = Self {
+            authority,
+            path,
+            timeout,
+            connection: None,
+            len: 0,
+            position: 0,
+            chunks: HashMap::new(),
+            next_chunk: 0,
+            read_ahead: 1,
+        };
+        // Fetching the first chunk gives the length of the file, and the database header.
+        file.fetch_chunks(0, 1)?;
+        Ok(file)
+    }
+
+    /// Fetch `count` chunks starting at the chunk with index `first`, stopping at the end of the
+    /// file.
+    fn fetch_chunks(&mut self, first: u64, count: u64) -> io::Result<()> {
+        let start = first * CHUNK_SIZE;
+        let end = (first + count) * CHUNK_SIZE;
+        let (total_len, body) = self.get_range(start, end)?;
+        // Servers which don't support ranges send the whole file, which is cached all the same.
+        let body_start = if total_len.is_some() { start } else { 0 };
+        self.len = total_len.unwrap_or(body.len() as u64);
+        for (idx, chunk) in body.chunks(CHUNK_SIZE as usize).enumerate() {
+            self.chunks
+                .insert(body_start / CHUNK_SIZE + idx as u64, chunk.into());
+        }
+        self.next_chunk = first + count;
+        Ok(())
+    }
+
+    /// Request the bytes from `start` up to `end`, following any redirects.
+    ///
+    /// Returns the body, along with the length of the whole file if the server sent only the
+    /// range.
+    fn get_range(&mut self, start: u64, end: u64) -> io::Result<(Option<u64>, Vec<u8>)> {
+        for _ in 0..=MAX_REDIRECTS {
+            match self.request_range(start, end)? {
+                Response::Body(total_len, body) => return Ok((total_len, body)),
+                Response::Redirect(location) => {
+                    // Later requests go straight to where the file is now.
+                    if location.starts_with('/') {
+                        self.path = location;
+                    } else {
+                        let (authority, path) = parse_url(&location)?;
+                        if authority != self.authority {
+                            self.connection = None;
+                        }
+                        self.authority = authority;
+                        self.path = path;
+                    }
+                }
+            }
+        }
+        Err(invalid_response("Too many redirects"))
+    }
+
+    /// Make a request for [`Self::get_range`], retrying once on a new connection if the kept one
+    /// was closed.
+    fn request_range(&mut self, start: u64, end: u64) -> io::Result<Response> {
+        if self.connection.is_some() {
+            if let Ok(response) = self.try_request_range(start, end) {
+                return Ok(response);
+            }
+            self.connection = None;
+        }
+        let response = self.try_request_range(start, end);
+        if response.is_err() {
+            // Whatever's left of the response can't be told apart from the next one.
+            self.connection = None;
+        }
+        response
+    }
+
+    /// Make a single attempt at [`Self::request_range`].
+    fn try_request_range(&mut self, start: u64, end: u64) -> io::Result<Response> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self
+                .connection
+                .insert(BufReader::new(connect(&self.authority, self.timeout)?)),
+        };
+        // The request is sent in one write, since writing it piece by piece waits on each piece
+        // to be acknowledged.
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={start}-{}\r\n\r\n",
+            self.path,
+            self.authority,
+            end - 1,
+        );
+        connection.get_mut().write_all(request.as_bytes())?;
+        let mut status_line = String::new();
+        connection.read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| invalid_response("Missing status code"))?;
+        let mut content_len = None;
+        let mut total_len = None;
+        let mut chunked = false;
+        let mut location = None;
+        let mut keep_alive = true;
+        loop {
+            let mut line = String::new();
+            connection.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(invalid_response("Malformed header"));
+            };
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    content_len = Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|e| invalid_response(&e.to_string()))?,
+                    );
+                }
+                // Formatted like `bytes 0-1023/4096`
+                "content-range" => {
+                    total_len = value
+                        .rsplit_once('/')
+                        .and_then(|(_, total)| total.parse::<u64>().ok());
+                }
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "location" => location = Some(value.to_owned()),
+                "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+        // A range response can't be any longer than the range, and a whole file is read as it
+        // arrives, so a bad length can't make this allocate more than is actually sent.
+        let max_len = if status == "206" {
+            end - start
+        } else {
+            u64::MAX
+        };
+        let body = if chunked {
+            read_chunked_body(connection, max_len)?
+        } else {
+            let content_len =
+                content_len.ok_or_else(|| invalid_response("Missing Content-Length"))?;
+            if content_len > max_len {
+                return Err(invalid_response(
+                    "The server sent more than it was asked for",
+                ));
+            }
+            read_body(connection, content_len)?
+        };
+        if !keep_alive {
+            self.connection = None;
+        }
+        match status {
+            "206" => Ok(Response::Body(
+                Some(total_len.ok_or_else(|| invalid_response("Missing Content-Range"))?),
+                body,
+            )),
+            "200" => Ok(Response::Body(None, body)),
+            // Ranges past the end of the file are answered with the file's length.
+            "416" => Ok(Response::Body(total_len, Vec::new())),
+            "301" | "302" | "303" | "307" | "308" => Ok(Response::Redirect(
+                location.ok_or_else(|| invalid_response("Missing Location"))?,
+            )),
+            _ => Err(io::Error::other(format!(
+                "Request for {} failed: {}",
+                self.path,
+                status_line.trim_end()
+            ))),
+        }
+    }
+}
+
+/// Split an `http://` URL into its authority and path.
+fn parse_url(url: &str) -> io::Result<(String, String)> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Only `http://` URLs are supported, not {url}"),
+        )
+    })?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |idx| rest.split_at(idx));
+    Ok((authority.to_owned(), path.to_owned()))
+}
+
+/// Connect to the server at `authority`, trying each of its addresses in turn, with every read
+/// and write on the connection giving up after `timeout`.
+fn connect(authority: &str, timeout: Duration) -> io::Result<TcpStream> {
+    // Ports can be left out of URLs, but not addresses.
+    let addrs = if authority.contains(':') {
+        authority.to_socket_addrs()
+    } else {
+        (authority, 80).to_socket_addrs()
+    }?;
+    let mut last_error = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No addresses found for {authority}"),
+    );
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Read a body of `len` bytes.
+fn read_body(connection: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    connection.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Err(invalid_response(
+            "The connection closed partway through the body",
+        ));
+    }
+    Ok(body)
+}
+
+/// Read a body sent with `Transfer-Encoding: chunked`, failing if it's longer than `max_len`.
+fn read_chunked_body(connection: &mut impl BufRead, max_len: u64) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        connection.read_line(&mut line)?;
+        // Each chunk starts with its length in hex, which may be followed by extensions.
+        let len = line.split(';').next().unwrap_or_default().trim();
+        let len = u64::from_str_radix(len, 16)
+            .map_err(|e| invalid_response(&format!("Malformed chunk length: {e}")))?;
+        if len == 0 {
+            break;
+        }
+        if body.len() as u64 + len > max_len {
+            return Err(invalid_response(
+                "The server sent more than it was asked for",
+            ));
+        }
+        body.extend(read_body(connection, len)?);
+        // Each chunk ends with a line break.
+        connection.read_line(&mut String::new())?;
+    }
+    // The body ends with any trailers, up to an empty line.
+    loop {
+        let mut line = String::new();
+        if connection.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            return Ok(body);
+        }
+    }
+}
+
+/// Make the error for a response which couldn't be understood.
+fn invalid_response(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid HTTP response: {message}"),
+    )
+}
+
+impl Read for HttpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_idx = self.position / CHUNK_SIZE;
+        if !self.chunks.contains_key(&chunk_idx) {
+            // Fetch all of a large read at once, and further ahead for sequential reads.
+            let last_chunk = (self.position + buf.len() as u64 - 1) / CHUNK_SIZE;
+            self.read_ahead = if chunk_idx == self.next_chunk {
+                (self.read_ahead * 2).min(MAX_READ_AHEAD_CHUNKS)
+            } else {
+                1
+            };
+            let count = (last_chunk - chunk_idx + 1).max(self.read_ahead);
+            // Chunks already fetched further on needn't be fetched again.
+            let count = (1..count)
+                .find(|offset| self.chunks.contains_key(&(chunk_idx + offset)))
+                .unwrap_or(count);
+            self.fetch_chunks(chunk_idx, count)?;
+        }
+        let chunk = self
+            .chunks
+            .get(&chunk_idx)
+            .ok_or_else(|| invalid_response("The server sent less than it was asked for"))?;
+        let chunk = chunk
+            .get((self.position % CHUNK_SIZE) as usize..)
+            .unwrap_or_default();
+        let len = chunk.len().min(buf.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for HttpFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Write for HttpFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Make the error for trying to change a database fetched over HTTP.
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Databases fetched over HTTP are read-only",
+    )
+}
+
+impl SyncFile for HttpFile {
+    /// Nothing is ever written, so there's nothing to sync.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for HttpFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    /// Nobody can write to the file through HTTP, so locks on it never conflict.
+    fn set_lock(&self, _kind: LockKind, _start: u64, _len: u64) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Reads already fetch ahead of themselves, so this is left to them.
+    fn prefetch(&self, _start: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::{record::OwnedValue, Database};
+
+    /// Serve HTTP on a local port, answering each request with what `respond` makes of its path
+    /// and range, and return the server's address.
+    fn serve_with(respond: impl Fn(&str, usize, usize) -> Vec<u8> + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to start server");
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                // Serve requests on the connection until it's closed.
+                loop {
+                    let mut path = None;
+                    let mut range = None;
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                        if let Some(request) = line.strip_prefix("GET ") {
+                            path = request.split(' ').next().map(str::to_owned);
+                        }
+                        if let Some(value) = line.strip_prefix("Range: bytes=") {
+                            let (start, end) = value.trim_end().split_once('-').unwrap();
+                            range = Some((
+                                start.parse::<usize>().unwrap(),
+                                end.parse::<usize>().unwrap(),
+                            ));
+                        }
+                        line.clear();
+                    }
+                    let Some((start, end)) = range else {
+                        break;
+                    };
+                    let response = respond(path.as_deref().unwrap_or("/"), start, end);
+                    if stream.get_mut().write_all(&response).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    /// Serve `contents` over HTTP on a local port, returning its URL and the number of requests.
+    fn serve(contents: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let requested = Arc::new(AtomicUsize::new(0));
+        let log = Arc::clone(&requested);
+        let addr = serve_with(move |_, start, end| {
+            log.fetch_add(1, Ordering::Relaxed);
+            let body = &contents[start..=end.min(contents.len() - 1)];
+            let response = format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                 Content-Range: bytes {start}-{}/{}\r\n\r\n",
+                body.len(),
+                start + body.len() - 1,
+                contents.len(),
+            );
+            let mut response = response.into_bytes();
+            response.extend_from_slice(body);
+            response
+        });
+        (format!("http://{addr}/test.sqlite"), requested)
+    }
+
+    /// Read every row of the schema table of `db`.
+    fn query<File: Storage>(db: &mut Database<File>) -> Vec<Vec<OwnedValue>> {
+        let statement = sqlparser::parser::Parser::parse_sql(
+            &sqlparser::dialect::SQLiteDialect {},
+            "SELECT * FROM sqlite_schema",
+        )
+        .unwrap()
+        .remove(0);
+        let mut rows = Vec::new();
+        db.execute_statement(&statement, |row| {
+            rows.push(row);
+            Ok(())
+        })
+        .expect("Failed to query");
+        rows
+    }
+
+    #[test]
+    fn test_http_database() {
+        let contents = fs::read("test-data/many-tables.sqlite").expect("Failed to read database");
+        let page_count = contents.len() / 4096;
+        let (url, requested) = serve(contents);
+        let file = HttpFile::open(&url).expect("Failed to open database");
+        let mut db = Database::new(file).expect("Failed to parse database");
+        let mut local = Database::new(fs::File::open("test-data/many-tables.sqlite").unwrap())
+            .expect("Failed to parse database");
+        let expected = query(&mut local);
+        assert_eq!(query(&mut db), expected);
+        let requests = requested.load(Ordering::Relaxed);
+        assert!(
+            requests < page_count / 4,
+            "Reading ahead should take fewer requests ({requests}) than pages ({page_count})",
+        );
+
+        // Everything read is cached, so reading it again doesn't make any more requests.
+        assert_eq!(query(&mut db), expected);
+        assert_eq!(requested.load(Ordering::Relaxed), requests);
+    }
+
+    #[test]
+    fn test_chunked_redirect() {
+        let contents = fs::read("test-data/many-tables.sqlite").expect("Failed to read database");
+        let addr = serve_with(move |path, start, end| {
+            if path == "/old.sqlite" {
+                return b"HTTP/1.1 301 Moved Permanently\r\nLocation: /test.sqlite\r\n\
+                         Content-Length: 0\r\n\r\n"
+                    .to_vec();
+            }
+            let body = &contents[start..=end.min(contents.len() - 1)];
+            let mut response = format!(
+                "HTTP/1.1 206 Partial Content\r\nTransfer-Encoding: chunked\r\n\
+                 Content-Range: bytes {start}-{}/{}\r\n\r\n",
+                start + body.len() - 1,
+                contents.len(),
+            )
+            .into_bytes();
+            for chunk in body.chunks(1000) {
+                response.extend(format!("{:x};ext=1\r\n", chunk.len()).into_bytes());
+                response.extend_from_slice(chunk);
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"0\r\nTrailer: yes\r\n\r\n");
+            response
+        });
+        let file =
+            HttpFile::open(&format!("http://{addr}/old.sqlite")).expect("Failed to open database");
+        let mut db = Database::new(file).expect("Failed to parse database");
+        let mut local = Database::new(fs::File::open("test-data/many-tables.sqlite").unwrap())
+            .expect("Failed to parse database");
+        assert_eq!(query(&mut db), query(&mut local));
+    }
+
+    #[test]
+    fn test_bad_responses() {
+        // Lengths longer than the range asked for are rejected before anything is read.
+        let addr = serve_with(|_, _, _| {
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 1099511627776\r\n\
+              Content-Range: bytes 0-16383/1099511627776\r\n\r\n"
+                .to_vec()
+        });
+        let error = HttpFile::open(&format!("http://{addr}/test.sqlite")).err();
+        assert_eq!(error.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        // Servers which never answer time out.
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to start server");
+        let url = format!("http://{}/test.sqlite", listener.local_addr().unwrap());
+        let error = HttpFile::open_with_timeout(&url, Duration::from_millis(100)).err();
+        assert!(
+            matches!(
+                error.map(|e| e.kind()),
+                Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+            ),
+            "Reading the response should time out",
+        );
+    }
+
+    #[test]
+    fn test_unsupported_urls() {
+        assert_eq!(
+            HttpFile::open("https://example.com/db.sqlite")
+                .err()
+                .map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidInput),
+        );
+    }
+}
@@ -0,0 +1,468 @@
+//! The rollback journal, which makes writes to the database crash-safe.
+//!
+//! Before any page of the database file is overwritten, its original contents are recorded in a
+//! journal file next to the database. If the write is interrupted, the journal can be played back
+//! to restore the database to how it was before the write started. Once the write completes, the
+//! journal is deleted, which is what makes the write take effect.
+//!
+//! A journal left behind by a connection which crashed partway through a write is "hot", and is
+//! played back by the next connection to read the database, before it reads anything else.
+
+use std::{
+    collections::HashSet,
+    hash::{BuildHasher, RandomState},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+
+use super::{LockLevel, OpenMode, Pager, Storage, SyncFile, SyncPolicy, Vfs, VfsFile};
+
+/// The magic number every rollback journal begins with.
+pub(crate) const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// The sector size we assume the underlying storage has.
+///
+/// The journal header is padded out to this size.
+pub(crate) const JOURNAL_SECTOR_SIZE: usize = 512;
+
+/// Get the path of the journal for the database at `db_path`.
+#[must_use]
+pub fn journal_path_for(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-journal");
+    PathBuf::from(path)
+}
+
+/// Get the path of the database whose journal is at `journal_path`, as given by
+/// [`journal_path_for`].
+pub(super) fn db_path_for_journal(journal_path: &Path) -> Option<&Path> {
+    journal_path
+        .to_str()?
+        .strip_suffix("-journal")
+        .map(Path::new)
+}
+
+/// A rollback journal being written.
+pub(crate) struct Journal {
+    /// The location of the journal file
+    path: PathBuf,
+    /// The open journal file
+    file: BufWriter<VfsFile>,
+    /// Where the journal file was opened, to delete it from
+    vfs: Arc<dyn Vfs>,
+    /// The random value which seeds the checksum of each page record
+    nonce: u32,
+    /// The size of each page, in bytes
+    page_size: usize,
+    /// The pages whose original contents have been written to the journal
+    journaled_pages: HashSet<usize>,
+    /// How carefully the journal is synced to persistent storage
+    sync_policy: SyncPolicy,
+}
+impl Journal {
+    /// Create a new journal at `path` in `vfs`, replacing any existing file there.
+    ///
+    /// # Arguments
+    /// * `page_size`: The size of each page in the database.
+    /// * `original_page_count`: The number of pages in the database before the write, which is
+    ///   what the database is truncated to if the journal is played back.
+    /// * `sync_policy`: How carefully to sync the journal in [`Self::sync`].
+    pub(crate) fn create(
+        vfs: Arc<dyn Vfs>,
+        path: PathBuf,
+        page_size: usize,
+        original_page_count: u32,
+        sync_policy: SyncPolicy,
+    ) -> Result<Self> {
+        let nonce = RandomState::new().hash_one(&path) as u32;
+        let file = vfs
+            .open(&path, OpenMode::Replace)
+            .with_context(|| format!("Failed to create journal at {}", path.display()))?;
+        let mut journal = Self {
+            path,
+            file: BufWriter::new(file),
+            vfs,
+            nonce,
+            page_size,
+            journaled_pages: HashSet::new(),
+            sync_policy,
+        };
+        journal.write_header(original_page_count)?;
+        Ok(journal)
+    }
+
+    /// Write the journal header, padded out to a full sector.
+    fn write_header(&mut self, original_page_count: u32) -> Result<()> {
+        let mut header = [0; JOURNAL_SECTOR_SIZE];
+        header[..8].copy_from_slice(&JOURNAL_MAGIC);
+        // When the journal isn't synced, the record count is left as -1, which tells readers to
+        // use every record that fits in the file. Otherwise it's 0 until the records are synced,
+        // so records which may not have been fully written are never played back.
+        let record_count = if self.sync_policy == SyncPolicy::Off {
+            u32::MAX
+        } else {
+            0
+        };
+        header[8..12].copy_from_slice(&record_count.to_be_bytes());
+        header[12..16].copy_from_slice(&self.nonce.to_be_bytes());
+        header[16..20].copy_from_slice(&original_page_count.to_be_bytes());
+        header[20..24].copy_from_slice(&(JOURNAL_SECTOR_SIZE as u32).to_be_bytes());
+        header[24..28].copy_from_slice(&(self.page_size as u32).to_be_bytes());
+        self.file
+            .write_all(&header)
+            .context("Failed to write journal header")
+    }
+
+    /// Record the original contents of a page, if it hasn't already been recorded.
+    pub(crate) fn append_page(&mut self, page_idx: usize, contents: &[u8]) -> Result<()> {
+        anyhow::ensure!(
+            contents.len() == self.page_size,
+            "Journaled page has the wrong size"
+        );
+        if !self.journaled_pages.insert(page_idx) {
+            return Ok(());
+        }
+        let page_num = u32::try_from(page_idx).context("Page index too large for journal")?;
+        self.file
+            .write_all(&page_num.to_be_bytes())
+            .and_then(|()| self.file.write_all(contents))
+            .and_then(|()| {
+                self.file
+                    .write_all(&checksum(self.nonce, contents).to_be_bytes())
+            })
+            .context("Failed to write page to journal")
+    }
+
+    /// Finish writing the journal, so the database can be overwritten.
+    ///
+    /// Unless the journal's sync policy is [`SyncPolicy::Off`], this records the number of pages
+    /// in the header and ensures the whole journal has reached persistent storage.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.flush().context("Failed to flush journal")?;
+        if self.sync_policy == SyncPolicy::Off {
+            return Ok(());
+        }
+        if self.sync_policy == SyncPolicy::Full {
+            // The records have to be durable before the header says they're there.
+            self.sync_file()?;
+        }
+        let record_count =
+            u32::try_from(self.journaled_pages.len()).context("Too many pages for the journal")?;
+        self.file
+            .seek(SeekFrom::Start(8))
+            .and_then(|_| self.file.write_all(&record_count.to_be_bytes()))
+            .and_then(|()| self.file.seek(SeekFrom::End(0)))
+            .and_then(|_| self.file.flush())
+            .context("Failed to write journal record count")?;
+        self.sync_file()
+    }
+
+    /// Ensure everything written to the journal file has reached persistent storage.
+    fn sync_file(&mut self) -> Result<()> {
+        self.file.get_mut().sync().context("Failed to sync journal")
+    }
+
+    /// Delete the journal, committing the write it protected.
+    pub(crate) fn delete(self) -> Result<()> {
+        drop(self.file);
+        self.vfs
+            .delete(&self.path)
+            .with_context(|| format!("Failed to delete journal at {}", self.path.display()))
+    }
+}
+
+/// The original contents of the pages recorded in a hot journal.
+struct HotJournal<'a> {
+    /// The number of pages in the database before the write, which it's truncated back to
+    original_page_count: u32,
+    /// The pages to restore, and their original contents, in the order they were journaled
+    pages: Vec<(usize, &'a [u8])>,
+}
+
+/// Read the records in a hot journal, for a database with pages of `page_size` bytes.
+///
+/// Like SQLite, reading stops at the first record with the wrong checksum, since it may not have
+/// been fully written before the crash. The journal can hold several segments, each starting
+/// with its own header on a sector boundary.
+fn read_hot_journal(contents: &[u8], page_size: usize) -> Result<HotJournal<'_>> {
+    let word = |offset: usize| {
+        contents
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    anyhow::ensure!(
+        contents.starts_with(&JOURNAL_MAGIC),
+        "Invalid journal header"
+    );
+    let original_page_count = word(16).context("Journal header too short")?;
+    let sector_size = word(20).context("Journal header too short")? as usize;
+    anyhow::ensure!(
+        sector_size.is_power_of_two() && sector_size >= 32,
+        "Invalid sector size {sector_size} in journal"
+    );
+    anyhow::ensure!(
+        word(24) == Some(page_size as u32),
+        "Journal page size doesn't match the database"
+    );
+    let record_size = 4 + page_size + 4;
+    let mut pages = Vec::new();
+    let mut header_offset = 0;
+    'segments: while contents
+        .get(header_offset..)
+        .is_some_and(|rest| rest.starts_with(&JOURNAL_MAGIC))
+    {
+        let record_count = word(header_offset + 8).context("Journal header too short")?;
+        let nonce = word(header_offset + 12).context("Journal header too short")?;
+        let mut offset = header_offset + sector_size;
+        // An unsynced journal holds as many records as fit in the rest of the file.
+        let record_count = if record_count == u32::MAX {
+            contents.len().saturating_sub(offset) / record_size
+        } else {
+            record_count as usize
+        };
+        for _ in 0..record_count {
+            let Some(record) = contents.get(offset..offset + record_size) else {
+                break 'segments;
+            };
+            let (page_num, rest) = record.split_at(4);
+            let (page, page_checksum) = rest.split_at(page_size);
+            let page_idx = u32::from_be_bytes(page_num.try_into().unwrap()) as usize;
+            if page_idx == 0 || checksum(nonce, page).to_be_bytes() != page_checksum {
+                break 'segments;
+            }
+            pages.push((page_idx, page));
+            offset += record_size;
+        }
+        header_offset = offset.next_multiple_of(sector_size);
+    }
+    Ok(HotJournal {
+        original_page_count,
+        pages,
+    })
+}
+
+impl<File: Storage> Pager<File> {
+    /// Play back the journal left behind by a connection which crashed partway through a write,
+    /// if there is one, restoring the database to how it was before that write.
+    ///
+    /// This must be called while holding a SHARED lock, before reading anything from the file.
+    /// The journal is only hot if no other connection holds a RESERVED lock, since one which does
+    /// may still be writing it, and playing it back takes an EXCLUSIVE lock, so this fails with
+    /// "database is locked" if there are other readers.
+    pub(super) fn roll_back_hot_journal(&mut self) -> Result<()> {
+        let Some(journal_path) = self.journal_path.clone() else {
+            return Ok(());
+        };
+        let mut contents = Vec::new();
+        match self.vfs.open(&journal_path, OpenMode::Existing) {
+            Ok(mut file) => file
+                .read_to_end(&mut contents)
+                .context("Failed to read journal")?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("Failed to read journal"),
+        };
+        // An empty or zeroed journal is left over from a write which finished.
+        if !contents.starts_with(&JOURNAL_MAGIC) {
+            return Ok(());
+        }
+        if self.lock_file(LockLevel::Reserved).is_err() {
+            return Ok(());
+        }
+        if let Err(e) = self.lock_file(LockLevel::Exclusive) {
+            self.unlock_file(LockLevel::Shared)?;
+            return Err(e);
+        }
+        let result = self.play_back_journal(&contents, &journal_path);
+        self.unlock_file(LockLevel::Shared)?;
+        result
+    }
+
+    /// Restore the pages recorded in the journal whose contents are given, then delete it.
+    fn play_back_journal(&mut self, contents: &[u8], journal_path: &Path) -> Result<()> {
+        let page_size = self.header.page_size();
+        let journal = read_hot_journal(contents, page_size)?;
+        for (page_idx, page) in journal.pages {
+            self.file
+                .seek(SeekFrom::Start((page_size * (page_idx - 1)) as u64))
+                .and_then(|_| self.file.write_all(page))
+                .with_context(|| format!("Error restoring page {page_idx} from journal"))?;
+        }
+        self.file
+            .set_len((page_size * journal.original_page_count as usize) as u64)
+            .context("Error truncating database file")?;
+        self.remap()?;
+        if self.sync_policy != SyncPolicy::Off {
+            self.file.sync().context("Error syncing database file")?;
+        }
+        self.vfs
+            .delete(journal_path)
+            .with_context(|| format!("Failed to delete journal at {}", journal_path.display()))
+    }
+}
+
+/// Compute the checksum SQLite uses for a page record in the journal.
+///
+/// This only samples every 200th byte, counting back from the end of the page.
+pub(crate) fn checksum(nonce: u32, contents: &[u8]) -> u32 {
+    let mut acc = nonce;
+    let mut idx = contents.len() as isize - 200;
+    while idx > 0 {
+        acc = acc.wrapping_add(u32::from(contents[idx as usize]));
+        idx -= 200;
+    }
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::pager::OsVfs;
+
+    #[test]
+    fn test_journal_layout() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-journal-layout-{}", std::process::id()));
+        let page_size = 1024;
+        let mut journal = Journal::create(
+            Arc::new(OsVfs),
+            path.clone(),
+            page_size,
+            7,
+            SyncPolicy::Full,
+        )
+        .expect("Failed to create");
+        let page = vec![1; page_size];
+        journal
+            .append_page(3, &page)
+            .expect("Failed to append page");
+        journal
+            .append_page(3, &vec![2; page_size])
+            .expect("Failed to append page");
+        journal.sync().expect("Failed to sync journal");
+
+        let contents = fs::read(&path).expect("Failed to read journal");
+        assert_eq!(
+            contents.len(),
+            JOURNAL_SECTOR_SIZE + 4 + page_size + 4,
+            "Each page should only be journaled once",
+        );
+        assert_eq!(contents[..8], JOURNAL_MAGIC);
+        assert_eq!(contents[8..12], 1_u32.to_be_bytes());
+        assert_eq!(contents[16..20], 7_u32.to_be_bytes());
+        assert_eq!(contents[24..28], 1024_u32.to_be_bytes());
+        let record = &contents[JOURNAL_SECTOR_SIZE..];
+        assert_eq!(record[..4], 3_u32.to_be_bytes());
+        assert_eq!(record[4..4 + page_size], page);
+        // Offsets 824, 624, 424, 224, and 24 are sampled, each holding a 1.
+        assert_eq!(
+            record[4 + page_size..],
+            journal.nonce.wrapping_add(5).to_be_bytes(),
+        );
+
+        journal.delete().expect("Failed to delete journal");
+        assert!(!path.exists(), "Journal should be deleted");
+    }
+
+    #[test]
+    fn test_roll_back_hot_journal() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-hot-journal-{}", std::process::id()));
+        fs::copy("test-data/minimal-test.sqlite", &path).expect("Failed to copy database");
+        let journal_path = journal_path_for(&path);
+        let original = fs::read(&path).expect("Failed to read database");
+        let page_size = 4096;
+
+        // Crash partway through a write which changes page 2 and adds page 4.
+        let mut journal = Journal::create(
+            Arc::new(OsVfs),
+            journal_path.clone(),
+            page_size,
+            3,
+            SyncPolicy::Full,
+        )
+        .expect("Failed to create journal");
+        journal
+            .append_page(2, &original[page_size..2 * page_size])
+            .expect("Failed to append page");
+        journal.sync().expect("Failed to sync journal");
+        let mut changed = original.clone();
+        changed[page_size..2 * page_size].fill(0xff);
+        changed.extend_from_slice(&vec![0xff; page_size]);
+        fs::write(&path, &changed).expect("Failed to write database");
+
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .expect("Failed to open database");
+        let mut pager =
+            Pager::with_journal(file, journal_path.clone()).expect("Failed to open database");
+        pager.lock(LockLevel::Shared).expect("Failed to lock");
+        assert_eq!(pager.lock_level(), LockLevel::Shared);
+        assert!(!journal_path.exists(), "The hot journal should be deleted");
+        assert_eq!(
+            fs::read(&path).expect("Failed to read database"),
+            original,
+            "The write should be undone",
+        );
+        assert_eq!(pager.page_count(), 3);
+    }
+
+    #[test]
+    fn test_read_torn_journal() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-torn-journal-{}", std::process::id()));
+        let mut journal = Journal::create(Arc::new(OsVfs), path.clone(), 512, 2, SyncPolicy::Off)
+            .expect("Failed to create");
+        for page_idx in 1..=3 {
+            journal
+                .append_page(page_idx, &[page_idx as u8; 512])
+                .expect("Failed to append page");
+        }
+        journal.sync().expect("Failed to sync journal");
+        let mut contents = fs::read(&path).expect("Failed to read journal");
+        journal.delete().expect("Failed to delete journal");
+        // Corrupt a byte of the last page which the checksum covers, and add part of a record
+        // after it.
+        let last_page = JOURNAL_SECTOR_SIZE + 2 * (512 + 8) + 4;
+        contents[last_page + 312] ^= 1;
+        contents.extend_from_slice(&[0; 100]);
+
+        let hot = read_hot_journal(&contents, 512).expect("Failed to read journal");
+        assert_eq!(hot.original_page_count, 2);
+        assert_eq!(
+            hot.pages
+                .iter()
+                .map(|&(page_idx, page)| (page_idx, page[0]))
+                .collect::<Vec<_>>(),
+            [(1, 1), (2, 2)],
+        );
+        assert!(read_hot_journal(&contents, 1024).is_err());
+    }
+
+    #[test]
+    fn test_unsynced_journal() {
+        let path = std::env::temp_dir().join(format!(
+            "sqlite-riir-journal-unsynced-{}",
+            std::process::id()
+        ));
+        let mut journal = Journal::create(Arc::new(OsVfs), path.clone(), 512, 1, SyncPolicy::Off)
+            .expect("Failed to create");
+        journal
+            .append_page(1, &[0; 512])
+            .expect("Failed to append page");
+        journal.sync().expect("Failed to sync journal");
+        let contents = fs::read(&path).expect("Failed to read journal");
+        assert_eq!(
+            contents[8..12],
+            u32::MAX.to_be_bytes(),
+            "Unsynced journals don't record how many pages they hold",
+        );
+        journal.delete().expect("Failed to delete journal");
+    }
+}
@@ -0,0 +1,435 @@
+//! SQLite's file locking protocol, so the database can be shared with other processes
+//!
+//! Locks are advisory locks on bytes in the lock-byte page, 1 GiB into the file, which SQLite
+//! never stores data in. A connection reading the database holds a SHARED lock, one which is
+//! preparing changes also holds a RESERVED lock (of which there can only be one), and one writing
+//! its changes to the file holds an EXCLUSIVE lock, which keeps out everyone else. The PENDING
+//! lock is taken on the way to EXCLUSIVE, and stops new readers from starting while existing ones
+//! finish.
+//!
+//! Other processes may change the database while we don't hold a lock on it, so the pages we have
+//! cached are checked whenever a SHARED lock is taken.
+
+use std::{
+    fs,
+    io::{self, Read, Seek},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use super::{codec, Pager, Storage};
+use crate::Error;
+
+/// The offset of the byte locked to take a PENDING lock.
+const PENDING_BYTE: u64 = 0x4000_0000;
+/// The offset of the byte locked to take a RESERVED lock.
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+/// The offset of the range of bytes locked to take a SHARED or EXCLUSIVE lock.
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+/// The length of the range of bytes locked to take a SHARED or EXCLUSIVE lock.
+const SHARED_SIZE: u64 = 510;
+/// How long to wait between tries to take a lock which conflicts with another connection's.
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How much access to the database file a connection has, from least to most.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    /// No access, so the file may change at any time.
+    None,
+    /// Permission to read from the file, which other connections may also have.
+    Shared,
+    /// Permission to read, along with an intent to write, which only one connection may have.
+    Reserved,
+    /// Waiting for the other connections to stop reading, so the file can be written.
+    Pending,
+    /// Permission to write to the file, which no other connection can read while it's held.
+    Exclusive,
+}
+
+/// A kind of advisory lock to set on a range of bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// Stop others from taking write locks on the range.
+    Read,
+    /// Stop others from taking any locks on the range.
+    Write,
+    /// Release any lock on the range.
+    Unlock,
+}
+
+impl<File> Pager<File> {
+    /// Get the lock currently held on the database file.
+    #[must_use]
+    pub fn lock_level(&self) -> LockLevel {
+        self.lock
+    }
+
+    /// Get how long [`Self::lock`] keeps trying to take a lock which conflicts with another
+    /// connection's.
+    #[must_use]
+    pub fn busy_timeout(&self) -> Duration {
+        self.busy_timeout
+    }
+
+    /// Set how long [`Self::lock`] keeps trying to take a lock which conflicts with another
+    /// connection's, like SQLite's `sqlite3_busy_timeout`. Zero, the default, fails right away.
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.busy_timeout = timeout;
+    }
+
+    /// Whether the given page is the lock-byte page, which is never used to store anything.
+    ///
+    /// Only databases of over 1 GiB reach it, and they skip over it.
+    #[must_use]
+    pub fn is_lock_byte_page(&self, page_idx: usize) -> bool {
+        page_idx == PENDING_BYTE as usize / self.header.page_size() + 1
+    }
+}
+
+impl<File: Read + Seek> Pager<File> {
+    /// Discard the cached pages if the file has changed since they were read, returning whether
+    /// it had.
+    ///
+    /// Other connections change the file counter in the header whenever they change the file, so
+    /// this should be called whenever a SHARED lock is taken.
+    pub fn reload_if_changed(&mut self) -> crate::Result<bool> {
+        if self.wal_snapshot.is_some() {
+            // A snapshot is of an earlier version of the database, so it never changes.
+            return Ok(false);
+        }
+        if self.wal.is_some() {
+            // Commits only change the WAL, which tracks them in its index instead.
+            return Ok(self.reload_wal_if_changed()?);
+        }
+        self.file.rewind().context("Error seeking in database")?;
+        let mut header = codec::read_header(&mut self.file, self.codec.as_deref())?;
+        // Like SQLite, the page count in the header is only trusted if it was written along with
+        // the current change counter, since older versions of SQLite change the file without
+        // updating it. Otherwise, it's worked out from the size of the file.
+        if header.version_valid_for != header.file_change_counter || header.page_count == 0 {
+            let len = self
+                .file
+                .seek(io::SeekFrom::End(0))
+                .context("Error seeking in database")?;
+            header.page_count = (len / header.page_size() as u64) as u32;
+        }
+        if header.file_change_counter == self.header.file_change_counter
+            && header.page_count == self.disk_page_count
+        {
+            return Ok(false);
+        }
+        if !self.dirty_pages.is_empty() {
+            return Err(Error::Busy(
+                "The database was changed by another connection during a write".to_owned(),
+            ));
+        }
+        self.header = header;
+        self.disk_page_count = header.page_count;
+        self.page_cache.clear(header.page_size());
+        self.remap()?;
+        Ok(true)
+    }
+}
+
+impl<File: Storage> Pager<File> {
+    /// Raise the lock held on the database file to `level`, if it isn't already held.
+    ///
+    /// If another connection's lock conflicts with it, this tries again until the
+    /// [busy timeout](Self::busy_timeout) runs out, then fails with "database is locked". The
+    /// lock is left as it was if so, except that failing to take an EXCLUSIVE lock leaves a
+    /// PENDING lock held, so no new readers start before the lock is tried again.
+    ///
+    /// In WAL mode, this also locks a read mark on the WAL index to read as of the last commit,
+    /// and the WAL's write lock instead of a RESERVED lock. If another connection has put the
+    /// database in WAL mode, the WAL is opened, and the cached pages are discarded.
+    ///
+    /// Before reading anything, a hot journal left behind by a connection which crashed partway
+    /// through writing is played back.
+    pub fn lock(&mut self, level: LockLevel) -> crate::Result<()> {
+        let mut deadline = None;
+        loop {
+            match self.try_lock(level) {
+                Err(Error::Busy(message)) if !self.busy_timeout.is_zero() => {
+                    // Only look at the clock once there's a conflict, since there isn't one on
+                    // some targets, where the timeout has to be left at zero.
+                    let deadline =
+                        *deadline.get_or_insert_with(|| Instant::now() + self.busy_timeout);
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(Error::Busy(message));
+                    }
+                    std::thread::sleep(remaining.min(BUSY_RETRY_INTERVAL));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Raise the lock held on the database file to `level` if no other connection's lock
+    /// conflicts with it, as in [`Self::lock`].
+    fn try_lock(&mut self, level: LockLevel) -> crate::Result<()> {
+        if self.wal.is_none() && self.lock == LockLevel::None {
+            self.lock_file(LockLevel::Shared)?;
+            if let Err(e) = self
+                .roll_back_hot_journal()
+                .and_then(|()| self.open_wal_if_needed())
+            {
+                self.wal = None;
+                self.unlock_file(LockLevel::None)?;
+                return Err(e.into());
+            }
+        }
+        if self.wal.is_some() {
+            return Ok(self.lock_wal(level)?);
+        }
+        Ok(self.lock_file(level)?)
+    }
+
+    /// Raise the lock held on the database file itself to `level`.
+    pub(super) fn lock_file(&mut self, level: LockLevel) -> Result<()> {
+        if self.lock >= level {
+            return Ok(());
+        }
+        if self.lock == LockLevel::None {
+            // The PENDING byte is held while taking the SHARED lock, so readers can't start while
+            // a writer is waiting for the existing ones to finish.
+            anyhow::ensure!(
+                self.file.set_lock(LockKind::Read, PENDING_BYTE, 1)?,
+                Error::busy()
+            );
+            let shared = self
+                .file
+                .set_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE);
+            self.file.set_lock(LockKind::Unlock, PENDING_BYTE, 1)?;
+            anyhow::ensure!(shared?, Error::busy());
+            self.lock = LockLevel::Shared;
+        }
+        // Only one connection can be preparing to write at once, so writers take a RESERVED lock
+        // before they start waiting for readers to finish.
+        if level >= LockLevel::Reserved && self.lock < LockLevel::Reserved {
+            anyhow::ensure!(
+                self.file.set_lock(LockKind::Write, RESERVED_BYTE, 1)?,
+                Error::busy()
+            );
+            self.lock = LockLevel::Reserved;
+        }
+        if level >= LockLevel::Pending && self.lock < LockLevel::Pending {
+            anyhow::ensure!(
+                self.file.set_lock(LockKind::Write, PENDING_BYTE, 1)?,
+                Error::busy()
+            );
+            self.lock = LockLevel::Pending;
+        }
+        if level == LockLevel::Exclusive {
+            anyhow::ensure!(
+                self.file
+                    .set_lock(LockKind::Write, SHARED_FIRST, SHARED_SIZE)?,
+                Error::busy()
+            );
+            self.lock = LockLevel::Exclusive;
+        }
+        Ok(())
+    }
+
+    /// Lower the lock held on the database file to `level`, which must be SHARED or no lock.
+    ///
+    /// Any changes must be written to the file or rolled back first.
+    pub fn unlock(&mut self, level: LockLevel) -> crate::Result<()> {
+        if level > LockLevel::Shared {
+            return Err(Error::Other(
+                "Can only unlock to a SHARED lock or none".to_owned(),
+            ));
+        }
+        if !self.dirty_pages.is_empty() {
+            return Err(Error::Other(
+                "Cannot unlock the database with unwritten changes".to_owned(),
+            ));
+        }
+        if self.wal.is_some() {
+            return Ok(self.unlock_wal(level)?);
+        }
+        Ok(self.unlock_file(level)?)
+    }
+
+    /// Lower the lock held on the database file itself to `level`.
+    pub(super) fn unlock_file(&mut self, level: LockLevel) -> Result<()> {
+        if self.lock <= level {
+            return Ok(());
+        }
+        if level == LockLevel::Shared {
+            if self.lock == LockLevel::Exclusive {
+                self.file
+                    .set_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE)?;
+            }
+            self.file.set_lock(LockKind::Unlock, PENDING_BYTE, 2)?;
+        } else {
+            self.file.set_lock(
+                LockKind::Unlock,
+                PENDING_BYTE,
+                SHARED_FIRST + SHARED_SIZE - PENDING_BYTE,
+            )?;
+        }
+        self.lock = level;
+        Ok(())
+    }
+}
+
+/// Set an advisory lock on `len` bytes of `file` starting at `start`, returning whether it was
+/// set or another process holds a conflicting lock.
+///
+/// On Linux, these are open file description locks, which work the same as the POSIX locks
+/// SQLite uses, except that they also conflict with locks taken through other handles to the
+/// file in this process, and closing another handle to the file doesn't release them.
+#[cfg(unix)]
+pub(super) fn set_lock(file: &fs::File, kind: LockKind, start: u64, len: u64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SET_LOCK: libc::c_int = libc::F_SETLK;
+
+    // SAFETY: `flock` is a plain C struct, which is valid when zeroed. Open file description
+    // locks also require the pid to be 0.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match kind {
+        LockKind::Read => libc::F_RDLCK,
+        LockKind::Write => libc::F_WRLCK,
+        LockKind::Unlock => libc::F_UNLCK,
+    } as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start as libc::off_t;
+    flock.l_len = len as libc::off_t;
+    // SAFETY: The file descriptor is open for as long as `file` is, and `flock` is a valid lock
+    // description which `fcntl` only reads during the call.
+    if unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &flock) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Set an advisory lock on part of `file`, which is never held by another process.
+///
+/// TODO Implement locking on platforms other than Unix.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub(super) fn set_lock(
+    _file: &fs::File,
+    _kind: LockKind,
+    _start: u64,
+    _len: u64,
+) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    fn temp_copy(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-{name}-{}.sqlite", std::process::id()));
+        fs::copy("test-data/minimal-test.sqlite", &path).expect("Failed to copy database");
+        path
+    }
+
+    fn open(path: &Path) -> Pager<fs::File> {
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .expect("Failed to open test database");
+        Pager::new(file).expect("Failed to parse test database")
+    }
+
+    /// Open file description locks conflict between handles in the same process, which the
+    /// POSIX locks used elsewhere don't.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_lock_conflicts() {
+        let path = temp_copy("locks");
+        let (mut a, mut b) = (open(&path), open(&path));
+        a.lock(LockLevel::Shared).expect("Failed to lock");
+        b.lock(LockLevel::Shared)
+            .expect("Readers shouldn't conflict");
+        a.lock(LockLevel::Reserved).expect("Failed to lock");
+        assert!(b.lock(LockLevel::Reserved).is_err());
+        assert_eq!(b.lock_level(), LockLevel::Shared);
+
+        // `a` has to wait for `b` to stop reading, and no new readers can start meanwhile.
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+        assert_eq!(a.lock_level(), LockLevel::Pending);
+        assert!(open(&path).lock(LockLevel::Shared).is_err());
+        b.unlock(LockLevel::None).unwrap();
+        a.lock(LockLevel::Exclusive).expect("Failed to lock");
+        assert!(b.lock(LockLevel::Shared).is_err());
+
+        a.unlock(LockLevel::Shared).unwrap();
+        b.lock(LockLevel::Reserved).expect("Failed to lock");
+        assert!(b.lock(LockLevel::Exclusive).is_err());
+        a.unlock(LockLevel::None).unwrap();
+        b.lock(LockLevel::Exclusive).expect("Failed to lock");
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_busy_timeout() {
+        let path = temp_copy("busy-timeout");
+        let (mut a, mut b) = (open(&path), open(&path));
+        a.lock(LockLevel::Exclusive).expect("Failed to lock");
+
+        let timeout = Duration::from_millis(50);
+        b.set_busy_timeout(timeout);
+        let start = Instant::now();
+        let error = b.lock(LockLevel::Shared).unwrap_err();
+        assert!(matches!(error, Error::Busy(_)), "{error:?}");
+        assert!(
+            start.elapsed() >= timeout,
+            "Gave up after {:?}",
+            start.elapsed()
+        );
+        assert_eq!(b.lock_level(), LockLevel::None);
+
+        // The lock is taken once the other connection lets go of its own within the timeout.
+        let unlock = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            a.unlock(LockLevel::None).unwrap();
+        });
+        b.set_busy_timeout(Duration::from_secs(10));
+        b.lock(LockLevel::Shared)
+            .expect("Failed to wait for the lock");
+        unlock.join().unwrap();
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let path = temp_copy("reload");
+        let (mut a, mut b) = (open(&path), open(&path));
+        let original = a.read_page_bytes(2).unwrap().to_vec();
+        assert!(!a.reload_if_changed().unwrap());
+
+        let page_size = b.page_size();
+        b.write_page(2, &vec![0xff; page_size]).unwrap();
+        b.write_page(4, &vec![0; page_size]).unwrap();
+        b.flush().expect("Failed to flush");
+        assert_eq!(
+            a.read_page_bytes(2).unwrap(),
+            original,
+            "Cached pages are kept until the change is noticed",
+        );
+        assert!(a.reload_if_changed().unwrap());
+        assert_eq!(a.read_page_bytes(2).unwrap(), vec![0xff; page_size]);
+        assert_eq!(a.page_count(), 4);
+        assert!(!a.reload_if_changed().unwrap());
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+}
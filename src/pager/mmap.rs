@@ -0,0 +1,142 @@
+//! Reading pages straight from a memory map of the database file, instead of copying them into the
+//! page cache
+//!
+//! The map is shared with the OS's own cache of the file, so writes through the file show up in it
+//! without it having to be mapped again. It only has to be mapped again when the file changes
+//! length, since pages past the end of the map can't be read from it, and reading from a map past
+//! the end of its file crashes the process.
+
+use std::{fs, io, path::PathBuf};
+
+use anyhow::Context;
+
+use super::Pager;
+
+/// A database file mapped into memory.
+pub(super) struct MappedFile {
+    /// A handle to the file, to map it again with
+    file: fs::File,
+    /// The map of the file, or `None` if it's empty, since empty files can't be mapped.
+    map: Option<memmap2::Mmap>,
+}
+
+impl MappedFile {
+    /// Map `file` into memory.
+    ///
+    /// # Safety
+    /// The file must only be changed as [`Pager::new_mmap`] requires.
+    unsafe fn new(file: fs::File) -> io::Result<Self> {
+        let mut mapped = Self { file, map: None };
+        mapped.remap()?;
+        Ok(mapped)
+    }
+
+    /// Get the contents of the given page, which is `page_size` bytes long, if it's in the map.
+    pub(super) fn page(&self, page_idx: usize, page_size: usize) -> Option<&[u8]> {
+        let start = page_size.checked_mul(page_idx.checked_sub(1)?)?;
+        self.map.as_ref()?.get(start..start.checked_add(page_size)?)
+    }
+
+    /// Map the file into memory again, to cover all of it after it changed length.
+    pub(super) fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // SAFETY: The file was mapped by `Pager::new_mmap`, whose caller promised to only
+            // change it as it requires, and the pager only reads pages which are in the file.
+            self.map = Some(unsafe { memmap2::Mmap::map(&self.file)? });
+        }
+        Ok(())
+    }
+}
+
+impl Pager<fs::File> {
+    /// Construct a new pager over the given file, which reads pages straight from a map of it in
+    /// memory, rather than copying them into its page cache.
+    ///
+    /// Pages are only read from the map outside of WAL mode. Changes to the file are written to
+    /// it as usual, and the map is updated whenever the file changes length.
+    ///
+    /// We assume that the file is currently at the beginning, this function may behave
+    /// unexpectedly otherwise.
+    ///
+    /// # Safety
+    /// Other processes must not change the file while this pager reads from it, which holds as long
+    /// as they follow SQLite's locking protocol and the pager is only read while it holds a lock.
+    /// Reading a page which another process cut off the end of the file crashes this one, and
+    /// reading one which another process is writing is undefined behavior.
+    pub unsafe fn new_mmap(file: fs::File) -> crate::Result<Self> {
+        let handle = file
+            .try_clone()
+            .context("Failed to duplicate database file handle")?;
+        // SAFETY: Guaranteed by the caller.
+        let mapped = unsafe { MappedFile::new(handle) }.context("Failed to map database file")?;
+        let mut pager = Self::new(file)?;
+        pager.mmap = Some(mapped);
+        Ok(pager)
+    }
+
+    /// Construct a new pager over the given file, as in [`Self::new_mmap`], which protects writes
+    /// with a rollback journal at `journal_path`.
+    ///
+    /// # Safety
+    /// The same as for [`Self::new_mmap`].
+    pub unsafe fn new_mmap_with_journal(
+        file: fs::File,
+        journal_path: PathBuf,
+    ) -> crate::Result<Self> {
+        Ok(Self {
+            journal_path: Some(journal_path),
+            // SAFETY: Guaranteed by the caller.
+            ..unsafe { Self::new_mmap(file) }?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmap() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-mmap-{}.sqlite", std::process::id()));
+        fs::copy("test-data/minimal-test.sqlite", &path).expect("Failed to copy database");
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .expect("Failed to open test database");
+        // SAFETY: Nothing else uses the copy of the database.
+        let mut pager = unsafe { Pager::new_mmap(file) }.expect("Failed to map database");
+        let mut copy = Pager::new(fs::File::open(&path).unwrap()).unwrap();
+        for page_idx in 1..=pager.page_count() {
+            assert_eq!(
+                pager.read_page_bytes(page_idx).unwrap(),
+                copy.read_page_bytes(page_idx).unwrap(),
+            );
+        }
+        assert!(
+            pager.page_cache.local.entries.is_empty(),
+            "Pages should be read from the map instead of the cache",
+        );
+
+        // Pages written past the end of the map are read from it once the file has grown.
+        let page_size = pager.page_size();
+        pager
+            .write_page(4, &vec![7; page_size])
+            .expect("Failed to append page");
+        pager.flush().expect("Failed to flush pager");
+        assert_eq!(pager.read_page_bytes(4).unwrap(), vec![7; page_size]);
+        assert!(pager.page_cache.local.entries.is_empty());
+
+        pager.truncate(2).expect("Failed to truncate");
+        pager.flush().expect("Failed to flush pager");
+        pager.truncate_file().expect("Failed to truncate file");
+        assert_eq!(
+            pager.mmap.as_ref().and_then(|mmap| mmap.page(3, page_size)),
+            None,
+            "The map shouldn't extend past the end of the file",
+        );
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,160 @@
+//! Keeping databases in the browser's origin private file system (OPFS), for running in a web
+//! worker on wasm32
+//!
+//! Browsers only allow reading and writing OPFS files synchronously from dedicated workers,
+//! through a [`FileSystemSyncAccessHandle`], which is what the pager needs. Only opening the
+//! handle is asynchronous, so that's done before the database is opened.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::Context;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetFileOptions,
+    FileSystemReadWriteOptions, FileSystemSyncAccessHandle, WorkerGlobalScope,
+};
+
+use super::{LockKind, Storage, SyncFile};
+
+/// A database file in the origin private file system.
+///
+/// This can be opened as a database with [`Database::new`](crate::Database::new), without a
+/// journal, since the journal would have to be opened asynchronously too.
+pub struct OpfsFile {
+    /// The handle the file is read and written through, which is closed when this is dropped
+    handle: FileSystemSyncAccessHandle,
+    /// The position reads and writes start from
+    position: u64,
+}
+
+impl OpfsFile {
+    /// Open the file called `name` in the root of the origin private file system, creating it
+    /// empty if there isn't one and `create` is set.
+    ///
+    /// This only works from a dedicated web worker, since browsers don't allow synchronous access
+    /// to files from the main thread.
+    pub async fn open(name: &str, create: bool) -> crate::Result<Self> {
+        let Ok(scope) = js_sys::global().dyn_into::<WorkerGlobalScope>() else {
+            return Err(crate::Error::Other(
+                "OPFS files can only be opened from a web worker".to_owned(),
+            ));
+        };
+        let root = JsFuture::from(scope.navigator().storage().get_directory())
+            .await
+            .map_err(|e| js_error(&e))
+            .context("Failed to open the origin private file system")?
+            .unchecked_into::<FileSystemDirectoryHandle>();
+        let options = FileSystemGetFileOptions::new();
+        options.set_create(create);
+        let file = JsFuture::from(root.get_file_handle_with_options(name, &options))
+            .await
+            .map_err(|e| js_error(&e))
+            .with_context(|| format!("Failed to open {name}"))?
+            .unchecked_into::<FileSystemFileHandle>();
+        let handle = JsFuture::from(file.create_sync_access_handle())
+            .await
+            .map_err(|e| js_error(&e))
+            .with_context(|| format!("Failed to get access to {name}"))?
+            .unchecked_into::<FileSystemSyncAccessHandle>();
+        Ok(Self::new(handle))
+    }
+
+    /// Use a file through a handle which has already been opened, such as by JavaScript code.
+    #[must_use]
+    pub fn new(handle: FileSystemSyncAccessHandle) -> Self {
+        Self {
+            handle,
+            position: 0,
+        }
+    }
+
+    /// Options to read or write at the current position.
+    fn at_position(&self) -> FileSystemReadWriteOptions {
+        let options = FileSystemReadWriteOptions::new();
+        options.set_at(self.position as f64);
+        options
+    }
+}
+
+impl Drop for OpfsFile {
+    /// Other handles to the file can't be opened until this one is closed.
+    fn drop(&mut self) {
+        self.handle.close();
+    }
+}
+
+/// Convert an exception thrown by the browser into an error.
+fn js_error(error: &JsValue) -> io::Error {
+    io::Error::other(format!("{error:?}"))
+}
+
+impl Read for OpfsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self
+            .handle
+            .read_with_u8_array_and_options(buf, &self.at_position())
+            .map_err(|e| js_error(&e))? as usize;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for OpfsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self
+            .handle
+            .write_with_u8_array_and_options(buf, &self.at_position())
+            .map_err(|e| js_error(&e))? as usize;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    /// Writes go straight to the file, so there's nothing to flush until it's synced.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for OpfsFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(position) => (position, 0),
+            SeekFrom::End(offset) => (self.size()?, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl SyncFile for OpfsFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.handle.flush().map_err(|e| js_error(&e))
+    }
+}
+
+impl Storage for OpfsFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.handle.get_size().map_err(|e| js_error(&e))? as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.handle
+            .truncate_with_f64(len as f64)
+            .map_err(|e| js_error(&e))
+    }
+
+    /// A file can only have one sync access handle open at once, so no other connection can be
+    /// using it.
+    fn set_lock(&self, _kind: LockKind, _start: u64, _len: u64) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Reads from the file are already served from local storage, so reading ahead doesn't help.
+    fn prefetch(&self, _start: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Reading and writing payloads too large to fit in their btree page
+//!
+//! The part of a payload which doesn't fit in its cell is stored in a linked list of overflow
+//! pages. Each one starts with the index of the next, or 0 for the last, followed by as much of
+//! the payload as fits in the rest of the usable part of the page.
+
+use std::io::{Read, Seek};
+
+use anyhow::{Context, Result};
+
+use super::{Pager, PtrmapEntry};
+use crate::Error;
+
+/// The size of the pointer to the next page at the start of each overflow page.
+const NEXT_POINTER_SIZE: usize = 4;
+
+impl<File: Read + Seek> Pager<File> {
+    /// Read `len` bytes of a payload from the chain of overflow pages starting at `first_page`.
+    pub fn read_overflow(&mut self, first_page: u32, len: usize) -> crate::Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(len);
+        let mut page_idx = first_page as usize;
+        let usable_size = self.usable_size();
+        // A chain can't have more pages than the database, so a longer one must have a loop.
+        for _ in 0..self.page_count() {
+            if !(2..=self.page_count()).contains(&page_idx) {
+                return Err(Error::corrupt(
+                    None,
+                    format!("Overflow page {page_idx} is out of bounds"),
+                ));
+            }
+            let page = &self.read_page_bytes(page_idx)?[..usable_size];
+            let (next, contents) = page
+                .split_first_chunk::<NEXT_POINTER_SIZE>()
+                .ok_or_else(|| Error::corrupt(Some(page_idx), "Overflow page too short"))?;
+            let take = contents.len().min(len - payload.len());
+            payload.extend_from_slice(&contents[..take]);
+            if payload.len() == len {
+                return Ok(payload);
+            }
+            page_idx = u32::from_be_bytes(*next) as usize;
+        }
+        Err(Error::corrupt(
+            Some(first_page as usize),
+            format!("Overflow chain starting at page {first_page} is too long"),
+        ))
+    }
+
+    /// Write the part of a payload which doesn't fit in its cell to a new chain of overflow pages,
+    /// returning the first page.
+    ///
+    /// The pointer map entry for the first page is left for whoever writes the cell to set.
+    pub fn write_overflow(&mut self, overflow: &[u8]) -> crate::Result<u32> {
+        let page_size = self.page_size();
+        let usable_size = self.usable_size();
+        let pages = overflow
+            .chunks(usable_size - NEXT_POINTER_SIZE)
+            .map(|_| self.allocate_page())
+            .collect::<crate::Result<Vec<_>>>()?;
+        for (idx, chunk) in overflow.chunks(usable_size - NEXT_POINTER_SIZE).enumerate() {
+            let next = pages.get(idx + 1).map_or(0, |page| *page as u32);
+            let mut page = vec![0; page_size];
+            page[..NEXT_POINTER_SIZE].copy_from_slice(&next.to_be_bytes());
+            page[NEXT_POINTER_SIZE..NEXT_POINTER_SIZE + chunk.len()].copy_from_slice(chunk);
+            self.write_page(pages[idx], &page)?;
+            if let Some(previous) = idx.checked_sub(1) {
+                self.set_ptrmap(pages[idx], PtrmapEntry::Overflow(pages[previous] as u32))?;
+            }
+        }
+        let first_page = pages
+            .first()
+            .ok_or_else(|| Error::Other("Overflow payloads can't be empty".to_owned()))?;
+        Ok(*first_page as u32)
+    }
+
+    /// Move the chain of overflow pages starting at `first_page`, which holds `len` bytes of a
+    /// payload, to the freelist.
+    pub fn free_overflow(&mut self, first_page: u32, len: usize) -> crate::Result<()> {
+        let page_count = len.div_ceil(self.usable_size() - NEXT_POINTER_SIZE);
+        // A chain can't have more pages than the database, so a longer one must have a loop.
+        if page_count > self.page_count() {
+            return Err(Error::corrupt(
+                Some(first_page as usize),
+                format!("Overflow chain starting at page {first_page} is too long"),
+            ));
+        }
+        let mut page_idx = first_page as usize;
+        for _ in 0..page_count {
+            if !(2..=self.page_count()).contains(&page_idx) {
+                return Err(Error::corrupt(
+                    None,
+                    format!("Overflow page {page_idx} is out of bounds"),
+                ));
+            }
+            let next = self
+                .read_page_bytes(page_idx)?
+                .first_chunk::<NEXT_POINTER_SIZE>()
+                .copied()
+                .ok_or_else(|| Error::corrupt(Some(page_idx), "Overflow page too short"))?;
+            self.free_page(page_idx)?;
+            page_idx = u32::from_be_bytes(next) as usize;
+        }
+        Ok(())
+    }
+}
+
+/// A chain of overflow pages which can be read from at any offset.
+///
+/// The page numbers in the chain are remembered as they're found, so reading from anywhere in
+/// the chain only reads the pages before it once, and none of the payload is kept in memory.
+pub(crate) struct OverflowChain {
+    /// The pages in the chain which have been found so far, in order
+    pages: Vec<u32>,
+    /// The number of bytes of the payload stored in the chain
+    len: usize,
+}
+impl OverflowChain {
+    /// Start reading the `len` bytes stored in the chain starting at `first_page`.
+    pub(crate) fn new(first_page: u32, len: usize) -> Self {
+        Self {
+            pages: vec![first_page],
+            len,
+        }
+    }
+
+    /// Get the number of bytes stored in the chain.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Read bytes starting `offset` bytes into the chain into `buf`, returning how many were
+    /// read.
+    ///
+    /// This reads from at most one page, so it may read fewer bytes than would fit in `buf`, and
+    /// reads none at the end of the chain.
+    pub(crate) fn read_at<File: Read + Seek>(
+        &mut self,
+        pager: &mut Pager<File>,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let usable_size = pager.usable_size();
+        let page_capacity = usable_size - NEXT_POINTER_SIZE;
+        let page_number = offset / page_capacity;
+        while self.pages.len() <= page_number {
+            // A chain can't have more pages than the database, so a longer one must have a loop.
+            anyhow::ensure!(
+                self.pages.len() < pager.page_count(),
+                "Overflow chain starting at page {} is too long",
+                self.pages[0]
+            );
+            let last = *self.pages.last().expect("Chains have a first page");
+            let page = overflow_page(pager, last)?;
+            let next = page
+                .first_chunk::<NEXT_POINTER_SIZE>()
+                .context("Overflow page too short")?;
+            self.pages.push(u32::from_be_bytes(*next));
+        }
+        let page = overflow_page(pager, self.pages[page_number])?;
+        let start = NEXT_POINTER_SIZE + offset % page_capacity;
+        let take = buf.len().min(usable_size - start).min(self.len - offset);
+        buf[..take].copy_from_slice(&page[start..start + take]);
+        Ok(take)
+    }
+}
+
+/// Read the usable part of the overflow page `page_idx`, checking that it's in the database.
+fn overflow_page<File: Read + Seek>(pager: &mut Pager<File>, page_idx: u32) -> Result<&[u8]> {
+    let page_idx = page_idx as usize;
+    anyhow::ensure!(
+        (2..=pager.page_count()).contains(&page_idx),
+        "Overflow page {page_idx} is out of bounds"
+    );
+    let usable_size = pager.usable_size();
+    Ok(&pager.read_page_bytes(page_idx)?[..usable_size])
+}
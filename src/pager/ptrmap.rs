@@ -0,0 +1,367 @@
+//! Pointer maps, which let auto-vacuum databases move pages around
+//!
+//! In a database with auto-vacuum enabled, page 2 is a pointer map page, as is every page after it
+//! which comes right after the pages covered by the pointer map page before it. Each one holds a
+//! 5-byte entry for every page it covers: the type of the page, and the page which points to it.
+//! That's enough to move a page into a gap left by a freed page and fix up the pointer to it, so
+//! free pages can be removed from the end of the file rather than left on the freelist.
+
+use std::{
+    collections::BTreeSet,
+    io::{Read, Seek},
+};
+
+use anyhow::{Context, Result};
+
+use super::{freelist::read_u32, Pager};
+use crate::page::btree_header_offset;
+
+/// The size of each entry in a pointer map page.
+const ENTRY_SIZE: usize = 5;
+/// The first byte of the header on an index internal page.
+const INDEX_INTERNAL_PAGE_TYPE: u8 = 0x02;
+/// The first byte of the header on a table internal page.
+const TABLE_INTERNAL_PAGE_TYPE: u8 = 0x05;
+
+/// Whether a database removes unused pages from the file by itself, and when.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AutoVacuum {
+    /// Unused pages stay on the freelist until the database is vacuumed.
+    None,
+    /// Unused pages are removed whenever changes are committed.
+    Full,
+    /// Unused pages stay on the freelist until [`Pager::incremental_vacuum`] removes them.
+    Incremental,
+}
+
+/// What a page is used for, according to its pointer map entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PtrmapEntry {
+    /// The root page of a btree
+    RootPage,
+    /// A page on the freelist
+    FreePage,
+    /// The first overflow page of a cell, with the btree page holding the cell.
+    FirstOverflow(u32),
+    /// A later overflow page, with the overflow page before it.
+    Overflow(u32),
+    /// A non-root btree page, with its parent page.
+    BTree(u32),
+}
+
+impl PtrmapEntry {
+    /// Parse an entry from its 5 bytes.
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let page = read_u32(bytes, 1);
+        Ok(match bytes[0] {
+            1 => Self::RootPage,
+            2 => Self::FreePage,
+            3 => Self::FirstOverflow(page),
+            4 => Self::Overflow(page),
+            5 => Self::BTree(page),
+            n => anyhow::bail!("Invalid pointer map entry type: {n}"),
+        })
+    }
+
+    /// Encode this entry as its 5 bytes.
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let (ty, page) = match self {
+            Self::RootPage => (1, 0),
+            Self::FreePage => (2, 0),
+            Self::FirstOverflow(page) => (3, page),
+            Self::Overflow(page) => (4, page),
+            Self::BTree(page) => (5, page),
+        };
+        let mut bytes = [ty, 0, 0, 0, 0];
+        bytes[1..].copy_from_slice(&page.to_be_bytes());
+        bytes
+    }
+}
+
+impl<File> Pager<File> {
+    /// Get whether this database removes unused pages from the file by itself.
+    #[must_use]
+    pub fn auto_vacuum(&self) -> AutoVacuum {
+        self.header.auto_vacuum
+    }
+
+    /// Whether the given page is a pointer map page, rather than holding database contents.
+    #[must_use]
+    pub fn is_ptrmap_page(&self, page_idx: usize) -> bool {
+        self.header.auto_vacuum != AutoVacuum::None
+            && page_idx >= 2
+            && self.ptrmap_page_for(page_idx) == page_idx
+    }
+
+    /// Find the pointer map page in the group of pages containing the given page.
+    fn ptrmap_page_for(&self, page_idx: usize) -> usize {
+        let ptrmap_page = page_idx - (page_idx - 2) % (self.ptrmap_entries_per_page() + 1);
+        // Nothing can be stored in the lock-byte page, so if it would be a pointer map page, the
+        // pointer map moves to the page after it.
+        if self.is_lock_byte_page(ptrmap_page) {
+            ptrmap_page + 1
+        } else {
+            ptrmap_page
+        }
+    }
+
+    /// The number of pages each pointer map page covers.
+    fn ptrmap_entries_per_page(&self) -> usize {
+        self.header.usable_size() / ENTRY_SIZE
+    }
+
+    /// Find the pointer map page covering the given page, and the offset of its entry in it.
+    fn ptrmap_location(&self, page_idx: usize) -> Result<(usize, usize)> {
+        // Page 1, the lock-byte page, and the pointer map pages themselves have no entries.
+        anyhow::ensure!(
+            page_idx > 2 && !self.is_ptrmap_page(page_idx) && !self.is_lock_byte_page(page_idx),
+            "Page {page_idx} has no pointer map entry"
+        );
+        let ptrmap_page = self.ptrmap_page_for(page_idx);
+        Ok((ptrmap_page, ENTRY_SIZE * (page_idx - ptrmap_page - 1)))
+    }
+}
+
+impl<File: Read + Seek> Pager<File> {
+    /// Read the pointer map entry for the given page.
+    pub(crate) fn read_ptrmap(&mut self, page_idx: usize) -> Result<PtrmapEntry> {
+        let (ptrmap_page, offset) = self.ptrmap_location(page_idx)?;
+        PtrmapEntry::parse(&self.read_page_bytes(ptrmap_page)?[offset..offset + ENTRY_SIZE])
+            .with_context(|| format!("Invalid pointer map entry for page {page_idx}"))
+    }
+
+    /// Record the pointer map entry for the given page, if this database has pointer maps.
+    pub(crate) fn set_ptrmap(&mut self, page_idx: usize, entry: PtrmapEntry) -> Result<()> {
+        if self.header.auto_vacuum == AutoVacuum::None {
+            return Ok(());
+        }
+        let (ptrmap_page, offset) = self.ptrmap_location(page_idx)?;
+        let bytes = entry.to_bytes();
+        let page = self.read_page_bytes(ptrmap_page)?;
+        // Btree pages re-record their children's entries every time they're written, so skip
+        // copying the pointer map page when nothing changes.
+        if page[offset..offset + ENTRY_SIZE] == bytes {
+            return Ok(());
+        }
+        let mut page = page.to_vec();
+        page[offset..offset + ENTRY_SIZE].copy_from_slice(&bytes);
+        Ok(self.write_page(ptrmap_page, &page)?)
+    }
+
+    /// Remove up to `max_pages` free pages (or all of them, if `None`) from the end of the
+    /// database, returning how many were removed.
+    ///
+    /// Pages in use at the end of the database are moved into free pages before it, so the
+    /// database can shrink. This does nothing if the database doesn't have auto-vacuum enabled.
+    pub fn incremental_vacuum(&mut self, max_pages: Option<usize>) -> crate::Result<usize> {
+        if self.header.auto_vacuum == AutoVacuum::None {
+            return Ok(0);
+        }
+        let mut free = self.free_pages()?.into_iter().collect::<BTreeSet<_>>();
+        if free.is_empty() {
+            return Ok(0);
+        }
+        let mut page_count = self.page_count();
+        for page_idx in 3..=page_count {
+            if !self.is_ptrmap_page(page_idx)
+                && !self.is_lock_byte_page(page_idx)
+                && matches!(
+                    self.read_ptrmap(page_idx)?,
+                    PtrmapEntry::FirstOverflow(_) | PtrmapEntry::Overflow(_)
+                )
+            {
+                // TODO Moving pages with overflow pages attached is unimplemented, so the free
+                // pages stay where they are.
+                return Ok(0);
+            }
+        }
+
+        let mut removed = 0;
+        while !free.is_empty() && max_pages.map_or(true, |max_pages| removed < max_pages) {
+            let holds_contents =
+                !self.is_ptrmap_page(page_count) && !self.is_lock_byte_page(page_count);
+            if holds_contents && !free.remove(&page_count) {
+                // The last page is in use, so it moves into the first free page.
+                let target = free.pop_first().context("No free page to move into")?;
+                self.relocate_page(page_count, target)?;
+            }
+            if holds_contents {
+                removed += 1;
+            }
+            page_count -= 1;
+        }
+        // A pointer map page with no pages after it has nothing to map, and the database can't
+        // end with the lock-byte page.
+        while self.is_ptrmap_page(page_count) || self.is_lock_byte_page(page_count) {
+            page_count -= 1;
+        }
+        self.truncate(page_count)?;
+        // The freelist's trunks may have been removed, so it's rebuilt from what's left.
+        self.set_freelist_head(0, 0)?;
+        for page_idx in free {
+            self.free_page(page_idx)?;
+        }
+        Ok(removed)
+    }
+
+    /// Move the contents of page `from` into the free page `to`, updating everything which points
+    /// to it.
+    fn relocate_page(&mut self, from: usize, to: usize) -> Result<()> {
+        let entry = self.read_ptrmap(from)?;
+        let contents = self.read_page_bytes(from)?.to_vec();
+        self.write_page(to, &contents)?;
+        self.set_ptrmap(to, entry)?;
+        for offset in child_pointer_offsets(&contents, from) {
+            self.set_ptrmap(
+                read_u32(&contents, offset) as usize,
+                PtrmapEntry::BTree(to as u32),
+            )?;
+        }
+        let PtrmapEntry::BTree(parent) = entry else {
+            anyhow::bail!("Cannot move page {from} of type {entry:?}");
+        };
+        let mut parent_contents = self.read_page_bytes(parent as usize)?.to_vec();
+        let offset = child_pointer_offsets(&parent_contents, parent as usize)
+            .find(|&offset| read_u32(&parent_contents, offset) as usize == from)
+            .with_context(|| format!("Page {parent} isn't the parent of page {from}"))?;
+        parent_contents[offset..offset + 4].copy_from_slice(&(to as u32).to_be_bytes());
+        Ok(self.write_page(parent as usize, &parent_contents)?)
+    }
+}
+
+/// Get the offsets in the given btree page of each of its pointers to its children.
+///
+/// Leaf pages have no children, so this is empty for them.
+fn child_pointer_offsets(page: &[u8], page_idx: usize) -> impl Iterator<Item = usize> + '_ {
+    let offset = btree_header_offset(page_idx);
+    let internal = matches!(
+        page[offset],
+        INDEX_INTERNAL_PAGE_TYPE | TABLE_INTERNAL_PAGE_TYPE
+    );
+    let cell_count = if internal {
+        u16::from_be_bytes([page[offset + 3], page[offset + 4]]).into()
+    } else {
+        0
+    };
+    (0..cell_count)
+        .map(move |idx| {
+            let pointer = offset + 12 + 2 * idx;
+            u16::from_be_bytes([page[pointer], page[pointer + 1]]).into()
+        })
+        // The rightmost child's pointer is in the page header.
+        .chain(internal.then_some(offset + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::btree;
+
+    /// The root pages of the table and its index in the test database.
+    const ROOT_PAGES: [usize; 2] = [3, 4];
+
+    fn open_fixture() -> Pager<Cursor<Vec<u8>>> {
+        let contents = std::fs::read("test-data/auto-vacuum.sqlite").unwrap();
+        Pager::new(Cursor::new(contents)).expect("Failed to parse test database")
+    }
+
+    /// Check that every page's pointer map entry matches what points to it.
+    fn check_ptrmap(pager: &mut Pager<Cursor<Vec<u8>>>) {
+        let free = pager.free_pages().unwrap();
+        for page_idx in 3..=pager.page_count() {
+            if pager.is_ptrmap_page(page_idx) {
+                continue;
+            }
+            let entry = pager.read_ptrmap(page_idx).unwrap();
+            if free.contains(&page_idx) {
+                assert_eq!(entry, PtrmapEntry::FreePage, "page {page_idx}");
+                continue;
+            }
+            if ROOT_PAGES.contains(&page_idx) {
+                assert_eq!(entry, PtrmapEntry::RootPage, "page {page_idx}");
+            }
+            let contents = pager.read_page_bytes(page_idx).unwrap().to_vec();
+            for offset in child_pointer_offsets(&contents, page_idx) {
+                let child = read_u32(&contents, offset) as usize;
+                assert_eq!(
+                    pager.read_ptrmap(child).unwrap(),
+                    PtrmapEntry::BTree(page_idx as u32),
+                    "page {child}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_ptrmap_pages() {
+        let mut pager = open_fixture();
+        assert_eq!(pager.auto_vacuum(), AutoVacuum::Incremental);
+        // Each 1024-byte pointer map page covers the 204 pages after it.
+        assert!(pager.is_ptrmap_page(2));
+        assert!(!pager.is_ptrmap_page(3));
+        assert!(pager.is_ptrmap_page(207));
+        assert!(pager.read_ptrmap(2).is_err());
+        check_ptrmap(&mut pager);
+
+        while pager.page_count() < 210 {
+            let page_idx = pager.allocate_page().unwrap();
+            assert!(!pager.is_ptrmap_page(page_idx));
+            pager
+                .set_ptrmap(page_idx, PtrmapEntry::BTree(3))
+                .expect("Failed to set pointer map entry");
+        }
+        assert_eq!(pager.read_ptrmap(209).unwrap(), PtrmapEntry::BTree(3));
+    }
+
+    #[test]
+    fn test_lock_byte_page() {
+        let mut pager = open_fixture();
+        // With 1024-byte pages, the lock-byte page would be a pointer map page, so the pointer
+        // map is moved to the page after it.
+        let lock_byte_page = 0x4000_0000 / 1024 + 1;
+        assert!(pager.is_lock_byte_page(lock_byte_page));
+        assert!(!pager.is_ptrmap_page(lock_byte_page));
+        assert!(pager.is_ptrmap_page(lock_byte_page + 1));
+        assert_eq!(
+            pager.ptrmap_location(lock_byte_page + 2).unwrap(),
+            (lock_byte_page + 1, 0)
+        );
+        assert!(pager.ptrmap_location(lock_byte_page).is_err());
+
+        // Pretend the database has grown up to the lock-byte page, which new pages skip.
+        pager.header.page_count = lock_byte_page as u32 - 1;
+        assert_eq!(pager.allocate_page().unwrap(), lock_byte_page + 2);
+        assert!(pager.free_page(lock_byte_page).is_err());
+    }
+
+    #[test]
+    fn test_incremental_vacuum() {
+        let mut pager = open_fixture();
+        let page_count = pager.page_count();
+        let rowids = btree::rowids(&mut pager, ROOT_PAGES[0]).unwrap();
+        for &rowid in &rowids[100..] {
+            btree::delete(&mut pager, ROOT_PAGES[0], rowid).unwrap();
+        }
+        let free_count = pager.free_pages().unwrap().len();
+        assert!(free_count > 3);
+        check_ptrmap(&mut pager);
+
+        assert_eq!(pager.incremental_vacuum(Some(3)).unwrap(), 3);
+        assert_eq!(pager.free_pages().unwrap().len(), free_count - 3);
+        assert_eq!(pager.page_count(), page_count - 3);
+        check_ptrmap(&mut pager);
+
+        assert_eq!(pager.incremental_vacuum(None).unwrap(), free_count - 3);
+        assert!(pager.free_pages().unwrap().is_empty());
+        assert_eq!(pager.page_count(), page_count - free_count);
+        check_ptrmap(&mut pager);
+        assert_eq!(
+            btree::rowids(&mut pager, ROOT_PAGES[0]).unwrap(),
+            rowids[..100],
+            "Moving pages shouldn't change the table",
+        );
+        assert_eq!(pager.incremental_vacuum(None).unwrap(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! The places a database can be stored, which the pager reads and writes its pages through
+
+use std::{
+    fs,
+    io::{self, Cursor, Read, Seek, Write},
+};
+
+use super::{lock, LockKind, SyncFile};
+
+/// Somewhere a database can be stored, like a file or a buffer in memory.
+///
+/// Besides reading and writing bytes, the pager needs to be able to resize the storage, and to
+/// take locks on it so it can be shared with other connections.
+pub trait Storage: Read + Write + Seek + SyncFile {
+    /// Get the length of the storage, in bytes.
+    ///
+    /// # Errors
+    /// If the length can't be read.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Change the length of the storage to `len` bytes, cutting off anything past it or filling
+    /// the new bytes with zeroes.
+    ///
+    /// # Errors
+    /// If the storage can't be resized.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Set an advisory lock on `len` bytes of the storage starting at `start`, returning whether
+    /// it was set or another connection holds a conflicting lock.
+    ///
+    /// # Errors
+    /// If the lock couldn't be set for any reason other than a conflicting lock.
+    fn set_lock(&self, kind: LockKind, start: u64, len: u64) -> io::Result<bool>;
+
+    /// Hint that `len` bytes of the storage starting at `start` are about to be read, so they can
+    /// be read ahead of time.
+    ///
+    /// # Errors
+    /// If the hint couldn't be given.
+    fn prefetch(&self, start: u64, len: u64) -> io::Result<()>;
+}
+
+impl Storage for fs::File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn set_lock(&self, kind: LockKind, start: u64, len: u64) -> io::Result<bool> {
+        lock::set_lock(self, kind, start, len)
+    }
+
+    fn prefetch(&self, start: u64, len: u64) -> io::Result<()> {
+        advise_will_need(self, start, len)
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn set_lock(&self, kind: LockKind, start: u64, len: u64) -> io::Result<bool> {
+        (**self).set_lock(kind, start, len)
+    }
+
+    fn prefetch(&self, start: u64, len: u64) -> io::Result<()> {
+        (**self).prefetch(start, len)
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len =
+            usize::try_from(len).map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        self.get_mut().resize(len, 0);
+        Ok(())
+    }
+
+    /// No other connection can see a buffer in memory, so locks on it never conflict.
+    fn set_lock(&self, _kind: LockKind, _start: u64, _len: u64) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// A buffer in memory has nothing to read ahead from.
+    fn prefetch(&self, _start: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tell the OS that `len` bytes of `file` starting at `start` will be needed soon, so it starts
+/// reading them into its cache in the background.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn advise_will_need(file: &fs::File, start: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: The file descriptor is open for as long as `file` is, and the advice only affects
+    // what the OS caches.
+    let result = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            start as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    // Unlike most calls, this returns the error instead of setting `errno`.
+    match result {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+/// Tell the OS that part of `file` will be needed soon, which is only supported on Linux.
+///
+/// TODO Read ahead on other platforms, with `F_RDADVISE` on macOS.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[allow(clippy::unnecessary_wraps)]
+fn advise_will_need(_file: &fs::File, _start: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
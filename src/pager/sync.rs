@@ -0,0 +1,107 @@
+//! Making writes durable, so a commit survives a crash or power loss once it has returned
+//!
+//! Writes only reach persistent storage once they're synced, and may reach it in any order
+//! before then. A commit has to sync the journal before overwriting any pages of the database, so
+//! the original contents can be restored if the overwrite is interrupted, and sync the database
+//! before deleting the journal, so the overwrite isn't undone by playing the journal back.
+
+use std::io::{self, Cursor};
+
+use super::Pager;
+
+/// How carefully writes are synced to persistent storage, as set by `PRAGMA synchronous`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync, leaving it to the operating system.
+    ///
+    /// This is fastest, but a crash of the operating system or a power loss during a commit can
+    /// corrupt the database.
+    Off,
+    /// Sync the journal once before writing to the database, and the database before deleting
+    /// the journal.
+    Normal,
+    /// As [`Self::Normal`], but sync the journal's pages before recording how many there are in
+    /// its header, and then sync the header too.
+    ///
+    /// This is SQLite's default, which stays safe even if a power loss persists the header's
+    /// update without the pages written just before it.
+    #[default]
+    Full,
+}
+impl SyncPolicy {
+    /// Parse the value given to `PRAGMA synchronous`, which is either a name or its number.
+    #[must_use]
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" | "0" => Some(Self::Off),
+            "normal" | "1" => Some(Self::Normal),
+            "full" | "2" => Some(Self::Full),
+            _ => None,
+        }
+    }
+
+    /// Get the number `PRAGMA synchronous` reports for this policy.
+    #[must_use]
+    pub fn as_number(self) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Normal => 1,
+            Self::Full => 2,
+        }
+    }
+}
+
+/// A file whose writes can be synced to persistent storage.
+pub trait SyncFile {
+    /// Wait until everything written to the file has reached persistent storage.
+    ///
+    /// # Errors
+    /// If the storage fails to persist the writes.
+    fn sync(&mut self) -> io::Result<()>;
+}
+impl SyncFile for std::fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+impl<F: SyncFile + ?Sized> SyncFile for Box<F> {
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+}
+impl<T> SyncFile for Cursor<T> {
+    /// In-memory files have no storage to sync to.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<File> Pager<File> {
+    /// Get how carefully commits are synced to persistent storage.
+    #[must_use]
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Set how carefully later commits are synced to persistent storage.
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for policy in [SyncPolicy::Off, SyncPolicy::Normal, SyncPolicy::Full] {
+            assert_eq!(
+                SyncPolicy::parse(&policy.as_number().to_string()),
+                Some(policy)
+            );
+        }
+        assert_eq!(SyncPolicy::parse("NORMAL"), Some(SyncPolicy::Normal));
+        assert_eq!(SyncPolicy::parse("extra"), None);
+    }
+}
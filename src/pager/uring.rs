@@ -0,0 +1,156 @@
+//! Reading pages from the database file with `io_uring`, which can have many reads in flight at
+//! once
+//!
+//! Reading pages one at a time waits out each read before starting the next, which leaves fast
+//! storage mostly idle. Submitting a batch of reads to the ring lets the kernel and the drive work
+//! on them all at once, so a batch takes about as long as its slowest read.
+
+use std::{fs, io, ops::Range, os::unix::fs::FileExt, os::unix::io::AsRawFd};
+
+use anyhow::Context;
+use io_uring::{opcode, types, IoUring};
+
+use super::Pager;
+
+/// The most reads to have in flight at once.
+const RING_ENTRIES: u32 = 32;
+
+/// A ring for reading pages from a database file.
+pub(super) struct PageRing {
+    /// A handle to the file, which the reads are made from
+    file: fs::File,
+    /// The ring the reads are submitted to
+    ring: IoUring,
+}
+
+impl PageRing {
+    /// Set up a ring to read from `file`.
+    fn new(file: fs::File) -> io::Result<Self> {
+        Ok(Self {
+            file,
+            ring: IoUring::new(RING_ENTRIES)?,
+        })
+    }
+
+    /// Read each of the given runs of adjacent pages, which are `page_size` bytes long, returning
+    /// the contents of each run.
+    ///
+    /// If this fails, reads may be left in flight on the ring, so it must be dropped rather than
+    /// used again.
+    pub(super) fn read_runs(
+        &mut self,
+        runs: &[Range<usize>],
+        page_size: usize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let mut buffers = runs
+            .iter()
+            .map(|run| vec![0; run.len() * page_size])
+            .collect::<Vec<_>>();
+        for batch_start in (0..runs.len()).step_by(RING_ENTRIES as usize) {
+            let batch_end = runs.len().min(batch_start + RING_ENTRIES as usize);
+            self.read_batch(runs, page_size, &mut buffers, batch_start..batch_end)?;
+        }
+        Ok(buffers)
+    }
+
+    /// Read the runs in `batch`, which fit in the ring together, into their buffers.
+    fn read_batch(
+        &mut self,
+        runs: &[Range<usize>],
+        page_size: usize,
+        buffers: &mut Vec<Vec<u8>>,
+        batch: Range<usize>,
+    ) -> io::Result<()> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        for idx in batch.clone() {
+            let buffer = &mut buffers[idx];
+            let entry = opcode::Read::new(
+                fd,
+                buffer.as_mut_ptr(),
+                u32::try_from(buffer.len()).map_err(io::Error::other)?,
+            )
+            .offset(((runs[idx].start - 1) * page_size) as u64)
+            .build()
+            .user_data(idx as u64);
+            // SAFETY: The buffer outlives the read, since reads are waited on before the buffers
+            // are returned, and the buffers are leaked if that fails.
+            unsafe { self.ring.submission().push(&entry) }
+                .expect("The batch should fit in the ring");
+        }
+        // Every read is waited on, and the completions are all taken before any are checked, to
+        // not leave any behind for the next batch.
+        let mut completions = Vec::with_capacity(batch.len());
+        while completions.len() < batch.len() {
+            match self.ring.submit_and_wait(batch.len() - completions.len()) {
+                Ok(_) => {}
+                // Interrupted waits are retried, since the reads are still in flight.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    // Some of the reads may still be writing into the buffers, and completing
+                    // onto the ring, which mustn't be used again.
+                    std::mem::forget(std::mem::take(buffers));
+                    return Err(e);
+                }
+            }
+            completions.extend(self.ring.completion());
+        }
+        for completion in completions {
+            let idx = completion.user_data() as usize;
+            // Failed reads give the negated error code.
+            let Ok(read) = usize::try_from(completion.result()) else {
+                return Err(io::Error::from_raw_os_error(-completion.result()));
+            };
+            // Reads can stop short, in which case the rest is read normally.
+            let offset = (runs[idx].start - 1) * page_size + read;
+            self.file
+                .read_exact_at(&mut buffers[idx][read..], offset as u64)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pager<fs::File> {
+    /// Construct a new pager over the given file, which uses `io_uring` to read batches of pages,
+    /// as [`Self::read_pages`] does, with all of their reads in flight at once.
+    ///
+    /// This mostly helps on storage which can serve many reads at once, like solid state drives. Pages
+    /// read one at a time are read from the file as usual.
+    ///
+    /// We assume that the file is currently at the beginning, this function may behave
+    /// unexpectedly otherwise.
+    pub fn new_io_uring(file: fs::File) -> crate::Result<Self> {
+        let handle = file
+            .try_clone()
+            .context("Failed to duplicate database file handle")?;
+        let ring = PageRing::new(handle).context("Failed to set up io_uring")?;
+        let mut pager = Self::new(file)?;
+        pager.uring = Some(ring);
+        Ok(pager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_uring() {
+        let file = fs::File::open("test-data/many-tables.sqlite").unwrap();
+        let mut pager = Pager::new_io_uring(file).expect("Failed to set up pager");
+        // More runs than fit in the ring at once.
+        let odd_pages = (1..=80).step_by(2);
+        pager
+            .read_pages(odd_pages.clone())
+            .expect("Failed to read pages");
+        assert_eq!(pager.page_loads(), 40);
+
+        let mut copy = Pager::new(fs::File::open("test-data/many-tables.sqlite").unwrap()).unwrap();
+        for page_idx in odd_pages {
+            assert_eq!(
+                pager.read_page_bytes(page_idx).unwrap(),
+                copy.read_page_bytes(page_idx).unwrap(),
+            );
+        }
+        assert_eq!(pager.page_loads(), 40, "The pages should have been cached");
+    }
+}
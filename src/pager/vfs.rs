@@ -0,0 +1,134 @@
+//! The layer between the pager and the OS, which opens and deletes the files a database is kept in
+//!
+//! Like SQLite's VFS, swapping this out changes where the database, its rollback journal, and its
+//! WAL are stored, without changing the pager. Reading, writing, syncing, locking, and resizing
+//! the files themselves is done through the [`Storage`] they're opened as.
+
+use std::{fs, io, path::Path, sync::Arc};
+
+use anyhow::Context;
+
+use super::{journal_path_for, Pager, Storage};
+
+/// A file opened by a [`Vfs`].
+pub type VfsFile = Box<dyn Storage + Send>;
+
+/// How a [`Vfs`] opens a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Open an existing file, failing with [`io::ErrorKind::NotFound`] if there isn't one.
+    Existing,
+    /// Open the file, creating it empty if there isn't one.
+    Create,
+    /// Create the file empty, replacing any existing one.
+    Replace,
+}
+
+/// Somewhere the files of a database can be kept, like the OS's filesystem.
+pub trait Vfs: Send + Sync {
+    /// Open the file at `path` for reading and writing.
+    ///
+    /// # Errors
+    /// If the file can't be opened.
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<VfsFile>;
+
+    /// Delete the file at `path`.
+    ///
+    /// # Errors
+    /// If the file can't be deleted, including if it doesn't exist.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The OS's filesystem, which databases are kept in unless another [`Vfs`] is given.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OsVfs;
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<VfsFile> {
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(mode != OpenMode::Existing)
+            .truncate(mode == OpenMode::Replace)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+impl Pager<VfsFile> {
+    /// Open the database at `path` through `vfs`, which the pager also keeps its rollback journal
+    /// and WAL in, next to the database.
+    pub fn open_with_vfs(vfs: Arc<dyn Vfs>, path: &Path) -> crate::Result<Self> {
+        let file = vfs
+            .open(path, OpenMode::Existing)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut pager = Self::with_journal(file, journal_path_for(path))?;
+        pager.vfs = vfs;
+        Ok(pager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Mutex, PoisonError},
+    };
+
+    use super::*;
+
+    /// A VFS which records the files opened through it, and fails to open any journals.
+    #[derive(Default)]
+    struct RecordingVfs {
+        opened: Mutex<Vec<(PathBuf, OpenMode)>>,
+    }
+    impl Vfs for RecordingVfs {
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<VfsFile> {
+            self.opened
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((path.to_owned(), mode));
+            if path.to_string_lossy().ends_with("-journal") && mode != OpenMode::Existing {
+                return Err(io::Error::other("Injected failure"));
+            }
+            OsVfs.open(path, mode)
+        }
+
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            OsVfs.delete(path)
+        }
+    }
+
+    #[test]
+    fn test_vfs() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-riir-vfs-{}.sqlite", std::process::id()));
+        fs::copy("test-data/minimal-test.sqlite", &path).expect("Failed to copy database");
+        let original = fs::read(&path).unwrap();
+        let vfs = Arc::new(RecordingVfs::default());
+        let mut pager = Pager::open_with_vfs(vfs.clone(), &path).expect("Failed to open database");
+        let page_size = pager.page_size();
+        pager
+            .write_page(2, &vec![0; page_size])
+            .expect("Failed to write page");
+        assert!(
+            pager.flush().is_err(),
+            "Flushing should fail without a journal"
+        );
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        let journal_path = journal_path_for(&path);
+        let opened = vfs.opened.lock().unwrap();
+        assert_eq!(
+            opened.first(),
+            Some(&(path.clone(), OpenMode::Existing)),
+            "The database should be opened through the VFS",
+        );
+        assert!(opened.contains(&(journal_path, OpenMode::Replace)));
+        fs::remove_file(path).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,1046 @@
+//! The write-ahead log
+//!
+//! In WAL mode, commits append the new version of each page they change to a log file next to the
+//! database, instead of overwriting the database file. Every version stays in the log until it's
+//! checkpointed back into the database, so the log can be replayed up to any commit in it to read
+//! the database as it was then.
+//!
+//! Once every frame has been checkpointed and nobody is reading from the log, the next commit
+//! restarts it from the beginning, with new salts so the old frames are ignored.
+
+mod index;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, RandomState},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    journal::db_path_for_journal, lock::LockKind, DatabaseHeader, LockLevel, OpenMode, Pager,
+    Storage, SyncFile, SyncPolicy, VfsFile, DATABASE_HEADER_SIZE,
+};
+use crate::Error;
+use index::{read_lock, WalIndex, CHECKPOINT_LOCK, READ_MARK_COUNT, READ_MARK_UNUSED, WRITE_LOCK};
+
+/// The size of the header at the start of the WAL.
+const WAL_HEADER_SIZE: usize = 32;
+/// The size of the header before each page in the WAL.
+const FRAME_HEADER_SIZE: usize = 24;
+/// The magic number for a WAL whose checksums are computed with little-endian words.
+const WAL_MAGIC_LITTLE_ENDIAN: u32 = 0x377f_0682;
+/// The magic number for a WAL whose checksums are computed with big-endian words.
+const WAL_MAGIC_BIG_ENDIAN: u32 = 0x377f_0683;
+/// The only WAL format version there is.
+const WAL_FORMAT_VERSION: u32 = 3_007_000;
+
+/// Get the path of the WAL for the database at `db_path`.
+#[must_use]
+pub fn wal_path_for(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-wal");
+    PathBuf::from(path)
+}
+
+/// Get the path of the WAL index for the database at `db_path`.
+#[must_use]
+pub fn shm_path_for(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-shm");
+    PathBuf::from(path)
+}
+
+/// Get the paths of the WAL and the WAL index for the database whose rollback journal is at
+/// `journal_path`, as given by [`journal_path_for`](super::journal_path_for).
+fn wal_paths_for_journal(journal_path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let db_path = db_path_for_journal(journal_path)?;
+    Some((wal_path_for(db_path), shm_path_for(db_path)))
+}
+
+/// How commits are made atomic and durable, as set by `PRAGMA journal_mode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    /// The original contents of changed pages are saved to a rollback journal, which is deleted
+    /// once the database file has been overwritten.
+    Delete,
+    /// Changed pages are appended to the WAL, and copied into the database file by checkpoints.
+    Wal,
+}
+impl JournalMode {
+    /// Get the name `PRAGMA journal_mode` uses for this mode.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Delete => "delete",
+            Self::Wal => "wal",
+        }
+    }
+}
+
+/// How hard a checkpoint tries to copy the whole WAL into the database.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// Copy as many frames as possible without waiting for other connections.
+    Passive,
+    /// Also keep writers out while copying, so the whole WAL is copied unless another connection
+    /// is still reading an older commit.
+    Full,
+}
+
+/// The outcome of a checkpoint, as reported by `PRAGMA wal_checkpoint`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Whether another connection stopped the checkpoint from copying the whole WAL
+    pub busy: bool,
+    /// The number of frames in the WAL
+    pub wal_frames: usize,
+    /// How many of those frames have been copied into the database
+    pub checkpointed_frames: usize,
+}
+
+/// A write-ahead log, holding the commits made since the database was last checkpointed.
+pub struct Wal<File> {
+    /// The WAL file
+    file: File,
+    /// The size of each page, in bytes
+    page_size: usize,
+    /// Whether checksums are computed with big-endian words
+    big_endian: bool,
+    /// The salts which every frame since the WAL was last restarted is marked with
+    salt: [u32; 2],
+    /// The number of times the WAL has been restarted
+    checkpoint_seq: u32,
+    /// The checksum of the last committed frame, or of the header if there are none, which the
+    /// checksum of the next frame continues from
+    checksum: [u32; 2],
+    /// The page which each committed frame holds, in order
+    frame_pages: Vec<u32>,
+    /// The index of the newest committed frame holding each page, counting from 0
+    newest_frames: HashMap<u32, usize>,
+    /// The commits in the WAL, in order
+    commits: Vec<WalCommit>,
+}
+
+/// A commit recorded in the WAL.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WalCommit {
+    /// The number of the last frame in the commit, counting from 1
+    pub frame: usize,
+    /// The number of pages in the database after the commit
+    pub page_count: u32,
+}
+
+/// The pages of the database as of some commit in the WAL.
+pub(crate) struct WalSnapshot {
+    /// The newest version of each page written by the commits up to the snapshot
+    pub(crate) pages: HashMap<usize, Box<[u8]>>,
+    /// The number of pages in the database at the snapshot, or `None` if the snapshot comes
+    /// before every commit in the WAL
+    pub(crate) page_count: Option<u32>,
+}
+
+impl<File: Read + Seek> Wal<File> {
+    /// Read the WAL in `file`.
+    ///
+    /// Only the frames before the first one with the wrong salt or checksum are used, since
+    /// anything after that is left over from before the WAL was last restarted, or from a commit
+    /// which didn't finish.
+    pub fn open(file: File) -> crate::Result<Self> {
+        let mut wal = Self {
+            file,
+            page_size: 0,
+            big_endian: false,
+            salt: [0; 2],
+            checkpoint_seq: 0,
+            checksum: [0; 2],
+            frame_pages: Vec::new(),
+            newest_frames: HashMap::new(),
+            commits: Vec::new(),
+        };
+        wal.reload(None)?;
+        Ok(wal)
+    }
+
+    /// Read the WAL from its file again, to pick up commits made by other connections.
+    ///
+    /// If `max_frame` is given, frames after it are ignored.
+    pub(crate) fn reload(&mut self, max_frame: Option<usize>) -> Result<()> {
+        let mut header = [0; WAL_HEADER_SIZE];
+        self.file
+            .seek(io::SeekFrom::Start(0))
+            .context("Error seeking in WAL")?;
+        self.file
+            .read_exact(&mut header)
+            .context("Error reading WAL header")?;
+        let word =
+            |idx: usize| u32::from_be_bytes(header[idx * 4..idx * 4 + 4].try_into().unwrap());
+        let big_endian = match word(0) {
+            WAL_MAGIC_LITTLE_ENDIAN => false,
+            WAL_MAGIC_BIG_ENDIAN => true,
+            _ => anyhow::bail!("File did not begin with WAL header, is it a WAL?"),
+        };
+        anyhow::ensure!(
+            word(1) == WAL_FORMAT_VERSION,
+            "Unsupported WAL format version {}",
+            word(1)
+        );
+        let page_size = word(2) as usize;
+        anyhow::ensure!(
+            page_size.is_power_of_two() && (512..=65536).contains(&page_size),
+            "Invalid page size in WAL header"
+        );
+        let salt = [word(4), word(5)];
+        let mut checksum = wal_checksum([0, 0], &header[..24], big_endian);
+        anyhow::ensure!(
+            checksum == [word(6), word(7)],
+            "WAL header checksum mismatch"
+        );
+        self.page_size = page_size;
+        self.big_endian = big_endian;
+        self.salt = salt;
+        self.checkpoint_seq = word(3);
+
+        let mut frame_pages = Vec::new();
+        let mut commits = Vec::new();
+        let mut frame = vec![0; FRAME_HEADER_SIZE + page_size];
+        // Frames after the last commit belong to a transaction which never finished.
+        let mut committed_frames = 0;
+        let mut committed_checksum = checksum;
+        while max_frame.map_or(true, |max_frame| frame_pages.len() < max_frame) {
+            match self.file.read_exact(&mut frame) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err).context("Error reading WAL frame"),
+            }
+            let word =
+                |idx: usize| u32::from_be_bytes(frame[idx * 4..idx * 4 + 4].try_into().unwrap());
+            if [word(2), word(3)] != salt {
+                break;
+            }
+            let frame_checksum = wal_checksum(checksum, &frame[..8], big_endian);
+            let frame_checksum =
+                wal_checksum(frame_checksum, &frame[FRAME_HEADER_SIZE..], big_endian);
+            if frame_checksum != [word(4), word(5)] {
+                break;
+            }
+            checksum = frame_checksum;
+            frame_pages.push(word(0));
+            if word(1) != 0 {
+                commits.push(WalCommit {
+                    frame: frame_pages.len(),
+                    page_count: word(1),
+                });
+                committed_frames = frame_pages.len();
+                committed_checksum = checksum;
+            }
+        }
+        frame_pages.truncate(committed_frames);
+        self.checksum = committed_checksum;
+        self.newest_frames = frame_pages
+            .iter()
+            .enumerate()
+            .map(|(frame_idx, &page_idx)| (page_idx, frame_idx))
+            .collect();
+        self.frame_pages = frame_pages;
+        self.commits = commits;
+        Ok(())
+    }
+
+    /// The size of each page in the database, in bytes.
+    #[must_use]
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The commits in the WAL, oldest first.
+    #[must_use]
+    pub fn commits(&self) -> &[WalCommit] {
+        &self.commits
+    }
+
+    /// Get the pages of the database as of the last commit which ends at or before `frame`.
+    ///
+    /// Frame 0 means the database as it was before every commit in the WAL.
+    pub(crate) fn snapshot(&mut self, frame: usize) -> Result<WalSnapshot> {
+        let commit = self
+            .commits
+            .iter()
+            .take_while(|commit| commit.frame <= frame)
+            .last()
+            .copied();
+        let frame_count = commit.map_or(0, |commit| commit.frame);
+        // Later frames hold newer versions, so only the last frame for each page is needed.
+        let mut newest_frames = HashMap::new();
+        for (idx, &page_idx) in self.frame_pages[..frame_count].iter().enumerate() {
+            newest_frames.insert(page_idx as usize, idx);
+        }
+        let mut pages = HashMap::with_capacity(newest_frames.len());
+        for (page_idx, frame_idx) in newest_frames {
+            let mut page = vec![0; self.page_size].into_boxed_slice();
+            self.read_frame(frame_idx, &mut page)?;
+            pages.insert(page_idx, page);
+        }
+        Ok(WalSnapshot {
+            pages,
+            page_count: commit.map(|commit| commit.page_count),
+        })
+    }
+
+    /// Get the number of committed frames in the WAL.
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frame_pages.len()
+    }
+
+    /// Get the number of pages in the database as of the last commit in the WAL, if there is
+    /// one.
+    pub(crate) fn page_count(&self) -> Option<u32> {
+        self.commits.last().map(|commit| commit.page_count)
+    }
+
+    /// Read the newest committed version of the given page into `buf`, returning whether the WAL
+    /// holds a version of it.
+    pub(crate) fn read_newest(&mut self, page_idx: usize, buf: &mut [u8]) -> Result<bool> {
+        let Some(&frame_idx) = u32::try_from(page_idx)
+            .ok()
+            .and_then(|page_idx| self.newest_frames.get(&page_idx))
+        else {
+            return Ok(false);
+        };
+        self.read_frame(frame_idx, buf)?;
+        Ok(true)
+    }
+
+    /// Get each page written by the frames after the first `start`, up to frame `end`, along
+    /// with the index of the newest of those frames holding it.
+    pub(crate) fn newest_frames_between(&self, start: usize, end: usize) -> BTreeMap<u32, usize> {
+        self.frame_pages[start..end]
+            .iter()
+            .enumerate()
+            .map(|(idx, &page_idx)| (page_idx, start + idx))
+            .collect()
+    }
+
+    /// Read the page in the frame at `frame_idx`, counting from 0, into `buf`.
+    pub(crate) fn read_frame(&mut self, frame_idx: usize, buf: &mut [u8]) -> Result<()> {
+        let offset =
+            WAL_HEADER_SIZE + frame_idx * (FRAME_HEADER_SIZE + self.page_size) + FRAME_HEADER_SIZE;
+        self.file
+            .seek(io::SeekFrom::Start(offset as u64))
+            .context("Error seeking in WAL")?;
+        self.file
+            .read_exact(buf)
+            .with_context(|| format!("Error reading frame {} from WAL", frame_idx + 1))
+    }
+}
+
+impl<File: Read + Write + Seek> Wal<File> {
+    /// Start a new, empty WAL in `file`, for a database with pages of `page_size` bytes.
+    ///
+    /// Anything already in `file` is ignored, since it won't have the new WAL's salts.
+    pub fn create(file: File, page_size: usize) -> crate::Result<Self> {
+        let mut wal = Self {
+            file,
+            page_size,
+            big_endian: cfg!(target_endian = "big"),
+            salt: [random_salt(), random_salt()],
+            checkpoint_seq: 0,
+            checksum: [0; 2],
+            frame_pages: Vec::new(),
+            newest_frames: HashMap::new(),
+            commits: Vec::new(),
+        };
+        wal.write_header()?;
+        Ok(wal)
+    }
+
+    /// Empty the WAL, so the next commit is written at its start.
+    ///
+    /// Every frame must have been checkpointed, and no other connection can be reading from it.
+    pub(crate) fn restart(&mut self) -> Result<()> {
+        self.checkpoint_seq = self.checkpoint_seq.wrapping_add(1);
+        self.salt = [self.salt[0].wrapping_add(1), random_salt()];
+        self.frame_pages.clear();
+        self.newest_frames.clear();
+        self.commits.clear();
+        self.write_header()
+    }
+
+    /// Write the WAL header, which starts the checksums over.
+    fn write_header(&mut self) -> Result<()> {
+        let magic = if self.big_endian {
+            WAL_MAGIC_BIG_ENDIAN
+        } else {
+            WAL_MAGIC_LITTLE_ENDIAN
+        };
+        let mut header = [0; WAL_HEADER_SIZE];
+        for (idx, word) in [
+            magic,
+            WAL_FORMAT_VERSION,
+            self.page_size as u32,
+            self.checkpoint_seq,
+            self.salt[0],
+            self.salt[1],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            header[idx * 4..idx * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        self.checksum = wal_checksum([0, 0], &header[..24], self.big_endian);
+        header[24..28].copy_from_slice(&self.checksum[0].to_be_bytes());
+        header[28..32].copy_from_slice(&self.checksum[1].to_be_bytes());
+        self.file
+            .seek(io::SeekFrom::Start(0))
+            .and_then(|_| self.file.write_all(&header))
+            .and_then(|()| self.file.flush())
+            .context("Error writing WAL header")
+    }
+
+    /// Append a commit writing each of `pages` to the WAL, after which the database has
+    /// `page_count` pages.
+    pub(crate) fn append_commit<'a>(
+        &mut self,
+        pages: impl IntoIterator<Item = (usize, &'a [u8])>,
+        page_count: u32,
+    ) -> Result<()> {
+        let pages = pages.into_iter().collect::<Vec<_>>();
+        anyhow::ensure!(!pages.is_empty(), "Cannot commit nothing to the WAL");
+        let frame_size = FRAME_HEADER_SIZE + self.page_size;
+        let mut frames = Vec::with_capacity(pages.len() * frame_size);
+        let mut checksum = self.checksum;
+        for (idx, &(page_idx, contents)) in pages.iter().enumerate() {
+            anyhow::ensure!(
+                contents.len() == self.page_size,
+                "Page contents must be exactly one page long"
+            );
+            let mut header = [0; FRAME_HEADER_SIZE];
+            header[..4].copy_from_slice(&(page_idx as u32).to_be_bytes());
+            // The last frame of a commit records the size of the database after it.
+            if idx == pages.len() - 1 {
+                header[4..8].copy_from_slice(&page_count.to_be_bytes());
+            }
+            header[8..12].copy_from_slice(&self.salt[0].to_be_bytes());
+            header[12..16].copy_from_slice(&self.salt[1].to_be_bytes());
+            checksum = wal_checksum(checksum, &header[..8], self.big_endian);
+            checksum = wal_checksum(checksum, contents, self.big_endian);
+            header[16..20].copy_from_slice(&checksum[0].to_be_bytes());
+            header[20..24].copy_from_slice(&checksum[1].to_be_bytes());
+            frames.extend_from_slice(&header);
+            frames.extend_from_slice(contents);
+        }
+        let offset = WAL_HEADER_SIZE + self.frame_pages.len() * frame_size;
+        self.file
+            .seek(io::SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(&frames))
+            .and_then(|()| self.file.flush())
+            .context("Error writing to WAL")?;
+        for (page_idx, _) in pages {
+            self.newest_frames
+                .insert(page_idx as u32, self.frame_pages.len());
+            self.frame_pages.push(page_idx as u32);
+        }
+        self.commits.push(WalCommit {
+            frame: self.frame_pages.len(),
+            page_count,
+        });
+        self.checksum = checksum;
+        Ok(())
+    }
+}
+
+impl<File: SyncFile> Wal<File> {
+    /// Ensure everything written to the WAL has reached persistent storage.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.sync().context("Failed to sync WAL")
+    }
+}
+
+/// The WAL of a database in WAL mode, which its pager reads from and commits to.
+pub(crate) struct LiveWal {
+    /// The WAL, as of the commit being read
+    log: Wal<VfsFile>,
+    /// The index of the WAL, which is shared with other connections
+    index: WalIndex<VfsFile>,
+    /// The read mark whose lock is held, while reading
+    read_mark: Option<usize>,
+    /// Whether the write lock is held
+    writing: bool,
+}
+impl LiveWal {
+    /// Lock a read mark recording the last commit in the WAL, so other connections don't
+    /// overwrite the frames up to it.
+    ///
+    /// If the index isn't valid, it's rebuilt from the WAL, for a database with `page_count`
+    /// pages if the WAL is empty.
+    fn begin_read(&mut self, page_count: u32) -> Result<()> {
+        // The index can change between reading its header and locking a mark, in which case
+        // it's tried again.
+        for _ in 0..100 {
+            let Some(header) = self.index.header()? else {
+                self.rebuild_index(page_count)?;
+                continue;
+            };
+            let Some(idx) = self.claim_read_mark(header.max_frame)? else {
+                break;
+            };
+            if self.index.header()? == Some(header) {
+                self.read_mark = Some(idx);
+                return Ok(());
+            }
+            self.index.lock(LockKind::Unlock, read_lock(idx))?;
+        }
+        anyhow::bail!(Error::busy())
+    }
+
+    /// Lock a read mark set to `max_frame`, setting an unused one if none are, returning which
+    /// mark it was.
+    fn claim_read_mark(&mut self, max_frame: u32) -> Result<Option<usize>> {
+        // Mark 0 is for readers ignoring the WAL, which this never does.
+        for idx in 1..READ_MARK_COUNT {
+            if self.index.read_mark(idx)? == max_frame
+                && self.index.lock(LockKind::Read, read_lock(idx))?
+            {
+                if self.index.read_mark(idx)? == max_frame {
+                    return Ok(Some(idx));
+                }
+                self.index.lock(LockKind::Unlock, read_lock(idx))?;
+            }
+        }
+        for idx in 1..READ_MARK_COUNT {
+            if self.index.lock(LockKind::Write, read_lock(idx))? {
+                self.index.set_read_mark(idx, max_frame)?;
+                self.index.lock(LockKind::Read, read_lock(idx))?;
+                return Ok(Some(idx));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rebuild the index from the WAL, if no other connection is using it.
+    fn rebuild_index(&mut self, page_count: u32) -> Result<()> {
+        if !self.index.lock_all(LockKind::Write)? {
+            // Another connection is using the index, and may be partway through writing it.
+            return Ok(());
+        }
+        let result = self.log.reload(None).and_then(|()| {
+            let page_count = self.log.page_count().unwrap_or(page_count);
+            self.index.rebuild(&self.log, page_count)
+        });
+        self.index.lock_all(LockKind::Unlock)?;
+        result
+    }
+
+    /// Take the write lock, which must be done before appending to the WAL.
+    fn begin_write(&mut self) -> Result<()> {
+        anyhow::ensure!(self.index.lock(LockKind::Write, WRITE_LOCK)?, Error::busy());
+        // Writing on top of an older commit would lose the commits made since.
+        if !self
+            .index
+            .header()?
+            .is_some_and(|header| header.matches(&self.log))
+        {
+            self.index.lock(LockKind::Unlock, WRITE_LOCK)?;
+            anyhow::bail!(Error::busy());
+        }
+        self.writing = true;
+        Ok(())
+    }
+
+    /// Release the write lock, if it's held.
+    fn end_write(&mut self) -> Result<()> {
+        if std::mem::take(&mut self.writing) {
+            self.index.lock(LockKind::Unlock, WRITE_LOCK)?;
+        }
+        Ok(())
+    }
+
+    /// Release the read mark, if one is held.
+    fn end_read(&mut self) -> Result<()> {
+        if let Some(idx) = self.read_mark.take() {
+            self.index.lock(LockKind::Unlock, read_lock(idx))?;
+        }
+        Ok(())
+    }
+
+    /// Read the WAL again if another connection committed to it since it was last read,
+    /// returning whether it had.
+    fn reload_if_changed(&mut self) -> Result<bool> {
+        let header = self
+            .index
+            .header()?
+            .context("WAL index changed while reading")?;
+        if header.matches(&self.log) {
+            return Ok(false);
+        }
+        self.log.reload(Some(header.max_frame as usize))?;
+        Ok(true)
+    }
+
+    /// Read the newest committed version of the given page into `buf`, returning whether the WAL
+    /// holds a version of it.
+    pub(super) fn read_newest(&mut self, page_idx: usize, buf: &mut [u8]) -> Result<bool> {
+        self.log.read_newest(page_idx, buf)
+    }
+
+    /// Append a commit of `pages` to the WAL, after which the database has `page_count` pages.
+    pub(super) fn commit(
+        &mut self,
+        pages: &BTreeMap<usize, Box<[u8]>>,
+        page_count: u32,
+        sync_policy: SyncPolicy,
+    ) -> Result<()> {
+        anyhow::ensure!(self.writing, "Cannot commit without the WAL write lock");
+        if self.log.frame_count() == 0 {
+            // Another connection may have rewritten the header of the empty WAL, so it's
+            // written again to be sure the frames are appended with matching salts.
+            self.log.restart()?;
+        } else if self.index.backfilled()? as usize == self.log.frame_count() {
+            self.try_restart()?;
+        }
+        let first_frame = self.log.frame_count() + 1;
+        self.log
+            .append_commit(pages.iter().map(|(&idx, page)| (idx, &**page)), page_count)?;
+        // Under the normal policy, the WAL is only synced before checkpoints.
+        if sync_policy == SyncPolicy::Full {
+            self.log.sync()?;
+        }
+        self.index.append(&self.log, first_frame, page_count)
+    }
+
+    /// Restart the WAL, which has been entirely checkpointed, unless another connection is
+    /// reading from it.
+    fn try_restart(&mut self) -> Result<()> {
+        let page_count = self.log.page_count().unwrap_or(0);
+        let mut locked = Vec::new();
+        let mut readers = false;
+        for idx in (1..READ_MARK_COUNT).filter(|&idx| Some(idx) != self.read_mark) {
+            if !self.index.lock(LockKind::Write, read_lock(idx))? {
+                readers = true;
+                break;
+            }
+            locked.push(idx);
+        }
+        let result = if readers {
+            Ok(())
+        } else {
+            self.log.restart().and_then(|()| {
+                self.index.reset_checkpoint(0)?;
+                // Readers starting now must see the WAL is empty before any frames are written.
+                self.index.append(&self.log, 1, page_count)
+            })
+        };
+        for idx in locked {
+            self.index.lock(LockKind::Unlock, read_lock(idx))?;
+        }
+        result
+    }
+}
+
+/// Pick a random salt for a WAL.
+fn random_salt() -> u32 {
+    RandomState::new().hash_one(WAL_FORMAT_VERSION) as u32
+}
+
+impl<File> Pager<File> {
+    /// Get how commits are made atomic and durable.
+    #[must_use]
+    pub fn journal_mode(&self) -> JournalMode {
+        if self.wal.is_some() {
+            JournalMode::Wal
+        } else {
+            JournalMode::Delete
+        }
+    }
+
+    /// Get the number of committed frames in the WAL, which is 0 outside of WAL mode.
+    #[must_use]
+    pub fn wal_frame_count(&self) -> usize {
+        self.wal.as_ref().map_or(0, |wal| wal.log.frame_count())
+    }
+}
+
+impl<File: Read + Seek> Pager<File> {
+    /// Read the WAL again if another connection committed to it, returning whether it had.
+    ///
+    /// This must only be called in WAL mode, while holding a read mark.
+    pub(super) fn reload_wal_if_changed(&mut self) -> Result<bool> {
+        let wal = self.wal.as_mut().context("Not in WAL mode")?;
+        if !wal.reload_if_changed()? {
+            return Ok(false);
+        }
+        anyhow::ensure!(
+            self.dirty_pages.is_empty(),
+            "The database was changed by another connection during a write"
+        );
+        self.reload_header_from_wal()?;
+        Ok(true)
+    }
+
+    /// Read the header again from the newest version of the first page, discarding the cached
+    /// pages, after the WAL has changed.
+    fn reload_header_from_wal(&mut self) -> Result<()> {
+        let page_size = self.page_size();
+        let mut first_page = vec![0; page_size];
+        Self::load_page(
+            &mut self.file,
+            self.wal.as_mut(),
+            self.codec.as_deref(),
+            &mut self.stats,
+            page_size,
+            1,
+            &mut first_page,
+        )?;
+        let mut header =
+            DatabaseHeader::parse(first_page[..DATABASE_HEADER_SIZE].try_into().unwrap())?;
+        if let Some(page_count) = self.wal.as_ref().and_then(|wal| wal.log.page_count()) {
+            header.page_count = page_count;
+        }
+        self.header = header;
+        self.disk_page_count = header.page_count;
+        self.page_cache.clear(header.page_size());
+        Ok(())
+    }
+
+    /// Set the file format version numbers in the database header, which say whether the
+    /// database is in WAL mode.
+    fn set_file_format_version(&mut self, journal_mode: JournalMode) -> Result<()> {
+        let version = match journal_mode {
+            JournalMode::Delete => 1,
+            JournalMode::Wal => 2,
+        };
+        let mut first_page = self.read_page_bytes(1)?.to_vec();
+        first_page[18..20].copy_from_slice(&[version; 2]);
+        Ok(self.write_page(1, &first_page)?)
+    }
+}
+
+impl<File: Storage> Pager<File> {
+    /// Start reading through the WAL if the database is in WAL mode, which the file format version
+    /// in its header records.
+    ///
+    /// This must be called while holding a SHARED lock on the database file but before reading
+    /// from it, since the file itself may be missing commits still in the WAL. If the database
+    /// is in WAL mode, this also takes a read mark, as [`Self::lock_wal`] would.
+    pub(super) fn open_wal_if_needed(&mut self) -> Result<()> {
+        if self.wal.is_some() || self.wal_snapshot.is_some() {
+            return Ok(());
+        }
+        let mut versions = [0; 2];
+        self.file
+            .seek(io::SeekFrom::Start(18))
+            .and_then(|_| self.file.read_exact(&mut versions))
+            .context("Error reading database header from file")?;
+        // Like SQLite, the write version decides whether the database is in WAL mode.
+        if versions[1] != 2 {
+            return Ok(());
+        }
+        let (wal_path, shm_path) = self
+            .journal_path
+            .as_deref()
+            .and_then(wal_paths_for_journal)
+            .context("The database is in WAL mode, but has no journal path to find the WAL by")?;
+        let open = |path: &Path| {
+            self.vfs
+                .open(path, OpenMode::Create)
+                .with_context(|| format!("Failed to open {}", path.display()))
+        };
+        let wal_file = open(&wal_path)?;
+        // The WAL is only given a header once something is committed to it.
+        let log = if wal_file.size().context("Failed to read WAL")? == 0 {
+            Wal::create(wal_file, self.page_size())?
+        } else {
+            Wal::open(wal_file)?
+        };
+        anyhow::ensure!(
+            log.page_size() == self.page_size(),
+            "WAL page size doesn't match the database"
+        );
+        let mut wal = LiveWal {
+            log,
+            index: WalIndex::open(open(&shm_path)?)?,
+            read_mark: None,
+            writing: false,
+        };
+        wal.begin_read(self.header.page_count)?;
+        // The WAL may hold frames which were never recorded in the index, which aren't committed.
+        wal.reload_if_changed()?;
+        self.wal = Some(wal);
+        self.reload_header_from_wal()
+    }
+
+    /// Switch between protecting commits with a rollback journal and with the WAL.
+    ///
+    /// The WAL and its index are kept next to the rollback journal, with `-wal` and `-shm`
+    /// suffixes in place of `-journal`. Leaving WAL mode copies everything in the WAL into the
+    /// database, and needs every other connection to have closed the database.
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> crate::Result<()> {
+        if mode == self.journal_mode() {
+            return Ok(());
+        }
+        if !self.dirty_pages.is_empty() {
+            return Err(Error::Other(
+                "Cannot change the journal mode with unwritten changes".to_owned(),
+            ));
+        }
+        let (wal_path, shm_path) = self
+            .journal_path
+            .as_deref()
+            .and_then(wal_paths_for_journal)
+            .context("Can only use WAL mode for databases with a journal next to them")?;
+        match mode {
+            JournalMode::Wal => {
+                self.lock(LockLevel::Exclusive)?;
+                self.set_file_format_version(JournalMode::Wal)?;
+                self.flush()?;
+                let open = |path: &Path| {
+                    self.vfs
+                        .open(path, OpenMode::Replace)
+                        .with_context(|| format!("Failed to create {}", path.display()))
+                };
+                let log = Wal::create(open(&wal_path)?, self.page_size())?;
+                let mut index = WalIndex::open(open(&shm_path)?)?;
+                index.rebuild(&log, self.header.page_count)?;
+                self.unlock(LockLevel::None)?;
+                self.wal = Some(LiveWal {
+                    log,
+                    index,
+                    read_mark: None,
+                    writing: false,
+                });
+                self.lock(LockLevel::Shared)
+            }
+            JournalMode::Delete => {
+                // SQLite checks that no other connection is using the WAL by taking an EXCLUSIVE
+                // lock on the database file.
+                self.lock(LockLevel::Shared)?;
+                self.lock_file(LockLevel::Exclusive)?;
+                let checkpoint = self.checkpoint(CheckpointMode::Full)?;
+                if checkpoint.busy {
+                    return Err(Error::busy());
+                }
+                // Closing the WAL and its index releases the locks on them.
+                self.wal = None;
+                // The shared page cache isn't used in WAL mode, so it may hold pages which the
+                // checkpoint changed.
+                self.page_cache.clear(self.page_size());
+                self.set_file_format_version(JournalMode::Delete)?;
+                self.flush()?;
+                self.vfs
+                    .delete(&wal_path)
+                    .and_then(|()| self.vfs.delete(&shm_path))
+                    .context("Failed to remove WAL")?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy the pages in the WAL back into the database file, so the WAL can be restarted.
+    ///
+    /// Frames which another connection might still need, because it's reading the database as
+    /// of an earlier commit, aren't copied, and neither are frames committed since this
+    /// connection started reading. The database file is shrunk to fit if the whole WAL was
+    /// copied.
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> crate::Result<Checkpoint> {
+        if !self.dirty_pages.is_empty() {
+            return Err(Error::Other(
+                "Cannot checkpoint with unwritten changes".to_owned(),
+            ));
+        }
+        let wal = self
+            .wal
+            .as_mut()
+            .context("The database is not in WAL mode")?;
+        let busy = Checkpoint {
+            busy: true,
+            wal_frames: wal.log.frame_count(),
+            checkpointed_frames: wal.index.backfilled()? as usize,
+        };
+        if !wal.index.lock(LockKind::Write, CHECKPOINT_LOCK)? {
+            return Ok(busy);
+        }
+        // A full checkpoint keeps writers from adding frames while it copies them.
+        let write_lock = mode == CheckpointMode::Full && !wal.writing;
+        if write_lock && !wal.index.lock(LockKind::Write, WRITE_LOCK)? {
+            wal.index.lock(LockKind::Unlock, CHECKPOINT_LOCK)?;
+            return Ok(busy);
+        }
+        let result = self.checkpoint_locked();
+        let wal = self
+            .wal
+            .as_mut()
+            .context("The database is not in WAL mode")?;
+        if write_lock {
+            wal.index.lock(LockKind::Unlock, WRITE_LOCK)?;
+        }
+        wal.index.lock(LockKind::Unlock, CHECKPOINT_LOCK)?;
+        Ok(result?)
+    }
+
+    /// Copy the WAL back into the database file, while holding the checkpoint lock.
+    fn checkpoint_locked(&mut self) -> Result<Checkpoint> {
+        let Self {
+            file,
+            wal,
+            header,
+            sync_policy,
+            ..
+        } = self;
+        let wal = wal.as_mut().context("The database is not in WAL mode")?;
+        let wal_frames = wal.log.frame_count();
+        let backfilled = wal.index.backfilled()? as usize;
+        // Readers of earlier commits would see the later versions of pages if they were copied.
+        let mut safe_frames = wal_frames;
+        for idx in (1..READ_MARK_COUNT).filter(|&idx| Some(idx) != wal.read_mark) {
+            let mark = wal.index.read_mark(idx)?;
+            if (mark as usize) < safe_frames {
+                if wal.index.lock(LockKind::Write, read_lock(idx))? {
+                    let mark = if idx == 1 {
+                        safe_frames as u32
+                    } else {
+                        READ_MARK_UNUSED
+                    };
+                    wal.index.set_read_mark(idx, mark)?;
+                    wal.index.lock(LockKind::Unlock, read_lock(idx))?;
+                } else {
+                    safe_frames = mark as usize;
+                }
+            }
+        }
+        if backfilled < safe_frames {
+            // Under the full policy, the WAL was synced as each commit was written.
+            if *sync_policy == SyncPolicy::Normal {
+                wal.log.sync()?;
+            }
+            let page_size = header.page_size();
+            let mut page = vec![0; page_size];
+            for (page_idx, frame_idx) in wal.log.newest_frames_between(backfilled, safe_frames) {
+                wal.log.read_frame(frame_idx, &mut page)?;
+                file.seek(io::SeekFrom::Start(
+                    (page_size * (page_idx as usize - 1)) as u64,
+                ))
+                .and_then(|_| file.write_all(&page))
+                .with_context(|| format!("Error writing page {page_idx} to database file"))?;
+            }
+            if *sync_policy != SyncPolicy::Off {
+                file.sync().context("Error syncing database file")?;
+            }
+            if safe_frames == wal_frames {
+                let page_count = wal.log.page_count().unwrap_or(header.page_count);
+                file.set_len((page_size * page_count as usize) as u64)
+                    .context("Error truncating database file")?;
+            }
+            wal.index.set_backfilled(safe_frames as u32)?;
+        }
+        // The map is used again if the database leaves WAL mode.
+        self.remap()?;
+        Ok(Checkpoint {
+            busy: safe_frames < wal_frames,
+            wal_frames,
+            checkpointed_frames: safe_frames.max(backfilled),
+        })
+    }
+
+    /// Raise the lock held on a database in WAL mode to `level`.
+    ///
+    /// Commits don't write to the database file, so writers never have to wait for readers, and
+    /// the locks above RESERVED aren't needed.
+    pub(super) fn lock_wal(&mut self, level: LockLevel) -> Result<()> {
+        let level = level.min(LockLevel::Reserved);
+        if self.lock >= level {
+            return Ok(());
+        }
+        if self.lock == LockLevel::None {
+            // The SHARED lock on the database file is usually still held from the last read.
+            self.lock_file(LockLevel::Shared)?;
+            let page_count = self.header.page_count;
+            let wal = self.wal.as_mut().context("Not in WAL mode")?;
+            if let Err(e) = wal.begin_read(page_count) {
+                self.lock = LockLevel::None;
+                return Err(e);
+            }
+        }
+        if level == LockLevel::Reserved {
+            self.wal
+                .as_mut()
+                .context("Not in WAL mode")?
+                .begin_write()?;
+            self.lock = LockLevel::Reserved;
+        }
+        Ok(())
+    }
+
+    /// Lower the lock held on a database in WAL mode to `level`.
+    pub(super) fn unlock_wal(&mut self, level: LockLevel) -> Result<()> {
+        if self.lock <= level {
+            return Ok(());
+        }
+        let wal = self.wal.as_mut().context("Not in WAL mode")?;
+        wal.end_write()?;
+        // Like SQLite, the SHARED lock on the database file is kept while in WAL mode, which stops
+        // other connections from deleting the WAL when they close.
+        if level == LockLevel::None {
+            wal.end_read()?;
+        }
+        self.lock = level;
+        Ok(())
+    }
+}
+
+/// Continue a WAL checksum from `checksum` over `data`, whose length must be a multiple of 8.
+///
+/// The data is read as 32-bit words, in the byte order the WAL's magic number specifies.
+pub(super) fn wal_checksum(checksum: [u32; 2], data: &[u8], big_endian: bool) -> [u32; 2] {
+    let [mut s0, mut s1] = checksum;
+    for pair in data.chunks_exact(8) {
+        let word = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+    }
+    [s0, s1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn test_read_wal() {
+        let contents = fs::read("test-data/wal-history.sqlite-wal").unwrap();
+        let mut wal = Wal::open(Cursor::new(contents.clone())).expect("Failed to read WAL");
+        assert_eq!(wal.page_size(), 4096);
+        assert_eq!(
+            wal.commits()
+                .iter()
+                .map(|commit| commit.frame)
+                .collect::<Vec<_>>(),
+            [2, 3, 4, 5, 10],
+        );
+        assert_eq!(wal.commits().last().unwrap().page_count, 5);
+
+        let snapshot = wal.snapshot(9).expect("Failed to read snapshot");
+        assert_eq!(snapshot.page_count, Some(2), "Frame 9 isn't a commit");
+        assert_eq!(snapshot.pages.len(), 2);
+        let snapshot = wal.snapshot(0).expect("Failed to read snapshot");
+        assert_eq!(snapshot.page_count, None);
+        assert!(snapshot.pages.is_empty());
+
+        // Corrupting a frame cuts the WAL off at the last commit before it.
+        let mut corrupted = contents;
+        corrupted[WAL_HEADER_SIZE + 6 * (FRAME_HEADER_SIZE + 4096) + 100] ^= 1;
+        let wal = Wal::open(Cursor::new(corrupted)).expect("Failed to read WAL");
+        assert_eq!(wal.commits().len(), 4);
+        assert_eq!(wal.frame_pages.len(), 5);
+    }
+}
@@ -0,0 +1,408 @@
+//! The WAL index, which lets connections share what's in the WAL
+//!
+//! The index lives in a file next to the WAL with a `-shm` suffix. SQLite maps it into the memory
+//! of every connection using the database, so its fields are in native byte order. It begins with
+//! two copies of a header describing the committed contents of the WAL, which writers update one
+//! after the other so readers can tell whether they saw a torn write. Then come the checkpoint
+//! state and the read marks, which readers use to say which commit they're reading as of.
+//!
+//! After that are 32 KiB blocks, each holding the page number of 4096 frames (fewer in the first
+//! block, which shares its space with the headers) and a hash table mapping page numbers to those
+//! frames.
+//!
+//! Connections coordinate with locks on bytes in the index's header, rather than in the database
+//! file.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::{Context, Result};
+
+use super::{wal_checksum, Wal};
+use crate::pager::{LockKind, Storage};
+
+/// The version of the index format, which is always the same as the WAL's.
+const INDEX_VERSION: u32 = super::WAL_FORMAT_VERSION;
+/// The size of each copy of the index header.
+const HEADER_SIZE: usize = 48;
+/// The offset of the number of frames which have been checkpointed into the database.
+const BACKFILL_OFFSET: usize = 2 * HEADER_SIZE;
+/// The offset of the first read mark.
+const READ_MARK_OFFSET: usize = BACKFILL_OFFSET + 4;
+/// The offset of the number of frames a checkpoint last tried to write into the database.
+const BACKFILL_ATTEMPTED_OFFSET: usize = 128;
+/// The total size of the headers, before the first block's page numbers.
+const INDEX_HEADER_SIZE: usize = 136;
+
+/// The number of read marks, of which mark 0 is only used by readers ignoring the WAL.
+pub(crate) const READ_MARK_COUNT: usize = 5;
+/// The value of a read mark which no reader is using.
+pub(crate) const READ_MARK_UNUSED: u32 = u32::MAX;
+
+/// The number of frames whose page numbers each block holds.
+const BLOCK_FRAMES: usize = 4096;
+/// The number of frames in the first block, whose start is taken up by the headers.
+const FIRST_BLOCK_FRAMES: usize = BLOCK_FRAMES - INDEX_HEADER_SIZE / 4;
+/// The number of slots in each block's hash table.
+const HASH_SLOTS: usize = 2 * BLOCK_FRAMES;
+/// The size of each block, in bytes.
+const BLOCK_SIZE: usize = 4 * BLOCK_FRAMES + 2 * HASH_SLOTS;
+
+/// The byte locked by the connection writing to the WAL.
+pub(crate) const WRITE_LOCK: u64 = 120;
+/// The byte locked by the connection checkpointing the WAL.
+pub(crate) const CHECKPOINT_LOCK: u64 = 121;
+/// The byte every connection using the index holds a read lock on, so the first connection to
+/// open it can tell it's left over from an earlier one.
+const DMS_LOCK: u64 = 128;
+
+/// The byte locked by readers using the read mark at `idx`.
+pub(crate) fn read_lock(idx: usize) -> u64 {
+    123 + idx as u64
+}
+
+/// The committed contents of the WAL, as recorded in the index header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IndexHeader {
+    /// How many times the index has been changed
+    change: u32,
+    /// Whether the WAL's checksums use big-endian words
+    big_endian: bool,
+    /// The size of each page, in bytes
+    page_size: usize,
+    /// The number of frames in the WAL up to its last commit
+    pub(crate) max_frame: u32,
+    /// The number of pages in the database as of the last commit
+    page_count: u32,
+    /// The checksum of the last frame of the last commit
+    frame_checksum: [u32; 2],
+    /// The salts from the WAL header, in the order they're stored there
+    salt: [u8; 8],
+}
+impl IndexHeader {
+    /// Get the header describing the committed contents of `wal`, in a database with
+    /// `page_count` pages.
+    fn for_wal<File>(wal: &Wal<File>, page_count: u32, change: u32) -> Self {
+        let mut salt = [0; 8];
+        salt[..4].copy_from_slice(&wal.salt[0].to_be_bytes());
+        salt[4..].copy_from_slice(&wal.salt[1].to_be_bytes());
+        Self {
+            change,
+            big_endian: wal.big_endian,
+            page_size: wal.page_size,
+            max_frame: wal.frame_pages.len() as u32,
+            page_count,
+            frame_checksum: wal.checksum,
+            salt,
+        }
+    }
+
+    /// Whether this header describes the same commits as are in `wal`.
+    ///
+    /// Every empty WAL matches, since the salts only matter once there are frames to check them
+    /// against.
+    pub(crate) fn matches<File>(&self, wal: &Wal<File>) -> bool {
+        let current = Self::for_wal(wal, self.page_count, self.change);
+        if self.max_frame == 0 && current.max_frame == 0 {
+            return true;
+        }
+        (self.max_frame, self.salt, self.frame_checksum)
+            == (current.max_frame, current.salt, current.frame_checksum)
+    }
+
+    /// Parse a copy of the header, returning `None` if it's uninitialized or its checksum is
+    /// wrong.
+    fn parse(bytes: &[u8; HEADER_SIZE]) -> Option<Self> {
+        let word =
+            |offset: usize| u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let checksum = wal_checksum([0, 0], &bytes[..40], cfg!(target_endian = "big"));
+        if word(0) != INDEX_VERSION || bytes[12] == 0 || checksum != [word(40), word(44)] {
+            return None;
+        }
+        let page_size = u16::from_ne_bytes(bytes[14..16].try_into().unwrap());
+        Some(Self {
+            change: word(8),
+            big_endian: bytes[13] != 0,
+            page_size: (usize::from(page_size) & 0xff00) | ((usize::from(page_size) & 1) << 16),
+            max_frame: word(16),
+            page_count: word(20),
+            frame_checksum: [word(24), word(28)],
+            salt: bytes[32..40].try_into().unwrap(),
+        })
+    }
+
+    /// Serialize a copy of the header.
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&INDEX_VERSION.to_ne_bytes());
+        bytes[8..12].copy_from_slice(&self.change.to_ne_bytes());
+        bytes[12] = 1;
+        bytes[13] = u8::from(self.big_endian);
+        // 65536 doesn't fit, so it's stored as 1.
+        let page_size = (self.page_size & 0xff00) | (self.page_size >> 16);
+        bytes[14..16].copy_from_slice(&(page_size as u16).to_ne_bytes());
+        bytes[16..20].copy_from_slice(&self.max_frame.to_ne_bytes());
+        bytes[20..24].copy_from_slice(&self.page_count.to_ne_bytes());
+        bytes[24..28].copy_from_slice(&self.frame_checksum[0].to_ne_bytes());
+        bytes[28..32].copy_from_slice(&self.frame_checksum[1].to_ne_bytes());
+        bytes[32..40].copy_from_slice(&self.salt);
+        let checksum = wal_checksum([0, 0], &bytes[..40], cfg!(target_endian = "big"));
+        bytes[40..44].copy_from_slice(&checksum[0].to_ne_bytes());
+        bytes[44..48].copy_from_slice(&checksum[1].to_ne_bytes());
+        bytes
+    }
+}
+
+/// The index of the WAL, shared with other connections to the database.
+pub(crate) struct WalIndex<File> {
+    /// The `-shm` file
+    file: File,
+}
+impl<File: Read + Write + Seek> WalIndex<File> {
+    /// Use the index in `file`.
+    pub(crate) fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    /// Read the index header, returning `None` if it isn't valid.
+    ///
+    /// The header is invalid if the index was never written, or if its copies differ because
+    /// another connection is partway through changing it.
+    pub(crate) fn header(&mut self) -> Result<Option<IndexHeader>> {
+        let mut bytes = [0; 2 * HEADER_SIZE];
+        self.read_at(0, &mut bytes)?;
+        let (first, second) = bytes.split_at(HEADER_SIZE);
+        if first != second {
+            return Ok(None);
+        }
+        Ok(IndexHeader::parse(first.try_into().unwrap()))
+    }
+
+    /// Record the frames committed to `wal` since the index was last updated, from frame
+    /// `first_frame` (counting from 1) onwards, in a database which now has `page_count` pages.
+    pub(crate) fn append<WalFile>(
+        &mut self,
+        wal: &Wal<WalFile>,
+        first_frame: usize,
+        page_count: u32,
+    ) -> Result<()> {
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut loaded_block = None;
+        for (frame_idx, &page_idx) in wal.frame_pages.iter().enumerate().skip(first_frame - 1) {
+            let frame = frame_idx + 1;
+            let block_idx = (frame + BLOCK_FRAMES - FIRST_BLOCK_FRAMES - 1) / BLOCK_FRAMES;
+            if loaded_block != Some(block_idx) {
+                if let Some(loaded_block) = loaded_block {
+                    self.write_block(loaded_block, &block)?;
+                }
+                self.read_at(block_idx * BLOCK_SIZE, &mut block)?;
+                loaded_block = Some(block_idx);
+            }
+            let (pages_start, first_block_frame) = if block_idx == 0 {
+                (INDEX_HEADER_SIZE, 0)
+            } else {
+                (0, FIRST_BLOCK_FRAMES + (block_idx - 1) * BLOCK_FRAMES)
+            };
+            // Entries are numbered from 1, so a zero in the hash table is an empty slot.
+            let entry = frame - first_block_frame;
+            if entry == 1 {
+                // The block is being reused after the WAL restarted, so its old entries are stale.
+                block[pages_start..].fill(0);
+            }
+            let page_offset = pages_start + 4 * (entry - 1);
+            block[page_offset..page_offset + 4].copy_from_slice(&page_idx.to_ne_bytes());
+            let hash_start = 4 * BLOCK_FRAMES;
+            let mut slot = (page_idx as usize * 383) % HASH_SLOTS;
+            while block[hash_start + 2 * slot..hash_start + 2 * slot + 2] != [0, 0] {
+                slot = (slot + 1) % HASH_SLOTS;
+            }
+            block[hash_start + 2 * slot..hash_start + 2 * slot + 2]
+                .copy_from_slice(&(entry as u16).to_ne_bytes());
+        }
+        if let Some(loaded_block) = loaded_block {
+            self.write_block(loaded_block, &block)?;
+        }
+        let change = self
+            .header()?
+            .map_or(0, |header| header.change.wrapping_add(1));
+        self.write_header(IndexHeader::for_wal(wal, page_count, change))
+    }
+
+    /// Rebuild the whole index from `wal`, forgetting any checkpoints and readers.
+    ///
+    /// This must only be done while no other connection is using the index.
+    pub(crate) fn rebuild<WalFile>(&mut self, wal: &Wal<WalFile>, page_count: u32) -> Result<()> {
+        // An invalid header, so nothing relies on the index while it's rebuilt
+        self.write_at(0, &[0; 2 * HEADER_SIZE])?;
+        self.reset_checkpoint(wal.frame_pages.len() as u32)?;
+        if wal.frame_pages.is_empty() {
+            let change = self
+                .header()?
+                .map_or(0, |header| header.change.wrapping_add(1));
+            return self.write_header(IndexHeader::for_wal(wal, page_count, change));
+        }
+        self.append(wal, 1, page_count)
+    }
+
+    /// Forget any frames which were checkpointed and any readers, after the WAL was restarted
+    /// or rebuilt with `max_frame` frames in it.
+    pub(crate) fn reset_checkpoint(&mut self, max_frame: u32) -> Result<()> {
+        self.set_backfilled(0)?;
+        self.set_read_mark(0, 0)?;
+        self.set_read_mark(1, max_frame)?;
+        for idx in 2..READ_MARK_COUNT {
+            self.set_read_mark(idx, READ_MARK_UNUSED)?;
+        }
+        Ok(())
+    }
+
+    /// Get the number of frames which have been checkpointed into the database.
+    pub(crate) fn backfilled(&mut self) -> Result<u32> {
+        self.read_u32(BACKFILL_OFFSET)
+    }
+
+    /// Record that the first `frames` frames have been checkpointed into the database.
+    pub(crate) fn set_backfilled(&mut self, frames: u32) -> Result<()> {
+        self.write_at(BACKFILL_OFFSET, &frames.to_ne_bytes())?;
+        self.write_at(BACKFILL_ATTEMPTED_OFFSET, &frames.to_ne_bytes())
+    }
+
+    /// Get the number of frames the reader holding the read mark at `idx` is using.
+    pub(crate) fn read_mark(&mut self, idx: usize) -> Result<u32> {
+        self.read_u32(READ_MARK_OFFSET + 4 * idx)
+    }
+
+    /// Set the read mark at `idx`, which requires holding its lock exclusively.
+    pub(crate) fn set_read_mark(&mut self, idx: usize, frames: u32) -> Result<()> {
+        self.write_at(READ_MARK_OFFSET + 4 * idx, &frames.to_ne_bytes())
+    }
+
+    /// Write both copies of the index header, one after the other.
+    fn write_header(&mut self, header: IndexHeader) -> Result<()> {
+        let bytes = header.to_bytes();
+        self.write_at(HEADER_SIZE, &bytes)?;
+        self.write_at(0, &bytes)
+    }
+
+    /// Write the page numbers and hash table of the block at `block_idx`.
+    fn write_block(&mut self, block_idx: usize, block: &[u8]) -> Result<()> {
+        let start = if block_idx == 0 { INDEX_HEADER_SIZE } else { 0 };
+        self.write_at(block_idx * BLOCK_SIZE + start, &block[start..])
+    }
+
+    /// Read the native-endian `u32` at `offset`.
+    fn read_u32(&mut self, offset: usize) -> Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_at(offset, &mut bytes)?;
+        Ok(u32::from_ne_bytes(bytes))
+    }
+
+    /// Fill `buf` from `offset` in the index, with zeroes past the end of the file.
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(offset as u64))
+            .context("Error seeking in WAL index")?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err).context("Error reading WAL index"),
+            }
+        }
+        buf[filled..].fill(0);
+        Ok(())
+    }
+
+    /// Write `bytes` at `offset` in the index.
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(bytes))
+            .context("Error writing WAL index")
+    }
+}
+
+impl<File: Storage> WalIndex<File> {
+    /// Start using the index in `file`, emptying it first if no other connection is using it.
+    ///
+    /// Without other connections, the index can't be trusted, since its last user may have
+    /// crashed partway through writing it.
+    pub(crate) fn open(file: File) -> Result<Self> {
+        let mut index = Self::new(file);
+        if index.lock(LockKind::Write, DMS_LOCK)? {
+            index.file.set_len(0).context("Failed to empty WAL index")?;
+        }
+        anyhow::ensure!(index.lock(LockKind::Read, DMS_LOCK)?, crate::Error::busy());
+        Ok(index)
+    }
+
+    /// Set a lock of the given kind on the byte at `offset` in the index, returning whether it
+    /// was set or another connection holds a conflicting lock.
+    pub(crate) fn lock(&self, kind: LockKind, offset: u64) -> Result<bool> {
+        self.file
+            .set_lock(kind, offset, 1)
+            .context("Error locking WAL index")
+    }
+
+    /// Set a lock of the given kind on every byte which is locked to use the index, as is done
+    /// to rebuild it.
+    pub(crate) fn lock_all(&self, kind: LockKind) -> Result<bool> {
+        let len = read_lock(READ_MARK_COUNT - 1) + 1 - WRITE_LOCK;
+        self.file
+            .set_lock(kind, WRITE_LOCK, len)
+            .context("Error locking WAL index")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_index_layout() {
+        let mut wal = Wal::create(Cursor::new(Vec::new()), 1024).expect("Failed to create WAL");
+        let pages = (1..=5000)
+            .map(|page_idx| {
+                (
+                    page_idx % 7 + 1,
+                    vec![page_idx as u8; 1024].into_boxed_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for commit in pages.chunks(100) {
+            let commit = commit.iter().map(|(idx, page)| (*idx, &**page));
+            wal.append_commit(commit, 8).expect("Failed to append");
+        }
+        let mut index = WalIndex::new(Cursor::new(Vec::new()));
+        assert_eq!(index.header().unwrap(), None);
+        index.rebuild(&wal, 8).expect("Failed to build index");
+        let header = index
+            .header()
+            .unwrap()
+            .expect("Index header should be valid");
+        assert!(header.matches(&wal));
+        assert_eq!(header.max_frame, 5000);
+        assert_eq!(header.page_size, 1024);
+        assert_eq!(index.file.get_ref().len(), 2 * BLOCK_SIZE);
+        assert_eq!(index.backfilled().unwrap(), 0);
+        assert_eq!(index.read_mark(1).unwrap(), 5000);
+        assert_eq!(index.read_mark(2).unwrap(), READ_MARK_UNUSED);
+
+        // Frame 4062 is the last in the first block, and frame 4063 is the first in the second.
+        let contents = index.file.get_ref();
+        let page_at =
+            |offset: usize| u32::from_ne_bytes(contents[offset..offset + 4].try_into().unwrap());
+        assert_eq!(page_at(INDEX_HEADER_SIZE), 2);
+        assert_eq!(page_at(INDEX_HEADER_SIZE + 4 * 4061), 4062 % 7 + 1);
+        assert_eq!(page_at(BLOCK_SIZE), 4063 % 7 + 1);
+        let slot = (4063 % 7 + 1) * 383;
+        assert_eq!(
+            contents[BLOCK_SIZE + 4 * BLOCK_FRAMES + 2 * slot..][..2],
+            1_u16.to_ne_bytes(),
+            "The first frame of a block should be in its hash table",
+        );
+    }
+}
@@ -1,10 +1,12 @@
 //! Tools for handling records
 
-use std::fmt;
+use std::{borrow::Cow, cmp::Ordering, fmt};
 
 use anyhow::{Context, Result};
 
-use crate::parse_varint;
+use crate::{parse_varint, varint_len, write_varint, Error};
+#[cfg(feature = "derive")]
+pub use sqlite_riir_derive::FromRow;
 
 #[derive(Copy, Clone)]
 pub struct Record<'a> {
@@ -22,6 +24,59 @@ impl<'a> Record<'a> {
         Ok(Self { header, body })
     }
 
+    /// Serialize `values` into the record format.
+    ///
+    /// Integers are stored as the serial type they were read as, so records are rebuilt exactly as
+    /// they were, unless they were made with [`Value::int`], which picks the smallest type that can
+    /// hold them.
+    pub fn build<Blob: AsRef<[u8]>>(values: &[Value<Blob>]) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut body = Vec::new();
+        for value in values {
+            let ty = value.serial_type();
+            write_varint(ty.to_numeric(), &mut types);
+            value.write_body(ty, &mut body);
+        }
+        let header_len = header_len(types.len());
+        let mut record = Vec::with_capacity(header_len + body.len());
+        write_varint(header_len as i64, &mut record);
+        record.extend(types);
+        record.extend(body);
+        record
+    }
+
+    /// Get the number of bytes [`Self::build`] would serialize `values` into, without building
+    /// the record.
+    ///
+    /// This is enough to work out whether a row fits in its page, or needs overflow pages, before
+    /// building it.
+    #[must_use]
+    pub fn serialized_size<Blob: AsRef<[u8]>>(values: &[Value<Blob>]) -> usize {
+        let (types_len, body_len) = values.iter().fold((0, 0), |(types_len, body_len), value| {
+            let ty = value.serial_type();
+            (
+                types_len + varint_len(ty.to_numeric()),
+                body_len + ty.body_len(),
+            )
+        });
+        header_len(types_len) + body_len
+    }
+
+    /// Serialize `values` into the record format, with text converted from UTF-8 to `encoding`.
+    pub fn build_encoded<Blob: AsRef<[u8]>>(
+        values: &[Value<Blob>],
+        encoding: TextEncoding,
+    ) -> Vec<u8> {
+        if encoding == TextEncoding::Utf8 {
+            return Self::build(values);
+        }
+        let values = values
+            .iter()
+            .map(|value| value.encode_text(encoding))
+            .collect::<Vec<_>>();
+        Self::build(&values)
+    }
+
     /// Return an iterator over the [types of values](ColumnType) in `self`.
     pub fn type_iter(&self) -> impl Iterator<Item = ColumnType> + 'a {
         HeaderTypesIter::new(self.header)
@@ -34,6 +89,123 @@ impl<'a> Record<'a> {
             body: self.body,
         }
     }
+
+    /// Get the value in column `idx`, or `None` if the record has fewer columns.
+    ///
+    /// The values before it are skipped over using the sizes in the header, without decoding
+    /// them, which makes this much cheaper than [`Self::value_iter`] for a late column in a wide
+    /// record. To read several columns, use [`Self::decoded`] instead.
+    pub fn value_at(&self, idx: usize) -> crate::Result<Option<Value<&'a [u8]>>> {
+        let mut types = HeaderTypesIter::new(self.header);
+        let offset = types.by_ref().take(idx).map(ColumnType::body_len).sum();
+        let Some(ty) = types.next() else {
+            return Ok(None);
+        };
+        value_in_body(self.body, ty, offset).map(Some)
+    }
+
+    /// Read every value in `self`, checking that the header is well-formed and describes exactly
+    /// the bytes in the body.
+    ///
+    /// Unlike [`Self::value_iter`], this doesn't panic on a corrupt record, so it can be used to
+    /// tell whether bytes which might not be a record are one.
+    pub(crate) fn checked_values(&self) -> Result<Vec<Value<&'a [u8]>>> {
+        let mut header = self.header;
+        parse_varint(&mut header)?;
+        let mut body = self.body;
+        let mut values = Vec::new();
+        while !header.is_empty() {
+            let numeric = parse_varint(&mut header)?;
+            anyhow::ensure!(
+                numeric >= 0 && !matches!(numeric, 10 | 11),
+                "Invalid serial type {numeric}"
+            );
+            values.push(Value::parse_for_ty(
+                ColumnType::from_numeric(numeric),
+                &mut body,
+            )?);
+        }
+        anyhow::ensure!(body.is_empty(), "Record is longer than its header says");
+        Ok(values)
+    }
+
+    /// Read the types of the first `columns` columns from the header, and where their values
+    /// are, so that any of them can then be read without going through the ones before it.
+    ///
+    /// Pass `usize::MAX` to read every column.
+    #[must_use]
+    pub fn decoded(&self, columns: usize) -> DecodedRecord<'a> {
+        let mut offset = 0;
+        let columns = HeaderTypesIter::new(self.header)
+            .take(columns)
+            .map(|ty| {
+                let start = offset;
+                offset += ty.body_len();
+                (ty, start)
+            })
+            .collect();
+        DecodedRecord {
+            body: self.body,
+            columns,
+        }
+    }
+}
+
+/// Get the length of a record header whose serial types take up `types_len` bytes.
+///
+/// The header length includes the varint which stores it, so this is found by iterating until the
+/// length fits in the varint it's given.
+fn header_len(types_len: usize) -> usize {
+    let mut header_len = types_len + 1;
+    while varint_len(header_len as i64) + types_len != header_len {
+        header_len = varint_len(header_len as i64) + types_len;
+    }
+    header_len
+}
+
+/// A [`Record`] whose header has been read, so its values can be read in any order.
+#[derive(Clone)]
+pub struct DecodedRecord<'a> {
+    /// The body, containing the raw data
+    body: &'a [u8],
+    /// The type of each column that was read from the header, and where its value starts in
+    /// the body
+    columns: Vec<(ColumnType, usize)>,
+}
+impl<'a> DecodedRecord<'a> {
+    /// Get the number of columns which were read from the header.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// Whether no columns were read from the header.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Get the type of the value in column `idx`, if it was read from the header.
+    #[must_use]
+    pub fn ty(&self, idx: usize) -> Option<ColumnType> {
+        self.columns.get(idx).map(|(ty, _)| *ty)
+    }
+
+    /// Get the value in column `idx`, or `None` if it wasn't read from the header.
+    pub fn get(&self, idx: usize) -> crate::Result<Option<Value<&'a [u8]>>> {
+        self.columns
+            .get(idx)
+            .map(|&(ty, offset)| value_in_body(self.body, ty, offset))
+            .transpose()
+    }
+}
+
+/// Parse the value of type `ty` which starts `offset` bytes into the body of a record.
+fn value_in_body(body: &[u8], ty: ColumnType, offset: usize) -> crate::Result<Value<&[u8]>> {
+    let mut value = body
+        .get(offset..)
+        .ok_or_else(|| Error::corrupt(None, "End of payload while skipping to a value"))?;
+    Value::parse_for_ty(ty, &mut value)
 }
 
 struct RecordValueIter<'a> {
@@ -83,143 +255,312 @@ impl<'a> Iterator for HeaderTypesIter<'a> {
     }
 }
 
+/// The format text is stored in, which is the same for every string in a database.
+///
+/// Strings are always UTF-8 once read out of a record, so they're converted to and from the
+/// database's encoding at that boundary.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+impl TextEncoding {
+    /// Get the name of this encoding, as `PRAGMA encoding` reports it.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Utf8 => "UTF-8",
+            Self::Utf16Le => "UTF-16le",
+            Self::Utf16Be => "UTF-16be",
+        }
+    }
+
+    /// Convert text stored in this encoding to UTF-8.
+    ///
+    /// Invalid text is decoded lossily, so it can still be displayed.
+    #[must_use]
+    pub fn decode(self, text: &[u8]) -> Cow<'_, [u8]> {
+        let from_bytes = match self {
+            Self::Utf8 => return Cow::Borrowed(text),
+            Self::Utf16Le => u16::from_le_bytes,
+            Self::Utf16Be => u16::from_be_bytes,
+        };
+        let units = text
+            .chunks_exact(2)
+            .map(|unit| from_bytes([unit[0], unit[1]]));
+        let mut decoded = char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect::<String>();
+        if text.len() % 2 == 1 {
+            decoded.push(char::REPLACEMENT_CHARACTER);
+        }
+        Cow::Owned(decoded.into_bytes())
+    }
+
+    /// Convert UTF-8 text to this encoding.
+    #[must_use]
+    pub fn encode(self, text: &[u8]) -> Cow<'_, [u8]> {
+        let to_bytes = match self {
+            Self::Utf8 => return Cow::Borrowed(text),
+            Self::Utf16Le => u16::to_le_bytes,
+            Self::Utf16Be => u16::to_be_bytes,
+        };
+        Cow::Owned(
+            String::from_utf8_lossy(text)
+                .encode_utf16()
+                .flat_map(to_bytes)
+                .collect(),
+        )
+    }
+}
+
 /// A value a column of a record can have
-#[derive(Copy, Clone, Debug, PartialEq)]
+///
+/// Values are ordered and compared as SQLite does, with [`Value::sqlite_cmp`].
+#[derive(Copy, Clone, Debug)]
 pub enum Value<Blob: AsRef<[u8]>> {
-    // TODO More efficient storage of `i24` and `i48`
     Null,
-    I8(i8),
-    I16(i16),
-    I24(i32),
-    I32(i32),
-    I48(i64),
-    I64(i64),
+    /// An integer, along with the serial type it's stored as
+    Int(i64, IntType),
     F64(f64),
-    Zero,
-    One,
     Blob(Blob),
     String(Blob),
     SQLiteReserved,
 }
 impl<'a, Blob: From<&'a [u8]> + AsRef<[u8]>> Value<Blob> {
     /// Parse a value for the given type.
-    pub fn parse_for_ty(ty: ColumnType, buffer: &mut &'a [u8]) -> Result<Self> {
+    pub fn parse_for_ty(ty: ColumnType, buffer: &mut &'a [u8]) -> crate::Result<Self> {
+        if let Some(int_type) = IntType::from_column_type(ty) {
+            let (head, tail) = buffer
+                .split_at_checked(ty.body_len())
+                .ok_or_else(|| Error::corrupt(None, "End of payload parsing cell values"))?;
+            *buffer = tail;
+            let mut bytes = [0; 8];
+            bytes[..head.len()].copy_from_slice(head);
+            // Shift down from the top to sign-extend, leaving 0 for the types without a body
+            let n = i64::from_be_bytes(bytes)
+                .checked_shr(64 - 8 * head.len() as u32)
+                .unwrap_or_default();
+            return Ok(Self::Int(int_type.constant().unwrap_or(n), int_type));
+        }
         Ok(match ty {
             ColumnType::Null => Self::Null,
-            ColumnType::I8 => {
-                let (head, tail) = buffer
-                    .split_first_chunk()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I8(i8::from_be_bytes(*head))
-            }
-            ColumnType::I16 => {
-                let (head, tail) = buffer
-                    .split_first_chunk()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I16(i16::from_be_bytes(*head))
-            }
-            ColumnType::I24 => {
-                let (head, tail) = buffer
-                    .split_first_chunk::<3>()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I24(i32::from_be_bytes([0, head[0], head[1], head[2]]))
-            }
-            ColumnType::I32 => {
-                let (head, tail) = buffer
-                    .split_first_chunk()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I32(i32::from_be_bytes(*head))
-            }
-            ColumnType::I48 => {
-                let (head, tail) = buffer
-                    .split_first_chunk::<6>()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I48(i64::from_be_bytes([
-                    0, 0, head[0], head[1], head[2], head[3], head[4], head[5],
-                ]))
-            }
-            ColumnType::I64 => {
-                let (head, tail) = buffer
-                    .split_first_chunk()
-                    .context("End of payload parsing cell values")?;
-                *buffer = tail;
-                Self::I64(i64::from_be_bytes(*head))
-            }
             ColumnType::F64 => {
                 let (head, tail) = buffer
                     .split_first_chunk()
-                    .context("End of payload parsing cell values")?;
+                    .ok_or_else(|| Error::corrupt(None, "End of payload parsing cell values"))?;
                 *buffer = tail;
                 Self::F64(f64::from_be_bytes(*head))
             }
-            ColumnType::Zero => Self::Zero,
-            ColumnType::One => Self::One,
             ColumnType::Blob(len) => {
-                let (head, tail) = buffer
-                    .split_at_checked(len as usize)
-                    .context("End of payload while parsing cell values")?;
+                let (head, tail) = buffer.split_at_checked(len as usize).ok_or_else(|| {
+                    Error::corrupt(None, "End of payload while parsing cell values")
+                })?;
                 *buffer = tail;
                 Self::Blob(Blob::from(head))
             }
             ColumnType::String(len) => {
-                let (head, tail) = buffer.split_at_checked(len as usize).with_context(|| {
-                    format!(
-                        "End of payload while parsing cell values:\n{}/{} bytes for string:\n{buffer:X?}",
-                        buffer.len(),
-                        len
+                let (head, tail) = buffer.split_at_checked(len as usize).ok_or_else(|| {
+                    Error::corrupt(
+                        None,
+                        format!(
+                            "End of payload while parsing cell values:\n{}/{} bytes for string:\n{buffer:X?}",
+                            buffer.len(),
+                            len
+                        ),
                     )
                 })?;
                 *buffer = tail;
                 Self::String(Blob::from(head))
             }
             ColumnType::SQLiteReserved => Self::SQLiteReserved,
+            _ => unreachable!("Integer types are handled above"),
         })
     }
 }
 
 impl<Blob: AsRef<[u8]>> Value<Blob> {
+    /// Make an integer value, which is stored as the smallest serial type that can hold it.
+    #[must_use]
+    pub fn int(n: i64) -> Self {
+        Self::Int(n, IntType::smallest_for(n))
+    }
+
     pub fn to_owned(&self) -> OwnedValue {
         match self {
             Self::Null => Value::Null,
-            Self::I8(n) => Value::I8(*n),
-            Self::I16(n) => Value::I16(*n),
-            Self::I24(n) => Value::I24(*n),
-            Self::I32(n) => Value::I32(*n),
-            Self::I48(n) => Value::I48(*n),
-            Self::I64(n) => Value::I64(*n),
+            Self::Int(n, int_type) => Value::Int(*n, *int_type),
             Self::F64(n) => Value::F64(*n),
-            Self::Zero => Value::Zero,
-            Self::One => Value::One,
             Self::Blob(blob) => Value::Blob(blob.as_ref().to_owned().into_boxed_slice()),
             Self::String(blob) => Value::String(blob.as_ref().to_owned().into_boxed_slice()),
             Self::SQLiteReserved => Value::SQLiteReserved,
         }
     }
 
+    /// Copy `self`, converting it to UTF-8 if it's text in the given encoding.
+    #[must_use]
+    pub fn decode_text(&self, encoding: TextEncoding) -> OwnedValue {
+        match self {
+            Self::String(text) => Value::String(encoding.decode(text.as_ref()).into()),
+            _ => self.to_owned(),
+        }
+    }
+
+    /// Copy `self`, converting it from UTF-8 to the given encoding if it's text.
+    #[must_use]
+    pub fn encode_text(&self, encoding: TextEncoding) -> OwnedValue {
+        match self {
+            Self::String(text) => Value::String(encoding.encode(text.as_ref()).into()),
+            _ => self.to_owned(),
+        }
+    }
+
+    /// Convert `self` as SQLite does when it's stored in a column with the given affinity.
+    ///
+    /// Text which looks like a number is stored as one in columns with numeric affinity, and
+    /// numbers are stored as text in columns with text affinity. Anything else is left alone.
+    #[must_use]
+    pub fn apply_affinity(&self, affinity: Affinity) -> OwnedValue {
+        match (affinity, self) {
+            (Affinity::Blob, _) | (_, Self::Null | Self::Blob(_) | Self::SQLiteReserved) => {
+                self.to_owned()
+            }
+            (Affinity::Text, Self::String(_)) => self.to_owned(),
+            (Affinity::Text, Self::F64(n)) => Value::String(format_real(*n).into_bytes().into()),
+            (Affinity::Text, _) => Value::String(
+                self.get::<i64>()
+                    .unwrap_or_default()
+                    .to_string()
+                    .into_bytes()
+                    .into(),
+            ),
+            (Affinity::Real, _) => match self.apply_affinity(Affinity::Numeric) {
+                value @ Value::String(_) => value,
+                value => Value::F64(value.get().unwrap_or_default()),
+            },
+            (Affinity::Numeric | Affinity::Integer, Self::String(text)) => {
+                std::str::from_utf8(text.as_ref())
+                    .ok()
+                    .and_then(parse_numeric_text)
+                    .map_or_else(|| self.to_owned(), |value| value.apply_affinity(affinity))
+            }
+            (Affinity::Numeric | Affinity::Integer, Self::F64(n)) => {
+                exact_integer(*n).map_or(Value::F64(*n), Value::int)
+            }
+            (Affinity::Numeric | Affinity::Integer, _) => self.to_owned(),
+        }
+    }
+
     pub fn ty(&self) -> ColumnType {
         match self {
             Self::Null => ColumnType::Null,
-            Self::I8(_) => ColumnType::I8,
-            Self::I16(_) => ColumnType::I16,
-            Self::I24(_) => ColumnType::I24,
-            Self::I32(_) => ColumnType::I32,
-            Self::I48(_) => ColumnType::I48,
-            Self::I64(_) => ColumnType::I64,
+            Self::Int(_, int_type) => int_type.column_type(),
             Self::F64(_) => ColumnType::F64,
-            Self::Zero => ColumnType::Zero,
-            Self::One => ColumnType::One,
             Self::Blob(blob) => ColumnType::Blob(blob.as_ref().len() as u64),
             Self::String(blob) => ColumnType::String(blob.as_ref().len() as u64),
             Self::SQLiteReserved => ColumnType::SQLiteReserved,
         }
     }
 
-    /// Get `self` as a utf-8 string, if valid.
+    /// The serial type `self` is stored as when [building a record](Record::build).
+    fn serial_type(&self) -> ColumnType {
+        match self {
+            Self::Int(n, int_type) if int_type.holds(*n) => int_type.column_type(),
+            Self::Int(n, _) => IntType::smallest_for(*n).column_type(),
+            Self::F64(_) => ColumnType::F64,
+            Self::Blob(blob) => ColumnType::Blob(blob.as_ref().len() as u64),
+            Self::String(blob) => ColumnType::String(blob.as_ref().len() as u64),
+            // The reserved types never appear in valid databases, so there's nothing sensible to
+            // store them as.
+            Self::Null | Self::SQLiteReserved => ColumnType::Null,
+        }
+    }
+
+    /// Append the body bytes for `self`, stored as the serial type `ty`, to `buffer`.
+    fn write_body(&self, ty: ColumnType, buffer: &mut Vec<u8>) {
+        match self {
+            Self::Int(n, _) => buffer.extend(&n.to_be_bytes()[8 - ty.body_len()..]),
+            Self::F64(n) => buffer.extend(n.to_be_bytes()),
+            Self::Blob(blob) | Self::String(blob) => buffer.extend(blob.as_ref()),
+            Self::Null | Self::SQLiteReserved => {}
+        }
+    }
+
+    /// Whether `self` is `NULL`.
+    #[must_use]
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    /// Convert `self` into a Rust type.
+    ///
+    /// Converting `NULL` fails unless `T` is an [`Option`], which makes it possible to tell a
+    /// `NULL` apart from a value of the wrong type.
+    pub fn get<T: FromValue>(&self) -> crate::Result<T> {
+        T::from_value(self)
+    }
+
+    /// Compare `self` to `other`, using SQLite's ordering with the `BINARY` collation.
+    ///
+    /// `NULL`s come first, then numbers, then text, then blobs. Numbers are compared by their
+    /// values whatever their types, so integers are equal to floats with the same value, and
+    /// large integers are compared exactly instead of being rounded to floats. Unlike in SQL
+    /// expressions, `NULL`s are equal to each other, as they are for `ORDER BY`, `DISTINCT`, and
+    /// `GROUP BY`.
+    pub fn sqlite_cmp<Other: AsRef<[u8]>>(&self, other: &Value<Other>) -> Ordering {
+        match (self, other) {
+            (Self::String(a), Value::String(b)) | (Self::Blob(a), Value::Blob(b)) => {
+                a.as_ref().cmp(b.as_ref())
+            }
+            (Self::F64(a), Value::F64(b)) => float_cmp(*a, *b),
+            (Self::F64(a), _) if other.sort_class() == 1 => {
+                int_float_cmp(other.get::<i64>().unwrap_or_default(), *a).reverse()
+            }
+            (_, Value::F64(b)) if self.sort_class() == 1 => {
+                int_float_cmp(self.get::<i64>().unwrap_or_default(), *b)
+            }
+            _ if self.sort_class() == 1 && other.sort_class() == 1 => self
+                .get::<i64>()
+                .unwrap_or_default()
+                .cmp(&other.get::<i64>().unwrap_or_default()),
+            _ => self.sort_class().cmp(&other.sort_class()),
+        }
+    }
+
+    /// The position of this kind of value in the order used by [`Self::sqlite_cmp`].
+    fn sort_class(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::String(_) => 2,
+            Self::Blob(_) => 3,
+            Self::SQLiteReserved => 4,
+            _ => 1,
+        }
+    }
+
+    /// Get `self` as an integer, if it's one.
+    #[must_use]
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Int(n, _) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Get `self` as a float, if it's a number.
+    #[must_use]
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(n, _) => Some(*n as f64),
+            Self::F64(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Get `self` as a utf-8 string, if it's text that's valid.
+    #[must_use]
     pub fn as_str(&self) -> Option<&str> {
         match self {
             Self::String(blob) => std::str::from_utf8(blob.as_ref()).ok(),
@@ -227,35 +568,439 @@ impl<Blob: AsRef<[u8]>> Value<Blob> {
         }
     }
 
-    /// Get `self` as a `usize`, if a number that fits.
+    /// Get the bytes of `self`, if it's a blob.
+    #[must_use]
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            Self::Blob(blob) => Some(blob.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Get `self` as a `usize`, if it's an integer that fits.
+    #[must_use]
     pub fn as_usize(&self) -> Option<usize> {
+        self.as_i64().and_then(|n| usize::try_from(n).ok())
+    }
+}
+pub type OwnedValue = Value<Box<[u8]>>;
+
+/// The serial types an integer can be stored as in a record.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntType {
+    /// The constant 0, which takes up no space in the body
+    Zero,
+    /// The constant 1, which takes up no space in the body
+    One,
+    I8,
+    I16,
+    I24,
+    I32,
+    I48,
+    I64,
+}
+impl IntType {
+    /// Get the smallest type which can hold `n`.
+    #[must_use]
+    pub fn smallest_for(n: i64) -> Self {
+        match n {
+            0 => Self::Zero,
+            1 => Self::One,
+            _ if i8::try_from(n).is_ok() => Self::I8,
+            _ if i16::try_from(n).is_ok() => Self::I16,
+            -0x80_0000..0x80_0000 => Self::I24,
+            _ if i32::try_from(n).is_ok() => Self::I32,
+            -0x8000_0000_0000..0x8000_0000_0000 => Self::I48,
+            _ => Self::I64,
+        }
+    }
+
+    /// Whether `n` can be stored as this type.
+    #[must_use]
+    pub fn holds(self, n: i64) -> bool {
+        match self.constant() {
+            Some(constant) => n == constant,
+            None => self.column_type().body_len() >= Self::smallest_for(n).column_type().body_len(),
+        }
+    }
+
+    /// Get the value of the types which always hold the same value.
+    fn constant(self) -> Option<i64> {
         match self {
             Self::Zero => Some(0),
             Self::One => Some(1),
-            Self::I8(n) => usize::try_from(*n).ok(),
-            Self::I16(n) => usize::try_from(*n).ok(),
-            Self::I24(n) | Self::I32(n) => usize::try_from(*n).ok(),
-            Self::I48(n) | Self::I64(n) => usize::try_from(*n).ok(),
             _ => None,
         }
     }
+
+    /// Get the integer type which `ty` is, if it's one.
+    #[must_use]
+    pub fn from_column_type(ty: ColumnType) -> Option<Self> {
+        Some(match ty {
+            ColumnType::Zero => Self::Zero,
+            ColumnType::One => Self::One,
+            ColumnType::I8 => Self::I8,
+            ColumnType::I16 => Self::I16,
+            ColumnType::I24 => Self::I24,
+            ColumnType::I32 => Self::I32,
+            ColumnType::I48 => Self::I48,
+            ColumnType::I64 => Self::I64,
+            _ => return None,
+        })
+    }
+
+    /// Get the column type this is.
+    #[must_use]
+    pub fn column_type(self) -> ColumnType {
+        match self {
+            Self::Zero => ColumnType::Zero,
+            Self::One => ColumnType::One,
+            Self::I8 => ColumnType::I8,
+            Self::I16 => ColumnType::I16,
+            Self::I24 => ColumnType::I24,
+            Self::I32 => ColumnType::I32,
+            Self::I48 => ColumnType::I48,
+            Self::I64 => ColumnType::I64,
+        }
+    }
+}
+
+/// How a column prefers to store values, which is worked out from its declared type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    /// Numbers are stored as text.
+    Text,
+    /// Text which looks like a number is stored as one, as an integer if it's a whole number.
+    Numeric,
+    /// Like [`Self::Numeric`], which it only differs from in `CAST` expressions.
+    Integer,
+    /// Like [`Self::Numeric`], but integers are stored as floats.
+    Real,
+    /// Values are stored as they are.
+    Blob,
+}
+impl Affinity {
+    /// Work out the affinity of a column declared with the given type, by the rules SQLite uses.
+    ///
+    /// The first rule which matches applies, so `CHARINT` has integer affinity.
+    #[must_use]
+    pub fn from_declared_type(declared_type: &str) -> Self {
+        let declared_type = declared_type.to_ascii_uppercase();
+        let contains_any = |names: &[&str]| names.iter().any(|name| declared_type.contains(name));
+        if contains_any(&["INT"]) {
+            Self::Integer
+        } else if contains_any(&["CHAR", "CLOB", "TEXT"]) {
+            Self::Text
+        } else if contains_any(&["BLOB"]) || declared_type.trim().is_empty() {
+            Self::Blob
+        } else if contains_any(&["REAL", "FLOA", "DOUB"]) {
+            Self::Real
+        } else {
+            Self::Numeric
+        }
+    }
+}
+
+/// Read text which is a well-formed integer or real number, possibly surrounded by whitespace,
+/// as an integer if it fits in one and as a float otherwise.
+fn parse_numeric_text(text: &str) -> Option<OwnedValue> {
+    let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    let (mantissa, exponent) = unsigned
+        .split_once(['e', 'E'])
+        .map_or((unsigned, None), |(mantissa, exponent)| {
+            (mantissa, Some(exponent))
+        });
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let is_digits = |digits: &str| digits.bytes().all(|b| b.is_ascii_digit());
+    let well_formed = is_digits(whole)
+        && is_digits(fraction)
+        && !(whole.is_empty() && fraction.is_empty())
+        && exponent.map_or(true, |exponent| {
+            let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            !digits.is_empty() && is_digits(digits)
+        });
+    if !well_formed {
+        return None;
+    }
+    Some(match text.parse::<i64>() {
+        Ok(n) => Value::int(n),
+        Err(_) => Value::F64(text.parse().ok()?),
+    })
+}
+
+/// Get `n` as an integer if it's a whole number which an integer can hold exactly.
+fn exact_integer(n: f64) -> Option<i64> {
+    // The bounds are left out, as SQLite does, since `i64::MAX` isn't exactly a float.
+    (n.fract() == 0.0 && n > i64::MIN as f64 && n < i64::MAX as f64).then_some(n as i64)
+}
+
+/// Format a float as SQLite does when converting it to text, with up to 15 significant digits
+/// and always with a decimal point, like `%!.15g`.
+pub(crate) fn format_real(n: f64) -> String {
+    if n.is_infinite() {
+        return if n > 0.0 { "Inf" } else { "-Inf" }.to_owned();
+    }
+    if n == 0.0 {
+        return "0.0".to_owned();
+    }
+    let scientific = format!("{n:.14e}");
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("Scientific notation has an exponent");
+    let exponent = exponent.parse::<i32>().expect("The exponent is an integer");
+    if (-4..15).contains(&exponent) {
+        match (14 - exponent) as usize {
+            // Without any decimal places, there's no point to trim zeros back to.
+            0 => format!("{n:.0}.0"),
+            places => with_point(format!("{n:.places$}").trim_end_matches('0')),
+        }
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{sign}{:02}",
+            with_point(mantissa.trim_end_matches('0')),
+            exponent.abs()
+        )
+    }
+}
+
+/// Add a zero after a trailing decimal point, so `1.` becomes `1.0`.
+fn with_point(number: &str) -> String {
+    if number.ends_with('.') {
+        format!("{number}0")
+    } else {
+        number.to_owned()
+    }
+}
+
+/// Compare two floats, where NaN, which SQLite stores as `NULL`, is less than any number.
+fn float_cmp(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b)
+        .unwrap_or_else(|| (!a.is_nan()).cmp(&!b.is_nan()))
+}
+
+/// Compare an integer to a float exactly, as SQLite does, without rounding the integer.
+fn int_float_cmp(int: i64, float: f64) -> Ordering {
+    // The bounds of `i64`, which are exactly representable as floats.
+    const MIN: f64 = i64::MIN as f64;
+    const MAX: f64 = -(i64::MIN as f64);
+    if float.is_nan() || float < MIN {
+        return Ordering::Greater;
+    }
+    if float >= MAX {
+        return Ordering::Less;
+    }
+    // `float` is in range, so its integer part fits, and only its fraction is left to compare.
+    match int.cmp(&(float as i64)) {
+        Ordering::Equal => float_cmp(int as f64, float),
+        ordering => ordering,
+    }
 }
-pub type OwnedValue = Value<Box<[u8]>>;
+
+/// Values are equal if [`Value::sqlite_cmp`] says so, so `1`, `1.0`, and a `1` stored in a
+/// single byte are all equal, while `1` and `'1'` aren't.
+impl<Blob: AsRef<[u8]>> PartialEq for Value<Blob> {
+    fn eq(&self, other: &Self) -> bool {
+        self.sqlite_cmp(other).is_eq()
+    }
+}
+impl<Blob: AsRef<[u8]>> Eq for Value<Blob> {}
+impl<Blob: AsRef<[u8]>> PartialOrd for Value<Blob> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<Blob: AsRef<[u8]>> Ord for Value<Blob> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sqlite_cmp(other)
+    }
+}
+
+/// A Rust type which can be converted from a [`Value`].
+pub trait FromValue: Sized {
+    /// Convert `value` into `Self`.
+    ///
+    /// This fails with [`Error::Mismatch`] if `value` can't be converted.
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self>;
+}
+impl FromValue for i64 {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::Int(n, _) => Ok(*n),
+            Value::Null => Err(mismatch("Unexpected NULL, expected an integer")),
+            _ => Err(mismatch(format!(
+                "Cannot convert {} to an integer",
+                value.ty()
+            ))),
+        }
+    }
+}
+impl FromValue for f64 {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::F64(n) => Ok(*n),
+            Value::Int(n, _) => Ok(*n as f64),
+            Value::Null => Err(mismatch("Unexpected NULL, expected a float")),
+            _ => Err(mismatch(format!(
+                "Cannot convert {} to a float",
+                value.ty()
+            ))),
+        }
+    }
+}
+impl FromValue for bool {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::Int(n, _) => Ok(*n != 0),
+            Value::Null => Err(mismatch("Unexpected NULL, expected a boolean")),
+            _ => Err(mismatch(format!(
+                "Cannot convert {} to a boolean",
+                value.ty()
+            ))),
+        }
+    }
+}
+impl FromValue for String {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::String(blob) => std::str::from_utf8(blob.as_ref())
+                .map(str::to_owned)
+                .map_err(|e| mismatch(format!("String is not valid utf-8: {e}"))),
+            Value::Null => Err(mismatch("Unexpected NULL, expected a string")),
+            _ => Err(mismatch(format!(
+                "Cannot convert {} to a string",
+                value.ty()
+            ))),
+        }
+    }
+}
+impl FromValue for Vec<u8> {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::Blob(blob) | Value::String(blob) => Ok(blob.as_ref().to_vec()),
+            Value::Null => Err(mismatch("Unexpected NULL, expected a blob")),
+            _ => Err(mismatch(format!("Cannot convert {} to a blob", value.ty()))),
+        }
+    }
+}
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value<Blob: AsRef<[u8]>>(value: &Value<Blob>) -> crate::Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// Make an [`Error::Mismatch`] with the given message.
+fn mismatch(message: impl Into<String>) -> Error {
+    Error::Mismatch(message.into())
+}
+
+/// Implement `TryFrom<Value>` for types which implement [`FromValue`], so they can also be made
+/// with `try_into`.
+macro_rules! try_from_value {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl<Blob: AsRef<[u8]>> TryFrom<Value<Blob>> for $ty {
+                type Error = Error;
+
+                fn try_from(value: Value<Blob>) -> crate::Result<Self> {
+                    Self::from_value(&value)
+                }
+            }
+            impl<Blob: AsRef<[u8]>> TryFrom<&Value<Blob>> for $ty {
+                type Error = Error;
+
+                fn try_from(value: &Value<Blob>) -> crate::Result<Self> {
+                    Self::from_value(value)
+                }
+            }
+        )+
+    };
+}
+// `Option<T>` is listed for each type, since a blanket impl would overlap with `T: From<T>`.
+try_from_value!(
+    i64,
+    f64,
+    bool,
+    String,
+    Vec<u8>,
+    Option<i64>,
+    Option<f64>,
+    Option<bool>,
+    Option<String>,
+    Option<Vec<u8>>,
+);
+
+/// Typed access to the columns of a row.
+pub trait RowExt {
+    /// Convert the value in column `idx` into a Rust type.
+    ///
+    /// See [`Value::get`] for how `NULL` is handled.
+    fn get_as<T: FromValue>(&self, idx: usize) -> crate::Result<T>;
+
+    /// Convert the value in the column called `name`, ignoring case, into a Rust type, where the
+    /// columns of the row are called `columns`.
+    fn get_named<T: FromValue>(&self, columns: &[String], name: &str) -> crate::Result<T> {
+        let idx = columns
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::NotFound(format!("No column named {name}")))?;
+        self.get_as(idx)
+    }
+}
+impl<Blob: AsRef<[u8]>> RowExt for [Value<Blob>] {
+    fn get_as<T: FromValue>(&self, idx: usize) -> crate::Result<T> {
+        self.get(idx)
+            .ok_or_else(|| Error::NotFound(format!("Column index {idx} out of range")))?
+            .get()
+            .map_err(|e| mismatch(format!("Invalid value in column {idx}: {e}")))
+    }
+}
+
+/// A Rust type which can be built from a row returned by a statement.
+///
+/// This is implemented for tuples, whose elements are read from the columns in order, and can be
+/// derived for structs with the `derive` feature.
+pub trait FromRow: Sized {
+    /// Build `Self` from `row`, whose columns are called `columns`.
+    ///
+    /// `columns` is empty if the names of the columns aren't known.
+    fn from_row<Row: RowExt + ?Sized>(columns: &[String], row: &Row) -> crate::Result<Self>;
+}
+/// Implement [`FromRow`] for a tuple of the given types, read from the columns in order.
+macro_rules! tuple_from_row {
+    ($($ty:ident: $idx:tt),+) => {
+        impl<$($ty: FromValue),+> FromRow for ($($ty,)+) {
+            fn from_row<Row: RowExt + ?Sized>(
+                _columns: &[String],
+                row: &Row,
+            ) -> crate::Result<Self> {
+                Ok(($(row.get_as::<$ty>($idx)?,)+))
+            }
+        }
+    };
+}
+tuple_from_row!(A: 0);
+tuple_from_row!(A: 0, B: 1);
+tuple_from_row!(A: 0, B: 1, C: 2);
+tuple_from_row!(A: 0, B: 1, C: 2, D: 3);
+tuple_from_row!(A: 0, B: 1, C: 2, D: 3, E: 4);
+tuple_from_row!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+tuple_from_row!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+tuple_from_row!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
 
 impl<Blob: AsRef<[u8]>> fmt::Display for Value<Blob> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Null => f.write_str("null"),
-            Self::I8(n) => n.fmt(f),
-            Self::I16(n) => n.fmt(f),
-            Self::I24(n) | Self::I32(n) => n.fmt(f),
-            Self::I48(n) | Self::I64(n) => n.fmt(f),
+            Self::Int(n, _) => n.fmt(f),
             Self::F64(n) => n.fmt(f),
-            Self::Zero => f.write_str("0"),
-            Self::One => f.write_str("1"),
             Self::Blob(blob) => write!(f, "{:X?}", blob.as_ref()),
             Self::String(blob) => {
-                // TODO: Detect string type and pretty-print for real
+                // Text from UTF-16 databases needs to go through `decode_text` first.
                 if let Ok(utf8) = std::str::from_utf8(blob.as_ref()) {
                     write!(f, "{utf8:?}")
                 } else {
@@ -309,6 +1054,40 @@ impl ColumnType {
             }
         }
     }
+
+    /// The number of bytes a value of this type takes up in the body of a record.
+    #[must_use]
+    pub fn body_len(self) -> usize {
+        match self {
+            Self::Null | Self::Zero | Self::One | Self::SQLiteReserved => 0,
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 => 4,
+            Self::I48 => 6,
+            Self::I64 | Self::F64 => 8,
+            Self::Blob(len) | Self::String(len) => len as usize,
+        }
+    }
+
+    /// The number stored in a record header for this type.
+    fn to_numeric(self) -> i64 {
+        match self {
+            Self::Null => 0,
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 => 4,
+            Self::I48 => 5,
+            Self::I64 => 6,
+            Self::F64 => 7,
+            Self::Zero => 8,
+            Self::One => 9,
+            Self::SQLiteReserved => 10,
+            Self::Blob(len) => 12 + 2 * len as i64,
+            Self::String(len) => 13 + 2 * len as i64,
+        }
+    }
 }
 impl fmt::Display for ColumnType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -329,3 +1108,465 @@ impl fmt::Display for ColumnType {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_encodings() {
+        let text = "héllo 🦀".as_bytes();
+        assert_eq!(TextEncoding::Utf8.encode(text), text);
+        let le = TextEncoding::Utf16Le.encode(text);
+        let be = TextEncoding::Utf16Be.encode(text);
+        assert_eq!(le[..4], [b'h', 0, 0xe9, 0]);
+        assert_eq!(be[..4], [0, b'h', 0, 0xe9]);
+        assert_eq!(le.len(), 2 * 8, "The crab takes a surrogate pair");
+        assert_eq!(TextEncoding::Utf16Le.decode(&le), text);
+        assert_eq!(TextEncoding::Utf16Be.decode(&be), text);
+        // A lone surrogate, and half of a code unit
+        assert_eq!(
+            TextEncoding::Utf16Le.decode(&[0x3d, 0xd8, b'a', 0, 0]),
+            "\u{fffd}a\u{fffd}".as_bytes(),
+        );
+
+        let value = Value::String(&b"hi"[..]);
+        let encoded = value.encode_text(TextEncoding::Utf16Be);
+        assert_eq!(encoded, Value::String(Box::from(&[0, b'h', 0, b'i'][..])));
+        assert_eq!(
+            encoded.decode_text(TextEncoding::Utf16Be).as_str(),
+            Some("hi")
+        );
+        assert_eq!(
+            Value::<&[u8]>::int(3).decode_text(TextEncoding::Utf16Le),
+            Value::int(3)
+        );
+    }
+
+    #[test]
+    fn test_null_conversions() {
+        let row: Vec<OwnedValue> = vec![
+            Value::Null,
+            Value::int(-7),
+            Value::String(Box::from(&b"hi"[..])),
+        ];
+        assert_eq!(row.get_as::<Option<i64>>(0).unwrap(), None);
+        assert_eq!(row.get_as::<Option<i64>>(1).unwrap(), Some(-7));
+        assert_eq!(row.get_as::<i64>(1).unwrap(), -7);
+        assert_eq!(
+            row.get_as::<Option<String>>(2).unwrap().as_deref(),
+            Some("hi")
+        );
+        assert!(
+            row.get_as::<i64>(0).is_err(),
+            "NULL should only convert to an `Option`",
+        );
+        assert!(
+            row.get_as::<Option<i64>>(2).is_err(),
+            "A string isn't an integer, even when `NULL` is allowed",
+        );
+        assert!(
+            row.get_as::<Option<i64>>(3).is_err(),
+            "Column 3 doesn't exist"
+        );
+    }
+
+    #[test]
+    fn test_accessors() {
+        let int = Value::<&[u8]>::int(3);
+        let float = Value::<&[u8]>::F64(2.5);
+        let text = Value::<&[u8]>::String(b"hi");
+        let blob = Value::<&[u8]>::Blob(&[1, 2]);
+        assert_eq!(int.as_i64(), Some(3));
+        assert_eq!(float.as_i64(), None);
+        assert_eq!(int.as_f64(), Some(3.0));
+        assert_eq!(float.as_f64(), Some(2.5));
+        assert_eq!(text.as_str(), Some("hi"));
+        assert_eq!(blob.as_str(), None);
+        assert_eq!(blob.as_blob(), Some(&[1, 2][..]));
+        assert_eq!(text.as_blob(), None);
+        assert_eq!(Value::<&[u8]>::int(-1).as_usize(), None);
+        assert!(Value::<&[u8]>::Null.is_null());
+        assert!(!int.is_null());
+
+        assert_eq!(i64::try_from(int).unwrap(), 3);
+        assert_eq!(f64::try_from(&int).ok(), Some(3.0));
+        assert!(bool::try_from(int).unwrap());
+        assert_eq!(String::try_from(text).unwrap(), "hi");
+        assert_eq!(Vec::<u8>::try_from(blob).unwrap(), [1, 2]);
+        assert_eq!(Option::<i64>::try_from(Value::<&[u8]>::Null).unwrap(), None);
+        assert!(i64::try_from(Value::<&[u8]>::Null).is_err());
+        let n: Result<i64, _> = text.try_into();
+        assert!(n.is_err(), "Text shouldn't convert to an integer");
+    }
+
+    #[test]
+    fn test_from_row() {
+        let columns = ["id".to_owned(), "Name".to_owned()];
+        let row: Vec<OwnedValue> = vec![Value::int(1), Value::String(Box::from(&b"alice"[..]))];
+        assert_eq!(
+            <(i64, String)>::from_row(&columns, row.as_slice()).unwrap(),
+            (1, "alice".to_owned())
+        );
+        assert_eq!(row.get_named::<String>(&columns, "name").unwrap(), "alice");
+        assert!(
+            row.get_named::<i64>(&columns, "team").is_err(),
+            "There's no column called team"
+        );
+        assert!(
+            <(i64, i64)>::from_row(&columns, row.as_slice()).is_err(),
+            "The name isn't an integer"
+        );
+        assert!(
+            <(i64, String, i64)>::from_row(&columns, row.as_slice()).is_err(),
+            "There are only two columns"
+        );
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_from_row() {
+        #[derive(Debug, PartialEq, FromRow)]
+        struct User {
+            id: i64,
+            #[from_row(rename = "email")]
+            address: Option<String>,
+            name: String,
+        }
+        #[derive(Debug, PartialEq, FromRow)]
+        struct Tag(String, i64);
+
+        let mut db = crate::Database::new(
+            std::fs::File::open("test-data/constraints.sqlite").expect("Failed to open database"),
+        )
+        .unwrap();
+        let users: Vec<User> = db.query_rows("SELECT * FROM users").unwrap();
+        assert_eq!(
+            users,
+            [
+                User {
+                    id: 1,
+                    address: Some("a@example.com".to_owned()),
+                    name: "alice".to_owned(),
+                },
+                User {
+                    id: 2,
+                    address: Some("b@example.com".to_owned()),
+                    name: "bob".to_owned(),
+                },
+            ]
+        );
+        let tag: Tag = db.query_row("SELECT * FROM tags").unwrap();
+        assert_eq!(tag, Tag("x".to_owned(), 1));
+
+        // Without the names of the columns, fields are read in order.
+        let row: Vec<OwnedValue> = vec![
+            Value::int(3),
+            Value::Null,
+            Value::String(Box::from(&b"carol"[..])),
+        ];
+        assert_eq!(
+            User::from_row(&[], row.as_slice()).unwrap(),
+            User {
+                id: 3,
+                address: None,
+                name: "carol".to_owned(),
+            }
+        );
+        let error = db.query_row::<User>("SELECT * FROM tags").unwrap_err();
+        assert!(
+            format!("{error:#}").contains("No column named id"),
+            "Unexpected error {error:#}"
+        );
+    }
+
+    #[test]
+    fn test_affinity() {
+        for (declared_type, affinity) in [
+            ("INTEGER", Affinity::Integer),
+            ("tinyint", Affinity::Integer),
+            ("VARCHAR(255)", Affinity::Text),
+            ("CHARINT", Affinity::Integer),
+            ("BLOB", Affinity::Blob),
+            ("", Affinity::Blob),
+            ("DOUBLE PRECISION", Affinity::Real),
+            ("FLOATING POINT", Affinity::Integer),
+            ("DECIMAL(10,5)", Affinity::Numeric),
+            ("STRING", Affinity::Numeric),
+        ] {
+            assert_eq!(
+                Affinity::from_declared_type(declared_type),
+                affinity,
+                "{declared_type:?}"
+            );
+        }
+
+        let text = |text: &str| Value::String(Box::from(text.as_bytes()));
+        let cases: &[(OwnedValue, Affinity, OwnedValue)] = &[
+            (text(" 12 "), Affinity::Integer, Value::int(12)),
+            (text("1e3"), Affinity::Numeric, Value::int(1000)),
+            (text("1.5"), Affinity::Integer, Value::F64(1.5)),
+            (text(".5"), Affinity::Real, Value::F64(0.5)),
+            (text("12abc"), Affinity::Numeric, text("12abc")),
+            (text("0x10"), Affinity::Numeric, text("0x10")),
+            (text("inf"), Affinity::Real, text("inf")),
+            (text("7"), Affinity::Text, text("7")),
+            (text("7"), Affinity::Blob, text("7")),
+            (Value::F64(3.0), Affinity::Numeric, Value::int(3)),
+            (Value::int(3), Affinity::Real, Value::F64(3.0)),
+            (Value::int(-3), Affinity::Text, text("-3")),
+            (Value::F64(0.1), Affinity::Text, text("0.1")),
+            (
+                Value::Blob(Box::from(&b"1"[..])),
+                Affinity::Integer,
+                Value::Blob(Box::from(&b"1"[..])),
+            ),
+            (Value::Null, Affinity::Text, Value::Null),
+        ];
+        for (value, affinity, expected) in cases {
+            let converted = value.apply_affinity(*affinity);
+            // `==` doesn't tell integers and floats apart, so compare their types too.
+            assert_eq!(
+                (&converted, converted.ty() == ColumnType::F64),
+                (expected, expected.ty() == ColumnType::F64),
+                "Applying {affinity:?} to {value}"
+            );
+        }
+
+        for (n, text) in [
+            (1.0, "1.0"),
+            (-2.5, "-2.5"),
+            (0.1 + 0.2, "0.3"),
+            (1e14, "100000000000000.0"),
+            (1e15, "1.0e+15"),
+            (1.5e-7, "1.5e-07"),
+            (0.000_1, "0.0001"),
+            (123_456_789.123_456_79, "123456789.123457"),
+            (f64::INFINITY, "Inf"),
+        ] {
+            assert_eq!(format_real(n), text);
+        }
+    }
+
+    #[test]
+    fn test_build_round_trip() {
+        let values: Vec<OwnedValue> = vec![
+            Value::Null,
+            Value::int(0),
+            Value::int(1),
+            Value::int(-100),
+            Value::int(1000),
+            Value::int(-0x40_0000),
+            Value::int(0x4000_0000),
+            Value::int(-0x4000_0000_0000),
+            Value::int(i64::MAX),
+            Value::F64(1.5),
+            Value::String(Box::from(&b"hello"[..])),
+            Value::Blob(Box::from(&[0xde, 0xad][..])),
+        ];
+        let record = Record::build(&values);
+        let parsed = Record::parse(&record).expect("Failed to parse built record");
+        assert_eq!(
+            parsed.type_iter().collect::<Vec<_>>(),
+            [
+                ColumnType::Null,
+                ColumnType::Zero,
+                ColumnType::One,
+                ColumnType::I8,
+                ColumnType::I16,
+                ColumnType::I24,
+                ColumnType::I32,
+                ColumnType::I48,
+                ColumnType::I64,
+                ColumnType::F64,
+                ColumnType::String(5),
+                ColumnType::Blob(2),
+            ],
+        );
+        for (idx, (built, parsed)) in values.iter().zip(parsed.value_iter()).enumerate() {
+            match built {
+                Value::F64(_) | Value::String(_) | Value::Blob(_) | Value::Null => {
+                    assert_eq!(*built, parsed.to_owned(), "Column {idx} changed");
+                }
+                _ => assert_eq!(
+                    built.get::<i64>().unwrap(),
+                    parsed.get::<i64>().unwrap(),
+                    "Column {idx} changed",
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_int_serial_types() {
+        // Integers stored wider than they need keep their type, and types that can't hold their
+        // value fall back to the smallest one that can.
+        let values: Vec<OwnedValue> = vec![
+            Value::Int(5, IntType::I64),
+            Value::Int(-3, IntType::I24),
+            Value::Int(0, IntType::I8),
+            Value::Int(1000, IntType::I8),
+            Value::Int(2, IntType::One),
+        ];
+        let record = Record::build(&values);
+        let parsed = Record::parse(&record).expect("Failed to parse built record");
+        assert_eq!(
+            parsed.type_iter().collect::<Vec<_>>(),
+            [
+                ColumnType::I64,
+                ColumnType::I24,
+                ColumnType::I8,
+                ColumnType::I16,
+                ColumnType::I8,
+            ],
+        );
+        assert_eq!(
+            parsed
+                .value_iter()
+                .map(|value| value.get::<i64>().unwrap())
+                .collect::<Vec<_>>(),
+            [5, -3, 0, 1000, 2],
+        );
+        let reparsed = parsed
+            .value_iter()
+            .map(|value| value.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(Record::build(&reparsed), record);
+    }
+
+    #[test]
+    fn test_lazy_access() {
+        let values: Vec<Value<&[u8]>> = vec![
+            Value::String(b"first"),
+            Value::Null,
+            Value::int(-0x4000_0000),
+            Value::Blob(&[1, 2, 3]),
+            Value::int(1),
+            Value::F64(2.5),
+            Value::String(b"last"),
+        ];
+        let built = Record::build(&values);
+        let record = Record::parse(&built).unwrap();
+        let decoded = record.decoded(usize::MAX);
+        assert_eq!(decoded.len(), values.len());
+        for (idx, value) in values.iter().enumerate() {
+            assert_eq!(record.value_at(idx).unwrap().as_ref(), Some(value));
+            assert_eq!(decoded.get(idx).unwrap().as_ref(), Some(value));
+        }
+        assert!(record.value_at(values.len()).unwrap().is_none());
+        assert!(decoded.get(values.len()).unwrap().is_none());
+
+        let decoded = record.decoded(4);
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(decoded.ty(3), Some(ColumnType::Blob(3)));
+        assert_eq!(decoded.get(3).unwrap(), Some(Value::Blob(&[1, 2, 3][..])));
+        assert!(
+            decoded.get(4).unwrap().is_none(),
+            "Only 4 columns were read"
+        );
+
+        // A body cut short is caught when reading a value past the end of it.
+        let record = Record::parse(&built[..built.len() - 5]).unwrap();
+        assert!(record.value_at(0).is_ok());
+        assert!(record.value_at(6).is_err());
+    }
+
+    #[test]
+    fn test_build_long_header() {
+        // Enough columns that the header length needs a two-byte varint
+        let values = vec![Value::<&[u8]>::Null; 200];
+        let record = Record::build(&values);
+        assert_eq!(record[..2], [0x81, 0x4a], "Header should be 202 bytes long");
+        let parsed = Record::parse(&record).expect("Failed to parse built record");
+        assert_eq!(parsed.value_iter().count(), 200);
+    }
+
+    #[test]
+    fn test_serialized_size() {
+        let text = |len| Value::String(vec![b'x'; len].into_boxed_slice());
+        let mut rows: Vec<Vec<OwnedValue>> = vec![
+            vec![],
+            vec![Value::Null],
+            vec![
+                Value::int(0),
+                Value::int(1),
+                Value::int(-200),
+                Value::F64(0.5),
+            ],
+            vec![Value::int(i64::MIN), Value::Int(7, IntType::I48)],
+            // Long enough that the serial types need several bytes
+            vec![text(100), Value::Blob(vec![0; 70_000].into_boxed_slice())],
+        ];
+        // Around the point where the header length needs a second byte
+        rows.extend((125..=130).map(|columns| vec![Value::Null; columns]));
+        rows.push(vec![text(60); 70]);
+        for row in rows {
+            let record = Record::build(&row);
+            assert_eq!(
+                Record::serialized_size(&row),
+                record.len(),
+                "Wrong size for {} columns",
+                row.len(),
+            );
+            // Records survive being parsed and built again unchanged.
+            let parsed = Record::parse(&record).expect("Failed to parse built record");
+            let values = parsed
+                .value_iter()
+                .map(|value| value.to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(values, row);
+            assert_eq!(Record::build(&values), record);
+        }
+    }
+
+    #[test]
+    fn test_compare_values() {
+        let values: [Value<&[u8]>; 7] = [
+            Value::Null,
+            Value::int(-3),
+            Value::F64(-2.5),
+            Value::int(0),
+            Value::int(1 << 40),
+            Value::String(b"abc"),
+            Value::Blob(b"\x00"),
+        ];
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(a.sqlite_cmp(b), i.cmp(&j), "Comparing {a} and {b}");
+            }
+        }
+
+        // Numbers compare by value, whatever their types.
+        assert_eq!(Value::<&[u8]>::int(1), Value::F64(1.0));
+        assert_eq!(Value::<&[u8]>::int(1), Value::<&[u8]>::int(1));
+        assert!(Value::<&[u8]>::F64(1.5) > Value::<&[u8]>::int(1));
+        assert!(Value::<&[u8]>::int(-2) < Value::<&[u8]>::F64(-1.5));
+        assert_ne!(Value::<&[u8]>::int(1), Value::<&[u8]>::String(b"1"));
+        assert_eq!(Value::<&[u8]>::Null, Value::<&[u8]>::Null);
+        // Large integers aren't rounded to floats to compare them.
+        let big = 1_i64 << 60;
+        assert!(Value::<&[u8]>::int(big + 1) > Value::<&[u8]>::F64(big as f64));
+        assert!(Value::<&[u8]>::int(i64::MAX) < Value::<&[u8]>::F64(1e19));
+        assert!(Value::<&[u8]>::int(i64::MIN) > Value::<&[u8]>::F64(-1e19));
+        assert!(Value::<&[u8]>::F64(f64::NAN) < Value::<&[u8]>::int(i64::MIN));
+
+        let mut sorted = vec![
+            Value::<&[u8]>::String(b"a"),
+            Value::F64(2.5),
+            Value::Null,
+            Value::int(2),
+            Value::Blob(b""),
+        ];
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            [
+                Value::<&[u8]>::Null,
+                Value::int(2),
+                Value::F64(2.5),
+                Value::String(b"a"),
+                Value::Blob(b""),
+            ]
+        );
+    }
+}
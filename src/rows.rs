@@ -0,0 +1,268 @@
+//! The rows returned by [`Database::query`](crate::Database::query)
+
+use std::sync::Arc;
+
+use crate::{
+    record::{FromRow, FromValue, OwnedValue, RowExt},
+    Error,
+};
+
+/// What's known about one of the columns of the rows a statement returns.
+///
+/// Like SQLite's `sqlite3_column_decltype` and `sqlite3_column_table_name`, the declared type and
+/// table are only known for columns which are read straight from a column of a table, possibly
+/// through a view, and not for expressions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnInfo {
+    /// The name of the column, in the same way as SQLite names it.
+    pub name: String,
+    /// The type the column it's read from was declared with, if any.
+    pub declared_type: Option<String>,
+    /// The table it's read from.
+    pub table: Option<String>,
+    /// The name of the column of [`Self::table`] it's read from.
+    pub origin: Option<String>,
+}
+impl ColumnInfo {
+    /// Make the info for a column called `name` which isn't read from a table.
+    pub(crate) fn named(name: String) -> Self {
+        Self {
+            name,
+            ..Self::default()
+        }
+    }
+}
+
+/// The columns of the rows a statement returns, shared by every row
+#[derive(Debug, PartialEq, Eq)]
+struct Columns {
+    /// The name of each column, kept apart for [`FromRow`]
+    names: Vec<String>,
+    /// What's known about each column
+    info: Vec<ColumnInfo>,
+}
+
+/// The rows returned by a statement, in order.
+///
+/// Made with [`Database::query`](crate::Database::query), which runs the statement to completion
+/// and holds every row it returns in memory before any are handed out, so a large result takes as
+/// much memory as all of its rows. Since the rows are already read, none of them fail, though each
+/// is returned as a [`Result`].
+#[derive(Debug)]
+pub struct Rows {
+    /// The columns of each row
+    columns: Arc<Columns>,
+    /// The rows which haven't been returned yet
+    rows: std::vec::IntoIter<Vec<OwnedValue>>,
+}
+
+impl Rows {
+    pub(crate) fn new(columns: Vec<ColumnInfo>, rows: Vec<Vec<OwnedValue>>) -> Self {
+        Self {
+            columns: Arc::new(Columns {
+                names: columns.iter().map(|column| column.name.clone()).collect(),
+                info: columns,
+            }),
+            rows: rows.into_iter(),
+        }
+    }
+
+    /// Get what's known about each column of the rows.
+    ///
+    /// This is empty if the statement doesn't return rows, or if its columns aren't known.
+    #[must_use]
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.columns.info
+    }
+
+    /// Get the names of the columns in each row.
+    #[must_use]
+    pub fn column_names(&self) -> &[String] {
+        &self.columns.names
+    }
+}
+
+impl Iterator for Rows {
+    type Item = crate::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let values = self.rows.next()?;
+        Some(Ok(Row {
+            columns: Arc::clone(&self.columns),
+            values,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Rows {}
+
+/// A row returned by a statement, along with what's known about its columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// The columns, shared with the other rows returned by the statement
+    columns: Arc<Columns>,
+    /// The value in each column
+    values: Vec<OwnedValue>,
+}
+
+impl Row {
+    /// Convert the value in `column`, given by its index or its name, into a Rust type.
+    ///
+    /// Names are matched ignoring case, and the first column with the name is used. See
+    /// [`Value::get`](crate::record::Value::get) for how `NULL` is handled.
+    pub fn get<T: FromValue>(&self, column: impl ColumnIndex) -> crate::Result<T> {
+        self.values.get_as(column.index(&self.columns.names)?)
+    }
+
+    /// Build a `T` from the whole row, such as a tuple or a struct deriving [`FromRow`].
+    pub fn get_row<T: FromRow>(&self) -> crate::Result<T> {
+        T::from_row(&self.columns.names, self.values.as_slice())
+    }
+
+    /// Get what's known about each column, such as its declared type.
+    #[must_use]
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.columns.info
+    }
+
+    /// Get the names of the columns.
+    #[must_use]
+    pub fn column_names(&self) -> &[String] {
+        &self.columns.names
+    }
+
+    /// Get the value in each column.
+    #[must_use]
+    pub fn values(&self) -> &[OwnedValue] {
+        &self.values
+    }
+
+    /// Take the value in each column out of the row.
+    #[must_use]
+    pub fn into_values(self) -> Vec<OwnedValue> {
+        self.values
+    }
+}
+
+/// A way of picking out a column of a [`Row`]: its index, or its name.
+pub trait ColumnIndex {
+    /// Find the index of the column in a row whose columns are called `columns`.
+    ///
+    /// This fails with [`Error::NotFound`] if there's no such column.
+    fn index(self, columns: &[String]) -> crate::Result<usize>;
+}
+impl ColumnIndex for usize {
+    fn index(self, _columns: &[String]) -> crate::Result<usize> {
+        // Rows whose columns' names aren't known still have values, which are checked when
+        // they're read.
+        Ok(self)
+    }
+}
+impl ColumnIndex for &str {
+    fn index(self, columns: &[String]) -> crate::Result<usize> {
+        columns
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(self))
+            .ok_or_else(|| Error::NotFound(format!("No column named {self}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColumnInfo;
+    use crate::{record::Value, Database, Error};
+
+    #[test]
+    fn test_query() {
+        let mut db = Database::from_bytes(
+            std::fs::read("test-data/constraints.sqlite").expect("Failed to read test database"),
+        )
+        .expect("Failed to parse test database");
+        let rows = db
+            .query("SELECT id, email, team FROM users")
+            .expect("Failed to run query");
+        assert_eq!(rows.column_names(), ["id", "email", "team"]);
+        assert_eq!(rows.len(), 2);
+        let rows = rows
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to read rows");
+        assert_eq!(rows[0].get::<i64>(0).unwrap(), 1);
+        assert_eq!(rows[0].get::<String>("EMAIL").unwrap(), "a@example.com");
+        assert_eq!(rows[1].get::<i64>("team").unwrap(), 2);
+        assert_eq!(
+            rows[1].get_row::<(i64, String, i64)>().unwrap(),
+            (2, "b@example.com".to_owned(), 2)
+        );
+        assert_eq!(
+            rows[1].values(),
+            [
+                Value::int(2),
+                Value::String(b"b@example.com".as_slice().into()),
+                Value::int(2)
+            ]
+        );
+
+        let error = rows[0].get::<i64>("name").unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{error:?}");
+        let error = rows[0].get::<i64>(3).unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{error:?}");
+        let error = rows[0].get::<i64>("email").unwrap_err();
+        assert!(matches!(error, Error::Mismatch(_)), "{error:?}");
+
+        // Columns read straight from a table know where they're from, even under another name.
+        let rows = db
+            .query("SELECT users.email AS address, team, id + 1 FROM users")
+            .expect("Failed to run query");
+        assert_eq!(
+            rows.columns(),
+            [
+                ColumnInfo {
+                    name: "address".to_owned(),
+                    declared_type: Some("TEXT".to_owned()),
+                    table: Some("users".to_owned()),
+                    origin: Some("email".to_owned()),
+                },
+                ColumnInfo {
+                    name: "team".to_owned(),
+                    declared_type: None,
+                    table: Some("users".to_owned()),
+                    origin: Some("team".to_owned()),
+                },
+                ColumnInfo::named("id + 1".to_owned()),
+            ]
+        );
+        let row = db
+            .query("DELETE FROM tags WHERE n = 0 RETURNING name, n * 2")
+            .expect("Failed to run delete");
+        assert_eq!(
+            row.columns(),
+            [
+                ColumnInfo {
+                    name: "name".to_owned(),
+                    declared_type: Some("TEXT".to_owned()),
+                    table: Some("tags".to_owned()),
+                    origin: Some("name".to_owned()),
+                },
+                ColumnInfo::named("n * 2".to_owned()),
+            ]
+        );
+
+        // Statements which don't return rows return none.
+        let mut rows = db
+            .query("UPDATE users SET team = 3 WHERE id = 1")
+            .expect("Failed to run update");
+        assert!(rows.columns().is_empty());
+        assert!(rows.next().is_none());
+        let team = db
+            .query("SELECT team FROM users")
+            .expect("Failed to run query")
+            .next()
+            .expect("Missing row")
+            .and_then(|row| row.get::<i64>(0));
+        assert_eq!(team.unwrap(), 3);
+    }
+}
@@ -0,0 +1,796 @@
+//! The definitions of tables, as parsed from the `CREATE` statements stored in `sqlite_schema`
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    record::{Affinity, OwnedValue, RowExt, Value},
+    Error,
+};
+
+/// Everything defined in a database's `sqlite_schema`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    /// Every object listed in `sqlite_schema`, in the order they're stored, whether or not its
+    /// definition could be parsed.
+    pub objects: Vec<SchemaObject>,
+    /// The tables whose definitions could be parsed.
+    pub tables: Vec<TableSchema>,
+    /// The indexes whose definitions could be parsed.
+    pub indexes: Vec<IndexSchema>,
+    /// The views whose definitions could be parsed.
+    pub views: Vec<ViewSchema>,
+    /// The triggers.
+    pub triggers: Vec<TriggerSchema>,
+    /// The problems with objects whose definitions couldn't be parsed.
+    pub warnings: Vec<SchemaWarning>,
+}
+
+/// An object listed in `sqlite_schema`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaObject {
+    /// What kind of object this is.
+    pub kind: ObjectKind,
+    /// The name of the object.
+    pub name: String,
+    /// The name of the table the object belongs to, which for a table or view is its own name.
+    pub table_name: String,
+    /// The page the object's btree is rooted at, for tables and indexes.
+    pub root_page: Option<usize>,
+    /// The statement which created the object, which automatic indexes don't have.
+    pub sql: Option<String>,
+}
+
+/// The kinds of object listed in `sqlite_schema`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObjectKind {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+/// The definition of a view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewSchema {
+    /// The name of the view.
+    pub name: String,
+    /// The names given to the view's columns, if it declared any.
+    pub columns: Vec<String>,
+    /// The query the view's rows come from.
+    pub query: Box<sqlparser::ast::Query>,
+}
+
+/// The definition of a trigger.
+///
+/// The body of the trigger isn't parsed, since `sqlparser` can't parse SQLite's `CREATE TRIGGER`
+/// syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggerSchema {
+    /// The name of the trigger.
+    pub name: String,
+    /// The name of the table or view the trigger is on.
+    pub table_name: String,
+    /// The statement which created the trigger.
+    pub sql: String,
+}
+
+/// An object in `sqlite_schema` whose definition couldn't be parsed.
+///
+/// Objects like these are skipped, so the rest of the database stays usable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaWarning {
+    /// The kind of object, as named in `sqlite_schema`.
+    pub kind: String,
+    /// The name of the object.
+    pub name: String,
+    /// What went wrong.
+    pub message: String,
+}
+
+/// The definition of a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    /// The name of the table.
+    pub name: String,
+    /// The columns, in the order they're stored in each record.
+    pub columns: Vec<ColumnDef>,
+    /// The index of the `INTEGER PRIMARY KEY` column, if there is one.
+    ///
+    /// Such a column is an alias for the rowid: it is stored as `NULL` in each record, and the
+    /// rowid stands in for it when reading.
+    pub rowid_alias: Option<usize>,
+    /// Whether the rowid alias was declared `AUTOINCREMENT`, so that rowids are never reused,
+    /// even once the rows which had them are deleted.
+    pub autoincrement: bool,
+    /// Whether the table was declared `WITHOUT ROWID`, making it an index btree.
+    pub without_rowid: bool,
+    /// The columns of the primary key, in order, or nothing if the table doesn't declare one.
+    pub primary_key: Vec<usize>,
+    /// The sets of columns which must be unique, from `UNIQUE` and `PRIMARY KEY` constraints.
+    ///
+    /// Each of these has an automatic index, and they're listed in the order SQLite numbers those
+    /// indexes in. Constraints which don't need an index, like an `INTEGER PRIMARY KEY`, aren't
+    /// included.
+    pub unique_constraints: Vec<Vec<usize>>,
+}
+
+/// The definition of an index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSchema {
+    /// The name of the index.
+    pub name: String,
+    /// The name of the table the index is on.
+    pub table_name: String,
+    /// The values the index is keyed on, in order.
+    ///
+    /// Each entry in the index holds these values followed by the rowid.
+    pub columns: Vec<IndexColumn>,
+    /// Whether each of [`Self::columns`] is sorted in descending order.
+    pub descending: Vec<bool>,
+    /// Whether no two rows may have the same values in all of [`Self::columns`].
+    ///
+    /// Rows with a `NULL` in any of those columns are exempt, since `NULL` is distinct from
+    /// everything.
+    pub unique: bool,
+}
+
+/// One of the values an index is keyed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexColumn {
+    /// The column of the table with this index.
+    Column(usize),
+    /// An expression over the columns of the table, like `lower(name)`.
+    Expr(sqlparser::ast::Expr),
+}
+
+/// The definition of a column in a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    /// The name of the column.
+    pub name: String,
+    /// The type the column was declared with, if any.
+    pub declared_type: Option<String>,
+    /// Whether the column was declared `NOT NULL`.
+    pub not_null: bool,
+    /// The text of the column's `DEFAULT` expression, if it has one.
+    pub default: Option<String>,
+}
+impl ColumnDef {
+    /// Get the affinity of the column, which is worked out from its declared type.
+    #[must_use]
+    pub fn affinity(&self) -> Affinity {
+        Affinity::from_declared_type(self.declared_type.as_deref().unwrap_or_default())
+    }
+}
+
+impl Schema {
+    /// Parse the rows of `sqlite_schema`.
+    ///
+    /// Rows which can't be understood become warnings instead of errors, so a database with a
+    /// few objects we can't parse can still be opened.
+    pub(crate) fn parse<Row: AsRef<[OwnedValue]>>(rows: impl IntoIterator<Item = Row>) -> Self {
+        let mut schema = Self::default();
+        // Indexes are parsed once all the tables are known, since they refer to them.
+        let mut index_sql = Vec::new();
+        for row in rows {
+            let row = row.as_ref();
+            let kind_name = row.get_as::<String>(0).unwrap_or_default();
+            let name = row.get_as::<String>(1).unwrap_or_default();
+            let mut warn = |message: String| {
+                schema.warnings.push(SchemaWarning {
+                    kind: kind_name.clone(),
+                    name: name.clone(),
+                    message,
+                });
+            };
+            let kind = match kind_name.as_str() {
+                "table" => ObjectKind::Table,
+                "index" => ObjectKind::Index,
+                "view" => ObjectKind::View,
+                "trigger" => ObjectKind::Trigger,
+                _ => {
+                    warn(format!("Unknown kind of schema object: {kind_name:?}"));
+                    continue;
+                }
+            };
+            let object = SchemaObject {
+                kind,
+                name: name.clone(),
+                table_name: row.get_as::<String>(2).unwrap_or_default(),
+                root_page: row
+                    .get(3)
+                    .and_then(Value::as_usize)
+                    .filter(|&page| page != 0),
+                sql: row.get_as::<Option<String>>(4).ok().flatten(),
+            };
+            let parsed = match (kind, object.sql.as_deref()) {
+                (ObjectKind::Table, Some(sql)) => TableSchema::parse(sql)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|table| {
+                        anyhow::ensure!(object.root_page.is_some(), "Missing root page");
+                        schema.tables.push(table);
+                        Ok(())
+                    }),
+                (ObjectKind::Index, _) if object.root_page.is_none() => {
+                    Err(anyhow::anyhow!("Missing root page"))
+                }
+                (ObjectKind::Index, _) => {
+                    index_sql.push(object.clone());
+                    Ok(())
+                }
+                (ObjectKind::View, Some(sql)) => ViewSchema::parse(sql)
+                    .map(|view| {
+                        schema.views.push(view);
+                    })
+                    .map_err(anyhow::Error::from),
+                (ObjectKind::Trigger, Some(sql)) => {
+                    schema.triggers.push(TriggerSchema {
+                        name: name.clone(),
+                        table_name: object.table_name.clone(),
+                        sql: sql.to_owned(),
+                    });
+                    Ok(())
+                }
+                (_, None) => Err(anyhow::anyhow!("Missing SQL")),
+            };
+            if let Err(e) = parsed {
+                warn(format!("{e:#}"));
+            }
+            schema.objects.push(object);
+        }
+        for object in index_sql {
+            let index = schema
+                .table(&object.table_name)
+                .with_context(|| Error::NotFound(format!("No such table: {}", object.table_name)))
+                .and_then(|table| {
+                    Ok(match &object.sql {
+                        Some(sql) => IndexSchema::parse(sql, table),
+                        // Indexes created automatically for constraints have no SQL.
+                        None => IndexSchema::automatic(&object.name, table),
+                    }?)
+                });
+            match index {
+                Ok(index) => schema.indexes.push(index),
+                Err(e) => schema.warnings.push(SchemaWarning {
+                    kind: "index".to_owned(),
+                    name: object.name,
+                    message: format!("{e:#}"),
+                }),
+            }
+        }
+        schema
+    }
+
+    /// Find the object of the given kind with the given name.
+    #[must_use]
+    pub fn object(&self, kind: ObjectKind, name: &str) -> Option<&SchemaObject> {
+        self.objects
+            .iter()
+            .find(|object| object.kind == kind && object.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Find the definition of the table with the given name.
+    #[must_use]
+    pub fn table(&self, name: &str) -> Option<&TableSchema> {
+        self.tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Find the definition of the index with the given name.
+    #[must_use]
+    pub fn index(&self, name: &str) -> Option<&IndexSchema> {
+        self.indexes
+            .iter()
+            .find(|index| index.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Find the definition of the view with the given name.
+    #[must_use]
+    pub fn view(&self, name: &str) -> Option<&ViewSchema> {
+        self.views
+            .iter()
+            .find(|view| view.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Find the warning about the object of the given kind with the given name, if there is one.
+    #[must_use]
+    pub fn warning(&self, kind: ObjectKind, name: &str) -> Option<&SchemaWarning> {
+        self.warnings.iter().find(|warning| {
+            warning.kind == kind.to_string() && warning.name.eq_ignore_ascii_case(name)
+        })
+    }
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Table => "table",
+            Self::Index => "index",
+            Self::View => "view",
+            Self::Trigger => "trigger",
+        })
+    }
+}
+
+impl fmt::Display for SchemaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to read {} {}: {}",
+            self.kind, self.name, self.message
+        )
+    }
+}
+
+impl TableSchema {
+    /// Whether `name` refers to `sqlite_schema`, which can also be called `sqlite_master`, in
+    /// any case.
+    #[must_use]
+    pub fn is_sqlite_schema(name: &str) -> bool {
+        ["sqlite_schema", "sqlite_master"]
+            .iter()
+            .any(|schema_name| schema_name.eq_ignore_ascii_case(name))
+    }
+
+    /// The definition of `sqlite_schema`, which isn't stored in the database itself.
+    pub(crate) fn sqlite_schema() -> Self {
+        let column = |name: &str, ty: &str| ColumnDef {
+            name: name.to_owned(),
+            declared_type: Some(ty.to_owned()),
+            not_null: false,
+            default: None,
+        };
+        Self {
+            name: "sqlite_schema".to_owned(),
+            columns: vec![
+                column("type", "TEXT"),
+                column("name", "TEXT"),
+                column("tbl_name", "TEXT"),
+                column("rootpage", "INTEGER"),
+                column("sql", "TEXT"),
+            ],
+            rowid_alias: None,
+            autoincrement: false,
+            without_rowid: false,
+            primary_key: Vec::new(),
+            unique_constraints: Vec::new(),
+        }
+    }
+
+    /// Parse the `CREATE TABLE` statement which defined a table.
+    pub fn parse(sql: &str) -> crate::Result<Self> {
+        let statements =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .context("Failed to parse table definition")?;
+        let [sqlparser::ast::Statement::CreateTable(create)] = statements.as_slice() else {
+            return Err(Error::Syntax(
+                "Table definition is not a single CREATE TABLE statement".to_owned(),
+            ));
+        };
+        let name = create
+            .name
+            .0
+            .last()
+            .context("Table definition is missing a name")?
+            .value
+            .clone();
+        let columns = create
+            .columns
+            .iter()
+            .map(|column| ColumnDef {
+                name: column.name.value.clone(),
+                declared_type: match column.data_type {
+                    sqlparser::ast::DataType::Unspecified => None,
+                    ref ty => Some(ty.to_string()),
+                },
+                not_null: column
+                    .options
+                    .iter()
+                    .any(|option| matches!(option.option, sqlparser::ast::ColumnOption::NotNull)),
+                default: column
+                    .options
+                    .iter()
+                    .find_map(|option| match option.option {
+                        sqlparser::ast::ColumnOption::Default(ref expr) => Some(expr.to_string()),
+                        _ => None,
+                    }),
+            })
+            .collect::<Vec<_>>();
+
+        // The primary key can be given either on the column or as a table constraint.
+        let column_primary_key = create.columns.iter().position(|column| {
+            column.options.iter().any(|option| {
+                matches!(
+                    option.option,
+                    sqlparser::ast::ColumnOption::Unique {
+                        is_primary: true,
+                        ..
+                    }
+                )
+            })
+        });
+        let constraint_primary_key = create.constraints.iter().find_map(|constraint| {
+            let sqlparser::ast::TableConstraint::PrimaryKey {
+                columns: key_columns,
+                ..
+            } = constraint
+            else {
+                return None;
+            };
+            Some(key_columns)
+        });
+        let primary_key = match (column_primary_key, constraint_primary_key) {
+            (Some(idx), _) => vec![idx],
+            (None, Some(key_columns)) => key_columns
+                .iter()
+                .map(|key_column| {
+                    columns
+                        .iter()
+                        .position(|column| column.name.eq_ignore_ascii_case(&key_column.value))
+                        .with_context(|| {
+                            Error::NotFound(format!("No such column: {}", key_column.value))
+                        })
+                })
+                .collect::<Result<Vec<_>>>()?,
+            (None, None) => Vec::new(),
+        };
+        let rowid_alias = match primary_key.as_slice() {
+            &[idx] if !create.without_rowid => Some(idx),
+            _ => None,
+        }
+        .filter(|&idx| {
+            columns[idx]
+                .declared_type
+                .as_deref()
+                .is_some_and(|ty| ty.eq_ignore_ascii_case("INTEGER"))
+        });
+        let autoincrement = rowid_alias.is_some_and(|idx| {
+            create.columns[idx].options.iter().any(|option| {
+                matches!(
+                    &option.option,
+                    sqlparser::ast::ColumnOption::DialectSpecific(tokens)
+                        if tokens.iter().any(|token| token.to_string() == "AUTOINCREMENT")
+                )
+            })
+        });
+
+        // SQLite creates the automatic indexes as it parses each constraint, so the column
+        // constraints come first, in column order, followed by the table constraints.
+        let column_constraints = create.columns.iter().enumerate().flat_map(|(idx, column)| {
+            column
+                .options
+                .iter()
+                .filter_map(move |option| match option.option {
+                    sqlparser::ast::ColumnOption::Unique { is_primary, .. } => {
+                        Some((is_primary, vec![idx]))
+                    }
+                    _ => None,
+                })
+        });
+        let table_constraints = create
+            .constraints
+            .iter()
+            .filter_map(|constraint| match constraint {
+                sqlparser::ast::TableConstraint::Unique {
+                    columns: key_columns,
+                    ..
+                } => Some((false, key_columns)),
+                sqlparser::ast::TableConstraint::PrimaryKey {
+                    columns: key_columns,
+                    ..
+                } => Some((true, key_columns)),
+                _ => None,
+            })
+            .map(|(is_primary, key_columns)| {
+                key_columns
+                    .iter()
+                    .map(|key_column| {
+                        columns
+                            .iter()
+                            .position(|column| column.name.eq_ignore_ascii_case(&key_column.value))
+                            .with_context(|| {
+                                Error::NotFound(format!("No such column: {}", key_column.value))
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .map(|key_columns| (is_primary, key_columns))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut unique_constraints: Vec<Vec<usize>> = Vec::new();
+        for (is_primary, key_columns) in column_constraints.chain(table_constraints) {
+            // The rowid (or the table itself, for `WITHOUT ROWID` tables) already provides the
+            // index for the primary key.
+            let keyed_by_table =
+                create.without_rowid || rowid_alias.is_some_and(|alias| key_columns == [alias]);
+            if (is_primary && keyed_by_table) || unique_constraints.contains(&key_columns) {
+                continue;
+            }
+            unique_constraints.push(key_columns);
+        }
+
+        Ok(Self {
+            name,
+            columns,
+            rowid_alias,
+            autoincrement,
+            without_rowid: create.without_rowid,
+            primary_key,
+            unique_constraints,
+        })
+    }
+
+    /// Find the index of the column with the given name.
+    #[must_use]
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl ViewSchema {
+    /// Parse the `CREATE VIEW` statement which defined a view.
+    pub fn parse(sql: &str) -> crate::Result<Self> {
+        let statements =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .context("Failed to parse view definition")?;
+        let [sqlparser::ast::Statement::CreateView {
+            name,
+            columns,
+            query,
+            ..
+        }] = statements.as_slice()
+        else {
+            return Err(Error::Syntax(
+                "View definition is not a single CREATE VIEW statement".to_owned(),
+            ));
+        };
+        Ok(Self {
+            name: name
+                .0
+                .last()
+                .context("View definition is missing a name")?
+                .value
+                .clone(),
+            columns: columns
+                .iter()
+                .map(|column| column.name.value.clone())
+                .collect(),
+            query: query.clone(),
+        })
+    }
+}
+
+impl IndexSchema {
+    /// Parse the `CREATE INDEX` statement which defined an index on `table`.
+    pub fn parse(sql: &str, table: &TableSchema) -> crate::Result<Self> {
+        let statements =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::SQLiteDialect {}, sql)
+                .context("Failed to parse index definition")?;
+        let [sqlparser::ast::Statement::CreateIndex(create)] = statements.as_slice() else {
+            return Err(Error::Syntax(
+                "Index definition is not a single CREATE INDEX statement".to_owned(),
+            ));
+        };
+        let name = create
+            .name
+            .as_ref()
+            .and_then(|name| name.0.last())
+            .context("Index definition is missing a name")?
+            .value
+            .clone();
+        // TODO Support partial indexes
+        if create.predicate.is_some() {
+            return Err(Error::UnsupportedSql(
+                "Partial indexes are unimplemented".to_owned(),
+            ));
+        }
+        let descending = create
+            .columns
+            .iter()
+            .map(|column| column.asc == Some(false))
+            .collect();
+        let columns = create
+            .columns
+            .iter()
+            .map(|column| match &column.expr {
+                sqlparser::ast::Expr::Identifier(ident) => table
+                    .column_index(&ident.value)
+                    .map(IndexColumn::Column)
+                    .with_context(|| Error::NotFound(format!("No such column: {}", ident.value))),
+                // TODO Support collations
+                sqlparser::ast::Expr::Collate { .. } => {
+                    anyhow::bail!(Error::UnsupportedSql(
+                        "Index collations are unimplemented".to_owned()
+                    ))
+                }
+                expr => Ok(IndexColumn::Expr(expr.clone())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            name,
+            table_name: table.name.clone(),
+            columns,
+            descending,
+            unique: create.unique,
+        })
+    }
+
+    /// Get the definition of an index SQLite created automatically for a constraint on `table`.
+    ///
+    /// These are named `sqlite_autoindex_<table>_<n>`, for the `n`th entry (counting from 1) in
+    /// [`TableSchema::unique_constraints`].
+    pub fn automatic(name: &str, table: &TableSchema) -> crate::Result<Self> {
+        let columns = name
+            .strip_prefix("sqlite_autoindex_")
+            .and_then(|name| name.rsplit_once('_'))
+            .and_then(|(_, n)| n.parse::<usize>().ok())
+            .and_then(|n| table.unique_constraints.get(n.checked_sub(1)?))
+            .with_context(|| format!("Unrecognized automatic index {name}"))?;
+        Ok(Self {
+            name: name.to_owned(),
+            table_name: table.name.clone(),
+            descending: vec![false; columns.len()],
+            columns: columns.iter().copied().map(IndexColumn::Column).collect(),
+            unique: true,
+        })
+    }
+
+    /// Get the indices of the columns the index is keyed on, or `None` if it's keyed on any
+    /// expressions.
+    #[must_use]
+    pub fn column_indices(&self) -> Option<Vec<usize>> {
+        self.columns
+            .iter()
+            .map(|column| match column {
+                IndexColumn::Column(idx) => Some(*idx),
+                IndexColumn::Expr(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rowid_alias() {
+        let parse = |sql| TableSchema::parse(sql).expect("Failed to parse table");
+        assert_eq!(parse("CREATE TABLE t(a, b)").rowid_alias, None);
+        assert_eq!(
+            parse("CREATE TABLE t(a, id integer PRIMARY KEY)").rowid_alias,
+            Some(1),
+        );
+        assert_eq!(
+            parse("CREATE TABLE t(id INTEGER, b, PRIMARY KEY(ID))").rowid_alias,
+            Some(0),
+        );
+        assert_eq!(
+            parse("CREATE TABLE t(id INT PRIMARY KEY)").rowid_alias,
+            None,
+            "Only the exact type INTEGER makes an alias",
+        );
+        assert_eq!(
+            parse("CREATE TABLE t(a INTEGER, b INTEGER, PRIMARY KEY(a, b))").rowid_alias,
+            None,
+        );
+        assert!(parse("CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, b)").autoincrement);
+        assert!(!parse("CREATE TABLE t(id INTEGER PRIMARY KEY, b)").autoincrement);
+        let schema = parse("CREATE TABLE t(a, b string)");
+        assert_eq!(schema.columns[0].declared_type, None);
+        assert_eq!(schema.column_index("B"), Some(1));
+    }
+
+    #[test]
+    fn test_column_constraints() {
+        let table = TableSchema::parse(
+            "CREATE TABLE t(a TEXT NOT NULL DEFAULT 'x', b DEFAULT (1 + 2), c, PRIMARY KEY(c, a))",
+        )
+        .expect("Failed to parse table");
+        assert!(table.columns[0].not_null);
+        assert!(!table.columns[1].not_null);
+        assert_eq!(table.columns[0].default.as_deref(), Some("'x'"));
+        assert_eq!(table.columns[1].default.as_deref(), Some("(1 + 2)"));
+        assert_eq!(table.columns[2].default, None);
+        assert_eq!(table.primary_key, [2, 0]);
+    }
+
+    #[test]
+    fn test_unique_constraints() {
+        let table = TableSchema::parse(
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, a UNIQUE, b, c, UNIQUE(b, c), UNIQUE(a))",
+        )
+        .expect("Failed to parse table");
+        assert_eq!(table.unique_constraints, [vec![1], vec![2, 3]]);
+        let table = TableSchema::parse("CREATE TABLE t(a TEXT, b, PRIMARY KEY(a), UNIQUE(b))")
+            .expect("Failed to parse table");
+        assert_eq!(table.unique_constraints, [vec![0], vec![1]]);
+
+        let index = IndexSchema::automatic("sqlite_autoindex_t_2", &table).unwrap();
+        assert_eq!(index.columns, [IndexColumn::Column(1)]);
+        assert!(index.unique);
+        assert!(IndexSchema::automatic("sqlite_autoindex_t_3", &table).is_err());
+        let index = IndexSchema::parse("CREATE INDEX i ON t(B, a)", &table).unwrap();
+        assert_eq!(index.column_indices(), Some(vec![1, 0]));
+        assert!(!index.unique);
+        let index = IndexSchema::parse("CREATE INDEX i ON t(lower(b), a)", &table).unwrap();
+        assert!(matches!(index.columns[0], IndexColumn::Expr(_)));
+        assert_eq!(index.column_indices(), None);
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        let row = |kind: &str, name: &str, table: &str, root_page: i64, sql: Option<&str>| {
+            let text = |text: &str| Value::String(text.as_bytes().into());
+            vec![
+                text(kind),
+                text(name),
+                text(table),
+                Value::int(root_page),
+                sql.map_or(Value::Null, text),
+            ]
+        };
+        let schema = Schema::parse([
+            // Indexes can come before the tables they're on.
+            row("index", "sqlite_autoindex_t_1", "t", 3, None),
+            row("table", "t", "t", 2, Some("CREATE TABLE t(a UNIQUE, b)")),
+            row("index", "t_b", "t", 4, Some("CREATE INDEX t_b ON t(b)")),
+            row(
+                "index",
+                "t_partial",
+                "t",
+                5,
+                Some("CREATE INDEX t_partial ON t(b) WHERE a > 0"),
+            ),
+            row(
+                "table",
+                "f",
+                "f",
+                0,
+                Some("CREATE VIRTUAL TABLE f USING fts5(body)"),
+            ),
+            row(
+                "view",
+                "v",
+                "v",
+                0,
+                Some("CREATE VIEW v AS SELECT a FROM t"),
+            ),
+            row("widget", "w", "w", 0, None),
+        ]);
+        assert_eq!(schema.objects.len(), 6);
+        assert_eq!(schema.tables.len(), 1);
+        assert_eq!(
+            schema
+                .indexes
+                .iter()
+                .map(|index| index.name.as_str())
+                .collect::<Vec<_>>(),
+            ["sqlite_autoindex_t_1", "t_b"],
+        );
+        assert_eq!(schema.views[0].name, "v");
+        assert_eq!(
+            schema
+                .warnings
+                .iter()
+                .map(|warning| warning.name.as_str())
+                .collect::<Vec<_>>(),
+            ["f", "w", "t_partial"],
+        );
+        assert_eq!(
+            schema
+                .warning(ObjectKind::Index, "T_PARTIAL")
+                .unwrap()
+                .to_string(),
+            "Failed to read index t_partial: Partial indexes are unimplemented",
+        );
+        assert_eq!(
+            schema.object(ObjectKind::Table, "f").unwrap().root_page,
+            None,
+        );
+    }
+}
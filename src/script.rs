@@ -0,0 +1,210 @@
+//! Splitting scripts of SQL into statements, for running them one at a time
+//!
+//! Scripts are split at the semicolons between statements, skipping those in strings, quoted
+//! names, comments, and the bodies of triggers, as the `sqlite3` shell does. Lines starting with
+//! a `.` between statements are the shell's commands, rather than SQL.
+
+/// A part of a script, which is run on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptPart<'a> {
+    /// A SQL statement, with its semicolon
+    Sql(&'a str),
+    /// A command for the shell, without the `.` it starts with
+    DotCommand(&'a str),
+}
+
+/// A part of a script, along with where it is in the script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptLine<'a> {
+    /// The line the part starts on, counting from 1
+    pub line: usize,
+    /// The part of the script
+    pub part: ScriptPart<'a>,
+}
+
+/// Split `script` into its statements and shell commands, in order.
+///
+/// Anything after the last semicolon is included as a statement, unless it's only whitespace
+/// and comments.
+#[must_use]
+pub fn split_script(script: &str) -> Vec<ScriptLine<'_>> {
+    split(script).0
+}
+
+/// Whether `script` ends at the end of a statement, so it's ready to run, rather than partway
+/// through one.
+///
+/// This is true if there's nothing but whitespace and comments after the last statement's
+/// semicolon, as in the `sqlite3` shell, which waits for more lines until then.
+#[must_use]
+pub fn is_complete(script: &str) -> bool {
+    !split(script).1
+}
+
+/// Split `script` into its parts, as for [`split_script`], and say whether it ends partway
+/// through a statement.
+fn split(script: &str) -> (Vec<ScriptLine<'_>>, bool) {
+    let mut parts = Vec::new();
+    let bytes = script.as_bytes();
+    // Lines are counted up to `counted_to` as they're needed.
+    let mut line = 1;
+    let mut counted_to = 0;
+    let mut line_at = |idx: usize| {
+        line += script[counted_to..idx].matches('\n').count();
+        counted_to = idx;
+        line
+    };
+    let mut idx = 0;
+    // The start of the current statement and the line it's on, once anything but whitespace and
+    // comments has been seen.
+    let mut start: Option<(usize, usize)> = None;
+    while idx < bytes.len() {
+        let byte = bytes[idx];
+        if start.is_none() && byte == b'.' && line_start(bytes, idx) {
+            let end = script[idx..]
+                .find('\n')
+                .map_or(script.len(), |end| idx + end);
+            parts.push(ScriptLine {
+                line: line_at(idx),
+                part: ScriptPart::DotCommand(script[idx + 1..end].trim_end()),
+            });
+            idx = end;
+            continue;
+        }
+        let next = match byte {
+            b'\'' | b'"' | b'`' => skip_past(bytes, idx + 1, &[byte]),
+            b'[' => skip_past(bytes, idx + 1, b"]"),
+            b'-' if bytes.get(idx + 1) == Some(&b'-') => skip_past(bytes, idx + 2, b"\n"),
+            b'/' if bytes.get(idx + 1) == Some(&b'*') => skip_past(bytes, idx + 2, b"*/"),
+            _ => idx + 1,
+        };
+        let is_comment = matches!(byte, b'-' | b'/') && next > idx + 1;
+        if start.is_none() && !is_comment && !byte.is_ascii_whitespace() {
+            start = Some((idx, line_at(idx)));
+        }
+        if byte == b';' {
+            if let Some((statement_start, statement_line)) = start {
+                let sql = &script[statement_start..next];
+                if !in_trigger_body(sql) {
+                    parts.push(ScriptLine {
+                        line: statement_line,
+                        part: ScriptPart::Sql(sql),
+                    });
+                    start = None;
+                }
+            }
+        }
+        idx = next;
+    }
+    let unfinished = start.is_some();
+    if let Some((statement_start, statement_line)) = start {
+        parts.push(ScriptLine {
+            line: statement_line,
+            part: ScriptPart::Sql(script[statement_start..].trim_end()),
+        });
+    }
+    (parts, unfinished)
+}
+
+/// Whether `idx` is the first thing on its line, other than whitespace.
+fn line_start(bytes: &[u8], idx: usize) -> bool {
+    bytes[..idx]
+        .iter()
+        .rev()
+        .take_while(|&&byte| byte != b'\n')
+        .all(u8::is_ascii_whitespace)
+}
+
+/// Get the index just past the first `end` at or after `idx`, or the end of `bytes` if there's
+/// none.
+///
+/// Quotes in strings and names are escaped by doubling them, which this reads as two strings in a
+/// row, so it doesn't need to handle them specially.
+fn skip_past(bytes: &[u8], idx: usize, end: &[u8]) -> usize {
+    bytes
+        .get(idx..)
+        .and_then(|rest| rest.windows(end.len()).position(|window| window == end))
+        .map_or(bytes.len(), |position| idx + position + end.len())
+}
+
+/// Whether `sql`, which ends in a semicolon, is a `CREATE TRIGGER` statement whose semicolon is
+/// between the statements in its body, rather than after its `END`.
+fn in_trigger_body(sql: &str) -> bool {
+    let words = sql
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let is_trigger = words
+        .first()
+        .is_some_and(|word| word.eq_ignore_ascii_case("create"))
+        && words
+            .iter()
+            .take(3)
+            .any(|word| word.eq_ignore_ascii_case("trigger"));
+    is_trigger
+        && !words
+            .last()
+            .is_some_and(|word| word.eq_ignore_ascii_case("end"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_complete, split_script, ScriptLine, ScriptPart};
+
+    #[test]
+    fn test_split_script() {
+        let script = "\
+-- Set up the tables
+CREATE TABLE t(a, b);
+INSERT INTO t VALUES ('a;b', \"c;\"\"d\"); /* not ; here */ INSERT INTO t
+  VALUES (1, 2);
+.mode table
+  SELECT * FROM [odd;name];
+CREATE TRIGGER t_insert AFTER INSERT ON t BEGIN
+  UPDATE t SET a = 1;
+  DELETE FROM t;
+END;
+SELECT 'it''s;' -- trailing
+";
+        let parts = split_script(script);
+        let sql = |line, sql| ScriptLine {
+            line,
+            part: ScriptPart::Sql(sql),
+        };
+        assert_eq!(
+            parts,
+            [
+                sql(2, "CREATE TABLE t(a, b);"),
+                sql(3, "INSERT INTO t VALUES ('a;b', \"c;\"\"d\");"),
+                sql(3, "INSERT INTO t\n  VALUES (1, 2);"),
+                ScriptLine {
+                    line: 5,
+                    part: ScriptPart::DotCommand("mode table"),
+                },
+                sql(6, "SELECT * FROM [odd;name];"),
+                sql(
+                    7,
+                    "CREATE TRIGGER t_insert AFTER INSERT ON t BEGIN\n  UPDATE t SET a = 1;\n  \
+                     DELETE FROM t;\nEND;"
+                ),
+                sql(11, "SELECT 'it''s;' -- trailing"),
+            ],
+        );
+        assert!(split_script("  -- nothing\n/* at all */\n").is_empty());
+    }
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete(""));
+        assert!(is_complete("SELECT 1; -- done\n"));
+        assert!(is_complete(".tables"));
+        assert!(!is_complete("CREATE TABLE t(\n  a,\n"));
+        assert!(!is_complete("SELECT 'a;"));
+        assert!(!is_complete(
+            "CREATE TRIGGER t_insert AFTER INSERT ON t BEGIN\n  DELETE FROM t;"
+        ));
+        assert!(is_complete(
+            "CREATE TRIGGER t_insert AFTER INSERT ON t BEGIN\n  DELETE FROM t;\nEND;"
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+//! Handles to individual tables
+
+use std::hash::{BuildHasher, RandomState};
+
+use anyhow::Result;
+
+use crate::{
+    btree::PartialPayload,
+    page::ParsedPage,
+    pager::Storage,
+    record::{OwnedValue, Record},
+    table_iter::{row_values, Row, TableIter},
+    Database, Error,
+};
+
+/// The most leaves [`Table::sample`] will read rows from.
+const MAX_SAMPLE_LEAVES: usize = 16;
+
+/// A table in a [`Database`].
+pub struct Table<'a, File = std::fs::File> {
+    /// The database containing the table
+    db: &'a mut Database<File>,
+    /// The page the table's btree is rooted at
+    root_page: usize,
+    /// The index of the column which aliases the rowid, if any
+    rowid_alias: Option<usize>,
+}
+
+impl<'a, File: Storage> Table<'a, File> {
+    pub(crate) fn new(
+        db: &'a mut Database<File>,
+        root_page: usize,
+        rowid_alias: Option<usize>,
+    ) -> Self {
+        Self {
+            db,
+            root_page,
+            rowid_alias,
+        }
+    }
+
+    /// Iterate over every row in the table.
+    #[must_use]
+    pub fn rows(self) -> TableIter<'a, File> {
+        TableIter::from_root_page(self.db, self.root_page, self.rowid_alias)
+    }
+
+    /// Call `f` on every row in the table, borrowing each from the page it's stored in.
+    ///
+    /// See [`TableIter::for_each_row`].
+    pub fn for_each_row(self, f: impl FnMut(&Row<'_>) -> crate::Result<()>) -> crate::Result<()> {
+        self.rows().for_each_row(f)
+    }
+
+    /// Count the rows in the table.
+    ///
+    /// This only reads the number of cells in each leaf, without decoding any rows.
+    pub fn count(&mut self) -> crate::Result<u64> {
+        let mut count = 0;
+        let mut pages = vec![self.root_page];
+        while let Some(page_num) = pages.pop() {
+            let page = self.db.pager.read_page(page_num)?;
+            match page.parse() {
+                ParsedPage::BTreeTableLeaf(page) => count += page.num_cells() as u64,
+                ParsedPage::BTreeTableInternal(page) => {
+                    pages.extend(page.cells().map(|cell| cell.left_child_page as usize));
+                    pages.push(page.rightmost_child_idx() as usize);
+                }
+                ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                    return Err(Error::corrupt(Some(page_num), "Expected a table page"));
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Get a preview of up to `n` rows from across the table, in rowid order.
+    ///
+    /// Unlike taking the first `n` rows, this reads rows from a handful of leaves spread over the
+    /// whole btree: the first one, the last one, and others reached by descending through randomly
+    /// chosen children. This gives a more representative picture of the table without having to
+    /// scan all of it, though it may return fewer than `n` rows even if the table has more.
+    pub fn sample(&mut self, n: usize) -> crate::Result<Vec<Vec<OwnedValue>>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut rng = XorShift::new();
+        let mut leaves = vec![
+            self.descend(|_| 0)?,
+            self.descend(|num_children| num_children - 1)?,
+        ];
+        for _ in 2..n.min(MAX_SAMPLE_LEAVES) {
+            leaves.push(self.descend(|num_children| rng.below(num_children))?);
+        }
+        leaves.sort_unstable();
+        leaves.dedup();
+
+        let mut rows = Vec::new();
+        // At least two rows per leaf, so the first and last rows of the table are always kept.
+        let per_leaf = n.div_ceil(leaves.len()).max(2);
+        for leaf in leaves {
+            let page = self.db.pager.read_page(leaf)?;
+            let ParsedPage::BTreeTableLeaf(page) = page.parse() else {
+                return Err(Error::corrupt(Some(leaf), "Expected a leaf page"));
+            };
+            let keep = spread(page.num_cells(), per_leaf);
+            let payloads = page
+                .cells()
+                .enumerate()
+                .filter(|(idx, _)| keep.binary_search(idx).is_ok())
+                .map(|(_, cell)| (cell.row_id(), PartialPayload::of(&cell)))
+                .collect::<Vec<_>>();
+            for (row_id, payload) in payloads {
+                let payload = payload.complete(&mut self.db.pager)?;
+                let row = row_values(
+                    Record::parse(&payload)?,
+                    row_id,
+                    self.rowid_alias,
+                    self.db.pager.text_encoding(),
+                );
+                rows.push((row_id, row));
+            }
+        }
+        rows.sort_unstable_by_key(|(row_id, _)| *row_id);
+        rows.dedup_by_key(|(row_id, _)| *row_id);
+        if rows.len() > n {
+            let keep = spread(rows.len(), n);
+            rows = rows
+                .into_iter()
+                .enumerate()
+                .filter(|(idx, _)| keep.binary_search(idx).is_ok())
+                .map(|(_, row)| row)
+                .collect();
+        }
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    }
+
+    /// Descend from the root to a leaf, returning the leaf's page number.
+    ///
+    /// At each interior page, `choose_child` is given the number of children and returns the
+    /// index of the one to descend into.
+    fn descend(&mut self, mut choose_child: impl FnMut(usize) -> usize) -> Result<usize> {
+        let mut page_num = self.root_page;
+        loop {
+            let page = self.db.pager.read_page(page_num)?;
+            match page.parse() {
+                ParsedPage::BTreeTableLeaf(_) => return Ok(page_num),
+                ParsedPage::BTreeTableInternal(page) => {
+                    let child = choose_child(page.num_cells() + 1);
+                    page_num = match page.cell(child) {
+                        Some(cell) => cell.left_child_page,
+                        None => page.rightmost_child_idx(),
+                    } as usize;
+                }
+                ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                    anyhow::bail!("Expected a table page at {page_num}")
+                }
+            }
+        }
+    }
+}
+
+/// Choose up to `n` indices spread evenly over `0..len`, including the first and last, in order.
+fn spread(len: usize, n: usize) -> Vec<usize> {
+    if len <= n {
+        return (0..len).collect();
+    }
+    (0..n).map(|idx| idx * (len - 1) / (n - 1).max(1)).collect()
+}
+
+/// A small, non-cryptographic random number generator.
+struct XorShift(u64);
+impl XorShift {
+    fn new() -> Self {
+        // Must never be zero, or it'll be stuck there
+        Self(RandomState::new().hash_one(()) | 1)
+    }
+
+    /// Get a random number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::record::RowExt;
+
+    #[test]
+    fn test_sample() {
+        let mut db = Database::new(
+            File::open("./test-data/many-tables.sqlite").expect("Failed to open database file"),
+        )
+        .expect("Failed to parse database file as database");
+        let sample = db
+            .table("sqlite_schema")
+            .expect("Failed to find table")
+            .sample(10)
+            .expect("Failed to sample table");
+        assert_eq!(sample.len(), 10);
+        let root_pages = sample
+            .iter()
+            .map(|row| row.get_as::<i64>(3).expect("Invalid root page"))
+            .collect::<Vec<_>>();
+        assert!(
+            root_pages.windows(2).all(|pair| pair[0] < pair[1]),
+            "Sampled rows should be distinct and in order",
+        );
+        assert_eq!(
+            root_pages[0], 2,
+            "The sample should start with the first row",
+        );
+        assert_eq!(
+            root_pages[9],
+            db.table_root_page("t1024").expect("Failed to find table") as i64,
+            "The sample should end with the last row",
+        );
+
+        let mut table = db.table("t1").expect("Failed to find table");
+        assert!(
+            table.sample(10).expect("Failed to sample table").is_empty(),
+            "Empty tables have nothing to sample",
+        );
+    }
+}
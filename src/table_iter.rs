@@ -1,42 +1,74 @@
 //! An iterator over the rows of a table
 
-use crate::{page::ParsedPage, record::Value, Database};
+use crate::{
+    btree::PartialPayload,
+    page::ParsedPage,
+    pager::Storage,
+    record::{FromValue, OwnedValue, Record, RowExt, TextEncoding, Value},
+    Database, Error,
+};
 
-use anyhow::{Context, Result};
+/// How many children of an interior page to read ahead while scanning it.
+const READ_AHEAD_PAGES: usize = 8;
 
-pub struct TableIter<'a> {
-    db: &'a mut Database,
+pub struct TableIter<'a, File = std::fs::File> {
+    db: &'a mut Database<File>,
     stack: Vec<StackFrame>,
+    /// The index of the column which aliases the rowid, if any.
+    rowid_alias: Option<usize>,
 }
 
-impl<'a> TableIter<'a> {
-    pub fn new(db: &'a mut Database, table_name: &str) -> Result<Self> {
-        const SCHEMA_TABLE_NAMES: &[&str] = &["sqlite_schema", "sqlite_master"];
-        let root_page_num = if SCHEMA_TABLE_NAMES.contains(&table_name) {
-            // schema table is always rooted at the first page
-            1
-        } else {
-            db.table_root_page_indices_by_name()?
-                .find(|(name, _)| name == table_name)
-                .with_context(|| format!("Failed to find table {table_name}"))?
-                .1
-        };
-        Ok(Self {
+impl<'a, File: Storage> TableIter<'a, File> {
+    pub fn new(db: &'a mut Database<File>, table_name: &str) -> crate::Result<Self> {
+        let root_page_num = db.table_root_page(table_name)?;
+        // A table we can't parse the definition of can still be read, just without any rowid
+        // alias filled in.
+        let rowid_alias = db
+            .table_schema(table_name)
+            .map_or(None, |schema| schema.rowid_alias);
+        Ok(Self::from_root_page(db, root_page_num, rowid_alias))
+    }
+
+    /// Iterate over the table whose btree is rooted at the given page.
+    ///
+    /// If `rowid_alias` is given, that column is filled in with the rowid of each row.
+    pub(crate) fn from_root_page(
+        db: &'a mut Database<File>,
+        root_page_num: usize,
+        rowid_alias: Option<usize>,
+    ) -> Self {
+        Self {
             db,
             stack: vec![StackFrame {
                 page_num: root_page_num,
                 idx_in_page: 0,
             }],
-        })
+            rowid_alias,
+        }
     }
-}
 
-impl<'a> Iterator for TableIter<'a> {
-    type Item = Vec<Value<Box<[u8]>>>;
+    /// Call `f` on each remaining row, without copying the row out of the page it's stored in.
+    ///
+    /// This avoids the allocations [`Iterator::next`] makes for each row, which adds up over large
+    /// scans. Rows with payloads in overflow pages still have to be read into a buffer first.
+    ///
+    /// This fails if the statement being run is interrupted partway through.
+    pub fn for_each_row(
+        mut self,
+        mut f: impl FnMut(&Row<'_>) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        while let Some(result) = self.advance(&mut f) {
+            result?;
+            self.db.check_interrupt()?;
+        }
+        Ok(())
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Move to the next row and pass it to `f`, or return `None` if there are no rows left.
+    fn advance<T>(&mut self, f: impl FnOnce(&Row<'_>) -> T) -> Option<T> {
         let stack_len = self.stack.len();
         let top_frame = self.stack.get_mut(stack_len.checked_sub(1)?)?;
+        let encoding = self.db.pager.text_encoding();
         let page = self
             .db
             .pager
@@ -44,9 +76,21 @@ impl<'a> Iterator for TableIter<'a> {
             .expect("Error reading pages");
         match page.parse() {
             ParsedPage::BTreeTableInternal(internal) => {
+                // Children are visited in order, so the next few are read ahead of time, a batch
+                // at a time, which takes a single read for any of them which are next to each
+                // other in the file.
+                let read_ahead = (top_frame.idx_in_page % READ_AHEAD_PAGES == 0).then(|| {
+                    internal
+                        .cells()
+                        .map(|cell| cell.left_child_page as usize)
+                        .chain([internal.rightmost_child_idx() as usize])
+                        .skip(top_frame.idx_in_page)
+                        .take(READ_AHEAD_PAGES)
+                        .collect::<Vec<_>>()
+                });
                 // If the top page is an internal node, we set the top of the stack to the next
                 // page to look in, and then recurse.
-                if let Some(cell) = internal.cells().nth(top_frame.idx_in_page) {
+                if let Some(cell) = internal.cell(top_frame.idx_in_page) {
                     top_frame.idx_in_page = top_frame.idx_in_page.saturating_add(1);
                     self.stack.push(StackFrame {
                         page_num: cell.left_child_page as usize,
@@ -65,29 +109,158 @@ impl<'a> Iterator for TableIter<'a> {
                     // the parent (should never be hit because of above optimization).
                     self.stack.pop();
                 }
+                if let Some(pages) = read_ahead {
+                    // The pages are read again as they're visited, so the scan carries on without
+                    // them if this fails.
+                    let _ = self.db.pager.read_pages(pages);
+                }
                 // We have a different stack frame on top (pointing to a different page), so we can
                 // recurse. This should get tail-call optimization, but even if it doesn't, we only
                 // have a pointer on the stack at this point, so it shouldn't explode the stack too
                 // much.
-                self.next()
+                self.advance(f)
             }
             ParsedPage::BTreeTableLeaf(leaf) => {
-                let Some(cell) = leaf.cells().nth(top_frame.idx_in_page) else {
+                let Some(cell) = leaf.cell(top_frame.idx_in_page) else {
                     self.stack.pop();
-                    return self.next();
+                    return self.advance(f);
                 };
                 top_frame.idx_in_page = top_frame.idx_in_page.saturating_add(1);
-                Some(
-                    cell.payload()
-                        .value_iter()
-                        .map(|value| value.to_owned())
-                        .collect(),
-                )
+                self.db.rows_examined += 1;
+                let row_id = cell.row_id();
+                if let Some(record) = cell.payload() {
+                    return Some(f(&Row {
+                        record,
+                        row_id,
+                        rowid_alias: self.rowid_alias,
+                        encoding,
+                    }));
+                }
+                let payload = PartialPayload::of(&cell)
+                    .complete(&mut self.db.pager)
+                    .expect("Error reading overflow pages");
+                Some(f(&Row {
+                    record: Record::parse(&payload).expect("Failed to parse row"),
+                    row_id,
+                    rowid_alias: self.rowid_alias,
+                    encoding,
+                }))
+            }
+            ParsedPage::BTreeIndexLeaf(_) | ParsedPage::BTreeIndexInternal(_) => {
+                panic!("Expected a table page at {}", top_frame.page_num)
             }
         }
     }
 }
 
+impl<'a, File: Storage> Iterator for TableIter<'a, File> {
+    type Item = Vec<Value<Box<[u8]>>>;
+
+    /// Get the next row, or `None` if there are none left or the statement being run has been
+    /// interrupted.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.db.check_interrupt().ok()?;
+        self.advance(|row: &Row<'_>| row.to_values())
+    }
+}
+
+/// A row of a table, borrowed from the page it's stored in.
+///
+/// See [`TableIter::for_each_row`].
+pub struct Row<'a> {
+    /// The record holding the row's values
+    record: Record<'a>,
+    /// The rowid of the row
+    row_id: i64,
+    /// The index of the column which aliases the rowid, if any
+    rowid_alias: Option<usize>,
+    /// The encoding of text in the database
+    encoding: TextEncoding,
+}
+
+impl<'a> Row<'a> {
+    /// Get the rowid of this row.
+    #[must_use]
+    pub fn row_id(&self) -> i64 {
+        self.row_id
+    }
+
+    /// Get the encoding the text in this row is stored in.
+    ///
+    /// Text values are borrowed as they're stored, so they're only UTF-8 if this is.
+    #[must_use]
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    /// Iterate over the values in this row, with the rowid filled in for its alias, if it has one.
+    pub fn values(&self) -> impl Iterator<Item = Value<&'a [u8]>> + '_ {
+        let row_id = self.row_id;
+        self.record
+            .value_iter()
+            .enumerate()
+            .map(move |(idx, value)| {
+                if value.is_null() && self.rowid_alias == Some(idx) {
+                    Value::int(row_id)
+                } else {
+                    value
+                }
+            })
+    }
+
+    /// Get the value in column `idx`, if there is one.
+    #[must_use]
+    pub fn get(&self, idx: usize) -> Option<Value<&'a [u8]>> {
+        let value = self.record.value_at(idx).expect("Failed to parse body")?;
+        Some(if value.is_null() && self.rowid_alias == Some(idx) {
+            Value::int(self.row_id)
+        } else {
+            value
+        })
+    }
+
+    /// Copy the values in this row out of its page, with text converted to UTF-8.
+    #[must_use]
+    pub fn to_values(&self) -> Vec<OwnedValue> {
+        row_values(self.record, self.row_id, self.rowid_alias, self.encoding)
+    }
+}
+
+impl RowExt for Row<'_> {
+    fn get_as<T: FromValue>(&self, idx: usize) -> crate::Result<T> {
+        let value = self
+            .get(idx)
+            .ok_or_else(|| Error::NotFound(format!("Column index {idx} out of range")))?;
+        let value = if self.encoding == TextEncoding::Utf8 {
+            value.get()
+        } else {
+            value.decode_text(self.encoding).get()
+        };
+        value.map_err(|e| Error::Mismatch(format!("Invalid value in column {idx}: {e}")))
+    }
+}
+
+/// Get the values in a row of a table, whose text is stored in `encoding`.
+///
+/// If `rowid_alias` is given, the `NULL` stored in that column is replaced with the rowid.
+pub(crate) fn row_values(
+    record: Record<'_>,
+    row_id: i64,
+    rowid_alias: Option<usize>,
+    encoding: TextEncoding,
+) -> Vec<OwnedValue> {
+    let mut values = record
+        .value_iter()
+        .map(|value| value.decode_text(encoding))
+        .collect::<Vec<_>>();
+    if let Some(value) = rowid_alias.and_then(|idx| values.get_mut(idx)) {
+        if value.is_null() {
+            *value = Value::int(row_id);
+        }
+    }
+    values
+}
+
 struct StackFrame {
     page_num: usize,
     idx_in_page: usize,
@@ -138,4 +311,93 @@ mod tests {
             1024,
         );
     }
+
+    #[test]
+    fn test_reads_unflushed_writes() {
+        let mut db = Database::new(
+            File::open("./test-data/minimal-test.sqlite").expect("Failed to open database file"),
+        )
+        .expect("Failed to parse database file as database");
+        // Replace the empty root page of `t1` with a leaf holding a single row, `(42)`.
+        let page_size = db.pager.page_size();
+        let cell = [3, 1, 2, 1, 42];
+        let cell_offset = (page_size - cell.len()) as u16;
+        let mut page = vec![0; page_size];
+        page[..8].copy_from_slice(&[0x0d, 0, 0, 0, 1, 0, 0, 0]);
+        page[5..7].copy_from_slice(&cell_offset.to_be_bytes());
+        page[8..10].copy_from_slice(&cell_offset.to_be_bytes());
+        page[usize::from(cell_offset)..].copy_from_slice(&cell);
+        db.pager.write_page(2, &page).expect("Failed to write page");
+        assert_eq!(
+            TableIter::new(&mut db, "t1")
+                .expect("Failed to make iterator")
+                .collect::<Vec<_>>(),
+            vec![vec![Value::int(42)]],
+        );
+    }
+
+    #[test]
+    fn test_for_each_row() {
+        let mut db = Database::new(
+            File::open("./test-data/overflow.sqlite").expect("Failed to open database file"),
+        )
+        .expect("Failed to parse database file as database");
+        let expected = TableIter::new(&mut db, "big")
+            .expect("Failed to make iterator")
+            .collect::<Vec<_>>();
+        let mut rows = Vec::new();
+        TableIter::new(&mut db, "big")
+            .expect("Failed to make iterator")
+            .for_each_row(|row| {
+                assert_eq!(row.get_as::<i64>(0)?, row.row_id());
+                rows.push(row.to_values());
+                Ok(())
+            })
+            .expect("Failed to scan table");
+        assert_eq!(rows, expected);
+
+        // Errors from the callback stop the scan.
+        let mut seen = 0;
+        let result = TableIter::new(&mut db, "big")
+            .expect("Failed to make iterator")
+            .for_each_row(|_| {
+                seen += 1;
+                if seen == 2 {
+                    return Err(Error::Other("Stop".to_owned()));
+                }
+                Ok(())
+            });
+        assert!(result.is_err());
+        assert_eq!(seen, 2);
+    }
+
+    #[test]
+    fn test_overflowing_rows() {
+        let mut db = Database::new(
+            File::open("./test-data/overflow.sqlite").expect("Failed to open database file"),
+        )
+        .expect("Failed to parse database file as database");
+        let text = (0..3000)
+            .map(|idx| b'a' + (idx % 26) as u8)
+            .collect::<Box<[u8]>>();
+        let blob = (0..4).flat_map(|_| 0..=255_u8).collect::<Box<[u8]>>();
+        let rows = TableIter::new(&mut db, "big")
+            .expect("Failed to make iterator")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                vec![Value::int(1), Value::String(Box::from(&b"small"[..]))],
+                vec![Value::int(2), Value::String(text.clone())],
+                vec![Value::int(3), Value::Blob(blob)],
+                vec![Value::int(4), Value::String(Box::from(&b"tail"[..]))],
+            ],
+        );
+        // Rows found by rowid are read in full too.
+        let payload = crate::btree::find_row(&mut db.pager, 2, 2)
+            .unwrap()
+            .expect("The row should exist");
+        assert_eq!(payload.len(), 4 + 3000);
+        assert!(payload.ends_with(&text));
+    }
 }
@@ -0,0 +1,33 @@
+//! Virtual tables, whose rows are computed instead of being read from the database file
+//!
+//! For now, these are only the built-in table-valued functions, which are used in `FROM` with
+//! their arguments, like `SELECT * FROM json_each('[1, 2]')`.
+
+use anyhow::Result;
+
+use crate::record::OwnedValue;
+
+mod json;
+
+/// A table whose rows are computed from the arguments it's called with.
+pub(crate) trait VirtualTable: Sync {
+    /// The name the table is called by in queries.
+    fn name(&self) -> &'static str;
+
+    /// The names of the table's columns.
+    fn columns(&self) -> &'static [&'static str];
+
+    /// Compute the rows of the table for the given arguments.
+    fn rows(&self, args: &[OwnedValue]) -> Result<Vec<Vec<OwnedValue>>>;
+}
+
+/// The table-valued functions which are built in.
+const BUILTINS: &[&dyn VirtualTable] = &[&json::JsonEach, &json::JsonTree];
+
+/// Look up the built-in table-valued function with the given name, ignoring case.
+pub(crate) fn table_function(name: &str) -> Option<&'static dyn VirtualTable> {
+    BUILTINS
+        .iter()
+        .copied()
+        .find(|table| table.name().eq_ignore_ascii_case(name))
+}
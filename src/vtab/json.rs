@@ -0,0 +1,267 @@
+//! The `json_each` and `json_tree` table-valued functions, which turn JSON into rows
+
+use anyhow::{Context, Result};
+
+use super::VirtualTable;
+use crate::{
+    json::Json,
+    record::{Affinity, OwnedValue, Value},
+};
+
+/// The columns of both functions, which are the same as SQLite's, without the hidden ones for the
+/// arguments.
+const COLUMNS: &[&str] = &[
+    "key", "value", "type", "atom", "id", "parent", "fullkey", "path",
+];
+
+/// The index of the `parent` column.
+const PARENT: usize = 5;
+
+/// `json_each(json, path)`, which has a row for each child of the array or object at `path`, or
+/// one row for the value at `path` if it isn't an array or object.
+pub(super) struct JsonEach;
+impl VirtualTable for JsonEach {
+    fn name(&self) -> &'static str {
+        "json_each"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        COLUMNS
+    }
+
+    fn rows(&self, args: &[OwnedValue]) -> Result<Vec<Vec<OwnedValue>>> {
+        let Some((root, rows)) = walk(self.name(), args)? else {
+            return Ok(Vec::new());
+        };
+        if !root.is_container() {
+            return Ok(rows);
+        }
+        // Only the children of the root are returned, and `parent` is always `NULL`, as it is in
+        // SQLite.
+        Ok(rows
+            .into_iter()
+            .filter(|row| row[PARENT].as_i64() == Some(0))
+            .map(|mut row| {
+                row[PARENT] = Value::Null;
+                row
+            })
+            .collect())
+    }
+}
+
+/// `json_tree(json, path)`, which has a row for the value at `path` and for everything inside
+/// it, recursively, with each value before the ones inside it.
+pub(super) struct JsonTree;
+impl VirtualTable for JsonTree {
+    fn name(&self) -> &'static str {
+        "json_tree"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        COLUMNS
+    }
+
+    fn rows(&self, args: &[OwnedValue]) -> Result<Vec<Vec<OwnedValue>>> {
+        Ok(walk(self.name(), args)?.map_or_else(Vec::new, |(_, rows)| rows))
+    }
+}
+
+/// Parse the arguments of the function `name`, and make a row for the value at the path they
+/// give and for everything inside it, returning that value along with the rows.
+///
+/// Returns `None` if either argument is `NULL`, or if nothing is at the path, in which case the
+/// function has no rows.
+///
+/// Each row's `id` is its position in the walk, so the value at the path has an `id` of 0. SQLite
+/// uses offsets into its parsed form of the JSON instead, which aren't reproduced here.
+fn walk(name: &str, args: &[OwnedValue]) -> Result<Option<(Json, Vec<Vec<OwnedValue>>)>> {
+    let (json, path) = match args {
+        [json] => (json, None),
+        [json, path] => (json, Some(path)),
+        _ => anyhow::bail!("{name}() takes 1 or 2 arguments"),
+    };
+    let Some(json) = text_argument(json)? else {
+        return Ok(None);
+    };
+    let path = match path.map(text_argument).transpose()? {
+        Some(None) => return Ok(None),
+        Some(Some(path)) => path,
+        None => "$".to_owned(),
+    };
+    let document = Json::parse(&json)?;
+    let steps = parse_path(&path)?;
+
+    let mut root = &document;
+    let mut fullkey = String::from("$");
+    let mut parent_path = fullkey.clone();
+    let mut key = Value::Null;
+    for step in &steps {
+        let child = match step {
+            Step::Key(name) => root.get_key(name),
+            Step::Index(idx) => root.get_index(*idx),
+        };
+        let Some(child) = child else {
+            return Ok(None);
+        };
+        root = child;
+        parent_path.clone_from(&fullkey);
+        step.append_to(&mut fullkey);
+        key = step.key();
+    }
+    let mut rows = Vec::new();
+    visit(root, key, &fullkey, &parent_path, None, &mut rows);
+    Ok(Some((root.clone(), rows)))
+}
+
+/// Convert an argument to text, or `None` if it's `NULL`.
+fn text_argument(value: &OwnedValue) -> Result<Option<String>> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Blob(_) => anyhow::bail!("JSON cannot hold BLOB values"),
+        _ => value
+            .apply_affinity(Affinity::Text)
+            .as_str()
+            .map(|text| Some(text.to_owned()))
+            .context("Argument is not valid utf-8"),
+    }
+}
+
+/// Add a row for `value` to `rows`, followed by a row for each value inside it.
+fn visit(
+    value: &Json,
+    key: OwnedValue,
+    fullkey: &str,
+    path: &str,
+    parent: Option<i64>,
+    rows: &mut Vec<Vec<OwnedValue>>,
+) {
+    let id = rows.len() as i64;
+    let text = |text: &str| Value::String(text.as_bytes().into());
+    let atom = if value.is_container() {
+        Value::Null
+    } else {
+        value.to_sql()
+    };
+    rows.push(vec![
+        key,
+        value.to_sql(),
+        text(value.type_name()),
+        atom,
+        Value::int(id),
+        parent.map_or(Value::Null, Value::int),
+        text(fullkey),
+        text(path),
+    ]);
+    let children: Box<dyn Iterator<Item = (Step, &Json)>> = match value {
+        Json::Array(items) => Box::new(
+            items
+                .iter()
+                .enumerate()
+                .map(|(idx, item)| (Step::Index(idx), item)),
+        ),
+        Json::Object(entries) => Box::new(
+            entries
+                .iter()
+                .map(|(name, item)| (Step::Key(name.clone()), item)),
+        ),
+        _ => return,
+    };
+    for (step, child) in children {
+        let mut child_key = fullkey.to_owned();
+        step.append_to(&mut child_key);
+        visit(child, step.key(), &child_key, fullkey, Some(id), rows);
+    }
+}
+
+/// One step of a JSON path, into an object or an array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    /// The value with this key in an object
+    Key(String),
+    /// The value at this index in an array
+    Index(usize),
+}
+impl Step {
+    /// Get the value of the `key` column for the value this step leads to.
+    fn key(&self) -> OwnedValue {
+        match self {
+            Self::Key(name) => Value::String(name.as_bytes().into()),
+            Self::Index(idx) => Value::int(*idx as i64),
+        }
+    }
+
+    /// Append this step to `path`, in the form SQLite uses in the `fullkey` and `path` columns.
+    ///
+    /// Keys are quoted unless they're made of letters and digits, starting with a letter.
+    fn append_to(&self, path: &mut String) {
+        match self {
+            Self::Key(name)
+                if name.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && name.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                path.push('.');
+                path.push_str(name);
+            }
+            Self::Key(name) => {
+                path.push_str(".\"");
+                path.push_str(name);
+                path.push('"');
+            }
+            Self::Index(idx) => {
+                path.push('[');
+                path.push_str(&idx.to_string());
+                path.push(']');
+            }
+        }
+    }
+}
+
+/// Parse a JSON path like `$.a[2]."b c"` into its steps.
+fn parse_path(path: &str) -> Result<Vec<Step>> {
+    let bad_path = || anyhow::anyhow!("bad JSON path: '{path}'");
+    let mut rest = path.strip_prefix('$').ok_or_else(bad_path)?;
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix(".\"") {
+            let (name, tail) = quoted.split_once('"').ok_or_else(bad_path)?;
+            steps.push(Step::Key(name.to_owned()));
+            rest = tail;
+        } else if let Some(key) = rest.strip_prefix('.') {
+            let end = key.find(['.', '[']).unwrap_or(key.len());
+            if end == 0 {
+                return Err(bad_path());
+            }
+            steps.push(Step::Key(key[..end].to_owned()));
+            rest = &key[end..];
+        } else if let Some(index) = rest.strip_prefix('[') {
+            let (idx, tail) = index.split_once(']').ok_or_else(bad_path)?;
+            steps.push(Step::Index(idx.parse().ok().ok_or_else(bad_path)?));
+            rest = tail;
+        } else {
+            return Err(bad_path());
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_path, Step};
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path(r#"$.a[2]."b c".d"#).unwrap(),
+            [
+                Step::Key("a".to_owned()),
+                Step::Index(2),
+                Step::Key("b c".to_owned()),
+                Step::Key("d".to_owned()),
+            ],
+        );
+        assert_eq!(parse_path("$").unwrap(), []);
+        for path in ["a", "$.", "$[x]", "$x", r#"$."a"#] {
+            assert!(parse_path(path).is_err(), "{path} should be a bad path");
+        }
+    }
+}